@@ -28,7 +28,11 @@ pub enum ChromeDebuggerMessage<S = String> {
     #[serde(rename = "Network.resourceChangedPriority", rename_all = "camelCase")]
     NetworkResourceChangedPriority { request_id: S },
     #[serde(rename = "Network.loadingFailed", rename_all = "camelCase")]
-    NetworkLoadingFailed { request_id: S },
+    NetworkLoadingFailed {
+        request_id: S,
+        /// Network error as reported by Chrome, e.g., `net::ERR_NAME_NOT_RESOLVED`
+        error_text: Option<S>,
+    },
     #[serde(rename = "Network.dataReceived", rename_all = "camelCase")]
     NetworkDataReceived { request_id: S },
     #[serde(rename = "Network.loadingFinished", rename_all = "camelCase")]
@@ -198,6 +202,11 @@ pub struct CallFrame<S> {
 pub struct Response<S> {
     pub url: S,
     pub timing: Option<Timing>,
+    /// HTTP status code
+    pub status: Option<u16>,
+    /// IP address of the server which sent the response
+    #[serde(rename = "remoteIPAddress")]
+    pub remote_ip_address: Option<S>,
 }
 
 #[derive(Clone, Copy, PartialEq, PartialOrd, Debug, Serialize, Deserialize)]
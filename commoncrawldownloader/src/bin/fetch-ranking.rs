@@ -0,0 +1,181 @@
+//! Download a popularity ranking, e.g., [Tranco] or Alexa, and look up the rank of the measured domains
+//!
+//! The output is a CSV file without header and with the columns domain and rank.
+//! It can be passed to `dns-sequence --ranking` to break down the accuracy by popularity.
+//!
+//! [Tranco]: https://tranco-list.eu/
+
+use anyhow::{bail, Context as _, Error};
+use log::{info, warn};
+use misc_utils::fs;
+use std::{
+    collections::HashMap,
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+};
+use structopt::StructOpt;
+
+static TRANCO_LATEST_ID_URL: &str = "https://tranco-list.eu/top-1m-id";
+
+/// Look up the popularity rank of the measured domains
+///
+/// The output is a CSV file without header and with the columns domain and rank.
+/// Domains which are not part of the ranking are omitted.
+#[derive(StructOpt)]
+#[structopt(global_settings(&[
+    structopt::clap::AppSettings::ColoredHelp,
+    structopt::clap::AppSettings::VersionlessSubcommands
+]))]
+struct CliArgs {
+    /// Source of the ranking
+    ///
+    /// Either `tranco` for the latest Tranco list, `tranco:<ID>` for a specific Tranco list, an URL, or a local file.
+    /// Each line of the ranking is either `<rank>,<domain>`, like the Tranco and Alexa CSV files, or only the domain, with the line number as rank.
+    #[structopt(long = "source", default_value = "tranco")]
+    source: String,
+    /// Number of entries to download from a Tranco list
+    #[structopt(long = "size", default_value = "1000000")]
+    size: usize,
+    /// Directory containing one folder per measured domain, e.g., the dnstap files
+    #[structopt(long = "dataset", parse(from_os_str), conflicts_with = "domains")]
+    dataset: Option<PathBuf>,
+    /// Write the ranks into this file instead of stdout
+    #[structopt(short = "o", long = "output", parse(from_os_str))]
+    output: Option<PathBuf>,
+    /// Measured domains to look up
+    #[structopt(value_name = "DOMAIN")]
+    domains: Vec<String>,
+}
+
+fn main() -> Result<(), Error> {
+    // generic setup
+    env_logger::init();
+    let cli_args = CliArgs::from_args();
+
+    let domains = match &cli_args.dataset {
+        Some(dataset) => dataset_domains(dataset)?,
+        None => cli_args.domains.clone(),
+    };
+    if domains.is_empty() {
+        bail!("No domains given, use `--dataset` or list them as arguments.");
+    }
+
+    let ranking = parse_ranking(&fetch_ranking(&cli_args.source, cli_args.size)?)?;
+    info!("The ranking contains {} domains.", ranking.len());
+
+    let mut output = String::new();
+    let mut missing = 0;
+    for domain in &domains {
+        match ranking.get(domain.as_str()) {
+            Some(rank) => output += &format!("{},{}\n", domain, rank),
+            None => {
+                warn!("Domain '{}' is not part of the ranking", domain);
+                missing += 1;
+            }
+        }
+    }
+    info!(
+        "Found the rank of {} out of {} domains.",
+        domains.len() - missing,
+        domains.len()
+    );
+
+    match &cli_args.output {
+        Some(path) => fs::write(path, output)?,
+        None => io::stdout().write_all(output.as_bytes())?,
+    }
+    Ok(())
+}
+
+/// List the names of all folders in `dataset`
+fn dataset_domains(dataset: &Path) -> Result<Vec<String>, Error> {
+    let mut domains = Vec::new();
+    for entry in std::fs::read_dir(dataset)
+        .with_context(|| format!("Cannot list the dataset '{}'", dataset.display()))?
+    {
+        let entry = entry?;
+        if entry.path().is_dir() {
+            domains.push(entry.file_name().to_string_lossy().into_owned());
+        }
+    }
+    domains.sort();
+    Ok(domains)
+}
+
+/// Return the content of the ranking described by `source`, see [`CliArgs::source`]
+fn fetch_ranking(source: &str, size: usize) -> Result<String, Error> {
+    let url = if source == "tranco" {
+        let id = download(TRANCO_LATEST_ID_URL)?;
+        format!("https://tranco-list.eu/download/{}/{}", id.trim(), size)
+    } else if let Some(id) = source.strip_prefix("tranco:") {
+        format!("https://tranco-list.eu/download/{}/{}", id, size)
+    } else if source.starts_with("http://") || source.starts_with("https://") {
+        source.to_string()
+    } else {
+        return fs::read_to_string(source)
+            .with_context(|| format!("Cannot read the ranking '{}'", source));
+    };
+    info!("Download ranking from {}", url);
+    download(&url)
+}
+
+fn download(url: &str) -> Result<String, Error> {
+    let mut response = reqwest::blocking::get(url)?;
+    if !response.status().is_success() {
+        bail!("Error while fetching {}: {}", url, response.status());
+    }
+    let mut content = String::new();
+    response.read_to_string(&mut content)?;
+    Ok(content)
+}
+
+/// Map each domain of the ranking to its rank
+///
+/// If a domain occurs multiple times, the best rank is kept.
+/// Lists without explicit ranks are ranked by their order, ignoring empty lines and comments.
+fn parse_ranking(content: &str) -> Result<HashMap<&str, usize>, Error> {
+    let mut ranking = HashMap::new();
+    let mut entries = 0;
+    for (line_number, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        entries += 1;
+        let (rank, domain) = match line.split_once(',') {
+            Some((rank, domain)) => (
+                rank.trim()
+                    .parse()
+                    .with_context(|| format!("Invalid rank in line {}", line_number + 1))?,
+                domain.trim(),
+            ),
+            None => (entries, line),
+        };
+        let best = ranking.entry(domain).or_insert(rank);
+        *best = (*best).min(rank);
+    }
+    Ok(ranking)
+}
+
+#[test]
+fn test_parse_ranking() {
+    let tranco = "1,google.com\n2,facebook.com\n3,microsoft.com\n";
+    let ranking = parse_ranking(tranco).unwrap();
+    assert_eq!(Some(&2), ranking.get("facebook.com"));
+    assert_eq!(None, ranking.get("example.com"));
+
+    // One domain per line, like the lists in the `alexa` folder
+    let alexa = "google.com\nyoutube.com\n";
+    let ranking = parse_ranking(alexa).unwrap();
+    assert_eq!(Some(&1), ranking.get("google.com"));
+    assert_eq!(Some(&2), ranking.get("youtube.com"));
+
+    // Comments and empty lines do not count towards the rank
+    let commented = "# Alexa top sites\n\ngoogle.com\n# video\nyoutube.com\n";
+    let ranking = parse_ranking(commented).unwrap();
+    assert_eq!(2, ranking.len());
+    assert_eq!(Some(&1), ranking.get("google.com"));
+    assert_eq!(Some(&2), ranking.get("youtube.com"));
+
+    assert!(parse_ranking("first,google.com").is_err());
+}
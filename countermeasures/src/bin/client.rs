@@ -4,31 +4,39 @@
 use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
 use chrono::{SecondsFormat, Utc};
 use futures::{future, Stream, StreamExt};
-use log::{info, trace, warn};
+use log::{error, info, trace, warn};
 use openssl::{
     pkey::PKey,
     ssl::{SslAcceptor, SslConnector, SslMethod, SslOptions, SslVerifyMode, SslVersion},
     x509::X509,
 };
-use sequences::{load_sequence::convert_to_sequence, AbstractQueryResponse, LoadSequenceConfig};
+use sequences::{
+    adaptive_padding::ApTraceWriter, load_sequence::convert_to_sequence, AbstractQueryResponse,
+    LoadSequenceConfig,
+};
 use std::{
     mem,
     net::SocketAddr,
-    path::PathBuf,
+    path::{Path, PathBuf},
     sync::{Arc, Mutex},
+    time::Duration,
 };
 use structopt::StructOpt;
 use tlsproxy::{
-    print_error, wrap_stream, DnsBytesStream, EnsurePadding, Error, HostnameSocketAddr, MyStream,
-    MyTcpStream, Payload, Strategy, TokioOpensslStream, Transport, SERVER_CERT, SERVER_KEY,
+    parse_duration_ms, print_error, refresh_addr, wrap_stream, AdaptivePaddingConfig,
+    AddressHealth, CapturedStream, DnsBytesStream, EnsurePadding, Error, HostnameSocketAddr,
+    MyStream, MyTcpStream, Payload, PcapWriter, SessionRegistry, Strategy, StrategyConfig,
+    TokioOpensslStream, TransferredBytes, Transport, UpstreamTls, SERVER_CERT, SERVER_KEY,
 };
 use tokio::{
     fs::File,
     io::{AsyncWrite, AsyncWriteExt},
     net::{TcpListener, TcpStream},
+    signal::unix::{signal, SignalKind},
+    task,
 };
 use trust_dns_proto::{
-    op::message::Message,
+    op::{message::Message, MessageType},
     serialize::binary::{BinEncodable, BinEncoder},
 };
 
@@ -73,6 +81,8 @@ struct CliArgs {
     listen: SocketAddr,
 
     /// Remote DNS over TLS endpoint
+    ///
+    /// All addresses of a hostname are used, failing over to the next one if an address is unreachable.
     #[structopt(
         short = "s",
         long = "server",
@@ -81,6 +91,28 @@ struct CliArgs {
     )]
     server: HostnameSocketAddr,
 
+    /// Order the resolved addresses of `server` like Happy Eyeballs, alternating between IPv6 and IPv4
+    #[structopt(long = "happy-eyeballs")]
+    happy_eyeballs: bool,
+
+    /// Abort connecting to an address of `server` after this many ms and fail over to the next address
+    #[structopt(
+        long = "connect-timeout",
+        value_name = "MS",
+        default_value = "5000",
+        parse(try_from_str = parse_duration_ms)
+    )]
+    connect_timeout: Duration,
+
+    /// Try unreachable addresses of `server` last for this many ms, doubling with each further failure
+    #[structopt(
+        long = "unhealthy-backoff",
+        value_name = "MS",
+        default_value = "30000",
+        parse(try_from_str = parse_duration_ms)
+    )]
+    unhealthy_backoff: Duration,
+
     /// Log all TLS keys into this file
     #[structopt(long = "sslkeylogfile", env = "SSLKEYLOGFILE", value_name = "FILE")]
     sslkeylogfile: Option<PathBuf>,
@@ -89,6 +121,10 @@ struct CliArgs {
     #[structopt(long = "dump-sequences", value_name = "DIR")]
     dump_sequences: Option<PathBuf>,
 
+    /// Append a JSON line per finished connection, linking it to its TLS keys, timestamps, and metrics
+    #[structopt(long = "session-index", value_name = "FILE")]
+    session_index: Option<PathBuf>,
+
     /// Force the connection to use TCP. Conflicts with `--tls`.
     ///
     /// If unspecified infer transport from `server` port.
@@ -101,16 +137,81 @@ struct CliArgs {
     #[structopt(long = "tls", conflicts_with = "tcp")]
     tls: bool,
 
+    #[structopt(flatten)]
+    upstream_tls: UpstreamTls,
+
+    /// Write the state transitions of Adaptive Padding as JSONL into this file
+    ///
+    /// The records are tagged with the client address and use the same format as the simulator in `sequences`.
+    #[structopt(long = "ap-trace", value_name = "FILE")]
+    ap_trace: Option<PathBuf>,
+
+    /// Record the connections to the upstream resolver, which carry the padded queries, into this pcap file
+    ///
+    /// The file contains the TLS records as seen on the wire and can be converted with `sequences::pcap`.
+    #[structopt(long = "pcap", value_name = "FILE")]
+    pcap: Option<PathBuf>,
+
+    /// Read the full strategy, including the parameters of Adaptive Padding, from this TOML file
+    ///
+    /// This replaces the subcommand.
+    /// The config is echoed into the session index.
+    /// On SIGHUP the file is read again and new connections use the updated strategy and upstream server.
+    #[structopt(long = "strategy-config", value_name = "FILE")]
+    strategy_config: Option<PathBuf>,
+
     #[structopt(subcommand)]
-    strategy: Strategy,
+    strategy: Option<Strategy>,
 }
 
 // #[derive(Debug)]
 struct Config {
     args: CliArgs,
+    /// Unreachable addresses of the upstream server, kept across reloads
+    health: AddressHealth,
     message: Mutex<Vec<AbstractQueryResponse>>,
     transport: Transport,
     acceptor: Option<SslAcceptor>,
+    registry: Arc<SessionRegistry>,
+    ap_trace: Option<ApTraceWriter>,
+    pcap: Option<Arc<PcapWriter>>,
+    /// Settings which are replaced when reloading the `--strategy-config` on SIGHUP
+    settings: Mutex<Settings>,
+}
+
+/// The part of the [`Config`] which can be reloaded at runtime
+///
+/// Each connection takes a copy of the settings when it starts.
+/// Thus, a reload only affects new connections, while established connections are kept.
+#[derive(Clone, Debug)]
+struct Settings {
+    /// Upstream resolver, which is resolved again after the DNS records expired
+    server: Arc<Mutex<HostnameSocketAddr>>,
+    /// Strategy of the subcommand or `--strategy-config`
+    strategy: Strategy,
+    ap_config: AdaptivePaddingConfig,
+    /// The `[tls]` table of the `--strategy-config` takes precedence over the `--tls-*` arguments
+    upstream_tls: UpstreamTls,
+}
+
+impl Settings {
+    fn new(args: &CliArgs, strategy_config: &StrategyConfig) -> Result<Self, Error> {
+        let mut server = strategy_config
+            .server()?
+            .unwrap_or_else(|| args.server.clone())
+            .with_happy_eyeballs(args.happy_eyeballs);
+        server.resolve_again()?;
+
+        Ok(Self {
+            server: Arc::new(Mutex::new(server)),
+            strategy: strategy_config.strategy()?,
+            ap_config: strategy_config.ap_config()?,
+            upstream_tls: strategy_config
+                .upstream_tls()?
+                .cloned()
+                .unwrap_or_else(|| args.upstream_tls.clone()),
+        })
+    }
 }
 
 fn main() -> Result<(), Error> {
@@ -131,11 +232,18 @@ fn main() -> Result<(), Error> {
 }
 
 async fn async_run(cli_args: CliArgs) -> Result<(), Error> {
+    let strategy_config = StrategyConfig::from_args(
+        cli_args.strategy_config.as_deref(),
+        cli_args.strategy.as_ref(),
+    )?;
+    let settings = Settings::new(&cli_args, &strategy_config)?;
+
     // Create a TCP listener which will listen for incoming connections.
     let mut socket = TcpListener::bind(&cli_args.listen).await?;
     println!(
         "Listening on: {}\nProxying to: {}\n",
-        cli_args.listen, cli_args.server
+        cli_args.listen,
+        settings.server.lock().unwrap()
     );
 
     let transport = if cli_args.tcp {
@@ -146,26 +254,48 @@ async fn async_run(cli_args: CliArgs) -> Result<(), Error> {
         Transport::Tcp
     };
 
+    let registry = SessionRegistry::new(
+        cli_args.sslkeylogfile.clone(),
+        cli_args.session_index.clone(),
+    )?;
+    registry.set_strategy_config(&strategy_config)?;
+
     let acceptor = if transport == Transport::Tls {
         let mut acceptor = SslAcceptor::mozilla_intermediate(SslMethod::tls())?;
         acceptor.set_verify(SslVerifyMode::NONE);
         acceptor.set_certificate(X509::from_pem(SERVER_CERT)?.as_ref())?;
         acceptor.set_private_key(PKey::private_key_from_pem(SERVER_KEY)?.as_ref())?;
-        if let Some(logfile) = &cli_args.sslkeylogfile {
-            let cb = tlsproxy::keylog_to_file(logfile.clone());
-            acceptor.set_keylog_callback(cb);
+        if registry.is_enabled() {
+            acceptor.set_keylog_callback(registry.keylog_callback());
         }
         Some(acceptor.build())
     } else {
         None
     };
 
+    let ap_trace = cli_args
+        .ap_trace
+        .as_deref()
+        .map(ApTraceWriter::create)
+        .transpose()?;
+    let pcap = cli_args
+        .pcap
+        .as_deref()
+        .map(PcapWriter::create)
+        .transpose()?;
+    let health = AddressHealth::new(cli_args.connect_timeout, cli_args.unhealthy_backoff);
     let config: Arc<Config> = Arc::new(Config {
         args: cli_args,
+        health,
         message: Mutex::default(),
         transport,
         acceptor,
+        registry,
+        ap_trace,
+        pcap,
+        settings: Mutex::new(settings),
     });
+    tokio::spawn(print_error(reload_on_hangup(config.clone())));
     let done = socket
         .incoming()
         // conver the Error to tlsproxy::Error
@@ -178,13 +308,77 @@ async fn async_run(cli_args: CliArgs) -> Result<(), Error> {
     Ok(())
 }
 
-async fn handle_client(config: Arc<Config>, client: Result<TcpStream, Error>) -> Result<(), Error> {
-    let client = client?;
-    client.set_nodelay(true)?;
+/// Read the `--strategy-config` again, whenever the process receives SIGHUP
+///
+/// If the new config is invalid, the previous settings stay in use.
+async fn reload_on_hangup(config: Arc<Config>) -> Result<(), Error> {
+    let mut hangup = signal(SignalKind::hangup())?;
+    while hangup.recv().await.is_some() {
+        let path = match &config.args.strategy_config {
+            Some(path) => path,
+            None => {
+                warn!("Ignoring SIGHUP, since the strategy is not read from a --strategy-config");
+                continue;
+            }
+        };
 
-    let server_socket_addr = config.args.server.socket_addr();
-    let server = TcpStream::connect(&server_socket_addr).await?;
-    server.set_nodelay(true)?;
+        // Resolving the upstream server blocks, so it must not run on the runtime
+        let args = config.args.clone();
+        let config_path = path.clone();
+        let loaded = task::spawn_blocking(move || load_settings(&args, &config_path)).await;
+        match loaded {
+            Ok(Ok((strategy_config, settings))) => {
+                info!(
+                    "Reloaded '{}', proxying to {} with strategy {}",
+                    path.display(),
+                    settings.server.lock().unwrap(),
+                    settings.strategy
+                );
+                if let Err(err) = config.registry.set_strategy_config(&strategy_config) {
+                    error!(
+                        "Could not record the reloaded strategy in the session index: {}",
+                        err
+                    );
+                }
+                *config.settings.lock().unwrap() = settings;
+            }
+            Ok(Err(err)) => error!(
+                "Keeping the previous configuration, since reloading '{}' failed: {}",
+                path.display(),
+                err
+            ),
+            Err(err) => error!(
+                "Keeping the previous configuration, since reloading '{}' panicked: {}",
+                path.display(),
+                err
+            ),
+        }
+    }
+    Ok(())
+}
+
+/// Load the strategy config at `path` and derive the [`Settings`] from it
+fn load_settings(args: &CliArgs, path: &Path) -> Result<(StrategyConfig, Settings), Error> {
+    let strategy_config = StrategyConfig::load(path)?;
+    let settings = Settings::new(args, &strategy_config)?;
+    Ok((strategy_config, settings))
+}
+
+async fn handle_client(config: Arc<Config>, client: Result<TcpStream, Error>) -> Result<(), Error> {
+    let client = client.map_err(Error::client_side)?;
+    let client_addr = client.peer_addr().map_err(Error::ClientIo)?;
+    client.set_nodelay(true).map_err(Error::ClientIo)?;
+
+    // Take a copy, such that a reload does not affect this connection
+    let settings = config.settings.lock().unwrap().clone();
+    let server_addr = refresh_addr(&settings.server).await;
+    let (server, server_socket_addr) = server_addr
+        .connect_with_health(&config.health)
+        .await
+        .map_err(Error::upstream_side)?;
+    server.set_nodelay(true).map_err(Error::UpstreamIo)?;
+    let local_addr = server.local_addr().map_err(Error::UpstreamIo)?;
+    let server = CapturedStream::new(server, config.pcap.clone(), local_addr, server_socket_addr);
     let mut connector = SslConnector::builder(SslMethod::tls())?;
     connector.set_min_proto_version(Some(SslVersion::TLS1_2))?;
     connector.set_options(SslOptions::NO_COMPRESSION);
@@ -208,14 +402,19 @@ async fn handle_client(config: Arc<Config>, client: Result<TcpStream, Error>) ->
             passed_openssl_cert_check || (cert_signature == good_cert_signature)
         },
     );
-    if let Some(logfile) = std::env::var_os("SSLKEYLOGFILE") {
-        let cb = tlsproxy::keylog_to_file(logfile);
-        connector.set_keylog_callback(cb);
+    settings.upstream_tls.configure_builder(&mut connector)?;
+    if config.registry.is_enabled() {
+        connector.set_keylog_callback(config.registry.keylog_callback());
     }
     let connector = connector.build();
-    let connector_config = connector.configure()?;
-    let hostname = &config.args.server.hostname();
-    let server = tokio_openssl::connect(connector_config, hostname, server).await?;
+    let (connector_config, hostname) = settings
+        .upstream_tls
+        .configure_connection(&connector, &server_addr.hostname())?;
+    let server = tokio_openssl::connect(connector_config, &hostname, server).await?;
+    let server_conn =
+        config
+            .registry
+            .open_connection("server", server_socket_addr, Some(server.ssl()));
 
     // Create separate read/write handles for the TCP clients that we're
     // proxying data between. Note that typically you'd use
@@ -226,13 +425,22 @@ async fn handle_client(config: Arc<Config>, client: Result<TcpStream, Error>) ->
     //
     // As a result, we wrap up our client/server manually in arcs and
     // use the impls below on our custom `MyTcpStream` type.
-    let client_reader: MyStream<_> = match config.transport {
-        Transport::Tcp => MyTcpStream::new(Arc::new(Mutex::new(client))).into(),
-        Transport::Tls => TokioOpensslStream::new(Arc::new(Mutex::new({
+    let (client_reader, client_conn): (MyStream<_>, _) = match config.transport {
+        Transport::Tcp => {
+            let conn = config.registry.open_connection("client", client_addr, None);
+            (MyTcpStream::new(Arc::new(Mutex::new(client))).into(), conn)
+        }
+        Transport::Tls => {
             let acceptor = &config.acceptor.clone().unwrap();
-            tokio_openssl::accept(acceptor, client).await?
-        })))
-        .into(),
+            let client = tokio_openssl::accept(acceptor, client).await?;
+            let conn = config
+                .registry
+                .open_connection("client", client_addr, Some(client.ssl()));
+            (
+                TokioOpensslStream::new(Arc::new(Mutex::new(client))).into(),
+                conn,
+            )
+        }
     };
     let client_writer = client_reader.clone();
     let server_reader = TokioOpensslStream::new(Arc::new(Mutex::new(server)));
@@ -241,14 +449,24 @@ async fn handle_client(config: Arc<Config>, client: Result<TcpStream, Error>) ->
     // Copy the data (in parallel) between the client and the server.
     // After the copy is done we indicate to the remote side that we've
     // finished by shutting down the connection.
-    let client_reader = DnsBytesStream::new(client_reader);
+    let client_reader = DnsBytesStream::new(client_reader).expect_message_type(MessageType::Query);
     let client_reader = EnsurePadding::new(client_reader);
-    let client_reader = wrap_stream(client_reader, &config.args.strategy);
+    let ap_trace = config
+        .ap_trace
+        .as_ref()
+        .map(|trace| trace.with_id(client_addr.to_string()));
+    let client_reader = wrap_stream(
+        client_reader,
+        &settings.strategy,
+        settings.ap_config,
+        ap_trace,
+    );
     let client_to_server = copy_client_to_server(client_reader, server_writer);
 
     let server_reader = DnsBytesStream::new(server_reader)
+        .expect_message_type(MessageType::Response)
         .map(|dns| {
-            let dns = dns?;
+            let dns = dns.map_err(|err| Error::from(err).upstream_side())?;
             let msg = trust_dns_proto::op::message::Message::from_vec(&*dns).unwrap();
             Ok((dns, msg))
         })
@@ -286,6 +504,15 @@ async fn handle_client(config: Arc<Config>, client: Result<TcpStream, Error>) ->
         "client wrote {} bytes and received {} bytes",
         from_client, from_server
     );
+    client_conn.finish(TransferredBytes {
+        sent: from_server,
+        received: 0,
+    })?;
+    // The bytes sent to the server include the dummy messages added by the strategy
+    server_conn.finish(TransferredBytes {
+        sent: from_client,
+        received: from_server,
+    })?;
 
     Ok(())
 }
@@ -302,7 +529,7 @@ where
         out.truncate(0);
         // write placeholder length, replaced later
         WriteBytesExt::write_u16::<BigEndian>(&mut out, 0)?;
-        match dns.transpose_error()? {
+        match dns.transpose_error().map_err(Error::client_side)? {
             Payload::Payload(p) => {
                 info!("Send payload");
                 let mut encoder = BinEncoder::new(&mut out);
@@ -319,15 +546,15 @@ where
         BigEndian::write_u16(&mut out[..2], len);
 
         total_bytes += out.len() as u64;
-        server.write_all(&out).await?;
-        server.flush().await?;
+        server.write_all(&out).await.map_err(Error::UpstreamIo)?;
+        server.flush().await.map_err(Error::UpstreamIo)?;
     }
 
     // We need to pass the shutdown from client to server, that the server sees that the client shut
     // down the connection. Automatic shutdown does not work in this case, as the reading and
     // writing part access the same underlying TcpStream, thus the drop based shutdown would be too
     // late.
-    server.shutdown().await?;
+    server.shutdown().await.map_err(Error::UpstreamIo)?;
     Ok(total_bytes)
 }
 
@@ -355,15 +582,15 @@ where
 
         // Add 2 for the length of the length header
         total_bytes += out.len() as u64;
-        client.write_all(&out).await?;
-        client.flush().await?;
+        client.write_all(&out).await.map_err(Error::ClientIo)?;
+        client.flush().await.map_err(Error::ClientIo)?;
     }
 
     // We need to pass the shutdown from client to server, that the server sees that the client shut
     // down the connection. Automatic shutdown does not work in this case, as the reading and
     // writing part access the same underlying TcpStream, thus the drop based shutdown would be too
     // late.
-    client.shutdown().await?;
+    client.shutdown().await.map_err(Error::ClientIo)?;
     Ok(total_bytes)
 }
 
@@ -390,3 +617,39 @@ async fn write_sequence(
     }
     Ok(())
 }
+
+#[test]
+fn test_load_settings() {
+    let dir = std::env::temp_dir().join(format!("tlsproxy-client-settings-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("strategy.toml");
+    let args = CliArgs::from_iter(&["client", "--strategy-config", &path.to_string_lossy()]);
+
+    // The server of the config takes precedence over `--server`
+    std::fs::write(
+        &path,
+        "strategy = \"constant\"\nrate = 50\nserver = \"127.0.0.1:8853\"\n",
+    )
+    .unwrap();
+    let (strategy_config, settings) = load_settings(&args, &path).unwrap();
+    assert_eq!(
+        "constant-50",
+        strategy_config.strategy().unwrap().to_string()
+    );
+    assert_eq!("constant-50", settings.strategy.to_string());
+    assert_eq!(8853, settings.server.lock().unwrap().port());
+
+    std::fs::write(&path, "strategy = \"pass\"\n").unwrap();
+    let (_, settings) = load_settings(&args, &path).unwrap();
+    assert_eq!("pass", settings.strategy.to_string());
+    assert_eq!(853, settings.server.lock().unwrap().port());
+
+    // Invalid configs are rejected, such that the previous settings stay in use
+    std::fs::write(&path, "strategy = \"pass\"\nrate = 50\n").unwrap();
+    assert!(load_settings(&args, &path).is_err());
+    std::fs::write(&path, "strategy = \"pass\"\nserver = \"no port\"\n").unwrap();
+    assert!(load_settings(&args, &path).is_err());
+    assert!(load_settings(&args, &dir.join("missing.toml")).is_err());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,839 @@
+#![deny(rust_2018_compatibility)]
+#![warn(rust_2018_idioms)]
+
+//! Evaluate a padding strategy end-to-end against the classifier
+//!
+//! The dnstap traces of a test dataset are replayed through the proxy server running the chosen strategy.
+//! For each trace a fresh proxy is started with `--pcap`, the client queries of the trace are sent with their original timing, and the padded traffic is recorded.
+//! Each trace is replayed a second time with the `pass` strategy as the baseline.
+//! Both recordings are converted into [`Sequence`]s in the same way and classified against the undefended training data.
+//! The report compares the accuracy on the defended traces with the accuracy on the baseline traces.
+//!
+//! This binary requires the `evaluate-defense` feature, which enables reading pcap files in `sequences`.
+
+use anyhow::{bail, Context as _, Error};
+use futures::{future, stream, Stream, StreamExt, TryStreamExt};
+use log::{debug, info, warn};
+use misc_utils::path::PathExt;
+use openssl::ssl::{SslConnector, SslMethod, SslVerifyMode};
+use sequences::{
+    dnstap::{load_matching_query_responses_from_dnstap_with_policy, QuerySource},
+    knn::{self, DistanceMetric, LabelledSequences, VoteWeighting},
+    load_all_files_with_extension_from_dir_with_config,
+    pcap::{build_sequence_with_summary, PcapFilter},
+    sequence_directories, LoadSequenceConfig, MarkerPolicy, Sequence,
+};
+use serde::Serialize;
+use serde_json::json;
+use std::{
+    collections::HashSet,
+    ffi::OsString,
+    fmt::{self, Display},
+    fs::{self, File},
+    net::{SocketAddr, SocketAddrV4},
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    thread,
+    time::Duration,
+};
+use structopt::StructOpt;
+use tlsproxy::{
+    parse_duration_ms, DnsBytesStream, DnsFrameError, EnsurePadding, Strategy, StrategyConfig,
+};
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+    runtime::Runtime,
+    time::{self, Instant},
+};
+use trust_dns_proto::{
+    op::{Message, MessageType, Query},
+    rr::{Name, RecordType},
+};
+
+/// Marker query sent before `start.example.`, see `taskmanager/docker/bin/control-chrome.py`
+const LARGE_START_MARKER: char = 'a';
+/// Marker query sent after `end.example.`, see `taskmanager/docker/bin/control-chrome.py`
+const LARGE_END_MARKER: char = 'z';
+const START_MARKER: &str = "start.example.";
+const END_MARKER: &str = "end.example.";
+/// The ids of the queries must not collide with the id of the dummy replies of the proxy
+const MAX_QUERIES: usize = 40_000;
+
+#[derive(Debug, StructOpt)]
+#[structopt(global_settings(&[
+    structopt::clap::AppSettings::ColoredHelp,
+    structopt::clap::AppSettings::VersionlessSubcommands
+]))]
+struct CliArgs {
+    /// Base directory containing per domain a folder with the undefended dnstap files for training
+    #[structopt(parse(from_os_str))]
+    base_dir: PathBuf,
+
+    /// Base directory containing per domain a folder with the dnstap files to replay through the proxy
+    #[structopt(parse(from_os_str))]
+    test_data: PathBuf,
+
+    /// Directory for the strategy configs, the pcap files, and the `report.json`
+    #[structopt(short = "o", long = "out", value_name = "DIR", parse(from_os_str))]
+    out_dir: PathBuf,
+
+    /// Path to the `server` binary of the proxy
+    #[structopt(long = "proxy", value_name = "FILE", parse(from_os_str))]
+    proxy: PathBuf,
+
+    /// Strategy of the proxy, written as `pass`, `constant-<ms>`, or `ap[-tin<ms>][-tout<ms>][-max<ms>]`
+    #[structopt(long = "strategy", value_name = "STRATEGY")]
+    strategy: Option<Strategy>,
+
+    /// Read the full strategy from this TOML file instead of `--strategy`
+    #[structopt(long = "strategy-config", value_name = "FILE", parse(from_os_str))]
+    strategy_config: Option<PathBuf>,
+
+    /// Upstream resolver of the proxy
+    ///
+    /// The `server` key of the strategy config takes precedence.
+    #[structopt(
+        long = "upstream",
+        value_name = "HOST:PORT",
+        default_value = "1.1.1.1:853"
+    )]
+    upstream: String,
+
+    /// Local address of the proxy during the replay
+    #[structopt(long = "listen", default_value = "127.0.0.1:1854")]
+    listen: SocketAddrV4,
+
+    /// File extension which must be available in the file to be recognized as a Sequence file
+    #[structopt(
+        long = "extension",
+        value_name = "ext",
+        default_value = "dnstap",
+        parse(from_os_str)
+    )]
+    file_extension: OsString,
+
+    /// Expected marker queries in dnstap files: `strict` or `tolerant`
+    ///
+    /// `tolerant` accepts retried marker queries and missing marker responses.
+    #[structopt(long = "marker-policy", default_value = "strict")]
+    marker_policy: MarkerPolicy,
+
+    /// Only replay this many traces per domain
+    #[structopt(long = "traces-per-domain", value_name = "N")]
+    traces_per_domain: Option<usize>,
+
+    /// Wait this many ms for the proxy to accept connections
+    #[structopt(
+        long = "startup-timeout",
+        value_name = "MS",
+        default_value = "5000",
+        parse(try_from_str = parse_duration_ms)
+    )]
+    startup_timeout: Duration,
+
+    /// Wait this many ms for outstanding responses after the last query of a trace
+    #[structopt(
+        long = "response-timeout",
+        value_name = "MS",
+        default_value = "5000",
+        parse(try_from_str = parse_duration_ms)
+    )]
+    response_timeout: Duration,
+
+    /// Number of neighbors for the k-NN
+    #[structopt(short = "k", default_value = "1")]
+    k: u8,
+
+    /// Additional arguments for the proxy, e.g., `-- --tcp`
+    #[structopt(last = true)]
+    proxy_args: Vec<String>,
+}
+
+/// Client query of the original trace
+#[derive(Clone, Debug)]
+struct ReplayQuery {
+    /// Time since the first query of the trace
+    offset: Duration,
+    name: Name,
+    query_type: RecordType,
+}
+
+/// Result of one trace of the test data
+#[derive(Debug, Serialize)]
+struct TraceReport {
+    domain: String,
+    dnstap: PathBuf,
+    pcap: PathBuf,
+    /// Recording of the trace with the `pass` strategy
+    baseline_pcap: PathBuf,
+    /// Number of replayed queries
+    queries: usize,
+    /// Number of replayed queries, which were answered by the proxy
+    answered: usize,
+    /// Number of replayed queries, which were answered by the proxy with the `pass` strategy
+    baseline_answered: usize,
+    undefended_messages: usize,
+    defended_messages: usize,
+    /// Classification of the trace recorded with the `pass` strategy
+    undefended_label: Option<String>,
+    /// Classification of the trace recorded at the proxy
+    defended_label: Option<String>,
+}
+
+/// Aggregated [`TraceReport`]s
+#[derive(Copy, Clone, Debug, Default, Serialize)]
+struct Summary {
+    traces: usize,
+    undefended_correct: usize,
+    defended_correct: usize,
+    undefended_messages: usize,
+    defended_messages: usize,
+}
+
+impl Summary {
+    fn add(&mut self, trace: &TraceReport) {
+        self.traces += 1;
+        self.undefended_correct +=
+            (trace.undefended_label.as_ref() == Some(&trace.domain)) as usize;
+        self.defended_correct += (trace.defended_label.as_ref() == Some(&trace.domain)) as usize;
+        self.undefended_messages += trace.undefended_messages;
+        self.defended_messages += trace.defended_messages;
+    }
+}
+
+impl Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let percent = |count: usize, total: usize| {
+            if total == 0 {
+                0.
+            } else {
+                count as f64 / total as f64 * 100.
+            }
+        };
+        writeln!(f, "Traces: {}", self.traces)?;
+        writeln!(
+            f,
+            "Accuracy without defense:  {:>6.2}%",
+            percent(self.undefended_correct, self.traces)
+        )?;
+        writeln!(
+            f,
+            "Accuracy with defense:     {:>6.2}%",
+            percent(self.defended_correct, self.traces)
+        )?;
+        write!(
+            f,
+            "Message overhead:          {:>6.2}%",
+            percent(self.defended_messages, self.undefended_messages) - 100.
+        )
+    }
+}
+
+/// Running proxy server, which is killed when dropped
+struct ProxyProcess(Child);
+
+impl ProxyProcess {
+    fn start(args: &CliArgs, strategy_config: &Path, pcap: &Path) -> Result<Self, Error> {
+        let child = Command::new(&args.proxy)
+            .arg("--listen")
+            .arg(args.listen.to_string())
+            .arg("--server")
+            .arg(&args.upstream)
+            .arg("--strategy-config")
+            .arg(strategy_config)
+            .arg("--pcap")
+            .arg(pcap)
+            .args(&args.proxy_args)
+            .stdin(Stdio::null())
+            .spawn()
+            .with_context(|| format!("Cannot start the proxy '{}'", args.proxy.display()))?;
+        Ok(Self(child))
+    }
+}
+
+impl Drop for ProxyProcess {
+    fn drop(&mut self) {
+        if let Err(err) = self.0.kill() {
+            warn!("Cannot stop the proxy: {}", err);
+        }
+        let _ = self.0.wait();
+    }
+}
+
+fn main() -> Result<(), Error> {
+    // generic setup
+    env_logger::init();
+    let args = CliArgs::from_args();
+
+    let strategy_config =
+        StrategyConfig::from_args(args.strategy_config.as_deref(), args.strategy.as_ref())?;
+    info!("Evaluate strategy {}", strategy_config.strategy()?);
+    fs::create_dir_all(&args.out_dir)?;
+    // The proxy always reads the strategy from a file, such that all parameters are applied
+    let strategy_config_path = args.out_dir.join("strategy.toml");
+    fs::write(&strategy_config_path, toml::to_string(&strategy_config)?)?;
+    let baseline_config_path = args.out_dir.join("baseline.toml");
+    fs::write(
+        &baseline_config_path,
+        toml::to_string(&baseline_config(&strategy_config))?,
+    )?;
+
+    let sequence_config = LoadSequenceConfig {
+        marker_policy: args.marker_policy,
+        ..LoadSequenceConfig::default()
+    };
+
+    info!("Start replaying test data...");
+    let mut rt = tokio::runtime::Runtime::new()?;
+    let mut traces = Vec::new();
+    let mut undefended = Vec::new();
+    let mut defended = Vec::new();
+    for (domain, dnstap) in test_files(&args)? {
+        let queries = match load_replay_queries(&dnstap, args.marker_policy) {
+            Ok(queries) => queries,
+            Err(err) => {
+                warn!("Skip '{}': {:#}", dnstap.display(), err);
+                continue;
+            }
+        };
+        let pcap_dir = args.out_dir.join(&domain);
+        fs::create_dir_all(&pcap_dir)?;
+        let pcap_stem = pcap_dir.join(dnstap.file_stem().expect("Each file has a name"));
+        let pcap = pcap_stem.with_extension("pcap");
+        let baseline_pcap = pcap_stem.with_extension("baseline.pcap");
+
+        info!("Replay {} queries of '{}'", queries.len(), dnstap.display());
+        let recordings = record_trace(
+            &mut rt,
+            &args,
+            &baseline_config_path,
+            &queries,
+            &baseline_pcap,
+            sequence_config,
+        )
+        .and_then(|baseline| {
+            let defended = record_trace(
+                &mut rt,
+                &args,
+                &strategy_config_path,
+                &queries,
+                &pcap,
+                sequence_config,
+            )?;
+            Ok((baseline, defended))
+        });
+        let ((baseline_answered, undefended_sequence), (answered, defended_sequence)) =
+            match recordings {
+                Ok(recordings) => recordings,
+                Err(err) => {
+                    warn!("Replaying '{}' failed: {:#}", dnstap.display(), err);
+                    continue;
+                }
+            };
+
+        traces.push(TraceReport {
+            domain,
+            dnstap,
+            pcap,
+            baseline_pcap,
+            queries: queries.len(),
+            answered,
+            baseline_answered,
+            undefended_messages: undefended_sequence.message_count(),
+            defended_messages: defended_sequence.message_count(),
+            undefended_label: None,
+            defended_label: None,
+        });
+        undefended.push(undefended_sequence);
+        defended.push(defended_sequence);
+    }
+    if traces.is_empty() {
+        bail!(
+            "No trace of '{}' could be replayed",
+            args.test_data.display()
+        );
+    }
+    info!("Done replaying {} traces.", traces.len());
+
+    info!("Start loading trainings data...");
+    let training_data: Vec<LabelledSequences<String>> =
+        load_all_files_with_extension_from_dir_with_config(
+            &args.base_dir,
+            &args.file_extension,
+            sequence_config,
+        )?
+        .into_iter()
+        .map(|(label, sequences)| LabelledSequences {
+            true_domain: label.clone(),
+            mapped_domain: label,
+            sequences,
+        })
+        .collect();
+    info!(
+        "Done loading trainings data. Found {} domains.",
+        training_data.len()
+    );
+
+    info!("Start classifying traces...");
+    let classify = |sequences: &[Sequence]| {
+        knn::knn_with_metric(
+            &training_data,
+            sequences,
+            args.k,
+            DistanceMetric::EditDistance,
+            VoteWeighting::Uniform,
+            false,
+        )
+    };
+    let undefended_results = classify(&undefended);
+    let defended_results = classify(&defended);
+    info!("Done classifying traces.");
+
+    let mut summary = Summary::default();
+    for ((trace, undefended), defended) in traces
+        .iter_mut()
+        .zip(&undefended_results)
+        .zip(&defended_results)
+    {
+        trace.undefended_label = undefended.best_label().map(String::from);
+        trace.defended_label = defended.best_label().map(String::from);
+        summary.add(trace);
+    }
+
+    let report = File::create(args.out_dir.join("report.json"))?;
+    serde_json::to_writer_pretty(
+        report,
+        &json!({
+            "strategy": strategy_config,
+            "proxy_args": args.proxy_args,
+            "k": args.k,
+            "summary": summary,
+            "traces": traces,
+        }),
+    )?;
+
+    println!("{}", summary);
+    Ok(())
+}
+
+/// The `pass` strategy with the upstream resolver and TLS options of `strategy_config`
+///
+/// Replaying the traces with this config records the undefended traffic in the same way as the defended traffic.
+fn baseline_config(strategy_config: &StrategyConfig) -> StrategyConfig {
+    StrategyConfig {
+        server: strategy_config.server.clone(),
+        tls: strategy_config.tls.clone(),
+        ..StrategyConfig::new(&Strategy::PassThrough)
+    }
+}
+
+/// Replay the `queries` through a fresh proxy using the strategy config at `strategy_config`
+///
+/// The traffic is recorded into `pcap` and converted into a [`Sequence`].
+/// Returns the number of answered queries and the recorded [`Sequence`].
+fn record_trace(
+    rt: &mut Runtime,
+    args: &CliArgs,
+    strategy_config: &Path,
+    queries: &[ReplayQuery],
+    pcap: &Path,
+    sequence_config: LoadSequenceConfig,
+) -> Result<(usize, Sequence), Error> {
+    let proxy = ProxyProcess::start(args, strategy_config, pcap)?;
+    let answered = rt.block_on(replay_trace(args, queries));
+    // The proxy writes the pcap file on a separate thread, so give it time to write the last packets
+    thread::sleep(Duration::from_millis(100));
+    drop(proxy);
+    let answered = answered?;
+
+    let filter = PcapFilter {
+        server: Some(args.listen),
+        ..PcapFilter::default()
+    };
+    let (sequence, _summary) = build_sequence_with_summary(pcap, filter, false, sequence_config)
+        .with_context(|| format!("Cannot build the sequence of '{}'", pcap.display()))?;
+    Ok((answered, sequence))
+}
+
+/// List the domain and path of all traces in the test data
+fn test_files(args: &CliArgs) -> Result<Vec<(String, PathBuf)>, Error> {
+    let mut files = Vec::new();
+    for dir in sequence_directories(&args.test_data)? {
+        let domain = dir
+            .file_name()
+            .expect("Each directory has a name")
+            .to_string_lossy()
+            .into_owned();
+
+        let mut filenames = Vec::new();
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.is_file() && path.extensions().any(|ext| ext == &*args.file_extension) {
+                filenames.push(path);
+            }
+        }
+        // sort filenames for predictable results
+        filenames.sort();
+        filenames.truncate(args.traces_per_domain.unwrap_or(usize::MAX));
+        files.extend(filenames.into_iter().map(|path| (domain.clone(), path)));
+    }
+    Ok(files)
+}
+
+/// Load the client queries of a dnstap file, which are sent to the proxy
+///
+/// The marker queries are not part of the result, as they are sent by [`replay_trace`] itself.
+fn load_replay_queries(
+    dnstap: &Path,
+    marker_policy: MarkerPolicy,
+) -> Result<Vec<ReplayQuery>, Error> {
+    let mut queries: Vec<_> =
+        load_matching_query_responses_from_dnstap_with_policy(dnstap, marker_policy)?
+            .into_iter()
+            .filter(|query| query.source == QuerySource::Client)
+            .collect();
+    queries.sort_by_key(|query| query.start);
+    let first = match queries.first() {
+        Some(query) => query.start,
+        None => bail!("The trace contains no client queries"),
+    };
+    if queries.len() > MAX_QUERIES {
+        bail!(
+            "Traces with more than {} queries are not supported, but found {}",
+            MAX_QUERIES,
+            queries.len()
+        );
+    }
+
+    queries
+        .into_iter()
+        .map(|query| {
+            Ok(ReplayQuery {
+                offset: (query.start - first).to_std()?,
+                name: Name::from_ascii(&query.qname)?,
+                query_type: query.qtype.parse()?,
+            })
+        })
+        .collect()
+}
+
+/// Send the `queries` with their original timing to the proxy, surrounded by the marker queries
+///
+/// The marker queries are the same as in the capturing pipeline, such that `sequences::pcap` finds the start and end of the trace.
+/// Returns the number of `queries` which were answered.
+async fn replay_trace(args: &CliArgs, queries: &[ReplayQuery]) -> Result<usize, Error> {
+    let marker = |label: char| {
+        let name = |len: usize| label.to_string().repeat(len);
+        Name::from_ascii(format!(
+            "{}.{}.{}.{}.",
+            name(61),
+            name(63),
+            name(63),
+            name(63)
+        ))
+    };
+    let mut names = vec![
+        (marker(LARGE_START_MARKER)?, RecordType::A),
+        (Name::from_ascii(START_MARKER)?, RecordType::A),
+    ];
+    names.extend(
+        queries
+            .iter()
+            .map(|query| (query.name.clone(), query.query_type)),
+    );
+    names.push((Name::from_ascii(END_MARKER)?, RecordType::A));
+    names.push((marker(LARGE_END_MARKER)?, RecordType::A));
+
+    // The id of each message is its position plus one
+    let messages: Vec<Vec<u8>> = names
+        .into_iter()
+        .enumerate()
+        .map(|(idx, (name, query_type))| {
+            let mut msg = Message::new();
+            msg.set_id(idx as u16 + 1)
+                .set_message_type(MessageType::Query)
+                .set_recursion_desired(true)
+                .add_query(Query::query(name, query_type));
+            msg.to_vec()
+        })
+        .collect::<Result<_, _>>()?;
+    // The proxy pads the queries only towards the upstream resolver
+    let messages: Vec<Vec<u8>> = EnsurePadding::new(stream::iter(
+        messages.into_iter().map(Ok::<_, tlsproxy::Error>),
+    ))
+    .and_then(|msg| future::ready(msg.to_vec().map_err(tlsproxy::Error::from)))
+    .try_collect()
+    .await?;
+    let (markers_before, rest) = messages.split_at(2);
+    let (trace, markers_after) = rest.split_at(queries.len());
+
+    let stream = connect_with_retry(SocketAddr::V4(args.listen), args.startup_timeout).await?;
+    stream.set_nodelay(true)?;
+    let mut connector = SslConnector::builder(SslMethod::tls())?;
+    // The proxy uses a self-signed certificate
+    connector.set_verify(SslVerifyMode::NONE);
+    let connector = connector.build();
+    let stream = tokio_openssl::connect(connector.configure()?, "localhost", stream)
+        .await
+        .map_err(tlsproxy::Error::from)?;
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut responses = DnsBytesStream::new(reader).expect_message_type(MessageType::Response);
+
+    for (idx, msg) in markers_before.iter().enumerate() {
+        let id = idx as u16 + 1;
+        exchange_marker(&mut writer, &mut responses, id, msg, args.response_timeout).await?;
+    }
+
+    let mut pending: HashSet<u16> = (3..3 + queries.len() as u16).collect();
+    let start = Instant::now();
+    let sender = async {
+        for (query, msg) in queries.iter().zip(trace) {
+            time::delay_until(start + query.offset).await;
+            send(&mut writer, msg).await?;
+        }
+        Ok::<_, Error>(())
+    };
+    let last_offset = queries.last().map(|query| query.offset).unwrap_or_default();
+    let receiver = time::timeout(
+        last_offset + args.response_timeout,
+        receive_responses(&mut responses, &mut pending),
+    );
+    let (sent, received) = future::join(sender, receiver).await;
+    sent?;
+    match received {
+        Ok(received) => received?,
+        Err(_) => warn!("{} queries were not answered in time", pending.len()),
+    }
+    let answered = queries.len() - pending.len();
+
+    let first_id = 3 + queries.len() as u16;
+    for (idx, msg) in markers_after.iter().enumerate() {
+        let id = first_id + idx as u16;
+        exchange_marker(&mut writer, &mut responses, id, msg, args.response_timeout).await?;
+    }
+    writer.shutdown().await?;
+    Ok(answered)
+}
+
+/// Connect to the proxy, retrying until it listens or `timeout` is over
+async fn connect_with_retry(addr: SocketAddr, timeout: Duration) -> Result<TcpStream, Error> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        match TcpStream::connect(addr).await {
+            Ok(stream) => return Ok(stream),
+            Err(err) if Instant::now() < deadline => {
+                debug!("The proxy is not listening yet: {}", err);
+                time::delay_for(Duration::from_millis(50)).await;
+            }
+            Err(err) => {
+                return Err(err).with_context(|| format!("The proxy does not listen on {}", addr))
+            }
+        }
+    }
+}
+
+/// Send the marker query `msg` and wait for its response
+async fn exchange_marker<W, S>(
+    writer: &mut W,
+    responses: &mut S,
+    id: u16,
+    msg: &[u8],
+    timeout: Duration,
+) -> Result<(), Error>
+where
+    W: AsyncWrite + Unpin,
+    S: Stream<Item = Result<Vec<u8>, DnsFrameError>> + Unpin,
+{
+    send(writer, msg).await?;
+    let mut pending: HashSet<u16> = std::iter::once(id).collect();
+    time::timeout(timeout, receive_responses(responses, &mut pending))
+        .await
+        .with_context(|| format!("The marker query {} was not answered", id))?
+}
+
+/// Send a single DNS message in the DNS over TCP framing
+async fn send<W>(writer: &mut W, msg: &[u8]) -> Result<(), Error>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut out = Vec::with_capacity(2 + msg.len());
+    out.extend_from_slice(&(msg.len() as u16).to_be_bytes());
+    out.extend_from_slice(msg);
+    writer.write_all(&out).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Read responses until all `pending` ids are answered
+///
+/// Responses with unknown ids, like the dummy replies of the padding strategies, are ignored.
+async fn receive_responses<S>(responses: &mut S, pending: &mut HashSet<u16>) -> Result<(), Error>
+where
+    S: Stream<Item = Result<Vec<u8>, DnsFrameError>> + Unpin,
+{
+    while !pending.is_empty() {
+        match responses.next().await {
+            Some(response) => {
+                let response = response?;
+                pending.remove(&u16::from_be_bytes([response[0], response[1]]));
+            }
+            None => bail!(
+                "The proxy closed the connection with {} unanswered queries",
+                pending.len()
+            ),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_summary() {
+        let trace = |label: Option<&str>, defended_label: Option<&str>| TraceReport {
+            domain: "a.example".to_string(),
+            dnstap: PathBuf::new(),
+            pcap: PathBuf::new(),
+            baseline_pcap: PathBuf::new(),
+            queries: 5,
+            answered: 5,
+            baseline_answered: 5,
+            undefended_messages: 10,
+            defended_messages: 15,
+            undefended_label: label.map(String::from),
+            defended_label: defended_label.map(String::from),
+        };
+        let mut summary = Summary::default();
+        summary.add(&trace(Some("a.example"), Some("b.example")));
+        summary.add(&trace(Some("a.example"), Some("a.example")));
+        summary.add(&trace(None, None));
+        assert_eq!(3, summary.traces);
+        assert_eq!(2, summary.undefended_correct);
+        assert_eq!(1, summary.defended_correct);
+
+        let summary = summary.to_string();
+        assert!(summary.contains("66.67%"), "{}", summary);
+        assert!(summary.contains("33.33%"), "{}", summary);
+        assert!(summary.ends_with("50.00%"), "{}", summary);
+    }
+
+    #[test]
+    fn test_baseline_config() {
+        let strategy_config: StrategyConfig = toml::from_str(
+            r#"
+            strategy = "ap"
+            server = "127.0.0.1:853"
+
+            [adaptive-padding]
+            seed = 42
+
+            [tls]
+            sni = "dns.example"
+            "#,
+        )
+        .unwrap();
+        let baseline = baseline_config(&strategy_config);
+        assert_eq!("pass", baseline.strategy().unwrap().to_string());
+        assert_eq!(strategy_config.server, baseline.server);
+        assert_eq!(strategy_config.tls, baseline.tls);
+        assert_eq!(None, baseline.adaptive_padding);
+    }
+
+    #[test]
+    fn test_test_files() {
+        let dir = std::env::temp_dir().join(format!("evaluate-defense-{}", std::process::id()));
+        for file in &[
+            "b.example/2.dnstap",
+            "b.example/1.dnstap.xz",
+            "b.example/1.json",
+            "a.example/1.dnstap",
+        ] {
+            let path = dir.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "").unwrap();
+        }
+
+        let args = |traces_per_domain: &str| {
+            CliArgs::from_iter(&[
+                "evaluate-defense",
+                "--out",
+                "out",
+                "--proxy",
+                "server",
+                "--strategy",
+                "pass",
+                "--traces-per-domain",
+                traces_per_domain,
+                "train",
+                &dir.to_string_lossy(),
+            ])
+        };
+        let files: Vec<_> = test_files(&args("5"))
+            .unwrap()
+            .into_iter()
+            .map(|(domain, path)| (domain, path.strip_prefix(&dir).unwrap().to_path_buf()))
+            .collect();
+        assert_eq!(
+            vec![
+                ("a.example".to_string(), PathBuf::from("a.example/1.dnstap")),
+                (
+                    "b.example".to_string(),
+                    PathBuf::from("b.example/1.dnstap.xz")
+                ),
+                ("b.example".to_string(), PathBuf::from("b.example/2.dnstap")),
+            ],
+            files
+        );
+        assert_eq!(2, test_files(&args("1")).unwrap().len());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_load_replay_queries() {
+        let dnstap = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("../sequences/tests/data/zuanke8.com-5-0.dnstap.xz");
+        let queries = load_replay_queries(&dnstap, MarkerPolicy::strict()).unwrap();
+        assert!(!queries.is_empty());
+        assert_eq!(Duration::from_secs(0), queries[0].offset);
+        assert!(queries
+            .windows(2)
+            .all(|pair| pair[0].offset <= pair[1].offset));
+        // The markers are sent by `replay_trace` itself
+        assert!(queries.iter().all(|query| {
+            let name = query.name.to_ascii();
+            name != START_MARKER && name != END_MARKER
+        }));
+    }
+
+    #[test]
+    fn test_receive_responses() {
+        let response = |id: u16| -> Result<Vec<u8>, DnsFrameError> {
+            let mut msg = Message::new();
+            msg.set_id(id).set_message_type(MessageType::Response);
+            Ok(msg.to_vec().unwrap())
+        };
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+
+        // Responses with unknown ids, like dummy replies, are ignored
+        let mut responses = stream::iter(vec![response(47255), response(3), response(4)]);
+        let mut pending: HashSet<u16> = vec![3, 4].into_iter().collect();
+        rt.block_on(receive_responses(&mut responses, &mut pending))
+            .unwrap();
+        assert!(pending.is_empty());
+
+        // The connection closes before all queries are answered
+        let mut responses = stream::iter(vec![response(3)]);
+        let mut pending: HashSet<u16> = vec![3, 4].into_iter().collect();
+        assert!(rt
+            .block_on(receive_responses(&mut responses, &mut pending))
+            .is_err());
+        assert_eq!(vec![4], pending.into_iter().collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,327 @@
+#![deny(rust_2018_compatibility)]
+#![warn(rust_2018_idioms)]
+
+//! Minimal DNS over TLS / DNS over TCP resolver for testing
+//!
+//! The resolver answers all queries from a small zone file or with a canned answer.
+//! It never contacts any upstream server, which makes it suitable for integration tests of the proxy and replay tools.
+//! Artificial latency and padding of the responses can be configured on the command line.
+
+use byteorder::{BigEndian, WriteBytesExt};
+use futures::{future, StreamExt};
+use log::{debug, info};
+use openssl::{
+    pkey::PKey,
+    ssl::{SslAcceptor, SslMethod, SslVerifyMode},
+    x509::X509,
+};
+use rand::Rng;
+use std::{
+    collections::HashMap,
+    fs::read_to_string,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
+use structopt::StructOpt;
+use tlsproxy::{parse_duration_ms, print_error, DnsBytesStream, Error, SERVER_CERT, SERVER_KEY};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, TcpStream},
+    prelude::*,
+};
+use trust_dns_proto::{
+    op::{Message, MessageType, ResponseCode},
+    rr::{
+        rdata::opt::{EdnsCode, EdnsOption},
+        Name, RData, Record, RecordType,
+    },
+};
+
+/// TTL used for all generated records
+const TTL: u32 = 300;
+
+#[derive(Clone, Debug, StructOpt)]
+#[structopt(global_settings(&[
+    structopt::clap::AppSettings::ColoredHelp,
+    structopt::clap::AppSettings::VersionlessSubcommands
+]))]
+struct CliArgs {
+    /// Local DNS over TLS port
+    #[structopt(
+        short = "l",
+        long = "listen",
+        default_value = "127.0.0.1:1853",
+        parse(try_from_str)
+    )]
+    listen: SocketAddr,
+
+    /// Serve plain DNS over TCP instead of DNS over TLS
+    #[structopt(long = "tcp")]
+    tcp: bool,
+
+    /// Zone file with the records to serve
+    ///
+    /// Each line has the format `<name> <type> <value>`, e.g., `example.com. A 127.0.0.1`.
+    /// Supported types are A, AAAA, and CNAME.
+    /// Empty lines and lines starting with `#` are ignored.
+    #[structopt(short = "z", long = "zone", parse(from_os_str))]
+    zone: Option<PathBuf>,
+
+    /// Canned answer for all A and AAAA queries not covered by the zone file
+    ///
+    /// If unspecified, such queries are answered with NXDOMAIN.
+    #[structopt(long = "default-answer")]
+    default_answer: Option<IpAddr>,
+
+    /// Delay each response by this many ms
+    #[structopt(long = "delay", default_value = "0", parse(try_from_str = parse_duration_ms))]
+    delay: Duration,
+
+    /// Add a uniformly random delay of up to this many ms on top of `--delay`
+    #[structopt(long = "jitter", default_value = "0", parse(try_from_str = parse_duration_ms))]
+    jitter: Duration,
+
+    /// Pad all responses to a multiple of this many bytes using the EDNS(0) Padding option
+    ///
+    /// A value of 0 disables the padding.
+    #[structopt(long = "pad-block", default_value = "468")]
+    pad_block: usize,
+
+    /// Log all TLS keys into this file
+    #[structopt(long = "sslkeylogfile", env = "SSLKEYLOGFILE")]
+    sslkeylogfile: Option<PathBuf>,
+}
+
+/// Records served by the resolver, indexed by query name and type
+type Zone = HashMap<(Name, RecordType), Vec<RData>>;
+
+#[derive(Debug)]
+struct Config {
+    args: CliArgs,
+    zone: Zone,
+}
+
+fn main() -> Result<(), Error> {
+    // generic setup
+    let log_settings = "mock_resolver=debug,tlsproxy=debug";
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(log_settings))
+        .format_timestamp_nanos()
+        .init();
+    let args = CliArgs::from_args();
+    let zone = match &args.zone {
+        Some(path) => load_zone(path)?,
+        None => Zone::default(),
+    };
+    info!("Loaded {} record sets", zone.len());
+
+    let mut rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async_run(Config { args, zone }))
+}
+
+/// Parse the zone file format described in [`CliArgs::zone`]
+fn load_zone(path: &Path) -> Result<Zone, Error> {
+    let content = read_to_string(path)?;
+    let mut zone = Zone::default();
+    for (lineno, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let invalid = |reason: &str| {
+            Error::from(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("{}:{}: {}", path.display(), lineno + 1, reason),
+            ))
+        };
+        let parts: Vec<_> = line.split_whitespace().collect();
+        if parts.len() != 3 {
+            return Err(invalid("Expected the format `<name> <type> <value>`"));
+        }
+        let mut name = Name::from_ascii(parts[0])?;
+        name.set_fqdn(true);
+        let rtype = RecordType::from_str(parts[1])?;
+        let rdata = match rtype {
+            RecordType::A => RData::A(parts[2].parse()?),
+            RecordType::AAAA => RData::AAAA(parts[2].parse()?),
+            RecordType::CNAME => {
+                let mut target = Name::from_ascii(parts[2])?;
+                target.set_fqdn(true);
+                RData::CNAME(target)
+            }
+            _ => return Err(invalid("Unsupported record type")),
+        };
+        zone.entry((name, rtype)).or_default().push(rdata);
+    }
+    Ok(zone)
+}
+
+async fn async_run(config: Config) -> Result<(), Error> {
+    let mut socket = TcpListener::bind(&config.args.listen).await?;
+    println!(
+        "Listening on: {} ({})\n",
+        config.args.listen,
+        if config.args.tcp { "TCP" } else { "TLS" }
+    );
+
+    let mut acceptor = SslAcceptor::mozilla_intermediate(SslMethod::tls())?;
+    acceptor.set_verify(SslVerifyMode::NONE);
+    acceptor.set_certificate(X509::from_pem(SERVER_CERT)?.as_ref())?;
+    acceptor.set_private_key(PKey::private_key_from_pem(SERVER_KEY)?.as_ref())?;
+    if let Some(logfile) = &config.args.sslkeylogfile {
+        let cb = tlsproxy::keylog_to_file(logfile.clone());
+        acceptor.set_keylog_callback(cb);
+    }
+    let acceptor = acceptor.build();
+
+    let config = Arc::new(config);
+    let done = socket
+        .incoming()
+        // conver the Error to tlsproxy::Error
+        .map(|x| Ok(x?))
+        .for_each_concurrent(100, move |client| {
+            tokio::spawn(print_error(handle_client(
+                config.clone(),
+                client,
+                acceptor.clone(),
+            )));
+            future::ready(())
+        });
+    done.await;
+    Ok(())
+}
+
+async fn handle_client(
+    config: Arc<Config>,
+    client: Result<TcpStream, Error>,
+    acceptor: SslAcceptor,
+) -> Result<(), Error> {
+    let client = client?;
+    client.set_nodelay(true)?;
+    if config.args.tcp {
+        serve_connection(&*config, client).await
+    } else {
+        let client = tokio_openssl::accept(&acceptor, client).await?;
+        serve_connection(&*config, client).await
+    }
+}
+
+/// Answer all DNS queries arriving on `stream` until the client closes the connection
+async fn serve_connection<S>(config: &Config, stream: S) -> Result<(), Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut queries = DnsBytesStream::new(reader).expect_message_type(MessageType::Query);
+
+    let mut out = Vec::with_capacity(468 * 5);
+    while let Some(query) = queries.next().await {
+        let query = Message::from_vec(&query.map_err(|err| Error::from(err).client_side())?)?;
+        let response = build_response(config, &query)?;
+
+        let delay = config.args.delay
+            + if config.args.jitter > Duration::new(0, 0) {
+                rand::thread_rng().gen_range(Duration::new(0, 0)..=config.args.jitter)
+            } else {
+                Duration::new(0, 0)
+            };
+        tokio::time::delay_for(delay).await;
+
+        out.truncate(0);
+        WriteBytesExt::write_u16::<BigEndian>(&mut out, response.len() as u16)?;
+        out.extend_from_slice(&response);
+        info!(
+            "Answer {} after {:?} with {}B",
+            query
+                .queries()
+                .first()
+                .map(|q| q.name().to_string())
+                .unwrap_or_default(),
+            delay,
+            response.len()
+        );
+        writer.write_all(&out).await.map_err(Error::ClientIo)?;
+        writer.flush().await.map_err(Error::ClientIo)?;
+    }
+
+    writer.shutdown().await.map_err(Error::ClientIo)?;
+    Ok(())
+}
+
+/// Create the wire format response for `query` from the zone and the default answer
+fn build_response(config: &Config, query: &Message) -> Result<Vec<u8>, Error> {
+    let mut response = Message::new();
+    response
+        .set_id(query.id())
+        .set_message_type(MessageType::Response)
+        .set_op_code(query.op_code())
+        .set_recursion_desired(query.recursion_desired())
+        .set_recursion_available(true)
+        .add_queries(query.queries().iter().cloned());
+
+    let mut found = false;
+    for q in query.queries() {
+        let mut name = q.name().clone();
+        // Follow CNAME chains within the zone, but protect against loops
+        for _ in 0..8 {
+            if let Some(rdatas) = config.zone.get(&(name.clone(), q.query_type())) {
+                found = true;
+                for rdata in rdatas {
+                    response.add_answer(Record::from_rdata(name.clone(), TTL, rdata.clone()));
+                }
+                break;
+            }
+            if let Some(rdatas) = config.zone.get(&(name.clone(), RecordType::CNAME)) {
+                found = true;
+                response.add_answer(Record::from_rdata(name.clone(), TTL, rdatas[0].clone()));
+                if let RData::CNAME(target) = &rdatas[0] {
+                    name = target.clone();
+                    continue;
+                }
+            }
+
+            match (q.query_type(), config.args.default_answer) {
+                (RecordType::A, Some(IpAddr::V4(ip))) => {
+                    found = true;
+                    response.add_answer(Record::from_rdata(name.clone(), TTL, RData::A(ip)));
+                }
+                (RecordType::AAAA, Some(IpAddr::V6(ip))) => {
+                    found = true;
+                    response.add_answer(Record::from_rdata(name.clone(), TTL, RData::AAAA(ip)));
+                }
+                (RecordType::AAAA, Some(IpAddr::V4(ip))) => {
+                    found = true;
+                    response.add_answer(Record::from_rdata(
+                        name.clone(),
+                        TTL,
+                        RData::AAAA(ip.to_ipv6_mapped()),
+                    ));
+                }
+                _ => {}
+            }
+            break;
+        }
+    }
+    if !found {
+        response.set_response_code(ResponseCode::NXDomain);
+    }
+
+    if config.args.pad_block > 0 {
+        // Create the EDNS record first, such that its size is accounted for
+        response.edns_mut().set_max_payload(1232);
+        let len = response.to_vec()?.len();
+        // Each EDNS option has 4B of overhead for the code and length
+        let missing_padding =
+            (config.args.pad_block - (len + 4) % config.args.pad_block) % config.args.pad_block;
+        debug!("Add {}B of padding to {}B response", missing_padding, len);
+        response.edns_mut().options_mut().insert(EdnsOption::from((
+            EdnsCode::Padding,
+            &vec![0; missing_padding][..],
+        )));
+    }
+
+    Ok(response.to_vec()?)
+}
@@ -2,29 +2,37 @@
 #![warn(rust_2018_idioms)]
 
 use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
-use futures::{future, Stream, StreamExt};
-use log::info;
+use futures::{future, stream, Stream, StreamExt};
+use log::{error, info, warn};
 use openssl::{
     pkey::PKey,
     ssl::{SslAcceptor, SslConnector, SslMethod, SslOptions, SslVerifyMode, SslVersion},
     x509::X509,
 };
+use sequences::adaptive_padding::ApTraceWriter;
 use std::{
     net::SocketAddr,
-    path::PathBuf,
+    path::{Path, PathBuf},
     sync::{Arc, Mutex},
+    time::Duration,
 };
 use structopt::StructOpt;
 use tlsproxy::{
-    print_error, wrap_stream, DnsBytesStream, EnsurePadding, Error, HostnameSocketAddr, MyStream,
-    MyTcpStream, Payload, Strategy, TokioOpensslStream, Transport, SERVER_CERT, SERVER_KEY,
+    magic_query_response, parse_duration_ms, print_error, refresh_addr, wrap_stream,
+    AdaptivePaddingConfig, AddressHealth, CapturedStream, ClientStrategyRule, DnsBytesStream,
+    EnsurePadding, Error, HostnameSocketAddr, MyStream, MyTcpStream, Payload, PcapWriter,
+    RegisteredConnection, SessionRegistry, Strategy, StrategyConfig, StrategySelector,
+    TokioOpensslStream, TransferredBytes, Transport, UpstreamProxy, UpstreamTls,
+    MAGIC_QUERY_SUFFIX, SERVER_CERT, SERVER_KEY,
 };
 use tokio::{
     net::{TcpListener, TcpStream},
     prelude::*,
+    signal::unix::{signal, SignalKind},
+    task,
 };
 use trust_dns_proto::{
-    op::message::Message,
+    op::{message::Message, MessageType},
     serialize::binary::{BinEncodable, BinEncoder},
 };
 
@@ -64,7 +72,69 @@ const DUMMY_DNS_REPLY: [u8; 468] = [
 #[derive(Clone, Debug)]
 struct Config {
     args: CliArgs,
+    registry: Arc<SessionRegistry>,
+    ap_trace: Option<ApTraceWriter>,
+    pcap: Option<Arc<PcapWriter>>,
+    /// Unreachable addresses of the upstream server, kept across reloads
+    health: Arc<AddressHealth>,
+    /// Settings which are replaced when reloading the `--strategy-config` on SIGHUP
+    settings: Arc<Mutex<Settings>>,
+}
+
+/// The part of the [`Config`] which can be reloaded at runtime
+///
+/// Each connection takes a copy of the settings when it starts.
+/// Thus, a reload only affects new connections, while established connections are kept.
+#[derive(Clone, Debug)]
+struct Settings {
+    /// Upstream resolver, which is resolved again after the DNS records expired
+    server: Arc<Mutex<HostnameSocketAddr>>,
     transport: Transport,
+    /// Chooses the strategy per client, falling back to the strategy of the subcommand or `--strategy-config`
+    selector: StrategySelector,
+    /// Parameters of Adaptive Padding, shared by all clients
+    ap_config: AdaptivePaddingConfig,
+    /// The `[tls]` table of the `--strategy-config` takes precedence over the `--tls-*` arguments
+    upstream_tls: UpstreamTls,
+}
+
+impl Settings {
+    fn new(args: &CliArgs, strategy_config: &StrategyConfig) -> Result<Self, Error> {
+        let mut server = strategy_config
+            .server()?
+            .unwrap_or_else(|| args.server.clone())
+            .with_happy_eyeballs(args.happy_eyeballs);
+        if !resolves_remotely(args) {
+            server.resolve_again()?;
+        }
+        let transport = match (args.tcp, args.tls, server.port()) {
+            (true, false, _) => Transport::Tcp,
+            (false, true, _) => Transport::Tls,
+            (false, false, 53) => Transport::Tcp,
+            (false, false, 853) => Transport::Tls,
+            (false, false, port) => return Err(Error::TransportNotInferable(port)),
+
+            (true, true, _) => unreachable!(
+                "This case is already checked in Clap by having those flags be mutually exclusive."
+            ),
+        };
+        let selector = StrategySelector::new(
+            strategy_config.strategy()?,
+            args.client_strategies.clone(),
+            args.magic_query,
+        );
+
+        Ok(Self {
+            server: Arc::new(Mutex::new(server)),
+            transport,
+            selector,
+            ap_config: strategy_config.ap_config()?,
+            upstream_tls: strategy_config
+                .upstream_tls()?
+                .cloned()
+                .unwrap_or_else(|| args.upstream_tls.clone()),
+        })
+    }
 }
 
 #[derive(Clone, Debug, StructOpt)]
@@ -83,14 +153,47 @@ struct CliArgs {
     listen: SocketAddr,
 
     /// Remote DNS over TCP / DNS over TLS endpoint
+    ///
+    /// Either an IPv4 or IPv6 socket address or `hostname:port`.
+    /// All addresses of a hostname are used, failing over to the next one if an address is unreachable.
+    /// The `server` key of the `--strategy-config` takes precedence.
     #[structopt(
         short = "s",
         long = "server",
         default_value = "1.1.1.1:853",
-        parse(try_from_str)
+        parse(try_from_str = HostnameSocketAddr::unresolved)
     )]
     server: HostnameSocketAddr,
 
+    /// Order the resolved addresses of `server` like Happy Eyeballs, alternating between IPv6 and IPv4
+    #[structopt(long = "happy-eyeballs")]
+    happy_eyeballs: bool,
+
+    /// Abort connecting to an address of `server` after this many ms and fail over to the next address
+    #[structopt(
+        long = "connect-timeout",
+        value_name = "MS",
+        default_value = "5000",
+        parse(try_from_str = parse_duration_ms)
+    )]
+    connect_timeout: Duration,
+
+    /// Try unreachable addresses of `server` last for this many ms, doubling with each further failure
+    #[structopt(
+        long = "unhealthy-backoff",
+        value_name = "MS",
+        default_value = "30000",
+        parse(try_from_str = parse_duration_ms)
+    )]
+    unhealthy_backoff: Duration,
+
+    /// Connect to `server` through this SOCKS5 or HTTP CONNECT proxy
+    ///
+    /// The proxy is given as `scheme://[user:password@]host:port`.
+    /// Supported schemes are `socks5`, `socks5h` (the proxy resolves the hostname of `server`, e.g., for Tor), and `http`.
+    #[structopt(long = "upstream-proxy", value_name = "URL", parse(try_from_str))]
+    upstream_proxy: Option<UpstreamProxy>,
+
     /// Force the connection to use TCP. Conflicts with `--tls`.
     ///
     /// If unspecified infer transport from `server` port.
@@ -103,12 +206,69 @@ struct CliArgs {
     #[structopt(long = "tls", conflicts_with = "tcp")]
     tls: bool,
 
+    #[structopt(flatten)]
+    upstream_tls: UpstreamTls,
+
     /// Log all TLS keys into this file
     #[structopt(long = "sslkeylogfile", env = "SSLKEYLOGFILE")]
     sslkeylogfile: Option<PathBuf>,
 
+    /// Append a JSON line per finished connection, linking it to its TLS keys, timestamps, and metrics
+    #[structopt(long = "session-index", value_name = "FILE")]
+    session_index: Option<PathBuf>,
+
+    /// Use a different strategy for all clients from this network, given as `NET[/PREFIX]=STRATEGY`
+    ///
+    /// Strategies are written as `pass`, `constant-<ms>`, or `ap[-tin<ms>][-tout<ms>][-max<ms>]`.
+    /// The first matching network wins, all other clients use the strategy given as subcommand or with `--strategy-config`.
+    /// This flag can be given multiple times.
+    #[structopt(
+        long = "client-strategy",
+        value_name = "NET=STRATEGY",
+        number_of_values = 1
+    )]
+    client_strategies: Vec<ClientStrategyRule>,
+
+    /// Allow clients to select their strategy with an initial query for `<STRATEGY>.strategy.tlsproxy.invalid.`
+    ///
+    /// The query must be the first message on the connection and is answered by the proxy.
+    /// It overrides the strategy selected by `--client-strategy`.
+    #[structopt(long = "magic-query")]
+    magic_query: bool,
+
+    /// Close client connections which send DNS messages larger than this many bytes
+    #[structopt(
+        long = "max-message-size",
+        value_name = "BYTES",
+        default_value = "65535"
+    )]
+    max_message_size: usize,
+
+    /// Write the state transitions of Adaptive Padding as JSONL into this file
+    ///
+    /// The records are tagged with the client address and use the same format as the simulator in `sequences`.
+    #[structopt(long = "ap-trace", value_name = "FILE")]
+    ap_trace: Option<PathBuf>,
+
+    /// Record the connections to the clients, which carry the padded responses, into this pcap file
+    ///
+    /// The file contains the TLS records as seen on the wire and can be converted with `sequences::pcap`.
+    #[structopt(long = "pcap", value_name = "FILE")]
+    pcap: Option<PathBuf>,
+
+    /// Read the full strategy, including the parameters of Adaptive Padding, from this TOML file
+    ///
+    /// This replaces the subcommand.
+    /// The Adaptive Padding parameters also apply to the strategies selected per client.
+    /// The config is echoed into the session index.
+    ///
+    /// On SIGHUP the file is read again and new connections use the updated strategy and upstream server.
+    /// Established connections are not affected.
+    #[structopt(long = "strategy-config", value_name = "FILE")]
+    strategy_config: Option<PathBuf>,
+
     #[structopt(subcommand)]
-    strategy: Strategy,
+    strategy: Option<Strategy>,
 }
 
 fn main() -> Result<(), Error> {
@@ -117,27 +277,31 @@ fn main() -> Result<(), Error> {
     env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(log_settings))
         .format_timestamp_nanos()
         .init();
-    let mut config = Config {
-        args: CliArgs::from_args(),
-        // This value will be overwritten later
-        transport: Transport::Tcp,
+    let args = CliArgs::from_args();
+    let strategy_config =
+        StrategyConfig::from_args(args.strategy_config.as_deref(), args.strategy.as_ref())?;
+    let settings = Settings::new(&args, &strategy_config)?;
+    let registry = SessionRegistry::new(args.sslkeylogfile.clone(), args.session_index.clone())?;
+    registry.set_strategy_config(&strategy_config)?;
+    let ap_trace = args
+        .ap_trace
+        .as_deref()
+        .map(ApTraceWriter::create)
+        .transpose()?;
+    let pcap = args.pcap.as_deref().map(PcapWriter::create).transpose()?;
+    let health = AddressHealth::new(args.connect_timeout, args.unhealthy_backoff);
+    let config = Config {
+        args,
+        registry,
+        ap_trace,
+        pcap,
+        health: Arc::new(health),
+        settings: Arc::new(Mutex::new(settings)),
     };
     if let Some(file) = &config.args.sslkeylogfile {
         std::env::set_var("SSLKEYLOGFILE", file.to_path_buf());
     }
 
-    match (config.args.tcp, config.args.tls, config.args.server.port()) {
-        (true, false, _) => config.transport = Transport::Tcp,
-        (false, true, _) => config.transport = Transport::Tls,
-        (false, false, 53) => config.transport = Transport::Tcp,
-        (false, false, 853) => config.transport = Transport::Tls,
-        (false, false, port) => return Err(Error::TransportNotInferable(port)),
-
-        (true, true, _) => unreachable!(
-            "This case is already checked in Clap by having those flags be mutually exclusive."
-        ),
-    }
-
     let mut rt = tokio::runtime::Runtime::new().unwrap();
     rt.block_on(async_run(config))
 }
@@ -147,20 +311,33 @@ async fn async_run(config: Config) -> Result<(), Error> {
     let mut socket = TcpListener::bind(&config.args.listen).await?;
     println!(
         "Listening on: {}\nProxying to: {}\n",
-        config.args.listen, config.args.server
+        config.args.listen,
+        config.settings.lock().unwrap().server.lock().unwrap()
     );
+    if let Some(proxy) = &config.args.upstream_proxy {
+        println!("Upstream proxy: {}\n", proxy);
+    }
+    for rule in &config.args.client_strategies {
+        println!("Clients from {} use: {}", rule.network, rule.strategy);
+    }
+    if config.args.magic_query {
+        println!(
+            "Magic queries for *.{} select the strategy",
+            MAGIC_QUERY_SUFFIX
+        );
+    }
 
     let mut acceptor = SslAcceptor::mozilla_intermediate(SslMethod::tls())?;
     acceptor.set_verify(SslVerifyMode::NONE);
     acceptor.set_certificate(X509::from_pem(SERVER_CERT)?.as_ref())?;
     acceptor.set_private_key(PKey::private_key_from_pem(SERVER_KEY)?.as_ref())?;
-    if let Some(logfile) = &config.args.sslkeylogfile {
-        let cb = tlsproxy::keylog_to_file(logfile.clone());
-        acceptor.set_keylog_callback(cb);
+    if config.registry.is_enabled() {
+        acceptor.set_keylog_callback(config.registry.keylog_callback());
     }
     let acceptor = acceptor.build();
 
     let config = Arc::new(config);
+    tokio::spawn(print_error(reload_on_hangup(config.clone())));
     let done = socket
         .incoming()
         // conver the Error to tlsproxy::Error
@@ -177,18 +354,81 @@ async fn async_run(config: Config) -> Result<(), Error> {
     Ok(())
 }
 
+/// Read the `--strategy-config` again, whenever the process receives SIGHUP
+///
+/// If the new config is invalid, the previous settings stay in use.
+async fn reload_on_hangup(config: Arc<Config>) -> Result<(), Error> {
+    let mut hangup = signal(SignalKind::hangup())?;
+    while hangup.recv().await.is_some() {
+        let path = match &config.args.strategy_config {
+            Some(path) => path,
+            None => {
+                warn!("Ignoring SIGHUP, since the strategy is not read from a --strategy-config");
+                continue;
+            }
+        };
+
+        // Resolving the upstream server blocks, so it must not run on the runtime
+        let args = config.args.clone();
+        let config_path = path.clone();
+        let loaded = task::spawn_blocking(move || load_settings(&args, &config_path)).await;
+        match loaded {
+            Ok(Ok((strategy_config, settings))) => {
+                info!(
+                    "Reloaded '{}', proxying to {} with strategy {}",
+                    path.display(),
+                    settings.server.lock().unwrap(),
+                    settings.selector.default_strategy()
+                );
+                if let Err(err) = config.registry.set_strategy_config(&strategy_config) {
+                    error!(
+                        "Could not record the reloaded strategy in the session index: {}",
+                        err
+                    );
+                }
+                *config.settings.lock().unwrap() = settings;
+            }
+            Ok(Err(err)) => error!(
+                "Keeping the previous configuration, since reloading '{}' failed: {}",
+                path.display(),
+                err
+            ),
+            Err(err) => error!(
+                "Keeping the previous configuration, since reloading '{}' panicked: {}",
+                path.display(),
+                err
+            ),
+        }
+    }
+    Ok(())
+}
+
+/// Load the strategy config at `path` and derive the [`Settings`] from it
+fn load_settings(args: &CliArgs, path: &Path) -> Result<(StrategyConfig, Settings), Error> {
+    let strategy_config = StrategyConfig::load(path)?;
+    let settings = Settings::new(args, &strategy_config)?;
+    Ok((strategy_config, settings))
+}
+
 async fn handle_client(
     config: Arc<Config>,
     client: Result<TcpStream, Error>,
     acceptor: SslAcceptor,
 ) -> Result<(), Error> {
-    let client = client?;
+    let client = client.map_err(Error::client_side)?;
+    let client_addr = client.peer_addr().map_err(Error::ClientIo)?;
     // Setup TLS to client
-    client.set_nodelay(true)?;
+    client.set_nodelay(true).map_err(Error::ClientIo)?;
+    let local_addr = client.local_addr().map_err(Error::ClientIo)?;
+    let client = CapturedStream::new(client, config.pcap.clone(), local_addr, client_addr);
     let client = tokio_openssl::accept(&acceptor, client).await?;
+    let client_conn = config
+        .registry
+        .open_connection("client", client_addr, Some(client.ssl()));
 
-    let (server_reader, server_writer) =
-        connect_to_server(config.args.server.clone(), &*config).await?;
+    // Later reloads of the config do not affect this connection
+    let settings = config.settings.lock().unwrap().clone();
+    let mut strategy = settings.selector.for_client(client_addr.ip()).clone();
 
     // Create separate read/write handles for the TCP clients that we're
     // proxying data between. Note that typically you'd use
@@ -200,17 +440,64 @@ async fn handle_client(
     // As a result, we wrap up our client/server manually in arcs and
     // use the impls below on our custom `MyTcpStream` type.
     let client_reader = TokioOpensslStream::new(Arc::new(Mutex::new(client)));
-    let client_writer = client_reader.clone();
+    let mut client_writer = client_reader.clone();
+    let mut client_reader = EnsurePadding::new(
+        DnsBytesStream::new(client_reader)
+            .with_max_message_size(config.args.max_message_size)
+            .expect_message_type(MessageType::Query),
+    );
+
+    // A magic query is answered by the proxy, all other messages are forwarded to the server.
+    // Without magic queries, the upstream connection is opened before the client sends anything.
+    let first_message = if config.args.magic_query {
+        match client_reader
+            .next()
+            .await
+            .map(|msg| msg.map_err(Error::client_side))
+        {
+            Some(Ok(msg)) => match settings.selector.magic_query(&msg) {
+                Some(selected) => {
+                    match selected {
+                        Ok(selected) => strategy = selected,
+                        Err(err) => warn!("Ignoring magic query from {}: {}", client_addr, err),
+                    }
+                    write_message(&magic_query_response(&msg), &mut client_writer).await?;
+                    None
+                }
+                None => Some(Ok(msg)),
+            },
+            first_message => first_message,
+        }
+    } else {
+        None
+    };
+    let client_reader = stream::iter(first_message).chain(client_reader);
+    info!("Client {} uses strategy {}", client_addr, strategy);
+    config.registry.set_strategy(client_conn.id(), &strategy);
+
+    let server_addr = if resolves_remotely(&config.args) {
+        settings.server.lock().unwrap().clone()
+    } else {
+        refresh_addr(&settings.server).await
+    };
+    let (server_reader, server_writer, server_conn) =
+        connect_to_server(server_addr, &settings, &*config)
+            .await
+            .map_err(Error::upstream_side)?;
 
     // Copy the data (in parallel) between the client and the server.
     // After the copy is done we indicate to the remote side that we've
     // finished by shutting down the connection.
-    let client_reader = DnsBytesStream::new(client_reader);
-    let client_reader = EnsurePadding::new(client_reader);
     let client_to_server = copy_client_to_server(client_reader, server_writer);
 
-    let server_reader = DnsBytesStream::new(server_reader).map(|x| Ok(x?));
-    let server_reader = wrap_stream(server_reader, &config.args.strategy);
+    let server_reader = DnsBytesStream::new(server_reader)
+        .expect_message_type(MessageType::Response)
+        .map(|x| x.map_err(|err| Error::from(err).upstream_side()));
+    let ap_trace = config
+        .ap_trace
+        .as_ref()
+        .map(|trace| trace.with_id(client_addr.to_string()));
+    let server_reader = wrap_stream(server_reader, &strategy, settings.ap_config, ap_trace);
     let server_to_client = copy_server_to_client(server_reader, client_writer);
 
     let (from_client, from_server) = future::join(client_to_server, server_to_client).await;
@@ -220,6 +507,15 @@ async fn handle_client(
         "client wrote {} bytes and received {} bytes",
         from_client, from_server
     );
+    client_conn.finish(TransferredBytes {
+        sent: from_server,
+        received: from_client,
+    })?;
+    // The bytes received from the server are not tracked, since the strategy might drop or add messages
+    server_conn.finish(TransferredBytes {
+        sent: from_client,
+        received: 0,
+    })?;
 
     Ok(())
 }
@@ -233,7 +529,7 @@ where
 
     let mut out = Vec::with_capacity(128 * 5);
     while let Some(dns) = client.next().await {
-        let dns = dns?;
+        let dns = dns.map_err(Error::client_side)?;
 
         out.truncate(0);
         // write placeholder length, replaced later
@@ -250,18 +546,32 @@ where
 
         // Add 2 for the length of the length header
         total_bytes += out.len() as u64;
-        server.write_all(&out).await?;
-        server.flush().await?;
+        server.write_all(&out).await.map_err(Error::UpstreamIo)?;
+        server.flush().await.map_err(Error::UpstreamIo)?;
     }
 
     // We need to pass the shutdown from client to server, that the server sees that the client shut
     // down the connection. Automatic shutdown does not work in this case, as the reading and
     // writing part access the same underlying TcpStream, thus the drop based shutdown would be too
     // late.
-    server.shutdown().await?;
+    server.shutdown().await.map_err(Error::UpstreamIo)?;
     Ok(total_bytes)
 }
 
+/// Write a single DNS message with its length header to `client`
+async fn write_message<W>(msg: &Message, client: &mut W) -> Result<(), Error>
+where
+    W: AsyncWrite + Unpin,
+{
+    let dns = msg.to_bytes()?;
+    let mut out = Vec::with_capacity(dns.len() + 2);
+    WriteBytesExt::write_u16::<BigEndian>(&mut out, dns.len() as u16)?;
+    out.extend_from_slice(&dns);
+    client.write_all(&out).await.map_err(Error::ClientIo)?;
+    client.flush().await.map_err(Error::ClientIo)?;
+    Ok(())
+}
+
 async fn copy_server_to_client<R, W>(mut server: R, mut client: W) -> Result<u64, Error>
 where
     R: Stream<Item = Payload<Result<Vec<u8>, Error>>> + Send + Unpin,
@@ -289,48 +599,124 @@ where
 
         // Add 2 for the length of the length header
         total_bytes += out.len() as u64;
-        client.write_all(&out).await?;
-        client.flush().await?;
+        client.write_all(&out).await.map_err(Error::ClientIo)?;
+        client.flush().await.map_err(Error::ClientIo)?;
     }
 
     // We need to pass the shutdown from client to server, that the server sees that the client shut
     // down the connection. Automatic shutdown does not work in this case, as the reading and
     // writing part access the same underlying TcpStream, thus the drop based shutdown would be too
     // late.
-    client.shutdown().await?;
+    client.shutdown().await.map_err(Error::ClientIo)?;
     Ok(total_bytes)
 }
 
+/// Returns `true` if the `--upstream-proxy` resolves the hostname of `server`
+///
+/// The hostname is then never resolved locally.
+fn resolves_remotely(args: &CliArgs) -> bool {
+    args.upstream_proxy
+        .as_ref()
+        .map_or(false, UpstreamProxy::resolves_remotely)
+}
+
 #[allow(clippy::needless_lifetimes)]
 async fn connect_to_server(
     server_addr: HostnameSocketAddr,
+    settings: &Settings,
     config: &Config,
-) -> Result<(impl AsyncRead, impl AsyncWrite), Error> {
+) -> Result<(impl AsyncRead, impl AsyncWrite, RegisteredConnection), Error> {
     // Open a tcp connection. This is always needed
-    let server_socket_addr = server_addr.socket_addr();
-    let server = TcpStream::connect(&server_socket_addr).await?;
+    // With a proxy, the TCP connection and `server_socket_addr` are to the proxy
+    let (server, server_socket_addr) = match &config.args.upstream_proxy {
+        Some(proxy) => proxy.connect(&server_addr).await?,
+        None => server_addr.connect_with_health(&config.health).await?,
+    };
     server.set_nodelay(true)?;
 
-    let server: MyStream<_> = match config.transport {
-        Transport::Tcp => MyTcpStream::new(Arc::new(Mutex::new(server))).into(),
+    let (server, conn): (MyStream<_>, _) = match settings.transport {
+        Transport::Tcp => {
+            let conn = config
+                .registry
+                .open_connection("server", server_socket_addr, None);
+            (MyTcpStream::new(Arc::new(Mutex::new(server))).into(), conn)
+        }
 
         Transport::Tls => {
             let mut connector = SslConnector::builder(SslMethod::tls())?;
             connector.set_min_proto_version(Some(SslVersion::TLS1_2))?;
             connector.set_options(SslOptions::NO_COMPRESSION);
-            if let Some(logfile) = &config.args.sslkeylogfile {
-                let cb = tlsproxy::keylog_to_file(logfile.clone());
-                connector.set_keylog_callback(cb);
+            settings.upstream_tls.configure_builder(&mut connector)?;
+            if config.registry.is_enabled() {
+                connector.set_keylog_callback(config.registry.keylog_callback());
             }
             let connector = connector.build();
-            let connector_config = connector.configure()?;
-            let hostname = server_addr.hostname();
+            let (connector_config, hostname) = settings
+                .upstream_tls
+                .configure_connection(&connector, &server_addr.hostname())?;
             let server = tokio_openssl::connect(connector_config, &hostname, server).await?;
-
-            TokioOpensslStream::new(Arc::new(Mutex::new(server))).into()
+            let conn =
+                config
+                    .registry
+                    .open_connection("server", server_socket_addr, Some(server.ssl()));
+
+            (
+                TokioOpensslStream::new(Arc::new(Mutex::new(server))).into(),
+                conn,
+            )
         }
     };
 
     let server_writer = server.clone();
-    Ok((server, server_writer))
+    Ok((server, server_writer, conn))
+}
+
+#[test]
+fn test_load_settings() {
+    let dir = std::env::temp_dir().join(format!("tlsproxy-server-settings-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("strategy.toml");
+    let args = CliArgs::from_iter(&["server", "--strategy-config", &path.to_string_lossy()]);
+
+    // The server of the config takes precedence and determines the transport
+    std::fs::write(
+        &path,
+        "strategy = \"constant\"\nrate = 50\nserver = \"127.0.0.1:53\"\n",
+    )
+    .unwrap();
+    let (strategy_config, settings) = load_settings(&args, &path).unwrap();
+    assert_eq!(
+        "constant-50",
+        strategy_config.strategy().unwrap().to_string()
+    );
+    assert_eq!(
+        "constant-50",
+        settings.selector.default_strategy().to_string()
+    );
+    let server = settings.server.lock().unwrap().clone();
+    assert_eq!(53, server.port());
+    assert!(server.to_string().starts_with("127.0.0.1"));
+    assert_eq!(Transport::Tcp, settings.transport);
+    assert_eq!(UpstreamTls::default(), settings.upstream_tls);
+
+    // Without a server in the config, the `--server` argument is used
+    std::fs::write(&path, "strategy = \"pass\"\n").unwrap();
+    let (_, settings) = load_settings(&args, &path).unwrap();
+    assert_eq!("pass", settings.selector.default_strategy().to_string());
+    assert_eq!(853, settings.server.lock().unwrap().port());
+    assert_eq!(Transport::Tls, settings.transport);
+
+    // Invalid configs are rejected, such that the previous settings stay in use
+    // The TLS options of the config replace the `--tls-*` arguments
+    std::fs::write(&path, "strategy = \"pass\"\n[tls]\nsni = \"dns.example\"\n").unwrap();
+    let (_, settings) = load_settings(&args, &path).unwrap();
+    assert_eq!(Some("dns.example"), settings.upstream_tls.sni.as_deref());
+
+    std::fs::write(&path, "strategy = \"unknown\"\n").unwrap();
+    assert!(load_settings(&args, &path).is_err());
+    std::fs::write(&path, "strategy = \"pass\"\nserver = \"127.0.0.1:5353\"\n").unwrap();
+    assert!(load_settings(&args, &path).is_err());
+    assert!(load_settings(&args, &dir.join("missing.toml")).is_err());
+
+    std::fs::remove_dir_all(&dir).unwrap();
 }
@@ -0,0 +1,296 @@
+//! Time source of the padding strategies
+//!
+//! [`ConstantRate`](crate::ConstantRate), [`AdaptivePadding`](crate::AdaptivePadding), and [`Throttle`](crate::throttle::Throttle) read the current time and wait for deadlines only through a [`Clock`].
+//! The proxy uses the [`RealClock`], which is backed by the tokio timer.
+//! A [`VirtualClock`] only advances when told so, which runs the same code paths in simulated time.
+//! This allows deterministic tests and processing recorded traces faster than real time.
+
+use futures::{task::noop_waker, Stream, StreamExt};
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+use tokio::time::{self, Delay, Instant};
+
+/// Source of the current time and of timers firing at a deadline
+pub trait Clock: Clone + Debug + Send + Sync + Unpin + 'static {
+    /// Timer type of this clock
+    type Timer: Timer;
+
+    /// The current time
+    fn now(&self) -> Instant;
+
+    /// Create a timer, which fires at `deadline`
+    fn timer_at(&self, deadline: Instant) -> Self::Timer;
+
+    /// Create a timer, which fires after `duration` elapsed
+    fn timer_for(&self, duration: Duration) -> Self::Timer {
+        self.timer_at(self.now() + duration)
+    }
+}
+
+/// Future which completes once its deadline is reached
+///
+/// In contrast to a normal future, the timer can be polled again after it completed.
+/// It then stays completed until [`Timer::reset`] moves the deadline into the future.
+pub trait Timer: Future<Output = ()> + Debug + Send + Unpin {
+    /// The time at which the timer fires
+    fn deadline(&self) -> Instant;
+
+    /// Change the deadline of the timer
+    fn reset(&mut self, deadline: Instant);
+}
+
+/// Wall clock time as provided by the tokio timer
+///
+/// Timers can only be created within a tokio runtime with the timer enabled.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct RealClock;
+
+impl Clock for RealClock {
+    type Timer = Delay;
+
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn timer_at(&self, deadline: Instant) -> Self::Timer {
+        time::delay_until(deadline)
+    }
+}
+
+impl Timer for Delay {
+    fn deadline(&self) -> Instant {
+        Delay::deadline(self)
+    }
+
+    fn reset(&mut self, deadline: Instant) {
+        Delay::reset(self, deadline)
+    }
+}
+
+/// Simulated time, which only advances by calling [`VirtualClock::advance`]
+///
+/// All clones share the same time.
+/// Advancing the clock wakes all tasks waiting for timers, whose deadline has passed.
+/// The clock does not need a tokio runtime.
+#[derive(Clone, Debug)]
+pub struct VirtualClock {
+    inner: Arc<Mutex<VirtualClockInner>>,
+}
+
+#[derive(Debug)]
+struct VirtualClockInner {
+    now: Instant,
+    /// Id of the next timer to create
+    next_id: u64,
+    /// Timers which returned pending, with their deadline and the task to wake
+    waiting: HashMap<u64, (Instant, Waker)>,
+}
+
+impl VirtualClock {
+    /// Create a new clock starting at `start`
+    pub fn new(start: Instant) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(VirtualClockInner {
+                now: start,
+                next_id: 0,
+                waiting: HashMap::new(),
+            })),
+        }
+    }
+
+    /// Move the time forward by `duration`
+    pub fn advance(&self, duration: Duration) {
+        let now = self.now() + duration;
+        self.advance_to(now);
+    }
+
+    /// Move the time forward to `time`
+    ///
+    /// Times in the past leave the clock unchanged.
+    pub fn advance_to(&self, time: Instant) {
+        let expired: Vec<Waker> = {
+            let mut inner = self.inner.lock().unwrap();
+            inner.now = inner.now.max(time);
+            let now = inner.now;
+            let expired_ids: Vec<u64> = inner
+                .waiting
+                .iter()
+                .filter(|(_, (deadline, _))| *deadline <= now)
+                .map(|(id, _)| *id)
+                .collect();
+            expired_ids
+                .into_iter()
+                .filter_map(|id| inner.waiting.remove(&id))
+                .map(|(_, waker)| waker)
+                .collect()
+        };
+        // Wake the tasks without holding the lock, as they might poll the timers immediately
+        expired.into_iter().for_each(Waker::wake);
+    }
+
+    /// The earliest deadline of all timers, which some task is waiting for
+    ///
+    /// Advancing the clock to this deadline is the smallest step which makes progress.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        let inner = self.inner.lock().unwrap();
+        inner.waiting.values().map(|(deadline, _)| *deadline).min()
+    }
+
+    /// Drive `stream` to completion and return each item with the time it was emitted
+    ///
+    /// Whenever the stream is pending, the clock jumps to the next deadline.
+    /// This runs as fast as the stream can be polled, independent of the simulated durations.
+    ///
+    /// # Panics
+    ///
+    /// The function panics, if the stream is pending without waiting for a timer of this clock, since the stream could never make progress.
+    pub fn simulate<S>(&self, mut stream: S) -> Vec<(Instant, S::Item)>
+    where
+        S: Stream + Unpin,
+    {
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut res = Vec::new();
+        loop {
+            match stream.poll_next_unpin(&mut cx) {
+                Poll::Ready(Some(item)) => res.push((self.now(), item)),
+                Poll::Ready(None) => return res,
+                Poll::Pending => {
+                    let deadline = self
+                        .next_deadline()
+                        .expect("The stream is pending, but does not wait for any timer");
+                    self.advance_to(deadline);
+                }
+            }
+        }
+    }
+}
+
+impl Clock for VirtualClock {
+    type Timer = VirtualTimer;
+
+    fn now(&self) -> Instant {
+        self.inner.lock().unwrap().now
+    }
+
+    fn timer_at(&self, deadline: Instant) -> Self::Timer {
+        let mut inner = self.inner.lock().unwrap();
+        let id = inner.next_id;
+        inner.next_id += 1;
+        VirtualTimer {
+            clock: self.clone(),
+            id,
+            deadline,
+        }
+    }
+}
+
+/// Timer of a [`VirtualClock`]
+#[derive(Debug)]
+pub struct VirtualTimer {
+    clock: VirtualClock,
+    id: u64,
+    deadline: Instant,
+}
+
+impl Future for VirtualTimer {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut inner = self.clock.inner.lock().unwrap();
+        if inner.now >= self.deadline {
+            inner.waiting.remove(&self.id);
+            Poll::Ready(())
+        } else {
+            inner
+                .waiting
+                .insert(self.id, (self.deadline, cx.waker().clone()));
+            Poll::Pending
+        }
+    }
+}
+
+impl Timer for VirtualTimer {
+    fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    fn reset(&mut self, deadline: Instant) {
+        self.deadline = deadline;
+        // The task has to poll the timer again to wait for the new deadline
+        self.clock.inner.lock().unwrap().waiting.remove(&self.id);
+    }
+}
+
+impl Drop for VirtualTimer {
+    fn drop(&mut self) {
+        if let Ok(mut inner) = self.clock.inner.lock() {
+            inner.waiting.remove(&self.id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{
+        task::{noop_waker, ArcWake},
+        FutureExt,
+    };
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[derive(Default)]
+    struct CountWakes(AtomicUsize);
+
+    impl ArcWake for CountWakes {
+        fn wake_by_ref(arc_self: &Arc<Self>) {
+            arc_self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn test_virtual_clock() {
+        let start = Instant::now();
+        let clock = VirtualClock::new(start);
+        let wakes = Arc::new(CountWakes::default());
+        let waker = futures::task::waker(wakes.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        let mut timer = clock.timer_for(Duration::from_millis(10));
+        assert_eq!(timer.poll_unpin(&mut cx), Poll::Pending);
+        assert_eq!(
+            clock.next_deadline(),
+            Some(start + Duration::from_millis(10))
+        );
+
+        clock.advance(Duration::from_millis(9));
+        assert_eq!(wakes.0.load(Ordering::SeqCst), 0);
+        clock.advance(Duration::from_millis(1));
+        assert_eq!(wakes.0.load(Ordering::SeqCst), 1);
+        assert_eq!(clock.now(), start + Duration::from_millis(10));
+        assert_eq!(timer.poll_unpin(&mut cx), Poll::Ready(()));
+        assert_eq!(clock.next_deadline(), None);
+
+        // Resetting the timer moves the deadline and the clock never runs backwards
+        timer.reset(start + Duration::from_millis(20));
+        assert_eq!(timer.poll_unpin(&mut cx), Poll::Pending);
+        clock.advance_to(start);
+        assert_eq!(clock.now(), start + Duration::from_millis(10));
+        drop(timer);
+        assert_eq!(clock.next_deadline(), None);
+
+        let mut timer = clock.timer_at(start);
+        let noop = noop_waker();
+        assert_eq!(
+            timer.poll_unpin(&mut Context::from_waker(&noop)),
+            Poll::Ready(())
+        );
+    }
+}
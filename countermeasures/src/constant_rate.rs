@@ -1,56 +1,76 @@
-use crate::Payload;
-use futures::Stream;
+use crate::{
+    clock::{Clock, RealClock, Timer},
+    Payload,
+};
+use futures::{ready, FutureExt, Stream};
 use std::{
     pin::Pin,
     task::{Context, Poll},
     time::Duration,
 };
-use tokio::{time, time::Interval};
 
-pub struct ConstantRate<S, T>
-where
-    S: Stream<Item = T> + Unpin,
-{
-    interval: Interval,
+/// Emit exactly one item every `interval`
+///
+/// The first item is emitted immediately, all following ones each `interval` later.
+/// At each tick, the next item of the underlying stream is forwarded as [`Payload::Payload`], if it is ready.
+/// Otherwise, a [`Payload::Dummy`] is emitted in its place.
+/// Items which become ready between ticks are delayed until the next tick, which means at most one payload item is forwarded per tick.
+///
+/// The stream ends at the first tick after the underlying stream ended.
+pub struct ConstantRate<S, C: Clock = RealClock> {
+    /// Fires at the next tick
+    tick: C::Timer,
+    interval: Duration,
     stream: S,
 }
 
-impl<S, T> ConstantRate<S, T>
+impl<S> ConstantRate<S>
 where
-    S: Stream<Item = T> + Unpin,
+    S: Stream + Unpin,
 {
     pub fn new(stream: S, interval: Duration) -> Self {
+        Self::with_clock(stream, interval, RealClock)
+    }
+}
+
+impl<S, C> ConstantRate<S, C>
+where
+    S: Stream + Unpin,
+    C: Clock,
+{
+    /// Emit the items at the times of `clock`
+    pub fn with_clock(stream: S, interval: Duration, clock: C) -> Self {
         Self {
-            interval: time::interval(interval),
+            tick: clock.timer_at(clock.now()),
+            interval,
             stream,
         }
     }
 }
 
-impl<S, T> Stream for ConstantRate<S, T>
+impl<S, C> Stream for ConstantRate<S, C>
 where
-    S: Stream<Item = T> + Unpin,
+    S: Stream + Unpin,
+    C: Clock,
 {
-    type Item = Payload<T>;
+    type Item = Payload<S::Item>;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
         let this = &mut *self;
 
-        match Pin::new(&mut this.interval).poll_next(cx) {
-            Poll::Ready(Some(_)) => {
-                // Time to send a new packet
-                match Pin::new(&mut this.stream).poll_next(cx) {
-                    Poll::Ready(Some(t)) => Poll::Ready(Some(Payload::Payload(t))),
-                    Poll::Ready(None) => Poll::Ready(None),
-                    Poll::Pending => {
-                        // No packet to send, send dummy
-                        Poll::Ready(Some(Payload::Dummy))
-                    }
-                }
+        ready!(this.tick.poll_unpin(cx));
+        // Schedule the ticks relative to the previous one, such that processing delays do not accumulate
+        let next_tick = this.tick.deadline() + this.interval;
+        this.tick.reset(next_tick);
+
+        // Time to send a new packet
+        match Pin::new(&mut this.stream).poll_next(cx) {
+            Poll::Ready(Some(t)) => Poll::Ready(Some(Payload::Payload(t))),
+            Poll::Ready(None) => Poll::Ready(None),
+            Poll::Pending => {
+                // No packet to send, send dummy
+                Poll::Ready(Some(Payload::Dummy))
             }
-            // The timer instance is done, this should never happen
-            Poll::Ready(None) => panic!("Timer instance is done. This should never happen."),
-            Poll::Pending => Poll::Pending,
         }
     }
 }
@@ -1,12 +1,56 @@
 use byteorder::{BigEndian, ByteOrder};
 use futures::Stream;
-use log::{debug, trace};
+use log::{debug, trace, warn};
 use std::{
     io,
     pin::Pin,
     task::{Context, Poll},
 };
 use tokio::prelude::*;
+use trust_dns_proto::op::MessageType;
+
+/// Size of the fixed DNS header
+const HEADER_SIZE: usize = 12;
+/// Minimal size of a question, a root label plus type and class
+const MIN_QUESTION_SIZE: usize = 5;
+/// Minimal size of a resource record, a root label plus type, class, TTL, and rdata length
+const MIN_RECORD_SIZE: usize = 11;
+
+/// Errors for malformed DNS over TCP frames
+///
+/// A [`DnsBytesStream`] ends after returning any of these errors, since the framing cannot be recovered reliably.
+#[derive(Debug, thiserror::Error)]
+pub enum DnsFrameError {
+    /// Reading from the underlying reader failed
+    #[error("Reading the DNS message failed: {}", _0)]
+    Io(#[source] io::Error),
+    /// The connection was closed in the middle of a message
+    #[error("Connection closed while {} bytes of a DNS message are missing", _0)]
+    Truncated(usize),
+    /// The length prefix exceeds the configured maximum
+    #[error(
+        "DNS message of {} bytes exceeds the maximum size of {} bytes",
+        len,
+        max
+    )]
+    TooLarge { len: usize, max: usize },
+    /// The message is shorter than the data announced in the DNS header
+    #[error(
+        "DNS message of {} bytes is too short, the header requires at least {} bytes",
+        len,
+        min
+    )]
+    TooShort { len: usize, min: usize },
+    /// The QR bit does not match the expected direction
+    #[error("Expected a DNS {:?}, but got a {:?}", expected, found)]
+    UnexpectedMessageType {
+        expected: MessageType,
+        found: MessageType,
+    },
+    /// The header uses a reserved opcode
+    #[error("DNS message uses the reserved opcode {}", _0)]
+    ReservedOpCode(u8),
+}
 
 /// Defines what element the stream is expecting to read next
 #[derive(Debug)]
@@ -18,6 +62,9 @@ enum DnsBytesReadState {
 }
 
 /// Stream which reads a DNS over TCP style communication.
+///
+/// Each message is checked for a plausible DNS header before it is returned.
+/// On the first malformed frame, the stream returns a [`DnsFrameError`] and ends, such that the connection can be torn down.
 pub struct DnsBytesStream<R>
 where
     R: Unpin,
@@ -32,6 +79,12 @@ where
     expected_bytes: usize,
     /// What to read next.
     read_state: DnsBytesReadState,
+    /// Messages with a larger length prefix are rejected
+    max_message_size: usize,
+    /// Reject messages where the QR bit does not match
+    message_type: Option<MessageType>,
+    /// Set after an error, all further polls return `None`
+    failed: bool,
 }
 
 impl<R> DnsBytesStream<R>
@@ -45,7 +98,71 @@ where
             buf: Vec::with_capacity(u16::max_value() as usize),
             expected_bytes: 2,
             read_state: DnsBytesReadState::Length,
+            max_message_size: usize::from(u16::MAX),
+            message_type: None,
+            failed: false,
+        }
+    }
+
+    /// Reject all messages larger than `max_message_size` bytes
+    ///
+    /// The length prefix is checked before the message is read, such that oversized messages are never buffered.
+    pub fn with_max_message_size(mut self, max_message_size: usize) -> Self {
+        self.max_message_size = max_message_size;
+        self
+    }
+
+    /// Only accept messages of type `message_type`, i.e., only queries or only responses
+    pub fn expect_message_type(mut self, message_type: MessageType) -> Self {
+        self.message_type = Some(message_type);
+        self
+    }
+
+    /// Check that `msg` starts with a plausible DNS header
+    fn validate(&self, msg: &[u8]) -> Result<(), DnsFrameError> {
+        if msg.len() < HEADER_SIZE {
+            return Err(DnsFrameError::TooShort {
+                len: msg.len(),
+                min: HEADER_SIZE,
+            });
         }
+
+        let found = if msg[2] & 0x80 == 0 {
+            MessageType::Query
+        } else {
+            MessageType::Response
+        };
+        if let Some(expected) = self.message_type {
+            if expected != found {
+                return Err(DnsFrameError::UnexpectedMessageType { expected, found });
+            }
+        }
+
+        // Only QUERY (0), IQUERY (1), STATUS (2), NOTIFY (4), UPDATE (5), and DSO (6) are assigned
+        let opcode = (msg[2] >> 3) & 0x0f;
+        if opcode == 3 || opcode > 6 {
+            return Err(DnsFrameError::ReservedOpCode(opcode));
+        }
+
+        let count = |offset: usize| BigEndian::read_u16(&msg[offset..offset + 2]) as usize;
+        let questions = count(4);
+        let records = count(6) + count(8) + count(10);
+        let min = HEADER_SIZE + questions * MIN_QUESTION_SIZE + records * MIN_RECORD_SIZE;
+        if msg.len() < min {
+            return Err(DnsFrameError::TooShort {
+                len: msg.len(),
+                min,
+            });
+        }
+        Ok(())
+    }
+
+    /// Return `err` and end the stream
+    fn fail(&mut self, err: DnsFrameError) -> Poll<Option<Result<Vec<u8>, DnsFrameError>>> {
+        warn!("Closing DNS stream: {}", err);
+        self.failed = true;
+        self.buf.clear();
+        Poll::Ready(Some(Err(err)))
     }
 }
 
@@ -54,13 +171,16 @@ where
     R: AsyncRead + Unpin,
 {
     // The same as our future above:
-    type Item = Result<Vec<u8>, io::Error>;
+    type Item = Result<Vec<u8>, DnsFrameError>;
 
     // poll is very similar to our Future implementation, except that
     // it returns an `Option<u8>` instead of a `u8`. This is so that the
     // Stream can signal that it's finished by returning `None`:
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
         let this = &mut *self;
+        if this.failed {
+            return Poll::Ready(None);
+        }
         debug!(
             "Read {} bytes, expects {} bytes, missing {} bytes",
             this.buf.len(),
@@ -75,10 +195,17 @@ where
                     // we should assume that it has got to the end, so we signal that
                     // the Stream is done in this case by returning None:
                     if n == 0 {
+                        // A message was started, but is incomplete
+                        if !this.buf.is_empty()
+                            || matches!(this.read_state, DnsBytesReadState::DnsMessage)
+                        {
+                            let missing = this.expected_bytes - this.buf.len();
+                            return this.fail(DnsFrameError::Truncated(missing));
+                        }
                         return Poll::Ready(None);
                     }
                 }
-                Poll::Ready(Err(err)) => return Poll::Ready(Some(Err(err))),
+                Poll::Ready(Err(err)) => return this.fail(DnsFrameError::Io(err)),
                 Poll::Pending => return Poll::Pending,
             }
         }
@@ -91,6 +218,12 @@ where
                     // remove the bytes
                     this.buf.drain(0..this.expected_bytes);
                     trace!("Read length field: {}", len);
+                    if len > this.max_message_size {
+                        return this.fail(DnsFrameError::TooLarge {
+                            len,
+                            max: this.max_message_size,
+                        });
+                    }
 
                     // init next state
                     this.expected_bytes = len;
@@ -103,6 +236,9 @@ where
                     // remove the bytes
                     this.buf.drain(0..this.expected_bytes);
                     trace!("Read DNS message of {} bytes", ret.len());
+                    if let Err(err) = this.validate(&ret) {
+                        return this.fail(err);
+                    }
 
                     // init next state
                     this.expected_bytes = 2;
@@ -115,3 +251,102 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+
+    /// Query for `example.com. A` with the given header flags
+    fn query(flags: u16) -> Vec<u8> {
+        let mut msg = vec![0x12, 0x34];
+        msg.extend_from_slice(&flags.to_be_bytes());
+        msg.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+        msg.extend_from_slice(b"\x07example\x03com\x00\x00\x01\x00\x01");
+        msg
+    }
+
+    /// Prepend the length header to each message
+    fn frames(messages: &[&[u8]]) -> Vec<u8> {
+        let mut out = Vec::new();
+        for msg in messages {
+            out.extend_from_slice(&(msg.len() as u16).to_be_bytes());
+            out.extend_from_slice(msg);
+        }
+        out
+    }
+
+    fn read_all(
+        input: &[u8],
+        f: impl FnOnce(DnsBytesStream<&[u8]>) -> DnsBytesStream<&[u8]>,
+    ) -> Vec<Result<Vec<u8>, DnsFrameError>> {
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        rt.block_on(f(DnsBytesStream::new(input)).collect())
+    }
+
+    #[test]
+    fn test_read_valid_messages() {
+        let q = query(0x0100);
+        let res = read_all(&frames(&[&q, &q]), |s| {
+            s.expect_message_type(MessageType::Query)
+        });
+        assert_eq!(res.len(), 2);
+        assert!(res.iter().all(|msg| msg.as_ref().unwrap() == &q));
+    }
+
+    #[test]
+    fn test_reject_oversized_message() {
+        let q = query(0x0100);
+        let res = read_all(&frames(&[&q, &q]), |s| s.with_max_message_size(20));
+        assert_eq!(res.len(), 1);
+        assert!(matches!(
+            res[0],
+            Err(DnsFrameError::TooLarge { len: 29, max: 20 })
+        ));
+    }
+
+    #[test]
+    fn test_reject_truncated_message() {
+        let q = query(0x0100);
+        let mut input = frames(&[&q]);
+        input.truncate(input.len() - 4);
+        let res = read_all(&input, |s| s);
+        assert_eq!(res.len(), 1);
+        assert!(matches!(res[0], Err(DnsFrameError::Truncated(4))));
+    }
+
+    #[test]
+    fn test_reject_malformed_header() {
+        let q = query(0x0100);
+        // Response instead of a query
+        let res = read_all(&frames(&[&query(0x8180), &q]), |s| {
+            s.expect_message_type(MessageType::Query)
+        });
+        assert_eq!(res.len(), 1);
+        assert!(matches!(
+            res[0],
+            Err(DnsFrameError::UnexpectedMessageType { .. })
+        ));
+
+        // Reserved opcode 3
+        let res = read_all(&frames(&[&query(0x1900)]), |s| s);
+        assert!(matches!(res[0], Err(DnsFrameError::ReservedOpCode(3))));
+
+        // Shorter than the header
+        let res = read_all(&frames(&[&q[..10], &q]), |s| s);
+        assert_eq!(res.len(), 1);
+        assert!(matches!(
+            res[0],
+            Err(DnsFrameError::TooShort { len: 10, min: 12 })
+        ));
+
+        // The header announces more records than fit into the message
+        let mut msg = q.clone();
+        msg[7] = 5;
+        let res = read_all(&frames(&[&msg]), |s| s);
+        assert!(matches!(res[0], Err(DnsFrameError::TooShort { .. })));
+    }
+}
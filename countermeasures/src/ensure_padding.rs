@@ -1,7 +1,6 @@
 use crate::Error;
 use futures::Stream;
 use std::{
-    io,
     pin::Pin,
     task::{Context, Poll},
 };
@@ -15,26 +14,29 @@ const BLOCK_SIZE: usize = 128;
 static PADDING_BYTES: [u8; 2 * BLOCK_SIZE] = [0; 2 * BLOCK_SIZE];
 
 /// Ensure that each message gets padded appropriatly
-pub struct EnsurePadding<S>
-where
-    S: Stream<Item = Result<Vec<u8>, io::Error>> + Unpin,
-{
+///
+/// Each item of the underlying stream must be a single DNS message in wire format.
+/// The message is parsed and its EDNS padding option is set, such that the message size is a multiple of 128 B.
+/// This follows the Block-Length Padding strategy of RFC 8467.
+pub struct EnsurePadding<S> {
     /// Underlying reader to read a byte stream.
     stream: S,
 }
 
-impl<S> EnsurePadding<S>
+impl<S, E> EnsurePadding<S>
 where
-    S: Stream<Item = Result<Vec<u8>, io::Error>> + Unpin,
+    S: Stream<Item = Result<Vec<u8>, E>> + Unpin,
+    E: Into<Error>,
 {
     pub fn new(stream: S) -> Self {
         Self { stream }
     }
 }
 
-impl<S> Stream for EnsurePadding<S>
+impl<S, E> Stream for EnsurePadding<S>
 where
-    S: Stream<Item = Result<Vec<u8>, io::Error>> + Unpin,
+    S: Stream<Item = Result<Vec<u8>, E>> + Unpin,
+    E: Into<Error>,
 {
     // The same as our future above:
     type Item = Result<Message, Error>;
@@ -88,3 +90,36 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{stream, StreamExt};
+    use std::io;
+    use trust_dns_proto::{
+        op::Query,
+        rr::{Name, RecordType},
+    };
+
+    #[test]
+    fn test_messages_are_padded_to_block_size() {
+        let names = ["example.com.", "a.very.long.subdomain.of.example.org."];
+        let messages: Vec<Result<Vec<u8>, io::Error>> = names
+            .iter()
+            .map(|name| {
+                let mut msg = Message::new();
+                msg.add_query(Query::query(Name::from_ascii(name).unwrap(), RecordType::A));
+                Ok(msg.to_vec().unwrap())
+            })
+            .collect();
+
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let padded: Vec<_> = rt.block_on(EnsurePadding::new(stream::iter(messages)).collect());
+        assert_eq!(padded.len(), names.len());
+        for (msg, name) in padded.into_iter().zip(&names) {
+            let msg = msg.unwrap();
+            assert_eq!(msg.queries()[0].name().to_ascii(), *name);
+            assert_eq!(msg.to_vec().unwrap().len() % BLOCK_SIZE, 0);
+        }
+    }
+}
@@ -1,4 +1,38 @@
-use std::fmt::Debug;
+use crate::DnsFrameError;
+use std::fmt::{self, Debug, Display};
+
+/// Coarse classification of an [`Error`]
+///
+/// The category is part of each error logged by [`print_error`](crate::print_error), such that failures can be counted by their cause.
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+pub enum ErrorCategory {
+    /// Reading from or writing to the client connection failed
+    ClientIo,
+    /// Connecting to, reading from, or writing to the upstream resolver failed
+    UpstreamIo,
+    /// TLS handshake or OpenSSL errors
+    Tls,
+    /// Malformed DNS messages
+    DnsParse,
+    /// Invalid padding strategy
+    Strategy,
+    /// Configuration errors and all errors not attributed to a connection
+    Other,
+}
+
+impl Display for ErrorCategory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ErrorCategory::ClientIo => "client-io",
+            ErrorCategory::UpstreamIo => "upstream-io",
+            ErrorCategory::Tls => "tls",
+            ErrorCategory::DnsParse => "dns-parse",
+            ErrorCategory::Strategy => "strategy",
+            ErrorCategory::Other => "other",
+        };
+        f.write_str(name)
+    }
+}
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
@@ -9,8 +43,16 @@ pub enum Error {
     #[error("Tokio Timer Error: {}", _0)]
     Timer(#[source] tokio::time::Error),
     /// Errors based on [`std::io`]
+    ///
+    /// Use [`Error::client_side`] or [`Error::upstream_side`] to attribute them to a connection.
     #[error("{}: Kind: {:?}", _0, _1)]
     Io(#[source] std::io::Error, std::io::ErrorKind),
+    /// I/O errors on the connection to the client
+    #[error("Client I/O error: {}", _0)]
+    ClientIo(#[source] std::io::Error),
+    /// I/O errors on the connection to the upstream resolver
+    #[error("Upstream I/O error: {}", _0)]
+    UpstreamIo(#[source] std::io::Error),
     /// Errors for parsing `ip:port` strings
     #[error("{}", _0)]
     AddrParseError(#[source] std::net::AddrParseError),
@@ -30,6 +72,58 @@ Please, specify the choice explicitly by using either --tcp or --tls."#,
     TransportNotInferable(u16),
     #[error("Tokio OpenSSL Handshake error: {}", _0)]
     TokioOpensslHandshakeError(String),
+    /// Errors while parsing or resolving a [`HostnameSocketAddr`](crate::HostnameSocketAddr)
+    #[error("{}", _0)]
+    HostnameSocketAddr(#[source] crate::HostnameSocketAddrError),
+    /// Errors while tunneling through the [`UpstreamProxy`](crate::UpstreamProxy)
+    #[error("{}", _0)]
+    UpstreamProxy(#[source] crate::UpstreamProxyError),
+    /// Malformed DNS over TCP frames read by a [`DnsBytesStream`](crate::DnsBytesStream)
+    #[error("{}", _0)]
+    DnsFrame(#[source] DnsFrameError),
+    /// Invalid [`Strategy`](crate::Strategy) descriptions
+    #[error("{}", _0)]
+    Strategy(#[source] crate::StrategyParseError),
+    /// Invalid or unreadable [`StrategyConfig`](crate::StrategyConfig) files
+    #[error("{}", _0)]
+    StrategyConfig(#[source] crate::StrategyConfigError),
+}
+
+impl Error {
+    /// Classify the error for logging and monitoring
+    pub fn category(&self) -> ErrorCategory {
+        match self {
+            Error::ClientIo(_) => ErrorCategory::ClientIo,
+            Error::UpstreamIo(_) | Error::HostnameSocketAddr(_) | Error::UpstreamProxy(_) => {
+                ErrorCategory::UpstreamIo
+            }
+            Error::OpensslError(_) | Error::TokioOpensslHandshakeError(_) => ErrorCategory::Tls,
+            Error::DnsFrame(DnsFrameError::Io(_)) => ErrorCategory::Other,
+            Error::DnsParseError(_) | Error::DnsFrame(_) => ErrorCategory::DnsParse,
+            Error::Strategy(_) | Error::StrategyConfig(_) => ErrorCategory::Strategy,
+            Error::Unknown
+            | Error::Timer(_)
+            | Error::Io(..)
+            | Error::AddrParseError(_)
+            | Error::TransportNotInferable(_) => ErrorCategory::Other,
+        }
+    }
+
+    /// Attribute unspecific I/O errors to the client connection
+    pub fn client_side(self) -> Self {
+        match self {
+            Error::Io(err, _) | Error::DnsFrame(DnsFrameError::Io(err)) => Error::ClientIo(err),
+            err => err,
+        }
+    }
+
+    /// Attribute unspecific I/O errors to the upstream connection
+    pub fn upstream_side(self) -> Self {
+        match self {
+            Error::Io(err, _) | Error::DnsFrame(DnsFrameError::Io(err)) => Error::UpstreamIo(err),
+            err => err,
+        }
+    }
 }
 
 impl From<()> for Error {
@@ -51,6 +145,36 @@ impl From<std::net::AddrParseError> for Error {
     }
 }
 
+impl From<crate::HostnameSocketAddrError> for Error {
+    fn from(error: crate::HostnameSocketAddrError) -> Self {
+        Error::HostnameSocketAddr(error)
+    }
+}
+
+impl From<crate::UpstreamProxyError> for Error {
+    fn from(error: crate::UpstreamProxyError) -> Self {
+        Error::UpstreamProxy(error)
+    }
+}
+
+impl From<DnsFrameError> for Error {
+    fn from(error: DnsFrameError) -> Self {
+        Error::DnsFrame(error)
+    }
+}
+
+impl From<crate::StrategyParseError> for Error {
+    fn from(error: crate::StrategyParseError) -> Self {
+        Error::Strategy(error)
+    }
+}
+
+impl From<crate::StrategyConfigError> for Error {
+    fn from(error: crate::StrategyConfigError) -> Self {
+        Error::StrategyConfig(error)
+    }
+}
+
 impl From<trust_dns_proto::error::ProtoError> for Error {
     fn from(error: trust_dns_proto::error::ProtoError) -> Self {
         Error::DnsParseError(error)
@@ -71,3 +195,38 @@ where
         Error::TokioOpensslHandshakeError(error.to_string())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+
+    fn io_error() -> io::Error {
+        io::Error::new(io::ErrorKind::ConnectionReset, "reset")
+    }
+
+    #[test]
+    fn test_attribute_io_errors() {
+        assert_eq!(Error::from(io_error()).category(), ErrorCategory::Other);
+        assert_eq!(
+            Error::from(io_error()).client_side().category(),
+            ErrorCategory::ClientIo
+        );
+        assert_eq!(
+            Error::from(io_error()).upstream_side().category(),
+            ErrorCategory::UpstreamIo
+        );
+        assert_eq!(
+            Error::from(DnsFrameError::Io(io_error()))
+                .upstream_side()
+                .category(),
+            ErrorCategory::UpstreamIo
+        );
+
+        // Only unattributed I/O errors are changed
+        let err = Error::from(DnsFrameError::Truncated(2)).client_side();
+        assert_eq!(err.category(), ErrorCategory::DnsParse);
+        let err = Error::UpstreamIo(io_error()).client_side();
+        assert_eq!(err.category(), ErrorCategory::UpstreamIo);
+    }
+}
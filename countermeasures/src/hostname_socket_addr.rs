@@ -0,0 +1,683 @@
+//! Socket addresses which keep the hostname for TLS and can be resolved again
+
+use crate::Error;
+use log::{debug, info, warn};
+use once_cell::sync::OnceCell;
+use std::{
+    collections::HashMap,
+    fmt::{self, Display},
+    io,
+    net::SocketAddr,
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tokio::{net::TcpStream, task, time};
+use trust_dns_resolver::{error::ResolveError, Resolver};
+
+/// Resolver with the system configuration, which is shared by all lookups
+static RESOLVER: OnceCell<Resolver> = OnceCell::new();
+
+/// Errors while parsing or resolving a [`HostnameSocketAddr`]
+#[derive(Debug, thiserror::Error)]
+pub enum HostnameSocketAddrError {
+    /// The address has no `:port` suffix
+    #[error("Missing port number in '{}'", _0)]
+    MissingPort(String),
+    /// The part after the last `:` is not a valid port number
+    #[error("Invalid port number in '{}': {}", _0, _1)]
+    InvalidPort(String, #[source] std::num::ParseIntError),
+    /// The system resolver could not be configured or the lookup failed
+    #[error("Cannot resolve '{}': {}", _0, _1)]
+    Resolve(String, #[source] ResolveError),
+    /// The lookup succeeded but returned no addresses
+    #[error("The hostname '{}' has no addresses", _0)]
+    NoAddresses(String),
+}
+
+/// Extension around [`SocketAddr`] and [`ToSocketAddrs`](std::net::ToSocketAddrs) which additionally stores the hostname
+///
+/// The hostname is an important feature for TLS (e.g., SNI and cert validity), therefore only a [`SocketAddr`] is often not enough
+///
+/// Hostnames are resolved while parsing, unless they are parsed with [`HostnameSocketAddr::unresolved`].
+/// The addresses are only valid as long as the TTL of the DNS records, see [`HostnameSocketAddr::resolve_again`].
+#[derive(Clone, Eq, PartialEq, Hash, Debug)]
+pub enum HostnameSocketAddr {
+    Hostname {
+        full_addr_string: String,
+        hostname_length: usize,
+        port: u16,
+        socket_addrs: Vec<SocketAddr>,
+        /// Expiration of the DNS records of `socket_addrs`
+        valid_until: Instant,
+        /// Order `socket_addrs` according to [`happy_eyeballs_order`]
+        happy_eyeballs: bool,
+    },
+    Ip([SocketAddr; 1]),
+}
+
+impl HostnameSocketAddr {
+    /// Parse `addr` like [`FromStr`], but without resolving the hostname
+    ///
+    /// The hostname has no addresses and is resolved by the next [`HostnameSocketAddr::resolve_again`].
+    /// This is useful if only the hostname is needed, e.g., if a proxy resolves it.
+    pub fn unresolved(addr: &str) -> Result<Self, HostnameSocketAddrError> {
+        use HostnameSocketAddr::*;
+
+        // Test if the `addr` is directly convertable to a SocketAddr, then it is an IP address
+        if let Ok(addr) = addr.parse() {
+            return Ok(Ip([addr]));
+        }
+
+        let parts: Vec<_> = addr.rsplitn(2, ':').collect();
+        if parts.len() != 2 {
+            return Err(HostnameSocketAddrError::MissingPort(addr.to_string()));
+        }
+        let port = parts[0]
+            .parse()
+            .map_err(|err| HostnameSocketAddrError::InvalidPort(addr.to_string(), err))?;
+        Ok(Hostname {
+            full_addr_string: addr.to_string(),
+            hostname_length: parts[1].len(),
+            port,
+            socket_addrs: Vec::new(),
+            valid_until: Instant::now(),
+            happy_eyeballs: false,
+        })
+    }
+
+    pub fn hostname(&self) -> String {
+        use HostnameSocketAddr::*;
+        match self {
+            Hostname {
+                full_addr_string,
+                hostname_length,
+                ..
+            } => full_addr_string[..*hostname_length].to_string(),
+            Ip(ip) => ip[0].ip().to_string(),
+        }
+    }
+
+    pub fn port(&self) -> u16 {
+        use HostnameSocketAddr::*;
+        match self {
+            Hostname { port, .. } => *port,
+            Ip(ip) => ip[0].port(),
+        }
+    }
+
+    /// The first address, `None` if the hostname is not resolved
+    pub fn socket_addr(&self) -> Option<SocketAddr> {
+        self.socket_addrs().first().copied()
+    }
+
+    pub fn socket_addrs(&self) -> &[SocketAddr] {
+        use HostnameSocketAddr::*;
+        match self {
+            Hostname { socket_addrs, .. } => socket_addrs,
+            Ip(ip) => ip,
+        }
+    }
+
+    /// Time until which the resolved addresses are valid, `None` for IP addresses
+    pub fn valid_until(&self) -> Option<Instant> {
+        use HostnameSocketAddr::*;
+        match self {
+            Hostname { valid_until, .. } => Some(*valid_until),
+            Ip(_) => None,
+        }
+    }
+
+    /// Returns `true` if the TTL of the DNS records expired at `now`
+    pub fn is_expired(&self, now: Instant) -> bool {
+        self.valid_until()
+            .map(|valid_until| now >= valid_until)
+            .unwrap_or(false)
+    }
+
+    /// Enable or disable the [`happy_eyeballs_order`] of the addresses
+    ///
+    /// The setting is kept for all future calls to [`HostnameSocketAddr::resolve_again`].
+    pub fn with_happy_eyeballs(mut self, enable: bool) -> Self {
+        if let HostnameSocketAddr::Hostname {
+            socket_addrs,
+            happy_eyeballs,
+            ..
+        } = &mut self
+        {
+            *happy_eyeballs = enable;
+            if enable {
+                *socket_addrs = happy_eyeballs_order(std::mem::take(socket_addrs));
+            }
+        }
+        self
+    }
+
+    /// Error of the last connection attempt, or [`HostnameSocketAddrError::NoAddresses`] if there was none
+    fn connect_error(&self, last_err: Option<io::Error>) -> Error {
+        match last_err {
+            Some(err) => err.into(),
+            None => HostnameSocketAddrError::NoAddresses(self.hostname()).into(),
+        }
+    }
+
+    /// Resolve the hostname again, if the TTL of the DNS records expired
+    ///
+    /// The lookup blocks the current thread, see [`refresh_addr`] for async code.
+    /// Returns `true` if the addresses were updated.
+    /// On errors the old addresses are kept, such that the caller can continue using them.
+    /// IP addresses never expire.
+    pub fn resolve_again(&mut self) -> Result<bool, HostnameSocketAddrError> {
+        if let HostnameSocketAddr::Hostname {
+            full_addr_string,
+            hostname_length,
+            port,
+            socket_addrs,
+            valid_until,
+            happy_eyeballs,
+        } = self
+        {
+            if Instant::now() < *valid_until {
+                return Ok(false);
+            }
+
+            let hostname = &full_addr_string[..*hostname_length];
+            let (mut addrs, new_valid_until) = resolve(hostname, *port)?;
+            if *happy_eyeballs {
+                addrs = happy_eyeballs_order(addrs);
+            }
+            if addrs != *socket_addrs {
+                debug!(
+                    "Addresses of {} changed from {:?} to {:?}",
+                    hostname, socket_addrs, addrs
+                );
+            }
+            *socket_addrs = addrs;
+            *valid_until = new_valid_until;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    /// Open a TCP connection to the first reachable address
+    ///
+    /// The addresses are tried in the order of [`HostnameSocketAddr::socket_addrs`].
+    /// Returns the connection and the address it is connected to, or the error of the last address.
+    /// Fails with [`HostnameSocketAddrError::NoAddresses`] if the hostname is not resolved.
+    pub async fn connect(&self) -> Result<(TcpStream, SocketAddr), Error> {
+        let mut last_err = None;
+        for addr in self.socket_addrs() {
+            match TcpStream::connect(addr).await {
+                Ok(stream) => return Ok((stream, *addr)),
+                Err(err) => {
+                    debug!("Cannot connect to {}: {}", addr, err);
+                    last_err = Some(err);
+                }
+            }
+        }
+        Err(self.connect_error(last_err))
+    }
+
+    /// Open a TCP connection to the first reachable address, preferring the healthy ones
+    ///
+    /// The addresses are tried in the order of [`AddressHealth::order`] and each attempt is aborted after the connect timeout.
+    /// Unreachable addresses are marked in `health`, such that later connections try them last.
+    /// Returns the connection and the address it is connected to, or the error of the last address.
+    /// Fails with [`HostnameSocketAddrError::NoAddresses`] if the hostname is not resolved.
+    pub async fn connect_with_health(
+        &self,
+        health: &AddressHealth,
+    ) -> Result<(TcpStream, SocketAddr), Error> {
+        let mut last_err = None;
+        for addr in health.order(self.socket_addrs(), Instant::now()) {
+            let res = match time::timeout(health.connect_timeout, TcpStream::connect(addr)).await {
+                Ok(res) => res,
+                Err(_) => Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("Connecting to {} timed out", addr),
+                )),
+            };
+            match res {
+                Ok(stream) => {
+                    health.mark_healthy(addr);
+                    return Ok((stream, addr));
+                }
+                Err(err) => {
+                    warn!(
+                        "Cannot connect to {}, trying the next address: {}",
+                        addr, err
+                    );
+                    health.mark_failed(addr, Instant::now());
+                    last_err = Some(err);
+                }
+            }
+        }
+        Err(self.connect_error(last_err))
+    }
+}
+
+/// Remember which upstream addresses are unreachable, such that connections fail over to the other addresses
+///
+/// An address which failed is considered unhealthy for the backoff time, which doubles with each consecutive failure.
+/// Unhealthy addresses are still tried, but only after all healthy ones.
+/// A successful connection marks the address as healthy again.
+#[derive(Debug)]
+pub struct AddressHealth {
+    /// Abort connection attempts to a single address after this time
+    connect_timeout: Duration,
+    /// Time for which an address is unhealthy after the first failure
+    backoff: Duration,
+    /// Number of consecutive failures and the end of the backoff per address
+    failures: Mutex<HashMap<SocketAddr, (u32, Instant)>>,
+}
+
+impl AddressHealth {
+    /// Maximal number of doublings of the backoff time
+    const MAX_BACKOFF_DOUBLINGS: u32 = 6;
+
+    pub fn new(connect_timeout: Duration, backoff: Duration) -> Self {
+        Self {
+            connect_timeout,
+            backoff,
+            failures: Mutex::default(),
+        }
+    }
+
+    /// Order `addrs` such that healthy addresses come first
+    ///
+    /// Healthy addresses keep their relative order.
+    /// Unhealthy addresses follow, starting with the one whose backoff ends first.
+    pub fn order(&self, addrs: &[SocketAddr], now: Instant) -> Vec<SocketAddr> {
+        let failures = self.failures.lock().unwrap();
+        let backoff_end = |addr: &SocketAddr| {
+            failures
+                .get(addr)
+                .map(|&(_, until)| until)
+                .filter(|&until| until > now)
+        };
+        let (mut unhealthy, healthy): (Vec<_>, Vec<_>) = addrs
+            .iter()
+            .copied()
+            .partition(|addr| backoff_end(addr).is_some());
+        unhealthy.sort_by_key(backoff_end);
+        healthy.into_iter().chain(unhealthy).collect()
+    }
+
+    /// Mark `addr` as unreachable
+    pub fn mark_failed(&self, addr: SocketAddr, now: Instant) {
+        let mut failures = self.failures.lock().unwrap();
+        let (count, until) = failures.entry(addr).or_insert((0, now));
+        let backoff = self.backoff * 2_u32.pow((*count).min(Self::MAX_BACKOFF_DOUBLINGS));
+        *count += 1;
+        *until = now + backoff;
+        info!(
+            "Upstream address {} failed {} times in a row, trying it last for {:?}",
+            addr, count, backoff
+        );
+    }
+
+    /// Mark `addr` as reachable again
+    pub fn mark_healthy(&self, addr: SocketAddr) {
+        if self.failures.lock().unwrap().remove(&addr).is_some() {
+            info!("Upstream address {} is reachable again", addr);
+        }
+    }
+}
+
+impl Default for AddressHealth {
+    fn default() -> Self {
+        Self::new(Duration::from_secs(5), Duration::from_secs(30))
+    }
+}
+
+/// Resolve `addr` again if necessary and return a copy of it
+///
+/// The lookup runs on the thread pool for blocking tasks and the lock is released during the lookup.
+/// Resolution failures are logged and the previous addresses are used.
+pub async fn refresh_addr(addr: &Mutex<HostnameSocketAddr>) -> HostnameSocketAddr {
+    let current = addr.lock().unwrap().clone();
+    if !current.is_expired(Instant::now()) {
+        return current;
+    }
+
+    let mut updated = current.clone();
+    let res = task::spawn_blocking(move || updated.resolve_again().map(|_| updated)).await;
+    match res {
+        Ok(Ok(updated)) => {
+            *addr.lock().unwrap() = updated.clone();
+            updated
+        }
+        Ok(Err(err)) => {
+            warn!(
+                "Keep the previous addresses of {}: {}",
+                current.hostname(),
+                err
+            );
+            current
+        }
+        Err(err) => {
+            warn!(
+                "Keep the previous addresses of {}, the lookup failed: {}",
+                current.hostname(),
+                err
+            );
+            current
+        }
+    }
+}
+
+/// Order addresses as recommended by Happy Eyeballs (RFC 8305)
+///
+/// The address families are interleaved, starting with IPv6, while the order within each family is kept.
+pub fn happy_eyeballs_order(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let (v6, v4): (Vec<_>, Vec<_>) = addrs.into_iter().partition(SocketAddr::is_ipv6);
+    let mut res = Vec::with_capacity(v6.len() + v4.len());
+    let mut v6 = v6.into_iter();
+    let mut v4 = v4.into_iter();
+    loop {
+        match (v6.next(), v4.next()) {
+            (None, None) => break,
+            (a, b) => res.extend(a.into_iter().chain(b)),
+        }
+    }
+    res
+}
+
+/// Resolve `hostname` with the system resolver configuration
+///
+/// The resolver is created on first use and shared afterwards.
+/// Returns all addresses and the time until which they are valid.
+fn resolve(
+    hostname: &str,
+    port: u16,
+) -> Result<(Vec<SocketAddr>, Instant), HostnameSocketAddrError> {
+    let resolve_err = |err| HostnameSocketAddrError::Resolve(hostname.to_string(), err);
+    let resolver = RESOLVER
+        .get_or_try_init(Resolver::from_system_conf)
+        .map_err(|err| resolve_err(err.into()))?;
+    let lookup = resolver.lookup_ip(hostname).map_err(resolve_err)?;
+    let socket_addrs: Vec<_> = lookup.iter().map(|ip| SocketAddr::new(ip, port)).collect();
+    if socket_addrs.is_empty() {
+        return Err(HostnameSocketAddrError::NoAddresses(hostname.to_string()));
+    }
+    Ok((socket_addrs, lookup.valid_until()))
+}
+
+impl Display for HostnameSocketAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        use HostnameSocketAddr::*;
+        match self {
+            Hostname {
+                full_addr_string,
+                socket_addrs,
+                ..
+            } => {
+                write!(f, "{} (", full_addr_string)?;
+                let mut first = true;
+                for addr in socket_addrs {
+                    write!(f, "{}{}", if first { "" } else { ", " }, addr.ip())?;
+                    first = false;
+                }
+                write!(f, ")")
+            }
+            Ip(ip) => write!(f, "{}", ip[0]),
+        }
+    }
+}
+
+impl FromStr for HostnameSocketAddr {
+    type Err = HostnameSocketAddrError;
+
+    fn from_str(addr: &str) -> Result<Self, Self::Err> {
+        let mut addr = Self::unresolved(addr)?;
+        addr.resolve_again()?;
+        Ok(addr)
+    }
+}
+
+#[cfg(test)]
+mod test_hostname_socket_add {
+    use super::{
+        happy_eyeballs_order, refresh_addr, AddressHealth, HostnameSocketAddr,
+        HostnameSocketAddrError,
+    };
+    use crate::Error;
+    use std::{
+        net::*,
+        sync::Mutex,
+        time::{Duration, Instant},
+    };
+
+    #[test]
+    fn test_ip_address() {
+        let addr1 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+        let addr1_hostname = "127.0.0.1";
+        let addr1_str_clean = "127.0.0.1:8080";
+
+        let hsa: HostnameSocketAddr = addr1_str_clean.parse().unwrap();
+        assert_eq!(addr1_hostname, hsa.hostname());
+        assert_eq!(Some(addr1), hsa.socket_addr());
+        assert_eq!(&[addr1], hsa.socket_addrs());
+
+        let addr2 = SocketAddr::new(
+            IpAddr::V6(Ipv6Addr::new(
+                0xfe80, 0x0123, 0x4567, 0x89ab, 0xcdef, 0x0, 0x0, 0x53,
+            )),
+            853,
+        );
+        let addr2_hostname = "fe80:123:4567:89ab:cdef::53";
+        let addr2_str_clean = "[fe80:123:4567:89ab:cdef::53]:853";
+        let addr2_str_1 = "[fe80:0123:4567:89ab:cdef::0053]:0853";
+        let addr2_str_2 = "[fe80:0123:4567:89ab:cdef:0:0:0053]:0853";
+
+        let hsa: HostnameSocketAddr = addr2_str_clean.parse().unwrap();
+        assert_eq!(addr2_hostname, hsa.hostname());
+        assert_eq!(Some(addr2), hsa.socket_addr());
+        assert_eq!(&[addr2], hsa.socket_addrs());
+        let hsa: HostnameSocketAddr = addr2_str_1.parse().unwrap();
+        assert_eq!(addr2_hostname, hsa.hostname());
+        assert_eq!(Some(addr2), hsa.socket_addr());
+        assert_eq!(&[addr2], hsa.socket_addrs());
+        let hsa: HostnameSocketAddr = addr2_str_2.parse().unwrap();
+        assert_eq!(addr2_hostname, hsa.hostname());
+        assert_eq!(Some(addr2), hsa.socket_addr());
+        assert_eq!(&[addr2], hsa.socket_addrs());
+
+        // Parsing without port should not work
+        assert!(addr1_hostname.parse::<HostnameSocketAddr>().is_err());
+        assert!(addr2_hostname.parse::<HostnameSocketAddr>().is_err());
+    }
+
+    #[test]
+    fn test_simple_network() {
+        let addr1 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4)), 53);
+        let addr1_hostname = "www.1-2-3-4.sslip.io";
+        let addr1_str_clean = "www.1-2-3-4.sslip.io:53";
+        let addr1_str_1 = "www.1-2-3-4.sslip.io:0053";
+
+        let hsa: HostnameSocketAddr = addr1_str_clean.parse().unwrap();
+        assert_eq!(addr1_hostname, hsa.hostname());
+        assert_eq!(Some(addr1), hsa.socket_addr());
+        assert_eq!(&[addr1], hsa.socket_addrs());
+        let hsa: HostnameSocketAddr = addr1_str_1.parse().unwrap();
+        assert_eq!(addr1_hostname, hsa.hostname());
+        assert_eq!(Some(addr1), hsa.socket_addr());
+        assert_eq!(&[addr1], hsa.socket_addrs());
+
+        let addr2 = SocketAddr::new(
+            IpAddr::V6(Ipv6Addr::new(
+                0x2001, 0x0123, 0x4567, 0x89ab, 0xcdef, 0x0, 0x0, 0x53,
+            )),
+            443,
+        );
+        let addr2_hostname = "m.2001-123-4567-89ab-cdef--53.sslip.io";
+        let addr2_str_clean = "m.2001-123-4567-89ab-cdef--53.sslip.io:443";
+        let addr2_str_1 = "m.2001-123-4567-89ab-cdef--53.sslip.io:00443";
+
+        let hsa: HostnameSocketAddr = addr2_str_clean.parse().unwrap();
+        assert_eq!(addr2_hostname, hsa.hostname());
+        assert_eq!(Some(addr2), hsa.socket_addr());
+        assert_eq!(&[addr2], hsa.socket_addrs());
+        let hsa: HostnameSocketAddr = addr2_str_1.parse().unwrap();
+        assert_eq!(addr2_hostname, hsa.hostname());
+        assert_eq!(Some(addr2), hsa.socket_addr());
+        assert_eq!(&[addr2], hsa.socket_addrs());
+
+        // Parsing without port should not work
+        assert!(addr1_hostname.parse::<HostnameSocketAddr>().is_err());
+        assert!(addr2_hostname.parse::<HostnameSocketAddr>().is_err());
+    }
+
+    #[test]
+    fn test_happy_eyeballs_order() {
+        let v4_1: SocketAddr = "192.0.2.1:853".parse().unwrap();
+        let v4_2: SocketAddr = "192.0.2.2:853".parse().unwrap();
+        let v4_3: SocketAddr = "192.0.2.3:853".parse().unwrap();
+        let v6_1: SocketAddr = "[2001:db8::1]:853".parse().unwrap();
+        let v6_2: SocketAddr = "[2001:db8::2]:853".parse().unwrap();
+
+        assert_eq!(
+            vec![v6_1, v4_1, v6_2, v4_2, v4_3],
+            happy_eyeballs_order(vec![v4_1, v4_2, v4_3, v6_1, v6_2])
+        );
+        assert_eq!(vec![v4_1, v4_2], happy_eyeballs_order(vec![v4_1, v4_2]));
+        assert_eq!(Vec::<SocketAddr>::new(), happy_eyeballs_order(vec![]));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(matches!(
+            "example.com".parse::<HostnameSocketAddr>(),
+            Err(super::HostnameSocketAddrError::MissingPort(_))
+        ));
+        assert!(matches!(
+            "example.com:99999".parse::<HostnameSocketAddr>(),
+            Err(super::HostnameSocketAddrError::InvalidPort(..))
+        ));
+    }
+
+    #[test]
+    fn test_address_health_order() {
+        let a: SocketAddr = "192.0.2.1:853".parse().unwrap();
+        let b: SocketAddr = "[2001:db8::1]:853".parse().unwrap();
+        let c: SocketAddr = "192.0.2.2:853".parse().unwrap();
+        let addrs = [a, b, c];
+        let health = AddressHealth::new(Duration::from_secs(1), Duration::from_secs(10));
+        let now = Instant::now();
+        assert_eq!(vec![a, b, c], health.order(&addrs, now));
+
+        // Failed addresses are tried last, the one which failed more often after the other
+        health.mark_failed(a, now);
+        health.mark_failed(a, now);
+        health.mark_failed(b, now);
+        assert_eq!(vec![c, b, a], health.order(&addrs, now));
+        // After 10 s the backoff of `b` ended, but `a` backs off for 20 s
+        assert_eq!(
+            vec![b, c, a],
+            health.order(&addrs, now + Duration::from_secs(15))
+        );
+        assert_eq!(
+            vec![a, b, c],
+            health.order(&addrs, now + Duration::from_secs(25))
+        );
+
+        health.mark_healthy(a);
+        assert_eq!(vec![a, c, b], health.order(&addrs, now));
+    }
+
+    #[test]
+    fn test_ip_never_expires() {
+        let mut hsa: HostnameSocketAddr = "127.0.0.1:853".parse().unwrap();
+        assert_eq!(None, hsa.valid_until());
+        assert!(!hsa.is_expired(Instant::now() + Duration::from_secs(3600)));
+        assert!(!hsa.resolve_again().unwrap());
+    }
+
+    #[test]
+    fn test_unresolved() {
+        let hsa = HostnameSocketAddr::unresolved("dns.example:853").unwrap();
+        assert_eq!("dns.example", hsa.hostname());
+        assert_eq!(853, hsa.port());
+        assert!(hsa.socket_addrs().is_empty());
+        assert_eq!(None, hsa.socket_addr());
+        // The next `resolve_again` performs the lookup
+        assert!(hsa.is_expired(Instant::now()));
+
+        assert_eq!(
+            "127.0.0.1:53".parse::<HostnameSocketAddr>().unwrap(),
+            HostnameSocketAddr::unresolved("127.0.0.1:53").unwrap()
+        );
+        assert!(HostnameSocketAddr::unresolved("dns.example").is_err());
+        assert!(HostnameSocketAddr::unresolved("dns.example:port").is_err());
+    }
+
+    #[test]
+    fn test_refresh_unexpired_addr() {
+        let hsa = HostnameSocketAddr::Hostname {
+            full_addr_string: "dns.example:853".to_string(),
+            hostname_length: 11,
+            port: 853,
+            socket_addrs: vec!["192.0.2.1:853".parse().unwrap()],
+            valid_until: Instant::now() + Duration::from_secs(3600),
+            happy_eyeballs: false,
+        };
+        assert!(!hsa.is_expired(Instant::now()));
+        assert!(hsa.is_expired(Instant::now() + Duration::from_secs(3600)));
+
+        // The addresses are still valid, so no lookup happens
+        let addr = Mutex::new(hsa.clone());
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        assert_eq!(hsa, rt.block_on(refresh_addr(&addr)));
+        assert_eq!(hsa, *addr.lock().unwrap());
+    }
+
+    #[test]
+    fn test_connect_without_addresses() {
+        // The hostname is never resolved, e.g., because the lookup failed
+        let hsa = HostnameSocketAddr::unresolved("dns.example:853").unwrap();
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .enable_all()
+            .build()
+            .unwrap();
+        assert!(matches!(
+            rt.block_on(hsa.connect()),
+            Err(Error::HostnameSocketAddr(
+                HostnameSocketAddrError::NoAddresses(_)
+            ))
+        ));
+    }
+
+    #[test]
+    fn test_connect_with_health_failures() {
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .enable_all()
+            .build()
+            .unwrap();
+        let health = AddressHealth::new(Duration::from_secs(1), Duration::from_secs(10));
+
+        let hsa = HostnameSocketAddr::unresolved("dns.example:853").unwrap();
+        assert!(matches!(
+            rt.block_on(hsa.connect_with_health(&health)),
+            Err(Error::HostnameSocketAddr(
+                HostnameSocketAddrError::NoAddresses(_)
+            ))
+        ));
+
+        // Reserve a port, which refuses connections afterwards
+        let closed = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let hsa: HostnameSocketAddr = closed.to_string().parse().unwrap();
+        // An address which is already unhealthy is still tried and reports its error
+        health.mark_failed(closed, Instant::now());
+        assert!(rt.block_on(hsa.connect_with_health(&health)).is_err());
+        assert_eq!(2, health.failures.lock().unwrap()[&closed].0);
+    }
+}
@@ -2,36 +2,54 @@
 #![warn(rust_2018_idioms)]
 
 mod adaptive_padding;
+mod clock;
 mod constant_rate;
 mod dns_tcp;
 mod ensure_padding;
 mod error;
+mod hostname_socket_addr;
 mod pass_through;
+mod pcap_capture;
+mod session_registry;
+mod strategy_config;
+mod strategy_selection;
+mod stream_ext;
 mod streams;
 pub mod throttle;
+mod upstream_proxy;
+mod upstream_tls;
+#[cfg(test)]
+mod virtual_time;
 
 use crate::throttle::Throttle;
 pub use crate::{
-    adaptive_padding::AdaptivePadding,
+    adaptive_padding::{AdaptivePadding, AdaptivePaddingConfig},
+    clock::{Clock, RealClock, Timer, VirtualClock, VirtualTimer},
     constant_rate::ConstantRate,
-    dns_tcp::DnsBytesStream,
+    dns_tcp::{DnsBytesStream, DnsFrameError},
     ensure_padding::EnsurePadding,
-    error::Error,
+    error::{Error, ErrorCategory},
+    hostname_socket_addr::{
+        happy_eyeballs_order, refresh_addr, AddressHealth, HostnameSocketAddr,
+        HostnameSocketAddrError,
+    },
     pass_through::PassThrough,
+    pcap_capture::{CapturedStream, PcapWriter},
+    session_registry::{RegisteredConnection, SessionRegistry, TransferredBytes},
+    strategy_config::{StrategyConfig, StrategyConfigError, StrategyKind, ThrottleConfig},
+    strategy_selection::{
+        magic_query_response, ClientNetwork, ClientStrategyRule, StrategyParseError,
+        StrategySelector, MAGIC_QUERY_SUFFIX,
+    },
+    stream_ext::PayloadStreamExt,
     streams::{MyStream, MyTcpStream, TokioOpensslStream},
+    upstream_proxy::{ProxyProtocol, UpstreamProxy, UpstreamProxyError},
+    upstream_tls::UpstreamTls,
 };
 use futures::Stream;
 use log::{error, warn};
-use std::{
-    fmt::{self, Display},
-    fs::OpenOptions,
-    io::Write,
-    net::{SocketAddr, ToSocketAddrs},
-    path::Path,
-    str::FromStr,
-    sync::Mutex,
-    time::Duration,
-};
+use sequences::adaptive_padding::ApTraceWriter;
+use std::{fs::OpenOptions, io::Write, path::Path, sync::Mutex, time::Duration};
 use structopt::StructOpt;
 
 /// Self Signed server certificate in PEM format
@@ -80,6 +98,13 @@ pub enum Strategy {
         /// Throttle the connection to at most 1 outgoing packet every `throttle-out` ms
         #[structopt(long = "tout", parse(try_from_str = parse_duration_ms))]
         throttle_out: Option<Duration>,
+        /// Bypass the throttles for queries which are queued for longer than `max-delay` ms
+        ///
+        /// This bounds the latency added by `--tin` and `--tout`.
+        /// The dummies of Adaptive Padding never bypass `--tout`.
+        /// Each bypass is logged.
+        #[structopt(long = "max-delay", parse(try_from_str = parse_duration_ms))]
+        max_delay: Option<Duration>,
     },
 }
 
@@ -89,192 +114,6 @@ pub fn parse_duration_ms(s: &str) -> Result<Duration, std::num::ParseFloatError>
     Ok(Duration::from_micros((ms * 1000.).round() as u64))
 }
 
-/// Extension around [`SocketAddr`] and [`ToSocketAddrs`] which additionally stores the hostname
-///
-/// The hostname is an important feature for TLS (e.g., SNI and cert validity), therefore only a [`SocketAddr`] is often not enough
-#[derive(Clone, Eq, PartialEq, Hash, Debug)]
-pub enum HostnameSocketAddr {
-    Hostname {
-        full_addr_string: String,
-        hostname_length: usize,
-        socket_addrs: Vec<SocketAddr>,
-    },
-    Ip([SocketAddr; 1]),
-}
-
-impl HostnameSocketAddr {
-    pub fn hostname(&self) -> String {
-        use HostnameSocketAddr::*;
-        match self {
-            Hostname {
-                full_addr_string,
-                hostname_length,
-                ..
-            } => full_addr_string[..*hostname_length].to_string(),
-            Ip(ip) => ip[0].ip().to_string(),
-        }
-    }
-
-    pub fn port(&self) -> u16 {
-        self.socket_addr().port()
-    }
-
-    pub fn socket_addr(&self) -> SocketAddr {
-        use HostnameSocketAddr::*;
-        match self {
-            Hostname { socket_addrs, .. } => socket_addrs[0],
-            Ip(ip) => ip[0],
-        }
-    }
-
-    pub fn socket_addrs(&self) -> &[SocketAddr] {
-        use HostnameSocketAddr::*;
-        match self {
-            Hostname { socket_addrs, .. } => socket_addrs,
-            Ip(ip) => ip,
-        }
-    }
-}
-
-impl Display for HostnameSocketAddr {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
-        use HostnameSocketAddr::*;
-        match self {
-            Hostname {
-                full_addr_string,
-                socket_addrs,
-                ..
-            } => {
-                write!(f, "{} (", full_addr_string)?;
-                let mut first = true;
-                for addr in socket_addrs {
-                    write!(f, "{}{}", if first { "" } else { ", " }, addr.ip())?;
-                    first = false;
-                }
-                write!(f, ")")
-            }
-            Ip(ip) => write!(f, "{}", ip[0]),
-        }
-    }
-}
-
-impl FromStr for HostnameSocketAddr {
-    // TODO fix error type
-    type Err = String;
-
-    fn from_str(addr: &str) -> Result<Self, Self::Err> {
-        use HostnameSocketAddr::*;
-
-        // Test if the `addr` is directly convertable to a SocketAddr, then it is an IP address
-        if let Ok(addr) = addr.parse() {
-            return Ok(Ip([addr]));
-        }
-
-        let parts: Vec<_> = addr.rsplitn(2, ':').collect();
-        if parts.len() != 2 {
-            return Err("Missing Port number".into());
-        }
-        let socket_addrs: Vec<_> = addr
-            .to_socket_addrs()
-            .map_err(|err| err.to_string())?
-            .collect();
-        if socket_addrs.is_empty() {
-            return Err("The list of SocketAddrs is empty".into());
-        }
-        Ok(Hostname {
-            full_addr_string: addr.to_string(),
-            hostname_length: parts[1].len(),
-            socket_addrs,
-        })
-    }
-}
-
-#[cfg(test)]
-mod test_hostname_socket_add {
-    use super::HostnameSocketAddr;
-    use std::net::*;
-
-    #[test]
-    fn test_ip_address() {
-        let addr1 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
-        let addr1_hostname = "127.0.0.1";
-        let addr1_str_clean = "127.0.0.1:8080";
-
-        let hsa: HostnameSocketAddr = addr1_str_clean.parse().unwrap();
-        assert_eq!(addr1_hostname, hsa.hostname());
-        assert_eq!(addr1, hsa.socket_addr());
-        assert_eq!(&[addr1], hsa.socket_addrs());
-
-        let addr2 = SocketAddr::new(
-            IpAddr::V6(Ipv6Addr::new(
-                0xfe80, 0x0123, 0x4567, 0x89ab, 0xcdef, 0x0, 0x0, 0x53,
-            )),
-            853,
-        );
-        let addr2_hostname = "fe80:123:4567:89ab:cdef::53";
-        let addr2_str_clean = "[fe80:123:4567:89ab:cdef::53]:853";
-        let addr2_str_1 = "[fe80:0123:4567:89ab:cdef::0053]:0853";
-        let addr2_str_2 = "[fe80:0123:4567:89ab:cdef:0:0:0053]:0853";
-
-        let hsa: HostnameSocketAddr = addr2_str_clean.parse().unwrap();
-        assert_eq!(addr2_hostname, hsa.hostname());
-        assert_eq!(addr2, hsa.socket_addr());
-        assert_eq!(&[addr2], hsa.socket_addrs());
-        let hsa: HostnameSocketAddr = addr2_str_1.parse().unwrap();
-        assert_eq!(addr2_hostname, hsa.hostname());
-        assert_eq!(addr2, hsa.socket_addr());
-        assert_eq!(&[addr2], hsa.socket_addrs());
-        let hsa: HostnameSocketAddr = addr2_str_2.parse().unwrap();
-        assert_eq!(addr2_hostname, hsa.hostname());
-        assert_eq!(addr2, hsa.socket_addr());
-        assert_eq!(&[addr2], hsa.socket_addrs());
-
-        // Parsing without port should not work
-        assert!(addr1_hostname.parse::<HostnameSocketAddr>().is_err());
-        assert!(addr2_hostname.parse::<HostnameSocketAddr>().is_err());
-    }
-
-    #[test]
-    fn test_simple_network() {
-        let addr1 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4)), 53);
-        let addr1_hostname = "www.1-2-3-4.sslip.io";
-        let addr1_str_clean = "www.1-2-3-4.sslip.io:53";
-        let addr1_str_1 = "www.1-2-3-4.sslip.io:0053";
-
-        let hsa: HostnameSocketAddr = addr1_str_clean.parse().unwrap();
-        assert_eq!(addr1_hostname, hsa.hostname());
-        assert_eq!(addr1, hsa.socket_addr());
-        assert_eq!(&[addr1], hsa.socket_addrs());
-        let hsa: HostnameSocketAddr = addr1_str_1.parse().unwrap();
-        assert_eq!(addr1_hostname, hsa.hostname());
-        assert_eq!(addr1, hsa.socket_addr());
-        assert_eq!(&[addr1], hsa.socket_addrs());
-
-        let addr2 = SocketAddr::new(
-            IpAddr::V6(Ipv6Addr::new(
-                0x2001, 0x0123, 0x4567, 0x89ab, 0xcdef, 0x0, 0x0, 0x53,
-            )),
-            443,
-        );
-        let addr2_hostname = "m.2001-123-4567-89ab-cdef--53.sslip.io";
-        let addr2_str_clean = "m.2001-123-4567-89ab-cdef--53.sslip.io:443";
-        let addr2_str_1 = "m.2001-123-4567-89ab-cdef--53.sslip.io:00443";
-
-        let hsa: HostnameSocketAddr = addr2_str_clean.parse().unwrap();
-        assert_eq!(addr2_hostname, hsa.hostname());
-        assert_eq!(addr2, hsa.socket_addr());
-        assert_eq!(&[addr2], hsa.socket_addrs());
-        let hsa: HostnameSocketAddr = addr2_str_1.parse().unwrap();
-        assert_eq!(addr2_hostname, hsa.hostname());
-        assert_eq!(addr2, hsa.socket_addr());
-        assert_eq!(&[addr2], hsa.socket_addrs());
-
-        // Parsing without port should not work
-        assert!(addr1_hostname.parse::<HostnameSocketAddr>().is_err());
-        assert!(addr2_hostname.parse::<HostnameSocketAddr>().is_err());
-    }
-}
-
 #[allow(dead_code)]
 type OpensslKeylogCallback = dyn Fn(&openssl::ssl::SslRef, &str) + 'static + Sync + Send;
 
@@ -320,22 +159,29 @@ fn test_function_has_correct_type() {
 }
 
 /// Log all errors produces by the future and discard the ok-value
+///
+/// Each message starts with the [`ErrorCategory`] of the error, followed by the chain of error messages.
 pub async fn print_error<F, T, E>(future: F)
 where
     F: std::future::Future<Output = Result<T, E>>,
-    E: std::error::Error,
+    E: Into<Error>,
 {
     use std::fmt::Write;
 
     if let Err(err) = future.await {
+        let err: Error = err.into();
+        let category = err.category();
+        let mut msg = err.to_string();
         let mut err: &dyn std::error::Error = &err;
-        let mut msg = String::new();
         while let Some(new_err) = err.source() {
-            // for fail in Fail::iter_chain(&err) {
-            let _ = writeln!(&mut msg, "{}", new_err);
+            // Many variants only forward the message of their source
+            let new_msg = new_err.to_string();
+            if !msg.contains(&new_msg) {
+                let _ = write!(&mut msg, "\n{}", new_msg);
+            }
             err = new_err;
         }
-        error!("{}", msg);
+        error!("[{}] {}", category, msg);
     }
 }
 
@@ -349,6 +195,11 @@ pub enum Payload<T> {
 }
 
 impl<T> Payload<T> {
+    /// Returns `true` if this is a real [`PAYLOAD`](Payload::Payload) element
+    pub fn is_payload(&self) -> bool {
+        matches!(self, Payload::Payload(_))
+    }
+
     /// Convert this instance of [`Payload`] into a `T`
     ///
     /// The function takes the payload value, if the variant is [`PAYLOAD`](Payload::Payload).
@@ -384,31 +235,83 @@ impl<T> Payload<Result<T, Error>> {
     }
 }
 
+/// Apply `strategy` to `stream`
+///
+/// `ap_config` contains the parameters of Adaptive Padding and is ignored by all other strategies.
+/// If `ap_trace` is set, the state transitions of Adaptive Padding are recorded into it.
 pub fn wrap_stream<S, T>(
     stream: S,
     strategy: &Strategy,
+    ap_config: AdaptivePaddingConfig,
+    ap_trace: Option<ApTraceWriter>,
 ) -> impl Stream<Item = Payload<T>> + Send + Unpin
 where
     S: Stream<Item = T> + Send + Unpin + 'static,
     T: Send + Sync + Unpin + 'static,
+{
+    wrap_stream_with_clock(stream, strategy, ap_config, ap_trace, RealClock)
+}
+
+/// Apply `strategy` to `stream` using the times of `clock`
+///
+/// With a [`VirtualClock`] the strategy runs in simulated time, see [`wrap_stream`] for the other arguments.
+pub fn wrap_stream_with_clock<S, T, C>(
+    stream: S,
+    strategy: &Strategy,
+    ap_config: AdaptivePaddingConfig,
+    ap_trace: Option<ApTraceWriter>,
+    clock: C,
+) -> impl Stream<Item = Payload<T>> + Send + Unpin
+where
+    S: Stream<Item = T> + Send + Unpin + 'static,
+    T: Send + Sync + Unpin + 'static,
+    C: Clock,
 {
     match strategy {
         Strategy::PassThrough => {
-            Box::new(PassThrough::new(stream)) as Box<dyn Stream<Item = _> + Send + Unpin>
+            Box::new(stream.pass_through()) as Box<dyn Stream<Item = _> + Send + Unpin>
         }
-        Strategy::Constant { rate, .. } => Box::new(ConstantRate::new(stream, *rate)),
+        Strategy::Constant { rate, .. } => Box::new(ConstantRate::with_clock(stream, *rate, clock)),
         Strategy::AdaptivePadding {
             throttle_in,
             throttle_out,
-            ..
+            max_delay,
         } => match (*throttle_in, *throttle_out) {
-            (Some(tin), Some(tout)) => Box::new(Throttle::new(
-                AdaptivePadding::new(Throttle::new(stream, tin)),
-                tout,
-            )) as Box<dyn Stream<Item = _> + Send + Unpin>,
-            (Some(tin), None) => Box::new(AdaptivePadding::new(Throttle::new(stream, tin))),
-            (None, Some(tout)) => Box::new(Throttle::new(AdaptivePadding::new(stream), tout)),
-            (None, None) => Box::new(AdaptivePadding::new(stream)),
+            (Some(tin), Some(tout)) => Box::new(
+                Throttle::with_clock(
+                    AdaptivePadding::with_clock(
+                        Throttle::with_clock(stream, tin, clock.clone()).with_max_delay(*max_delay),
+                        ap_config,
+                        clock.clone(),
+                    )
+                    .with_trace(ap_trace),
+                    tout,
+                    clock,
+                )
+                .with_max_delay(*max_delay)
+                .with_tracked_items(Payload::is_payload),
+            ) as Box<dyn Stream<Item = _> + Send + Unpin>,
+            (Some(tin), None) => Box::new(
+                AdaptivePadding::with_clock(
+                    Throttle::with_clock(stream, tin, clock.clone()).with_max_delay(*max_delay),
+                    ap_config,
+                    clock,
+                )
+                .with_trace(ap_trace),
+            ),
+            (None, Some(tout)) => Box::new(
+                Throttle::with_clock(
+                    AdaptivePadding::with_clock(stream, ap_config, clock.clone())
+                        .with_trace(ap_trace),
+                    tout,
+                    clock,
+                )
+                .with_max_delay(*max_delay)
+                .with_tracked_items(Payload::is_payload),
+            ),
+            (None, None) => {
+                Box::new(AdaptivePadding::with_clock(stream, ap_config, clock).with_trace(ap_trace))
+            }
         },
     }
 }
@@ -5,27 +5,27 @@ use std::{
     task::{Context, Poll},
 };
 
-pub struct PassThrough<S, T>
-where
-    S: Stream<Item = T> + Unpin,
-{
+/// Forward all items as [`Payload::Payload`] without any delay and without adding dummies
+///
+/// This is the baseline without any defense, but with the same item type as the other strategies.
+pub struct PassThrough<S> {
     stream: S,
 }
 
-impl<S, T> PassThrough<S, T>
+impl<S> PassThrough<S>
 where
-    S: Stream<Item = T> + Unpin,
+    S: Stream + Unpin,
 {
     pub fn new(stream: S) -> Self {
         PassThrough { stream }
     }
 }
 
-impl<S, T> Stream for PassThrough<S, T>
+impl<S> Stream for PassThrough<S>
 where
-    S: Stream<Item = T> + Unpin,
+    S: Stream + Unpin,
 {
-    type Item = Payload<T>;
+    type Item = Payload<S::Item>;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
         match Pin::new(&mut self.stream).poll_next(cx) {
@@ -0,0 +1,392 @@
+//! Record the padded traffic of the proxy into a pcap file
+//!
+//! A [`CapturedStream`] wraps the TCP stream below TLS and writes all sent and received bytes as synthetic IPv4/TCP packets.
+//! The timestamps are the times of the reads and writes.
+//! The resulting file contains the encrypted TLS records and can be fed directly into `sequences::pcap`, without running tcpdump next to the proxy.
+//!
+//! Only the payload carrying segments are recorded.
+//! The TCP handshake, acknowledgments, and retransmissions are not part of the capture.
+//! Connections over IPv6 are not recorded, since `sequences::pcap` only supports IPv4.
+//!
+//! The packets are written by a dedicated thread, such that the file IO never blocks the runtime.
+
+use byteorder::{BigEndian, LittleEndian, WriteBytesExt};
+use log::{error, warn};
+use std::{
+    fs::File,
+    io::{self, Write},
+    net::{SocketAddr, SocketAddrV4},
+    path::Path,
+    pin::Pin,
+    sync::{mpsc, Arc, Mutex},
+    task::{Context, Poll},
+    thread::{self, JoinHandle},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// Link type for raw IPv4/IPv6 packets without a link layer header
+const LINKTYPE_RAW: u32 = 101;
+/// Maximal payload per recorded TCP segment
+const MAX_SEGMENT_SIZE: usize = 1460;
+const IPV4_HEADER_LEN: usize = 20;
+const TCP_HEADER_LEN: usize = 20;
+
+/// Shared pcap output of all captured connections
+///
+/// Dropping the last reference waits until all recorded packets are written.
+pub struct PcapWriter {
+    /// Sends the records of the packets to the writer thread
+    ///
+    /// Only `None` while dropping.
+    records: Mutex<Option<mpsc::Sender<Vec<u8>>>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl std::fmt::Debug for PcapWriter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PcapWriter").finish()
+    }
+}
+
+impl PcapWriter {
+    /// Create a new pcap file at `path`
+    pub fn create(path: &Path) -> io::Result<Arc<Self>> {
+        Self::new(File::create(path)?)
+    }
+
+    /// Write the pcap file header to `output` and start the writer thread
+    ///
+    /// Each packet is written with a single call to [`Write::write_all`], thus `output` should not be buffered.
+    /// Otherwise, the packets are lost if the proxy is killed.
+    pub fn new<W>(mut output: W) -> io::Result<Arc<Self>>
+    where
+        W: Write + Send + 'static,
+    {
+        let mut header = Vec::with_capacity(24);
+        header.write_u32::<LittleEndian>(0xa1b2_c3d4)?;
+        header.write_u16::<LittleEndian>(2)?;
+        header.write_u16::<LittleEndian>(4)?;
+        // Timezone offset and timestamp accuracy
+        header.write_i32::<LittleEndian>(0)?;
+        header.write_u32::<LittleEndian>(0)?;
+        // Snapshot length
+        header.write_u32::<LittleEndian>(65535)?;
+        header.write_u32::<LittleEndian>(LINKTYPE_RAW)?;
+        output.write_all(&header)?;
+        output.flush()?;
+
+        let (sender, receiver) = mpsc::channel::<Vec<u8>>();
+        let thread = thread::Builder::new()
+            .name("pcap-writer".to_string())
+            .spawn(move || {
+                for record in receiver {
+                    if let Err(err) = output.write_all(&record).and_then(|()| output.flush()) {
+                        error!("Could not write to the pcap file: {}", err);
+                    }
+                }
+            })?;
+
+        Ok(Arc::new(Self {
+            records: Mutex::new(Some(sender)),
+            thread: Some(thread),
+        }))
+    }
+
+    /// Record `payload` as TCP segments from `src` to `dst`
+    ///
+    /// `seq` is the sequence number of the first byte and is advanced by the length of `payload`.
+    /// The packets are only queued here and written by the writer thread.
+    fn write_segments(
+        &self,
+        src: SocketAddrV4,
+        dst: SocketAddrV4,
+        seq: &mut u32,
+        ack: u32,
+        payload: &[u8],
+    ) {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let mut record = Vec::new();
+        for chunk in payload.chunks(MAX_SEGMENT_SIZE) {
+            let packet = build_packet(src, dst, *seq, ack, chunk);
+            *seq = seq.wrapping_add(chunk.len() as u32);
+
+            record
+                .write_u32::<LittleEndian>(time.as_secs() as u32)
+                .unwrap();
+            record
+                .write_u32::<LittleEndian>(time.subsec_micros())
+                .unwrap();
+            record
+                .write_u32::<LittleEndian>(packet.len() as u32)
+                .unwrap();
+            record
+                .write_u32::<LittleEndian>(packet.len() as u32)
+                .unwrap();
+            record.extend_from_slice(&packet);
+        }
+
+        if let Some(records) = &*self.records.lock().unwrap() {
+            if records.send(record).is_err() {
+                error!("Could not write to the pcap file, since the writer thread stopped");
+            }
+        }
+    }
+}
+
+impl Drop for PcapWriter {
+    fn drop(&mut self) {
+        // Closing the channel stops the writer thread after it wrote all queued records
+        self.records.get_mut().unwrap().take();
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                error!("The pcap writer thread panicked");
+            }
+        }
+    }
+}
+
+/// Build an IPv4 packet containing a TCP segment with the PSH and ACK flags
+fn build_packet(
+    src: SocketAddrV4,
+    dst: SocketAddrV4,
+    seq: u32,
+    ack: u32,
+    payload: &[u8],
+) -> Vec<u8> {
+    let total_len = IPV4_HEADER_LEN + TCP_HEADER_LEN + payload.len();
+    let mut packet = Vec::with_capacity(total_len);
+
+    // IPv4 header
+    packet.push(0x45);
+    packet.push(0);
+    packet.write_u16::<BigEndian>(total_len as u16).unwrap();
+    // Identification, flags (Don't Fragment), and fragment offset
+    packet.write_u16::<BigEndian>(0).unwrap();
+    packet.write_u16::<BigEndian>(0x4000).unwrap();
+    // TTL and protocol TCP
+    packet.push(64);
+    packet.push(6);
+    // Checksum, filled in below
+    packet.write_u16::<BigEndian>(0).unwrap();
+    packet.extend_from_slice(&src.ip().octets());
+    packet.extend_from_slice(&dst.ip().octets());
+    let checksum = internet_checksum(&[&packet[..IPV4_HEADER_LEN]]);
+    packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+
+    // TCP header
+    packet.write_u16::<BigEndian>(src.port()).unwrap();
+    packet.write_u16::<BigEndian>(dst.port()).unwrap();
+    packet.write_u32::<BigEndian>(seq).unwrap();
+    packet.write_u32::<BigEndian>(ack).unwrap();
+    // Data offset and flags PSH + ACK
+    packet.push((TCP_HEADER_LEN as u8 / 4) << 4);
+    packet.push(0x18);
+    // Window, checksum (filled in below), and urgent pointer
+    packet.write_u16::<BigEndian>(0xffff).unwrap();
+    packet.write_u16::<BigEndian>(0).unwrap();
+    packet.write_u16::<BigEndian>(0).unwrap();
+    packet.extend_from_slice(payload);
+
+    let mut pseudo_header = Vec::with_capacity(12);
+    pseudo_header.extend_from_slice(&src.ip().octets());
+    pseudo_header.extend_from_slice(&dst.ip().octets());
+    pseudo_header.push(0);
+    pseudo_header.push(6);
+    pseudo_header
+        .write_u16::<BigEndian>((TCP_HEADER_LEN + payload.len()) as u16)
+        .unwrap();
+    let checksum = internet_checksum(&[&pseudo_header, &packet[IPV4_HEADER_LEN..]]);
+    packet[IPV4_HEADER_LEN + 16..IPV4_HEADER_LEN + 18].copy_from_slice(&checksum.to_be_bytes());
+
+    packet
+}
+
+/// Checksum of IPv4 and TCP (RFC 1071) over the concatenation of `parts`
+///
+/// All parts except the last must have an even length.
+fn internet_checksum(parts: &[&[u8]]) -> u16 {
+    let mut sum: u32 = 0;
+    for part in parts {
+        for word in part.chunks(2) {
+            let word = match *word {
+                [high, low] => u16::from_be_bytes([high, low]),
+                [high] => u16::from_be_bytes([high, 0]),
+                _ => unreachable!("chunks(2) returns one or two bytes"),
+            };
+            sum += u32::from(word);
+        }
+    }
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Direction and TCP state of a captured connection
+#[derive(Debug)]
+struct Flow {
+    writer: Arc<PcapWriter>,
+    local: SocketAddrV4,
+    peer: SocketAddrV4,
+    /// Next sequence number of data sent by `local`
+    local_seq: u32,
+    /// Next sequence number of data sent by `peer`
+    peer_seq: u32,
+}
+
+/// Wrapper around a TCP stream, which records all transferred bytes into a [`PcapWriter`]
+///
+/// Without a writer, the stream only forwards all calls.
+#[derive(Debug)]
+pub struct CapturedStream<S> {
+    stream: S,
+    flow: Option<Flow>,
+}
+
+impl<S> CapturedStream<S> {
+    /// Wrap the connection `stream` between `local` and `peer`
+    pub fn new(
+        stream: S,
+        writer: Option<Arc<PcapWriter>>,
+        local: SocketAddr,
+        peer: SocketAddr,
+    ) -> Self {
+        let flow = writer.and_then(|writer| match (local, peer) {
+            (SocketAddr::V4(local), SocketAddr::V4(peer)) => Some(Flow {
+                writer,
+                local,
+                peer,
+                local_seq: 1,
+                peer_seq: 1,
+            }),
+            _ => {
+                warn!("Not capturing the IPv6 connection to {}", peer);
+                None
+            }
+        });
+        Self { stream, flow }
+    }
+
+    /// Access the wrapped stream
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+}
+
+impl<S> AsyncRead for CapturedStream<S>
+where
+    S: AsyncRead + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let res = Pin::new(&mut this.stream).poll_read(cx, buf);
+        if let (Poll::Ready(Ok(len)), Some(flow)) = (&res, &mut this.flow) {
+            if *len > 0 {
+                flow.writer.write_segments(
+                    flow.peer,
+                    flow.local,
+                    &mut flow.peer_seq,
+                    flow.local_seq,
+                    &buf[..*len],
+                );
+            }
+        }
+        res
+    }
+}
+
+impl<S> AsyncWrite for CapturedStream<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let res = Pin::new(&mut this.stream).poll_write(cx, buf);
+        if let (Poll::Ready(Ok(len)), Some(flow)) = (&res, &mut this.flow) {
+            if *len > 0 {
+                flow.writer.write_segments(
+                    flow.local,
+                    flow.peer,
+                    &mut flow.local_seq,
+                    flow.peer_seq,
+                    &buf[..*len],
+                );
+            }
+        }
+        res
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// In-memory output for a [`PcapWriter`]
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_internet_checksum() {
+        // Example header from https://en.wikipedia.org/wiki/Internet_checksum
+        let header = [
+            0x45, 0x00, 0x00, 0x73, 0x00, 0x00, 0x40, 0x00, 0x40, 0x11, 0x00, 0x00, 0xc0, 0xa8,
+            0x00, 0x01, 0xc0, 0xa8, 0x00, 0xc7,
+        ];
+        assert_eq!(0xb861, internet_checksum(&[&header]));
+        // Odd length parts are padded with zero
+        assert_eq!(!0x1200, internet_checksum(&[&[0x12]]));
+    }
+
+    #[test]
+    fn test_write_segments() {
+        let buf = SharedBuf::default();
+        let writer = PcapWriter::new(buf.clone()).unwrap();
+        let src: SocketAddrV4 = "192.0.2.1:12345".parse().unwrap();
+        let dst: SocketAddrV4 = "192.0.2.2:853".parse().unwrap();
+        let mut seq = 100;
+        writer.write_segments(src, dst, &mut seq, 1, &[0xab; 2000]);
+        assert_eq!(2100, seq);
+        // Wait for the writer thread
+        drop(writer);
+
+        let output = buf.0.lock().unwrap();
+        // File header, then two records with their own headers
+        let headers = 24 + 2 * (16 + IPV4_HEADER_LEN + TCP_HEADER_LEN);
+        assert_eq!(headers + 2000, output.len());
+        let first_packet = &output[24 + 16..24 + 16 + IPV4_HEADER_LEN + TCP_HEADER_LEN];
+        // Valid checksums sum up to zero
+        assert_eq!(0, internet_checksum(&[&first_packet[..IPV4_HEADER_LEN]]));
+        assert_eq!(
+            &first_packet[IPV4_HEADER_LEN + 4..IPV4_HEADER_LEN + 8],
+            &100_u32.to_be_bytes()
+        );
+    }
+}
@@ -0,0 +1,479 @@
+use crate::{Error, Strategy, StrategyConfig};
+use chrono::{DateTime, SecondsFormat, Utc};
+use log::error;
+use openssl::ssl::SslRef;
+use serde_json::{json, Value};
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    fs::{File, OpenOptions},
+    io::Write,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+/// Upper bound of client randoms for which keylog lines are kept without a matching connection
+///
+/// Keylog lines are recorded during the handshake, i.e., before the connection is registered.
+/// Lines of failed handshakes never get claimed, so the oldest ones are dropped once this limit is reached.
+const MAX_UNCLAIMED_KEYLOGS: usize = 1024;
+
+/// Registry linking proxied connections to their TLS sessions and SSLKEYLOG entries
+///
+/// The registry replaces the plain [`keylog_to_file`](crate::keylog_to_file) callback.
+/// All keylog lines are still appended to the SSLKEYLOGFILE, if one is configured.
+/// Additionally, each line is assigned to the connection with the same client random, which allows exporting an index.
+/// The index is a JSON Lines file with one entry per finished connection, listing the keys, the timestamps, and the number of transferred bytes.
+/// It also contains the [`StrategyConfig`]s of the proxy, such that the measurement can be reproduced.
+/// This makes it possible to later find the matching keys for a connection in a pcap file.
+///
+/// Only open connections are kept in memory.
+/// Finished connections are appended to the index and then forgotten, such that long running proxies do not grow without bounds.
+/// Connections opened with [`SessionRegistry::open_connection`] are recorded as aborted, if the handler returns early.
+#[derive(Debug)]
+pub struct SessionRegistry {
+    keylog_path: Option<PathBuf>,
+    keylog_file: Option<Mutex<File>>,
+    index_file: Option<Mutex<File>>,
+    state: Mutex<RegistryState>,
+}
+
+#[derive(Debug, Default)]
+struct RegistryState {
+    next_connection_id: u64,
+    /// Keylog lines indexed by the hex encoded client random
+    keylog_lines: HashMap<String, Vec<String>>,
+    /// Insertion order of the client randoms in `keylog_lines`, used to evict the oldest entries
+    keylog_order: VecDeque<String>,
+    /// All connections which are not finished yet
+    connections: BTreeMap<u64, ConnectionEntry>,
+}
+
+#[derive(Debug)]
+struct ConnectionEntry {
+    /// Free text description which side of the proxy this connection belongs to
+    role: &'static str,
+    peer: SocketAddr,
+    client_random: Option<String>,
+    session_id: Option<String>,
+    tls_version: Option<&'static str>,
+    cipher: Option<&'static str>,
+    /// Padding strategy applied to this connection, in the format of [`Strategy`]'s `FromStr`
+    strategy: Option<String>,
+    start: DateTime<Utc>,
+}
+
+/// Number of bytes a connection transferred, as seen from the proxy
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct TransferredBytes {
+    /// Bytes the proxy wrote to the peer of the connection
+    pub sent: u64,
+    /// Bytes the proxy read from the peer of the connection
+    pub received: u64,
+}
+
+impl SessionRegistry {
+    /// Create a new registry
+    ///
+    /// `keylog_path` is the SSLKEYLOGFILE to which all key material is appended.
+    /// `index_path` is the location of the JSON Lines index, to which each finished connection is appended.
+    pub fn new(
+        keylog_path: Option<PathBuf>,
+        index_path: Option<PathBuf>,
+    ) -> Result<Arc<Self>, Error> {
+        let open_append = |path: &PathBuf| OpenOptions::new().append(true).create(true).open(path);
+        let keylog_file = keylog_path
+            .as_ref()
+            .map(open_append)
+            .transpose()?
+            .map(Mutex::new);
+        let index_file = index_path
+            .as_ref()
+            .map(open_append)
+            .transpose()?
+            .map(Mutex::new);
+
+        Ok(Arc::new(Self {
+            keylog_path,
+            keylog_file,
+            index_file,
+            state: Mutex::default(),
+        }))
+    }
+
+    /// Returns `true` if the registry needs to observe the TLS key material
+    pub fn is_enabled(&self) -> bool {
+        self.keylog_file.is_some() || self.index_file.is_some()
+    }
+
+    /// Create a callback usable with `set_keylog_callback` which records into this registry
+    pub fn keylog_callback(self: &Arc<Self>) -> impl Fn(&SslRef, &str) + 'static + Sync + Send {
+        let this = self.clone();
+        move |_ssl, line| this.record_keylog_line(line)
+    }
+
+    fn record_keylog_line(&self, line: &str) {
+        if let (Some(path), Some(file)) = (&self.keylog_path, &self.keylog_file) {
+            let mut file = file.lock().unwrap();
+            if let Err(err) = writeln!(file, "{}", line) {
+                error!(
+                    "Could not write to SSLKEYLOGFILE {}: {}",
+                    path.display(),
+                    err
+                );
+            }
+        }
+
+        // The lines are only needed for the index
+        if self.index_file.is_none() {
+            return;
+        }
+        // All keylog formats have the structure `<LABEL> <client random> <secret>`
+        if let Some(client_random) = line.split_whitespace().nth(1) {
+            let client_random = client_random.to_ascii_lowercase();
+            let mut state = self.state.lock().unwrap();
+            if !state.keylog_lines.contains_key(&client_random) {
+                if state.keylog_order.len() >= MAX_UNCLAIMED_KEYLOGS {
+                    if let Some(oldest) = state.keylog_order.pop_front() {
+                        state.keylog_lines.remove(&oldest);
+                    }
+                }
+                state.keylog_order.push_back(client_random.clone());
+            }
+            state
+                .keylog_lines
+                .entry(client_random)
+                .or_default()
+                .push(line.to_string());
+        }
+    }
+
+    /// Register a new connection and return its connection id
+    ///
+    /// `ssl` should be provided for TLS connections after the handshake finished.
+    pub fn register_connection(
+        &self,
+        role: &'static str,
+        peer: SocketAddr,
+        ssl: Option<&SslRef>,
+    ) -> u64 {
+        let (client_random, session_id, tls_version, cipher) = match ssl {
+            Some(ssl) => {
+                let mut buf = [0; 32];
+                let len = ssl.client_random(&mut buf);
+                (
+                    Some(to_hex(&buf[..len])),
+                    ssl.session().map(|session| to_hex(session.id())),
+                    Some(ssl.version_str()),
+                    ssl.current_cipher().map(|cipher| cipher.name()),
+                )
+            }
+            None => (None, None, None, None),
+        };
+
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_connection_id;
+        state.next_connection_id += 1;
+        state.connections.insert(
+            id,
+            ConnectionEntry {
+                role,
+                peer,
+                client_random,
+                session_id,
+                tls_version,
+                cipher,
+                strategy: None,
+                start: Utc::now(),
+            },
+        );
+        id
+    }
+
+    /// Register a new connection, which is finished once the returned [`RegisteredConnection`] is dropped
+    ///
+    /// See [`SessionRegistry::register_connection`].
+    pub fn open_connection(
+        self: &Arc<Self>,
+        role: &'static str,
+        peer: SocketAddr,
+        ssl: Option<&SslRef>,
+    ) -> RegisteredConnection {
+        RegisteredConnection {
+            registry: self.clone(),
+            id: self.register_connection(role, peer, ssl),
+            finished: false,
+        }
+    }
+
+    /// Record the full strategy description used for all connections starting from now on
+    ///
+    /// Previous configs are kept in the index, such that the config of a connection can be found by its start time.
+    pub fn set_strategy_config(&self, config: &StrategyConfig) -> Result<(), Error> {
+        let now = Utc::now();
+        self.append_to_index(&json!({
+            "strategy_config": {
+                "valid_from": format_time(now),
+                "valid_from_unix": unix_time(now),
+                "config": config,
+            }
+        }))
+    }
+
+    /// Record the padding strategy selected for the connection
+    pub fn set_strategy(&self, id: u64, strategy: &Strategy) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(conn) = state.connections.get_mut(&id) {
+            conn.strategy = Some(strategy.to_string());
+        }
+    }
+
+    /// Mark the connection as closed and append it to the index
+    ///
+    /// The connection and its keylog lines are removed from the registry afterwards.
+    pub fn finish_connection(&self, id: u64, bytes: TransferredBytes) -> Result<(), Error> {
+        self.finish_connection_with_status(id, bytes, "closed")
+    }
+
+    fn finish_connection_with_status(
+        &self,
+        id: u64,
+        bytes: TransferredBytes,
+        status: &'static str,
+    ) -> Result<(), Error> {
+        let end = Utc::now();
+        let entry = {
+            let mut state = self.state.lock().unwrap();
+            let conn = match state.connections.remove(&id) {
+                Some(conn) => conn,
+                None => return Ok(()),
+            };
+            let keylog = conn
+                .client_random
+                .as_ref()
+                .and_then(|cr| {
+                    state.keylog_order.retain(|other| other != cr);
+                    state.keylog_lines.remove(cr)
+                })
+                .unwrap_or_default();
+            json!({
+                "connection": {
+                    "connection_id": id,
+                    "role": conn.role,
+                    "peer": conn.peer.to_string(),
+                    "client_random": conn.client_random,
+                    "session_id": conn.session_id,
+                    "tls_version": conn.tls_version,
+                    "cipher": conn.cipher,
+                    "strategy": conn.strategy,
+                    "start": format_time(conn.start),
+                    "start_unix": unix_time(conn.start),
+                    "end": format_time(end),
+                    "end_unix": unix_time(end),
+                    "bytes_sent": bytes.sent,
+                    "bytes_received": bytes.received,
+                    "status": status,
+                    "keylog": keylog,
+                }
+            })
+        };
+        self.append_to_index(&entry)
+    }
+
+    /// Append a single line to the index file, if one is configured
+    fn append_to_index(&self, entry: &Value) -> Result<(), Error> {
+        if let Some(file) = &self.index_file {
+            let line =
+                serde_json::to_string(entry).expect("Serializing a serde_json::Value cannot fail");
+            let mut file = file.lock().unwrap();
+            writeln!(file, "{}", line)?;
+        }
+        Ok(())
+    }
+}
+
+/// Connection in a [`SessionRegistry`], which is recorded as aborted if it is dropped before [`RegisteredConnection::finish`]
+#[derive(Debug)]
+#[must_use = "The connection is recorded as aborted once dropped"]
+pub struct RegisteredConnection {
+    registry: Arc<SessionRegistry>,
+    id: u64,
+    finished: bool,
+}
+
+impl RegisteredConnection {
+    /// Connection id in the [`SessionRegistry`]
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Mark the connection as closed and append it to the index
+    pub fn finish(mut self, bytes: TransferredBytes) -> Result<(), Error> {
+        self.finished = true;
+        self.registry.finish_connection(self.id, bytes)
+    }
+}
+
+impl Drop for RegisteredConnection {
+    fn drop(&mut self) {
+        if self.finished {
+            return;
+        }
+        // The number of bytes is unknown, since the copying failed or never started
+        if let Err(err) = self.registry.finish_connection_with_status(
+            self.id,
+            TransferredBytes::default(),
+            "aborted",
+        ) {
+            error!("Could not record aborted connection {}: {}", self.id, err);
+        }
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn format_time(time: DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Nanos, true)
+}
+
+/// Seconds since the UNIX epoch, the same format as the timestamps in pcap files
+fn unix_time(time: DateTime<Utc>) -> f64 {
+    time.timestamp() as f64 + f64::from(time.timestamp_subsec_nanos()) / 1_000_000_000.
+}
+
+#[cfg(test)]
+fn registry_with_index() -> (Arc<SessionRegistry>, PathBuf) {
+    let path = std::env::temp_dir().join(format!(
+        "session-index-{}-{}.jsonl",
+        std::process::id(),
+        rand::random::<u64>()
+    ));
+    let registry = SessionRegistry::new(None, Some(path.clone())).unwrap();
+    (registry, path)
+}
+
+#[test]
+fn test_keylog_lines_are_assigned_by_client_random() {
+    let (registry, path) = registry_with_index();
+    registry.record_keylog_line("CLIENT_RANDOM ABCDEF 0123");
+    registry.record_keylog_line("SERVER_TRAFFIC_SECRET_0 abcdef 4567");
+    registry.record_keylog_line("CLIENT_RANDOM 012345 89ab");
+
+    let state = registry.state.lock().unwrap();
+    assert_eq!(state.keylog_lines.len(), 2);
+    assert_eq!(
+        state.keylog_lines["abcdef"],
+        vec![
+            "CLIENT_RANDOM ABCDEF 0123".to_string(),
+            "SERVER_TRAFFIC_SECRET_0 abcdef 4567".to_string()
+        ]
+    );
+    drop(state);
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn test_unclaimed_keylog_lines_are_bounded() {
+    let (registry, path) = registry_with_index();
+    for i in 0..MAX_UNCLAIMED_KEYLOGS + 10 {
+        registry.record_keylog_line(&format!("CLIENT_RANDOM {:08x} 0123", i));
+    }
+    // Additional lines for an existing client random do not evict anything
+    registry.record_keylog_line(&format!("SERVER_TRAFFIC_SECRET_0 {:08x} 4567", 20));
+
+    let state = registry.state.lock().unwrap();
+    assert_eq!(state.keylog_lines.len(), MAX_UNCLAIMED_KEYLOGS);
+    assert_eq!(state.keylog_order.len(), MAX_UNCLAIMED_KEYLOGS);
+    assert!(!state.keylog_lines.contains_key(&format!("{:08x}", 9)));
+    assert!(state.keylog_lines.contains_key(&format!("{:08x}", 10)));
+    assert_eq!(state.keylog_lines[&format!("{:08x}", 20)].len(), 2);
+    drop(state);
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn test_finished_connections_are_appended_and_forgotten() {
+    let (registry, path) = registry_with_index();
+    let peer: SocketAddr = "127.0.0.1:853".parse().unwrap();
+    let first = registry.register_connection("client", peer, None);
+    let second = registry.register_connection("server", peer, None);
+    registry
+        .finish_connection(
+            first,
+            TransferredBytes {
+                sent: 10,
+                received: 20,
+            },
+        )
+        .unwrap();
+    registry
+        .finish_connection(
+            second,
+            TransferredBytes {
+                sent: 30,
+                received: 0,
+            },
+        )
+        .unwrap();
+    // Finishing a connection twice does not add another entry
+    registry
+        .finish_connection(first, TransferredBytes::default())
+        .unwrap();
+    assert!(registry.state.lock().unwrap().connections.is_empty());
+
+    let content = std::fs::read_to_string(&path).unwrap();
+    let lines: Vec<Value> = content
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(lines.len(), 2);
+    assert_eq!(lines[0]["connection"]["connection_id"], first);
+    assert_eq!(lines[0]["connection"]["role"], "client");
+    assert_eq!(lines[0]["connection"]["bytes_sent"], 10);
+    assert_eq!(lines[0]["connection"]["bytes_received"], 20);
+    assert_eq!(lines[1]["connection"]["connection_id"], second);
+    assert_eq!(lines[1]["connection"]["bytes_sent"], 30);
+
+    // A new registry appends to the existing index instead of replacing it
+    let registry = SessionRegistry::new(None, Some(path.clone())).unwrap();
+    let third = registry.register_connection("client", peer, None);
+    registry
+        .finish_connection(third, TransferredBytes::default())
+        .unwrap();
+    let content = std::fs::read_to_string(&path).unwrap();
+    assert_eq!(content.lines().count(), 3);
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn test_failed_handler_records_aborted_connection() {
+    let (registry, path) = registry_with_index();
+    let peer: SocketAddr = "127.0.0.1:853".parse().unwrap();
+    let handler = |fail: bool| -> Result<(), Error> {
+        let conn = registry.open_connection("client", peer, None);
+        if fail {
+            return Err(Error::ClientIo(std::io::ErrorKind::ConnectionReset.into()));
+        }
+        conn.finish(TransferredBytes {
+            sent: 10,
+            received: 20,
+        })
+    };
+    assert!(handler(true).is_err());
+    handler(false).unwrap();
+    assert!(registry.state.lock().unwrap().connections.is_empty());
+
+    let content = std::fs::read_to_string(&path).unwrap();
+    let lines: Vec<Value> = content
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(lines.len(), 2);
+    assert_eq!(lines[0]["connection"]["status"], "aborted");
+    assert_eq!(lines[0]["connection"]["bytes_sent"], 0);
+    assert_eq!(lines[1]["connection"]["status"], "closed");
+    assert_eq!(lines[1]["connection"]["bytes_sent"], 10);
+    std::fs::remove_file(path).unwrap();
+}
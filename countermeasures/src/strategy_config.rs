@@ -0,0 +1,387 @@
+//! Describe the full padding [`Strategy`] in a TOML file
+//!
+//! The subcommands of the binaries only expose the throttles of each strategy.
+//! A strategy config file additionally contains the parameters of the Adaptive Padding state machine.
+//! It is passed with `--strategy-config` instead of the subcommand.
+//! The resolved config is written into the session index, such that each measurement records all parameters.
+//! The client and server binaries read the file again on SIGHUP, which allows changing the parameters during a measurement.
+//!
+//! ```toml
+//! # One of `pass`, `constant`, or `ap`
+//! strategy = "ap"
+//! # Only used by `constant`, in ms
+//! # rate = 50
+//! # Optional upstream resolver, overrides `--server`
+//! server = "1.1.1.1:853"
+//!
+//! # Only used by `ap`, all values in ms
+//! [throttle]
+//! in = 10
+//! out = 20
+//! max-delay = 200
+//!
+//! # Only used by `ap`, missing values use the defaults
+//! [adaptive-padding]
+//! median-burst-length = 4
+//! probability-fake-burst = 0.5
+//! seed = 42
+//!
+//! # Optional ClientHello towards the upstream resolver, replaces all `--tls-*` arguments
+//! [tls]
+//! alpn = ["dot"]
+//! ciphers = "ECDHE-ECDSA-AES128-GCM-SHA256:ECDHE-RSA-AES128-GCM-SHA256"
+//! ciphersuites = "TLS_AES_128_GCM_SHA256"
+//! no-session-tickets = true
+//! sni = "dns.example"
+//! ```
+
+use crate::{
+    AdaptivePaddingConfig, HostnameSocketAddr, HostnameSocketAddrError, Strategy, UpstreamTls,
+};
+use serde::{Deserialize, Serialize};
+use std::{path::Path, time::Duration};
+
+/// Errors while loading a [`StrategyConfig`]
+#[derive(Debug, thiserror::Error)]
+pub enum StrategyConfigError {
+    #[error("Cannot read strategy config '{}': {}", _0, _1)]
+    Io(String, #[source] std::io::Error),
+    #[error("Cannot parse strategy config '{}': {}", _0, _1)]
+    Parse(String, #[source] toml::de::Error),
+    #[error("Invalid strategy config: {}", _0)]
+    Invalid(String),
+    #[error("Invalid server in strategy config: {}", _0)]
+    InvalidServer(#[source] HostnameSocketAddrError),
+    #[error("Specify the strategy either as subcommand or with --strategy-config, but not both")]
+    Conflict,
+    #[error("No strategy given, specify it as subcommand or with --strategy-config")]
+    Missing,
+}
+
+/// Name of the strategy in a [`StrategyConfig`]
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum StrategyKind {
+    /// [`Strategy::PassThrough`]
+    Pass,
+    /// [`Strategy::Constant`]
+    Constant,
+    /// [`Strategy::AdaptivePadding`]
+    Ap,
+}
+
+/// Throttles around Adaptive Padding, see [`Strategy::AdaptivePadding`]
+///
+/// All values are in milliseconds.
+#[derive(Copy, Clone, PartialEq, Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ThrottleConfig {
+    #[serde(rename = "in", skip_serializing_if = "Option::is_none")]
+    pub throttle_in: Option<f64>,
+    #[serde(rename = "out", skip_serializing_if = "Option::is_none")]
+    pub throttle_out: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_delay: Option<f64>,
+}
+
+/// Complete description of a padding strategy, including the parameters not available on the command line
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct StrategyConfig {
+    pub strategy: StrategyKind,
+    /// Rate of the constant strategy in milliseconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rate: Option<f64>,
+    /// Upstream resolver as `host:port`, which takes precedence over the `--server` argument
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub server: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub throttle: Option<ThrottleConfig>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub adaptive_padding: Option<AdaptivePaddingConfig>,
+    /// TLS options towards the upstream resolver, which replace the `--tls-*` arguments
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tls: Option<UpstreamTls>,
+}
+
+impl StrategyConfig {
+    /// Describe a `strategy` given on the command line, which uses the default [`AdaptivePaddingConfig`]
+    pub fn new(strategy: &Strategy) -> Self {
+        fn ms(d: Duration) -> f64 {
+            d.as_secs_f64() * 1000.
+        }
+
+        match strategy {
+            Strategy::PassThrough => Self {
+                strategy: StrategyKind::Pass,
+                rate: None,
+                server: None,
+                throttle: None,
+                adaptive_padding: None,
+                tls: None,
+            },
+            Strategy::Constant { rate } => Self {
+                strategy: StrategyKind::Constant,
+                rate: Some(ms(*rate)),
+                server: None,
+                throttle: None,
+                adaptive_padding: None,
+                tls: None,
+            },
+            Strategy::AdaptivePadding {
+                throttle_in,
+                throttle_out,
+                max_delay,
+            } => Self {
+                strategy: StrategyKind::Ap,
+                rate: None,
+                server: None,
+                throttle: Some(ThrottleConfig {
+                    throttle_in: throttle_in.map(ms),
+                    throttle_out: throttle_out.map(ms),
+                    max_delay: max_delay.map(ms),
+                }),
+                adaptive_padding: Some(AdaptivePaddingConfig::default()),
+                tls: None,
+            },
+        }
+    }
+
+    /// Load and validate the config from the TOML file at `path`
+    pub fn load(path: &Path) -> Result<Self, StrategyConfigError> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|err| StrategyConfigError::Io(path.display().to_string(), err))?;
+        let config: Self = toml::from_str(&content)
+            .map_err(|err| StrategyConfigError::Parse(path.display().to_string(), err))?;
+        config.strategy()?;
+        config.ap_config()?;
+        config.upstream_tls()?;
+        Ok(config)
+    }
+
+    /// Take the strategy either from the config file at `path` or from the subcommand
+    ///
+    /// Exactly one of both must be given.
+    pub fn from_args(
+        path: Option<&Path>,
+        strategy: Option<&Strategy>,
+    ) -> Result<Self, StrategyConfigError> {
+        match (path, strategy) {
+            (Some(path), None) => Self::load(path),
+            (None, Some(strategy)) => Ok(Self::new(strategy)),
+            (Some(_), Some(_)) => Err(StrategyConfigError::Conflict),
+            (None, None) => Err(StrategyConfigError::Missing),
+        }
+    }
+
+    /// The [`Strategy`] described by this config
+    pub fn strategy(&self) -> Result<Strategy, StrategyConfigError> {
+        let invalid = |msg: &str| Err(StrategyConfigError::Invalid(msg.to_string()));
+
+        match self.strategy {
+            StrategyKind::Pass | StrategyKind::Constant if self.throttle.is_some() => {
+                invalid("The throttle is only supported by the `ap` strategy")
+            }
+            StrategyKind::Pass | StrategyKind::Constant if self.adaptive_padding.is_some() => {
+                invalid("The adaptive-padding parameters are only supported by the `ap` strategy")
+            }
+            StrategyKind::Pass | StrategyKind::Ap if self.rate.is_some() => {
+                invalid("The rate is only supported by the `constant` strategy")
+            }
+            StrategyKind::Pass => Ok(Strategy::PassThrough),
+            StrategyKind::Constant => match self.rate {
+                Some(rate) => Ok(Strategy::Constant {
+                    rate: duration_ms("rate", rate)?,
+                }),
+                None => invalid("The `constant` strategy requires a rate"),
+            },
+            StrategyKind::Ap => {
+                let throttle = self.throttle.unwrap_or_default();
+                let duration =
+                    |name, ms: Option<f64>| ms.map(|ms| duration_ms(name, ms)).transpose();
+                Ok(Strategy::AdaptivePadding {
+                    throttle_in: duration("throttle.in", throttle.throttle_in)?,
+                    throttle_out: duration("throttle.out", throttle.throttle_out)?,
+                    max_delay: duration("throttle.max-delay", throttle.max_delay)?,
+                })
+            }
+        }
+    }
+
+    /// The upstream resolver, if the config overrides the `--server` argument
+    ///
+    /// The hostname of the server is not resolved yet, see [`HostnameSocketAddr::unresolved`].
+    pub fn server(&self) -> Result<Option<HostnameSocketAddr>, StrategyConfigError> {
+        self.server
+            .as_deref()
+            .map(HostnameSocketAddr::unresolved)
+            .transpose()
+            .map_err(StrategyConfigError::InvalidServer)
+    }
+
+    /// The parameters of the Adaptive Padding state machine
+    pub fn ap_config(&self) -> Result<AdaptivePaddingConfig, StrategyConfigError> {
+        let config = self.adaptive_padding.unwrap_or_default();
+        if config.median_burst_length < 2 {
+            return Err(StrategyConfigError::Invalid(format!(
+                "The median-burst-length must be at least 2, but is {}",
+                config.median_burst_length
+            )));
+        }
+        if !(config.probability_fake_burst > 0. && config.probability_fake_burst <= 1.) {
+            return Err(StrategyConfigError::Invalid(format!(
+                "The probability-fake-burst must be in the range (0, 1], but is {}",
+                config.probability_fake_burst
+            )));
+        }
+        Ok(config)
+    }
+
+    /// The TLS options towards the upstream resolver, if the config replaces the `--tls-*` arguments
+    pub fn upstream_tls(&self) -> Result<Option<&UpstreamTls>, StrategyConfigError> {
+        match &self.tls {
+            Some(tls) => {
+                tls.validate().map_err(StrategyConfigError::Invalid)?;
+                Ok(Some(tls))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+/// Convert a non-negative number of milliseconds into a [`Duration`]
+fn duration_ms(name: &str, ms: f64) -> Result<Duration, StrategyConfigError> {
+    if !ms.is_finite() || ms < 0. {
+        return Err(StrategyConfigError::Invalid(format!(
+            "The {} must be a non-negative number of milliseconds, but is {}",
+            name, ms
+        )));
+    }
+    Ok(Duration::from_micros((ms * 1000.).round() as u64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_strategy_config() {
+        let config: StrategyConfig = toml::from_str(
+            r#"
+            strategy = "ap"
+
+            [throttle]
+            in = 10
+            max-delay = 200.5
+
+            [adaptive-padding]
+            probability-fake-burst = 0.5
+            seed = 42
+            "#,
+        )
+        .unwrap();
+        match config.strategy().unwrap() {
+            Strategy::AdaptivePadding {
+                throttle_in,
+                throttle_out,
+                max_delay,
+            } => {
+                assert_eq!(throttle_in, Some(Duration::from_millis(10)));
+                assert_eq!(throttle_out, None);
+                assert_eq!(max_delay, Some(Duration::from_micros(200_500)));
+            }
+            strategy => panic!("Unexpected strategy {}", strategy),
+        }
+        assert_eq!(
+            config.ap_config().unwrap(),
+            AdaptivePaddingConfig {
+                median_burst_length: 2,
+                probability_fake_burst: 0.5,
+                seed: Some(42),
+            }
+        );
+
+        let constant: StrategyConfig = toml::from_str(
+            r#"
+            strategy = "constant"
+            rate = 50
+            server = "127.0.0.1:53"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(constant.strategy().unwrap().to_string(), "constant-50");
+        assert_eq!(
+            constant.server().unwrap(),
+            Some("127.0.0.1:53".parse().unwrap())
+        );
+        assert_eq!(config.server().unwrap(), None);
+        assert_eq!(config.upstream_tls().unwrap(), None);
+
+        let tls: StrategyConfig = toml::from_str(
+            r#"
+            strategy = "pass"
+
+            [tls]
+            alpn = ["dot"]
+            ciphersuites = "TLS_AES_128_GCM_SHA256"
+            no-session-tickets = true
+            "#,
+        )
+        .unwrap();
+        let upstream_tls = tls.upstream_tls().unwrap().unwrap();
+        assert_eq!(upstream_tls.alpn, vec!["dot".to_string()]);
+        assert_eq!(upstream_tls.ciphers, None);
+        assert!(upstream_tls.no_session_tickets);
+        let reloaded: StrategyConfig = toml::from_str(&toml::to_string(&tls).unwrap()).unwrap();
+        assert_eq!(reloaded, tls);
+    }
+
+    #[test]
+    fn test_invalid_strategy_config() {
+        let invalid = |s: &str| {
+            let config: StrategyConfig = toml::from_str(s).unwrap();
+            config.strategy().is_err() || config.ap_config().is_err()
+        };
+        assert!(invalid(r#"strategy = "constant""#));
+        assert!(invalid("strategy = \"pass\"\nrate = 10"));
+        assert!(invalid("strategy = \"constant\"\nrate = -1"));
+        assert!(invalid(
+            "strategy = \"constant\"\nrate = 10\n[throttle]\nin = 10"
+        ));
+        assert!(invalid(
+            "strategy = \"ap\"\n[adaptive-padding]\nmedian-burst-length = 1"
+        ));
+        assert!(invalid(
+            "strategy = \"ap\"\n[adaptive-padding]\nprobability-fake-burst = 0"
+        ));
+        let invalid_tls = |s: &str| {
+            let config: StrategyConfig = toml::from_str(s).unwrap();
+            config.upstream_tls().is_err()
+        };
+        assert!(invalid_tls(
+            "strategy = \"pass\"\n[tls]\nciphers = \"NO-SUCH-CIPHER\""
+        ));
+        assert!(invalid_tls("strategy = \"pass\"\n[tls]\nalpn = [\"\"]"));
+        assert!(toml::from_str::<StrategyConfig>("strategy = \"pass\"\n[tls]\nfoo = 1").is_err());
+
+        assert!(toml::from_str::<StrategyConfig>(r#"strategy = "wtf-pad""#).is_err());
+        assert!(
+            toml::from_str::<StrategyConfig>("strategy = \"ap\"\n[throttle]\nfoo = 1").is_err()
+        );
+    }
+
+    #[test]
+    fn test_strategy_config_roundtrip() {
+        for strategy in &["pass", "constant-12.5", "ap", "ap-tin10-tout20-max200"] {
+            let strategy: Strategy = strategy.parse().unwrap();
+            let config = StrategyConfig::new(&strategy);
+            let reloaded: StrategyConfig =
+                toml::from_str(&toml::to_string(&config).unwrap()).unwrap();
+            assert_eq!(reloaded, config);
+            assert_eq!(
+                reloaded.strategy().unwrap().to_string(),
+                strategy.to_string()
+            );
+        }
+    }
+}
@@ -0,0 +1,393 @@
+//! Select the [`Strategy`] per client connection
+//!
+//! This allows running A/B experiments, e.g., defended and undefended clients, through the same proxy instance.
+//! The strategy is chosen by the source address of the client or by an initial magic query.
+//!
+//! Strategies are written in a compact form, which is also a valid DNS label:
+//! * `pass`: [`Strategy::PassThrough`]
+//! * `constant-<ms>`: [`Strategy::Constant`], e.g., `constant-50`
+//! * `ap[-tin<ms>][-tout<ms>][-max<ms>]`: [`Strategy::AdaptivePadding`], e.g., `ap-tin10-tout20-max200`
+
+use crate::{parse_duration_ms, Strategy};
+use std::{
+    fmt::{self, Display},
+    net::IpAddr,
+    str::FromStr,
+    time::Duration,
+};
+use trust_dns_proto::{
+    op::{Message, MessageType, ResponseCode},
+    rr::Name,
+};
+
+/// Queries for `<strategy>.strategy.tlsproxy.invalid.` select the strategy of the connection
+pub const MAGIC_QUERY_SUFFIX: &str = "strategy.tlsproxy.invalid.";
+
+/// Errors while parsing a strategy, a client network, or a [`ClientStrategyRule`]
+#[derive(Debug, thiserror::Error)]
+pub enum StrategyParseError {
+    #[error(
+        "Unknown strategy '{}', expected pass, constant-<ms>, or ap[-tin<ms>][-tout<ms>][-max<ms>]",
+        _0
+    )]
+    UnknownStrategy(String),
+    #[error("Invalid duration in strategy '{}': {}", _0, _1)]
+    InvalidDuration(String, #[source] std::num::ParseFloatError),
+    #[error(
+        "Invalid client network '{}', expected an IP address with optional prefix length",
+        _0
+    )]
+    InvalidNetwork(String),
+    #[error("The client strategy '{}' must have the form <network>=<strategy>", _0)]
+    InvalidRule(String),
+}
+
+impl FromStr for Strategy {
+    type Err = StrategyParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse_ms = |ms: &str| {
+            parse_duration_ms(ms)
+                .map_err(|err| StrategyParseError::InvalidDuration(s.to_string(), err))
+        };
+
+        let mut parts = s.split('-');
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some("pass"), None, ..) => Ok(Strategy::PassThrough),
+            (Some("constant"), Some(rate), None) => Ok(Strategy::Constant {
+                rate: parse_ms(rate)?,
+            }),
+            (Some("ap"), first, second) => {
+                let mut throttle_in = None;
+                let mut throttle_out = None;
+                let mut max_delay = None;
+                for part in first.into_iter().chain(second).chain(parts) {
+                    // Check `tout` first, since `tin` is not a prefix of it
+                    let (option, ms) = if let Some(ms) = part.strip_prefix("tout") {
+                        (&mut throttle_out, ms)
+                    } else if let Some(ms) = part.strip_prefix("tin") {
+                        (&mut throttle_in, ms)
+                    } else if let Some(ms) = part.strip_prefix("max") {
+                        (&mut max_delay, ms)
+                    } else {
+                        return Err(StrategyParseError::UnknownStrategy(s.to_string()));
+                    };
+                    // Each option may only be given once
+                    if option.replace(parse_ms(ms)?).is_some() {
+                        return Err(StrategyParseError::UnknownStrategy(s.to_string()));
+                    }
+                }
+                Ok(Strategy::AdaptivePadding {
+                    throttle_in,
+                    throttle_out,
+                    max_delay,
+                })
+            }
+            _ => Err(StrategyParseError::UnknownStrategy(s.to_string())),
+        }
+    }
+}
+
+impl Display for Strategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        fn ms(d: Duration) -> f64 {
+            d.as_secs_f64() * 1000.
+        }
+
+        match self {
+            Strategy::PassThrough => write!(f, "pass"),
+            Strategy::Constant { rate } => write!(f, "constant-{}", ms(*rate)),
+            Strategy::AdaptivePadding {
+                throttle_in,
+                throttle_out,
+                max_delay,
+            } => {
+                write!(f, "ap")?;
+                if let Some(tin) = throttle_in {
+                    write!(f, "-tin{}", ms(*tin))?;
+                }
+                if let Some(tout) = throttle_out {
+                    write!(f, "-tout{}", ms(*tout))?;
+                }
+                if let Some(max_delay) = max_delay {
+                    write!(f, "-max{}", ms(*max_delay))?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// An IP network given by an address and a prefix length, e.g., `10.0.0.0/8`
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub struct ClientNetwork {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl ClientNetwork {
+    /// Check if `ip` is part of this network
+    ///
+    /// IPv4 addresses never match IPv6 networks and vice versa.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                prefix_matches(&net.octets(), &ip.octets(), self.prefix_len)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_matches(&net.octets(), &ip.octets(), self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Compare the first `prefix_len` bits of `a` and `b`
+fn prefix_matches(a: &[u8], b: &[u8], prefix_len: u8) -> bool {
+    let full_bytes = usize::from(prefix_len / 8);
+    let rest_bits = prefix_len % 8;
+    if a[..full_bytes] != b[..full_bytes] {
+        return false;
+    }
+    if rest_bits == 0 {
+        return true;
+    }
+    let mask = 0xff_u8 << (8 - rest_bits);
+    a[full_bytes] & mask == b[full_bytes] & mask
+}
+
+impl FromStr for ClientNetwork {
+    type Err = StrategyParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || StrategyParseError::InvalidNetwork(s.to_string());
+        let mut parts = s.splitn(2, '/');
+        let addr: IpAddr = parts
+            .next()
+            .unwrap_or_default()
+            .parse()
+            .map_err(|_| err())?;
+        let max_prefix_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match parts.next() {
+            Some(len) => len.parse().map_err(|_| err())?,
+            None => max_prefix_len,
+        };
+        if prefix_len > max_prefix_len {
+            return Err(err());
+        }
+        Ok(Self { addr, prefix_len })
+    }
+}
+
+impl Display for ClientNetwork {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+/// Use `strategy` for all clients from `network`, written as `<network>=<strategy>`
+#[derive(Clone, Debug)]
+pub struct ClientStrategyRule {
+    pub network: ClientNetwork,
+    pub strategy: Strategy,
+}
+
+impl FromStr for ClientStrategyRule {
+    type Err = StrategyParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<_> = s.splitn(2, '=').collect();
+        if parts.len() != 2 {
+            return Err(StrategyParseError::InvalidRule(s.to_string()));
+        }
+        Ok(Self {
+            network: parts[0].parse()?,
+            strategy: parts[1].parse()?,
+        })
+    }
+}
+
+/// Choose the [`Strategy`] of each client connection
+#[derive(Clone, Debug)]
+pub struct StrategySelector {
+    default: Strategy,
+    rules: Vec<ClientStrategyRule>,
+    magic_queries: bool,
+}
+
+impl StrategySelector {
+    /// Create a selector which falls back to `default` if no rule matches
+    ///
+    /// Magic queries are only recognized if `magic_queries` is set, such that normal clients cannot change the strategy.
+    pub fn new(default: Strategy, rules: Vec<ClientStrategyRule>, magic_queries: bool) -> Self {
+        Self {
+            default,
+            rules,
+            magic_queries,
+        }
+    }
+
+    /// Return the strategy used for all clients without a matching rule
+    pub fn default_strategy(&self) -> &Strategy {
+        &self.default
+    }
+
+    /// Return the strategy for a client with address `ip`
+    ///
+    /// The first matching rule wins.
+    pub fn for_client(&self, ip: IpAddr) -> &Strategy {
+        self.rules
+            .iter()
+            .find(|rule| rule.network.contains(ip))
+            .map(|rule| &rule.strategy)
+            .unwrap_or(&self.default)
+    }
+
+    /// Return the strategy selected by `msg`, if it is a magic query
+    ///
+    /// Returns `None` for all other messages, which need to be forwarded as usual.
+    pub fn magic_query(&self, msg: &Message) -> Option<Result<Strategy, StrategyParseError>> {
+        if !self.magic_queries {
+            return None;
+        }
+        let suffix = Name::from_ascii(MAGIC_QUERY_SUFFIX).expect("The suffix is a valid name");
+        match msg.queries() {
+            [query] if query.name().num_labels() == suffix.num_labels() + 1 => {
+                if !suffix.zone_of(query.name()) {
+                    return None;
+                }
+                let label = query.name().iter().next()?;
+                Some(String::from_utf8_lossy(label).parse())
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Create the empty response to a magic query
+pub fn magic_query_response(query: &Message) -> Message {
+    let mut response = Message::new();
+    response
+        .set_id(query.id())
+        .set_message_type(MessageType::Response)
+        .set_op_code(query.op_code())
+        .set_recursion_desired(query.recursion_desired())
+        .set_recursion_available(true)
+        .set_response_code(ResponseCode::NoError)
+        .add_queries(query.queries().iter().cloned());
+    response
+}
+
+#[cfg(test)]
+mod test_strategy_selection {
+    use super::*;
+    use trust_dns_proto::{op::Query, rr::RecordType};
+
+    #[test]
+    fn test_strategy_roundtrip() {
+        for s in &[
+            "pass",
+            "constant-50",
+            "ap",
+            "ap-tin10",
+            "ap-tout2.5",
+            "ap-tin10-tout20",
+            "ap-max100",
+            "ap-tin10-tout20-max200",
+        ] {
+            let strategy: Strategy = s.parse().unwrap();
+            assert_eq!(*s, strategy.to_string());
+        }
+        assert!(matches!(
+            "ap-tout20-tin10".parse::<Strategy>().unwrap(),
+            Strategy::AdaptivePadding {
+                throttle_in: Some(_),
+                throttle_out: Some(_),
+                max_delay: None,
+            }
+        ));
+
+        assert!("constant".parse::<Strategy>().is_err());
+        assert!("constant-abc".parse::<Strategy>().is_err());
+        assert!("pass-1".parse::<Strategy>().is_err());
+        assert!("ap-foo".parse::<Strategy>().is_err());
+        assert!("ap-tin10-tin20".parse::<Strategy>().is_err());
+        assert!("unknown".parse::<Strategy>().is_err());
+    }
+
+    #[test]
+    fn test_client_network() {
+        let net: ClientNetwork = "10.1.0.0/16".parse().unwrap();
+        assert!(net.contains("10.1.2.3".parse().unwrap()));
+        assert!(!net.contains("10.2.0.1".parse().unwrap()));
+        assert!(!net.contains("::1".parse().unwrap()));
+
+        let net: ClientNetwork = "192.168.0.128/25".parse().unwrap();
+        assert!(net.contains("192.168.0.200".parse().unwrap()));
+        assert!(!net.contains("192.168.0.100".parse().unwrap()));
+
+        let net: ClientNetwork = "127.0.0.1".parse().unwrap();
+        assert_eq!("127.0.0.1/32", net.to_string());
+        assert!(net.contains("127.0.0.1".parse().unwrap()));
+        assert!(!net.contains("127.0.0.2".parse().unwrap()));
+
+        let net: ClientNetwork = "::/0".parse().unwrap();
+        assert!(net.contains("2001:db8::1".parse().unwrap()));
+
+        assert!("10.0.0.0/33".parse::<ClientNetwork>().is_err());
+        assert!("example.com/8".parse::<ClientNetwork>().is_err());
+    }
+
+    #[test]
+    fn test_selector() {
+        let rules = vec![
+            "127.0.0.2=pass".parse().unwrap(),
+            "127.0.0.0/8=constant-50".parse().unwrap(),
+        ];
+        let selector = StrategySelector::new(Strategy::PassThrough, rules, true);
+        assert_eq!(
+            "pass",
+            selector
+                .for_client("127.0.0.2".parse().unwrap())
+                .to_string()
+        );
+        assert_eq!(
+            "constant-50",
+            selector
+                .for_client("127.0.0.1".parse().unwrap())
+                .to_string()
+        );
+        assert_eq!(
+            "pass",
+            selector.for_client("10.0.0.1".parse().unwrap()).to_string()
+        );
+
+        let mut msg = Message::new();
+        msg.add_query(Query::query(
+            Name::from_ascii("ap-tin10.strategy.tlsproxy.invalid.").unwrap(),
+            RecordType::A,
+        ));
+        let strategy = selector.magic_query(&msg).unwrap().unwrap();
+        assert_eq!("ap-tin10", strategy.to_string());
+
+        let response = magic_query_response(&msg);
+        assert_eq!(msg.id(), response.id());
+        assert_eq!(MessageType::Response, response.message_type());
+        assert_eq!(msg.queries(), response.queries());
+
+        let mut msg = Message::new();
+        msg.add_query(Query::query(
+            Name::from_ascii("www.example.com.").unwrap(),
+            RecordType::A,
+        ));
+        assert!(selector.magic_query(&msg).is_none());
+
+        let selector = StrategySelector::new(Strategy::PassThrough, vec![], false);
+        let mut msg = Message::new();
+        msg.add_query(Query::query(
+            Name::from_ascii("ap.strategy.tlsproxy.invalid.").unwrap(),
+            RecordType::A,
+        ));
+        assert!(selector.magic_query(&msg).is_none());
+    }
+}
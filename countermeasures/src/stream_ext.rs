@@ -0,0 +1,128 @@
+//! Apply the traffic shaping adapters to arbitrary streams
+//!
+//! The adapters are independent of DNS and work for any [`Stream`].
+//! They all produce [`Payload`](crate::Payload) items, where [`Payload::Dummy`](crate::Payload::Dummy) marks the positions at which the caller needs to send cover traffic.
+//! All timing is based on the tokio timer, which means a tokio runtime with the time driver must be running.
+
+use crate::{AdaptivePadding, AdaptivePaddingConfig, ConstantRate, PassThrough};
+use futures::Stream;
+use std::time::Duration;
+
+/// Extension trait providing the traffic shaping adapters as combinators
+pub trait PayloadStreamExt: Stream {
+    /// Forward all items without any delay, see [`PassThrough`]
+    fn pass_through(self) -> PassThrough<Self>
+    where
+        Self: Sized + Unpin,
+    {
+        PassThrough::new(self)
+    }
+
+    /// Emit exactly one item every `rate`, see [`ConstantRate`]
+    fn constant_rate(self, rate: Duration) -> ConstantRate<Self>
+    where
+        Self: Sized + Unpin,
+    {
+        ConstantRate::new(self, rate)
+    }
+
+    /// Insert dummy items according to Adaptive Padding, see [`AdaptivePadding`]
+    fn adaptive_padding(self, config: AdaptivePaddingConfig) -> AdaptivePadding<Self::Item>
+    where
+        Self: Sized + Send + Unpin + 'static,
+        Self::Item: Send + 'static,
+    {
+        AdaptivePadding::with_config(self, config)
+    }
+}
+
+impl<S> PayloadStreamExt for S where S: Stream + ?Sized {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        virtual_time::{payloads, run_paused, scripted, timeline},
+        Payload,
+    };
+    use futures::stream;
+    use tokio::time::Instant;
+
+    const MS_10: Duration = Duration::from_millis(10);
+
+    #[test]
+    fn test_pass_through_keeps_timing() {
+        let res = run_paused(async {
+            let start = Instant::now();
+            timeline(start, scripted(start, &[0, 5, 25]).pass_through()).await
+        });
+        assert_eq!(
+            res,
+            vec![
+                (0, Payload::Payload(0)),
+                (5, Payload::Payload(1)),
+                (25, Payload::Payload(2)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_constant_rate_schedule() {
+        let res = run_paused(async {
+            let start = Instant::now();
+            timeline(start, scripted(start, &[5, 25]).constant_rate(MS_10)).await
+        });
+        assert_eq!(
+            res,
+            vec![
+                (0, Payload::Dummy),
+                (10, Payload::Payload(0)),
+                (20, Payload::Dummy),
+                (30, Payload::Payload(1)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_constant_rate_delays_bursts() {
+        // All items are available immediately, but only one is emitted per tick
+        let res = run_paused(async {
+            let start = Instant::now();
+            timeline(start, stream::iter(0..3).constant_rate(MS_10)).await
+        });
+        assert_eq!(
+            res,
+            vec![
+                (0, Payload::Payload(0)),
+                (10, Payload::Payload(1)),
+                (20, Payload::Payload(2)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_adaptive_padding_forwards_payload_immediately() {
+        let offsets = [0, 3, 50, 51, 400];
+        for _ in 0..20 {
+            let res = run_paused(async {
+                let start = Instant::now();
+                timeline(
+                    start,
+                    scripted(start, &offsets).adaptive_padding(AdaptivePaddingConfig::default()),
+                )
+                .await
+            });
+
+            let expected: Vec<_> = offsets
+                .iter()
+                .enumerate()
+                .map(|(i, offset)| (u128::from(*offset), i))
+                .collect();
+            assert_eq!(payloads(&res), expected);
+            // No dummies are created before the first payload, since the state machine starts idle
+            assert_eq!(res[0], (0, Payload::Payload(0)));
+            // The stream ends together with the underlying stream
+            assert!(res.last().unwrap().0 <= 400);
+        }
+    }
+}
@@ -1,47 +1,142 @@
 //! Slow down a stream by enforcing a delay between items.
 
+use crate::clock::{Clock, RealClock, Timer};
 use futures::{ready, Stream};
+use log::{info, warn};
 use std::{
+    collections::VecDeque,
     future::Future,
     marker::Unpin,
     pin::Pin,
     task::{Context, Poll},
 };
-use tokio::time::{self, Delay, Duration, Instant};
+use tokio::time::{Duration, Instant};
 
 /// Slow down a stream by enforcing a delay between items.
+///
+/// The items are read eagerly from the underlying stream and their queuing delay is tracked in the [`ThrottleStats`].
+/// With [`Throttle::with_max_delay`] items which are queued for longer than the maximal delay bypass the throttle, which bounds the added latency.
+/// [`Throttle::with_tracked_items`] restricts the statistics and the bypass to some items, e.g., to the queries but not the dummies.
 #[derive(Debug)]
 #[must_use = "streams do nothing unless polled"]
-pub struct Throttle<T> {
+pub struct Throttle<T: Stream, C: Clock = RealClock> {
     /// `None` when duration is zero.
-    delay: Option<(Delay, Duration)>,
+    delay: Option<(C::Timer, Duration)>,
 
     /// Set to true when `delay` has returned ready, but `stream` hasn't.
     has_delayed: bool,
 
     /// The stream to throttle
     stream: T,
+
+    /// Maximal time an item may be queued before it bypasses the throttle
+    max_delay: Option<Duration>,
+    /// Fires once the oldest tracked item exceeds `max_delay`
+    bypass: Option<C::Timer>,
+    /// Selects the items which are counted in `stats` and may bypass the throttle
+    tracked: fn(&T::Item) -> bool,
+    /// Items read ahead from `stream` together with the time they became available
+    queue: VecDeque<(Instant, T::Item)>,
+    /// Set to true when `stream` has ended
+    stream_done: bool,
+
+    stats: ThrottleStats,
+    clock: C,
+}
+
+/// Queuing statistics of a [`Throttle`]
+///
+/// Only the tracked items are counted, see [`Throttle::with_tracked_items`].
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct ThrottleStats {
+    /// Number of items forwarded by the throttle
+    pub items: u64,
+    /// Number of items which bypassed the throttle, because they exceeded the maximal queuing delay
+    pub bypassed: u64,
+    /// Sum of the queuing delays of all items
+    pub total_delay: Duration,
+    /// Largest queuing delay of any item
+    pub max_delay: Duration,
 }
 
-impl<T> Throttle<T> {
+impl ThrottleStats {
+    /// Average queuing delay per item
+    pub fn mean_delay(&self) -> Duration {
+        if self.items == 0 {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_secs_f64(self.total_delay.as_secs_f64() / self.items as f64)
+        }
+    }
+
+    fn record(&mut self, delay: Duration, bypassed: bool) {
+        self.items += 1;
+        if bypassed {
+            self.bypassed += 1;
+        }
+        self.total_delay += delay;
+        self.max_delay = self.max_delay.max(delay);
+    }
+}
+
+impl<T: Stream> Throttle<T> {
     /// Slow down a stream by enforcing a delay between items.
     pub fn new(stream: T, duration: Duration) -> Self {
+        Self::with_clock(stream, duration, RealClock)
+    }
+}
+
+impl<T: Stream, C: Clock> Throttle<T, C> {
+    /// Slow down a stream by enforcing a delay between items, which is measured by `clock`
+    pub fn with_clock(stream: T, duration: Duration, clock: C) -> Self {
         let delay = if duration == Duration::from_millis(0) {
             None
         } else {
-            Some((time::delay_for(duration), duration))
+            Some((clock.timer_for(duration), duration))
         };
 
         Self {
             delay,
             has_delayed: true,
             stream,
+            max_delay: None,
+            bypass: None,
+            tracked: |_| true,
+            queue: VecDeque::new(),
+            stream_done: false,
+            stats: ThrottleStats::default(),
+            clock,
         }
     }
+
+    /// Bound the time an item can be queued by the throttle
+    ///
+    /// Tracked items which are queued for longer than `max_delay` are forwarded immediately, ignoring the throttle.
+    /// Each bypass is logged and counted in [`ThrottleStats::bypassed`].
+    /// `None` disables the limit and restores the default behavior.
+    pub fn with_max_delay(mut self, max_delay: Option<Duration>) -> Self {
+        self.max_delay = max_delay;
+        self
+    }
+
+    /// Only track the items for which `tracked` returns `true`
+    ///
+    /// Only the tracked items are counted in the [`ThrottleStats`] and may bypass the throttle.
+    /// All other items always wait for the throttle.
+    /// By default, all items are tracked.
+    pub fn with_tracked_items(mut self, tracked: fn(&T::Item) -> bool) -> Self {
+        self.tracked = tracked;
+        self
+    }
+
+    /// Queuing statistics for all tracked items forwarded so far
+    pub fn stats(&self) -> ThrottleStats {
+        self.stats
+    }
 }
 
 // XXX: are these safe if `T: !Unpin`?
-impl<T: Unpin> Throttle<T> {
+impl<T: Stream + Unpin, C: Clock> Throttle<T, C> {
     /// Acquires a reference to the underlying stream that this combinator is
     /// pulling from.
     pub fn get_ref(&self) -> &T {
@@ -66,33 +161,214 @@ impl<T: Unpin> Throttle<T> {
     }
 }
 
-impl<T: Stream> Stream for Throttle<T> {
+impl<T: Stream, C: Clock> Throttle<T, C> {
+    /// Read all available items from `stream` into the queue
+    fn poll_fill_queue(self: Pin<&mut Self>, cx: &mut Context<'_>) {
+        let this = unsafe { self.get_unchecked_mut() };
+        while !this.stream_done {
+            match unsafe { Pin::new_unchecked(&mut this.stream) }.poll_next(cx) {
+                Poll::Ready(Some(item)) => this.queue.push_back((this.clock.now(), item)),
+                Poll::Ready(None) => this.stream_done = true,
+                Poll::Pending => break,
+            }
+        }
+        if this.bypass.is_none() {
+            this.bypass = this.next_bypass();
+        }
+    }
+
+    /// Timer which fires once the oldest queued tracked item exceeds `max_delay`
+    fn next_bypass(&self) -> Option<C::Timer> {
+        let max_delay = self.max_delay?;
+        let (arrival, _) = self.queue.iter().find(|(_, item)| (self.tracked)(item))?;
+        Some(self.clock.timer_at(*arrival + max_delay))
+    }
+
+    /// Forward the oldest queued item, or the oldest tracked item if the throttle is bypassed
+    fn pop_queue(self: Pin<&mut Self>, bypassed: bool) -> Option<T::Item> {
+        let this = unsafe { self.get_unchecked_mut() };
+        let index = if bypassed {
+            this.queue
+                .iter()
+                .position(|(_, item)| (this.tracked)(item))?
+        } else {
+            0
+        };
+        let (arrival, item) = this.queue.remove(index)?;
+        if (this.tracked)(&item) {
+            let queuing_delay = this.clock.now() - arrival;
+            this.stats.record(queuing_delay, bypassed);
+            if bypassed {
+                warn!(
+                    "Bypassing throttle for item queued for {:?} (bypassed {} of {} items)",
+                    queuing_delay, this.stats.bypassed, this.stats.items
+                );
+            }
+        }
+        this.bypass = this.next_bypass();
+        Some(item)
+    }
+}
+
+impl<T: Stream, C: Clock> Stream for Throttle<T, C> {
     type Item = T::Item;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        unsafe {
-            if !self.has_delayed && self.delay.is_some() {
-                ready!(self
-                    .as_mut()
-                    .map_unchecked_mut(|me| &mut me.delay.as_mut().unwrap().0)
-                    .poll(cx));
-                self.as_mut().get_unchecked_mut().has_delayed = true;
+        self.as_mut().poll_fill_queue(cx);
+        if self.queue.is_empty() {
+            if self.stream_done {
+                let stats = self.stats;
+                info!(
+                    "Throttle forwarded {} items, {} bypassed the throttle, queuing delay mean {:?} max {:?}",
+                    stats.items,
+                    stats.bypassed,
+                    stats.mean_delay(),
+                    stats.max_delay,
+                );
+                return Poll::Ready(None);
             }
+            return Poll::Pending;
+        }
 
-            let value = ready!(self
-                .as_mut()
-                .map_unchecked_mut(|me| &mut me.stream)
-                .poll_next(cx));
+        unsafe {
+            let this = self.as_mut().get_unchecked_mut();
+            if !this.has_delayed {
+                match &mut this.delay {
+                    Some((delay, _)) => {
+                        if Pin::new(delay).poll(cx).is_ready() {
+                            this.has_delayed = true;
+                        }
+                    }
+                    None => this.has_delayed = true,
+                }
+            }
 
-            if value.is_some() {
-                if let Some((ref mut delay, duration)) = self.as_mut().get_unchecked_mut().delay {
-                    delay.reset(Instant::now() + duration);
+            let bypassed = if this.has_delayed {
+                false
+            } else {
+                match &mut this.bypass {
+                    Some(bypass) => {
+                        ready!(Pin::new(bypass).poll(cx));
+                        true
+                    }
+                    None => return Poll::Pending,
                 }
+            };
 
-                self.as_mut().get_unchecked_mut().has_delayed = false;
+            if let Some((ref mut delay, duration)) = this.delay {
+                delay.reset(this.clock.now() + duration);
             }
-
-            Poll::Ready(value)
+            this.has_delayed = false;
+            Poll::Ready(self.pop_queue(bypassed))
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        virtual_time::{run_paused, scripted, scripted_with_clock},
+        VirtualClock,
+    };
+    use futures::StreamExt;
+
+    const MS_10: Duration = Duration::from_millis(10);
+
+    /// Throttle items arriving at `offsets_ms` and return the emission times with the final statistics
+    fn run_throttle(
+        offsets_ms: &[u64],
+        max_delay: Option<Duration>,
+    ) -> (Vec<(u128, usize)>, ThrottleStats) {
+        run_paused(async {
+            let start = Instant::now();
+            let mut throttle = Box::pin(
+                Throttle::new(scripted(start, offsets_ms), MS_10).with_max_delay(max_delay),
+            );
+            let mut res = Vec::new();
+            while let Some(item) = throttle.next().await {
+                res.push(((Instant::now() - start).as_millis(), item));
+            }
+            (res, throttle.stats())
+        })
+    }
+
+    #[test]
+    fn test_throttle_spaces_items() {
+        let (res, stats) = run_throttle(&[0, 1, 2, 35], None);
+        assert_eq!(res, vec![(0, 0), (10, 1), (20, 2), (35, 3)]);
+        // The queuing delays are tracked even without a maximal delay
+        assert_eq!(stats.items, 4);
+        assert_eq!(stats.bypassed, 0);
+        assert_eq!(stats.max_delay, Duration::from_millis(18));
+    }
+
+    #[test]
+    fn test_throttle_max_delay_not_reached() {
+        let (res, stats) = run_throttle(&[0, 1, 2, 35], Some(Duration::from_millis(50)));
+        assert_eq!(res, vec![(0, 0), (10, 1), (20, 2), (35, 3)]);
+        assert_eq!(stats.items, 4);
+        assert_eq!(stats.bypassed, 0);
+        // Item 2 is queued from 2 ms to 20 ms
+        assert_eq!(stats.max_delay, Duration::from_millis(18));
+        assert_eq!(stats.total_delay, Duration::from_millis(9 + 18));
+    }
+
+    #[test]
+    fn test_throttle_max_delay_bypass() {
+        let (res, stats) = run_throttle(&[0, 1, 2, 3, 4, 50], Some(Duration::from_millis(15)));
+        assert_eq!(
+            res,
+            vec![(0, 0), (10, 1), (17, 2), (18, 3), (19, 4), (50, 5)]
+        );
+        assert_eq!(stats.items, 6);
+        assert_eq!(stats.bypassed, 3);
+        assert_eq!(stats.max_delay, Duration::from_millis(15));
+    }
+
+    #[test]
+    fn test_throttle_virtual_clock() {
+        let offsets = [0, 1, 2, 3, 4, 50];
+        let max_delay = Some(Duration::from_millis(15));
+        let clock = VirtualClock::new(Instant::now());
+        let start = clock.now();
+        let throttle = Throttle::with_clock(
+            scripted_with_clock(clock.clone(), start, &offsets),
+            MS_10,
+            clock.clone(),
+        )
+        .with_max_delay(max_delay);
+        let res: Vec<_> = clock
+            .simulate(throttle)
+            .into_iter()
+            .map(|(time, item)| ((time - start).as_millis(), item))
+            .collect();
+        assert_eq!(res, run_throttle(&offsets, max_delay).0);
+    }
+
+    #[test]
+    fn test_throttle_untracked_items_wait() {
+        // Only the even items may bypass the throttle, like queries compared to dummies
+        let (res, stats) = run_paused(async {
+            let start = Instant::now();
+            let mut throttle = Box::pin(
+                Throttle::new(scripted(start, &[0, 1, 2, 3, 4, 50]), MS_10)
+                    .with_max_delay(Some(Duration::from_millis(15)))
+                    .with_tracked_items(|item| item % 2 == 0),
+            );
+            let mut res = Vec::new();
+            while let Some(item) = throttle.next().await {
+                res.push(((Instant::now() - start).as_millis(), item));
+            }
+            (res, throttle.stats())
+        });
+        // Item 2 and 4 overtake item 3, which waits for the throttle
+        assert_eq!(
+            res,
+            vec![(0, 0), (10, 1), (17, 2), (19, 4), (29, 3), (50, 5)]
+        );
+        assert_eq!(stats.items, 3);
+        assert_eq!(stats.bypassed, 2);
+        assert_eq!(stats.max_delay, Duration::from_millis(15));
+    }
+}
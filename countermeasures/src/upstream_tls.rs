@@ -0,0 +1,220 @@
+//! Control the TLS ClientHello sent to the upstream resolver
+//!
+//! Without any options, the proxy presents the ClientHello of OpenSSL with the settings of this crate.
+//! This fingerprint is rare and might itself identify the users of the defense.
+//! The options of [`UpstreamTls`] allow mimicking the ClientHello of common stub resolvers instead.
+//! They are given as `--tls-*` arguments or as the `[tls]` table of a [`StrategyConfig`](crate::StrategyConfig).
+
+use openssl::{
+    error::ErrorStack,
+    ssl::{ConnectConfiguration, SslConnector, SslConnectorBuilder, SslMethod, SslOptions},
+};
+use serde::{Deserialize, Serialize};
+use structopt::StructOpt;
+
+/// Options for the TLS connection to the upstream resolver
+#[derive(Clone, PartialEq, Debug, Default, StructOpt, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct UpstreamTls {
+    /// Offer this ALPN protocol to the upstream resolver, e.g., `dot`
+    ///
+    /// The protocols are offered in the given order.
+    /// This flag can be given multiple times.
+    #[structopt(
+        long = "tls-alpn",
+        value_name = "PROTOCOL",
+        number_of_values = 1,
+        parse(try_from_str = parse_alpn_protocol)
+    )]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub alpn: Vec<String>,
+
+    /// Cipher list for TLS 1.2 and below in the OpenSSL format
+    ///
+    /// The order of the list is also the order in the ClientHello.
+    #[structopt(
+        long = "tls-ciphers",
+        value_name = "LIST",
+        parse(try_from_str = parse_cipher_list)
+    )]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ciphers: Option<String>,
+
+    /// Cipher suites for TLS 1.3 in the OpenSSL format, e.g., `TLS_AES_128_GCM_SHA256:TLS_AES_256_GCM_SHA384`
+    ///
+    /// The order of the list is also the order in the ClientHello.
+    #[structopt(
+        long = "tls-ciphersuites",
+        value_name = "LIST",
+        parse(try_from_str = parse_ciphersuites)
+    )]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ciphersuites: Option<String>,
+
+    /// Do not offer session tickets to the upstream resolver
+    #[structopt(long = "tls-no-session-tickets")]
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub no_session_tickets: bool,
+
+    /// Send this name in the SNI extension instead of the hostname of the upstream resolver
+    ///
+    /// The certificate of the upstream resolver is verified against this name.
+    /// An empty name disables SNI.
+    #[structopt(long = "tls-sni", value_name = "NAME")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sni: Option<String>,
+}
+
+impl UpstreamTls {
+    /// Check the options like the argument parser does
+    ///
+    /// This is needed for options, which are not read from the command line.
+    pub fn validate(&self) -> Result<(), String> {
+        for protocol in &self.alpn {
+            parse_alpn_protocol(protocol)?;
+        }
+        if let Some(ciphers) = &self.ciphers {
+            parse_cipher_list(ciphers)?;
+        }
+        if let Some(ciphersuites) = &self.ciphersuites {
+            parse_ciphersuites(ciphersuites)?;
+        }
+        Ok(())
+    }
+
+    /// Apply the options, which are shared by all connections, to `builder`
+    pub fn configure_builder(&self, builder: &mut SslConnectorBuilder) -> Result<(), ErrorStack> {
+        if !self.alpn.is_empty() {
+            builder.set_alpn_protos(&alpn_wire_format(&self.alpn))?;
+        }
+        if let Some(ciphers) = &self.ciphers {
+            builder.set_cipher_list(ciphers)?;
+        }
+        if let Some(ciphersuites) = &self.ciphersuites {
+            builder.set_ciphersuites(ciphersuites)?;
+        }
+        if self.no_session_tickets {
+            builder.set_options(SslOptions::NO_TICKET);
+        }
+        Ok(())
+    }
+
+    /// Create the configuration of a single connection to `hostname`
+    ///
+    /// Returns the configuration and the name, which must be passed to `connect`.
+    /// The name is used for SNI and the verification of the certificate.
+    pub fn configure_connection(
+        &self,
+        connector: &SslConnector,
+        hostname: &str,
+    ) -> Result<(ConnectConfiguration, String), ErrorStack> {
+        let mut config = connector.configure()?;
+        let name = match self.sni.as_deref() {
+            None => hostname,
+            Some("") => {
+                config.set_use_server_name_indication(false);
+                hostname
+            }
+            Some(sni) => sni,
+        };
+        Ok((config, name.to_string()))
+    }
+}
+
+/// ALPN protocol ids must be between 1 and 255 bytes long
+fn parse_alpn_protocol(s: &str) -> Result<String, String> {
+    if s.is_empty() || s.len() > 255 {
+        return Err(format!(
+            "The ALPN protocol '{}' must be between 1 and 255 bytes long",
+            s
+        ));
+    }
+    Ok(s.to_string())
+}
+
+/// Reject cipher lists, which OpenSSL does not support, before any connection is made
+fn parse_cipher_list(s: &str) -> Result<String, String> {
+    let mut builder = SslConnector::builder(SslMethod::tls()).map_err(|err| err.to_string())?;
+    builder
+        .set_cipher_list(s)
+        .map_err(|err| format!("Invalid TLS cipher list '{}': {}", s, err))?;
+    Ok(s.to_string())
+}
+
+/// Reject TLS 1.3 cipher suites, which OpenSSL does not support, before any connection is made
+fn parse_ciphersuites(s: &str) -> Result<String, String> {
+    let mut builder = SslConnector::builder(SslMethod::tls()).map_err(|err| err.to_string())?;
+    builder
+        .set_ciphersuites(s)
+        .map_err(|err| format!("Invalid TLS 1.3 cipher suites '{}': {}", s, err))?;
+    Ok(s.to_string())
+}
+
+/// Encode the protocols as length prefixed strings, as required by [`SslConnectorBuilder::set_alpn_protos`]
+fn alpn_wire_format(protocols: &[String]) -> Vec<u8> {
+    let mut res = Vec::new();
+    for protocol in protocols {
+        res.push(protocol.len() as u8);
+        res.extend_from_slice(protocol.as_bytes());
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_alpn_wire_format() {
+        assert_eq!(
+            alpn_wire_format(&["dot".to_string(), "h2".to_string()]),
+            b"\x03dot\x02h2".to_vec()
+        );
+        assert!(parse_alpn_protocol("").is_err());
+        assert!(parse_alpn_protocol(&"x".repeat(256)).is_err());
+    }
+
+    #[test]
+    fn test_configure_upstream_tls() {
+        let tls = UpstreamTls::from_iter(&[
+            "upstream-tls",
+            "--tls-alpn",
+            "dot",
+            "--tls-ciphers",
+            "ECDHE-RSA-AES128-GCM-SHA256:ECDHE-ECDSA-AES128-GCM-SHA256",
+            "--tls-no-session-tickets",
+            "--tls-sni",
+            "",
+        ]);
+        let mut builder = SslConnector::builder(SslMethod::tls()).unwrap();
+        tls.configure_builder(&mut builder).unwrap();
+        let connector = builder.build();
+        let (_, name) = tls.configure_connection(&connector, "dns.example").unwrap();
+        assert_eq!(name, "dns.example");
+
+        let invalid = UpstreamTls {
+            ciphers: Some("NO-SUCH-CIPHER".to_string()),
+            ..UpstreamTls::default()
+        };
+        let mut builder = SslConnector::builder(SslMethod::tls()).unwrap();
+        assert!(invalid.configure_builder(&mut builder).is_err());
+        assert!(invalid.validate().is_err());
+    }
+
+    #[test]
+    fn test_reject_invalid_ciphers_while_parsing() {
+        let parse = |args: &[&str]| {
+            UpstreamTls::from_iter_safe(std::iter::once("upstream-tls").chain(args.iter().cloned()))
+        };
+        assert!(parse(&["--tls-ciphers", "NO-SUCH-CIPHER"]).is_err());
+        assert!(parse(&["--tls-ciphersuites", "NO_SUCH_SUITE"]).is_err());
+        let tls = parse(&[
+            "--tls-ciphers",
+            "ECDHE-RSA-AES128-GCM-SHA256",
+            "--tls-ciphersuites",
+            "TLS_AES_128_GCM_SHA256",
+        ])
+        .unwrap();
+        assert!(tls.validate().is_ok());
+    }
+}
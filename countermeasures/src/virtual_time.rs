@@ -0,0 +1,453 @@
+//! Test harness to run the padding strategies on a virtual clock
+//!
+//! The tokio clock is paused, such that time only advances when all tasks wait for a timer.
+//! This makes the emission schedule of the strategies exact and the tests fast, independent of the load of the machine.
+//! Scripted payload timelines are fed into the strategies and the time of each emitted item is recorded.
+//! The same timelines can be scheduled on a [`VirtualClock`], which does not need a runtime at all.
+
+use crate::{Clock, Payload, RealClock, VirtualClock};
+use futures::{stream, FutureExt, Stream, StreamExt};
+use std::{future::Future, time::Duration};
+use tokio::time::{self, Instant};
+
+/// Run `fut` on a single threaded runtime with a paused clock
+///
+/// The clock automatically advances to the next timer, whenever the runtime is idle.
+pub(crate) fn run_paused<F: Future>(fut: F) -> F::Output {
+    let mut rt = tokio::runtime::Builder::new()
+        .basic_scheduler()
+        .enable_time()
+        .build()
+        .unwrap();
+    rt.block_on(async {
+        time::pause();
+        fut.await
+    })
+}
+
+/// Create a stream which yields the item `i` at the time `offsets_ms[i]` after `start`
+pub(crate) fn scripted(
+    start: Instant,
+    offsets_ms: &[u64],
+) -> impl Stream<Item = usize> + Send + Unpin + 'static {
+    scripted_with_clock(RealClock, start, offsets_ms)
+}
+
+/// Same as [`scripted`], but the items are scheduled using the timers of `clock`
+pub(crate) fn scripted_with_clock<C: Clock>(
+    clock: C,
+    start: Instant,
+    offsets_ms: &[u64],
+) -> impl Stream<Item = usize> + Send + Unpin + 'static {
+    let offsets: Vec<_> = offsets_ms.to_vec();
+    Box::pin(
+        stream::iter(offsets.into_iter().enumerate()).then(move |(i, offset)| {
+            clock
+                .timer_at(start + Duration::from_millis(offset))
+                .map(move |_| i)
+        }),
+    )
+}
+
+/// Run `stream` on `clock` and record the time in ms after the current time of `clock` for each item
+pub(crate) fn simulated_timeline<S, T>(clock: &VirtualClock, stream: S) -> Vec<(u128, Payload<T>)>
+where
+    S: Stream<Item = Payload<T>> + Unpin,
+{
+    let start = clock.now();
+    clock
+        .simulate(stream)
+        .into_iter()
+        .map(|(time, item)| ((time - start).as_millis(), item))
+        .collect()
+}
+
+/// Record the time in ms after `start` for each item of `stream`
+pub(crate) async fn timeline<S, T>(start: Instant, stream: S) -> Vec<(u128, Payload<T>)>
+where
+    S: Stream<Item = Payload<T>>,
+{
+    stream
+        .map(|item| ((Instant::now() - start).as_millis(), item))
+        .collect()
+        .await
+}
+
+/// Only keep the payload items of a timeline
+pub(crate) fn payloads<T: Copy>(timeline: &[(u128, Payload<T>)]) -> Vec<(u128, T)> {
+    timeline
+        .iter()
+        .filter_map(|&(time, item)| match item {
+            Payload::Payload(p) => Some((time, p)),
+            Payload::Dummy => None,
+        })
+        .collect()
+}
+
+mod tests {
+    use super::*;
+    use crate::{
+        wrap_stream, wrap_stream_with_clock, AdaptivePadding, AdaptivePaddingConfig,
+        PayloadStreamExt, Strategy,
+    };
+    use chrono::NaiveDateTime;
+    use rand::SeedableRng;
+    use sequences::{
+        adaptive_padding::{
+            AdaptivePaddingCore, ApRng, ApState, ApTraceEvent, ApTraceRecord, ApTraceWriter,
+        },
+        AbstractQueryResponse, PrecisionSequence, Probability,
+    };
+    use std::{
+        io::{self, Write},
+        sync::{Arc, Mutex},
+    };
+
+    /// In-memory output for an [`ApTraceWriter`]
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl SharedBuf {
+        fn records(&self) -> Vec<ApTraceRecord> {
+            let output = String::from_utf8(self.0.lock().unwrap().clone()).unwrap();
+            output
+                .lines()
+                .map(|line| serde_json::from_str(line).unwrap())
+                .collect()
+        }
+    }
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    const MS_5: Duration = Duration::from_millis(5);
+    const MS_10: Duration = Duration::from_millis(10);
+
+    /// Feed items at `offsets_ms` into `strategy` and return the emission schedule
+    fn run_strategy(strategy: &Strategy, offsets_ms: &[u64]) -> Vec<(u128, Payload<usize>)> {
+        run_paused(async {
+            let start = Instant::now();
+            timeline(
+                start,
+                wrap_stream(
+                    scripted(start, offsets_ms),
+                    strategy,
+                    AdaptivePaddingConfig::default(),
+                    None,
+                ),
+            )
+            .await
+        })
+    }
+
+    /// Feed items at `offsets_ms` into `strategy` running on a [`VirtualClock`]
+    fn simulate_strategy(
+        strategy: &Strategy,
+        config: AdaptivePaddingConfig,
+        offsets_ms: &[u64],
+    ) -> Vec<(u128, Payload<usize>)> {
+        let clock = VirtualClock::new(Instant::now());
+        let stream = wrap_stream_with_clock(
+            scripted_with_clock(clock.clone(), clock.now(), offsets_ms),
+            strategy,
+            config,
+            None,
+            clock.clone(),
+        );
+        simulated_timeline(&clock, stream)
+    }
+
+    #[test]
+    fn test_strategy_pass_through() {
+        let res = run_strategy(&Strategy::PassThrough, &[0, 5, 5, 25]);
+        assert_eq!(
+            res,
+            vec![
+                (0, Payload::Payload(0)),
+                (5, Payload::Payload(1)),
+                (5, Payload::Payload(2)),
+                (25, Payload::Payload(3)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_strategy_constant_rate() {
+        let res = run_strategy(&Strategy::Constant { rate: MS_10 }, &[1, 2, 35]);
+        assert_eq!(
+            res,
+            vec![
+                (0, Payload::Dummy),
+                (10, Payload::Payload(0)),
+                (20, Payload::Payload(1)),
+                (30, Payload::Dummy),
+                (40, Payload::Payload(2)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_strategy_adaptive_padding_throttle_in() {
+        let strategy = Strategy::AdaptivePadding {
+            throttle_in: Some(MS_10),
+            throttle_out: None,
+            max_delay: None,
+        };
+        for _ in 0..20 {
+            let res = run_strategy(&strategy, &[0, 3, 50]);
+            // Payload is delayed by the input throttle, but forwarded without delay by AP
+            assert_eq!(payloads(&res), vec![(0, 0), (10, 1), (50, 2)]);
+            assert_eq!(res[0], (0, Payload::Payload(0)));
+            // The throttle also delays noticing the end of the stream, which leaves time for more dummies
+            assert!(res.last().unwrap().0 <= 60);
+        }
+    }
+
+    #[test]
+    fn test_strategy_adaptive_padding_throttle_out() {
+        let strategy = Strategy::AdaptivePadding {
+            throttle_in: None,
+            throttle_out: Some(MS_5),
+            max_delay: None,
+        };
+        for _ in 0..20 {
+            let res = run_strategy(&strategy, &[0, 1, 2, 100]);
+            assert_eq!(
+                payloads(&res)
+                    .into_iter()
+                    .map(|(_, p)| p)
+                    .collect::<Vec<_>>(),
+                vec![0, 1, 2, 3]
+            );
+            // Payload and dummy items share the output throttle
+            for window in res.windows(2) {
+                assert!(
+                    window[1].0 - window[0].0 >= 5,
+                    "Items are emitted too close together: {:?}",
+                    window
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_virtual_clock_matches_paused_runtime() {
+        // Without Adaptive Padding all deadlines are full milliseconds, which the tokio timer represents exactly
+        let offsets = [1, 2, 3, 35, 36, 80];
+        for strategy in &["pass", "constant-10", "constant-3"] {
+            let strategy: Strategy = strategy.parse().unwrap();
+            assert_eq!(
+                simulate_strategy(&strategy, AdaptivePaddingConfig::default(), &offsets),
+                run_strategy(&strategy, &offsets),
+                "Schedules differ for strategy {}",
+                strategy
+            );
+        }
+    }
+
+    #[test]
+    fn test_virtual_clock_adaptive_padding() {
+        let strategy: Strategy = "ap-tin10-tout5".parse().unwrap();
+        let config = AdaptivePaddingConfig {
+            seed: Some(7),
+            ..AdaptivePaddingConfig::default()
+        };
+        let offsets = [0, 1, 2, 3, 200, 1000];
+        let res = simulate_strategy(&strategy, config, &offsets);
+        assert_eq!(res, simulate_strategy(&strategy, config, &offsets));
+        assert_eq!(
+            payloads(&res)
+                .into_iter()
+                .map(|(_, p)| p)
+                .collect::<Vec<_>>(),
+            vec![0, 1, 2, 3, 4, 5]
+        );
+        for window in res.windows(2) {
+            assert!(
+                window[1].0 - window[0].0 >= 5,
+                "Items are emitted too close together: {:?}",
+                window
+            );
+        }
+    }
+
+    #[test]
+    fn test_adaptive_padding_seed_is_reproducible() {
+        let offsets = [0, 2, 4, 200, 201, 1000];
+        let run = |seed| {
+            run_paused(async {
+                let start = Instant::now();
+                let config = AdaptivePaddingConfig {
+                    seed: Some(seed),
+                    ..AdaptivePaddingConfig::default()
+                };
+                timeline(start, scripted(start, &offsets).adaptive_padding(config)).await
+            })
+        };
+
+        let res = run(42);
+        assert_eq!(res, run(42));
+        assert_eq!(
+            payloads(&res),
+            vec![(0, 0), (2, 1), (4, 2), (200, 3), (201, 4), (1000, 5)]
+        );
+        // The state machine starts idle, such that no dummy can precede the first payload
+        assert_eq!(res[0], (0, Payload::Payload(0)));
+        assert_eq!(res.last().unwrap().0, 1000);
+    }
+
+    #[test]
+    fn test_adaptive_padding_trace() {
+        let buf = SharedBuf::default();
+        let trace = ApTraceWriter::new(buf.clone()).with_id("test");
+        let res = run_paused(async {
+            let start = Instant::now();
+            let config = AdaptivePaddingConfig {
+                seed: Some(1),
+                ..AdaptivePaddingConfig::default()
+            };
+            let ap = scripted(start, &[0, 100])
+                .adaptive_padding(config)
+                .with_trace(Some(trace));
+            timeline(start, ap).await
+        });
+
+        let records = buf.records();
+        assert!(records.iter().all(|r| r.id.as_deref() == Some("test")));
+        assert_eq!(
+            records[0].event,
+            ApTraceEvent::Payload {
+                state: ApState::Idle
+            }
+        );
+        assert_eq!(
+            records[1].event,
+            ApTraceEvent::StateChange {
+                from: ApState::Idle,
+                to: ApState::Burst
+            }
+        );
+        assert!(matches!(
+            records[2].event,
+            ApTraceEvent::SampledTimeout {
+                state: ApState::Burst,
+                ..
+            }
+        ));
+        // Each dummy item corresponds to one timeout in the trace
+        let timeouts = records
+            .iter()
+            .filter(|r| matches!(r.event, ApTraceEvent::Timeout { .. }))
+            .count();
+        let dummies = res.iter().filter(|(_, p)| *p == Payload::Dummy).count();
+        assert_eq!(timeouts, dummies);
+    }
+
+    #[test]
+    fn test_live_adaptive_padding_matches_core() {
+        // The live implementation and the simulator share the state machine.
+        // Given the same events at the same times, they must make identical decisions.
+        // Only the times of the events differ, since the tokio timer has a resolution of 1 ms.
+        for seed in 0..10 {
+            let live_buf = SharedBuf::default();
+            let trace = ApTraceWriter::new(live_buf.clone());
+            run_paused(async {
+                let start = Instant::now();
+                let config = AdaptivePaddingConfig {
+                    seed: Some(seed),
+                    ..AdaptivePaddingConfig::default()
+                };
+                let ap = scripted(start, &[0, 1, 2, 30, 31, 500, 2000])
+                    .adaptive_padding(config)
+                    .with_trace(Some(trace));
+                timeline(start, ap).await
+            });
+            let live = live_buf.records();
+
+            let replay_buf = SharedBuf::default();
+            let mut core = AdaptivePaddingCore::new(ApRng::seed_from_u64(seed), 2, 0.9);
+            core.set_trace(Some(ApTraceWriter::new(replay_buf.clone())));
+            for record in &live {
+                let now = Duration::from_nanos((record.time * 1_000_000_000.).round() as u64);
+                match record.event {
+                    ApTraceEvent::Payload { .. } => {
+                        core.handle_payload(now);
+                    }
+                    ApTraceEvent::Timeout { .. } => {
+                        core.handle_timeout(now);
+                    }
+                    _ => {}
+                }
+            }
+
+            assert_eq!(
+                live,
+                replay_buf.records(),
+                "Traces differ for seed {}",
+                seed
+            );
+        }
+    }
+
+    #[test]
+    fn test_live_adaptive_padding_matches_simulator() {
+        // The live stream adapter must emit the same schedule as the simulator for `PrecisionSequence`.
+        // The live stream ends with the last payload, while the simulator continues until Adaptive Padding is idle.
+        let offsets_ms = [0, 1, 2, 30, 31, 500, 2000];
+        let probability_fake_burst = Probability::new(0.9).unwrap();
+        let start = NaiveDateTime::from_timestamp(1_500_000_000, 0);
+        for id in &["a.json", "b.json", "c.json", "d.json"] {
+            let sequence = PrecisionSequence::new(
+                offsets_ms.iter().map(|&offset| AbstractQueryResponse {
+                    time: start + chrono::Duration::milliseconds(offset as i64),
+                    size: 128,
+                }),
+                id.to_string(),
+            );
+            let simulated: Vec<_> = sequence
+                .apply_adaptive_padding(2, probability_fake_burst)
+                .events()
+                .iter()
+                .map(|event| {
+                    let time = (event.time() - start).to_std().unwrap();
+                    (time, event.is_dummy_event())
+                })
+                .collect();
+
+            let clock = VirtualClock::new(Instant::now());
+            let live_start = clock.now();
+            let config = AdaptivePaddingConfig {
+                median_burst_length: 2,
+                probability_fake_burst: f64::from(probability_fake_burst.to_float()),
+                seed: Some(sequence.adaptive_padding_seed()),
+            };
+            let ap = AdaptivePadding::with_clock(
+                scripted_with_clock(clock.clone(), live_start, &offsets_ms),
+                config,
+                clock.clone(),
+            );
+            let live: Vec<_> = clock
+                .simulate(ap)
+                .into_iter()
+                .map(|(time, item)| (time - live_start, item == Payload::Dummy))
+                .collect();
+
+            assert_eq!(
+                live,
+                simulated[..live.len()],
+                "Schedules differ for sequence {}",
+                id
+            );
+            assert_eq!(
+                offsets_ms.len(),
+                live.iter().filter(|(_, is_dummy)| !is_dummy).count()
+            );
+        }
+    }
+}
@@ -0,0 +1,31 @@
+use std::process::Command;
+
+/// Run git in the source tree and return the trimmed stdout, if it succeeds
+fn git(args: &[&str]) -> Option<String> {
+    let output = Command::new("git").args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+fn main() {
+    // Embed the commit into the binary, such that it is known even if the binary runs outside of the source tree
+    if let Some(commit) = git(&["rev-parse", "HEAD"]) {
+        println!("cargo:rustc-env=DNS_SEQUENCE_GIT_COMMIT={}", commit);
+    }
+    if let Some(status) = git(&["status", "--porcelain"]) {
+        println!(
+            "cargo:rustc-env=DNS_SEQUENCE_GIT_DIRTY={}",
+            !status.is_empty()
+        );
+    }
+
+    // Update the values after a commit, a checkout, or changes to the sources
+    if let Some(git_dir) = git(&["rev-parse", "--absolute-git-dir"]) {
+        println!("cargo:rerun-if-changed={}/HEAD", git_dir);
+        println!("cargo:rerun-if-changed={}/index", git_dir);
+    }
+    println!("cargo:rerun-if-changed=src");
+    println!("cargo:rerun-if-changed=Cargo.toml");
+}
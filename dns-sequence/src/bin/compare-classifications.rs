@@ -0,0 +1,176 @@
+use anyhow::{Context as _, Error};
+use dns_sequence::compare::{compare, load_classifications, Comparison};
+use log::info;
+use misc_utils::fs::file_write;
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use structopt::StructOpt;
+
+/// Compare the classification results of two runs on the same test data
+///
+/// The inputs are the files written with `--misclassifications` by `dns-sequence`, e.g., once with and once without a defense.
+/// For each `k` the accuracy of both runs and McNemar's test are printed.
+/// The per domain changes and the sequences, whose outcome flipped, can be written to CSV files.
+#[derive(StructOpt, Debug)]
+#[structopt(global_settings(&[
+    structopt::clap::AppSettings::ColoredHelp,
+    structopt::clap::AppSettings::VersionlessSubcommands
+]))]
+struct CliArgs {
+    /// Classification results of the first run
+    #[structopt(parse(from_os_str))]
+    first: PathBuf,
+    /// Classification results of the second run
+    #[structopt(parse(from_os_str))]
+    second: PathBuf,
+    /// Only compare the results for this k
+    #[structopt(short = "k")]
+    k: Option<usize>,
+    /// Number of domains with the largest changes to print
+    #[structopt(long = "top", default_value = "10")]
+    top: usize,
+    /// Write the changes of all domains to this CSV file
+    #[structopt(long = "per-domain", value_name = "FILE", parse(from_os_str))]
+    per_domain: Option<PathBuf>,
+    /// Write all sequences, which are correct in only one of the runs, to this CSV file
+    #[structopt(long = "flipped", value_name = "FILE", parse(from_os_str))]
+    flipped: Option<PathBuf>,
+}
+
+fn main() -> Result<(), Error> {
+    // generic setup
+    env_logger::init();
+    let cli_args = CliArgs::from_args();
+
+    info!("Start loading classification results...");
+    let mut first = load_classifications(&cli_args.first)?;
+    let mut second = load_classifications(&cli_args.second)?;
+    if let Some(k) = cli_args.k {
+        first.retain(|record| record.k == k);
+        second.retain(|record| record.k == k);
+    }
+    info!(
+        "Done loading classification results. Found {} and {} results.",
+        first.len(),
+        second.len()
+    );
+
+    let comparisons = compare(first, second)?;
+    for comparison in &comparisons {
+        print_comparison(comparison, cli_args.top);
+    }
+
+    if let Some(path) = &cli_args.per_domain {
+        write_per_domain(path, &comparisons)?;
+    }
+    if let Some(path) = &cli_args.flipped {
+        let mut writer = csv_writer(path)?;
+        for flipped in comparisons
+            .iter()
+            .flat_map(|comparison| &comparison.flipped)
+        {
+            writer.serialize(flipped)?;
+        }
+        writer.flush()?;
+    }
+
+    Ok(())
+}
+
+fn print_comparison(comparison: &Comparison, top: usize) {
+    let total = comparison.total();
+    let mcnemar = total.mcnemar();
+    println!("knn with k={}:", comparison.k);
+    println!("  Paired sequences: {}", total.total);
+    if comparison.unpaired_first > 0 || comparison.unpaired_second > 0 {
+        println!(
+            "  Unpaired sequences: {} only in the first run, {} only in the second run",
+            comparison.unpaired_first, comparison.unpaired_second
+        );
+    }
+    println!(
+        "  Accuracy: {:.2}% -> {:.2}% ({:+.2} percentage points)",
+        total.accuracy_first() * 100.,
+        total.accuracy_second() * 100.,
+        total.delta() * 100.
+    );
+    println!(
+        "  Flipped: {} only correct in the first run, {} only correct in the second run",
+        total.only_first, total.only_second
+    );
+    println!(
+        "  McNemar: chi^2 = {:.3}, p = {:.4e}",
+        mcnemar.statistic, mcnemar.p_value
+    );
+
+    let mut domains: Vec<_> = comparison
+        .domains
+        .iter()
+        .filter(|(_, delta)| delta.only_first + delta.only_second > 0)
+        .collect();
+    domains.sort_by(|(a_name, a), (b_name, b)| {
+        b.delta()
+            .abs()
+            .partial_cmp(&a.delta().abs())
+            .unwrap_or(std::cmp::Ordering::Equal)
+            .then_with(|| a_name.cmp(b_name))
+    });
+    if !domains.is_empty() && top > 0 {
+        println!("  Domains with the largest changes:");
+        for (domain, delta) in domains.into_iter().take(top) {
+            println!(
+                "    {}: {:.2}% -> {:.2}% ({:+}/-{} of {})",
+                domain,
+                delta.accuracy_first() * 100.,
+                delta.accuracy_second() * 100.,
+                delta.only_second,
+                delta.only_first,
+                delta.total
+            );
+        }
+    }
+    println!();
+}
+
+fn write_per_domain(path: &Path, comparisons: &[Comparison]) -> Result<(), Error> {
+    #[derive(Serialize)]
+    struct Out<'a> {
+        k: usize,
+        domain: &'a str,
+        total: usize,
+        correct_first: usize,
+        correct_second: usize,
+        only_first: usize,
+        only_second: usize,
+        delta: f64,
+        p_value: f64,
+    }
+
+    let mut writer = csv_writer(path)?;
+    for comparison in comparisons {
+        for (domain, delta) in &comparison.domains {
+            writer.serialize(Out {
+                k: comparison.k,
+                domain,
+                total: delta.total,
+                correct_first: delta.correct_first,
+                correct_second: delta.correct_second,
+                only_first: delta.only_first,
+                only_second: delta.only_second,
+                delta: delta.delta(),
+                p_value: delta.mcnemar().p_value,
+            })?;
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+fn csv_writer(path: &Path) -> Result<csv::Writer<impl std::io::Write>, Error> {
+    Ok(csv::Writer::from_writer(
+        file_write(path)
+            .create(true)
+            .truncate()
+            .with_context(|| format!("Opening output file '{}' failed", path.display()))?,
+    ))
+}
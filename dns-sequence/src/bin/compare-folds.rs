@@ -0,0 +1,180 @@
+use anyhow::{bail, Context as _, Error};
+use dns_sequence::significance::{holm_adjust, wilcoxon_signed_rank};
+use misc_utils::fs::{file_open_read, file_write};
+use prettytable::{cell, format::consts::FORMAT_CLEAN, row, Table};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+use structopt::StructOpt;
+
+/// Test whether configurations differ significantly in their crossvalidation accuracy
+///
+/// The inputs are the `<statistics>.folds.csv` files of `dns-sequence crossvalidate`, e.g., of runs with different distance metrics or simulated countermeasures.
+/// Every `k` of every file is one configuration.
+/// All pairs of configurations are compared with the Wilcoxon signed-rank test over the accuracies of the folds.
+/// The p-values are additionally adjusted for the number of comparisons with the Holm-Bonferroni method.
+#[derive(StructOpt, Debug)]
+#[structopt(global_settings(&[
+    structopt::clap::AppSettings::ColoredHelp,
+    structopt::clap::AppSettings::VersionlessSubcommands
+]))]
+struct CliArgs {
+    /// Fold accuracies of the runs, either as `<path>` or as `<name>=<path>`
+    ///
+    /// Without a name, the file name is used.
+    #[structopt(required = true, min_values = 1)]
+    runs: Vec<String>,
+    /// Only compare configurations with this k
+    ///
+    /// This option can be applied multiple times.
+    #[structopt(short = "k")]
+    ks: Vec<u8>,
+    /// Write the results of all comparisons to this CSV file
+    #[structopt(short = "o", long = "out", value_name = "FILE", parse(from_os_str))]
+    outfile: Option<PathBuf>,
+}
+
+#[derive(Debug, Deserialize)]
+struct FoldRecord {
+    k: u8,
+    fold: u8,
+    accuracy: Option<f64>,
+}
+
+#[derive(Debug, Serialize)]
+struct Out<'a> {
+    first: &'a str,
+    second: &'a str,
+    folds: usize,
+    mean_first: f64,
+    mean_second: f64,
+    mean_delta: f64,
+    statistic: f64,
+    p_value: f64,
+    p_holm: f64,
+}
+
+fn main() -> Result<(), Error> {
+    // generic setup
+    env_logger::init();
+    let cli_args = CliArgs::from_args();
+
+    // Per configuration the accuracy of each fold
+    let mut configurations: Vec<(String, BTreeMap<u8, f64>)> = Vec::new();
+    for run in &cli_args.runs {
+        let (name, path) = match run.split_once('=') {
+            Some((name, path)) => (name.to_string(), PathBuf::from(path)),
+            None => {
+                let path = PathBuf::from(run);
+                let name = path
+                    .file_name()
+                    .map(|name| {
+                        name.to_string_lossy()
+                            .trim_end_matches(".folds.csv")
+                            .to_string()
+                    })
+                    .unwrap_or_else(|| run.clone());
+                (name, path)
+            }
+        };
+        for (k, folds) in load_folds(&path)? {
+            if cli_args.ks.is_empty() || cli_args.ks.contains(&k) {
+                configurations.push((format!("{} k={}", name, k), folds));
+            }
+        }
+    }
+    if configurations.len() < 2 {
+        bail!("At least two configurations are required for a comparison.");
+    }
+
+    let mut results = Vec::new();
+    for (idx, (first, first_folds)) in configurations.iter().enumerate() {
+        for (second, second_folds) in &configurations[idx + 1..] {
+            let pairs: Vec<(f64, f64)> = first_folds
+                .iter()
+                .filter_map(|(fold, &acc)| Some((acc, *second_folds.get(fold)?)))
+                .collect();
+            let differences: Vec<f64> = pairs.iter().map(|(a, b)| b - a).collect();
+            let test = wilcoxon_signed_rank(&differences);
+            let folds = pairs.len().max(1) as f64;
+            results.push(Out {
+                first,
+                second,
+                folds: pairs.len(),
+                mean_first: pairs.iter().map(|p| p.0).sum::<f64>() / folds,
+                mean_second: pairs.iter().map(|p| p.1).sum::<f64>() / folds,
+                mean_delta: differences.iter().sum::<f64>() / folds,
+                statistic: test.statistic,
+                p_value: test.p_value,
+                p_holm: 0.,
+            });
+        }
+    }
+    let p_values: Vec<f64> = results.iter().map(|res| res.p_value).collect();
+    for (res, p_holm) in results.iter_mut().zip(holm_adjust(&p_values)) {
+        res.p_holm = p_holm;
+    }
+
+    let mut table = Table::new();
+    table.set_format(*FORMAT_CLEAN);
+    table.set_titles(row![
+        b->"First",
+        b->"Second",
+        b->"Folds",
+        b->"Accuracy",
+        b->"Delta",
+        b->"W+",
+        b->"p",
+        b->"p (Holm)",
+    ]);
+    for res in &results {
+        table.add_row(row![
+            l->res.first,
+            l->res.second,
+            r->res.folds,
+            r->format!("{:.2}% -> {:.2}%", res.mean_first * 100., res.mean_second * 100.),
+            r->format!("{:+.2}", res.mean_delta * 100.),
+            r->res.statistic,
+            r->format!("{:.4}", res.p_value),
+            r->format!("{:.4}", res.p_holm),
+        ]);
+    }
+    print!("{}", table);
+
+    if let Some(outfile) = &cli_args.outfile {
+        let mut writer = csv::Writer::from_writer(
+            file_write(outfile)
+                .create(true)
+                .truncate()
+                .with_context(|| format!("Opening output file '{}' failed", outfile.display()))?,
+        );
+        for res in &results {
+            writer.serialize(res)?;
+        }
+        writer.flush()?;
+    }
+
+    Ok(())
+}
+
+/// Read the accuracy per `k` and fold
+///
+/// Folds without any results are skipped.
+fn load_folds(path: &Path) -> Result<BTreeMap<u8, BTreeMap<u8, f64>>, Error> {
+    let rdr = file_open_read(path)
+        .with_context(|| format!("Cannot open fold statistics {}", path.display()))?;
+    let mut reader = csv::Reader::from_reader(rdr);
+    let mut res: BTreeMap<u8, BTreeMap<u8, f64>> = BTreeMap::new();
+    for record in reader.deserialize() {
+        let record: FoldRecord =
+            record.with_context(|| format!("Invalid record in {}", path.display()))?;
+        if let Some(accuracy) = record.accuracy {
+            res.entry(record.k)
+                .or_default()
+                .insert(record.fold, accuracy);
+        }
+    }
+    Ok(res)
+}
@@ -0,0 +1,70 @@
+use anyhow::{Context as _, Error};
+use dns_sequence::{prepare_confusion_domains, DatasetOptions};
+use log::info;
+use sequences::{information_gain::information_gain, SimulatedCountermeasure};
+use std::path::PathBuf;
+use structopt::StructOpt;
+
+/// Calculate the mutual information between the features of the sequences and the domains
+///
+/// The result is written as CSV, which can be plotted with `scripts/information_gain.py`.
+#[derive(StructOpt, Debug)]
+#[structopt(global_settings(&[
+    structopt::clap::AppSettings::ColoredHelp,
+    structopt::clap::AppSettings::VersionlessSubcommands
+]))]
+struct CliArgs {
+    /// Base directory containing per domain a folder which contains the dnstap files
+    #[structopt(parse(from_os_str))]
+    base_dir: PathBuf,
+    /// Countermeasure to simulate while loading the sequences
+    ///
+    /// One of `None`, `PerfectPadding`, `PerfectTiming`, `PerfectPaddingAndTiming`, `CountOnly`, `ConstantLength[:<n>]`, `DpLaplace[:<epsilon>]`, or `DpGaussian[:<epsilon>]`.
+    /// The length defaults to 50 and epsilon to 1, smaller values of epsilon add more noise.
+    #[structopt(long = "simulate", default_value = "None")]
+    simulate: SimulatedCountermeasure,
+    #[structopt(flatten)]
+    dataset: DatasetOptions,
+    /// Number of message positions to analyze
+    #[structopt(long = "max-positions", default_value = "50")]
+    max_positions: usize,
+    /// Output CSV file
+    #[structopt(short = "o", long = "out", value_name = "FILE", parse(from_os_str))]
+    outfile: PathBuf,
+}
+
+fn main() -> Result<(), Error> {
+    // generic setup
+    env_logger::init();
+    let cli_args = CliArgs::from_args();
+
+    info!("Start loading confusion domains...");
+    prepare_confusion_domains(&cli_args.dataset.confusion_domains)?;
+    info!("Done loading confusion domains.");
+
+    info!("Start loading dnstap files...");
+    let data = cli_args
+        .dataset
+        .load(&cli_args.base_dir, cli_args.simulate)?;
+    info!("Done loading dnstap files. Found {} domains.", data.len());
+
+    let results = information_gain(&data, cli_args.max_positions);
+
+    let mut writer = csv::Writer::from_writer(
+        misc_utils::fs::file_write(&cli_args.outfile)
+            .create(true)
+            .truncate()
+            .with_context(|| {
+                format!(
+                    "Opening output file '{}' failed",
+                    cli_args.outfile.display()
+                )
+            })?,
+    );
+    for result in &results {
+        writer.serialize(result)?;
+    }
+    writer.flush()?;
+
+    Ok(())
+}
@@ -0,0 +1,186 @@
+use anyhow::{bail, Context as _, Error};
+use dns_sequence::{prepare_confusion_domains, DatasetOptions};
+use log::{info, warn};
+use misc_utils::{fs::file_write, path::PathExt};
+use sequences::{
+    knn::{self, DistanceMetric, VoteWeighting},
+    precision_sequence::PrecisionSequence,
+    scenario::{ScenarioGenerator, SubsetAccuracy},
+    sequence_directories, Sequence, SimulatedCountermeasure,
+};
+use serde_json::json;
+use std::{
+    ffi::OsStr,
+    io::Write,
+    path::{Path, PathBuf},
+};
+use structopt::StructOpt;
+
+/// Classify overlapping page loads, e.g., multiple tabs opened at the same time
+///
+/// Each scenario merges the traces of a few randomly chosen test domains with random start offsets.
+/// The classifier predicts as many domains as there are tabs and is scored on identifying any or all of them.
+#[derive(StructOpt, Debug)]
+#[structopt(global_settings(&[
+    structopt::clap::AppSettings::ColoredHelp,
+    structopt::clap::AppSettings::VersionlessSubcommands
+]))]
+struct CliArgs {
+    /// Base directory containing per domain a folder which contains the dnstap files
+    #[structopt(parse(from_os_str))]
+    base_dir: PathBuf,
+    /// Base directory containing per domain a folder with the traces to merge into scenarios
+    #[structopt(parse(from_os_str))]
+    test_data: PathBuf,
+    #[structopt(flatten)]
+    dataset: DatasetOptions,
+    /// Number of page loads per scenario
+    #[structopt(long = "tabs", default_value = "2")]
+    tabs: usize,
+    /// Maximal start offset of a page load relative to the first one in milliseconds
+    #[structopt(long = "max-offset", default_value = "2000")]
+    max_offset: i64,
+    /// Number of scenarios to generate
+    #[structopt(long = "scenarios", default_value = "1000")]
+    scenarios: usize,
+    /// Seed for sampling the scenarios
+    #[structopt(long = "seed", default_value = "0")]
+    seed: u64,
+    /// Number of neighbors for the k-NN, should be at least the number of tabs
+    #[structopt(short = "k", default_value = "5")]
+    k: u8,
+    #[structopt(long = "use-cr-mode")]
+    use_cr_mode: bool,
+    /// Write the scenarios and the predicted domains as JSON lines to this file
+    #[structopt(short = "o", long = "out", value_name = "FILE", parse(from_os_str))]
+    outfile: Option<PathBuf>,
+}
+
+fn main() -> Result<(), Error> {
+    // generic setup
+    env_logger::init();
+    let cli_args = CliArgs::from_args();
+
+    if usize::from(cli_args.k) < cli_args.tabs {
+        warn!(
+            "With k={} the classifier cannot predict all {} domains of a scenario.",
+            cli_args.k, cli_args.tabs
+        );
+    }
+
+    info!("Start loading confusion domains...");
+    prepare_confusion_domains(&cli_args.dataset.confusion_domains)?;
+    info!("Done loading confusion domains.");
+
+    info!("Start loading trainings data...");
+    let training_data = cli_args
+        .dataset
+        .load(&cli_args.base_dir, SimulatedCountermeasure::None)?;
+    info!(
+        "Done loading trainings data. Found {} domains.",
+        training_data.len()
+    );
+
+    info!("Start loading test data...");
+    let test_data =
+        load_precision_sequences(&cli_args.test_data, &cli_args.dataset.file_extension)?;
+    info!("Done loading test data. Found {} domains.", test_data.len());
+
+    let generator = ScenarioGenerator {
+        tabs: cli_args.tabs,
+        max_offset: chrono::Duration::milliseconds(cli_args.max_offset),
+        seed: cli_args.seed,
+    };
+    let scenarios = generator.generate(&test_data, cli_args.scenarios)?;
+    let sequences: Vec<Sequence> = scenarios
+        .iter()
+        .map(|scenario| scenario.sequence.clone())
+        .collect();
+
+    info!("Start classifying {} scenarios...", scenarios.len());
+    let results = knn::knn_with_metric(
+        &training_data,
+        &sequences,
+        cli_args.k,
+        DistanceMetric::EditDistance,
+        VoteWeighting::Uniform,
+        cli_args.use_cr_mode,
+    );
+    info!("Done classifying scenarios.");
+
+    let mut writer = cli_args
+        .outfile
+        .as_ref()
+        .map(|outfile| file_write(outfile).create(true).truncate())
+        .transpose()?;
+    let mut accuracy = SubsetAccuracy::default();
+    for (scenario, result) in scenarios.iter().zip(&results) {
+        // The test directories are named after the true domains
+        let subset_match = scenario.evaluate(&result.true_domain_result());
+        accuracy.add(&subset_match);
+        if let Some(writer) = &mut writer {
+            serde_json::to_writer(
+                &mut *writer,
+                &json!({
+                    "scenario": scenario,
+                    "predicted": subset_match.predicted,
+                    "any": subset_match.any(),
+                    "all": subset_match.all(),
+                }),
+            )?;
+            writeln!(writer)?;
+        }
+    }
+
+    println!("{}", accuracy);
+    Ok(())
+}
+
+/// Load the [`PrecisionSequence`]s of each domain folder in `base_dir`
+fn load_precision_sequences(
+    base_dir: &Path,
+    file_extension: &OsStr,
+) -> Result<Vec<(String, Vec<PrecisionSequence>)>, Error> {
+    let mut data = Vec::new();
+    for dir in sequence_directories(base_dir)? {
+        let label = dir
+            .file_name()
+            .expect("Each directory has a name")
+            .to_string_lossy()
+            .into_owned();
+
+        let mut filenames = Vec::new();
+        for entry in std::fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.is_file() && path.extensions().any(|ext| ext == file_extension) {
+                filenames.push(path);
+            }
+        }
+        // sort filenames for predictable results
+        filenames.sort();
+
+        let traces: Vec<PrecisionSequence> = filenames
+            .iter()
+            .filter_map(|file| {
+                match PrecisionSequence::from_path(file)
+                    .with_context(|| format!("Processing file '{}'", file.display()))
+                {
+                    Ok(trace) => Some(trace),
+                    Err(err) => {
+                        warn!("{}", err);
+                        None
+                    }
+                }
+            })
+            .collect();
+        if traces.is_empty() {
+            warn!("Directory contains no data: {}", dir.display());
+            continue;
+        }
+        data.push((label, traces));
+    }
+    if data.is_empty() {
+        bail!("No traces found in '{}'", base_dir.display());
+    }
+    Ok(data)
+}
@@ -0,0 +1,88 @@
+use anyhow::{Context as _, Error};
+use log::info;
+use sequences::{
+    dnstap::{aggregate_padding, analyze_padding, PaddingCounts},
+    Padding,
+};
+use serde_json::json;
+use std::path::PathBuf;
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug)]
+#[structopt(global_settings(&[
+    structopt::clap::AppSettings::ColoredHelp,
+    structopt::clap::AppSettings::VersionlessSubcommands
+]))]
+struct CliArgs {
+    /// Padding policy the resolver is expected to enforce
+    #[structopt(long = "padding", default_value = "Q128R468")]
+    padding: Padding,
+    /// Write the per message and per resolver results as JSON to this file
+    #[structopt(short = "o", long = "out", value_name = "FILE", parse(from_os_str))]
+    outfile: Option<PathBuf>,
+    /// The dnstap files to analyze
+    #[structopt(parse(from_os_str), required = true)]
+    dnstap_files: Vec<PathBuf>,
+}
+
+fn main() -> Result<(), Error> {
+    // generic setup
+    env_logger::init();
+    let cli_args = CliArgs::from_args();
+
+    let mut messages = Vec::new();
+    for file in &cli_args.dnstap_files {
+        info!("Analyze file {}", file.display());
+        messages.extend(
+            analyze_padding(file)
+                .with_context(|| format!("Failed to analyze file {}", file.display()))?,
+        );
+    }
+    let resolvers = aggregate_padding(&messages, cli_args.padding);
+
+    for (resolver, stats) in &resolvers {
+        match resolver {
+            Some(resolver) => println!("Resolver {}", resolver),
+            None => println!("Resolver unknown"),
+        }
+        print_counts("Queries", &stats.queries);
+        print_counts("Responses", &stats.responses);
+        println!();
+    }
+
+    if let Some(outfile) = cli_args.outfile {
+        let resolvers: Vec<_> = resolvers
+            .into_iter()
+            .map(|(resolver, stats)| {
+                json!({
+                    "resolver": resolver,
+                    "queries": stats.queries,
+                    "responses": stats.responses,
+                })
+            })
+            .collect();
+        let writer = misc_utils::fs::file_write(outfile)
+            .create(true)
+            .truncate()?;
+        serde_json::to_writer(
+            writer,
+            &json!({
+                "padding": format!("{:?}", cli_args.padding),
+                "resolvers": resolvers,
+                "messages": messages,
+            }),
+        )?;
+    }
+
+    Ok(())
+}
+
+fn print_counts(name: &str, counts: &PaddingCounts) {
+    println!(
+        "  {:<9} {:>7} messages, {:>7} padded, {:>7} policy violations",
+        name, counts.messages, counts.padded, counts.policy_violations
+    );
+    for (block_size, count) in &counts.block_sizes {
+        println!("    block size {:>3}B: {:>7}", block_size, count);
+    }
+}
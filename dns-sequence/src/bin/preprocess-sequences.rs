@@ -1,7 +1,8 @@
 use anyhow::Error;
-use dns_sequence::{load_all_files, prepare_confusion_domains, SimulateOption};
+use dns_sequence::{prepare_confusion_domains, DatasetOptions};
 use log::info;
-use std::{ffi::OsString, path::PathBuf};
+use sequences::SimulatedCountermeasure;
+use std::path::PathBuf;
 use structopt::StructOpt;
 
 #[derive(StructOpt, Debug)]
@@ -13,27 +14,14 @@ struct CliArgs {
     /// Base directory containing per domain a folder which contains the dnstap files
     #[structopt(parse(from_os_str))]
     base_dir: PathBuf,
-    /// Some domains are known similar. Specify a CSV file renaming the "original" domain to some other identifier.
-    /// This option can be applied multiple times. It is not permitted to have conflicting entries to the same domain.
-    #[structopt(short = "d", long = "confusion_domains", parse(from_os_str))]
-    confusion_domains: Vec<PathBuf>,
-    /// File extension which must be available in the file to be recognized as a Sequence file
+    /// Countermeasure to simulate while loading the sequences
     ///
-    /// This can be `pcap`, `dnstap`, `json`
-    #[structopt(
-        long = "extension",
-        value_name = "ext",
-        default_value = "dnstap",
-        parse(from_os_str)
-    )]
-    file_extension: OsString,
-    #[structopt(
-        long = "simulate",
-        default_value = "Normal",
-        possible_values = &SimulateOption::variants(),
-        case_insensitive = true
-    )]
-    simulate: SimulateOption,
+    /// One of `None`, `PerfectPadding`, `PerfectTiming`, `PerfectPaddingAndTiming`, `CountOnly`, `ConstantLength[:<n>]`, `DpLaplace[:<epsilon>]`, or `DpGaussian[:<epsilon>]`.
+    /// The length defaults to 50 and epsilon to 1, smaller values of epsilon add more noise.
+    #[structopt(long = "simulate", default_value = "None")]
+    simulate: SimulatedCountermeasure,
+    #[structopt(flatten)]
+    dataset: DatasetOptions,
     #[structopt(short = "o", long = "out", value_name = "FILE", parse(from_os_str))]
     outfile: PathBuf,
 }
@@ -44,15 +32,13 @@ fn main() -> Result<(), Error> {
     let cli_args = CliArgs::from_args();
 
     info!("Start loading confusion domains...");
-    prepare_confusion_domains(&cli_args.confusion_domains)?;
+    prepare_confusion_domains(&cli_args.dataset.confusion_domains)?;
     info!("Done loading confusion domains.");
 
     info!("Start loading dnstap files...");
-    let training_data = load_all_files(
-        &cli_args.base_dir,
-        &cli_args.file_extension,
-        cli_args.simulate,
-    )?;
+    let training_data = cli_args
+        .dataset
+        .load(&cli_args.base_dir, cli_args.simulate)?;
     info!(
         "Done loading dnstap files. Found {} domains.",
         training_data.len()
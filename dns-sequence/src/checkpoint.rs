@@ -0,0 +1,268 @@
+//! Checkpoints for long running classifications
+//!
+//! After each finished combination of part and `k` the [`StatsCollector`] is written to the checkpoint file.
+//! The part is the fold during crossvalidation and the batch of test data during classification.
+//! A resumed run restores the [`StatsCollector`] and skips all finished combinations.
+//!
+//! The misclassifications are written through a [`CheckpointedOutput`].
+//! The checkpoint stores how many lines it covers, such that a resumed run removes the lines of the unfinished combinations instead of writing them twice.
+
+use crate::{config::ExperimentConfig, stats::StatsCollector};
+use anyhow::{bail, Context as _, Error};
+use log::info;
+use misc_utils::fs::{file_write, read_to_string};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeSet,
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+#[derive(Debug)]
+pub(crate) struct Checkpointer {
+    /// File to store the checkpoints in, or `None` if checkpointing is disabled
+    path: Option<PathBuf>,
+    /// All options of this run influencing the results
+    ///
+    /// A checkpoint can only be resumed with the same configuration.
+    configuration: ExperimentConfig,
+    /// Finished combinations of part and `k`
+    completed: BTreeSet<(usize, usize)>,
+    /// Number of lines of the [`CheckpointedOutput`] covered by the restored checkpoint
+    ///
+    /// `None` if no checkpoint was restored.
+    restored_output_lines: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+struct CheckpointRef<'a> {
+    configuration: &'a ExperimentConfig,
+    completed: &'a BTreeSet<(usize, usize)>,
+    output_lines: usize,
+    stats: &'a StatsCollector,
+}
+
+#[derive(Debug, Deserialize)]
+struct Checkpoint {
+    configuration: ExperimentConfig,
+    completed: BTreeSet<(usize, usize)>,
+    output_lines: usize,
+    stats: StatsCollector,
+}
+
+impl Checkpointer {
+    /// Create a new [`Checkpointer`] and return the [`StatsCollector`] to continue with
+    ///
+    /// If `resume` is set and the checkpoint file exists, the [`StatsCollector`] and the finished combinations are restored from it.
+    /// Otherwise, an empty [`StatsCollector`] is returned.
+    pub fn new(
+        path: Option<PathBuf>,
+        configuration: ExperimentConfig,
+        resume: bool,
+    ) -> Result<(Self, StatsCollector), Error> {
+        let mut checkpointer = Self {
+            path,
+            configuration,
+            completed: BTreeSet::new(),
+            restored_output_lines: None,
+        };
+        if !resume {
+            return Ok((checkpointer, StatsCollector::new()));
+        }
+
+        let path = match &checkpointer.path {
+            Some(path) => path,
+            None => bail!("Resuming requires a checkpoint file."),
+        };
+        if !path.exists() {
+            info!(
+                "Checkpoint file '{}' does not exist yet, starting from the beginning.",
+                path.display()
+            );
+            return Ok((checkpointer, StatsCollector::new()));
+        }
+
+        let checkpoint: Checkpoint = serde_json::from_str(&read_to_string(path)?)
+            .with_context(|| format!("Cannot parse checkpoint file '{}'", path.display()))?;
+        if checkpoint.configuration != checkpointer.configuration {
+            bail!(
+                "The checkpoint file '{}' was created with a different configuration:\n{}",
+                path.display(),
+                checkpoint.configuration.to_toml()?
+            );
+        }
+        info!(
+            "Resuming from checkpoint '{}' with {} finished combinations of part and k.",
+            path.display(),
+            checkpoint.completed.len()
+        );
+        checkpointer.completed = checkpoint.completed;
+        checkpointer.restored_output_lines = Some(checkpoint.output_lines);
+        Ok((checkpointer, checkpoint.stats))
+    }
+
+    /// Open the line based output file at `path`, or discard the output if `path` is `None`
+    ///
+    /// After restoring a checkpoint, the existing file is truncated to the lines covered by the checkpoint.
+    /// Otherwise, the file is created anew.
+    pub fn open_output(&self, path: Option<&Path>) -> Result<CheckpointedOutput, Error> {
+        let path = match path {
+            Some(path) => path,
+            None => {
+                return Ok(CheckpointedOutput {
+                    writer: Box::new(io::sink()),
+                    lines: 0,
+                })
+            }
+        };
+
+        let kept_lines = match self.restored_output_lines {
+            Some(lines) if lines > 0 => {
+                let content = read_to_string(path).with_context(|| {
+                    format!("Cannot read the existing output file '{}'", path.display())
+                })?;
+                let kept: Vec<&str> = content.lines().take(lines).collect();
+                if kept.len() != lines {
+                    bail!(
+                        "The output file '{}' has only {} of the {} lines of the checkpoint.",
+                        path.display(),
+                        kept.len(),
+                        lines
+                    );
+                }
+                kept.into_iter().map(|line| format!("{}\n", line)).collect()
+            }
+            _ => String::new(),
+        };
+
+        let mut output = CheckpointedOutput {
+            writer: file_write(path)
+                .create(true)
+                .truncate()
+                .with_context(|| format!("Cannot open output file '{}'", path.display()))?,
+            lines: 0,
+        };
+        output.write_all(kept_lines.as_bytes())?;
+        Ok(output)
+    }
+
+    /// Returns `true` if the combination of `part` and `k` was already finished in a previous run
+    pub fn is_completed(&self, part: usize, k: usize) -> bool {
+        self.completed.contains(&(part, k))
+    }
+
+    /// Mark the combination of `part` and `k` as finished and write the checkpoint
+    ///
+    /// `stats` and `output` must contain the results of this combination.
+    pub fn complete(
+        &mut self,
+        part: usize,
+        k: usize,
+        stats: &StatsCollector,
+        output: &mut CheckpointedOutput,
+    ) -> Result<(), Error> {
+        self.completed.insert((part, k));
+        if let Some(path) = &self.path {
+            // The checkpoint must not cover lines, which are still buffered
+            output.flush().context("Cannot flush the output file")?;
+            write_checkpoint(
+                path,
+                &CheckpointRef {
+                    configuration: &self.configuration,
+                    completed: &self.completed,
+                    output_lines: output.lines,
+                    stats,
+                },
+            )
+            .with_context(|| format!("Cannot write checkpoint file '{}'", path.display()))?;
+        }
+        Ok(())
+    }
+}
+
+/// Line based output file, which counts the lines written to it
+///
+/// See [`Checkpointer::open_output`].
+pub(crate) struct CheckpointedOutput {
+    writer: Box<dyn Write>,
+    /// Number of complete lines written so far
+    lines: usize,
+}
+
+impl Write for CheckpointedOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.writer.write(buf)?;
+        self.lines += buf[..written].iter().filter(|&&b| b == b'\n').count();
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+fn write_checkpoint(path: &Path, checkpoint: &CheckpointRef<'_>) -> Result<(), Error> {
+    let content = serde_json::to_string(checkpoint)?;
+    // Write to a temporary file first, such that an interruption never leaves a partial checkpoint
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, content)?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+#[test]
+fn test_resume_checkpoint() {
+    use sequences::knn::ClassificationResultQuality;
+    use string_cache::DefaultAtom as Atom;
+
+    let dir = std::env::temp_dir().join(format!("dns-sequence-checkpoint-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let checkpoint_path = dir.join("checkpoint.json");
+    let output_path = dir.join("misclassifications.json");
+    let configuration = ExperimentConfig::default();
+
+    // Resuming without an existing checkpoint starts from the beginning
+    let (mut checkpointer, mut stats) =
+        Checkpointer::new(Some(checkpoint_path.clone()), configuration.clone(), true).unwrap();
+    assert!(!checkpointer.is_completed(0, 1));
+    let mut output = checkpointer.open_output(Some(&output_path)).unwrap();
+    stats.update(
+        1,
+        Atom::from("a"),
+        Atom::from("a"),
+        None,
+        None,
+        ClassificationResultQuality::Exact,
+        ClassificationResultQuality::Exact,
+        None,
+    );
+    writeln!(output, "first").unwrap();
+    checkpointer.complete(0, 1, &stats, &mut output).unwrap();
+    // The run is interrupted during the second combination
+    writeln!(output, "second").unwrap();
+    drop(output);
+
+    let (checkpointer, stats) =
+        Checkpointer::new(Some(checkpoint_path.clone()), configuration, true).unwrap();
+    assert!(checkpointer.is_completed(0, 1));
+    assert!(!checkpointer.is_completed(1, 1));
+    assert_eq!(stats.correct_and_total(1).1, 1);
+    // The lines of the unfinished combination are written again, so they must only occur once
+    let mut output = checkpointer.open_output(Some(&output_path)).unwrap();
+    writeln!(output, "second").unwrap();
+    drop(output);
+    assert_eq!("first\nsecond\n", read_to_string(&output_path).unwrap());
+
+    // A checkpoint cannot be resumed with a different configuration
+    let other_configuration: ExperimentConfig = toml::from_str("k = 3").unwrap();
+    assert!(Checkpointer::new(Some(checkpoint_path.clone()), other_configuration, true).is_err());
+    // Without resuming, the checkpoint and the output start empty
+    let (checkpointer, _) =
+        Checkpointer::new(Some(checkpoint_path), ExperimentConfig::default(), false).unwrap();
+    assert!(!checkpointer.is_completed(0, 1));
+    drop(checkpointer.open_output(Some(&output_path)).unwrap());
+    assert_eq!("", read_to_string(&output_path).unwrap());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,276 @@
+//! Compare the classification results of two runs on the same test data
+//!
+//! The runs are read from the files written with `--misclassifications`, which contain one classification result per test sequence and `k`.
+//! Results are paired by the sequence id and `k`, such that both runs must use the same test data, e.g., once with and once without a defense.
+//! A result counts as correct with the same rule as in the statistics, i.e., a quality of at least [`ClassificationResultQuality::PluralityThenMinDist`].
+
+use anyhow::{bail, Context as _, Error};
+use misc_utils::fs::file_open_read;
+use sequences::knn::{ClassificationResult, ClassificationResultQuality};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    f64::consts::LN_2,
+    path::Path,
+};
+
+/// Single line of the `--misclassifications` file
+#[derive(Clone, Debug, Deserialize)]
+pub struct ClassificationRecord {
+    pub id: String,
+    pub k: usize,
+    pub true_label: String,
+    pub label: String,
+    pub class_result: ClassificationResult,
+    #[serde(default)]
+    pub reason: Option<String>,
+}
+
+impl ClassificationRecord {
+    /// Returns `true` if the classifier found the (mapped) label of the sequence
+    pub fn is_correct(&self) -> bool {
+        self.class_result.determine_quality(&self.label)
+            >= ClassificationResultQuality::PluralityThenMinDist
+    }
+}
+
+/// Read all records of a `--misclassifications` file
+pub fn load_classifications(path: &Path) -> Result<Vec<ClassificationRecord>, Error> {
+    let rdr = file_open_read(path)
+        .with_context(|| format!("Cannot open classification file {}", path.display()))?;
+    serde_json::Deserializer::from_reader(rdr)
+        .into_iter()
+        .enumerate()
+        .map(|(idx, record)| {
+            record.with_context(|| format!("Invalid record {} in file {}", idx + 1, path.display()))
+        })
+        .collect()
+}
+
+/// Change of the results of a single domain between the two runs
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct DomainDelta {
+    /// Number of paired sequences
+    pub total: usize,
+    pub correct_first: usize,
+    pub correct_second: usize,
+    /// Number of sequences only correct in the first run
+    pub only_first: usize,
+    /// Number of sequences only correct in the second run
+    pub only_second: usize,
+}
+
+impl DomainDelta {
+    fn add(&mut self, correct_first: bool, correct_second: bool) {
+        self.total += 1;
+        self.correct_first += correct_first as usize;
+        self.correct_second += correct_second as usize;
+        self.only_first += (correct_first && !correct_second) as usize;
+        self.only_second += (!correct_first && correct_second) as usize;
+    }
+
+    pub fn accuracy_first(&self) -> f64 {
+        self.correct_first as f64 / self.total.max(1) as f64
+    }
+
+    pub fn accuracy_second(&self) -> f64 {
+        self.correct_second as f64 / self.total.max(1) as f64
+    }
+
+    /// Change of the accuracy from the first to the second run
+    pub fn delta(&self) -> f64 {
+        self.accuracy_second() - self.accuracy_first()
+    }
+
+    /// McNemar's test whether both runs have the same accuracy
+    pub fn mcnemar(&self) -> McNemar {
+        mcnemar(self.only_first, self.only_second)
+    }
+}
+
+/// Sequence, which is correctly classified in only one of the runs
+#[derive(Clone, Debug, Serialize)]
+pub struct FlippedTrace {
+    pub id: String,
+    pub k: usize,
+    pub label: String,
+    /// Label chosen in the first run
+    pub first: Option<String>,
+    /// Label chosen in the second run
+    pub second: Option<String>,
+    /// `true` if the sequence is only correct in the second run
+    pub fixed: bool,
+}
+
+/// Comparison of the two runs for a single `k`
+#[derive(Clone, Debug, Default)]
+pub struct Comparison {
+    pub k: usize,
+    pub domains: BTreeMap<String, DomainDelta>,
+    pub flipped: Vec<FlippedTrace>,
+    /// Number of sequences, which only occur in the first run
+    pub unpaired_first: usize,
+    /// Number of sequences, which only occur in the second run
+    pub unpaired_second: usize,
+}
+
+impl Comparison {
+    /// Sum of all domains
+    pub fn total(&self) -> DomainDelta {
+        self.domains
+            .values()
+            .fold(DomainDelta::default(), |acc, domain| DomainDelta {
+                total: acc.total + domain.total,
+                correct_first: acc.correct_first + domain.correct_first,
+                correct_second: acc.correct_second + domain.correct_second,
+                only_first: acc.only_first + domain.only_first,
+                only_second: acc.only_second + domain.only_second,
+            })
+    }
+}
+
+/// Pair the results of both runs and compare them separately for each `k`
+///
+/// Returns an error if a sequence occurs multiple times for the same `k` or if the paired results disagree about the label of the sequence.
+pub fn compare(
+    first: Vec<ClassificationRecord>,
+    second: Vec<ClassificationRecord>,
+) -> Result<Vec<Comparison>, Error> {
+    let mut unpaired: HashMap<(usize, String), ClassificationRecord> = HashMap::new();
+    for record in first {
+        let key = (record.k, record.id.clone());
+        if unpaired.contains_key(&key) {
+            bail!(
+                "The sequence {} occurs multiple times for k={} in the first file",
+                key.1,
+                key.0
+            );
+        }
+        unpaired.insert(key, record);
+    }
+
+    let mut comparisons: BTreeMap<usize, Comparison> = BTreeMap::new();
+    let mut seen_second: HashSet<(usize, String)> = HashSet::new();
+    for record in second {
+        let key = (record.k, record.id.clone());
+        if !seen_second.insert(key.clone()) {
+            bail!(
+                "The sequence {} occurs multiple times for k={} in the second file",
+                key.1,
+                key.0
+            );
+        }
+        let comparison = comparisons.entry(record.k).or_insert_with(|| Comparison {
+            k: record.k,
+            ..Comparison::default()
+        });
+        let first = match unpaired.remove(&key) {
+            Some(first) => first,
+            None => {
+                comparison.unpaired_second += 1;
+                continue;
+            }
+        };
+        if first.label != record.label {
+            bail!(
+                "The sequence {} has the label {} in the first file, but {} in the second file",
+                record.id,
+                first.label,
+                record.label
+            );
+        }
+
+        let correct_first = first.is_correct();
+        let correct_second = record.is_correct();
+        comparison
+            .domains
+            .entry(record.label.clone())
+            .or_default()
+            .add(correct_first, correct_second);
+        if correct_first != correct_second {
+            comparison.flipped.push(FlippedTrace {
+                first: first.class_result.best_label().map(ToString::to_string),
+                second: record.class_result.best_label().map(ToString::to_string),
+                fixed: correct_second,
+                id: record.id,
+                k: record.k,
+                label: record.label,
+            });
+        }
+    }
+    for (k, _) in unpaired.keys() {
+        comparisons
+            .entry(*k)
+            .or_insert_with(|| Comparison {
+                k: *k,
+                ..Comparison::default()
+            })
+            .unpaired_first += 1;
+    }
+
+    for comparison in comparisons.values_mut() {
+        comparison
+            .flipped
+            .sort_by(|a, b| (&a.label, &a.id).cmp(&(&b.label, &b.id)));
+    }
+    Ok(comparisons
+        .into_iter()
+        .map(|(_, comparison)| comparison)
+        .collect())
+}
+
+/// Result of McNemar's test
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct McNemar {
+    /// Chi-squared statistic with continuity correction
+    pub statistic: f64,
+    /// Two-sided p-value of the exact binomial test
+    pub p_value: f64,
+}
+
+/// McNemar's test for paired binary outcomes
+///
+/// `only_first` and `only_second` are the numbers of discordant pairs, i.e., sequences correctly classified by only one of the runs.
+/// The p-value is computed exactly from the binomial distribution, which is also valid for few discordant pairs.
+pub fn mcnemar(only_first: usize, only_second: usize) -> McNemar {
+    let n = only_first + only_second;
+    if n == 0 {
+        return McNemar {
+            statistic: 0.,
+            p_value: 1.,
+        };
+    }
+    let diff = (only_first as f64 - only_second as f64).abs();
+    let statistic = (diff - 1.).max(0.).powi(2) / n as f64;
+
+    // P(X <= m) for X ~ Binomial(n, 0.5), summed in log space to support large n
+    let m = only_first.min(only_second);
+    let mut log_terms = Vec::with_capacity(m + 1);
+    let mut log_term = -(n as f64) * LN_2;
+    for i in 0..=m {
+        log_terms.push(log_term);
+        log_term += ((n - i) as f64).ln() - ((i + 1) as f64).ln();
+    }
+    // The terms are increasing, since m <= n / 2
+    let max = log_terms[m];
+    let log_cdf = max + log_terms.iter().map(|t| (t - max).exp()).sum::<f64>().ln();
+    McNemar {
+        statistic,
+        p_value: (2. * log_cdf.exp()).min(1.),
+    }
+}
+
+#[test]
+fn test_mcnemar() {
+    let res = mcnemar(0, 0);
+    assert_eq!(res.p_value, 1.);
+    let res = mcnemar(0, 5);
+    assert!((res.p_value - 0.0625).abs() < 1e-12);
+    assert!((res.statistic - 3.2).abs() < 1e-12);
+    let res = mcnemar(9, 1);
+    assert!((res.p_value - 22. / 1024.).abs() < 1e-12);
+    assert_eq!(mcnemar(7, 7).p_value, 1.);
+    // Large counts must not underflow
+    let res = mcnemar(50_000, 51_000);
+    assert!(res.p_value > 0. && res.p_value < 0.01);
+}
@@ -0,0 +1,451 @@
+//! Experiment configuration files
+//!
+//! All options influencing the results can be specified in a TOML file, which is passed with `--config`.
+//! The keys are the names of the long command line options, e.g., `exact-k = 3` or `simulate = "PerfectTiming"`.
+//! The positional base directory is called `base-dir` and the options of the subcommands are also top-level keys.
+//! Options given on the command line take precedence over the values in the file.
+//!
+//! The resolved configuration, i.e., after combining the file and the command line, is written next to the statistics.
+
+use crate::{quality::QualityMetricSpec, CliArgs, SubCommand};
+use anyhow::{Context as _, Error};
+use log::warn;
+use misc_utils::fs::read_to_string;
+use sequences::{
+    augment::Augmentation,
+    knn::{ClassBalance, EnsembleMember, EnsembleVoting, VoteWeighting, WindowSpec},
+    MarkerPolicy, PageLoadSegmentation, SimulatedCountermeasure,
+};
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DisplayFromStr};
+use std::{
+    ffi::OsString,
+    path::{Path, PathBuf},
+};
+use structopt::clap::ArgMatches;
+
+#[serde_as]
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub(crate) struct ExperimentConfig {
+    base_dir: Option<PathBuf>,
+    confusion_domains: Option<Vec<PathBuf>>,
+    categories: Option<Vec<PathBuf>>,
+    ranking: Option<Vec<PathBuf>>,
+    extension: Option<String>,
+    k: Option<usize>,
+    exact_k: Option<usize>,
+    early_classification: Option<usize>,
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    window_classification: Option<WindowSpec>,
+    min_confidence: Option<f64>,
+    bootstrap: Option<usize>,
+    #[serde_as(as = "Option<Vec<DisplayFromStr>>")]
+    #[serde(default)]
+    quality_metrics: Option<Vec<QualityMetricSpec>>,
+    #[serde_as(as = "Option<Vec<DisplayFromStr>>")]
+    #[serde(default)]
+    ensemble: Option<Vec<EnsembleMember>>,
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    ensemble_voting: Option<EnsembleVoting>,
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    vote_weighting: Option<VoteWeighting>,
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    class_balance: Option<ClassBalance>,
+    #[serde_as(as = "Option<Vec<DisplayFromStr>>")]
+    #[serde(default)]
+    augment_train: Option<Vec<Augmentation>>,
+    #[serde_as(as = "Option<Vec<DisplayFromStr>>")]
+    #[serde(default)]
+    augment_test: Option<Vec<Augmentation>>,
+    train_vantage_points: Option<Vec<String>>,
+    test_vantage_points: Option<Vec<String>>,
+    /// Either `strict` or `tolerant`
+    marker_policy: Option<String>,
+    min_length: Option<usize>,
+    max_length: Option<usize>,
+    min_complexity: Option<usize>,
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    segmentation: Option<PageLoadSegmentation>,
+    dist_thres: Option<f32>,
+    use_cr_mode: Option<bool>,
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    simulate: Option<SimulatedCountermeasure>,
+    /// Only used by the `classify` subcommand
+    test_data: Option<PathBuf>,
+    /// Only used by the `classify` subcommand
+    memory_budget: Option<usize>,
+}
+
+impl ExperimentConfig {
+    /// Load the configuration from the TOML file at `path`
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let content = read_to_string(path)
+            .with_context(|| format!("Cannot read config file '{}'", path.display()))?;
+        toml::from_str(&content)
+            .with_context(|| format!("Cannot parse config file '{}'", path.display()))
+    }
+
+    /// Set all options of `cli_args`, which are not given on the command line according to `matches`
+    pub fn apply(self, cli_args: &mut CliArgs, matches: &ArgMatches<'_>) -> Result<(), Error> {
+        merge(
+            matches,
+            "base_dir",
+            &mut cli_args.base_dir,
+            self.base_dir.map(Some),
+        );
+        merge(
+            matches,
+            "confusion_domains",
+            &mut cli_args.dataset.confusion_domains,
+            self.confusion_domains,
+        );
+        merge(
+            matches,
+            "categories",
+            &mut cli_args.categories,
+            self.categories,
+        );
+        merge(matches, "ranking", &mut cli_args.ranking, self.ranking);
+        merge(
+            matches,
+            "file_extension",
+            &mut cli_args.dataset.file_extension,
+            self.extension.map(OsString::from),
+        );
+        merge(matches, "k", &mut cli_args.k, self.k);
+        merge(
+            matches,
+            "exact_k",
+            &mut cli_args.exact_k,
+            self.exact_k.map(Some),
+        );
+        merge(
+            matches,
+            "early_classification",
+            &mut cli_args.early_classification,
+            self.early_classification.map(Some),
+        );
+        merge(
+            matches,
+            "window_classification",
+            &mut cli_args.window_classification,
+            self.window_classification.map(Some),
+        );
+        merge(
+            matches,
+            "min_confidence",
+            &mut cli_args.min_confidence,
+            self.min_confidence.map(Some),
+        );
+        merge(
+            matches,
+            "bootstrap",
+            &mut cli_args.bootstrap,
+            self.bootstrap.map(Some),
+        );
+        merge(
+            matches,
+            "quality_metrics",
+            &mut cli_args.quality_metrics,
+            self.quality_metrics,
+        );
+        merge(matches, "ensemble", &mut cli_args.ensemble, self.ensemble);
+        merge(
+            matches,
+            "ensemble_voting",
+            &mut cli_args.ensemble_voting,
+            self.ensemble_voting,
+        );
+        merge(
+            matches,
+            "vote_weighting",
+            &mut cli_args.vote_weighting,
+            self.vote_weighting,
+        );
+        merge(
+            matches,
+            "class_balance",
+            &mut cli_args.class_balance,
+            self.class_balance,
+        );
+        merge(
+            matches,
+            "augment_train",
+            &mut cli_args.augment_train,
+            self.augment_train,
+        );
+        merge(
+            matches,
+            "augment_test",
+            &mut cli_args.augment_test,
+            self.augment_test,
+        );
+        merge(
+            matches,
+            "train_vantage_points",
+            &mut cli_args.train_vantage_points,
+            self.train_vantage_points,
+        );
+        merge(
+            matches,
+            "test_vantage_points",
+            &mut cli_args.test_vantage_points,
+            self.test_vantage_points,
+        );
+        let marker_policy = self
+            .marker_policy
+            .map(|policy| policy.parse::<MarkerPolicy>())
+            .transpose()?;
+        merge(
+            matches,
+            "marker_policy",
+            &mut cli_args.dataset.marker_policy,
+            marker_policy,
+        );
+        merge(
+            matches,
+            "min_length",
+            &mut cli_args.dataset.min_length,
+            self.min_length.map(Some),
+        );
+        merge(
+            matches,
+            "max_length",
+            &mut cli_args.dataset.max_length,
+            self.max_length.map(Some),
+        );
+        merge(
+            matches,
+            "min_complexity",
+            &mut cli_args.dataset.min_complexity,
+            self.min_complexity.map(Some),
+        );
+        merge(
+            matches,
+            "segmentation",
+            &mut cli_args.dataset.segmentation,
+            self.segmentation,
+        );
+
+        match &mut cli_args.cmd {
+            None => unreachable!("The `SubCommand` is set before applying the config file."),
+            Some(SubCommand::Crossvalidate {
+                distance_threshold,
+                use_cr_mode,
+                simulate,
+            }) => {
+                let matches = matches.subcommand_matches("crossvalidate");
+                merge_sub(
+                    matches,
+                    "distance_threshold",
+                    distance_threshold,
+                    self.dist_thres.map(Some),
+                );
+                merge_sub(matches, "use_cr_mode", use_cr_mode, self.use_cr_mode);
+                merge_sub(matches, "simulate", simulate, self.simulate);
+                if self.test_data.is_some() || self.memory_budget.is_some() {
+                    warn!("The options `test-data` and `memory-budget` of the config file are only used for classification.");
+                }
+            }
+            Some(SubCommand::Classify {
+                test_data,
+                distance_threshold,
+                use_cr_mode,
+                simulate,
+                memory_budget,
+            }) => {
+                let matches = matches.subcommand_matches("classify");
+                merge_sub(matches, "test_data", test_data, self.test_data.map(Some));
+                merge_sub(
+                    matches,
+                    "distance_threshold",
+                    distance_threshold,
+                    self.dist_thres.map(Some),
+                );
+                merge_sub(matches, "use_cr_mode", use_cr_mode, self.use_cr_mode);
+                merge_sub(matches, "simulate", simulate, self.simulate);
+                merge_sub(
+                    matches,
+                    "memory_budget",
+                    memory_budget,
+                    self.memory_budget.map(Some),
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Describe all options of `cli_args` influencing the results as a configuration
+    pub fn resolved(cli_args: &CliArgs) -> Self {
+        let marker_policy = if cli_args.dataset.marker_policy == MarkerPolicy::tolerant() {
+            "tolerant"
+        } else {
+            "strict"
+        };
+        let mut config = Self {
+            base_dir: cli_args.base_dir.clone(),
+            confusion_domains: Some(cli_args.dataset.confusion_domains.clone()),
+            categories: Some(cli_args.categories.clone()),
+            ranking: Some(cli_args.ranking.clone()),
+            extension: Some(
+                cli_args
+                    .dataset
+                    .file_extension
+                    .to_string_lossy()
+                    .into_owned(),
+            ),
+            k: Some(cli_args.k),
+            exact_k: cli_args.exact_k,
+            early_classification: cli_args.early_classification,
+            window_classification: cli_args.window_classification,
+            min_confidence: cli_args.min_confidence,
+            bootstrap: cli_args.bootstrap,
+            quality_metrics: Some(cli_args.quality_metrics.clone()),
+            ensemble: Some(cli_args.ensemble.clone()),
+            ensemble_voting: Some(cli_args.ensemble_voting),
+            vote_weighting: Some(cli_args.vote_weighting),
+            class_balance: Some(cli_args.class_balance),
+            augment_train: Some(cli_args.augment_train.clone()),
+            augment_test: Some(cli_args.augment_test.clone()),
+            train_vantage_points: Some(cli_args.train_vantage_points.clone()),
+            test_vantage_points: Some(cli_args.test_vantage_points.clone()),
+            marker_policy: Some(marker_policy.to_string()),
+            min_length: cli_args.dataset.min_length,
+            max_length: cli_args.dataset.max_length,
+            min_complexity: cli_args.dataset.min_complexity,
+            segmentation: Some(cli_args.dataset.segmentation),
+            ..Self::default()
+        };
+        match &cli_args.cmd {
+            None => {}
+            Some(SubCommand::Crossvalidate {
+                distance_threshold,
+                use_cr_mode,
+                simulate,
+            }) => {
+                config.dist_thres = *distance_threshold;
+                config.use_cr_mode = Some(*use_cr_mode);
+                config.simulate = Some(*simulate);
+            }
+            Some(SubCommand::Classify {
+                test_data,
+                distance_threshold,
+                use_cr_mode,
+                simulate,
+                memory_budget,
+            }) => {
+                config.test_data = test_data.clone();
+                config.dist_thres = *distance_threshold;
+                config.use_cr_mode = Some(*use_cr_mode);
+                config.simulate = Some(*simulate);
+                config.memory_budget = *memory_budget;
+            }
+        }
+        config
+    }
+
+    /// Serialize the configuration in the format of the config file
+    pub fn to_toml(&self) -> Result<String, Error> {
+        Ok(toml::to_string(self)?)
+    }
+}
+
+/// Overwrite `target` with `value`, unless the option `name` is given on the command line
+fn merge<T>(matches: &ArgMatches<'_>, name: &str, target: &mut T, value: Option<T>) {
+    merge_sub(Some(matches), name, target, value)
+}
+
+/// Same as [`merge`] for the options of a subcommand, which might not be given at all
+fn merge_sub<T>(matches: Option<&ArgMatches<'_>>, name: &str, target: &mut T, value: Option<T>) {
+    let on_command_line = matches
+        .map(|matches| matches.occurrences_of(name) > 0)
+        .unwrap_or(false);
+    if on_command_line {
+        return;
+    }
+    if let Some(value) = value {
+        *target = value;
+    }
+}
+
+#[test]
+fn test_command_line_overrides_config() {
+    use structopt::StructOpt;
+
+    let config: ExperimentConfig = toml::from_str(
+        r#"
+        base-dir = "/data/train"
+        k = 9
+        exact-k = 3
+        simulate = "DpLaplace:0.5"
+        use-cr-mode = true
+        vote-weighting = "rank"
+        augment-test = ["drop:0.1", "gap-jitter:2"]
+        test-data = "/data/test"
+        min-length = 2
+        max-length = 100
+        segmentation = "ChangePoint:4"
+        "#,
+    )
+    .unwrap();
+
+    let matches = CliArgs::clap().get_matches_from(vec![
+        "dns-sequence",
+        "-k",
+        "5",
+        "--max-length",
+        "40",
+        "classify",
+        "--simulate",
+        "PerfectTiming",
+    ]);
+    let mut cli_args = CliArgs::from_clap(&matches);
+    config.apply(&mut cli_args, &matches).unwrap();
+
+    assert_eq!(cli_args.base_dir, Some(PathBuf::from("/data/train")));
+    assert_eq!(cli_args.k, 5);
+    assert_eq!(cli_args.exact_k, Some(3));
+    assert_eq!(cli_args.vote_weighting, VoteWeighting::Rank);
+    assert_eq!(cli_args.dataset.min_length, Some(2));
+    assert_eq!(cli_args.dataset.max_length, Some(40));
+    assert_eq!(cli_args.dataset.min_complexity, None);
+    assert_eq!(
+        cli_args.dataset.segmentation,
+        PageLoadSegmentation::ChangePoint(4)
+    );
+    assert_eq!(
+        cli_args.augment_test,
+        vec!["drop:0.1".parse().unwrap(), Augmentation::GapJitter(2)]
+    );
+    match &cli_args.cmd {
+        Some(SubCommand::Classify {
+            test_data,
+            use_cr_mode,
+            simulate,
+            ..
+        }) => {
+            assert_eq!(test_data, &Some(PathBuf::from("/data/test")));
+            assert!(use_cr_mode);
+            assert_eq!(*simulate, SimulatedCountermeasure::PerfectTiming);
+        }
+        cmd => panic!("Unexpected subcommand {:?}", cmd),
+    }
+
+    // The resolved configuration describes the same experiment
+    let resolved = ExperimentConfig::resolved(&cli_args);
+    let reloaded: ExperimentConfig = toml::from_str(&resolved.to_toml().unwrap()).unwrap();
+    assert_eq!(reloaded, resolved);
+}
+
+#[test]
+fn test_config_rejects_unknown_keys() {
+    assert!(toml::from_str::<ExperimentConfig>("dist-threshold = 0.1").is_err());
+    assert!(toml::from_str::<ExperimentConfig>(r#"simulate = "Perfect""#).is_err());
+}
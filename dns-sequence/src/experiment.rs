@@ -0,0 +1,165 @@
+//! Provenance of experiment results
+//!
+//! An [`Experiment`] collects everything needed to reproduce a run: the git commit and crate versions of the code, the host, the resolved configuration, hashes of the datasets, and the wall-clock time of the individual phases.
+//! It is written as `manifest.json` into the directories containing the outputs of the run.
+
+use anyhow::{Context as _, Error};
+use log::warn;
+use serde::Serialize;
+use serde_json::Value;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::Path,
+    process::Command,
+    time::{Duration, Instant},
+};
+
+/// File name of the manifest, which is placed next to the outputs
+pub const MANIFEST_FILE_NAME: &str = "manifest.json";
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Experiment {
+    /// Commit of the source tree at build time, if it was built from a git checkout
+    git_commit: Option<&'static str>,
+    /// The source tree contained uncommitted changes at build time
+    git_dirty: Option<bool>,
+    crate_versions: BTreeMap<&'static str, &'static str>,
+    hostname: Option<String>,
+    configuration: Value,
+    /// Hash of each dataset, see [`sequences::knn::dataset_hash`]
+    dataset_hashes: BTreeMap<String, String>,
+    timings: Vec<Timing>,
+    #[serde(skip)]
+    started: Instant,
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct Timing {
+    phase: String,
+    seconds: f64,
+}
+
+impl Experiment {
+    /// Start recording a new experiment run with the resolved `configuration`
+    pub fn new(configuration: &impl Serialize) -> Result<Self, Error> {
+        let mut crate_versions = BTreeMap::new();
+        crate_versions.insert(env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+        crate_versions.insert("sequences", sequences::VERSION);
+
+        Ok(Self {
+            // Both are set by the build script
+            git_commit: option_env!("DNS_SEQUENCE_GIT_COMMIT"),
+            git_dirty: option_env!("DNS_SEQUENCE_GIT_DIRTY").map(|dirty| dirty == "true"),
+            crate_versions,
+            hostname: command_output(Command::new("hostname")),
+            configuration: serde_json::to_value(configuration)
+                .context("Cannot serialize the experiment configuration")?,
+            dataset_hashes: BTreeMap::new(),
+            timings: Vec::new(),
+            started: Instant::now(),
+        })
+    }
+
+    /// Record the hash of the dataset `name`
+    pub fn add_dataset_hash(&mut self, name: impl Into<String>, hash: String) {
+        self.dataset_hashes.insert(name.into(), hash);
+    }
+
+    /// Record the wall-clock time needed for `phase`
+    pub fn add_timing(&mut self, phase: impl Into<String>, duration: Duration) {
+        self.timings.push(Timing {
+            phase: phase.into(),
+            seconds: duration.as_secs_f64(),
+        });
+    }
+
+    /// Write the manifest into the directory of each of the `outputs`
+    ///
+    /// The total runtime is recorded as the last timing.
+    /// Each directory only receives one manifest, even if it contains multiple outputs.
+    pub fn write_manifests<'a>(
+        mut self,
+        outputs: impl IntoIterator<Item = &'a Path>,
+    ) -> Result<(), Error> {
+        self.add_timing("total", self.started.elapsed());
+        let manifest = serde_json::to_string_pretty(&self)?;
+
+        let dirs: BTreeSet<&Path> = outputs
+            .into_iter()
+            .map(|output| match output.parent() {
+                Some(dir) if dir != Path::new("") => dir,
+                _ => Path::new("."),
+            })
+            .collect();
+        for dir in dirs {
+            let path = dir.join(MANIFEST_FILE_NAME);
+            std::fs::write(&path, &manifest)
+                .with_context(|| format!("Cannot write manifest '{}'", path.display()))?;
+        }
+        Ok(())
+    }
+}
+
+/// Return the trimmed stdout of `cmd`, if it runs successfully
+fn command_output(mut cmd: Command) -> Option<String> {
+    match cmd.output() {
+        Ok(output) if output.status.success() => {
+            Some(String::from_utf8_lossy(&output.stdout).trim().to_string())
+        }
+        Ok(output) => {
+            warn!(
+                "Command {:?} failed: {}",
+                cmd,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+            None
+        }
+        Err(err) => {
+            warn!("Cannot run command {:?}: {}", cmd, err);
+            None
+        }
+    }
+}
+
+#[test]
+fn test_write_manifests() {
+    let dir = std::env::temp_dir().join(format!("dns-sequence-manifest-{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("sub")).unwrap();
+
+    let mut experiment = Experiment::new(&serde_json::json!({ "k": 3 })).unwrap();
+    experiment.add_dataset_hash("training", "abc".to_string());
+    experiment.add_timing("loading", Duration::from_secs(2));
+    let outputs = [
+        dir.join("statistics.json"),
+        dir.join("misclassifications.json"),
+        dir.join("sub").join("results.json"),
+    ];
+    experiment
+        .write_manifests(outputs.iter().map(|path| path.as_path()))
+        .unwrap();
+
+    for manifest_dir in &[dir.clone(), dir.join("sub")] {
+        let manifest: Value = serde_json::from_str(
+            &std::fs::read_to_string(manifest_dir.join(MANIFEST_FILE_NAME)).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(manifest["configuration"], serde_json::json!({ "k": 3 }));
+        assert_eq!(manifest["dataset_hashes"]["training"], "abc");
+        assert_eq!(
+            manifest["crate_versions"][env!("CARGO_PKG_NAME")],
+            env!("CARGO_PKG_VERSION")
+        );
+        let timings = manifest["timings"].as_array().unwrap();
+        assert_eq!(timings[0]["phase"], "loading");
+        assert_eq!(timings[0]["seconds"], 2.);
+        assert_eq!(timings.last().unwrap()["phase"], "total");
+        // The commit is embedded at build time, if the crate is built from a git checkout
+        if let Some(commit) = manifest["git_commit"].as_str() {
+            assert_eq!(commit.len(), 40);
+            assert!(commit.chars().all(|c| c.is_ascii_hexdigit()));
+            assert!(manifest["git_dirty"].is_boolean());
+        }
+    }
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -1,39 +1,33 @@
+pub mod compare;
+pub mod experiment;
+pub mod significance;
+
 use anyhow::{anyhow, Context as _, Error};
 use csv::ReaderBuilder;
 use log::{error, info, warn};
 use misc_utils::fs::file_open_read;
 use once_cell::sync::Lazy;
-use sequences::{knn::LabelledSequences, LoadSequenceConfig, Sequence, SimulatedCountermeasure};
-use serde::Deserialize;
+use sequences::{
+    conversion_cache::ConversionCache, knn::LabelledSequences, FilteredSequences,
+    LoadSequenceConfig, MarkerPolicy, PageLoadSegmentation, Sequence, SequenceElement,
+    SimulatedCountermeasure,
+};
+use serde::{de::DeserializeOwned, Deserialize};
 use std::{
     collections::HashMap,
-    ffi::OsStr,
-    path::Path,
+    ffi::{OsStr, OsString},
+    fmt::Display,
+    mem,
+    path::{Path, PathBuf},
     sync::{Arc, RwLock},
 };
 use string_cache::DefaultAtom as Atom;
-use structopt::clap::arg_enum;
+use structopt::StructOpt;
 
 static CONFUSION_DOMAINS: Lazy<RwLock<Arc<HashMap<Atom, Atom>>>> = Lazy::new(Default::default);
-
-arg_enum! {
-    #[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
-    pub enum SimulateOption {
-        Normal,
-        PerfectPadding,
-        PerfectTiming,
-    }
-}
-
-impl From<SimulateOption> for SimulatedCountermeasure {
-    fn from(so: SimulateOption) -> Self {
-        match so {
-            SimulateOption::Normal => SimulatedCountermeasure::None,
-            SimulateOption::PerfectPadding => SimulatedCountermeasure::PerfectPadding,
-            SimulateOption::PerfectTiming => SimulatedCountermeasure::PerfectTiming,
-        }
-    }
-}
+static DOMAIN_CATEGORIES: Lazy<RwLock<Arc<HashMap<Atom, Atom>>>> = Lazy::new(Default::default);
+static DOMAIN_RANKS: Lazy<RwLock<Arc<HashMap<Atom, u32>>>> = Lazy::new(Default::default);
+static CONVERSION_CACHE: Lazy<RwLock<Option<Arc<ConversionCache>>>> = Lazy::new(Default::default);
 
 pub fn prepare_confusion_domains<D, P>(data: D) -> Result<(), Error>
 where
@@ -75,35 +69,198 @@ where
     Ok(())
 }
 
+/// Load the mapping from domains to their categories, e.g., news, shop, adult, or CDN
+///
+/// Each file is a CSV file without header and with the two columns domain and category.
+/// Lines starting with `#` are comments.
+/// Use [`make_domain_categories`] to look up the category of a domain.
+pub fn prepare_domain_categories<D, P>(data: D) -> Result<(), Error>
+where
+    D: IntoIterator<Item = P>,
+    P: AsRef<Path>,
+{
+    let categories = read_domain_mapping(data, "category")?;
+    let mut lock = DOMAIN_CATEGORIES.write().unwrap();
+    *lock = Arc::new(categories);
+    Ok(())
+}
+
+/// Load the popularity rank of the domains, e.g., in the Tranco or Alexa list
+///
+/// Each file is a CSV file without header and with the two columns domain and rank, as created by `fetch-ranking`.
+/// Lines starting with `#` are comments.
+/// Use [`make_domain_ranks`] to look up the rank of a domain.
+pub fn prepare_domain_ranks<D, P>(data: D) -> Result<(), Error>
+where
+    D: IntoIterator<Item = P>,
+    P: AsRef<Path>,
+{
+    let ranks = read_domain_mapping(data, "rank")?;
+    let mut lock = DOMAIN_RANKS.write().unwrap();
+    *lock = Arc::new(ranks);
+    Ok(())
+}
+
+/// Store the converted pcap and dnstap files in `dir` and reuse them in later runs
+///
+/// All following calls of [`load_all_files`] and [`iter_all_files`] use the [`ConversionCache`].
+pub fn prepare_conversion_cache(dir: PathBuf) -> Result<(), Error> {
+    let cache = ConversionCache::new(dir)?;
+    let mut lock = CONVERSION_CACHE.write().unwrap();
+    *lock = Some(Arc::new(cache));
+    Ok(())
+}
+
+/// Read CSV files mapping each domain to a value, the `kind` is used in messages
+///
+/// The files have no header and lines starting with `#` are comments.
+pub fn read_domain_mapping<D, P, T>(data: D, kind: &str) -> Result<HashMap<Atom, T>, Error>
+where
+    D: IntoIterator<Item = P>,
+    P: AsRef<Path>,
+    T: DeserializeOwned + PartialEq + Display,
+{
+    let mut mapping = HashMap::default();
+
+    for path in data {
+        let path = path.as_ref();
+        let file = file_open_read(path)
+            .with_context(|| format!("Opening {} file '{}' failed", kind, path.display()))?;
+        let mut reader = ReaderBuilder::new()
+            .has_headers(false)
+            .comment(Some(b'#'))
+            .from_reader(file);
+        for record in reader.deserialize() {
+            let (domain, value): (Atom, T) =
+                record.with_context(|| format!("Invalid {} file '{}'", kind, path.display()))?;
+            match mapping.get(&domain) {
+                Some(existing) if *existing != value => error!(
+                    "Duplicate {} for domain '{}': 1) '{}' 2) '{}'",
+                    kind, domain, existing, value
+                ),
+                _ => {}
+            }
+            mapping.insert(domain, value);
+        }
+    }
+
+    Ok(mapping)
+}
+
+/// Options to load a labelled dataset, shared by the binaries of this crate
+///
+/// Include them into the command line arguments with `#[structopt(flatten)]`.
+#[derive(StructOpt, Debug, Clone)]
+pub struct DatasetOptions {
+    /// Some domains are known similar. Specify a CSV file renaming the "original" domain to some other identifier.
+    /// This option can be applied multiple times. It is not permitted to have conflicting entries to the same domain.
+    #[structopt(short = "d", long = "confusion_domains", parse(from_os_str))]
+    pub confusion_domains: Vec<PathBuf>,
+    /// File extension which must be available in the file to be recognized as a Sequence file
+    ///
+    /// This can be `pcap`, `dnstap`, `json`
+    #[structopt(
+        long = "extension",
+        value_name = "ext",
+        default_value = "dnstap",
+        parse(from_os_str)
+    )]
+    pub file_extension: OsString,
+    /// Expected marker queries in dnstap files: `strict` or `tolerant`
+    ///
+    /// `tolerant` accepts retried marker queries and missing marker responses.
+    #[structopt(long = "marker-policy", default_value = "strict")]
+    pub marker_policy: MarkerPolicy,
+    /// Skip sequences with fewer messages, e.g., parked domains or pages with a single query
+    ///
+    /// The length and complexity filters are checked before simulating a countermeasure.
+    #[structopt(long = "min-length", value_name = "messages")]
+    pub min_length: Option<usize>,
+    /// Skip sequences with more messages
+    #[structopt(long = "max-length", value_name = "messages")]
+    pub max_length: Option<usize>,
+    /// Skip sequences with a lower complexity, i.e., the sum of all message sizes in blocks
+    #[structopt(long = "min-complexity")]
+    pub min_complexity: Option<usize>,
+    /// Remove trailing traffic after the initial page load: `None` or `ChangePoint:<n>`
+    ///
+    /// `ChangePoint:<n>` truncates a trace where the gaps become at least 2^n times longer than during the page load.
+    #[structopt(long = "segmentation", default_value = "None")]
+    pub segmentation: PageLoadSegmentation,
+}
+
+impl DatasetOptions {
+    /// Load all sequences in `base_dir` with these options, see [`load_all_files`]
+    ///
+    /// The confusion domains must be prepared before, see [`prepare_confusion_domains`].
+    pub fn load(
+        &self,
+        base_dir: &Path,
+        simulate: SimulatedCountermeasure,
+    ) -> Result<Vec<LabelledSequences>, Error> {
+        load_all_files(
+            base_dir,
+            &self.file_extension,
+            self.sequence_config(simulate),
+        )
+    }
+
+    /// Configuration to load the individual sequences with these options
+    pub fn sequence_config(&self, simulate: SimulatedCountermeasure) -> LoadSequenceConfig {
+        LoadSequenceConfig {
+            simulated_countermeasure: simulate,
+            marker_policy: self.marker_policy,
+            segmentation: self.segmentation,
+            min_length: self.min_length,
+            max_length: self.max_length,
+            min_complexity: self.min_complexity,
+            ..LoadSequenceConfig::default()
+        }
+    }
+}
+
 pub fn load_all_files(
     base_dir: &Path,
     file_extension: &OsStr,
-    simulate: SimulateOption,
+    sequence_config: LoadSequenceConfig,
 ) -> Result<Vec<LabelledSequences>, Error> {
     // Support to read a pre-processed JSON file instead of reading many directories from disk
     // Implementing this here means this works in all cases
     if base_dir.is_file() {
         let s = misc_utils::fs::read_to_string(base_dir)
             .with_context(|| anyhow!("Could not open {} to read from it.", base_dir.display()))?;
-        return serde_json::from_str(&s).with_context(|| {
-            anyhow!(
-                "The file {} could not be deserialized into LabelledSequences",
-                base_dir.display()
-            )
-        });
+        let mut data: Vec<LabelledSequences> =
+            sequences::from_json_any_version(&s).with_context(|| {
+                anyhow!(
+                    "The file {} could not be deserialized into LabelledSequences",
+                    base_dir.display()
+                )
+            })?;
+        // The preprocessed sequences can only be filtered after loading
+        if sequence_config.has_filters() {
+            let mut filtered = FilteredSequences::default();
+            for lseqs in &mut data {
+                filtered += sequence_config.filter_sequences(&mut lseqs.sequences);
+            }
+            data.retain(|lseqs| !lseqs.sequences.is_empty());
+            info!(
+                "Filtered {} sequences while loading '{}': {}",
+                filtered.total(),
+                base_dir.display(),
+                filtered
+            );
+        }
+        return Ok(data);
     }
 
     let check_confusion_domains = make_check_confusion_domains();
 
-    let sequence_config = LoadSequenceConfig {
-        simulated_countermeasure: simulate.into(),
-        ..LoadSequenceConfig::default()
-    };
-
-    let seqs = sequences::load_all_files_with_extension_from_dir_with_config(
+    let conversion_cache = CONVERSION_CACHE.read().unwrap().clone();
+    let seqs = sequences::load_all_files_with_extension_from_dir_with_cache(
         base_dir,
         file_extension,
         sequence_config,
+        conversion_cache.as_deref(),
     )
     .with_context(|| {
         format!(
@@ -126,6 +283,141 @@ pub fn load_all_files(
         .collect::<Vec<_>>())
 }
 
+/// Lazily load the [`LabelledSequences`] from `base_dir`, one domain at a time
+///
+/// This is the same as [`load_all_files`], but only a single domain is loaded at once.
+/// Unlike [`load_all_files`], `base_dir` must be a directory.
+pub fn iter_all_files<'a>(
+    base_dir: &Path,
+    file_extension: &'a OsStr,
+    sequence_config: LoadSequenceConfig,
+) -> Result<impl Iterator<Item = Result<LabelledSequences, Error>> + 'a, Error> {
+    let check_confusion_domains = make_check_confusion_domains();
+
+    let conversion_cache = CONVERSION_CACHE.read().unwrap().clone();
+    let directories = sequences::sequence_directories(base_dir)
+        .with_context(|| format!("Could not list the directories of: {}", base_dir.display()))?;
+    Ok(directories.into_iter().filter_map(move |dir| {
+        sequences::load_sequence_directory_with_cache(
+            &dir,
+            file_extension,
+            sequence_config,
+            conversion_cache.as_deref(),
+        )
+        .with_context(|| {
+            format!(
+                "Could not load some sequence files from dir: {}",
+                dir.display()
+            )
+        })
+        .map(|res| {
+            res.map(|(label, seqs)| {
+                let label = Atom::from(label);
+                let mapped_label = check_confusion_domains(&label);
+                LabelledSequences {
+                    true_domain: label,
+                    mapped_domain: mapped_label,
+                    sequences: seqs,
+                }
+            })
+        })
+        .transpose()
+    }))
+}
+
+/// Estimate the memory in bytes required to hold `data`
+///
+/// This only counts the [`Sequence`]s and their identifiers, since they make up the bulk of the data.
+/// Sequences are interned, such that identical sequences are counted multiple times.
+pub fn estimated_memory(data: &[LabelledSequences]) -> usize {
+    data.iter()
+        .flat_map(|lseqs| &lseqs.sequences)
+        .map(|seq| {
+            mem::size_of::<Sequence>()
+                + seq.len() * mem::size_of::<SequenceElement>()
+                + seq.id().len()
+        })
+        .sum::<usize>()
+        + data.len() * mem::size_of::<LabelledSequences>()
+}
+
+/// Group the [`LabelledSequences`] into batches, which fit into a memory budget
+///
+/// The size of a batch is estimated with [`estimated_memory`].
+/// A batch always contains at least one domain, even if the domain alone exceeds the budget.
+/// Errors while loading a domain are returned immediately.
+pub struct MemoryBoundedBatches<I> {
+    inner: I,
+    /// Memory budget of a batch in bytes
+    budget: usize,
+    /// Domain which did not fit into the previous batch anymore
+    pending: Option<LabelledSequences>,
+}
+
+impl<I> MemoryBoundedBatches<I>
+where
+    I: Iterator<Item = Result<LabelledSequences, Error>>,
+{
+    pub fn new(inner: I, budget: usize) -> Self {
+        Self {
+            inner,
+            budget,
+            pending: None,
+        }
+    }
+}
+
+impl<I> Iterator for MemoryBoundedBatches<I>
+where
+    I: Iterator<Item = Result<LabelledSequences, Error>>,
+{
+    type Item = Result<Vec<LabelledSequences>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut batch: Vec<LabelledSequences> = self.pending.take().into_iter().collect();
+        let mut size = estimated_memory(&batch);
+        for lseqs in &mut self.inner {
+            let lseqs = match lseqs {
+                Ok(lseqs) => lseqs,
+                Err(err) => return Some(Err(err)),
+            };
+            let lseqs_size = estimated_memory(std::slice::from_ref(&lseqs));
+            if !batch.is_empty() && size + lseqs_size > self.budget {
+                self.pending = Some(lseqs);
+                break;
+            }
+            size += lseqs_size;
+            batch.push(lseqs);
+        }
+
+        if batch.is_empty() {
+            None
+        } else {
+            Some(Ok(batch))
+        }
+    }
+}
+
+/// Return a function looking up the category of a domain
+///
+/// The categories are loaded with [`prepare_domain_categories`].
+/// Domains without a category are mapped to [`None`].
+pub fn make_domain_categories() -> impl Fn(&Atom) -> Option<Atom> {
+    let lock = DOMAIN_CATEGORIES.read().unwrap();
+    let categories: Arc<_> = lock.clone();
+    move |domain: &Atom| categories.get(domain).cloned()
+}
+
+/// Return a function looking up the popularity rank of a domain
+///
+/// The ranks are loaded with [`prepare_domain_ranks`].
+/// Domains without a rank are mapped to [`None`].
+pub fn make_domain_ranks() -> impl Fn(&Atom) -> Option<u32> {
+    let lock = DOMAIN_RANKS.read().unwrap();
+    let ranks: Arc<_> = lock.clone();
+    move |domain: &Atom| ranks.get(domain).copied()
+}
+
 fn make_check_confusion_domains() -> impl Fn(&Atom) -> Atom {
     let lock = CONFUSION_DOMAINS.read().unwrap();
     let conf_domains: Arc<_> = lock.clone();
@@ -155,3 +447,91 @@ fn make_check_confusion_domains() -> impl Fn(&Atom) -> Atom {
         curr.into()
     }
 }
+
+#[cfg(test)]
+fn test_domain(domain: &str, sequences: usize) -> LabelledSequences {
+    LabelledSequences {
+        true_domain: domain.into(),
+        mapped_domain: domain.into(),
+        sequences: (0..sequences)
+            .map(|idx| {
+                Sequence::new(
+                    vec![SequenceElement::Size(1); 10],
+                    format!("{}-{}", domain, idx),
+                )
+            })
+            .collect(),
+    }
+}
+
+#[test]
+fn test_memory_bounded_batches() {
+    let domains = vec![
+        test_domain("a.example", 1),
+        test_domain("b.example", 1),
+        test_domain("c.example", 5),
+        test_domain("d.example", 1),
+    ];
+    let single = estimated_memory(&domains[..1]);
+    let batches: Vec<Vec<String>> =
+        MemoryBoundedBatches::new(domains.into_iter().map(Ok), 2 * single)
+            .map(|batch| {
+                batch
+                    .unwrap()
+                    .iter()
+                    .map(|lseqs| lseqs.mapped_domain.to_string())
+                    .collect()
+            })
+            .collect();
+    // The large domain exceeds the budget on its own, but still forms a batch
+    assert_eq!(
+        vec![
+            vec!["a.example".to_string(), "b.example".to_string()],
+            vec!["c.example".to_string()],
+            vec!["d.example".to_string()],
+        ],
+        batches
+    );
+
+    // A budget of zero classifies one domain at a time
+    let batches = MemoryBoundedBatches::new(
+        vec![test_domain("a.example", 1), test_domain("b.example", 1)]
+            .into_iter()
+            .map(Ok),
+        0,
+    );
+    assert_eq!(2, batches.count());
+
+    // Loading errors are passed on
+    let mut batches = MemoryBoundedBatches::new(
+        vec![Ok(test_domain("a.example", 1)), Err(anyhow!("broken"))].into_iter(),
+        usize::MAX,
+    );
+    assert!(batches.next().unwrap().is_err());
+}
+
+#[test]
+fn test_read_domain_mapping_skips_comments() {
+    let dir = std::env::temp_dir().join(format!("dns-sequence-categories-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("categories.csv");
+    std::fs::write(
+        &path,
+        "# domain,category\nexample.com,news\n#shop.example,shop\nexample.org,cdn\n",
+    )
+    .unwrap();
+
+    let categories: HashMap<Atom, Atom> = read_domain_mapping(&[&path], "category").unwrap();
+    assert_eq!(2, categories.len());
+    assert_eq!(
+        Some(&Atom::from("news")),
+        categories.get(&Atom::from("example.com"))
+    );
+    assert_eq!(
+        Some(&Atom::from("cdn")),
+        categories.get(&Atom::from("example.org"))
+    );
+    assert_eq!(None, categories.get(&Atom::from("#shop.example")));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -1,22 +1,42 @@
+mod checkpoint;
+mod config;
 mod jsonl;
+mod neighbor_cache;
+mod plot;
+mod quality;
 mod stats;
 
-use crate::{jsonl::JsonlFormatter, stats::StatsCollector};
-use anyhow::{anyhow, Context as _, Error};
-use dns_sequence::{load_all_files, prepare_confusion_domains, SimulateOption};
-use log::{error, info};
-use misc_utils::fs::file_write;
+use crate::{
+    checkpoint::{CheckpointedOutput, Checkpointer},
+    config::ExperimentConfig,
+    jsonl::JsonlFormatter,
+    neighbor_cache::NeighborCache,
+    quality::QualityMetricSpec,
+    stats::{SequenceFeatures, StatsCollector, BOOTSTRAP_CONFIDENCE},
+};
+use anyhow::{anyhow, bail, Context as _, Error};
+use dns_sequence::{
+    estimated_memory, experiment::Experiment, iter_all_files, make_domain_categories,
+    make_domain_ranks, prepare_confusion_domains, prepare_conversion_cache,
+    prepare_domain_categories, prepare_domain_ranks, DatasetOptions, MemoryBoundedBatches,
+};
+use log::{error, info, warn};
 use sequences::{
-    knn::{self, ClassificationResult, LabelledSequences},
-    Sequence,
+    augment::{self, Augmentation},
+    knn::{
+        self, ClassBalance, ClassificationResult, ClassificationResultQuality, DistanceMetric,
+        Ensemble, EnsembleMember, EnsembleVoting, LabelledSequences, Neighbor, QualityMetric,
+        VoteWeighting, WindowSpec,
+    },
+    Sequence, SimulatedCountermeasure,
 };
 use serde::Serialize;
 use serde_json::Serializer as JsonSerializer;
-use std::{ffi::OsString, fs::OpenOptions, io::Write, path::PathBuf};
+use std::{fs, io::Write, path::PathBuf, time::Instant};
 use string_cache::DefaultAtom as Atom;
 use structopt::StructOpt;
 
-#[derive(StructOpt, Debug)]
+#[derive(StructOpt, Debug, Clone)]
 #[structopt(global_settings(&[
     structopt::clap::AppSettings::ColoredHelp,
     structopt::clap::AppSettings::VersionlessSubcommands
@@ -26,34 +46,200 @@ struct CliArgs {
     #[structopt(subcommand)]
     cmd: Option<SubCommand>,
     /// Base directory containing per domain a folder which contains the dnstap files
+    ///
+    /// This is required, unless it is specified in the config file.
     #[structopt(parse(from_os_str))]
-    base_dir: PathBuf,
-    /// Some domains are known similar. Specify a CSV file renaming the "original" domain to some other identifier.
-    /// This option can be applied multiple times. It is not permitted to have conflicting entries to the same domain.
-    #[structopt(short = "d", long = "confusion_domains", parse(from_os_str))]
-    confusion_domains: Vec<PathBuf>,
+    base_dir: Option<PathBuf>,
+    /// TOML file with the options of the experiment
+    ///
+    /// The keys are the long option names, e.g., `exact-k = 3`, and `base-dir` for the base directory.
+    /// Options given on the command line take precedence over the file.
+    /// The resolved configuration is written next to the statistics file.
+    #[structopt(long = "config", value_name = "FILE", parse(from_os_str))]
+    config: Option<PathBuf>,
+    #[structopt(flatten)]
+    dataset: DatasetOptions,
+    /// CSV file assigning a category to each domain, e.g., news, shop, adult, or CDN
+    ///
+    /// The statistics then contain the accuracy per category of the true domains.
+    /// This option can be applied multiple times.
+    #[structopt(long = "categories", value_name = "FILE", parse(from_os_str))]
+    categories: Vec<PathBuf>,
+    /// CSV file with the popularity rank of each domain, as created by `fetch-ranking`
+    ///
+    /// The statistics then contain the accuracy per order of magnitude of the rank of the true domains.
+    /// This option can be applied multiple times.
+    #[structopt(long = "ranking", value_name = "FILE", parse(from_os_str))]
+    ranking: Vec<PathBuf>,
     /// Path to dump a CSV file containing all the wrongly classified data
+    ///
+    /// The ids of the sequences are relative to the directory of the classified data, i.e., `base_dir` or `--test-data`.
     #[structopt(long = "misclassifications", parse(from_os_str))]
     misclassifications: Option<PathBuf>,
     /// Path for the resulting CSV-statistics file and plot/json-files
+    ///
+    /// The features of each sequence, like the message count and the complexity, are written next to it together with their classification result.
+    /// This allows correlating the properties of the traces with the accuracy.
     #[structopt(long = "statistics", parse(from_os_str))]
     statistics: Option<PathBuf>,
+    /// Path to the checkpoint file, which is updated after each finished combination of fold, or batch of test data, and `k`
+    #[structopt(long = "checkpoint", value_name = "FILE", parse(from_os_str))]
+    checkpoint: Option<PathBuf>,
+    /// Continue the run stored in the checkpoint file
+    ///
+    /// The finished combinations are skipped and the misclassifications of the unfinished ones are removed from the existing file.
+    /// The remaining options must be identical to the ones of the interrupted run.
+    #[structopt(long = "resume", requires = "checkpoint")]
+    resume: bool,
+    /// Directory to store the nearest neighbors of the test data in, one file per fold or batch
+    ///
+    /// Later runs on the same data reuse the stored neighbors for any `k` up to the largest one of the first run, which skips all distance calculations.
+    /// With `--dist-thres` the threshold is applied to the stored neighbors, which gives the same results as without the cache.
+    /// The cache is not used for ensembles.
+    #[structopt(long = "neighbor-cache", value_name = "DIR", parse(from_os_str))]
+    neighbor_cache: Option<PathBuf>,
+    /// Directory to store the Sequences converted from pcap and dnstap files in
+    ///
+    /// The Sequences are stored by the hash of the file and the loading configuration.
+    /// Later runs over the same raw data skip parsing the files.
+    #[structopt(long = "conversion-cache", value_name = "DIR", parse(from_os_str))]
+    conversion_cache: Option<PathBuf>,
     /// The largest `k` to be used for knn. Only odd numbers are tested.
     #[structopt(short = "k", default_value = "1")]
     k: usize,
     /// Only test a single k. Overwrites `-k` option.
     #[structopt(long = "exact-k", value_name = "k")]
     exact_k: Option<usize>,
-    /// File extension which must be available in the file to be recognized as a Sequence file
+    /// Measure how early the classification decision stabilizes
     ///
-    /// This can be `pcap`, `dnstap`, `json`
-    #[structopt(
-        long = "extension",
-        value_name = "ext",
-        default_value = "dnstap",
-        parse(from_os_str)
-    )]
-    file_extension: OsString,
+    /// Each test sequence is additionally classified using prefixes growing in steps of this many elements.
+    /// The CDF of the prefix lengths, after which the decision does not change anymore, is written next to the statistics file.
+    #[structopt(long = "early-classification", value_name = "step")]
+    early_classification: Option<usize>,
+    /// Measure after how many windows the classification is exact, given as `<size>[:<stride>]`
+    ///
+    /// All sequences are split into windows of `size` elements, which start every `stride` elements.
+    /// The stride defaults to the size.
+    /// The windows of each test sequence are classified against the windows of the trainings data and their votes are aggregated.
+    /// The CDF of the number of windows until the aggregated decision is exact is written next to the statistics file.
+    #[structopt(long = "window-classification", value_name = "size[:stride]")]
+    window_classification: Option<WindowSpec>,
+    /// Abstain from classifying sequences if the confidence of the best label is below this value
+    ///
+    /// The confidence is between 0 and 1 and combines the fraction of votes with the distance of the best label.
+    /// Such sequences are counted as `Unclassified`.
+    /// The reliability diagram and the rejection curve, which help choosing this value, are written next to the statistics file.
+    #[structopt(long = "min-confidence", value_name = "confidence")]
+    min_confidence: Option<f64>,
+    /// Estimate 95% confidence intervals of the accuracy, precision, and recall by resampling the results this many times
+    ///
+    /// The intervals per `k` and per mapped domain are written next to the statistics file.
+    /// The intervals over all domains are also printed.
+    /// Values around 1000 give stable intervals.
+    #[structopt(long = "bootstrap", value_name = "resamples")]
+    bootstrap: Option<usize>,
+    /// Additionally count the results as correct or wrong according to this metric
+    ///
+    /// Supported are `exact` for the label with the highest count, `etld1` to accept labels with the same registrable domain, `top:<n>` to accept the real label among the n labels with the most votes, and `confusion-set:<file>` to accept all labels in the same set.
+    /// The set file has the same format as `--confusion_domains`.
+    /// This option can be applied multiple times and the accuracy of each metric is reported.
+    #[structopt(long = "quality-metric", value_name = "metric")]
+    quality_metrics: Vec<QualityMetricSpec>,
+    /// Classify with an ensemble of multiple distance metrics instead of only the edit distance
+    ///
+    /// Each member has the format `<metric>[=<weight>]`, where metric is one of `edit`, `dtw`, `cumul`, or `rle`.
+    /// Multiple members are separated by comma, e.g., `edit=2,dtw,cumul`.
+    /// The distance threshold is not supported for ensembles.
+    #[structopt(long = "ensemble", value_name = "members", use_delimiter = true)]
+    ensemble: Vec<EnsembleMember>,
+    /// How the votes of the ensemble members are combined: `majority` or `weighted`
+    #[structopt(long = "ensemble-voting", default_value = "majority")]
+    ensemble_voting: EnsembleVoting,
+    /// How the k nearest neighbors vote: `uniform`, `inverse-distance`, or `rank`
+    ///
+    /// `uniform` gives each neighbor one vote.
+    /// `inverse-distance` weights each neighbor with `1 / (1 + distance)` and `rank` gives the nearest of the k neighbors k votes, the next one k - 1, and so on.
+    #[structopt(long = "vote-weighting", default_value = "uniform")]
+    vote_weighting: VoteWeighting,
+    /// Balance the number of trainings sequences per domain: `none`, `undersample`, or `cap:<n>`
+    ///
+    /// `undersample` reduces each domain to the size of the smallest domain and `cap:<n>` keeps at most n sequences per domain.
+    /// The domains are the mapped domains and the kept sequences are drawn randomly, but deterministically.
+    /// The statistics contain the resulting number of sequences per domain and the balanced accuracy, which weights each domain equally.
+    #[structopt(long = "class-balance", default_value = "none")]
+    class_balance: ClassBalance,
+    /// Transform the trainings sequences to simulate measurement noise
+    ///
+    /// Supported are `drop:<p>` to drop each message with probability p, `gap-jitter:<n>` to change each gap by up to n, `size-shift:<p>` to change each size by one block with probability p, `truncate:<n>` to only keep the first n elements, and `expired-cache:<TTL model>` to add extra queries for answers expired from the cache.
+    /// The TTL model is either `fixed:<p>` or `exponential:<mean TTL>:<cache age>` in seconds.
+    /// This option can be applied multiple times and the transformations are applied in order.
+    #[structopt(long = "augment-train", value_name = "augmentation")]
+    augment_train: Vec<Augmentation>,
+    /// Transform the test sequences to simulate measurement noise
+    ///
+    /// Accepts the same transformations as `--augment-train`.
+    /// Comparing runs with and without augmentation quantifies the robustness of the classifier.
+    #[structopt(long = "augment-test", value_name = "augmentation")]
+    augment_test: Vec<Augmentation>,
+    /// Only use trainings sequences recorded at this vantage point
+    ///
+    /// The vantage point is part of the file name, e.g., `example.com-1-1@frankfurt.dnstap.xz`, and defaults to `local`.
+    /// This option can be applied multiple times. Without it, sequences of all vantage points are used.
+    #[structopt(long = "train-vantage-point", value_name = "name")]
+    train_vantage_points: Vec<String>,
+    /// Only use test sequences recorded at this vantage point
+    ///
+    /// Together with `--train-vantage-point` this allows to train on one location and test on another one.
+    /// This option can be applied multiple times. Without it, sequences of all vantage points are used.
+    #[structopt(long = "test-vantage-point", value_name = "name")]
+    test_vantage_points: Vec<String>,
+}
+
+impl CliArgs {
+    /// Return the [`NeighborCache`], if it is configured and supported by the classification
+    fn neighbor_cache(&self, use_cr_mode: bool) -> Result<Option<NeighborCache>, Error> {
+        match &self.neighbor_cache {
+            None => Ok(None),
+            Some(_) if self.ensemble().is_some() => {
+                warn!("The neighbor cache is ignored for ensemble classification.");
+                Ok(None)
+            }
+            Some(dir) => Ok(Some(NeighborCache::new(
+                dir.clone(),
+                DistanceMetric::EditDistance,
+                use_cr_mode,
+            )?)),
+        }
+    }
+
+    /// Create the configured [`QualityMetric`]s
+    fn quality_metrics(&self) -> Result<Vec<Box<dyn QualityMetric>>, Error> {
+        self.quality_metrics
+            .iter()
+            .map(QualityMetricSpec::build)
+            .collect()
+    }
+
+    /// Return all values of `k` to test
+    fn ks(&self) -> Vec<usize> {
+        if let Some(exact_k) = self.exact_k {
+            vec![exact_k]
+        } else {
+            (1..=self.k).step_by(2).collect()
+        }
+    }
+
+    /// Return the configured [`Ensemble`], if any members are specified
+    fn ensemble(&self) -> Option<Ensemble> {
+        if self.ensemble.is_empty() {
+            None
+        } else {
+            Some(Ensemble {
+                members: self.ensemble.clone(),
+                voting: self.ensemble_voting,
+            })
+        }
+    }
 }
 
 #[derive(StructOpt, Debug, Clone)]
@@ -71,13 +257,12 @@ enum SubCommand {
         distance_threshold: Option<f32>,
         #[structopt(long = "use-cr-mode")]
         use_cr_mode: bool,
-        #[structopt(
-            long = "simulate",
-            default_value = "Normal",
-            possible_values = &SimulateOption::variants(),
-            case_insensitive = true
-        )]
-        simulate: SimulateOption,
+        /// Countermeasure to simulate while loading the sequences
+        ///
+        /// One of `None`, `PerfectPadding`, `PerfectTiming`, `PerfectPaddingAndTiming`, `CountOnly`, `ConstantLength[:<n>]`, `DpLaplace[:<epsilon>]`, or `DpGaussian[:<epsilon>]`.
+        /// The length defaults to 50 and epsilon to 1, smaller values of epsilon add more noise.
+        #[structopt(long = "simulate", default_value = "None")]
+        simulate: SimulatedCountermeasure,
     },
     /// Perform classification of the test data against the trainings data
     #[structopt(
@@ -89,83 +274,218 @@ enum SubCommand {
     )]
     Classify {
         /// Data to be classified. Directory containing a folder per domain, like `base_dir`.
+        ///
+        /// This is required, unless it is specified in the config file.
         #[structopt(long = "test-data", parse(from_os_str))]
-        test_data: PathBuf,
+        test_data: Option<PathBuf>,
         #[structopt(long = "dist-thres")]
         distance_threshold: Option<f32>,
         #[structopt(long = "use-cr-mode")]
         use_cr_mode: bool,
-        #[structopt(
-            long = "simulate",
-            default_value = "Normal",
-                possible_values = &SimulateOption::variants(),
-                case_insensitive = true
-        )]
-        simulate: SimulateOption,
+        /// Countermeasure to simulate while loading the sequences
+        ///
+        /// One of `None`, `PerfectPadding`, `PerfectTiming`, `PerfectPaddingAndTiming`, `CountOnly`, `ConstantLength[:<n>]`, `DpLaplace[:<epsilon>]`, or `DpGaussian[:<epsilon>]`.
+        /// The length defaults to 50 and epsilon to 1, smaller values of epsilon add more noise.
+        #[structopt(long = "simulate", default_value = "None")]
+        simulate: SimulatedCountermeasure,
+        /// Load the test data one domain at a time and classify it in batches
+        ///
+        /// The budget in MiB covers the trainings data, which is kept in memory, and one batch of test data.
+        /// The memory usage is only estimated, so leave some headroom.
+        /// This requires the test data to be a directory.
+        #[structopt(long = "memory-budget", value_name = "MiB")]
+        memory_budget: Option<usize>,
     },
 }
 
 fn main() -> Result<(), Error> {
     // generic setup
     env_logger::init();
-    let mut cli_args = CliArgs::from_args();
-
-    let writer: Box<dyn Write> = cli_args
-        .misclassifications
-        .as_ref()
-        .map(|path| file_write(path).create(true).truncate())
-        .unwrap_or_else(|| {
-            Ok(Box::new(
-                OpenOptions::new().write(true).open("/dev/null").unwrap(),
-            ))
-        })
-        .context("Cannot open writer for misclassifications.")?;
-    let mut mis_writer = JsonSerializer::with_formatter(writer, JsonlFormatter::new());
+    let matches = CliArgs::clap().get_matches();
+    let mut cli_args = CliArgs::from_clap(&matches);
+    if cli_args.cmd.is_none() {
+        // In case of `None` overwrite it to make sure the individual functions never have to handle a `None`.
+        cli_args.cmd = Some(SubCommand::Crossvalidate {
+            distance_threshold: None,
+            use_cr_mode: false,
+            simulate: SimulatedCountermeasure::None,
+        });
+    }
+    if let Some(path) = &cli_args.config {
+        ExperimentConfig::load(path)?.apply(&mut cli_args, &matches)?;
+    }
+    let base_dir = cli_args.base_dir.clone().ok_or_else(|| {
+        anyhow!("The base directory must be given on the command line or in the config file.")
+    })?;
+    // Fail before spending the time to load the trainings data
+    if let Some(SubCommand::Classify { test_data, .. }) = &cli_args.cmd {
+        match test_data {
+            None => bail!("The test data must be given on the command line or in the config file."),
+            Some(test_data) if !test_data.is_dir() => bail!(
+                "The test data directory '{}' does not exist.",
+                test_data.display()
+            ),
+            Some(_) => {}
+        }
+    }
+    let mut experiment = Experiment::new(&ExperimentConfig::resolved(&cli_args))?;
 
     info!("Start loading confusion domains...");
-    prepare_confusion_domains(&cli_args.confusion_domains)?;
+    prepare_confusion_domains(&cli_args.dataset.confusion_domains)?;
     info!("Done loading confusion domains.");
+    if !cli_args.categories.is_empty() {
+        info!("Start loading domain categories...");
+        prepare_domain_categories(&cli_args.categories)?;
+        info!("Done loading domain categories.");
+    }
+    if !cli_args.ranking.is_empty() {
+        info!("Start loading domain ranks...");
+        prepare_domain_ranks(&cli_args.ranking)?;
+        info!("Done loading domain ranks.");
+    }
+    if let Some(dir) = &cli_args.conversion_cache {
+        prepare_conversion_cache(dir.clone())?;
+    }
 
     info!("Start loading dnstap files...");
+    let start = Instant::now();
     let simulate = match &cli_args.cmd {
-        None => SimulateOption::Normal,
+        None => unreachable!("The `SubCommand` is set above."),
         Some(SubCommand::Crossvalidate { simulate, .. }) => *simulate,
         Some(SubCommand::Classify { simulate, .. }) => *simulate,
     };
-    let training_data = load_all_files(&cli_args.base_dir, &cli_args.file_extension, simulate)?;
+    let mut training_data = cli_args.dataset.load(&base_dir, simulate)?;
     info!(
         "Done loading dnstap files. Found {} domains.",
         training_data.len()
     );
+    experiment.add_timing("loading", start.elapsed());
+    let removed = knn::balance_classes(&mut training_data, cli_args.class_balance);
+    if removed > 0 {
+        info!(
+            "Removed {} sequences to balance the domains ({}).",
+            removed, cli_args.class_balance
+        );
+    }
+    // Hash the data actually used, i.e., after balancing the domains
+    experiment.add_dataset_hash("training", knn::dataset_hash(&training_data, &[])?);
 
     // Collect the stats during the execution and print them at the end
-    let mut stats = StatsCollector::new();
+    let (mut checkpointer, mut stats) = Checkpointer::new(
+        cli_args.checkpoint.clone(),
+        ExperimentConfig::resolved(&cli_args),
+        cli_args.resume,
+    )?;
+    let mut mis_writer = checkpointer
+        .open_output(cli_args.misclassifications.as_deref())
+        .context("Cannot open writer for misclassifications.")?;
+    stats.set_class_distribution(
+        training_data
+            .iter()
+            .map(|lseqs| (lseqs.mapped_domain.clone(), lseqs.sequences.len())),
+    );
 
+    let start = Instant::now();
     match cli_args.cmd {
-        None => {
-            // In case of `None` overwrite it to make sure the individual functions never have to handle a `None`.
-            cli_args.cmd = Some(SubCommand::Crossvalidate {
-                distance_threshold: None,
-                use_cr_mode: false,
-                simulate: SimulateOption::Normal,
-            });
-            run_crossvalidation(&cli_args, training_data, &mut stats, &mut mis_writer)
-        }
-        Some(SubCommand::Crossvalidate { .. }) => {
-            run_crossvalidation(&cli_args, training_data, &mut stats, &mut mis_writer)
-        }
-        Some(SubCommand::Classify { .. }) => {
-            run_classify(&cli_args, training_data, &mut stats, &mut mis_writer)?;
-        }
+        None => unreachable!("The `SubCommand` is set above."),
+        Some(SubCommand::Crossvalidate { .. }) => run_crossvalidation(
+            &cli_args,
+            training_data,
+            &mut stats,
+            &mut checkpointer,
+            &mut mis_writer,
+        )?,
+        Some(SubCommand::Classify { .. }) => run_classify(
+            &cli_args,
+            training_data,
+            &mut stats,
+            &mut checkpointer,
+            &mut mis_writer,
+            &mut experiment,
+        )?,
     }
+    experiment.add_timing("classification", start.elapsed());
 
     // TODO print final stats
     println!("{}", stats);
+    let bootstrap = cli_args.bootstrap.map(|resamples| {
+        info!("Start bootstrapping with {} resamples...", resamples);
+        let intervals = stats.bootstrap(resamples);
+        info!("Done bootstrapping.");
+        intervals
+    });
+    if let Some(intervals) = &bootstrap {
+        println!(
+            "\nBootstrap {:.0}% confidence intervals:",
+            BOOTSTRAP_CONFIDENCE * 100.
+        );
+        for interval in intervals
+            .iter()
+            .filter(|interval| interval.domain.is_none())
+        {
+            if let (Some(estimate), Some(lower), Some(upper)) =
+                (interval.estimate, interval.lower, interval.upper)
+            {
+                println!(
+                    "  k={} {}: {:.2}% [{:.2}%, {:.2}%]",
+                    interval.k,
+                    interval.metric,
+                    estimate * 100.,
+                    lower * 100.,
+                    upper * 100.
+                );
+            }
+        }
+    }
     if let Some(path) = &cli_args.statistics {
         stats.dump_stats_to_file(path)?;
+        fs::write(
+            path.with_extension("config.toml"),
+            ExperimentConfig::resolved(&cli_args).to_toml()?,
+        )
+        .context("Cannot write the resolved configuration.")?;
+        if cli_args.early_classification.is_some() {
+            stats.dump_early_classification_to_file(&path.with_extension("early.csv"))?;
+        }
+        if cli_args.window_classification.is_some() {
+            stats.dump_window_classification_to_file(&path.with_extension("windows.csv"))?;
+        }
+        stats.dump_accuracy_to_file(&path.with_extension("accuracy.csv"))?;
+        if !cli_args.quality_metrics.is_empty() {
+            stats.dump_quality_metrics_to_file(&path.with_extension("metrics.csv"))?;
+        }
+        if let Some(SubCommand::Crossvalidate { .. }) = cli_args.cmd {
+            stats.dump_fold_accuracy_to_file(&path.with_extension("folds.csv"))?;
+        }
+        stats.dump_sequence_features_to_file(&path.with_extension("sequences.csv"))?;
+        stats.dump_class_distribution_to_file(&path.with_extension("classes.csv"))?;
+        if !cli_args.categories.is_empty() {
+            stats.dump_category_accuracy_to_file(&path.with_extension("categories.csv"))?;
+        }
+        if !cli_args.ranking.is_empty() {
+            stats.dump_popularity_accuracy_to_file(&path.with_extension("popularity.csv"))?;
+        }
+        stats.dump_calibration_to_file(&path.with_extension("calibration.csv"))?;
+        stats.dump_rejection_curve_to_file(&path.with_extension("rejection.csv"))?;
+        if let Some(intervals) = &bootstrap {
+            StatsCollector::dump_bootstrap_to_file(
+                intervals,
+                &path.with_extension("bootstrap.csv"),
+            )?;
+        }
         // the file extension will be overwritten later
         stats.plot(&path.with_extension("placeholder"))?;
     }
+    mis_writer
+        .flush()
+        .context("Cannot flush writer for misclassifications.")?;
+    experiment.write_manifests(
+        cli_args
+            .statistics
+            .iter()
+            .chain(&cli_args.misclassifications)
+            .map(PathBuf::as_path),
+    )?;
 
     Ok(())
 }
@@ -174,49 +494,110 @@ fn run_crossvalidation(
     cli_args: &CliArgs,
     data: Vec<LabelledSequences>,
     stats: &mut StatsCollector,
-    mis_writer: &mut JsonSerializer<impl Write, impl serde_json::ser::Formatter>,
-) {
+    checkpointer: &mut Checkpointer,
+    mis_writer: &mut CheckpointedOutput,
+) -> Result<(), Error> {
     if let Some(SubCommand::Crossvalidate {
         distance_threshold,
         use_cr_mode,
         ..
     }) = cli_args.cmd.clone()
     {
-        for fold in 0..10 {
+        let neighbor_cache = cli_args.neighbor_cache(use_cr_mode)?;
+        let quality_metrics = cli_args.quality_metrics()?;
+        let ks = cli_args.ks();
+        for fold in 0..10_u8 {
             info!("Testing for fold {}", fold);
             info!("Start splitting trainings and test data...");
-            let (training_data, test) = knn::split_training_test_data(&*data, fold as u8);
+            let (mut training_data, mut test) = knn::split_training_test_data(&*data, fold);
+            training_data
+                .iter_mut()
+                .for_each(|elem| elem.retain_vantage_points(&cli_args.train_vantage_points));
+            augment::augment_data(&mut training_data, &cli_args.augment_train);
+            if !cli_args.test_vantage_points.is_empty() {
+                test.retain(|elem| {
+                    cli_args
+                        .test_vantage_points
+                        .iter()
+                        .any(|vp| vp == elem.sequence.vantage_point())
+                });
+            }
             let len = test.len();
             let (test_labels, test_data) = test.into_iter().fold(
                 (Vec::with_capacity(len), Vec::with_capacity(len)),
                 |(mut test_labels, mut data), elem| {
                     test_labels.push((elem.true_domain, elem.mapped_domain));
-                    data.push(elem.sequence);
+                    data.push(augment::augment(&elem.sequence, &cli_args.augment_test));
                     (test_labels, data)
                 },
             );
             info!("Done splitting trainings and test data.");
 
-            let ks: Vec<usize>;
-            if let Some(exact_k) = cli_args.exact_k {
-                ks = vec![exact_k];
-            } else {
-                ks = (1..=(cli_args.k)).step_by(2).collect();
-            }
+            let neighbors = cached_neighbors(
+                neighbor_cache.as_ref(),
+                &format!("fold-{}", fold),
+                &ks,
+                |k| checkpointer.is_completed(fold.into(), k),
+                &*training_data,
+                &*test_data,
+            )?;
 
-            for k in ks {
+            for &k in &ks {
+                if checkpointer.is_completed(fold.into(), k) {
+                    info!(
+                        "Skipping fold {} with k={}, which is already finished",
+                        fold, k
+                    );
+                    continue;
+                }
+                let (correct_before, total_before) = stats.correct_and_total(k as u8);
                 classify_and_evaluate(
                     k,
                     distance_threshold,
+                    cli_args.min_confidence,
+                    cli_args.ensemble().as_ref(),
+                    cli_args.vote_weighting,
+                    neighbors.as_deref(),
                     use_cr_mode,
+                    &quality_metrics,
                     &*training_data,
                     &*test_data,
                     &*test_labels,
                     stats,
                     mis_writer,
                 );
+                let (correct, total) = stats.correct_and_total(k as u8);
+                stats.update_fold(
+                    k as u8,
+                    fold,
+                    correct - correct_before,
+                    total - total_before,
+                );
+                if let Some(step) = cli_args.early_classification {
+                    evaluate_early_classification(
+                        k,
+                        step,
+                        use_cr_mode,
+                        &*training_data,
+                        &*test_data,
+                        stats,
+                    );
+                }
+                if let Some(window) = cli_args.window_classification {
+                    evaluate_window_classification(
+                        k,
+                        window,
+                        use_cr_mode,
+                        &*training_data,
+                        &*test_data,
+                        &*test_labels,
+                        stats,
+                    );
+                }
+                checkpointer.complete(fold.into(), k, stats, mis_writer)?;
             }
         }
+        Ok(())
     } else {
         unreachable!("The value of `SubCommand` must be a `Crossvalidate`.")
     }
@@ -224,55 +605,152 @@ fn run_crossvalidation(
 
 fn run_classify(
     cli_args: &CliArgs,
-    data: Vec<LabelledSequences>,
+    mut data: Vec<LabelledSequences>,
     stats: &mut StatsCollector,
-    mis_writer: &mut JsonSerializer<impl Write, impl serde_json::ser::Formatter>,
+    checkpointer: &mut Checkpointer,
+    mis_writer: &mut CheckpointedOutput,
+    experiment: &mut Experiment,
 ) -> Result<(), Error> {
     if let Some(SubCommand::Classify {
         test_data,
         distance_threshold,
         use_cr_mode,
         simulate,
+        memory_budget,
     }) = cli_args.cmd.clone()
     {
-        info!("Start loading test data dnstap files...");
-        let test_data = load_all_files(&test_data, &cli_args.file_extension, simulate)?;
-        info!(
-            "Done loading test data dnstap files. Found {} domains.",
-            test_data.len()
-        );
+        let test_data =
+            test_data.expect("The test data is checked before loading the trainings data");
+        data.iter_mut()
+            .for_each(|elem| elem.retain_vantage_points(&cli_args.train_vantage_points));
+        augment::augment_data(&mut data, &cli_args.augment_train);
 
-        // Separate labels from sequences
-        let len = test_data.len();
-        let (test_labels, test_sequences) = test_data.into_iter().fold(
-            (Vec::with_capacity(len), Vec::with_capacity(len)),
-            |(mut test_labels, mut test_sequences), elem| {
-                for seq in elem.sequences {
-                    test_labels.push((elem.true_domain.clone(), elem.mapped_domain.clone()));
-                    test_sequences.push(seq);
+        let test_batches: Box<dyn Iterator<Item = Result<Vec<LabelledSequences>, Error>>> =
+            if let Some(memory_budget) = memory_budget {
+                // The budget covers the resident trainings data and one batch of test data
+                let training_memory = estimated_memory(&data);
+                let budget = memory_budget
+                    .saturating_mul(1024 * 1024)
+                    .saturating_sub(training_memory);
+                if budget == 0 {
+                    warn!(
+                        "The trainings data alone needs about {} MiB, which exceeds the memory budget. The test data is classified one domain at a time.",
+                        training_memory / 1024 / 1024
+                    );
                 }
-                (test_labels, test_sequences)
-            },
-        );
+                info!(
+                    "Stream test data dnstap files in batches of up to {} MiB.",
+                    budget / 1024 / 1024
+                );
+                Box::new(MemoryBoundedBatches::new(
+                    iter_all_files(
+                        &test_data,
+                        &cli_args.dataset.file_extension,
+                        cli_args.dataset.sequence_config(simulate),
+                    )?,
+                    budget,
+                ))
+            } else {
+                info!("Start loading test data dnstap files...");
+                let test_data = cli_args.dataset.load(&test_data, simulate)?;
+                info!(
+                    "Done loading test data dnstap files. Found {} domains.",
+                    test_data.len()
+                );
+                Box::new(std::iter::once(Ok(test_data)))
+            };
 
-        let ks: Vec<usize>;
-        if let Some(exact_k) = cli_args.exact_k {
-            ks = vec![exact_k];
-        } else {
-            ks = (1..=(cli_args.k)).step_by(2).collect();
-        }
+        let neighbor_cache = cli_args.neighbor_cache(use_cr_mode)?;
+        let quality_metrics = cli_args.quality_metrics()?;
+        let ks = cli_args.ks();
 
-        for k in ks {
-            classify_and_evaluate(
-                k,
-                distance_threshold,
-                use_cr_mode,
+        // Without a memory budget, all test data is a single batch
+        for (batch, test_data) in test_batches.enumerate() {
+            let mut test_data = test_data?;
+            if memory_budget.is_some() {
+                info!(
+                    "Classifying batch {} with {} domains",
+                    batch,
+                    test_data.len()
+                );
+            }
+            experiment.add_dataset_hash(
+                format!("test-batch-{}", batch),
+                knn::dataset_hash(&test_data, &[])?,
+            );
+            test_data
+                .iter_mut()
+                .for_each(|elem| elem.retain_vantage_points(&cli_args.test_vantage_points));
+            augment::augment_data(&mut test_data, &cli_args.augment_test);
+
+            // Separate labels from sequences
+            let len = test_data.len();
+            let (test_labels, test_sequences) = test_data.into_iter().fold(
+                (Vec::with_capacity(len), Vec::with_capacity(len)),
+                |(mut test_labels, mut test_sequences), elem| {
+                    for seq in elem.sequences {
+                        test_labels.push((elem.true_domain.clone(), elem.mapped_domain.clone()));
+                        test_sequences.push(seq);
+                    }
+                    (test_labels, test_sequences)
+                },
+            );
+
+            let neighbors = cached_neighbors(
+                neighbor_cache.as_ref(),
+                &format!("batch-{}", batch),
+                &ks,
+                |k| checkpointer.is_completed(batch, k),
                 &*data,
                 &*test_sequences,
-                &*test_labels,
-                stats,
-                mis_writer,
-            )
+            )?;
+
+            for &k in &ks {
+                if checkpointer.is_completed(batch, k) {
+                    info!(
+                        "Skipping batch {} with k={}, which is already finished",
+                        batch, k
+                    );
+                    continue;
+                }
+                classify_and_evaluate(
+                    k,
+                    distance_threshold,
+                    cli_args.min_confidence,
+                    cli_args.ensemble().as_ref(),
+                    cli_args.vote_weighting,
+                    neighbors.as_deref(),
+                    use_cr_mode,
+                    &quality_metrics,
+                    &*data,
+                    &*test_sequences,
+                    &*test_labels,
+                    stats,
+                    mis_writer,
+                );
+                if let Some(step) = cli_args.early_classification {
+                    evaluate_early_classification(
+                        k,
+                        step,
+                        use_cr_mode,
+                        &*data,
+                        &*test_sequences,
+                        stats,
+                    );
+                }
+                if let Some(window) = cli_args.window_classification {
+                    evaluate_window_classification(
+                        k,
+                        window,
+                        use_cr_mode,
+                        &*data,
+                        &*test_sequences,
+                        &*test_labels,
+                        stats,
+                    );
+                }
+                checkpointer.complete(batch, k, stats, mis_writer)?;
+            }
         }
 
         Ok(())
@@ -284,90 +762,250 @@ fn run_classify(
 /// This function takes trainings and test data and performs classification with them.
 ///
 /// Results of the classification process are logged to the `stats/StatsCollector` and
-/// `mis_writer`/`JsonSerializer`.
+/// `mis_writer`.
 ///
 /// The parameters `k` and `distance_threshold` configure the behaviour of the function. `k` refers
 /// to the k in k-NN, while the `distance_threshold`, if not `None`, allows to specify an additional
 /// threshold, in which case no classification should happen. This toggles the two different k-NN
-/// variants from the paper.
+/// variants from the paper. If `min_confidence` is not `None`, results with a lower
+/// confidence are rejected and counted as unclassified. If `ensemble` is not `None`, the
+/// classification combines multiple distance metrics instead. The votes of the neighbors are
+/// weighted according to `weighting`. If `neighbors` is not `None`, the
+/// precomputed nearest neighbors of the test data are used instead of calculating the distances.
+/// Each of the `quality_metrics` is evaluated on every result in addition to the quality ladder.
 #[allow(clippy::too_many_arguments)]
 fn classify_and_evaluate(
     // The `k` for k-NN
     k: usize,
     distance_threshold: Option<f32>,
+    min_confidence: Option<f64>,
+    ensemble: Option<&Ensemble>,
+    weighting: VoteWeighting,
+    neighbors: Option<&[Vec<Neighbor>]>,
     use_cr_mode: bool,
+    quality_metrics: &[Box<dyn QualityMetric>],
     training_data: &[LabelledSequences],
     test_data: &[Sequence],
     test_labels: &[(Atom, Atom)],
     stats: &mut StatsCollector,
-    mis_writer: &mut JsonSerializer<impl Write, impl serde_json::ser::Formatter>,
+    mis_writer: &mut CheckpointedOutput,
 ) {
     info!("Start classification for k={}...", k);
     let classification;
-    if let Some(distance_threshold) = distance_threshold {
+    if let Some(ensemble) = ensemble {
+        if distance_threshold.is_some() {
+            warn!("The distance threshold is ignored for ensemble classification.");
+        }
+        classification = knn::knn_ensemble(
+            &*training_data,
+            &*test_data,
+            k as u8,
+            ensemble,
+            weighting,
+            use_cr_mode,
+        )
+    } else if let Some(neighbors) = neighbors {
+        classification = knn::knn_from_neighbors(
+            &*training_data,
+            &*test_data,
+            neighbors,
+            k as u8,
+            distance_threshold.map(f64::from),
+            weighting,
+            use_cr_mode,
+        )
+    } else if let Some(distance_threshold) = distance_threshold {
         classification = knn::knn_with_threshold(
             &*training_data,
             &*test_data,
             k as u8,
             f64::from(distance_threshold),
+            weighting,
             use_cr_mode,
         )
     } else {
-        classification = knn::knn(&*training_data, &*test_data, k as u8, use_cr_mode)
+        classification = knn::knn_with_metric(
+            &*training_data,
+            &*test_data,
+            k as u8,
+            DistanceMetric::EditDistance,
+            weighting,
+            use_cr_mode,
+        )
     }
     assert_eq!(classification.len(), test_labels.len());
     info!("Done classification for k={}, start evaluation...", k);
+    let domain_category = make_domain_categories();
+    let domain_rank = make_domain_ranks();
     classification
-        .iter()
+        .into_iter()
         .zip(test_labels)
         .zip(test_data)
-        .for_each(|((class_result, (true_domain, mapped_domain)), sequence)| {
-            let result_quality = class_result.determine_quality(&*mapped_domain);
-            let known_problems = sequence.classify().map(Atom::from);
-
-            stats.update(
-                k as u8,
-                true_domain.clone(),
-                mapped_domain.clone(),
-                result_quality,
-                known_problems.clone(),
-            );
+        .for_each(
+            |((mut class_result, (true_domain, mapped_domain)), sequence)| {
+                stats.update_calibration(
+                    k as u8,
+                    class_result.confidence(),
+                    class_result.top_label() == Some(&**mapped_domain),
+                );
+                if let Some(distance) = class_result.nearest_distance() {
+                    stats.update_distance(
+                        k as u8,
+                        distance,
+                        class_result.top_label() == Some(&**mapped_domain),
+                    );
+                }
+                if let Some(min_confidence) = min_confidence {
+                    class_result.reject_below(min_confidence);
+                }
+                for metric in quality_metrics {
+                    stats.update_quality_metric(
+                        k as u8,
+                        metric.name(),
+                        metric.is_correct(&class_result, mapped_domain),
+                    );
+                }
 
-            if let Err(err) = log_misclassification(
-                mis_writer,
-                k,
-                sequence,
-                mapped_domain,
-                class_result,
-                known_problems.as_deref(),
-            ) {
-                error!(
-                    "Cannot log misclassification for sequence `{}`: {}",
-                    sequence.id(),
-                    err,
+                let result_quality = class_result.determine_quality(&*mapped_domain);
+                let true_domain_quality = class_result
+                    .true_domain_result()
+                    .determine_quality(&*true_domain);
+                let known_problems = sequence.classify().map(Atom::from);
+
+                stats.update_confusion(
+                    k as u8,
+                    mapped_domain.clone(),
+                    class_result.best_label().map(Atom::from),
                 );
-            }
-        });
+
+                stats.update(
+                    k as u8,
+                    true_domain.clone(),
+                    mapped_domain.clone(),
+                    domain_category(true_domain),
+                    domain_rank(true_domain),
+                    result_quality,
+                    true_domain_quality,
+                    known_problems.clone(),
+                );
+                stats.update_sequence_features(
+                    k as u8,
+                    SequenceFeatures::new(
+                        sequence,
+                        true_domain.clone(),
+                        mapped_domain.clone(),
+                        class_result.best_label().map(Atom::from),
+                        result_quality,
+                        known_problems.clone(),
+                    ),
+                );
+
+                if let Err(err) = log_misclassification(
+                    mis_writer,
+                    k,
+                    sequence,
+                    true_domain,
+                    mapped_domain,
+                    &class_result,
+                    known_problems.as_deref(),
+                ) {
+                    error!(
+                        "Cannot log misclassification for sequence `{}`: {}",
+                        sequence.id(),
+                        err,
+                    );
+                }
+            },
+        );
     info!("Done evaluation for k={}", k);
 }
 
+/// Load or calculate the nearest neighbors of the test data for `part`
+///
+/// Returns `None` without a `neighbor_cache` or if all `ks` are already completed according to `is_completed`.
+fn cached_neighbors(
+    neighbor_cache: Option<&NeighborCache>,
+    part: &str,
+    ks: &[usize],
+    is_completed: impl Fn(usize) -> bool,
+    training_data: &[LabelledSequences],
+    test_data: &[Sequence],
+) -> Result<Option<Vec<Vec<Neighbor>>>, Error> {
+    let neighbor_cache = match neighbor_cache {
+        Some(neighbor_cache) => neighbor_cache,
+        None => return Ok(None),
+    };
+    let depth = match ks.iter().copied().filter(|&k| !is_completed(k)).max() {
+        Some(depth) => depth,
+        None => return Ok(None),
+    };
+    neighbor_cache
+        .neighbors(part, training_data, test_data, depth)
+        .map(Some)
+}
+
+/// Classify growing prefixes of the test data and record when the decision stabilizes
+///
+/// The prefix length after which the classification does not change anymore is logged to the `stats/StatsCollector`.
+fn evaluate_early_classification(
+    // The `k` for k-NN
+    k: usize,
+    step: usize,
+    use_cr_mode: bool,
+    training_data: &[LabelledSequences],
+    test_data: &[Sequence],
+    stats: &mut StatsCollector,
+) {
+    info!("Start early classification for k={}...", k);
+    let classification =
+        knn::knn_prefixes(&*training_data, &*test_data, k as u8, step, use_cr_mode);
+    for class_result in &classification {
+        stats.update_early_classification(k as u8, class_result.stable_prefix_length());
+    }
+    info!("Done early classification for k={}", k);
+}
+
+/// Classify windows of the test data and record after how many windows the decision is exact
+///
+/// The number of windows is logged to the `stats/StatsCollector`.
+fn evaluate_window_classification(
+    // The `k` for k-NN
+    k: usize,
+    window: WindowSpec,
+    use_cr_mode: bool,
+    training_data: &[LabelledSequences],
+    test_data: &[Sequence],
+    test_labels: &[(Atom, Atom)],
+    stats: &mut StatsCollector,
+) {
+    info!("Start window classification for k={}...", k);
+    let classification =
+        knn::knn_windows(&*training_data, &*test_data, k as u8, window, use_cr_mode);
+    for (class_result, (_, mapped_domain)) in classification.iter().zip(test_labels) {
+        stats.update_window_classification(
+            k as u8,
+            class_result
+                .windows_until_classified(mapped_domain, ClassificationResultQuality::Exact),
+        );
+    }
+    info!("Done window classification for k={}", k);
+}
+
 #[allow(clippy::too_many_arguments)]
-fn log_misclassification<W, FMT>(
-    writer: &mut JsonSerializer<W, FMT>,
+fn log_misclassification(
+    writer: &mut impl Write,
     k: usize,
     sequence: &Sequence,
+    true_label: &str,
     label: &str,
     class_result: &ClassificationResult,
     reason: Option<&str>,
-) -> Result<(), Error>
-where
-    W: Write,
-    FMT: serde_json::ser::Formatter,
-{
+) -> Result<(), Error> {
     #[derive(Serialize)]
     struct Out<'a> {
         id: &'a str,
         k: usize,
+        true_label: &'a str,
         label: &'a str,
         class_result: &'a ClassificationResult,
         reason: Option<&'a str>,
@@ -376,12 +1014,17 @@ where
     let out = Out {
         id: sequence.id(),
         k,
+        true_label,
         label,
         class_result,
         reason,
     };
 
-    out.serialize(writer).map_err(|err| anyhow!("{}", err))
+    out.serialize(&mut JsonSerializer::with_formatter(
+        writer,
+        JsonlFormatter::new(),
+    ))
+    .map_err(|err| anyhow!("{}", err))
 }
 
 /// Calculate the reverse cumulitive sum
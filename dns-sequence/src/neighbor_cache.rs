@@ -0,0 +1,162 @@
+//! Persistent nearest neighbor lists for repeated classifications
+//!
+//! The distance calculations dominate the runtime of the classification.
+//! The sorted neighbors of each test sequence only depend on the data and the distance configuration, but not on `k` or the distance threshold.
+//! They are stored in one file per part, such that later runs with a different `k` or distance threshold skip the distance calculations.
+//! The part is the fold during crossvalidation and the batch of test data during classification.
+//!
+//! A cache file is only reused if it was created for the same data, the same distance configuration, and at least as many neighbors.
+//! Otherwise, the neighbors are calculated again and the file is replaced.
+
+use anyhow::{Context as _, Error};
+use log::{info, warn};
+use misc_utils::fs::read_to_string;
+use sequences::{
+    knn::{self, DistanceMetric, LabelledSequences, Neighbor},
+    Sequence,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+#[derive(Debug)]
+pub(crate) struct NeighborCache {
+    /// Directory containing one cache file per part
+    dir: PathBuf,
+    metric: DistanceMetric,
+    use_cr_mode: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct NeighborCacheRef<'a> {
+    dataset_hash: &'a str,
+    configuration: &'a str,
+    depth: usize,
+    neighbors: &'a [Vec<Neighbor>],
+}
+
+#[derive(Debug, Deserialize)]
+struct NeighborCacheFile {
+    dataset_hash: String,
+    configuration: String,
+    depth: usize,
+    neighbors: Vec<Vec<Neighbor>>,
+}
+
+impl NeighborCache {
+    /// Create a new [`NeighborCache`] storing its files in `dir`
+    pub fn new(dir: PathBuf, metric: DistanceMetric, use_cr_mode: bool) -> Result<Self, Error> {
+        fs::create_dir_all(&dir).with_context(|| {
+            format!("Cannot create neighbor cache directory '{}'", dir.display())
+        })?;
+        Ok(Self {
+            dir,
+            metric,
+            use_cr_mode,
+        })
+    }
+
+    /// Description of the distance configuration, which must match to reuse a cache file
+    fn configuration(&self) -> String {
+        format!("metric={} use_cr_mode={}", self.metric, self.use_cr_mode)
+    }
+
+    /// Return the `depth` nearest neighbors of each test sequence of `part`
+    ///
+    /// The neighbors are loaded from the cache file, if it is valid, and calculated and stored otherwise.
+    pub fn neighbors(
+        &self,
+        part: &str,
+        training_data: &[LabelledSequences],
+        test_data: &[Sequence],
+        depth: usize,
+    ) -> Result<Vec<Vec<Neighbor>>, Error> {
+        let path = self.dir.join(format!("{}.json", part));
+        let dataset_hash = knn::dataset_hash(training_data, test_data)?;
+        let configuration = self.configuration();
+
+        if path.exists() {
+            let cache: NeighborCacheFile = match serde_json::from_str(&read_to_string(&path)?) {
+                Ok(cache) => cache,
+                Err(err) => {
+                    warn!(
+                        "Cannot parse neighbor cache file '{}', recalculating: {}",
+                        path.display(),
+                        err
+                    );
+                    return self.calculate(&path, training_data, test_data, depth, &dataset_hash);
+                }
+            };
+            if cache.dataset_hash != dataset_hash {
+                info!(
+                    "Neighbor cache '{}' was created for different data, recalculating.",
+                    path.display()
+                );
+            } else if cache.configuration != configuration {
+                info!(
+                    "Neighbor cache '{}' was created with a different configuration ({}), recalculating.",
+                    path.display(),
+                    cache.configuration
+                );
+            } else if cache.depth < depth {
+                info!(
+                    "Neighbor cache '{}' only contains {} neighbors, but {} are needed, recalculating.",
+                    path.display(),
+                    cache.depth,
+                    depth
+                );
+            } else {
+                info!("Reusing neighbor cache '{}'.", path.display());
+                return Ok(cache.neighbors);
+            }
+        }
+
+        self.calculate(&path, training_data, test_data, depth, &dataset_hash)
+    }
+
+    /// Calculate the neighbors and replace the cache file at `path`
+    fn calculate(
+        &self,
+        path: &Path,
+        training_data: &[LabelledSequences],
+        test_data: &[Sequence],
+        depth: usize,
+        dataset_hash: &str,
+    ) -> Result<Vec<Vec<Neighbor>>, Error> {
+        info!(
+            "Start calculating the {} nearest neighbors of {} test sequences...",
+            depth,
+            test_data.len()
+        );
+        let neighbors = knn::nearest_neighbors(
+            training_data,
+            test_data,
+            depth,
+            self.metric,
+            self.use_cr_mode,
+        );
+        info!("Done calculating the nearest neighbors.");
+        write_cache(
+            path,
+            &NeighborCacheRef {
+                dataset_hash,
+                configuration: &self.configuration(),
+                depth,
+                neighbors: &neighbors,
+            },
+        )
+        .with_context(|| format!("Cannot write neighbor cache file '{}'", path.display()))?;
+        Ok(neighbors)
+    }
+}
+
+fn write_cache(path: &Path, cache: &NeighborCacheRef<'_>) -> Result<(), Error> {
+    let content = serde_json::to_string(cache)?;
+    // Write to a temporary file first, such that an interruption never leaves a partial cache file
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, content)?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
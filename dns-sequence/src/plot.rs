@@ -0,0 +1,154 @@
+//! Fake implementation of the plot feature such that this binary can be build without python dependencies
+//!
+//! Instead of plotting this simply dumps the plotting data as JSON.
+//! The stacked area chart is rendered by `scripts/percentage_stacked_area_chart.py`, all other plots by `scripts/plot_json.py`.
+
+use anyhow::Error;
+use log::info;
+use misc_utils::fs::file_write;
+use serde::Serialize;
+use std::{collections::HashMap, path::Path};
+
+/// Configuration of a single axis
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Axis {
+    pub label: Option<String>,
+    /// Use a logarithmic instead of a linear scale
+    pub log_scale: bool,
+}
+
+impl Axis {
+    pub fn new(label: impl Into<String>) -> Self {
+        Self {
+            label: Some(label.into()),
+            log_scale: false,
+        }
+    }
+
+    pub fn log_scale(mut self) -> Self {
+        self.log_scale = true;
+        self
+    }
+}
+
+/// Configuration shared by all plots
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct PlotConfig {
+    pub title: Option<String>,
+    pub x_axis: Axis,
+    pub y_axis: Axis,
+    /// Scale of the colors in a heatmap
+    pub color_axis: Axis,
+    /// Colors of the data series in order, cycled if there are more series than colors
+    ///
+    /// Uses the default colors if empty.
+    pub colors: Vec<String>,
+}
+
+/// The JSON data written for all plots except the stacked area chart
+#[derive(Debug, Serialize)]
+struct PlotData<'a, T> {
+    /// Type of the plot, which selects the plotting function
+    kind: &'static str,
+    data: T,
+    config: &'a PlotConfig,
+}
+
+pub fn percentage_stacked_area_chart<S: ::std::hash::BuildHasher>(
+    data: &[(impl AsRef<str>, impl AsRef<[f64]>)],
+    output: impl AsRef<Path>,
+    config: HashMap<&str, &[&str], S>,
+) -> Result<(), Error> {
+    // The JSON data will have the following shape:
+    // t.Tuple[
+    //     # This is the data to plot. They will be plottet in order
+    //     t.List[t.Tuple[
+    //         str,  # part of the legend
+    //         t.List[float]  # the data
+    //     ]],
+    //     # Additional configuration parameters
+    //     t.Dict[
+    //         str,
+    //         t.List[str]
+    //     ]
+    // ]
+
+    info!("Dump json of plotting data");
+    let path = output.as_ref().with_extension("json");
+
+    let mut wtr = file_write(&path).create(true).truncate()?;
+    let data: Vec<(&str, &[f64])> = data
+        .iter()
+        .map(|(label, value)| (label.as_ref(), value.as_ref()))
+        .collect();
+    serde_json::to_writer(&mut wtr, &(data, config))?;
+    Ok(())
+}
+
+/// Plot the empirical cumulative distribution function of each data series
+///
+/// Each data series is a pair of legend label and values.
+pub fn cdf(
+    data: &[(impl AsRef<str>, impl AsRef<[f64]>)],
+    output: impl AsRef<Path>,
+    config: &PlotConfig,
+) -> Result<(), Error> {
+    dump_plot("cdf", series(data), output, config)
+}
+
+/// Draw one box plot for each data series
+///
+/// Each data series is a pair of label on the x-axis and values.
+pub fn boxplot(
+    data: &[(impl AsRef<str>, impl AsRef<[f64]>)],
+    output: impl AsRef<Path>,
+    config: &PlotConfig,
+) -> Result<(), Error> {
+    dump_plot("boxplot", series(data), output, config)
+}
+
+/// Draw a heatmap of the matrix `values`
+///
+/// `values` contains one entry per row, each with one value per column.
+pub fn heatmap(
+    row_labels: &[impl AsRef<str>],
+    column_labels: &[impl AsRef<str>],
+    values: &[Vec<f64>],
+    output: impl AsRef<Path>,
+    config: &PlotConfig,
+) -> Result<(), Error> {
+    #[derive(Debug, Serialize)]
+    struct Heatmap<'a> {
+        row_labels: Vec<&'a str>,
+        column_labels: Vec<&'a str>,
+        values: &'a [Vec<f64>],
+    }
+
+    assert_eq!(row_labels.len(), values.len(), "Every row needs a label");
+    let data = Heatmap {
+        row_labels: row_labels.iter().map(AsRef::as_ref).collect(),
+        column_labels: column_labels.iter().map(AsRef::as_ref).collect(),
+        values,
+    };
+    dump_plot("heatmap", data, output, config)
+}
+
+fn series(data: &[(impl AsRef<str>, impl AsRef<[f64]>)]) -> Vec<(&str, &[f64])> {
+    data.iter()
+        .map(|(label, value)| (label.as_ref(), value.as_ref()))
+        .collect()
+}
+
+fn dump_plot(
+    kind: &'static str,
+    data: impl Serialize,
+    output: impl AsRef<Path>,
+    config: &PlotConfig,
+) -> Result<(), Error> {
+    info!("Dump json of {} plotting data", kind);
+    let path = output.as_ref().with_extension("json");
+
+    let mut wtr = file_write(&path).create(true).truncate()?;
+    serde_json::to_writer(&mut wtr, &PlotData { kind, data, config })?;
+    Ok(())
+}
@@ -0,0 +1,136 @@
+//! Additional [`QualityMetric`]s evaluated on every classification result
+//!
+//! The metrics are selected with `--quality-metric` and their accuracies are reported next to the quality ladder of [`ClassificationResultQuality`](sequences::knn::ClassificationResultQuality).
+
+use anyhow::{bail, Context as _, Error};
+use dns_sequence::read_domain_mapping;
+use sequences::knn::{ConfusionSetMatch, ExactMatch, QualityMetric, RegistrableDomainMatch, TopK};
+use std::{
+    collections::HashMap,
+    fmt::{self, Display},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+use string_cache::DefaultAtom as Atom;
+
+/// Command line description of a [`QualityMetric`]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) enum QualityMetricSpec {
+    /// See [`ExactMatch`]
+    Exact,
+    /// See [`RegistrableDomainMatch`]
+    Etld1,
+    /// See [`TopK`]
+    TopK(usize),
+    /// See [`ConfusionSetMatch`], the sets are loaded from a CSV file
+    ConfusionSet(PathBuf),
+}
+
+impl QualityMetricSpec {
+    /// Create the metric, which includes loading the confusion sets
+    pub fn build(&self) -> Result<Box<dyn QualityMetric>, Error> {
+        Ok(match self {
+            QualityMetricSpec::Exact => Box::new(ExactMatch),
+            QualityMetricSpec::Etld1 => Box::new(RegistrableDomainMatch),
+            QualityMetricSpec::TopK(n) => Box::new(TopK(*n)),
+            QualityMetricSpec::ConfusionSet(path) => Box::new(ConfusionSetMatch {
+                name: self.to_string(),
+                sets: load_confusion_sets(path)?,
+            }),
+        })
+    }
+}
+
+impl Display for QualityMetricSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QualityMetricSpec::Exact => write!(f, "exact"),
+            QualityMetricSpec::Etld1 => write!(f, "etld1"),
+            QualityMetricSpec::TopK(n) => write!(f, "top:{}", n),
+            QualityMetricSpec::ConfusionSet(path) => {
+                write!(f, "confusion-set:{}", path.display())
+            }
+        }
+    }
+}
+
+impl FromStr for QualityMetricSpec {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, arg) = match s.split_once(':') {
+            Some((name, arg)) => (name, Some(arg)),
+            None => (s, None),
+        };
+        match (&*name.to_ascii_lowercase(), arg) {
+            ("exact", None) => Ok(QualityMetricSpec::Exact),
+            ("etld1", None) => Ok(QualityMetricSpec::Etld1),
+            ("top", Some(n)) => {
+                let n = n
+                    .parse()
+                    .with_context(|| format!("Invalid number of labels in `{}`", s))?;
+                if n == 0 {
+                    bail!("The quality metric `{}` needs at least one label", s);
+                }
+                Ok(QualityMetricSpec::TopK(n))
+            }
+            ("confusion-set", Some(path)) if !path.is_empty() => {
+                Ok(QualityMetricSpec::ConfusionSet(PathBuf::from(path)))
+            }
+            _ => bail!(
+                "Unknown quality metric `{}`. Supported are `exact`, `etld1`, `top:<n>`, and `confusion-set:<file>`.",
+                s
+            ),
+        }
+    }
+}
+
+/// Load the confusion sets from a CSV file without header and with the two columns domain and set
+///
+/// This is the same format as for `--confusion_domains`, such that the target domain can serve as the set.
+/// Lines starting with `#` are comments.
+fn load_confusion_sets(path: &Path) -> Result<HashMap<String, String>, Error> {
+    let sets: HashMap<Atom, String> = read_domain_mapping(&[path], "confusion set")?;
+    Ok(sets
+        .into_iter()
+        .map(|(domain, set)| (domain.to_string(), set))
+        .collect())
+}
+
+#[test]
+fn test_parse_quality_metric() {
+    for spec in &["exact", "etld1", "top:3", "confusion-set:sets.csv"] {
+        let parsed: QualityMetricSpec = spec.parse().unwrap();
+        assert_eq!(parsed.to_string(), *spec);
+    }
+    assert!("top:0".parse::<QualityMetricSpec>().is_err());
+    assert!("top".parse::<QualityMetricSpec>().is_err());
+    assert!("confusion-set:".parse::<QualityMetricSpec>().is_err());
+    assert!("exact:1".parse::<QualityMetricSpec>().is_err());
+}
+
+#[test]
+fn test_load_confusion_sets() {
+    let dir = std::env::temp_dir().join(format!("dns-sequence-quality-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("sets.csv");
+    std::fs::write(
+        &path,
+        "# domain,set\nnews.example,example\nmirror.example,example\n",
+    )
+    .unwrap();
+
+    let metric = QualityMetricSpec::ConfusionSet(path.clone())
+        .build()
+        .unwrap();
+    assert_eq!(format!("confusion-set:{}", path.display()), metric.name());
+    let sets = load_confusion_sets(&path).unwrap();
+    assert_eq!(2, sets.len());
+    assert_eq!(
+        Some("example"),
+        sets.get("mirror.example").map(|set| &**set)
+    );
+    assert!(load_confusion_sets(&dir.join("missing.csv")).is_err());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
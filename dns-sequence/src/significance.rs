@@ -0,0 +1,120 @@
+//! Paired significance tests for comparing experiment configurations
+//!
+//! Crossvalidation evaluates each configuration on the same ten folds.
+//! The per fold accuracies of two configurations are therefore paired and can be compared with the [Wilcoxon signed-rank test][`wilcoxon_signed_rank`], which does not assume normally distributed differences.
+
+/// Result of the Wilcoxon signed-rank test
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Wilcoxon {
+    /// Number of non-zero differences
+    pub n: usize,
+    /// Sum of the ranks of the positive differences
+    pub statistic: f64,
+    /// Two-sided p-value
+    pub p_value: f64,
+}
+
+/// Two-sided Wilcoxon signed-rank test whether the `differences` are symmetric around zero
+///
+/// Zero differences are dropped and tied absolute values get the average rank.
+/// The p-value is computed from the exact permutation distribution of the ranks, which is necessary for the few pairs of a crossvalidation.
+pub fn wilcoxon_signed_rank(differences: &[f64]) -> Wilcoxon {
+    let mut differences: Vec<f64> = differences
+        .iter()
+        .copied()
+        .filter(|&diff| diff != 0. && !diff.is_nan())
+        .collect();
+    let n = differences.len();
+    if n == 0 {
+        return Wilcoxon {
+            n,
+            statistic: 0.,
+            p_value: 1.,
+        };
+    }
+    differences.sort_by(|a, b| a.abs().partial_cmp(&b.abs()).unwrap());
+
+    // Twice the ranks, such that the average ranks of ties are integers
+    let mut ranks = Vec::with_capacity(n);
+    let mut start = 0;
+    while start < n {
+        let mut end = start + 1;
+        while end < n && differences[end].abs() == differences[start].abs() {
+            end += 1;
+        }
+        // Average of the ranks start + 1 to end
+        ranks.extend((start..end).map(|_| start + 1 + end));
+        start = end;
+    }
+    let statistic: usize = differences
+        .iter()
+        .zip(&ranks)
+        .filter(|(&diff, _)| diff > 0.)
+        .map(|(_, &rank)| rank)
+        .sum();
+
+    // Number of sign assignments for each possible rank sum
+    let max_sum: usize = ranks.iter().sum();
+    let mut counts = vec![0_f64; max_sum + 1];
+    counts[0] = 1.;
+    for &rank in &ranks {
+        for sum in (rank..=max_sum).rev() {
+            counts[sum] += counts[sum - rank];
+        }
+    }
+    let all: f64 = counts.iter().sum();
+    let lower: f64 = counts[..=statistic].iter().sum::<f64>() / all;
+    let upper: f64 = counts[statistic..].iter().sum::<f64>() / all;
+
+    Wilcoxon {
+        n,
+        statistic: statistic as f64 / 2.,
+        p_value: (2. * lower.min(upper)).min(1.),
+    }
+}
+
+/// Adjust the p-values of multiple tests with the Holm-Bonferroni method
+///
+/// The adjusted p-values are returned in the same order as `p_values`.
+pub fn holm_adjust(p_values: &[f64]) -> Vec<f64> {
+    let mut order: Vec<usize> = (0..p_values.len()).collect();
+    order.sort_by(|&a, &b| p_values[a].partial_cmp(&p_values[b]).unwrap());
+    let mut adjusted = vec![0.; p_values.len()];
+    let mut running_max: f64 = 0.;
+    for (pos, &idx) in order.iter().enumerate() {
+        let value = (p_values[idx] * (p_values.len() - pos) as f64).min(1.);
+        running_max = running_max.max(value);
+        adjusted[idx] = running_max;
+    }
+    adjusted
+}
+
+#[test]
+fn test_wilcoxon_signed_rank() {
+    assert_eq!(wilcoxon_signed_rank(&[0., 0.]).p_value, 1.);
+
+    // The smallest possible p-value for ten folds
+    let res = wilcoxon_signed_rank(&[0.01; 10]);
+    assert_eq!(res.n, 10);
+    assert_eq!(res.statistic, 55.);
+    assert!((res.p_value - 2. / 1024.).abs() < 1e-12);
+
+    let res = wilcoxon_signed_rank(&[1., 2., 3., -4., 0.]);
+    assert_eq!(res.n, 4);
+    assert_eq!(res.statistic, 6.);
+    assert!((res.p_value - 0.875).abs() < 1e-12);
+
+    // Ties get the average rank
+    let res = wilcoxon_signed_rank(&[1., -1., 2.]);
+    assert_eq!(res.statistic, 4.5);
+    assert!(res.p_value > 0.5);
+}
+
+#[test]
+fn test_holm_adjust() {
+    let adjusted = holm_adjust(&[0.04, 0.01, 0.03]);
+    assert_eq!(adjusted.len(), 3);
+    assert!((adjusted[1] - 0.03).abs() < 1e-12);
+    assert!((adjusted[2] - 0.06).abs() < 1e-12);
+    assert!((adjusted[0] - 0.06).abs() < 1e-12);
+}
@@ -1,4 +1,7 @@
-use crate::reverse_cum_sum;
+use crate::{
+    plot::{self, Axis, PlotConfig},
+    reverse_cum_sum,
+};
 use anyhow::{anyhow, Context as _, Error};
 use csv::WriterBuilder;
 use misc_utils::fs::file_write;
@@ -8,21 +11,37 @@ use prettytable::{
     format::{FormatBuilder, LinePosition, LineSeparator, TableFormat},
     row, Table,
 };
-use sequences::knn::ClassificationResultQuality;
-use serde::Serialize;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use sequences::{knn::ClassificationResultQuality, Sequence, SequenceElement};
+use serde::{Deserialize, Serialize};
+use serde_with::serde_as;
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
     fmt::{self, Display},
     hash::Hash,
+    io::Write,
     path::Path,
 };
 use string_cache::DefaultAtom as Atom;
 
 const COLORS: &[&str] = &[
     "#2ca02c", "#98df8a", "#bcbd22", "#dbdb8d", "#1f77b4", "#aec7e8", "#ff7f0e", "#ffbb78",
-    "#9467bd", "#c5b0d5", "#d62728", "#ff9896",
+    "#9467bd", "#c5b0d5", "#d62728", "#ff9896", "#7f7f7f", "#c7c7c7",
 ];
 
+/// Number of equally sized confidence bins of the reliability diagram
+const CALIBRATION_BINS: usize = 10;
+/// Step size between the confidence thresholds of the rejection curve
+const REJECTION_STEP: f64 = 0.05;
+/// Number of domains shown in the confusion matrix plot
+const CONFUSION_MATRIX_SIZE: usize = 20;
+/// Number of buckets for the message sizes of [`SequenceFeatures`], the last one collects all larger messages
+const SIZE_BUCKETS: usize = 4;
+/// Confidence level of the bootstrap confidence intervals
+pub(crate) const BOOTSTRAP_CONFIDENCE: f64 = 0.95;
+/// Fixed seed of the bootstrap resampling, such that repeated runs report the same intervals
+const BOOTSTRAP_SEED: u64 = 0x5EED_B007;
+
 /// A line separator made of light unicode table elements
 #[allow(dead_code)]
 static UNICODE_LIGHT_SEP: Lazy<LineSeparator> =
@@ -44,39 +63,695 @@ static FORMAT_NO_BORDER_UNICODE: Lazy<TableFormat> = Lazy::new(|| {
         .build()
 });
 
-#[derive(Debug)]
+#[serde_as]
+#[derive(Debug, Serialize, Deserialize)]
 pub(crate) struct StatsCollector<S: Eq + Hash = Atom> {
     data: HashMap<u8, StatsInternal<S>>,
+    /// Per `k` the prefix lengths after which the classification stabilized
+    ///
+    /// `None` represents sequences without any classification result.
+    early_classification: HashMap<u8, Vec<Option<usize>>>,
+    /// Per `k` the number of windows until the classification of a sequence was exact
+    ///
+    /// `None` represents sequences, whose windows never reached an exact classification.
+    #[serde(default)]
+    window_classification: HashMap<u8, Vec<Option<usize>>>,
+    /// Per `k` the confidence of each classification result and if the label with the highest count is correct
+    calibration: HashMap<u8, Vec<(f64, bool)>>,
+    /// Per `k` the distance to the nearest neighbor of each classification result and if the label with the highest count is correct
+    distances: HashMap<u8, Vec<(usize, bool)>>,
+    /// Per `k` how often sequences of a mapped domain were labelled with another domain
+    ///
+    /// The key is the pair of mapped domain and assigned label, where `None` represents results without a label.
+    #[serde_as(as = "HashMap<_, Vec<(_, _)>>")]
+    confusion: HashMap<u8, HashMap<(S, Option<S>), usize>>,
+    /// Number of sequences per mapped domain in the evaluated data
+    #[serde(default)]
+    class_distribution: HashMap<S, usize>,
+    /// Per `k` the features of each classified sequence together with its classification result
+    #[serde(default)]
+    sequence_features: HashMap<u8, Vec<SequenceFeatures<S>>>,
+    /// Per `k` and fold of the crossvalidation the number of correct and all results
+    #[serde(default)]
+    folds: HashMap<u8, BTreeMap<u8, (usize, usize)>>,
+    /// Per `k` and [`QualityMetric`](sequences::knn::QualityMetric) the number of correct and all results
+    #[serde(default)]
+    quality_metrics: HashMap<u8, BTreeMap<String, (usize, usize)>>,
+}
+
+/// Properties of a single classified [`Sequence`], which might explain its classification result
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct SequenceFeatures<S = Atom> {
+    id: String,
+    true_domain: S,
+    mapped_domain: S,
+    /// Label with the highest count, if any
+    label: Option<S>,
+    result: ClassificationResultQuality,
+    /// Number of [`SequenceElement`]s
+    length: usize,
+    message_count: usize,
+    complexity: usize,
+    /// Number of [`SequenceElement::Gap`]s
+    gaps: usize,
+    /// Number of messages per number of padding blocks, starting at one block
+    ///
+    /// The last bucket also contains all larger messages.
+    size_buckets: [usize; SIZE_BUCKETS],
+    /// Reason why the sequence is known to be problematic, see [`Sequence::classify`]
+    reason: Option<S>,
 }
 
-#[derive(Debug)]
+impl<S> SequenceFeatures<S> {
+    pub fn new(
+        sequence: &Sequence,
+        true_domain: S,
+        mapped_domain: S,
+        label: Option<S>,
+        result: ClassificationResultQuality,
+        reason: Option<S>,
+    ) -> Self {
+        let mut gaps = 0;
+        let mut size_buckets = [0; SIZE_BUCKETS];
+        for elem in sequence.as_elements() {
+            match *elem {
+                SequenceElement::Size(size) => {
+                    let bucket = usize::from(size).clamp(1, SIZE_BUCKETS) - 1;
+                    size_buckets[bucket] += 1;
+                }
+                SequenceElement::Gap(_) => gaps += 1,
+            }
+        }
+
+        Self {
+            id: sequence.id().to_string(),
+            true_domain,
+            mapped_domain,
+            label,
+            result,
+            length: sequence.len(),
+            message_count: sequence.message_count(),
+            complexity: sequence.complexity(),
+            gaps,
+            size_buckets,
+            reason,
+        }
+    }
+}
+
+/// Point estimate of a metric together with its bootstrap confidence interval
+///
+/// See [`StatsCollector::bootstrap`].
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub(crate) struct BootstrapInterval<'a, S> {
+    pub k: u8,
+    /// The mapped domain or `None` for the metrics over all domains
+    pub domain: Option<&'a S>,
+    /// One of `accuracy`, `macro_precision`, and `macro_recall` over all domains, or `precision` and `recall` per domain
+    pub metric: &'static str,
+    /// Value of the metric on the original results
+    pub estimate: Option<f64>,
+    pub lower: Option<f64>,
+    pub upper: Option<f64>,
+}
+
+#[serde_as]
+#[derive(Debug, Serialize, Deserialize)]
 struct StatsCounter<S: Eq + Hash = Atom> {
     /// Counts pairs of `ClassificationResultQuality` and if it is known problematic (bool).
+    #[serde_as(as = "Vec<(_, _)>")]
     results: HashMap<(ClassificationResultQuality, bool), usize>,
     /// Counts the problematic reasons
     reasons: HashMap<S, usize>,
 }
 
-#[derive(Debug)]
+#[serde_as]
+#[derive(Debug, Serialize, Deserialize)]
 struct StatsInternal<S: Eq + Hash = Atom> {
     true_domain: HashMap<S, StatsCounter<S>>,
     mapped_domain: HashMap<S, StatsCounter<S>>,
     global: StatsCounter<S>,
+    /// Same as `global`, but the results are evaluated against the true domains instead of the mapped domains
+    ///
+    /// Confusing two domains merged by the confusion domain mapping is counted as wrong here.
+    global_true_domain: StatsCounter<S>,
+    /// Same as `global`, but per category of the true domain
+    ///
+    /// `None` collects all domains without a category.
+    #[serde(default)]
+    #[serde_as(as = "Vec<(_, _)>")]
+    category: HashMap<Option<S>, StatsCounter<S>>,
+    /// Same as `global`, but per popularity of the true domain, see [`rank_bucket`]
+    ///
+    /// `None` collects all domains without a rank.
+    #[serde(default)]
+    #[serde_as(as = "Vec<(_, _)>")]
+    popularity: HashMap<Option<u32>, StatsCounter<S>>,
 }
 
 impl<S: Eq + Hash> StatsCollector<S> {
     pub fn new() -> Self {
         Self {
             data: HashMap::new(),
+            early_classification: HashMap::new(),
+            window_classification: HashMap::new(),
+            calibration: HashMap::new(),
+            distances: HashMap::new(),
+            confusion: HashMap::new(),
+            class_distribution: HashMap::new(),
+            sequence_features: HashMap::new(),
+            folds: HashMap::new(),
+            quality_metrics: HashMap::new(),
+        }
+    }
+
+    /// Record the number of sequences per mapped domain, replacing any previous distribution
+    ///
+    /// Multiple entries for the same mapped domain are summed up.
+    pub fn set_class_distribution(&mut self, classes: impl IntoIterator<Item = (S, usize)>) {
+        self.class_distribution.clear();
+        for (mapped_domain, count) in classes {
+            *self.class_distribution.entry(mapped_domain).or_default() += count;
+        }
+    }
+
+    /// Write the number of sequences per mapped domain as CSV file, sorted by the domain
+    pub fn dump_class_distribution_to_file<P: AsRef<Path>>(&self, path: P) -> Result<(), Error>
+    where
+        S: Ord + Serialize,
+    {
+        let wtr = file_write(path.as_ref())
+            .create(true)
+            .truncate()
+            .context("Cannot open writer for class distribution.")?;
+        let mut writer = WriterBuilder::new().has_headers(true).from_writer(wtr);
+
+        #[derive(Serialize)]
+        struct Out<'a, S> {
+            mapped_domain: &'a S,
+            sequences: usize,
+        }
+
+        let classes: BTreeMap<_, _> = self.class_distribution.iter().collect();
+        for (mapped_domain, &sequences) in classes {
+            let out = Out {
+                mapped_domain,
+                sequences,
+            };
+            writer.serialize(&out).map_err(|err| anyhow!("{}", err))?;
+        }
+
+        Ok(())
+    }
+
+    /// Record the distance to the nearest neighbor of a single classification result and if the label with the highest count is correct
+    pub fn update_distance(&mut self, k: u8, distance: usize, correct: bool) {
+        self.distances
+            .entry(k)
+            .or_default()
+            .push((distance, correct));
+    }
+
+    /// Record which `label` was assigned to a sequence of the `mapped_domain`
+    ///
+    /// `label` is `None` if the classifier did not assign any label.
+    pub fn update_confusion(&mut self, k: u8, mapped_domain: S, label: Option<S>) {
+        *self
+            .confusion
+            .entry(k)
+            .or_default()
+            .entry((mapped_domain, label))
+            .or_default() += 1;
+    }
+
+    /// Estimate confidence intervals of the accuracy, precision, and recall per `k` and per mapped domain
+    ///
+    /// The classification results, as recorded by [`StatsCollector::update_confusion`], are resampled with replacement `resamples` times.
+    /// The interval contains the central [`BOOTSTRAP_CONFIDENCE`] fraction of the metric values of the resamples.
+    /// A result is correct if its label is the mapped domain.
+    /// Metrics, which are undefined on the original results, e.g., the precision of a domain which was never predicted, have no estimate.
+    pub fn bootstrap(&self, resamples: usize) -> Vec<BootstrapInterval<'_, S>>
+    where
+        S: Ord,
+    {
+        let mut res = Vec::new();
+        let mut ks: Vec<_> = self.confusion.keys().collect();
+        ks.sort();
+        for &k in ks {
+            // Sorted, such that the fixed seed always produces the same resamples
+            let mut confusion: Vec<_> = self.confusion[&k].iter().collect();
+            confusion.sort_by(|a, b| a.0.cmp(b.0));
+
+            let mut domains: Vec<&S> = confusion.iter().map(|((domain, _), _)| domain).collect();
+            domains.dedup();
+            let domain_idx = |domain: &S| domains.binary_search(&domain).ok();
+            // Per result the index of the mapped domain and of the label, if it is one of the mapped domains
+            let mut results: Vec<(usize, Option<usize>)> = Vec::new();
+            for ((domain, label), &count) in &confusion {
+                let domain = domain_idx(domain).expect("All mapped domains are in the list");
+                let label = label.as_ref().and_then(domain_idx);
+                results.resize(results.len() + count, (domain, label));
+            }
+            // Labels which are not a mapped domain never count as correct or towards the precision of a mapped domain
+            let count_metrics = |counts: &BootstrapCounts| -> Vec<Option<f64>> {
+                let mut metrics = Vec::with_capacity(3 + 2 * domains.len());
+                let tp_total: usize = counts.tp.iter().sum();
+                metrics.push(ratio(tp_total, counts.total));
+                let precisions: Vec<_> = (0..domains.len())
+                    .map(|idx| ratio(counts.tp[idx], counts.predicted[idx]))
+                    .collect();
+                let recalls: Vec<_> = (0..domains.len())
+                    .map(|idx| ratio(counts.tp[idx], counts.support[idx]))
+                    .collect();
+                metrics.push(mean(&precisions));
+                metrics.push(mean(&recalls));
+                for (precision, recall) in precisions.into_iter().zip(recalls) {
+                    metrics.push(precision);
+                    metrics.push(recall);
+                }
+                metrics
+            };
+
+            let estimates = count_metrics(&BootstrapCounts::new(
+                domains.len(),
+                results.iter().copied(),
+            ));
+            let mut samples: Vec<Vec<f64>> = vec![Vec::with_capacity(resamples); estimates.len()];
+            if !results.is_empty() {
+                let mut rng = StdRng::seed_from_u64(BOOTSTRAP_SEED ^ u64::from(k));
+                for _ in 0..resamples {
+                    let counts = BootstrapCounts::new(
+                        domains.len(),
+                        (0..results.len()).map(|_| results[rng.gen_range(0..results.len())]),
+                    );
+                    for (sample, metric) in samples.iter_mut().zip(count_metrics(&counts)) {
+                        sample.extend(metric);
+                    }
+                }
+            }
+
+            let names = ["accuracy", "macro_precision", "macro_recall"]
+                .iter()
+                .map(|&metric| (None, metric))
+                .chain(domains.iter().flat_map(|&domain| {
+                    vec![(Some(domain), "precision"), (Some(domain), "recall")]
+                }));
+            for (((domain, metric), estimate), mut sample) in names.zip(estimates).zip(samples) {
+                let (lower, upper) = match estimate {
+                    Some(_) => percentile_interval(&mut sample, BOOTSTRAP_CONFIDENCE),
+                    None => (None, None),
+                };
+                res.push(BootstrapInterval {
+                    k,
+                    domain,
+                    metric,
+                    estimate,
+                    lower,
+                    upper,
+                });
+            }
+        }
+        res
+    }
+
+    /// Write the bootstrap confidence intervals as CSV file
+    ///
+    /// The metrics over all domains have an empty `domain`.
+    pub fn dump_bootstrap_to_file<P: AsRef<Path>>(
+        intervals: &[BootstrapInterval<'_, S>],
+        path: P,
+    ) -> Result<(), Error>
+    where
+        S: Serialize,
+    {
+        let wtr = file_write(path.as_ref())
+            .create(true)
+            .truncate()
+            .context("Cannot open writer for bootstrap statistics.")?;
+        let mut writer = WriterBuilder::new().has_headers(true).from_writer(wtr);
+        for interval in intervals {
+            writer
+                .serialize(interval)
+                .map_err(|err| anyhow!("{}", err))?;
+        }
+        Ok(())
+    }
+
+    /// Record the confidence of a single classification result and if the label with the highest count is correct
+    ///
+    /// This must be recorded before rejecting any results, such that all confidence levels are covered.
+    pub fn update_calibration(&mut self, k: u8, confidence: f64, correct: bool) {
+        self.calibration
+            .entry(k)
+            .or_default()
+            .push((confidence, correct));
+    }
+
+    /// Sort the confidences of `k` into the bins of the reliability diagram
+    ///
+    /// Each bin contains the number of results, the sum of their confidences, and the number of correct results.
+    fn calibration_bins(&self, k: u8) -> Vec<(usize, f64, usize)> {
+        let mut bins = vec![(0, 0., 0); CALIBRATION_BINS];
+        for &(confidence, correct) in self.calibration.get(&k).into_iter().flatten() {
+            let idx = ((confidence * CALIBRATION_BINS as f64) as usize).min(CALIBRATION_BINS - 1);
+            let bin = &mut bins[idx];
+            bin.0 += 1;
+            bin.1 += confidence;
+            if correct {
+                bin.2 += 1;
+            }
+        }
+        bins
+    }
+
+    /// Calculate the expected calibration error for `k`
+    ///
+    /// This is the average difference between the confidence and the accuracy of the bins, weighted by the size of the bins.
+    /// Returns [`None`] if there are no recorded results.
+    fn expected_calibration_error(&self, k: u8) -> Option<f64> {
+        let total = self.calibration.get(&k)?.len();
+        if total == 0 {
+            return None;
+        }
+        let error: f64 = self
+            .calibration_bins(k)
+            .into_iter()
+            .filter(|&(count, _, _)| count > 0)
+            .map(|(count, confidence_sum, correct)| (confidence_sum - correct as f64).abs())
+            .sum();
+        Some(error / total as f64)
+    }
+
+    /// Write the reliability diagram as CSV file
+    ///
+    /// Each row is one confidence bin with the mean confidence and the accuracy of the results in it.
+    /// A well calibrated classifier has a similar mean confidence and accuracy in each bin.
+    pub fn dump_calibration_to_file<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        let wtr = file_write(path.as_ref())
+            .create(true)
+            .truncate()
+            .context("Cannot open writer for calibration statistics.")?;
+        let mut writer = WriterBuilder::new().has_headers(true).from_writer(wtr);
+
+        #[derive(Serialize)]
+        struct Out {
+            k: u8,
+            bin_lower: f64,
+            bin_upper: f64,
+            count: usize,
+            mean_confidence: Option<f64>,
+            accuracy: Option<f64>,
+        }
+
+        let mut ks: Vec<_> = self.calibration.keys().collect();
+        ks.sort();
+        for &k in ks {
+            for (idx, (count, confidence_sum, correct)) in
+                self.calibration_bins(k).into_iter().enumerate()
+            {
+                let out = Out {
+                    k,
+                    bin_lower: idx as f64 / CALIBRATION_BINS as f64,
+                    bin_upper: (idx + 1) as f64 / CALIBRATION_BINS as f64,
+                    count,
+                    mean_confidence: Some(confidence_sum / count as f64).filter(|_| count > 0),
+                    accuracy: Some(correct as f64 / count as f64).filter(|_| count > 0),
+                };
+                writer.serialize(&out).map_err(|err| anyhow!("{}", err))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Write the rejection curve as CSV file
+    ///
+    /// For each confidence threshold, the `coverage` is the fraction of results with at least this confidence and `accuracy` is the accuracy of those results.
+    /// This shows which `--min-confidence` trades off the number of unclassified results against the accuracy.
+    pub fn dump_rejection_curve_to_file<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        let wtr = file_write(path.as_ref())
+            .create(true)
+            .truncate()
+            .context("Cannot open writer for rejection statistics.")?;
+        let mut writer = WriterBuilder::new().has_headers(true).from_writer(wtr);
+
+        #[derive(Serialize)]
+        struct Out {
+            k: u8,
+            threshold: f64,
+            accepted: usize,
+            coverage: f64,
+            accuracy: Option<f64>,
+        }
+
+        let mut ks: Vec<_> = self.calibration.keys().collect();
+        ks.sort();
+        for &k in ks {
+            let results = &self.calibration[&k];
+            let steps = (1. / REJECTION_STEP).round() as usize;
+            for step in 0..=steps {
+                let threshold = step as f64 * REJECTION_STEP;
+                let (accepted, correct) = results
+                    .iter()
+                    .filter(|&&(confidence, _)| confidence >= threshold)
+                    .fold((0, 0), |(accepted, correct), &(_, is_correct)| {
+                        (accepted + 1, correct + usize::from(is_correct))
+                    });
+                let out = Out {
+                    k,
+                    threshold,
+                    accepted,
+                    coverage: accepted as f64 / results.len() as f64,
+                    accuracy: Some(correct as f64 / accepted as f64).filter(|_| accepted > 0),
+                };
+                writer.serialize(&out).map_err(|err| anyhow!("{}", err))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Record the prefix length after which the classification of a single sequence stabilized
+    pub fn update_early_classification(&mut self, k: u8, stable_prefix_length: Option<usize>) {
+        self.early_classification
+            .entry(k)
+            .or_default()
+            .push(stable_prefix_length);
+    }
+
+    /// Record the number of windows after which the classification of a single sequence was exact
+    pub fn update_window_classification(&mut self, k: u8, windows: Option<usize>) {
+        self.window_classification
+            .entry(k)
+            .or_default()
+            .push(windows);
+    }
+
+    /// Number of correct and all results for `k` over the mapped domains, see [`StatsCounter::correct_and_total`]
+    pub fn correct_and_total(&self, k: u8) -> (usize, usize) {
+        self.data
+            .get(&k)
+            .map(|k_stats| k_stats.global.correct_and_total())
+            .unwrap_or_default()
+    }
+
+    /// Record if a single result is correct according to the quality metric `metric`
+    pub fn update_quality_metric(&mut self, k: u8, metric: String, correct: bool) {
+        let counts = self
+            .quality_metrics
+            .entry(k)
+            .or_default()
+            .entry(metric)
+            .or_default();
+        counts.0 += usize::from(correct);
+        counts.1 += 1;
+    }
+
+    /// Write the accuracy according to each quality metric as CSV file
+    pub fn dump_quality_metrics_to_file<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        let wtr = file_write(path.as_ref())
+            .create(true)
+            .truncate()
+            .context("Cannot open writer for quality metric statistics.")?;
+        let mut writer = WriterBuilder::new().has_headers(true).from_writer(wtr);
+
+        #[derive(Serialize)]
+        struct Out<'a> {
+            k: u8,
+            metric: &'a str,
+            total: usize,
+            correct: usize,
+            accuracy: Option<f64>,
+        }
+
+        let mut ks: Vec<_> = self.quality_metrics.keys().collect();
+        ks.sort();
+        for &k in ks {
+            for (metric, &(correct, total)) in &self.quality_metrics[&k] {
+                let out = Out {
+                    k,
+                    metric,
+                    total,
+                    correct,
+                    accuracy: ratio(correct, total),
+                };
+                writer.serialize(&out).map_err(|err| anyhow!("{}", err))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Record the number of correct and all results of a single fold of the crossvalidation
+    pub fn update_fold(&mut self, k: u8, fold: u8, correct: usize, total: usize) {
+        self.folds
+            .entry(k)
+            .or_default()
+            .insert(fold, (correct, total));
+    }
+
+    /// Write the accuracy per fold of the crossvalidation as CSV file
+    ///
+    /// The files of multiple runs can be compared with the `compare-folds` tool.
+    pub fn dump_fold_accuracy_to_file<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        let wtr = file_write(path.as_ref())
+            .create(true)
+            .truncate()
+            .context("Cannot open writer for fold statistics.")?;
+        let mut writer = WriterBuilder::new().has_headers(true).from_writer(wtr);
+
+        #[derive(Serialize)]
+        struct Out {
+            k: u8,
+            fold: u8,
+            total: usize,
+            correct: usize,
+            accuracy: Option<f64>,
+        }
+
+        let mut ks: Vec<_> = self.folds.keys().collect();
+        ks.sort();
+        for &k in ks {
+            for (&fold, &(correct, total)) in &self.folds[&k] {
+                let out = Out {
+                    k,
+                    fold,
+                    total,
+                    correct,
+                    accuracy: ratio(correct, total),
+                };
+                writer.serialize(&out).map_err(|err| anyhow!("{}", err))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Record the features of a single classified sequence
+    pub fn update_sequence_features(&mut self, k: u8, features: SequenceFeatures<S>) {
+        self.sequence_features.entry(k).or_default().push(features);
+    }
+
+    /// Write the features and the classification result of each sequence as CSV file
+    ///
+    /// This allows correlating the properties of the sequences with the accuracy.
+    /// A sequence is `correct`, if the quality is at least [`ClassificationResultQuality::PluralityThenMinDist`], like for the accuracy.
+    pub fn dump_sequence_features_to_file<P: AsRef<Path>>(&self, path: P) -> Result<(), Error>
+    where
+        S: Serialize,
+    {
+        let wtr = file_write(path.as_ref())
+            .create(true)
+            .truncate()
+            .context("Cannot open writer for sequence features.")?;
+        let mut writer = WriterBuilder::new().has_headers(true).from_writer(wtr);
+
+        #[derive(Serialize)]
+        struct Out<'a, S> {
+            k: u8,
+            id: &'a str,
+            true_domain: &'a S,
+            mapped_domain: &'a S,
+            label: Option<&'a S>,
+            result: ClassificationResultQuality,
+            correct: bool,
+            length: usize,
+            message_count: usize,
+            complexity: usize,
+            gaps: usize,
+            size_1: usize,
+            size_2: usize,
+            size_3: usize,
+            size_4_plus: usize,
+            reason: Option<&'a S>,
+        }
+
+        let mut ks: Vec<_> = self.sequence_features.keys().collect();
+        ks.sort();
+        for &k in ks {
+            for features in &self.sequence_features[&k] {
+                let [size_1, size_2, size_3, size_4_plus] = features.size_buckets;
+                let out = Out {
+                    k,
+                    id: &features.id,
+                    true_domain: &features.true_domain,
+                    mapped_domain: &features.mapped_domain,
+                    label: features.label.as_ref(),
+                    result: features.result,
+                    correct: features.result >= ClassificationResultQuality::PluralityThenMinDist,
+                    length: features.length,
+                    message_count: features.message_count,
+                    complexity: features.complexity,
+                    gaps: features.gaps,
+                    size_1,
+                    size_2,
+                    size_3,
+                    size_4_plus,
+                    reason: features.reason.as_ref(),
+                };
+                writer.serialize(&out).map_err(|err| anyhow!("{}", err))?;
+            }
         }
+
+        Ok(())
+    }
+
+    /// Write the CDF of the stable prefix lengths as CSV file
+    ///
+    /// The `cdf` column is relative to all classified sequences, including those without any classification result.
+    pub fn dump_early_classification_to_file<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        let wtr = file_write(path.as_ref())
+            .create(true)
+            .truncate()
+            .context("Cannot open writer for early classification statistics.")?;
+        dump_cdf(wtr, &self.early_classification, "prefix_length")
+    }
+
+    /// Write the CDF of the number of windows until the exact classification as CSV file
+    ///
+    /// The `cdf` column is relative to all classified sequences, including those never classified exactly.
+    pub fn dump_window_classification_to_file<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        let wtr = file_write(path.as_ref())
+            .create(true)
+            .truncate()
+            .context("Cannot open writer for window classification statistics.")?;
+        dump_cdf(wtr, &self.window_classification, "windows")
     }
 
+    /// Record a single classification result
+    ///
+    /// `result` is the quality with respect to the `mapped_domain`, while `true_domain_result` is the quality with respect to the `true_domain`.
+    /// `category` and `rank` are the category and the popularity rank of the `true_domain`, if it has them.
+    #[allow(clippy::too_many_arguments)]
     pub fn update(
         &mut self,
         k: u8,
         true_domain: S,
         mapped_domain: S,
+        category: Option<S>,
+        rank: Option<u32>,
         result: ClassificationResultQuality,
+        true_domain_result: ClassificationResultQuality,
         known_problems: Option<S>,
     ) where
         S: Clone,
@@ -92,9 +767,201 @@ impl<S: Eq + Hash> StatsCollector<S> {
             .entry(mapped_domain)
             .or_default()
             .update(result, known_problems.clone());
+        k_stats
+            .category
+            .entry(category)
+            .or_default()
+            .update(result, known_problems.clone());
+        k_stats
+            .popularity
+            .entry(rank.map(rank_bucket))
+            .or_default()
+            .update(result, known_problems.clone());
+        k_stats
+            .global_true_domain
+            .update(true_domain_result, known_problems.clone());
         k_stats.global.update(result, known_problems);
     }
 
+    /// Return if any result belongs to a domain with a category
+    fn has_categories(&self) -> bool {
+        self.data
+            .values()
+            .any(|k_stats| k_stats.category.keys().any(Option::is_some))
+    }
+
+    /// Return if any result belongs to a domain with a popularity rank
+    fn has_ranks(&self) -> bool {
+        self.data
+            .values()
+            .any(|k_stats| k_stats.popularity.keys().any(Option::is_some))
+    }
+
+    /// Per popularity bucket the largest rank in the bucket and the counter, sorted from most to least popular
+    ///
+    /// Domains without a rank are listed last with a `None` rank.
+    fn popularity(k_stats: &StatsInternal<S>) -> Vec<(Option<u32>, &StatsCounter<S>)> {
+        let mut popularity: Vec<_> = k_stats
+            .popularity
+            .iter()
+            .map(|(&max_rank, counter)| (max_rank, counter))
+            .collect();
+        // `None` sorts first, so sort by `u32::MAX` instead
+        popularity.sort_by_key(|(max_rank, _)| max_rank.unwrap_or(u32::MAX));
+        popularity
+    }
+
+    /// Write the accuracies per popularity of the true domains as CSV file
+    ///
+    /// Each row covers the ranks up to `max_rank`, starting after the `max_rank` of the previous row.
+    /// Domains without a rank have an empty `max_rank`.
+    pub fn dump_popularity_accuracy_to_file<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        let wtr = file_write(path.as_ref())
+            .create(true)
+            .truncate()
+            .context("Cannot open writer for popularity statistics.")?;
+        let mut writer = WriterBuilder::new().has_headers(true).from_writer(wtr);
+
+        #[derive(Serialize)]
+        struct Out {
+            k: u8,
+            max_rank: Option<u32>,
+            total: usize,
+            correct: usize,
+            accuracy: Option<f64>,
+        }
+
+        let mut ks: Vec<_> = self.data.keys().collect();
+        ks.sort();
+        for &k in ks {
+            for (max_rank, counter) in Self::popularity(&self.data[&k]) {
+                let (correct, total) = counter.correct_and_total();
+                let out = Out {
+                    k,
+                    max_rank,
+                    total,
+                    correct,
+                    accuracy: counter.accuracy(),
+                };
+                writer.serialize(&out).map_err(|err| anyhow!("{}", err))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Per category the name and the counter, sorted by name
+    ///
+    /// Domains without a category are listed as `uncategorized`.
+    fn categories(k_stats: &StatsInternal<S>) -> Vec<(String, &StatsCounter<S>)>
+    where
+        S: Display,
+    {
+        let mut categories: Vec<_> = k_stats
+            .category
+            .iter()
+            .map(|(category, counter)| {
+                let name = category
+                    .as_ref()
+                    .map(ToString::to_string)
+                    .unwrap_or_else(|| "uncategorized".to_string());
+                (name, counter)
+            })
+            .collect();
+        categories.sort_by(|a, b| a.0.cmp(&b.0));
+        categories
+    }
+
+    /// Write the accuracies per category of the true domains as CSV file
+    ///
+    /// Domains without a category are listed as `uncategorized`.
+    pub fn dump_category_accuracy_to_file<P: AsRef<Path>>(&self, path: P) -> Result<(), Error>
+    where
+        S: Display,
+    {
+        let wtr = file_write(path.as_ref())
+            .create(true)
+            .truncate()
+            .context("Cannot open writer for category statistics.")?;
+        let mut writer = WriterBuilder::new().has_headers(true).from_writer(wtr);
+
+        #[derive(Serialize)]
+        struct Out {
+            k: u8,
+            category: String,
+            total: usize,
+            correct: usize,
+            accuracy: Option<f64>,
+        }
+
+        let mut ks: Vec<_> = self.data.keys().collect();
+        ks.sort();
+        for &k in ks {
+            for (category, counter) in Self::categories(&self.data[&k]) {
+                let (correct, total) = counter.correct_and_total();
+                let out = Out {
+                    k,
+                    category,
+                    total,
+                    correct,
+                    accuracy: counter.accuracy(),
+                };
+                writer.serialize(&out).map_err(|err| anyhow!("{}", err))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Write the accuracies for the mapped and the true domains as CSV file
+    ///
+    /// The difference between both shows how many errors are only confusions between domains merged by the confusion domain mapping.
+    /// The balanced accuracy weights each mapped domain equally, see [`balanced_accuracy`], and is only available for the mapped domains.
+    pub fn dump_accuracy_to_file<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        let wtr = file_write(path.as_ref())
+            .create(true)
+            .truncate()
+            .context("Cannot open writer for accuracy statistics.")?;
+        let mut writer = WriterBuilder::new().has_headers(true).from_writer(wtr);
+
+        #[derive(Serialize)]
+        struct Out {
+            k: u8,
+            labels: &'static str,
+            total: usize,
+            correct: usize,
+            accuracy: Option<f64>,
+            balanced_accuracy: Option<f64>,
+        }
+
+        let mut ks: Vec<_> = self.data.keys().collect();
+        ks.sort();
+        for &k in ks {
+            let k_stats = &self.data[&k];
+            for &(labels, counter, balanced) in &[
+                (
+                    "mapped",
+                    &k_stats.global,
+                    balanced_accuracy(&k_stats.mapped_domain),
+                ),
+                ("true", &k_stats.global_true_domain, None),
+            ] {
+                let (correct, total) = counter.correct_and_total();
+                let out = Out {
+                    k,
+                    labels,
+                    total,
+                    correct,
+                    accuracy: counter.accuracy(),
+                    balanced_accuracy: balanced,
+                };
+                writer.serialize(&out).map_err(|err| anyhow!("{}", err))?;
+            }
+        }
+
+        Ok(())
+    }
+
     pub fn dump_stats_to_file<P: AsRef<Path>>(&self, path: P) -> Result<(), Error>
     where
         S: Serialize,
@@ -111,6 +978,8 @@ impl<S: Eq + Hash> StatsCollector<S> {
             label: &'a S,
             no_result: usize,
             no_result_w_reason: usize,
+            unclassified: usize,
+            unclassified_w_reason: usize,
             wrong: usize,
             wrong_w_reason: usize,
             contains: usize,
@@ -143,6 +1012,16 @@ impl<S: Eq + Hash> StatsCollector<S> {
                         .get(&(ClassificationResultQuality::NoResult, true))
                         .cloned()
                         .unwrap_or_default(),
+                    unclassified: stats
+                        .results
+                        .get(&(ClassificationResultQuality::Unclassified, false))
+                        .cloned()
+                        .unwrap_or_default(),
+                    unclassified_w_reason: stats
+                        .results
+                        .get(&(ClassificationResultQuality::Unclassified, true))
+                        .cloned()
+                        .unwrap_or_default(),
                     wrong: stats
                         .results
                         .get(&(ClassificationResultQuality::Wrong, false))
@@ -215,7 +1094,7 @@ impl<S: Eq + Hash> StatsCollector<S> {
 
     pub fn plot(&self, output: impl AsRef<Path>) -> Result<(), Error>
     where
-        S: Ord,
+        S: AsRef<str> + Ord,
     {
         for k in self.data.keys() {
             let mut plot_data: HashMap<(ClassificationResultQuality, bool), Vec<f64>> =
@@ -262,6 +1141,61 @@ impl<S: Eq + Hash> StatsCollector<S> {
                 config,
             )?;
         }
+
+        for (&k, distances) in &self.distances {
+            let (correct, wrong): (Vec<_>, Vec<_>) =
+                distances.iter().partition(|&&(_, correct)| correct);
+            let data = [("Correct", to_f64(&correct)), ("Wrong", to_f64(&wrong))];
+            let config = PlotConfig {
+                title: Some(format!("Distance to the nearest neighbor (k={})", k)),
+                colors: vec![COLORS[0].to_string(), COLORS[10].to_string()],
+                ..Default::default()
+            };
+            plot::cdf(
+                &data,
+                output
+                    .as_ref()
+                    .with_extension(format!("k{}.distances_cdf.svg", k)),
+                &PlotConfig {
+                    x_axis: Axis::new("Distance").log_scale(),
+                    y_axis: Axis::new("CDF"),
+                    ..config.clone()
+                },
+            )?;
+            plot::boxplot(
+                &data,
+                output
+                    .as_ref()
+                    .with_extension(format!("k{}.distances_box.svg", k)),
+                &PlotConfig {
+                    x_axis: Axis::new("Classification result"),
+                    y_axis: Axis::new("Distance").log_scale(),
+                    ..config
+                },
+            )?;
+        }
+
+        for (&k, confusion) in &self.confusion {
+            let (labels, values) = confusion_matrix(confusion);
+            let mut column_labels: Vec<&str> = labels.iter().map(|label| label.as_ref()).collect();
+            column_labels.push("other");
+            column_labels.push("unclassified");
+            plot::heatmap(
+                &labels,
+                &column_labels,
+                &values,
+                output
+                    .as_ref()
+                    .with_extension(format!("k{}.confusion.svg", k)),
+                &PlotConfig {
+                    title: Some(format!("Most confused domains (k={})", k)),
+                    x_axis: Axis::new("Assigned label"),
+                    y_axis: Axis::new("Mapped domain"),
+                    color_axis: Axis::new("Number of sequences").log_scale(),
+                    ..Default::default()
+                },
+            )?;
+        }
         Ok(())
     }
 
@@ -319,6 +1253,19 @@ where
         let count_corrects = self.count_correct();
         keys.sort();
 
+        let mut class_sizes: Vec<_> = self.class_distribution.values().copied().collect();
+        class_sizes.sort_unstable();
+        if let (Some(min), Some(max)) = (class_sizes.first(), class_sizes.last()) {
+            writeln!(
+                f,
+                "Classes: {}, sequences per class: min {}, median {}, max {}\n",
+                class_sizes.len(),
+                min,
+                class_sizes[class_sizes.len() / 2],
+                max
+            )?;
+        }
+
         let mut first = true;
         for k in keys {
             if !first {
@@ -330,6 +1277,71 @@ where
             let k_stats = &self.data[k];
             writeln!(f, "knn with k={}:", k)?;
             k_stats.global.fmt(f)?;
+            if let (Some(mapped), Some(true_domain)) = (
+                k_stats.global.accuracy(),
+                k_stats.global_true_domain.accuracy(),
+            ) {
+                writeln!(
+                    f,
+                    "\nAccuracy: {:.2}% (mapped domains), {:.2}% (true domains)",
+                    mapped * 100.,
+                    true_domain * 100.
+                )?;
+            }
+            if let Some(balanced) = balanced_accuracy(&k_stats.mapped_domain) {
+                writeln!(
+                    f,
+                    "Balanced accuracy: {:.2}% (mean over the mapped domains)",
+                    balanced * 100.
+                )?;
+            }
+            if let Some(metrics) = self.quality_metrics.get(k) {
+                writeln!(f, "\nAccuracy per quality metric:")?;
+                for (metric, &(correct, total)) in metrics {
+                    writeln!(
+                        f,
+                        "  {}: {:.2}% ({} of {})",
+                        metric,
+                        ratio(correct, total).unwrap_or_default() * 100.,
+                        correct,
+                        total
+                    )?;
+                }
+            }
+            if self.has_categories() {
+                writeln!(f, "\nAccuracy per category:")?;
+                for (category, counter) in Self::categories(k_stats) {
+                    let (correct, total) = counter.correct_and_total();
+                    writeln!(
+                        f,
+                        "  {}: {:.2}% ({} of {})",
+                        category,
+                        counter.accuracy().unwrap_or_default() * 100.,
+                        correct,
+                        total
+                    )?;
+                }
+            }
+            if self.has_ranks() {
+                writeln!(f, "\nAccuracy per popularity:")?;
+                for (max_rank, counter) in Self::popularity(k_stats) {
+                    let (correct, total) = counter.correct_and_total();
+                    let bucket = max_rank
+                        .map(|max_rank| format!("rank <= {}", max_rank))
+                        .unwrap_or_else(|| "unranked".to_string());
+                    writeln!(
+                        f,
+                        "  {}: {:.2}% ({} of {})",
+                        bucket,
+                        counter.accuracy().unwrap_or_default() * 100.,
+                        correct,
+                        total
+                    )?;
+                }
+            }
+            if let Some(ece) = self.expected_calibration_error(*k) {
+                writeln!(f, "\nExpected calibration error: {:.4}", ece)?;
+            }
 
             writeln!(
                 f,
@@ -347,6 +1359,7 @@ where
             let counts: Vec<_> = ClassificationResultQuality::iter_variants()
                 // skip some qualitys, as this does not match the semantics of the rest
                 .filter(|&q| q != ClassificationResultQuality::NoResult)
+                .filter(|&q| q != ClassificationResultQuality::Unclassified)
                 .filter(|&q| q != ClassificationResultQuality::Wrong)
                 .map(|quality| {
                     let mut num_class = tmp[&quality].clone();
@@ -375,6 +1388,9 @@ impl<S: Eq + Hash> Default for StatsInternal<S> {
             true_domain: HashMap::default(),
             mapped_domain: HashMap::default(),
             global: StatsCounter::default(),
+            global_true_domain: StatsCounter::default(),
+            category: HashMap::default(),
+            popularity: HashMap::default(),
         }
     }
 }
@@ -429,45 +1445,315 @@ impl<S: Eq + Hash> StatsCounter<S> {
             *self.reasons.entry(reason).or_default() += 1;
         }
     }
+
+    /// Count the correct and all results
+    ///
+    /// A result is correct, if the label with the highest count is the real label, i.e., the quality is at least [`ClassificationResultQuality::PluralityThenMinDist`].
+    fn correct_and_total(&self) -> (usize, usize) {
+        self.results
+            .iter()
+            .fold((0, 0), |(correct, total), (&(quality, _), &count)| {
+                if quality >= ClassificationResultQuality::PluralityThenMinDist {
+                    (correct + count, total + count)
+                } else {
+                    (correct, total + count)
+                }
+            })
+    }
+
+    /// Fraction of correct results, see [`StatsCounter::correct_and_total`]
+    ///
+    /// Returns [`None`] if there are no results.
+    fn accuracy(&self) -> Option<f64> {
+        let (correct, total) = self.correct_and_total();
+        if total == 0 {
+            None
+        } else {
+            Some(correct as f64 / total as f64)
+        }
+    }
 }
 
-/// Fake implementation of the plot feature such that this binary can be build without python dependencies
+/// Mean of the accuracies of all classes with results
 ///
-/// Instead of plotting this simply dumps the plotting data as JSON
-mod plot {
-    use anyhow::Error;
-    use log::info;
-    use misc_utils::fs::file_write;
-    use std::{collections::HashMap, path::Path};
-
-    pub fn percentage_stacked_area_chart<S: ::std::hash::BuildHasher>(
-        data: &[(impl AsRef<str>, impl AsRef<[f64]>)],
-        output: impl AsRef<Path>,
-        config: HashMap<&str, &[&str], S>,
-    ) -> Result<(), Error> {
-        // The JSON data will have the following shape:
-        // t.Tuple[
-        //     # This is the data to plot. They will be plottet in order
-        //     t.List[t.Tuple[
-        //         str,  # part of the legend
-        //         t.List[float]  # the data
-        //     ]],
-        //     # Additional configuration parameters
-        //     t.Dict[
-        //         str,
-        //         t.List[str]
-        //     ]
-        // ]
-
-        info!("Dump json of plotting data");
-        let path = output.as_ref().with_extension("json");
-
-        let mut wtr = file_write(&path).create(true).truncate()?;
-        let data: Vec<(&str, &[f64])> = data
-            .iter()
-            .map(|(label, value)| (label.as_ref(), value.as_ref()))
-            .collect();
-        serde_json::to_writer(&mut wtr, &(data, config))?;
-        Ok(())
+/// Unlike the accuracy over all results, each class has the same weight, independent of its number of sequences.
+fn balanced_accuracy<S: Eq + Hash>(classes: &HashMap<S, StatsCounter<S>>) -> Option<f64> {
+    let accuracies: Vec<f64> = classes
+        .values()
+        .filter_map(StatsCounter::accuracy)
+        .collect();
+    if accuracies.is_empty() {
+        None
+    } else {
+        Some(accuracies.iter().sum::<f64>() / accuracies.len() as f64)
+    }
+}
+
+/// Per mapped domain the number of correct results, results, and results labelled with the domain
+struct BootstrapCounts {
+    total: usize,
+    tp: Vec<usize>,
+    support: Vec<usize>,
+    predicted: Vec<usize>,
+}
+
+impl BootstrapCounts {
+    fn new(domains: usize, results: impl Iterator<Item = (usize, Option<usize>)>) -> Self {
+        let mut counts = Self {
+            total: 0,
+            tp: vec![0; domains],
+            support: vec![0; domains],
+            predicted: vec![0; domains],
+        };
+        for (domain, label) in results {
+            counts.total += 1;
+            counts.support[domain] += 1;
+            if let Some(label) = label {
+                counts.predicted[label] += 1;
+                if label == domain {
+                    counts.tp[domain] += 1;
+                }
+            }
+        }
+        counts
+    }
+}
+
+/// Write the CDF of the `values` per `k` as CSV with the columns `k`, `column`, `count`, and `cdf`
+///
+/// `None` values count towards the total, but never appear as a row.
+fn dump_cdf<W: Write>(
+    wtr: W,
+    values: &HashMap<u8, Vec<Option<usize>>>,
+    column: &str,
+) -> Result<(), Error> {
+    let mut writer = WriterBuilder::new().has_headers(false).from_writer(wtr);
+    writer.write_record(&["k", column, "count", "cdf"])?;
+
+    let mut ks: Vec<_> = values.keys().collect();
+    ks.sort();
+    for &k in ks {
+        let values = &values[&k];
+        let total = values.len();
+        let mut counts: BTreeMap<usize, usize> = BTreeMap::new();
+        for &value in values.iter().flatten() {
+            *counts.entry(value).or_default() += 1;
+        }
+
+        let mut accu = 0;
+        for (value, count) in counts {
+            accu += count;
+            writer.serialize((k, value, count, accu as f64 / total as f64))?;
+        }
+    }
+
+    Ok(())
+}
+
+fn ratio(numerator: usize, denominator: usize) -> Option<f64> {
+    Some(numerator as f64 / denominator as f64).filter(|_| denominator > 0)
+}
+
+/// Mean of all defined values
+fn mean(values: &[Option<f64>]) -> Option<f64> {
+    let defined: Vec<f64> = values.iter().flatten().copied().collect();
+    if defined.is_empty() {
+        None
+    } else {
+        Some(defined.iter().sum::<f64>() / defined.len() as f64)
+    }
+}
+
+/// Central interval containing the `confidence` fraction of the `samples`
+///
+/// Returns `None` for both bounds, if there are no samples.
+fn percentile_interval(samples: &mut [f64], confidence: f64) -> (Option<f64>, Option<f64>) {
+    if samples.is_empty() {
+        return (None, None);
+    }
+    samples.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    let alpha = (1. - confidence) / 2.;
+    let at = |q: f64| samples[((samples.len() - 1) as f64 * q).round() as usize];
+    (Some(at(alpha)), Some(at(1. - alpha)))
+}
+
+/// Group popularity ranks by their order of magnitude
+///
+/// Returns the largest rank of the bucket, i.e., 10 for the ranks 1 to 10, 100 for the ranks 11 to 100, and so on.
+fn rank_bucket(rank: u32) -> u32 {
+    let mut max_rank = 10;
+    while max_rank < rank {
+        match max_rank.checked_mul(10) {
+            Some(next) => max_rank = next,
+            None => return u32::MAX,
+        }
+    }
+    max_rank
+}
+
+#[test]
+fn test_rank_bucket() {
+    assert_eq!(10, rank_bucket(1));
+    assert_eq!(10, rank_bucket(10));
+    assert_eq!(100, rank_bucket(11));
+    assert_eq!(1_000_000, rank_bucket(999_999));
+    assert_eq!(u32::MAX, rank_bucket(u32::MAX));
+}
+
+fn to_f64(distances: &[&(usize, bool)]) -> Vec<f64> {
+    distances
+        .iter()
+        .map(|&&(distance, _)| distance as f64)
+        .collect()
+}
+
+/// Build the confusion matrix of the [`CONFUSION_MATRIX_SIZE`] mapped domains with the most wrong labels
+///
+/// Returns the domains and one row per domain.
+/// The columns are the domains in the same order, followed by the count of all other labels and the count of results without a label.
+fn confusion_matrix<S>(confusion: &HashMap<(S, Option<S>), usize>) -> (Vec<&S>, Vec<Vec<f64>>)
+where
+    S: AsRef<str> + Eq + Hash + Ord,
+{
+    let mut errors: HashMap<&S, usize> = HashMap::new();
+    for ((domain, label), &count) in confusion {
+        if label.as_ref() != Some(domain) {
+            *errors.entry(domain).or_default() += count;
+        }
+    }
+    let mut domains: Vec<(&S, usize)> = errors.into_iter().collect();
+    // Sort by decreasing number of errors and use the name to make the order deterministic
+    domains.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+    let domains: Vec<&S> = domains
+        .into_iter()
+        .take(CONFUSION_MATRIX_SIZE)
+        .map(|(domain, _)| domain)
+        .collect();
+
+    let values = domains
+        .iter()
+        .map(|&domain| {
+            let mut row = vec![0.; domains.len() + 2];
+            for ((other_domain, label), &count) in confusion {
+                if other_domain != domain {
+                    continue;
+                }
+                let column = match label {
+                    Some(label) => domains
+                        .iter()
+                        .position(|&d| d == label)
+                        .unwrap_or(domains.len()),
+                    None => domains.len() + 1,
+                };
+                row[column] += count as f64;
+            }
+            row
+        })
+        .collect();
+    (domains, values)
+}
+
+#[test]
+fn test_sequence_features() {
+    use SequenceElement::{Gap, Size};
+
+    let sequence = Sequence::new(
+        vec![Size(1), Gap(3), Size(2), Size(7), Gap(1), Size(1)],
+        "example.com/1.dnstap".to_string(),
+    );
+    let features = SequenceFeatures::new(
+        &sequence,
+        "example.com",
+        "example.com",
+        Some("example.org"),
+        ClassificationResultQuality::Wrong,
+        None,
+    );
+    assert_eq!(6, features.length);
+    assert_eq!(4, features.message_count);
+    assert_eq!(11, features.complexity);
+    assert_eq!(2, features.gaps);
+    assert_eq!([2, 1, 0, 1], features.size_buckets);
+}
+
+#[test]
+fn test_bootstrap() {
+    let mut stats: StatsCollector<String> = StatsCollector::new();
+    for _ in 0..8 {
+        stats.update_confusion(1, "a".to_string(), Some("a".to_string()));
+    }
+    for _ in 0..2 {
+        stats.update_confusion(1, "a".to_string(), Some("b".to_string()));
     }
+    for _ in 0..10 {
+        stats.update_confusion(1, "b".to_string(), Some("b".to_string()));
+    }
+
+    let intervals = stats.bootstrap(200);
+    assert_eq!(intervals, stats.bootstrap(200), "The resampling is seeded");
+    let get = |domain: Option<&str>, metric: &str| {
+        intervals
+            .iter()
+            .find(|i| i.domain.map(String::as_str) == domain && i.metric == metric)
+            .unwrap()
+    };
+    let accuracy = get(None, "accuracy");
+    assert_eq!(accuracy.estimate, Some(0.9));
+    assert!(accuracy.lower.unwrap() < 0.9 && accuracy.upper.unwrap() > 0.9);
+    let precision = get(Some("b"), "precision");
+    assert!((precision.estimate.unwrap() - 10. / 12.).abs() < 1e-12);
+    // All sequences of `b` are correct in every resample
+    let recall = get(Some("b"), "recall");
+    assert_eq!(
+        (recall.estimate, recall.lower, recall.upper),
+        (Some(1.), Some(1.), Some(1.))
+    );
+    assert_eq!(intervals.len(), 3 + 2 * 2);
+}
+
+#[test]
+fn test_dump_cdf() {
+    let mut values = HashMap::new();
+    values.insert(3, vec![Some(2), None, Some(1), Some(2)]);
+    values.insert(1, vec![Some(4)]);
+
+    let mut out = Vec::new();
+    dump_cdf(&mut out, &values, "windows").unwrap();
+    assert_eq!(
+        "k,windows,count,cdf\n1,4,1,1.0\n3,1,1,0.25\n3,2,2,0.75\n",
+        String::from_utf8(out).unwrap()
+    );
+}
+
+#[test]
+fn test_calibration() {
+    let mut stats: StatsCollector<String> = StatsCollector::new();
+    stats.update_calibration(1, 0.95, true);
+    stats.update_calibration(1, 1., true);
+    stats.update_calibration(1, 0.15, false);
+    stats.update_calibration(1, 0.15, true);
+
+    let bins = stats.calibration_bins(1);
+    assert_eq!(bins.len(), CALIBRATION_BINS);
+    // A confidence of 1 belongs to the last bin
+    assert_eq!(bins[9].0, 2);
+    assert_eq!(bins[9].2, 2);
+    assert_eq!(bins[1].0, 2);
+    assert_eq!(bins[1].2, 1);
+    assert_eq!(bins.iter().map(|bin| bin.0).sum::<usize>(), 4);
+
+    // (|1.95 - 2| + |0.3 - 1|) / 4
+    let ece = stats.expected_calibration_error(1).unwrap();
+    assert!((ece - 0.1875).abs() < 1e-12, "{}", ece);
+    assert_eq!(stats.expected_calibration_error(3), None);
+
+    let path = std::env::temp_dir().join(format!("rejection-curve-{}.csv", std::process::id()));
+    stats.dump_rejection_curve_to_file(&path).unwrap();
+    let content = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let lines: Vec<_> = content.lines().collect();
+    assert_eq!(lines.len(), 1 + 21);
+    assert_eq!(lines[0], "k,threshold,accepted,coverage,accuracy");
+    assert_eq!(lines[1], "1,0.0,4,1.0,0.75");
+    assert_eq!(lines[21], "1,1.0,1,0.25,1.0");
 }
@@ -1,60 +1,13 @@
-// Copied from https://github.com/stepancheg/rust-protobuf/issues/324#issuecomment-476109276
-
-use std::{
-    env, fs,
-    ops::Deref,
-    path::{Path, PathBuf},
-};
-
-fn out_dir() -> PathBuf {
-    Path::new(&env::var("OUT_DIR").expect("env")).join("proto")
-}
-
-fn cleanup() {
-    let _ = fs::remove_dir_all(&out_dir());
-}
-
-fn compile() {
-    let proto_dir = Path::new(&env::var("CARGO_MANIFEST_DIR").expect("env")).join("protos");
-
-    let files = glob::glob(&proto_dir.join("**/*.proto").to_string_lossy())
-        .expect("glob")
-        .filter_map(|p| p.ok().map(|p| p.to_string_lossy().into_owned()))
-        .collect::<Vec<_>>();
-
-    let slices = files.iter().map(Deref::deref).collect::<Vec<_>>();
-
-    let out_dir = out_dir();
-    fs::create_dir(&out_dir).expect("create_dir");
-
-    protobuf_codegen_pure::Codegen::new()
-        .out_dir(&out_dir)
-        .inputs(&slices)
-        .include(&proto_dir)
-        .run()
-        .expect("protoc");
-}
-
-fn generate_mod_rs() {
-    let out_dir = out_dir();
-
-    let mods = glob::glob(&out_dir.join("*.rs").to_string_lossy())
-        .expect("glob")
-        .filter_map(|p| {
-            p.ok()
-                .map(|p| format!("pub mod {};", p.file_stem().unwrap().to_string_lossy()))
-        })
-        .collect::<Vec<_>>()
-        .join("\n");
-
-    let mod_rs = out_dir.join("mod.rs");
-    fs::write(&mod_rs, format!("// @generated\n{}\n", mods)).expect("write");
-
-    println!("cargo:rustc-env=PROTO_MOD_RS={}", mod_rs.to_string_lossy());
-}
+use std::{env, path::Path};
 
 fn main() {
-    cleanup();
-    compile();
-    generate_mod_rs();
+    let proto_dir = Path::new(&env::var("CARGO_MANIFEST_DIR").expect("env")).join("protos");
+    println!("cargo:rerun-if-changed={}", proto_dir.display());
+
+    // Use a bundled protoc, such that the build does not depend on a system installation
+    env::set_var(
+        "PROTOC",
+        protoc_bin_vendored::protoc_bin_path().expect("bundled protoc"),
+    );
+    prost_build::compile_protos(&[proto_dir.join("dnstap.proto")], &[proto_dir]).expect("protoc");
 }
@@ -0,0 +1,372 @@
+//! Replace the domain names in dnstap files with salted hashes
+//!
+//! This allows sharing datasets without revealing which websites were visited.
+//! Each label is replaced by a hash of the same length, such that the message sizes stay identical.
+//! All other information, such as timestamps and IP addresses, is kept unchanged.
+//! The same label is always replaced by the same hash for a fixed salt, which keeps the relation between names intact.
+//! The marker names [`START_MARKER`] and [`END_MARKER`] are kept, since they are required to process the files.
+
+use crate::{dnstap, writer::create_dnstap_file, END_MARKER, START_MARKER};
+use anyhow::{bail, Context as _, Error};
+use framestream::DecoderReader;
+use misc_utils::fs::file_open_read;
+use prost::Message;
+use std::path::Path;
+
+/// Characters used for the anonymized labels
+const ALPHABET: &[u8] = b"abcdefghijklmnopqrstuvwxyz0123456789";
+
+/// Rewrites domain names using a salted hash
+#[derive(Clone, Debug)]
+pub struct Anonymizer {
+    key: [u8; 32],
+}
+
+impl Anonymizer {
+    pub fn new(salt: &[u8]) -> Self {
+        Self {
+            key: *blake3::hash(salt).as_bytes(),
+        }
+    }
+
+    /// Replace the `label` with a hash of the same length
+    fn anonymize_label(&self, label: &mut [u8]) {
+        let mut hasher = blake3::Hasher::new_keyed(&self.key);
+        hasher.update(&label.to_ascii_lowercase());
+        hasher.finalize_xof().fill(label);
+        for b in label {
+            *b = ALPHABET[*b as usize % ALPHABET.len()];
+        }
+    }
+
+    /// Anonymize all domain names in a DNS message in wire format
+    ///
+    /// This covers the question section and the owner names of all resource records, as well as names in the RDATA of common record types.
+    /// Messages with record types, which contain names but are not supported, are rejected, such that no name is left unchanged.
+    pub fn anonymize_dns_message(&self, msg: &mut [u8]) -> Result<(), Error> {
+        if msg.len() < 12 {
+            bail!("The DNS message is shorter than its header");
+        }
+        let count = |idx: usize| u16::from_be_bytes([msg[idx], msg[idx + 1]]) as usize;
+        let questions = count(4);
+        let records = count(6) + count(8) + count(10);
+
+        let mut pos = 12;
+        for _ in 0..questions {
+            // Skip QTYPE and QCLASS
+            pos = self.anonymize_name(msg, pos)? + 4;
+        }
+        for _ in 0..records {
+            pos = self.anonymize_name(msg, pos)?;
+            if msg.len() < pos + 10 {
+                bail!("The resource record at offset {} is truncated", pos);
+            }
+            let rtype = u16::from_be_bytes([msg[pos], msg[pos + 1]]);
+            let rdlength = u16::from_be_bytes([msg[pos + 8], msg[pos + 9]]) as usize;
+            pos += 10;
+            if msg.len() < pos + rdlength {
+                bail!("The RDATA at offset {} is truncated", pos);
+            }
+            match rtype {
+                // NS, CNAME, MB, MG, MR, PTR, DNAME, and NSEC (next domain name)
+                2 | 5 | 7 | 8 | 9 | 12 | 39 | 47 => {
+                    self.anonymize_name(msg, pos)?;
+                }
+                // SOA, MINFO, RP
+                6 | 14 | 17 => {
+                    let second = self.anonymize_name(msg, pos)?;
+                    self.anonymize_name(msg, second)?;
+                }
+                // MX, AFSDB, RT, KX, SVCB, HTTPS
+                15 | 18 | 21 | 36 | 64 | 65 => {
+                    self.anonymize_name(msg, pos + 2)?;
+                }
+                // SRV
+                33 => {
+                    self.anonymize_name(msg, pos + 6)?;
+                }
+                // RRSIG (signer name)
+                46 => {
+                    self.anonymize_name(msg, pos + 18)?;
+                }
+                // NAPTR, TKEY, TSIG
+                35 | 249 | 250 => bail!("Anonymizing records of type {} is not supported", rtype),
+                _ => {}
+            }
+            pos += rdlength;
+        }
+        Ok(())
+    }
+
+    /// Anonymize the labels of the name starting at `start` and return the position after the name
+    ///
+    /// Only the labels stored at this position are changed.
+    /// Labels referenced by compression pointers are anonymized where they are stored.
+    fn anonymize_name(&self, msg: &mut [u8], start: usize) -> Result<usize, Error> {
+        let mut labels = Vec::new();
+        let mut pos = start;
+        let end = loop {
+            let len = *msg
+                .get(pos)
+                .with_context(|| format!("The name at offset {} is truncated", start))?
+                as usize;
+            match len & 0xC0 {
+                0x00 if len == 0 => break pos + 1,
+                0x00 => {
+                    if msg.len() < pos + 1 + len {
+                        bail!("The name at offset {} is truncated", start);
+                    }
+                    labels.push(pos + 1..pos + 1 + len);
+                    pos += 1 + len;
+                }
+                0xC0 => break pos + 2,
+                _ => bail!("Unsupported label type at offset {}", pos),
+            }
+        };
+
+        if !labels.is_empty() {
+            let name = read_name(msg, start)?;
+            if name != START_MARKER && name != END_MARKER {
+                for label in labels {
+                    self.anonymize_label(&mut msg[label]);
+                }
+            }
+        }
+        Ok(end)
+    }
+
+    /// Anonymize the DNS messages and the query zone of a dnstap message
+    pub fn anonymize_dnstap(&self, dnstap: &mut dnstap::Dnstap) -> Result<(), Error> {
+        if let Some(message) = &mut dnstap.message {
+            if let Some(query_message) = &mut message.query_message {
+                self.anonymize_dns_message(query_message)
+                    .context("Failed to anonymize the query message")?;
+            }
+            if let Some(response_message) = &mut message.response_message {
+                self.anonymize_dns_message(response_message)
+                    .context("Failed to anonymize the response message")?;
+            }
+            if let Some(query_zone) = &mut message.query_zone {
+                self.anonymize_name(query_zone, 0)
+                    .context("Failed to anonymize the query zone")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Read the name starting at `start` in lowercase and with a trailing dot, following compression pointers
+fn read_name(msg: &[u8], start: usize) -> Result<String, Error> {
+    let mut name = String::new();
+    let mut pos = start;
+    // Limit the number of pointers to prevent loops
+    for _ in 0..128 {
+        let len = *msg
+            .get(pos)
+            .with_context(|| format!("The name at offset {} is truncated", start))?
+            as usize;
+        match len & 0xC0 {
+            0x00 if len == 0 => {
+                if name.is_empty() {
+                    name.push('.');
+                }
+                return Ok(name);
+            }
+            0x00 => {
+                let label = msg
+                    .get(pos + 1..pos + 1 + len)
+                    .with_context(|| format!("The name at offset {} is truncated", start))?;
+                name.push_str(&String::from_utf8_lossy(label).to_ascii_lowercase());
+                name.push('.');
+                pos += 1 + len;
+            }
+            0xC0 => {
+                let low = *msg
+                    .get(pos + 1)
+                    .with_context(|| format!("The name at offset {} is truncated", start))?;
+                pos = ((len & 0x3F) << 8) | low as usize;
+            }
+            _ => bail!("Unsupported label type at offset {}", pos),
+        }
+    }
+    bail!("The name at offset {} contains too many labels", start)
+}
+
+/// Anonymize a whole dnstap file and write the result to `output`
+///
+/// Both files can be compressed, which is determined by their file extension.
+/// Returns the number of processed dnstap messages.
+pub fn anonymize_dnstap_file(
+    input: &Path,
+    output: &Path,
+    anonymizer: &Anonymizer,
+) -> Result<usize, Error> {
+    let rdr = file_open_read(input)
+        .with_context(|| format!("Opening input file '{}' failed", input.display()))?;
+    let fstrm = DecoderReader::with_content_type(rdr, crate::CONTENT_TYPE.into());
+    let mut wtr = create_dnstap_file(output)?;
+
+    for msg in fstrm {
+        let mut raw_dnstap = dnstap::Dnstap::decode(&*msg?).context("Parsing protobuf failed.")?;
+        anonymizer
+            .anonymize_dnstap(&mut raw_dnstap)
+            .with_context(|| format!("Failed to anonymize message {}", wtr.count()))?;
+        wtr.write_raw(&raw_dnstap)?;
+    }
+    let count = wtr.count();
+    wtr.finish()?;
+    Ok(count)
+}
+
+#[test]
+fn test_anonymize_dns_message() {
+    #[rustfmt::skip]
+    let msg: Vec<u8> = vec![
+        // Header: 1 question, 1 answer
+        0x12, 0x34, 0x81, 0x80, 0, 1, 0, 1, 0, 0, 0, 0,
+        // Question: www.example.com. A IN
+        3, b'w', b'w', b'w', 7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 3, b'c', b'o', b'm', 0,
+        0, 1, 0, 1,
+        // Answer: Pointer to the question name, CNAME IN, TTL 60
+        0xC0, 12, 0, 5, 0, 1, 0, 0, 0, 60,
+        // RDATA: cdn + pointer to example.com.
+        0, 6, 3, b'c', b'd', b'n', 0xC0, 16,
+    ];
+
+    let anonymizer = Anonymizer::new(b"salt");
+    let mut anonymized = msg.clone();
+    anonymizer.anonymize_dns_message(&mut anonymized).unwrap();
+    assert_eq!(msg.len(), anonymized.len());
+    assert_ne!(msg, anonymized);
+    let qname = read_name(&anonymized, 12).unwrap();
+    assert_eq!("www.example.com.".len(), qname.len());
+    assert_ne!("www.example.com.", qname);
+    // The CNAME target shares the suffix with the question
+    let cname = read_name(&anonymized, 45).unwrap();
+    assert_eq!(qname[4..], cname[4..]);
+    assert_ne!("cdn.", &cname[..4]);
+
+    // The same salt produces the same result, a different salt not
+    let mut again = msg.clone();
+    anonymizer.anonymize_dns_message(&mut again).unwrap();
+    assert_eq!(anonymized, again);
+    let mut other = msg.clone();
+    Anonymizer::new(b"other")
+        .anonymize_dns_message(&mut other)
+        .unwrap();
+    assert_ne!(anonymized, other);
+
+    // Marker names stay unchanged
+    #[rustfmt::skip]
+    let marker: Vec<u8> = vec![
+        0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0,
+        5, b's', b't', b'a', b'r', b't', 7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 0,
+        0, 1, 0, 1,
+    ];
+    let mut anonymized = marker.clone();
+    anonymizer.anonymize_dns_message(&mut anonymized).unwrap();
+    assert_eq!(marker, anonymized);
+}
+
+#[cfg(test)]
+fn message_with_record(rtype: u16, rdata: &[u8]) -> Vec<u8> {
+    #[rustfmt::skip]
+    let mut msg: Vec<u8> = vec![
+        // Header: 1 question, 1 answer
+        0x12, 0x34, 0x81, 0x80, 0, 1, 0, 1, 0, 0, 0, 0,
+        // Question: www.example.com. A IN
+        3, b'w', b'w', b'w', 7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 3, b'c', b'o', b'm', 0,
+        0, 1, 0, 1,
+        // Answer: Pointer to the question name
+        0xC0, 12,
+    ];
+    msg.extend_from_slice(&rtype.to_be_bytes());
+    // Class IN, TTL 60
+    msg.extend_from_slice(&[0, 1, 0, 0, 0, 60]);
+    msg.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+    msg.extend_from_slice(rdata);
+    msg
+}
+
+/// Offset of the RDATA in [`message_with_record`]
+#[cfg(test)]
+const RDATA_OFFSET: usize = 45;
+
+/// Name `target.example.net.` in wire format
+#[cfg(test)]
+const TARGET_NAME: &[u8] = &[
+    6, b't', b'a', b'r', b'g', b'e', b't', 7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 3, b'n',
+    b'e', b't', 0,
+];
+
+/// Anonymize a message with a single record and check that only the name between `prefix` and `suffix` in the RDATA changed
+#[cfg(test)]
+fn check_rdata_name_is_anonymized(rtype: u16, prefix: &[u8], suffix: &[u8]) {
+    let rdata = [prefix, TARGET_NAME, suffix].concat();
+    let msg = message_with_record(rtype, &rdata);
+    let mut anonymized = msg.clone();
+    Anonymizer::new(b"salt")
+        .anonymize_dns_message(&mut anonymized)
+        .unwrap();
+    assert_eq!(msg.len(), anonymized.len());
+
+    let name_start = RDATA_OFFSET + prefix.len();
+    let name_end = name_start + TARGET_NAME.len();
+    let target = read_name(&anonymized, name_start).unwrap();
+    assert_eq!("target.example.net.".len(), target.len());
+    assert_ne!("target.", &target[..7], "type {}", rtype);
+    assert_ne!("example.", &target[7..15], "type {}", rtype);
+    // Everything else in the RDATA is unchanged
+    assert_eq!(
+        msg[RDATA_OFFSET..name_start],
+        anonymized[RDATA_OFFSET..name_start]
+    );
+    assert_eq!(msg[name_end..], anonymized[name_end..]);
+}
+
+#[test]
+fn test_anonymize_svcb() {
+    // Priority 1, target name, alpn=h2
+    check_rdata_name_is_anonymized(64, &[0, 1], &[0, 1, 0, 3, 2, b'h', b'2']);
+}
+
+#[test]
+fn test_anonymize_https() {
+    // Priority 1, target name, alpn=h2
+    check_rdata_name_is_anonymized(65, &[0, 1], &[0, 1, 0, 3, 2, b'h', b'2']);
+}
+
+#[test]
+fn test_anonymize_rrsig() {
+    #[rustfmt::skip]
+    let prefix = [
+        // Type covered, algorithm, labels, original TTL
+        0, 1, 13, 3, 0, 0, 0, 60,
+        // Signature expiration and inception
+        0x5F, 0x5E, 0x10, 0x00, 0x5F, 0x40, 0x00, 0x00,
+        // Key tag
+        0x12, 0x34,
+    ];
+    check_rdata_name_is_anonymized(46, &prefix, &[0xAB; 64]);
+}
+
+#[test]
+fn test_anonymize_nsec() {
+    // Next domain name, type bitmap for A and RRSIG
+    check_rdata_name_is_anonymized(47, &[], &[0, 6, 0x40, 0, 0, 0, 0, 0x02]);
+}
+
+#[test]
+fn test_anonymize_unsupported_record_fails() {
+    #[rustfmt::skip]
+    let rdata = [
+        // Order, preference, flags "U", service "E2U+sip", empty regexp
+        0, 10, 0, 100, 1, b'U', 7, b'E', b'2', b'U', b'+', b's', b'i', b'p', 0,
+        // Replacement
+        3, b's', b'i', b'p', 7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 3, b'c', b'o', b'm', 0,
+    ];
+    let mut msg = message_with_record(35, &rdata);
+    assert!(Anonymizer::new(b"salt")
+        .anonymize_dns_message(&mut msg)
+        .is_err());
+}
@@ -1,15 +1,26 @@
 #![cfg_attr(feature = "cargo-clippy", allow(renamed_and_removed_lints))]
 
+pub mod anonymize;
 pub mod protos;
+pub mod writer;
 
-pub use crate::protos::dnstap;
-use crate::{dnstap::Message_Type, protos::DnstapContent};
+use crate::protos::DnstapContent;
+pub use crate::protos::{dnstap, MessageType};
 use anyhow::{bail, Context as _, Error};
 use framestream::DecoderReader;
 use log::warn;
 use misc_utils::fs::file_open_read;
-use protobuf::Message;
-use std::{convert::TryFrom, path::Path};
+use prost::Message;
+use std::{
+    convert::TryFrom,
+    fmt::{self, Display},
+    path::Path,
+    str::FromStr,
+};
+use trust_dns_proto::rr::Name;
+
+/// Content type of the frame streams containing dnstap messages
+pub const CONTENT_TYPE: &str = "protobuf:dnstap.Dnstap";
 
 pub fn process_dnstap<P: AsRef<Path>>(
     path: P,
@@ -19,12 +30,11 @@ pub fn process_dnstap<P: AsRef<Path>>(
 
     let rdr = file_open_read(path)
         .with_context(|| format!("Opening input file '{}' failed", path.display()))?;
-    let fstrm = DecoderReader::with_content_type(rdr, "protobuf:dnstap.Dnstap".into());
+    let fstrm = DecoderReader::with_content_type(rdr, CONTENT_TYPE.into());
 
     Ok(fstrm
         .map(move |msg| -> Result<Option<protos::Dnstap>, Error> {
-            let raw_dnstap =
-                dnstap::Dnstap::parse_from_bytes(&msg?).context("Parsing protobuf failed.")?;
+            let raw_dnstap = dnstap::Dnstap::decode(&*msg?).context("Parsing protobuf failed.")?;
             match protos::Dnstap::try_from(raw_dnstap) {
                 Ok(dnstap) => Ok(Some(dnstap)),
                 Err(err) => {
@@ -39,66 +49,379 @@ pub fn process_dnstap<P: AsRef<Path>>(
         .filter_map(Result::transpose))
 }
 
-pub fn sanity_check_dnstap(events: &[protos::Dnstap]) -> Result<(), Error> {
-    let mut client_query_start_count = 0;
-    let mut client_response_start_count = 0;
-    let mut client_query_end_count = 0;
-    let mut client_response_end_count = 0;
-
-    for ev in events {
-        match &ev.content {
-            DnstapContent::Message {
-                message_type: Message_Type::CLIENT_QUERY,
-                ref query_message,
-                ..
-            } => {
-                let (dnsmsg, _size) = query_message.as_ref().expect("Unbound always sets this");
-                let qname = dnsmsg.queries()[0].name().to_utf8();
-
-                match &*qname {
-                    "start.example." => client_query_start_count += 1,
-                    "end.example." => client_query_end_count += 1,
-                    _ => {}
-                }
-            }
+/// Query name of the marker query sent before the measurement starts
+pub const START_MARKER: &str = "start.example.";
+/// Query name of the marker query sent after the measurement ended
+pub const END_MARKER: &str = "end.example.";
 
-            DnstapContent::Message {
-                message_type: Message_Type::CLIENT_RESPONSE,
-                ref response_message,
-                ..
-            } => {
-                let (dnsmsg, _size) = response_message.as_ref().expect("Unbound always sets this");
-                let qname = dnsmsg.queries()[0].name().to_utf8();
-
-                match &*qname {
-                    "start.example." => client_response_start_count += 1,
-                    "end.example." => client_response_end_count += 1,
-                    _ => {}
-                }
-            }
+/// Marker queries delimiting a measurement
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+pub enum Marker {
+    /// Query for [`START_MARKER`]
+    Start,
+    /// Query for [`END_MARKER`]
+    End,
+}
 
-            _ => {}
+impl Marker {
+    pub fn qname(self) -> &'static str {
+        match self {
+            Self::Start => START_MARKER,
+            Self::End => END_MARKER,
         }
     }
 
-    if client_query_start_count == 0 {
-        bail!("Expected at least 1 CLIENT_QUERY for 'start.example.' but found none");
-    } else if client_query_end_count != 1 {
-        bail!(
-            "Unexpected number of CLIENT_QUERYs for 'end.example.': {}, expected 1",
-            client_query_end_count
+    /// Check if `name` is the query name of this marker
+    ///
+    /// This compares the labels directly, since it runs for every event of a dnstap file.
+    fn matches(self, name: &Name) -> bool {
+        name.is_fqdn()
+            && name.iter().eq(self
+                .qname()
+                .trim_end_matches('.')
+                .split('.')
+                .map(str::as_bytes))
+    }
+}
+
+/// Allowed number of occurences of a marker message
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+pub struct MarkerCount {
+    pub min: u32,
+    /// Upper bound, or unbounded if [`None`]
+    pub max: Option<u32>,
+}
+
+impl MarkerCount {
+    pub fn exactly(count: u32) -> Self {
+        Self {
+            min: count,
+            max: Some(count),
+        }
+    }
+
+    pub fn at_least(count: u32) -> Self {
+        Self {
+            min: count,
+            max: None,
+        }
+    }
+
+    fn contains(self, count: u32) -> bool {
+        self.min <= count && self.max.map_or(true, |max| count <= max)
+    }
+}
+
+impl Display for MarkerCount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.max {
+            Some(max) if max == self.min => write!(f, "exactly {}", max),
+            Some(max) => write!(f, "between {} and {}", self.min, max),
+            None => write!(f, "at least {}", self.min),
+        }
+    }
+}
+
+/// Expected number of marker messages in a dnstap file, see [`sanity_check_dnstap`]
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+pub struct MarkerPolicy {
+    pub start_queries: MarkerCount,
+    pub start_responses: MarkerCount,
+    pub end_queries: MarkerCount,
+    pub end_responses: MarkerCount,
+}
+
+impl MarkerPolicy {
+    /// Require exactly one of each marker message, except for retried start queries \[DEFAULT\]
+    pub fn strict() -> Self {
+        Self {
+            start_queries: MarkerCount::at_least(1),
+            start_responses: MarkerCount::exactly(1),
+            end_queries: MarkerCount::exactly(1),
+            end_responses: MarkerCount::exactly(1),
+        }
+    }
+
+    /// Accept retried marker queries and missing marker responses
+    ///
+    /// Only the queries are required, since they delimit the measurement.
+    pub fn tolerant() -> Self {
+        Self {
+            start_queries: MarkerCount::at_least(1),
+            start_responses: MarkerCount::at_least(0),
+            end_queries: MarkerCount::at_least(1),
+            end_responses: MarkerCount::at_least(0),
+        }
+    }
+}
+
+impl Default for MarkerPolicy {
+    fn default() -> Self {
+        Self::strict()
+    }
+}
+
+impl FromStr for MarkerPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "Strict" | "strict" => Ok(Self::strict()),
+            "Tolerant" | "tolerant" => Ok(Self::tolerant()),
+            unkwn => bail!("Unknown variant: '{}'", unkwn),
+        }
+    }
+}
+
+/// Result of [`sanity_check_dnstap`]
+#[derive(Clone, Eq, PartialEq, Debug, Default)]
+pub struct SanityReport {
+    /// Unusual properties of the file, which do not prevent processing it, e.g., retried marker queries
+    pub warnings: Vec<String>,
+    /// Violations of the [`MarkerPolicy`], the file should not be processed
+    pub errors: Vec<String>,
+}
+
+impl SanityReport {
+    pub fn is_ok(&self) -> bool {
+        self.errors.is_empty()
+    }
+
+    /// Turn all errors into an [`Error`] and otherwise return the warnings
+    pub fn into_result(self) -> Result<Vec<String>, Error> {
+        if self.errors.is_empty() {
+            Ok(self.warnings)
+        } else {
+            bail!("{}", self.errors.join("; "))
+        }
+    }
+
+    fn check(&mut self, count: u32, expected: MarkerCount, message_type: &str, marker: &str) {
+        if !expected.contains(count) {
+            self.errors.push(format!(
+                "Unexpected number of {}s for '{}': {}, expected {}",
+                message_type, marker, count, expected
+            ));
+        } else if count != 1 {
+            self.warnings.push(format!(
+                "Found {} {}s for '{}'",
+                count, message_type, marker
+            ));
+        }
+    }
+}
+
+/// Return the query name of a client query or response
+///
+/// Returns [`None`] for all other message types and for messages without a DNS message or question.
+pub fn client_message_qname(ev: &protos::Dnstap) -> Option<(MessageType, String)> {
+    client_message_name(ev).map(|(message_type, name)| (message_type, name.to_utf8()))
+}
+
+/// Return the [`Marker`] of a client query or response
+///
+/// Returns [`None`] for all other message types and query names, and for messages without a DNS message or question.
+/// Unlike [`client_message_qname`], this does not allocate.
+pub fn client_message_marker(ev: &protos::Dnstap) -> Option<(MessageType, Marker)> {
+    let (message_type, name) = client_message_name(ev)?;
+    [Marker::Start, Marker::End]
+        .iter()
+        .find(|marker| marker.matches(name))
+        .map(|&marker| (message_type, marker))
+}
+
+fn client_message_name(ev: &protos::Dnstap) -> Option<(MessageType, &Name)> {
+    match &ev.content {
+        DnstapContent::Message {
+            message_type: message_type @ MessageType::ClientQuery,
+            query_message: msg,
+            ..
+        }
+        | DnstapContent::Message {
+            message_type: message_type @ MessageType::ClientResponse,
+            response_message: msg,
+            ..
+        } => {
+            let (dnsmsg, _size) = msg.as_ref()?;
+            Some((*message_type, dnsmsg.queries().first()?.name()))
+        }
+        _ => None,
+    }
+}
+
+/// Check that the marker messages of a dnstap file match the `policy`
+pub fn sanity_check_dnstap(events: &[protos::Dnstap], policy: &MarkerPolicy) -> SanityReport {
+    let mut counts = MarkerCounts::default();
+    events.iter().for_each(|ev| counts.add(ev));
+    counts.check(policy)
+}
+
+/// Number of marker messages seen so far
+///
+/// This performs the same checks as [`sanity_check_dnstap`], but allows processing the events one at a time.
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug, Default)]
+pub struct MarkerCounts {
+    pub client_query_start: u32,
+    pub client_response_start: u32,
+    pub client_query_end: u32,
+    pub client_response_end: u32,
+}
+
+impl MarkerCounts {
+    /// Count `ev`, if it is a marker message
+    pub fn add(&mut self, ev: &protos::Dnstap) {
+        if let Some((message_type, marker)) = client_message_marker(ev) {
+            match (message_type, marker) {
+                (MessageType::ClientQuery, Marker::Start) => self.client_query_start += 1,
+                (MessageType::ClientQuery, Marker::End) => self.client_query_end += 1,
+                (MessageType::ClientResponse, Marker::Start) => self.client_response_start += 1,
+                (MessageType::ClientResponse, Marker::End) => self.client_response_end += 1,
+                _ => {}
+            }
+        }
+    }
+
+    /// Check that the counted marker messages match the `policy`
+    pub fn check(&self, policy: &MarkerPolicy) -> SanityReport {
+        let mut report = SanityReport::default();
+        report.check(
+            self.client_query_start,
+            policy.start_queries,
+            "CLIENT_QUERY",
+            START_MARKER,
+        );
+        report.check(
+            self.client_response_start,
+            policy.start_responses,
+            "CLIENT_RESPONSE",
+            START_MARKER,
+        );
+        report.check(
+            self.client_query_end,
+            policy.end_queries,
+            "CLIENT_QUERY",
+            END_MARKER,
         );
-    } else if client_response_start_count != 1 {
-        bail!(
-            "Unexpected number of CLIENT_RESPONSEs for 'start.example.': {}, expected 1",
-            client_response_start_count
+        report.check(
+            self.client_response_end,
+            policy.end_responses,
+            "CLIENT_RESPONSE",
+            END_MARKER,
         );
-    } else if client_response_end_count != 1 {
-        bail!(
-            "Unexpected number of CLIENT_RESPONSEs for 'end.example.': {}, expected 1",
-            client_response_end_count
+        report
+    }
+}
+
+#[cfg(test)]
+fn client_message(message_type: MessageType, qname: &str) -> protos::Dnstap {
+    use trust_dns_proto::{
+        op::{Message as DnsMessage, Query},
+        rr::RecordType,
+    };
+
+    let mut msg = DnsMessage::new();
+    msg.add_query(Query::query(
+        Name::from_ascii(qname).unwrap(),
+        RecordType::A,
+    ));
+    let (query_message, response_message) = match message_type {
+        MessageType::ClientResponse => (None, Some((msg, 0))),
+        _ => (Some((msg, 0)), None),
+    };
+    protos::Dnstap {
+        identity: None,
+        version: None,
+        extra: None,
+        content: DnstapContent::Message {
+            message_type,
+            query_address: None,
+            response_address: None,
+            query_port: None,
+            response_port: None,
+            query_time: None,
+            response_time: None,
+            query_message,
+            response_message,
+            query_zone: None,
+        },
+    }
+}
+
+#[test]
+fn test_client_message_marker() {
+    assert_eq!(
+        client_message_marker(&client_message(MessageType::ClientQuery, START_MARKER)),
+        Some((MessageType::ClientQuery, Marker::Start))
+    );
+    assert_eq!(
+        client_message_marker(&client_message(MessageType::ClientResponse, END_MARKER)),
+        Some((MessageType::ClientResponse, Marker::End))
+    );
+    for qname in &[
+        "example.",
+        "www.start.example.",
+        "start.example.com.",
+        "start",
+    ] {
+        assert_eq!(
+            client_message_marker(&client_message(MessageType::ClientQuery, qname)),
+            None,
+            "{}",
+            qname
         );
     }
+    assert_eq!(
+        client_message_qname(&client_message(
+            MessageType::ClientQuery,
+            "www.example.com."
+        )),
+        Some((MessageType::ClientQuery, "www.example.com.".to_string()))
+    );
+}
+
+#[test]
+fn test_sanity_check_dnstap() {
+    let events = vec![
+        client_message(MessageType::ClientQuery, START_MARKER),
+        client_message(MessageType::ClientQuery, START_MARKER),
+        client_message(MessageType::ClientResponse, START_MARKER),
+        client_message(MessageType::ClientQuery, "www.example.com."),
+        client_message(MessageType::ClientResponse, "www.example.com."),
+        client_message(MessageType::ClientQuery, END_MARKER),
+        client_message(MessageType::ClientResponse, END_MARKER),
+    ];
+    // A retried start marker query is only a warning
+    let report = sanity_check_dnstap(&events, &MarkerPolicy::strict());
+    assert!(report.is_ok());
+    assert_eq!(
+        report.warnings,
+        vec!["Found 2 CLIENT_QUERYs for 'start.example.'".to_string()]
+    );
+
+    // Missing responses violate the strict policy, but not the tolerant one
+    let without_responses: Vec<_> = events
+        .into_iter()
+        .filter(|ev| client_message_marker(ev).map(|(t, _)| t) != Some(MessageType::ClientResponse))
+        .collect();
+    let report = sanity_check_dnstap(&without_responses, &MarkerPolicy::strict());
+    assert!(!report.is_ok());
+    assert_eq!(report.errors.len(), 2);
+    assert!(report.clone().into_result().is_err());
+    let report = sanity_check_dnstap(&without_responses, &MarkerPolicy::tolerant());
+    assert!(report.is_ok());
+    assert_eq!(report.warnings.len(), 3);
 
-    Ok(())
+    // The end marker query is always required
+    let without_end: Vec<_> = without_responses
+        .into_iter()
+        .filter(|ev| client_message_marker(ev).map(|(_, m)| m) != Some(Marker::End))
+        .collect();
+    let report = sanity_check_dnstap(&without_end, &MarkerPolicy::tolerant());
+    assert_eq!(
+        report.errors,
+        vec![
+            "Unexpected number of CLIENT_QUERYs for 'end.example.': 0, expected at least 1"
+                .to_string()
+        ]
+    );
 }
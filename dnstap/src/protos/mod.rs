@@ -1,18 +1,84 @@
-// generated protobuf files will be included here. See build.rs for details
-#![allow(renamed_and_removed_lints)]
+//! Types of dnstap messages
+//!
+//! The module [`dnstap`] contains the protobuf types generated by `prost` from the `dnstap.proto` file, see build.rs for details.
+//! They mirror the wire format, so all fields are optional and enums are stored as plain integers.
+//! [`Dnstap`] and [`DnstapContent`] are the checked representation used by the rest of the crate.
+//! Converting between both never panics, but reports invalid messages with a [`ConversionError`].
 
-include!(env!("PROTO_MOD_RS"));
+pub mod dnstap {
+    #![allow(clippy::all)]
+    include!(concat!(env!("OUT_DIR"), "/dnstap.rs"));
+}
 
-use anyhow::{anyhow, bail, Error};
+/// Type of a dnstap [`DnstapContent::Message`], e.g., a client query
+pub use self::dnstap::message::Type as MessageType;
+use self::dnstap::SocketFamily;
 use chrono::{DateTime, NaiveDateTime, Utc};
 use std::{
     convert::TryFrom,
     net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    string::FromUtf8Error,
 };
+use thiserror::Error;
 use trust_dns_proto::{
-    op::Message as DnsMessage, rr::Name as DnsName, serialize::binary::BinDecodable,
+    error::ProtoError,
+    op::Message as DnsMessage,
+    rr::Name as DnsName,
+    serialize::binary::{BinDecodable, BinEncodable},
 };
 
+/// Errors while converting between the protobuf types and [`Dnstap`]
+#[derive(Debug, Error)]
+pub enum ConversionError {
+    #[error("The {} has the unknown value {}.", field, value)]
+    UnknownEnumValue { field: &'static str, value: i32 },
+    #[error("The dnstap message of type MESSAGE does not contain a message.")]
+    MissingMessage,
+    #[error("Specifying a query or response address requires to specify the socket family.")]
+    MissingSocketFamily,
+    #[error("The query and response address must belong to the same socket family.")]
+    SocketFamilyMismatch,
+    #[error(
+        "The {} has {} bytes, but an address of the socket family {:?} has {} bytes.",
+        field,
+        len,
+        family,
+        expected
+    )]
+    InvalidAddress {
+        field: &'static str,
+        family: SocketFamily,
+        len: usize,
+        expected: usize,
+    },
+    #[error("The {} {} is not a valid port.", field, port)]
+    InvalidPort { field: &'static str, port: u32 },
+    #[error("The {} {}.{:09} s is out of range for a timestamp.", field, sec, nsec)]
+    InvalidTimestamp {
+        field: &'static str,
+        sec: u64,
+        nsec: u32,
+    },
+    #[error("The {} is not valid UTF-8: {}", field, source)]
+    InvalidUtf8 {
+        field: &'static str,
+        #[source]
+        source: FromUtf8Error,
+    },
+    #[error("Processing the {} failed: {}", field, source)]
+    InvalidDnsData {
+        field: &'static str,
+        #[source]
+        source: ProtoError,
+    },
+    #[error("Serializing the {} failed: {}", field, source)]
+    Serialization {
+        field: &'static str,
+        #[source]
+        source: ProtoError,
+    },
+}
+
 #[derive(Clone, Debug)]
 pub struct Dnstap {
     pub identity: Option<String>,
@@ -24,7 +90,7 @@ pub struct Dnstap {
 #[derive(Clone, Debug)]
 pub enum DnstapContent {
     Message {
-        message_type: dnstap::Message_Type,
+        message_type: MessageType,
         query_address: Option<IpAddr>,
         response_address: Option<IpAddr>,
         query_port: Option<u16>,
@@ -38,143 +104,166 @@ pub enum DnstapContent {
 }
 
 impl DnstapContent {
-    fn convert_message(mut from: dnstap::Message) -> Result<DnstapContent, Error> {
-        let message_type = from.get_field_type();
-        let (query_address, response_address) = if !from.has_socket_family() {
-            if from.has_query_address() || from.has_response_address() {
-                bail!(
-                    "Specifying a query or response address requires to specify the socket family."
-                )
-            }
-            // nothing exists, so its fine
-            (None, None)
-        } else {
-            // has socket family
-            match from.get_socket_family() {
-                dnstap::SocketFamily::INET => {
-                    let q = if from.has_query_address() {
-                        let q_bytes = from.take_query_address();
-                        if q_bytes.len() != 4 {
-                            bail!("An IPv4 address has to consists of exactly four bytes!")
-                        }
-                        Some(Ipv4Addr::from(*<&[u8; 4]>::try_from(&*q_bytes).unwrap()).into())
-                    } else {
-                        None
-                    };
-                    let r = if from.has_response_address() {
-                        let r_bytes = from.take_response_address();
-                        if r_bytes.len() != 4 {
-                            bail!("An IPv4 address has to consists of exactly four bytes!")
-                        }
-                        Some(Ipv4Addr::from(*<&[u8; 4]>::try_from(&*r_bytes).unwrap()).into())
-                    } else {
-                        None
-                    };
-                    (q, r)
-                }
-                dnstap::SocketFamily::INET6 => {
-                    let q = if from.has_query_address() {
-                        let q_bytes = from.take_query_address();
-                        if q_bytes.len() != 16 {
-                            bail!("An IPv6 address has to consists of exactly 16 bytes!")
-                        }
-                        Some(Ipv6Addr::from(*<&[u8; 16]>::try_from(&*q_bytes).unwrap()).into())
-                    } else {
-                        None
-                    };
-                    let r = if from.has_response_address() {
-                        let r_bytes = from.take_query_address();
-                        if r_bytes.len() != 16 {
-                            bail!("An IPv6 address has to consists of exactly 16 bytes!")
-                        }
-                        Some(Ipv6Addr::from(*<&[u8; 16]>::try_from(&*r_bytes).unwrap()).into())
-                    } else {
-                        None
-                    };
-                    (q, r)
+    fn convert_message(from: dnstap::Message) -> Result<DnstapContent, ConversionError> {
+        let message_type =
+            MessageType::from_i32(from.r#type).ok_or(ConversionError::UnknownEnumValue {
+                field: "message type",
+                value: from.r#type,
+            })?;
+        let (query_address, response_address) = match from.socket_family {
+            None => {
+                if from.query_address.is_some() || from.response_address.is_some() {
+                    return Err(ConversionError::MissingSocketFamily);
                 }
+                // nothing exists, so its fine
+                (None, None)
             }
-        };
-        let query_port = if from.has_query_port() {
-            Some(from.get_query_port() as u16)
-        } else {
-            None
-        };
-        let response_port = if from.has_response_port() {
-            Some(from.get_response_port() as u16)
-        } else {
-            None
-        };
-        let query_time = if from.has_query_time_sec() {
-            let q_sec = from.get_query_time_sec();
-            let q_nsec = if from.has_query_time_nsec() {
-                from.get_query_time_nsec()
-            } else {
-                0
-            };
-            let ndt = NaiveDateTime::from_timestamp_opt(q_sec as i64, q_nsec);
-            if let Some(ndt) = ndt {
-                Some(DateTime::<Utc>::from_utc(ndt, Utc))
-            } else {
-                bail!(
-                    "Query Time: Invalid or out of range value for DateTime: {}.{} s",
-                    q_sec,
-                    q_nsec
+            Some(family) => {
+                let family =
+                    SocketFamily::from_i32(family).ok_or(ConversionError::UnknownEnumValue {
+                        field: "socket family",
+                        value: family,
+                    })?;
+                (
+                    from.query_address
+                        .map(|addr| convert_address(family, addr, "query address"))
+                        .transpose()?,
+                    from.response_address
+                        .map(|addr| convert_address(family, addr, "response address"))
+                        .transpose()?,
                 )
             }
-        } else {
-            None
-        };
-        let response_time = if from.has_response_time_sec() {
-            let r_sec = from.get_response_time_sec();
-            let r_nsec = if from.has_response_time_nsec() {
-                from.get_response_time_nsec()
-            } else {
-                0
-            };
-            let ndt = NaiveDateTime::from_timestamp_opt(r_sec as i64, r_nsec);
-            if let Some(ndt) = ndt {
-                Some(DateTime::<Utc>::from_utc(ndt, Utc))
-            } else {
-                bail!(
-                    "Response Time: Invalid or out of range value for DateTime: {}.{} s",
-                    r_sec,
-                    r_nsec
-                )
-            }
-        } else {
-            None
-        };
-        let query_zone = if from.has_query_zone() {
-            Some(
-                DnsName::from_bytes(&*from.take_query_zone())
-                    .map_err(|err| anyhow!("Processing the query zone failed: {}", err))?,
-            )
-        } else {
-            None
         };
-        let query_message = if from.has_query_message() {
-            let buf = from.take_query_message();
-            Some((
-                DnsMessage::from_vec(&*buf)
-                    .map_err(|err| anyhow!("Processing the query message failed: {}", err))?,
-                buf.len(),
-            ))
-        } else {
-            None
+
+        Ok(DnstapContent::Message {
+            message_type,
+            query_address,
+            response_address,
+            query_port: from
+                .query_port
+                .map(|port| convert_port(port, "query port"))
+                .transpose()?,
+            response_port: from
+                .response_port
+                .map(|port| convert_port(port, "response port"))
+                .transpose()?,
+            query_time: convert_time(from.query_time_sec, from.query_time_nsec, "query time")?,
+            response_time: convert_time(
+                from.response_time_sec,
+                from.response_time_nsec,
+                "response time",
+            )?,
+            query_message: from
+                .query_message
+                .map(|buf| convert_dns_message(buf, "query message"))
+                .transpose()?,
+            response_message: from
+                .response_message
+                .map(|buf| convert_dns_message(buf, "response message"))
+                .transpose()?,
+            query_zone: from
+                .query_zone
+                .map(|buf| {
+                    DnsName::from_bytes(&buf).map_err(|source| ConversionError::InvalidDnsData {
+                        field: "query zone",
+                        source,
+                    })
+                })
+                .transpose()?,
+        })
+    }
+}
+
+fn convert_address(
+    family: SocketFamily,
+    bytes: Vec<u8>,
+    field: &'static str,
+) -> Result<IpAddr, ConversionError> {
+    let invalid = |expected| ConversionError::InvalidAddress {
+        field,
+        family,
+        len: bytes.len(),
+        expected,
+    };
+    Ok(match family {
+        SocketFamily::Inet => {
+            Ipv4Addr::from(<[u8; 4]>::try_from(&*bytes).map_err(|_| invalid(4))?).into()
+        }
+        SocketFamily::Inet6 => {
+            Ipv6Addr::from(<[u8; 16]>::try_from(&*bytes).map_err(|_| invalid(16))?).into()
+        }
+    })
+}
+
+fn convert_port(port: u32, field: &'static str) -> Result<u16, ConversionError> {
+    u16::try_from(port).map_err(|_| ConversionError::InvalidPort { field, port })
+}
+
+fn convert_time(
+    sec: Option<u64>,
+    nsec: Option<u32>,
+    field: &'static str,
+) -> Result<Option<DateTime<Utc>>, ConversionError> {
+    let sec = match sec {
+        Some(sec) => sec,
+        None => return Ok(None),
+    };
+    let nsec = nsec.unwrap_or(0);
+    i64::try_from(sec)
+        .ok()
+        .and_then(|secs| NaiveDateTime::from_timestamp_opt(secs, nsec))
+        .map(|ndt| Some(DateTime::<Utc>::from_utc(ndt, Utc)))
+        .ok_or(ConversionError::InvalidTimestamp { field, sec, nsec })
+}
+
+fn convert_dns_message(
+    buf: Vec<u8>,
+    field: &'static str,
+) -> Result<(DnsMessage, usize), ConversionError> {
+    let msg = DnsMessage::from_vec(&buf)
+        .map_err(|source| ConversionError::InvalidDnsData { field, source })?;
+    Ok((msg, buf.len()))
+}
+
+impl TryFrom<dnstap::Dnstap> for Dnstap {
+    type Error = ConversionError;
+
+    fn try_from(from: dnstap::Dnstap) -> Result<Self, ConversionError> {
+        let convert_string = |bytes: Option<Vec<u8>>, field| {
+            bytes
+                .map(String::from_utf8)
+                .transpose()
+                .map_err(|source| ConversionError::InvalidUtf8 { field, source })
         };
-        let response_message = if from.has_response_message() {
-            let buf = from.take_response_message();
-            Some((
-                DnsMessage::from_vec(&*buf)
-                    .map_err(|err| anyhow!("Processing the response message failed: {}", err))?,
-                buf.len(),
-            ))
-        } else {
-            None
+        let identity = convert_string(from.identity, "identity")?;
+        let version = convert_string(from.version, "version")?;
+
+        let content = match dnstap::dnstap::Type::from_i32(from.r#type) {
+            Some(dnstap::dnstap::Type::Message) => DnstapContent::convert_message(
+                from.message.ok_or(ConversionError::MissingMessage)?,
+            )?,
+            None => {
+                return Err(ConversionError::UnknownEnumValue {
+                    field: "dnstap type",
+                    value: from.r#type,
+                })
+            }
         };
 
-        Ok(DnstapContent::Message {
+        Ok(Dnstap {
+            identity,
+            version,
+            extra: from.extra,
+            content,
+        })
+    }
+}
+
+impl TryFrom<&DnstapContent> for dnstap::Message {
+    type Error = ConversionError;
+
+    fn try_from(from: &DnstapContent) -> Result<Self, ConversionError> {
+        let DnstapContent::Message {
             message_type,
             query_address,
             response_address,
@@ -185,43 +274,115 @@ impl DnstapContent {
             query_message,
             response_message,
             query_zone,
-        })
-    }
-}
+        } = from;
 
-impl TryFrom<dnstap::Dnstap> for Dnstap {
-    type Error = Error;
-
-    fn try_from(mut from: dnstap::Dnstap) -> Result<Self, Error> {
-        let identity = if from.has_identity() {
-            Some(String::from_utf8(from.take_identity())?)
-        } else {
-            None
+        let family = |addr: &IpAddr| match addr {
+            IpAddr::V4(_) => SocketFamily::Inet,
+            IpAddr::V6(_) => SocketFamily::Inet6,
+        };
+        let socket_family = match (
+            query_address.as_ref().map(family),
+            response_address.as_ref().map(family),
+        ) {
+            (Some(q), Some(r)) if q != r => return Err(ConversionError::SocketFamilyMismatch),
+            (Some(family), _) | (None, Some(family)) => Some(family as i32),
+            (None, None) => None,
         };
-        let version = if from.has_version() {
-            Some(String::from_utf8(from.take_version())?)
-        } else {
-            None
+        let address_bytes = |addr: &IpAddr| match addr {
+            IpAddr::V4(addr) => addr.octets().to_vec(),
+            IpAddr::V6(addr) => addr.octets().to_vec(),
         };
-        let extra = if from.has_extra() {
-            Some(from.take_extra())
-        } else {
-            None
+        let serialize_message = |msg: &Option<(DnsMessage, usize)>, field| {
+            msg.as_ref()
+                .map(|(dnsmsg, _size)| dnsmsg.to_vec())
+                .transpose()
+                .map_err(|source| ConversionError::Serialization { field, source })
         };
 
-        if !from.has_field_type() {
-            panic!("Need to have a field type!")
-        }
+        Ok(dnstap::Message {
+            r#type: *message_type as i32,
+            socket_family,
+            socket_protocol: None,
+            query_address: query_address.as_ref().map(address_bytes),
+            response_address: response_address.as_ref().map(address_bytes),
+            query_port: query_port.map(u32::from),
+            response_port: response_port.map(u32::from),
+            query_time_sec: query_time.map(|time| time.timestamp() as u64),
+            query_time_nsec: query_time.map(|time| time.timestamp_subsec_nanos()),
+            query_message: serialize_message(query_message, "query message")?,
+            query_zone: query_zone
+                .as_ref()
+                .map(BinEncodable::to_bytes)
+                .transpose()
+                .map_err(|source| ConversionError::Serialization {
+                    field: "query zone",
+                    source,
+                })?,
+            response_time_sec: response_time.map(|time| time.timestamp() as u64),
+            response_time_nsec: response_time.map(|time| time.timestamp_subsec_nanos()),
+            response_message: serialize_message(response_message, "response message")?,
+        })
+    }
+}
 
-        let content = match from.get_field_type() {
-            dnstap::Dnstap_Type::MESSAGE => DnstapContent::convert_message(from.take_message())?,
-        };
+impl TryFrom<&Dnstap> for dnstap::Dnstap {
+    type Error = ConversionError;
 
-        Ok(Dnstap {
-            identity,
-            version,
-            extra,
-            content,
+    fn try_from(from: &Dnstap) -> Result<Self, ConversionError> {
+        Ok(dnstap::Dnstap {
+            identity: from.identity.as_ref().map(|s| s.as_bytes().to_vec()),
+            version: from.version.as_ref().map(|s| s.as_bytes().to_vec()),
+            extra: from.extra.clone(),
+            r#type: dnstap::dnstap::Type::Message as i32,
+            message: Some(dnstap::Message::try_from(&from.content)?),
         })
     }
 }
+
+#[test]
+fn test_conversion_errors() {
+    let raw = dnstap::Dnstap::default();
+    assert!(matches!(
+        Dnstap::try_from(raw),
+        Err(ConversionError::UnknownEnumValue { value: 0, .. })
+    ));
+
+    let raw = dnstap::Dnstap {
+        r#type: dnstap::dnstap::Type::Message as i32,
+        ..Default::default()
+    };
+    assert!(matches!(
+        Dnstap::try_from(raw),
+        Err(ConversionError::MissingMessage)
+    ));
+
+    let mut message = dnstap::Message {
+        r#type: MessageType::ClientQuery as i32,
+        query_address: Some(vec![127, 0, 0, 1]),
+        ..Default::default()
+    };
+    let raw = dnstap::Dnstap {
+        r#type: dnstap::dnstap::Type::Message as i32,
+        message: Some(message.clone()),
+        ..Default::default()
+    };
+    assert!(matches!(
+        Dnstap::try_from(raw),
+        Err(ConversionError::MissingSocketFamily)
+    ));
+
+    message.socket_family = Some(SocketFamily::Inet6 as i32);
+    let raw = dnstap::Dnstap {
+        r#type: dnstap::dnstap::Type::Message as i32,
+        message: Some(message),
+        ..Default::default()
+    };
+    assert!(matches!(
+        Dnstap::try_from(raw),
+        Err(ConversionError::InvalidAddress {
+            len: 4,
+            expected: 16,
+            ..
+        })
+    ));
+}
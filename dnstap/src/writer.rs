@@ -0,0 +1,149 @@
+//! Write dnstap messages into frame stream files
+//!
+//! The [`DnstapWriter`] produces files, which can be read again with [`process_dnstap`](crate::process_dnstap).
+//! This allows creating synthetic traces, e.g., as input files for tests, or storing traces after they were transformed.
+//!
+//! The DNS messages of a [`protos::Dnstap`] are serialized again, so the stored size of the messages is not written.
+//! Reading the file back reports the length of the serialized message, which can differ from the original wire format, e.g., if the original message did not use name compression.
+//! Use [`DnstapWriter::write_raw`] to keep the exact bytes of existing messages.
+
+use crate::{dnstap, protos, CONTENT_TYPE};
+use anyhow::{Context as _, Error};
+use framestream::EncoderWriter;
+use misc_utils::fs::file_write;
+use prost::Message;
+use std::{convert::TryFrom, io::Write, path::Path};
+
+/// Writer for dnstap messages in the frame stream format
+pub struct DnstapWriter<W: Write> {
+    encoder: EncoderWriter<W>,
+    count: usize,
+}
+
+impl<W: Write> DnstapWriter<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            encoder: EncoderWriter::with_content_type(writer, CONTENT_TYPE.into()),
+            count: 0,
+        }
+    }
+
+    /// Serialize and append a single dnstap message
+    pub fn write(&mut self, dnstap: &protos::Dnstap) -> Result<(), Error> {
+        let raw_dnstap = dnstap::Dnstap::try_from(dnstap)
+            .with_context(|| format!("Failed to convert message {}", self.count))?;
+        self.write_raw(&raw_dnstap)
+    }
+
+    /// Append a single dnstap message in the protobuf representation
+    pub fn write_raw(&mut self, raw_dnstap: &dnstap::Dnstap) -> Result<(), Error> {
+        self.encoder.write_frame(&raw_dnstap.encode_to_vec())?;
+        self.count += 1;
+        Ok(())
+    }
+
+    /// Number of messages written so far
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    /// Write the end of the frame stream and return the inner writer
+    ///
+    /// The file is incomplete, if this function is not called.
+    pub fn finish(self) -> Result<W, Error> {
+        Ok(self.encoder.finish()?)
+    }
+}
+
+/// Create a new dnstap file at `path`, overwriting any existing file
+///
+/// The file is compressed based on the file extension.
+pub fn create_dnstap_file(path: &Path) -> Result<DnstapWriter<impl Write>, Error> {
+    let wtr = file_write(path)
+        .create(true)
+        .truncate()
+        .with_context(|| format!("Opening output file '{}' failed", path.display()))?;
+    Ok(DnstapWriter::new(wtr))
+}
+
+#[test]
+fn test_write_dnstap_roundtrip() {
+    use crate::protos::{DnstapContent, MessageType};
+    use chrono::{TimeZone, Utc};
+    use framestream::DecoderReader;
+    use std::net::{IpAddr, Ipv6Addr};
+    use trust_dns_proto::{
+        op::{Message as DnsMessage, Query},
+        rr::{Name, RecordType},
+    };
+
+    let name = Name::from_ascii("www.example.com.").unwrap();
+    let mut query = DnsMessage::new();
+    query.set_id(1234);
+    query.add_query(Query::query(name.clone(), RecordType::A));
+    let query_len = query.to_vec().unwrap().len();
+    let event = protos::Dnstap {
+        identity: Some("resolver".to_string()),
+        version: None,
+        extra: Some(vec![1, 2, 3]),
+        content: DnstapContent::Message {
+            message_type: MessageType::ClientQuery,
+            query_address: Some(IpAddr::V6(Ipv6Addr::LOCALHOST)),
+            response_address: Some(IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1))),
+            query_port: Some(12345),
+            response_port: Some(53),
+            query_time: Some(Utc.timestamp(1_600_000_000, 123_456_789)),
+            response_time: None,
+            query_message: Some((query, 0)),
+            response_message: None,
+            query_zone: Some(Name::from_ascii("example.com.").unwrap()),
+        },
+    };
+
+    let mut wtr = DnstapWriter::new(Vec::new());
+    wtr.write(&event).unwrap();
+    wtr.write(&event).unwrap();
+    assert_eq!(wtr.count(), 2);
+    let bytes = wtr.finish().unwrap();
+
+    let frames: Vec<Vec<u8>> = DecoderReader::with_content_type(&bytes[..], CONTENT_TYPE.into())
+        .collect::<Result<_, _>>()
+        .unwrap();
+    assert_eq!(frames.len(), 2);
+    let read = protos::Dnstap::try_from(dnstap::Dnstap::decode(&*frames[0]).unwrap()).unwrap();
+    assert_eq!(read.identity, event.identity);
+    assert_eq!(read.version, None);
+    assert_eq!(read.extra, event.extra);
+
+    let DnstapContent::Message {
+        message_type,
+        query_address,
+        response_address,
+        query_port,
+        response_port,
+        query_time,
+        response_time,
+        query_message,
+        response_message,
+        query_zone,
+    } = read.content;
+    let DnstapContent::Message {
+        query_address: orig_query_address,
+        response_address: orig_response_address,
+        query_time: orig_query_time,
+        ..
+    } = event.content;
+    assert_eq!(message_type, MessageType::ClientQuery);
+    assert_eq!(query_address, orig_query_address);
+    assert_eq!(response_address, orig_response_address);
+    assert_eq!(query_port, Some(12345));
+    assert_eq!(response_port, Some(53));
+    assert_eq!(query_time, orig_query_time);
+    assert_eq!(response_time, None);
+    assert!(response_message.is_none());
+    assert_eq!(query_zone, Some(Name::from_ascii("example.com.").unwrap()));
+    let (query_message, size) = query_message.unwrap();
+    assert_eq!(size, query_len);
+    assert_eq!(query_message.id(), 1234);
+    assert_eq!(query_message.queries()[0].name(), &name);
+}
@@ -1,28 +1,26 @@
-use anyhow::Error;
+//! Extract DNS sequences from pcap files
+//!
+//! This is a thin command line interface over [`sequences::pcap`], which contains the whole pcap processing logic.
+//! With `--recursive` whole directory trees, like the results store, are converted in parallel.
+
+use anyhow::{bail, Context as _, Error};
 use misc_utils::fs;
-use sequences::{pcap::build_sequence, LoadSequenceConfig};
+use rayon::prelude::*;
+use sequences::{
+    pcap::{
+        build_precision_sequence, build_sequence_with_summary,
+        decrypt::{build_decrypted_precision_sequence, build_decrypted_sequence},
+        PcapFilter, PcapSummary,
+    },
+    GapMode, LoadSequenceConfig, SimulatedCountermeasure, TlsOverheadModel, TruncationMode,
+};
+use serde_json::json;
 use std::{
-    net::SocketAddrV4,
+    collections::HashMap,
+    net::{Ipv4Addr, SocketAddrV4},
     path::{Path, PathBuf},
 };
-use structopt::{clap::arg_enum, StructOpt};
-
-arg_enum! {
-    #[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
-    pub enum GapMode {
-        Log2,
-        Ident
-    }
-}
-
-impl From<GapMode> for sequences::GapMode {
-    fn from(gm: GapMode) -> Self {
-        match gm {
-            GapMode::Log2 => sequences::GapMode::Log2,
-            GapMode::Ident => sequences::GapMode::Ident,
-        }
-    }
-}
+use structopt::StructOpt;
 
 #[derive(Clone, Debug, StructOpt)]
 #[structopt(global_settings(&[
@@ -40,15 +38,71 @@ struct CliArgs {
     /// The program tries its best to determine this automatically.
     #[structopt(short = "f", long = "filter")]
     filter: Option<SocketAddrV4>,
+    /// Only consider connections from this client IP
+    ///
+    /// This is necessary if the pcap contains connections of multiple clients to the same DNS server.
+    #[structopt(long = "client")]
+    client: Option<Ipv4Addr>,
+    /// Keep the TLS records of the client in addition to those of the server
+    #[structopt(long = "both-directions")]
+    both_directions: bool,
     /// List of PCAP files
     #[structopt(name = "PCAPS")]
     pcap_files: Vec<String>,
     /// Creates a `.json.xz` file for each pcap in the same directory
     #[structopt(long = "convert-to-json")]
     convert_to_json: bool,
+    /// Print a JSON summary of the TLS connections for each pcap
+    ///
+    /// The summary contains the TLS version, cipher suite, session resumption, and the number of TLS records per type.
+    /// Each summary is printed on a separate line.
+    #[structopt(long = "summary")]
+    summary: bool,
     /// Method to convert the time between messages into a gap value
-    #[structopt(long = "gap-mode", possible_values = &GapMode::variants(), case_insensitive = true)]
+    ///
+    /// Possible values are `Log2` and `Ident`.
+    #[structopt(long = "gap-mode")]
     gap_mode: Option<GapMode>,
+    /// Countermeasure to simulate while building the sequences
+    ///
+    /// One of `None`, `PerfectPadding`, `PerfectTiming`, `PerfectPaddingAndTiming`, `CountOnly`, `ConstantLength[:<n>]`, `DpLaplace[:<epsilon>]`, or `DpGaussian[:<epsilon>]`.
+    #[structopt(long = "simulate")]
+    simulate: Option<SimulatedCountermeasure>,
+    /// How many bytes of each TLS record are overhead
+    ///
+    /// Either a constant number of bytes or `negotiated[:<fallback>]` to derive it from the TLS version and cipher suite.
+    #[structopt(long = "tls-overhead")]
+    tls_overhead: Option<TlsOverheadModel>,
+    /// Process pcaps with truncated packets, e.g., due to a limited snaplen
+    ///
+    /// The missing bytes are filled with zeros.
+    /// The number of truncated packets is reported by `--summary` and stored in the metadata of the sequence.
+    #[structopt(long = "allow-truncated")]
+    allow_truncated: bool,
+    /// Also build the ground truth sequence from the decrypted DNS messages
+    ///
+    /// This requires the TLS key log file next to each pcap, e.g., `website.tlskeys.txt.xz` for `website.pcap.xz`.
+    /// With `--convert-to-json` the sequence is written to a `.groundtruth.json.xz` file.
+    #[structopt(long = "ground-truth")]
+    ground_truth: bool,
+    /// Treat PCAPS as directories and convert all pcaps found in them recursively
+    ///
+    /// This converts a directory tree laid out like the results store, i.e., `<domain>/<task>.pcap.xz`, in parallel.
+    /// The files are always written as with `--convert-to-json`.
+    /// A summary of all failed pcaps is printed at the end and the program fails if any pcap could not be converted.
+    #[structopt(short = "r", long = "recursive")]
+    recursive: bool,
+    /// Write the files into a mirror of the directory tree below this directory, instead of next to the pcaps
+    ///
+    /// Only used with `--recursive`.
+    #[structopt(long = "output-dir", value_name = "dir", parse(from_os_str))]
+    output_dir: Option<PathBuf>,
+    /// Build `PrecisionSequence`s instead of `Sequence`s in the recursive mode
+    ///
+    /// They are written to `.precision.json.xz` files.
+    /// Only `--filter` restricts the TLS records, and `--gap-mode`, `--simulate`, `--tls-overhead`, and `--summary` have no effect.
+    #[structopt(long = "precision")]
+    precision: bool,
 }
 
 fn main() -> Result<(), Error> {
@@ -57,17 +111,277 @@ fn main() -> Result<(), Error> {
     let cli_args = CliArgs::from_args();
     let mut config = LoadSequenceConfig::default();
     if let Some(gap_mode) = cli_args.gap_mode {
-        config.gap_mode = gap_mode.into();
+        config.gap_mode = gap_mode;
+    }
+    if let Some(simulate) = cli_args.simulate {
+        config.simulated_countermeasure = simulate;
+    }
+    if let Some(tls_overhead) = cli_args.tls_overhead {
+        config.tls_overhead = tls_overhead;
+    }
+    if cli_args.allow_truncated {
+        config.truncation = TruncationMode::Lenient;
+    }
+
+    let filter = PcapFilter {
+        server: cli_args.filter,
+        client: cli_args.client,
+        both_directions: cli_args.both_directions,
+    };
+
+    if cli_args.recursive {
+        return convert_recursive(&cli_args, filter, config);
+    }
+    if cli_args.output_dir.is_some() || cli_args.precision {
+        bail!("`--output-dir` and `--precision` require `--recursive`");
     }
 
     for file in cli_args.pcap_files {
-        let seq = build_sequence(Path::new(&file), cli_args.filter, cli_args.verbose, config)?;
+        let (seq, summary) =
+            build_sequence_with_summary(Path::new(&file), filter, cli_args.verbose, config)?;
+        if cli_args.summary {
+            println!(
+                "{}",
+                serde_json::to_string(&json!({"file": file, "summary": summary}))?
+            );
+        }
         if cli_args.convert_to_json {
             let mut path = PathBuf::from(&file);
             path.set_extension("json.xz");
             let _ = fs::write(&path, seq.to_json()?);
         }
+        if cli_args.ground_truth {
+            let keylog = tls_keys_path(Path::new(&file));
+            let seq = build_decrypted_sequence(Path::new(&file), &keylog, filter, config)?;
+            if cli_args.convert_to_json {
+                let mut path = PathBuf::from(&file);
+                path.set_extension("groundtruth.json.xz");
+                let _ = fs::write(&path, seq.to_json()?);
+            }
+        }
     }
 
     Ok(())
 }
+
+/// File name suffixes of the pcaps converted in the recursive mode
+const PCAP_SUFFIXES: &[&str] = &[".pcap", ".pcap.xz", ".pcap.gz"];
+
+/// Convert all pcaps below the directories in [`CliArgs::pcap_files`] and print a summary of the failures
+fn convert_recursive(
+    cli_args: &CliArgs,
+    filter: PcapFilter,
+    config: LoadSequenceConfig,
+) -> Result<(), Error> {
+    let files = output_paths(&cli_args.pcap_files, cli_args.output_dir.as_deref())?;
+
+    let results: Vec<Result<Option<PcapSummary>, Error>> = files
+        .par_iter()
+        .map(|(file, output)| convert_pcap(file, output, cli_args, filter, config))
+        .collect();
+
+    let mut failures = Vec::new();
+    for ((file, _), result) in files.iter().zip(results) {
+        match result {
+            Ok(Some(summary)) if cli_args.summary => println!(
+                "{}",
+                serde_json::to_string(&json!({"file": file, "summary": summary}))?
+            ),
+            Ok(_) => {}
+            Err(err) => failures.push((file, err)),
+        }
+    }
+
+    eprintln!(
+        "Converted {} of {} pcaps",
+        files.len() - failures.len(),
+        files.len()
+    );
+    if failures.is_empty() {
+        return Ok(());
+    }
+    eprintln!("Failed pcaps:");
+    for (file, err) in &failures {
+        eprintln!("  {}: {:#}", file.display(), err);
+    }
+    bail!(
+        "{} of {} pcaps could not be converted",
+        failures.len(),
+        files.len()
+    )
+}
+
+/// List all pcaps below the `roots` together with their path in the output tree
+///
+/// Without an `output_dir`, the output is written next to the pcap.
+/// Otherwise, the path of the pcap relative to its root is used below `output_dir`.
+/// Fails if two pcaps would be written to the same output, e.g., because the roots contain the same relative paths.
+fn output_paths<P: AsRef<Path>>(
+    roots: &[P],
+    output_dir: Option<&Path>,
+) -> Result<Vec<(PathBuf, PathBuf)>, Error> {
+    let mut files: Vec<(PathBuf, PathBuf)> = Vec::new();
+    for root in roots {
+        let root = root.as_ref();
+        let mut pcaps = Vec::new();
+        find_pcaps(root, &mut pcaps)
+            .with_context(|| format!("Cannot list the pcaps in {}", root.display()))?;
+        files.extend(pcaps.into_iter().map(|file| {
+            let output = match output_dir {
+                Some(output_dir) => output_dir.join(
+                    file.strip_prefix(root)
+                        .expect("All pcaps are below the root directory"),
+                ),
+                None => file.clone(),
+            };
+            (file, output)
+        }));
+    }
+    files.sort();
+
+    let mut outputs: HashMap<&Path, &Path> = HashMap::new();
+    for (file, output) in &files {
+        if let Some(other) = outputs.insert(output, file) {
+            bail!(
+                "The pcaps {} and {} would both be written to {}. Convert the directories separately.",
+                other.display(),
+                file.display(),
+                output.display()
+            );
+        }
+    }
+    Ok(files)
+}
+
+/// Recursively collect all files ending in one of the [`PCAP_SUFFIXES`] in `dir`
+fn find_pcaps(dir: &Path, pcaps: &mut Vec<PathBuf>) -> Result<(), Error> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            find_pcaps(&path, pcaps)?;
+        } else if path.is_file() {
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            if PCAP_SUFFIXES.iter().any(|suffix| name.ends_with(suffix)) {
+                pcaps.push(path);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Convert a single pcap in the recursive mode
+///
+/// `output` is the path of the pcap in the output tree, whose extension is replaced like with `--convert-to-json`.
+/// Returns the [`PcapSummary`], unless a `PrecisionSequence` is built.
+fn convert_pcap(
+    file: &Path,
+    output: &Path,
+    cli_args: &CliArgs,
+    filter: PcapFilter,
+    config: LoadSequenceConfig,
+) -> Result<Option<PcapSummary>, Error> {
+    if let Some(parent) = output.parent() {
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("Cannot create the directory {}", parent.display()))?;
+    }
+
+    let summary = if cli_args.precision {
+        let seq = build_precision_sequence(file, filter.server, cli_args.verbose, config)?;
+        fs::write(
+            output.with_extension("precision.json.xz"),
+            serde_json::to_string(&seq)?,
+        )?;
+        None
+    } else {
+        let (seq, summary) = build_sequence_with_summary(file, filter, cli_args.verbose, config)?;
+        fs::write(output.with_extension("json.xz"), seq.to_json()?)?;
+        Some(summary)
+    };
+
+    if cli_args.ground_truth {
+        let keylog = tls_keys_path(file);
+        if cli_args.precision {
+            let seq = build_decrypted_precision_sequence(file, &keylog, filter)?;
+            fs::write(
+                output.with_extension("groundtruth.precision.json.xz"),
+                serde_json::to_string(&seq)?,
+            )?;
+        } else {
+            let seq = build_decrypted_sequence(file, &keylog, filter, config)?;
+            fs::write(output.with_extension("groundtruth.json.xz"), seq.to_json()?)?;
+        }
+    }
+    Ok(summary)
+}
+
+/// Path of the TLS key log file belonging to the pcap `file`
+///
+/// The `.pcap` extension is replaced by `.tlskeys.txt`, keeping a compression extension.
+fn tls_keys_path(file: &Path) -> PathBuf {
+    let name = file.to_string_lossy();
+    match name.rfind(".pcap") {
+        Some(idx) => PathBuf::from(format!(
+            "{}.tlskeys.txt{}",
+            &name[..idx],
+            &name[idx + ".pcap".len()..]
+        )),
+        None => file.with_extension("tlskeys.txt"),
+    }
+}
+
+#[test]
+fn test_output_paths() {
+    let dir = std::env::temp_dir().join(format!("extract-sequence-{}", std::process::id()));
+    for file in &[
+        "a/example.com/0.pcap",
+        "a/example.com/1.pcap.xz",
+        "a/example.com/1.tlskeys.txt.xz",
+        "a/example.com/2.pcap.gz",
+        "a/example.com/3.pcapng",
+        "a/example.com/4.pcap.json.xz",
+        "b/example.org/0.pcap.xz",
+        "c/example.com/0.pcap",
+    ] {
+        let path = dir.join(file);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, "").unwrap();
+    }
+    let out = dir.join("out");
+    let relative = |files: Vec<(PathBuf, PathBuf)>| -> Vec<(PathBuf, PathBuf)> {
+        files
+            .into_iter()
+            .map(|(file, output)| {
+                (
+                    file.strip_prefix(&dir).unwrap().to_path_buf(),
+                    output.strip_prefix(&dir).unwrap().to_path_buf(),
+                )
+            })
+            .collect()
+    };
+
+    let files = output_paths(&[dir.join("a"), dir.join("b")], Some(&out)).unwrap();
+    assert_eq!(
+        vec![
+            ("a/example.com/0.pcap", "out/example.com/0.pcap"),
+            ("a/example.com/1.pcap.xz", "out/example.com/1.pcap.xz"),
+            ("a/example.com/2.pcap.gz", "out/example.com/2.pcap.gz"),
+            ("b/example.org/0.pcap.xz", "out/example.org/0.pcap.xz"),
+        ]
+        .into_iter()
+        .map(|(file, output)| (PathBuf::from(file), PathBuf::from(output)))
+        .collect::<Vec<_>>(),
+        relative(files)
+    );
+
+    // Both roots contain `example.com/0.pcap`
+    assert!(output_paths(&[dir.join("a"), dir.join("c")], Some(&out)).is_err());
+    // Next to the pcaps there are no collisions
+    assert_eq!(
+        4,
+        output_paths(&[dir.join("a"), dir.join("c")], None)
+            .unwrap()
+            .len()
+    );
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
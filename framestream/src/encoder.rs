@@ -0,0 +1,106 @@
+use crate::constants::{CONTROL_ESCAPE, CONTROL_FIELD_CONTENT_TYPE, CONTROL_START, CONTROL_STOP};
+use byteorder::{BigEndian, WriteBytesExt};
+use std::io::{self, Write};
+
+/// Writer for unidirectional Frame Streams, the counterpart to [`DecoderReader`](crate::DecoderReader)
+///
+/// The start frame is written before the first content frame.
+/// [`EncoderWriter::finish`] must be called to write the stop frame.
+#[derive(Clone, Debug)]
+pub struct EncoderWriter<W: Write> {
+    writer: W,
+    content_type: Option<String>,
+    wrote_start: bool,
+}
+
+impl<W: Write> EncoderWriter<W> {
+    pub fn new(writer: W) -> EncoderWriter<W> {
+        EncoderWriter {
+            writer,
+            content_type: None,
+            wrote_start: false,
+        }
+    }
+
+    pub fn with_content_type(writer: W, content_type: String) -> EncoderWriter<W> {
+        EncoderWriter {
+            writer,
+            content_type: Some(content_type),
+            wrote_start: false,
+        }
+    }
+
+    fn write_start_frame(&mut self) -> io::Result<()> {
+        let content_type_length = self
+            .content_type
+            .as_ref()
+            .map(|content_type| 8 + content_type.len())
+            .unwrap_or(0);
+        self.writer.write_u32::<BigEndian>(CONTROL_ESCAPE)?;
+        self.writer
+            .write_u32::<BigEndian>(4 + content_type_length as u32)?;
+        self.writer.write_u32::<BigEndian>(CONTROL_START)?;
+        if let Some(content_type) = &self.content_type {
+            self.writer
+                .write_u32::<BigEndian>(CONTROL_FIELD_CONTENT_TYPE)?;
+            self.writer
+                .write_u32::<BigEndian>(content_type.len() as u32)?;
+            self.writer.write_all(content_type.as_bytes())?;
+        }
+        self.wrote_start = true;
+        Ok(())
+    }
+
+    /// Write a single content frame
+    pub fn write_frame(&mut self, content: &[u8]) -> io::Result<()> {
+        if !self.wrote_start {
+            self.write_start_frame()?;
+        }
+        if content.is_empty() {
+            // A length of 0 is reserved for control frames
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Content frames cannot be empty",
+            ));
+        }
+        self.writer.write_u32::<BigEndian>(content.len() as u32)?;
+        self.writer.write_all(content)
+    }
+
+    /// Write the stop frame and return the inner writer
+    pub fn finish(mut self) -> io::Result<W> {
+        if !self.wrote_start {
+            self.write_start_frame()?;
+        }
+        self.writer.write_u32::<BigEndian>(CONTROL_ESCAPE)?;
+        self.writer.write_u32::<BigEndian>(4)?;
+        self.writer.write_u32::<BigEndian>(CONTROL_STOP)?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+#[test]
+fn test_roundtrip() {
+    use crate::DecoderReader;
+    use std::io::Cursor;
+
+    let frames: Vec<&[u8]> = vec![b"Hello", b"World", b"!"];
+    let mut wtr = EncoderWriter::with_content_type(Vec::new(), "test".into());
+    for frame in &frames {
+        wtr.write_frame(frame).unwrap();
+    }
+    let data = wtr.finish().unwrap();
+
+    let rdr = DecoderReader::with_content_type(Cursor::new(data), "test".into());
+    let read: Vec<_> = rdr.map(Result::unwrap).collect();
+    assert_eq!(frames, read);
+
+    // Re-encoding the test file reproduces it exactly
+    let data = include_bytes!("../test.fstrm");
+    let mut wtr = EncoderWriter::with_content_type(Vec::new(), "test:hello".into());
+    for frame in DecoderReader::new(Cursor::new(&data[..])) {
+        wtr.write_frame(&frame.unwrap()).unwrap();
+    }
+    assert_eq!(&data[..], &*wtr.finish().unwrap());
+}
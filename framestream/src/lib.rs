@@ -1,4 +1,8 @@
 mod constants;
 mod decoder;
+mod encoder;
 
-pub use crate::decoder::{DecodeError, DecoderReader, Frame};
+pub use crate::{
+    decoder::{DecodeError, DecoderReader, Frame},
+    encoder::EncoderWriter,
+};
@@ -8,7 +8,7 @@ from os import path
 import keras
 import keras.utils
 import numpy as np
-import pylib
+import padding_aint_enough as pylib
 from keras import layers
 from keras.models import Sequential
 from keras.preprocessing.sequence import pad_sequences
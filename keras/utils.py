@@ -132,7 +132,7 @@ def load_data(
     `extension_pattern`: A pattern for glob describing which file extensions to load
     `training_validation_split`: The first ID which should be used for validation instead of training.
     """
-    import pylib
+    import padding_aint_enough as pylib
 
     canonicalizer = Canonicalize(confusion_domains)
 
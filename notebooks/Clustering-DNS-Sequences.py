@@ -30,7 +30,7 @@ from glob import glob
 from pprint import pprint
 
 import matplotlib.pyplot as plt
-import pylib
+import padding_aint_enough as pylib
 import scipy.cluster.hierarchy as cluster
 from scipy.spatial.distance import pdist
 
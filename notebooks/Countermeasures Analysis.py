@@ -18,7 +18,7 @@ from glob import glob
 from os import path
 
 import matplotlib.pyplot as plt
-import pylib
+import padding_aint_enough as pylib
 import scipy.stats
 from functional import seq
 
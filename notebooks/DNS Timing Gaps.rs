@@ -30,10 +30,9 @@ extern crate itertools;
 use chrono::Duration;
 use counter::Counter;
 use dnstap::{
-    dnstap::Message_Type,
     process_dnstap,
     protos::{self, DnstapContent},
-    sanity_check_dnstap,
+    sanity_check_dnstap, MessageType,
 };
 use itertools::Itertools;
 use std::path::{Path, PathBuf};
@@ -75,7 +74,7 @@ pub fn extract_gaps<P: AsRef<Path>>(file: P) -> Vec<(Duration, String)> {
                 ref response_message,
                 ..
             } = ev.content;
-            if message_type == Message_Type::CLIENT_RESPONSE {
+            if message_type == MessageType::ClientResponse {
                 let (dnsmsg, _size) =
                     response_message.as_ref().expect("Unbound always sets this");
                 let qname = dnsmsg.queries()[0].name().to_utf8();
@@ -96,7 +95,7 @@ pub fn extract_gaps<P: AsRef<Path>>(file: P) -> Vec<(Duration, String)> {
                 ref query_message,
                 ..
             } = ev.content;
-            if message_type == Message_Type::CLIENT_QUERY {
+            if message_type == MessageType::ClientQuery {
                 let (dnsmsg, _size) = query_message.as_ref().expect("Unbound always sets this");
                 let qname = dnsmsg.queries()[0].name().to_utf8();
                 if qname == "end.example." {
@@ -114,12 +113,12 @@ pub fn extract_gaps<P: AsRef<Path>>(file: P) -> Vec<(Duration, String)> {
                 ..
             } = ev.content;
             match message_type {
-// //                 Message_Type::CLIENT_QUERY => {
-//                 Message_Type::FORWARDER_QUERY => {
+// //                 MessageType::ClientQuery => {
+//                 MessageType::ForwarderQuery => {
 //                     Some(query_time.expect("Unbound always sets this"))
 //                 }
-//                 Message_Type::CLIENT_QUERY => {
-                Message_Type::FORWARDER_RESPONSE => {
+//                 MessageType::ClientQuery => {
+                MessageType::ForwarderResponse => {
                     let response_time = response_time.expect("Unbound always sets this");
                     let (dnsmsg, _size) = response_message.expect("Unbound always sets this");
 //                     let type_ = dnsmsg.queries()[0].query_type();
@@ -19,7 +19,7 @@ from collections import OrderedDict
 from itertools import chain, combinations_with_replacement
 
 import matplotlib.pyplot as plt
-import pylib
+import padding_aint_enough as pylib
 from natsort import natsorted
 
 # %%
@@ -23,7 +23,7 @@
 import typing as t
 
 import matplotlib.pyplot as plt
-import pylib
+import padding_aint_enough as pylib
 import seaborn as sns
 
 # %%
@@ -22,7 +22,7 @@ from glob import glob
 
 import matplotlib.pyplot as plt
 import numpy as np
-import pylib
+import padding_aint_enough as pylib
 import tldextract
 from natsort import natsorted
 
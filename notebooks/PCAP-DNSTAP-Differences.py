@@ -47,7 +47,7 @@
 import json
 from pprint import pprint
 
-import pylib
+import padding_aint_enough as pylib
 
 # %%
 file = "/home/jbushart/tmp/wetter.com/wetter.com-9-0.dnstap.xz"
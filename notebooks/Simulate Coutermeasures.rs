@@ -43,7 +43,7 @@ use rayon::prelude::*;
 use std::collections::BTreeMap;
 
 // %%
-let pseqs: Vec<PrecisionSequence> = serde_json::from_str(&std::fs::read_to_string("pass-pcap.json").unwrap()).unwrap();
+let pseqs: Vec<PrecisionSequence> = sequences::from_json_any_version(&std::fs::read_to_string("pass-pcap.json").unwrap()).unwrap();
 pseqs[0]
 
 // %%
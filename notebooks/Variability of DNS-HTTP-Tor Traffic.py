@@ -74,7 +74,7 @@ from os import path
 import matplotlib.patches as mpatches
 import matplotlib.pyplot as plt
 import numpy as np
-import pylib
+import padding_aint_enough as pylib
 import scipy
 import tabulate
 from dataclasses_json import dataclass_json
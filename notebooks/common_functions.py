@@ -27,7 +27,7 @@ from glob import glob
 from itertools import cycle
 
 import matplotlib.cm
-import pylib
+import padding_aint_enough as pylib
 import tabulate
 from IPython.display import HTML, display
 
@@ -0,0 +1,73 @@
+//! Structured representation of Python exceptions
+
+use pyo3::{types::PyTraceback, PyErr, PyResult, Python};
+use std::fmt;
+
+/// An exception raised while executing Python code
+///
+/// In contrast to [`PyErr`], this does not require the GIL and can be stored in an [`anyhow::Error`].
+#[derive(Clone, Debug)]
+pub struct PythonError {
+    /// Name of the exception type, e.g., `ValueError`
+    pub exception_type: String,
+    /// The message of the exception, i.e., `str(exception)`
+    pub message: String,
+    /// Lines of the formatted traceback, starting with the outermost frame
+    pub traceback: Vec<String>,
+}
+
+impl PythonError {
+    /// Extract all information from a [`PyErr`]
+    ///
+    /// This never panics. Information which cannot be extracted is replaced by a placeholder.
+    pub fn from_pyerr(py: Python<'_>, err: &PyErr) -> Self {
+        let exception_type = err
+            .get_type(py)
+            .name()
+            .map(ToString::to_string)
+            .unwrap_or_else(|_| "<unknown exception>".to_string());
+        let message = err
+            .value(py)
+            .str()
+            .map(|msg| msg.to_string_lossy().into_owned())
+            .unwrap_or_else(|_| "<message not printable>".to_string());
+        let traceback = err
+            .traceback(py)
+            .and_then(|traceback| format_traceback(py, traceback).ok())
+            .unwrap_or_default();
+
+        Self {
+            exception_type,
+            message,
+            traceback,
+        }
+    }
+}
+
+/// Format the `traceback` with Python's `traceback` module and split it into lines
+fn format_traceback(py: Python<'_>, traceback: &PyTraceback) -> PyResult<Vec<String>> {
+    let entries: Vec<String> = py
+        .import("traceback")?
+        .call_method1("format_tb", (traceback,))?
+        .extract()?;
+    Ok(entries
+        .iter()
+        .flat_map(|entry| entry.lines())
+        .map(ToString::to_string)
+        .collect())
+}
+
+impl fmt::Display for PythonError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.exception_type, self.message)?;
+        if !self.traceback.is_empty() {
+            write!(f, "\nTraceback (most recent call last):")?;
+            for line in &self.traceback {
+                write!(f, "\n{}", line)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for PythonError {}
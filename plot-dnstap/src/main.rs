@@ -1,6 +1,14 @@
-use anyhow::{anyhow, Context as _, Error};
-use pyo3::{types::PyDict, PyErr, PyResult, Python};
-use sequences::dnstap::Query;
+mod error;
+
+use crate::error::PythonError;
+use anyhow::{anyhow, bail, Context as _, Error};
+use chrono::{DateTime, Utc};
+use pyo3::{exceptions::PyImportError, PyErr, Python};
+use sequences::{
+    dnstap::{MarkerTimes, Query, QuerySource},
+    precision_sequence::PrecisionSequence,
+};
+use serde::Serialize;
 use std::path::{Path, PathBuf};
 use structopt::StructOpt;
 
@@ -22,6 +30,11 @@ struct CliArgs {
     /// Height of the output graphic in inches
     #[structopt(short, long, default_value = "6")]
     height: u32,
+    /// Defended PrecisionSequence for each DNSTAP file, in the same order
+    ///
+    /// The dummy packets of the defended PrecisionSequence are marked in the plot.
+    #[structopt(long, value_name = "FILE", number_of_values = 1)]
+    defended: Vec<PathBuf>,
     /// List of DNSTAP files to process and plot
     #[structopt(value_name = "DNSTAP FILES")]
     dnstap_files: Vec<PathBuf>,
@@ -40,77 +53,158 @@ fn main() -> Result<(), Error> {
     let width = cli_args.width;
     let height = cli_args.height;
 
-    let querysets: Vec<(Vec<Query>, PathBuf)> = cli_args
+    if !cli_args.defended.is_empty() && cli_args.defended.len() != cli_args.dnstap_files.len() {
+        bail!(
+            "Got {} defended PrecisionSequences for {} DNSTAP files, but there must be one for each file.",
+            cli_args.defended.len(),
+            cli_args.dnstap_files.len()
+        );
+    }
+    let mut defended = cli_args.defended.into_iter();
+
+    let querysets: Vec<(QuerySet, PathBuf)> = cli_args
         .dnstap_files
         .into_iter()
         .map(|file| {
-            let queries = sequences::dnstap::load_matching_query_responses_from_dnstap(&file)
+            let mut queryset = QuerySet::load(&file, defended.next().as_deref())
                 .with_context(|| anyhow!("Cannot process file {}", file.display()))?;
             let outfile = if let Some(outdir) = outdir {
                 outdir.join(file.file_name().unwrap()).with_extension("svg")
             } else {
                 file.with_extension("svg")
             };
+            queryset.filename = stem_file(&outfile);
 
-            Ok((queries, outfile))
+            Ok((queryset, outfile))
         })
         .collect::<Result<_, Error>>()?;
 
     if cli_args.single_file {
         let outfile = querysets[0].1.clone();
-        let querysets = querysets
-            .into_iter()
-            .map(|(qs, fname)| (qs, stem_file(&fname)))
-            .collect();
-        plot_queries(querysets, &outfile, width, height).map_err(pyerr2error)?;
+        let querysets = querysets.into_iter().map(|(qs, _)| qs).collect();
+        plot_queries(querysets, &outfile, width, height)?;
     } else {
-        querysets.into_iter().try_for_each(|(queries, outfile)| {
-            plot_queries(
-                vec![(queries, stem_file(&outfile))],
-                &outfile,
-                width,
-                height,
-            )
-            .map_err(pyerr2error)
+        querysets.into_iter().try_for_each(|(queryset, outfile)| {
+            plot_queries(vec![queryset], &outfile, width, height)
         })?;
     }
 
     Ok(())
 }
 
+/// All data plotted for a single DNSTAP file
+#[derive(Debug, Serialize)]
+struct QuerySet {
+    queries: Vec<PlotQuery>,
+    filename: String,
+    markers: MarkerTimes,
+    /// Times of the dummy packets of the defended PrecisionSequence
+    dummy_events: Vec<DateTime<Utc>>,
+}
+
+/// A [`Query`] with additional information for plotting
+#[derive(Debug, Serialize)]
+struct PlotQuery {
+    #[serde(flatten)]
+    query: Query,
+    /// For client queries, `true` if the resolver answered from the cache without any forwarder query
+    cache_hit: Option<bool>,
+}
+
+impl QuerySet {
+    fn load(dnstap_file: &Path, defended: Option<&Path>) -> Result<Self, Error> {
+        let queries = sequences::dnstap::load_matching_query_responses_from_dnstap(dnstap_file)?;
+        let markers = sequences::dnstap::load_marker_times_from_dnstap(dnstap_file)?;
+        let dummy_events = match defended {
+            Some(defended) => PrecisionSequence::from_path(defended)
+                .with_context(|| anyhow!("Cannot load {}", defended.display()))?
+                .events()
+                .iter()
+                .filter(|event| event.is_dummy_event())
+                .map(|event| DateTime::from_utc(event.time(), Utc))
+                .collect(),
+            None => Vec::new(),
+        };
+
+        let queries = queries
+            .iter()
+            .map(|query| PlotQuery {
+                query: query.clone(),
+                cache_hit: if query.source == QuerySource::Client {
+                    Some(is_cache_hit(query, &queries))
+                } else {
+                    None
+                },
+            })
+            .collect();
+        Ok(Self {
+            queries,
+            filename: String::new(),
+            markers,
+            dummy_events,
+        })
+    }
+}
+
+/// A client query is a cache hit, if there is no forwarder query for the same name and type while the client query is outstanding
+fn is_cache_hit(client_query: &Query, queries: &[Query]) -> bool {
+    !queries.iter().any(|query| {
+        query.source == QuerySource::Forwarder
+            && query.qname.eq_ignore_ascii_case(&client_query.qname)
+            && query.qtype == client_query.qtype
+            && query.start >= client_query.start
+            && query.start <= client_query.end
+    })
+}
+
+/// Python modules required by `plot.py`
+const REQUIRED_MODULES: &[&str] = &["matplotlib", "numpy"];
+
 fn plot_queries(
-    queries: Vec<(Vec<Query>, String)>,
+    queries: Vec<QuerySet>,
     output_filename: &Path,
     width: u32,
     height: u32,
-) -> PyResult<()> {
+) -> Result<(), Error> {
+    let queries = serde_json::to_string_pretty(&queries)?;
+
     let gil = Python::acquire_gil();
     let py = gil.python();
+    let to_error = |err: PyErr| Error::new(PythonError::from_pyerr(py, &err));
 
-    let main_module = py.import("__main__").unwrap();
+    check_backend(py)?;
+    let main_module = py.import("__main__").map_err(to_error)?;
     let globals = main_module.dict();
-    globals.set_item("queries", serde_json::to_string_pretty(&queries).unwrap())?;
-    globals.set_item("image_width", width)?;
-    globals.set_item("image_height", height)?;
-    globals.set_item("output_filename", output_filename.to_string_lossy())?;
-    py.run(include_str!("plot.py"), Some(globals), None)?;
+    globals.set_item("queries", queries).map_err(to_error)?;
+    globals.set_item("image_width", width).map_err(to_error)?;
+    globals.set_item("image_height", height).map_err(to_error)?;
+    globals
+        .set_item("output_filename", output_filename.to_string_lossy())
+        .map_err(to_error)?;
+    py.run(include_str!("plot.py"), Some(globals), None)
+        .map_err(to_error)
+        .with_context(|| anyhow!("Cannot plot {}", output_filename.display()))?;
     Ok(())
 }
 
-/// Convert a [`PyErr`] into an [`Error`]
-fn pyerr2error(err: PyErr) -> Error {
-    let gil = Python::acquire_gil();
-    let py = gil.python();
-
-    err.clone_ref(py).print_and_set_sys_last_vars(py);
-    let locals = PyDict::new(py);
-    let _ = locals.set_item("err", err);
-    let err_msg: String = py
-        .eval("repr(err)", None, Some(locals))
-        .unwrap()
-        .extract()
-        .unwrap();
-    anyhow!("{}", err_msg)
+/// Ensure that all [`REQUIRED_MODULES`] can be imported
+///
+/// Returns an error explaining how to set up the Python environment otherwise.
+fn check_backend(py: Python<'_>) -> Result<(), Error> {
+    for module in REQUIRED_MODULES {
+        if let Err(err) = py.import(*module) {
+            let error = Error::new(PythonError::from_pyerr(py, &err));
+            if err.is_instance_of::<PyImportError>(py) {
+                return Err(error.context(format!(
+                    "The plotting backend is not configured: The Python module `{}` is missing. \
+                     Install it into the Python environment used by plot-dnstap, e.g., with `pip install {}`.",
+                    module, module
+                )));
+            }
+            return Err(error.context(format!("Cannot import the Python module `{}`", module)));
+        }
+    }
+    Ok(())
 }
 
 /// Return the filename without the extension
@@ -122,9 +122,41 @@ def get_next_index() -> int:
 #     end: datetime
 #     query_size: int
 #     response_size: int
+#     resolver: t.Optional[str]
+#     cache_hit: t.Optional[bool]
+#
+# class QuerySet(t.TypedDict):
+#     queries: t.List[Query]
+#     filename: str
+#     markers: t.Dict[str, t.Optional[str]]
+#     dummy_events: t.List[str]
+
+# Glyphs for the annotated events, as pairs of marker and color
+GLYPHS: t.Dict[str, t.Tuple[str, str]] = {
+    "Start marker": (">", "green"),
+    "End marker": ("<", "red"),
+    "Cache hit": ("o", "green"),
+    "Cache miss": ("x", "black"),
+    "Dummy packet": ("|", "purple"),
+}
+USED_GLYPHS: t.Set[str] = set()
+# Row label for the markers and the dummy packets
+EVENTS_LABEL = "Markers & dummy packets"
+
+
+def annotate(kind: str, x: t.List[float], y: t.List[float]) -> None:
+    """
+    Draw the glyph of the event `kind` at all positions
+    """
+
+    if len(x) == 0:
+        return
+    marker, color = GLYPHS[kind]
+    USED_GLYPHS.add(kind)
+    plt.scatter(x, y, marker=marker, color=color, s=60, zorder=3)
 
 
-parsed_queries: t.List[t.List[t.Any]] = json.loads(queries)
+parsed_queries: t.List[t.Dict[str, t.Any]] = json.loads(queries)
 if len(parsed_queries) == 0:
     sys.exit(1)
 
@@ -143,9 +175,12 @@ if num_querysets > 1:
 
 end_time = 0.0
 
-for queryset_id, (queryset, filename) in enumerate(parsed_queries):
+for queryset_id, parsed_queryset in enumerate(parsed_queries):
     # A queryset is the set of queries from a single source file
     # Multiple sourcefiles can be combined into one output plot
+    queryset = parsed_queryset["queries"]
+    if len(queryset) == 0:
+        continue
     for q in queryset:
         q["start"] = parse_date(q["start"])
         q["end"] = parse_date(q["end"])
@@ -187,6 +222,16 @@ for queryset_id, (queryset, filename) in enumerate(parsed_queries):
             hatch=hatch,
         )
 
+        if q["cache_hit"] is not None:
+            annotate(
+                "Cache hit" if q["cache_hit"] else "Cache miss",
+                [
+                    (start - min_dns_start).total_seconds() / correction_factor
+                    + (end - start).total_seconds()
+                ],
+                [ind],
+            )
+
         if num_querysets == 1:
             # Only attach labels, if we print a single queryset
             label = ""
@@ -203,15 +248,53 @@ for queryset_id, (queryset, filename) in enumerate(parsed_queries):
                 fontname="Symbola",
             )
 
+    # The markers and the dummy packets are shown in an additional row below the queries
+    if EVENTS_LABEL not in LABEL2INDEX.keys():
+        LABEL2INDEX[EVENTS_LABEL] = get_next_index()
+    events_ind = LABEL2INDEX[EVENTS_LABEL] * num_querysets - queryset_id
+    for kind, time in (
+        ("Start marker", parsed_queryset["markers"]["start"]),
+        ("End marker", parsed_queryset["markers"]["end"]),
+    ):
+        if time is not None:
+            offset = (parse_date(time) - min_dns_start).total_seconds()
+            plt.axvline(
+                offset / correction_factor,
+                color=GLYPHS[kind][1],
+                linestyle=":",
+                linewidth=1,
+            )
+            annotate(kind, [offset / correction_factor], [events_ind])
+    annotate(
+        "Dummy packet",
+        [
+            (parse_date(time) - min_dns_start).total_seconds() / correction_factor
+            for time in parsed_queryset["dummy_events"]
+        ],
+        [events_ind] * len(parsed_queryset["dummy_events"]),
+    )
+
 legend_handles = [
     Line2D(
         [0],
         [0],
         color=info_from_source(colormap, "Forwarder", 128, queryset_id)[0],
         lw=8,
-        label=filename,
+        label=parsed_queryset["filename"],
+    )
+    for queryset_id, parsed_queryset in enumerate(parsed_queries)
+] + [
+    Line2D(
+        [0],
+        [0],
+        marker=GLYPHS[kind][0],
+        color=GLYPHS[kind][1],
+        linestyle="",
+        markersize=8,
+        label=kind,
     )
-    for queryset_id, (_queries, filename) in enumerate(parsed_queries)
+    for kind in GLYPHS
+    if kind in USED_GLYPHS
 ]
 
 yticks: t.List[float] = []
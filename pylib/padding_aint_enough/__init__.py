@@ -0,0 +1,4 @@
+"""Python bindings for loading and comparing DNS sequences"""
+
+from .padding_aint_enough import *  # noqa: F401,F403
+from .padding_aint_enough import __version__  # noqa: F401
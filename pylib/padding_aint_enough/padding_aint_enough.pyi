@@ -0,0 +1,63 @@
+import typing as t
+
+import numpy as np
+import numpy.typing as npt
+
+__version__: str
+
+class Sequence:
+    """Represents a sequence of DNS packets as measured on the wire"""
+
+    @classmethod
+    def from_path(cls, path: str) -> "Sequence": ...
+    def id(self) -> str: ...
+    def distance(self, other: "Sequence") -> int: ...
+    def distance_with_details(
+        self, other: "Sequence"
+    ) -> t.Tuple[int, t.Dict[str, int]]: ...
+    def distance_cost_profile(
+        self, other: "Sequence"
+    ) -> t.Tuple[int, t.List[int]]: ...
+    def classify(self) -> t.Optional[str]: ...
+    def to_one_hot_encoding(self) -> t.List[t.List[int]]: ...
+    def to_vector_encoding(self) -> t.List[t.Tuple[int, int]]: ...
+    def len(self) -> int: ...
+    def message_count(self) -> int: ...
+    def element_histogram(self) -> t.Tuple[t.Dict[int, int], t.Dict[int, int]]: ...
+    def complexity(self) -> int: ...
+    def to_json(self) -> str: ...
+    def __lt__(self, other: "Sequence") -> bool: ...
+    def __le__(self, other: "Sequence") -> bool: ...
+    def __gt__(self, other: "Sequence") -> bool: ...
+    def __ge__(self, other: "Sequence") -> bool: ...
+
+def load_file(
+    path: str, gap_mode: t.Optional[str] = None, padding: t.Optional[str] = None
+) -> Sequence: ...
+def load_folder(
+    path: str,
+    extension: t.Optional[str] = None,
+    gap_mode: t.Optional[str] = None,
+    padding: t.Optional[str] = None,
+    min_length: t.Optional[int] = None,
+    max_length: t.Optional[int] = None,
+    min_complexity: t.Optional[int] = None,
+    segmentation: t.Optional[str] = None,
+) -> t.List[t.Tuple[str, t.List[Sequence]]]: ...
+def load_preprocessed(path: str) -> t.List[t.Tuple[str, t.List[Sequence]]]: ...
+def sequence_path(id: str, root: str) -> str: ...
+def element_histogram(
+    sequences: t.List[Sequence],
+) -> t.Tuple[t.Dict[int, int], t.Dict[int, int]]: ...
+def distances(
+    seqs_a: t.List[Sequence],
+    seqs_b: t.List[Sequence],
+    metric: t.Optional[str] = None,
+) -> npt.NDArray[np.uint64]: ...
+def knn_predict(
+    training: t.List[t.Tuple[str, t.List[Sequence]]],
+    test: t.List[Sequence],
+    k: int,
+    metric: t.Optional[str] = None,
+    weighting: t.Optional[str] = None,
+) -> t.List[t.Tuple[t.Optional[str], float]]: ...
@@ -13,11 +13,22 @@ else:
 
 
 setup(
-    name="pylib",
-    version="1.1.0",
+    name="padding_aint_enough",
+    version="1.2.0",
+    packages=["padding_aint_enough"],
+    package_data={"padding_aint_enough": ["py.typed", "*.pyi"]},
     rust_extensions=[
-        RustExtension("pylib", "Cargo.toml", binding=Binding.PyO3, debug=force_debug)
+        RustExtension(
+            "padding_aint_enough.padding_aint_enough",
+            "Cargo.toml",
+            binding=Binding.PyO3,
+            debug=force_debug,
+            # The extension only uses the stable ABI, such that one wheel works for all Python versions
+            py_limited_api=True,
+        )
     ],
+    python_requires=">=3.7",
+    install_requires=["numpy"],
     # rust extensions are not zip safe, just like C-extensions.
     zip_safe=False,
 )
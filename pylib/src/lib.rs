@@ -1,13 +1,16 @@
 #![allow(clippy::all)]
 
 use anyhow::{anyhow, Context as _, Error};
+use numpy::{PyArray, PyArray2};
 use pyo3::{
     basic::CompareOp, exceptions::PyException, prelude::*, types::PyType, PyObjectProtocol,
 };
+use rayon::prelude::*;
 use sequences::{
-    distance_cost_info::CostTracker, knn::LabelledSequences,
-    load_all_files_with_extension_from_dir_with_config, GapMode, LoadSequenceConfig,
-    OneHotEncoding, Padding, Sequence,
+    distance_cost_info::CostTracker,
+    knn::{knn_with_metric, DistanceMetric, LabelledSequences, VoteWeighting},
+    load_all_files_with_extension_from_dir_with_config, LoadSequenceConfig, OneHotEncoding,
+    Sequence,
 };
 use std::{collections::BTreeMap, ffi::OsStr, path::Path};
 
@@ -15,9 +18,37 @@ fn error2py(err: Error) -> PyErr {
     PyErr::new::<PyException, _>(err.to_string())
 }
 
+/// Parse the name of a [`DistanceMetric`], defaulting to the edit distance
+fn parse_metric(metric: Option<String>) -> PyResult<DistanceMetric> {
+    metric
+        .as_deref()
+        .unwrap_or("edit")
+        .parse()
+        .map_err(error2py)
+}
+
+/// Create the [`LoadSequenceConfig`] from the optional `gap_mode` and `padding` arguments
+///
+/// Unknown values are reported as an error.
+fn load_config(gap_mode: Option<String>, padding: Option<String>) -> PyResult<LoadSequenceConfig> {
+    let mut config = LoadSequenceConfig::default();
+    if let Some(gap_mode) = gap_mode {
+        config.gap_mode = gap_mode.parse().map_err(error2py)?;
+    }
+    if let Some(padding) = padding {
+        config.padding = padding.parse().map_err(error2py)?;
+    }
+    Ok(config)
+}
+
+/// Copy the sequences out of the Python objects, such that they can be used without holding the GIL
+fn clone_sequences(sequences: &[PyRef<'_, PySequence>]) -> Vec<Sequence> {
+    sequences.iter().map(|seq| seq.sequence.clone()).collect()
+}
+
 // Function name is module name
 #[pymodule]
-fn pylib(_py: Python<'_>, m: &PyModule) -> PyResult<()> {
+fn padding_aint_enough(_py: Python<'_>, m: &PyModule) -> PyResult<()> {
     m.add_class::<PySequence>()?;
     m.add("__version__", env!("CARGO_PKG_VERSION"))?;
 
@@ -25,6 +56,7 @@ fn pylib(_py: Python<'_>, m: &PyModule) -> PyResult<()> {
     /// --
     ///
     /// Load a dnstap file from disk and create a `Sequence` object
+    /// `gap_mode` is `Log2` or `Ident` and `padding` is `Q128R468`.
     #[pyfn(m)]
     #[pyo3(name = "load_file")]
     fn load_file(
@@ -32,23 +64,22 @@ fn pylib(_py: Python<'_>, m: &PyModule) -> PyResult<()> {
         gap_mode: Option<String>,
         padding: Option<String>,
     ) -> PyResult<PySequence> {
-        let mut config = LoadSequenceConfig::default();
-        if let Some(gap_mode) = gap_mode {
-            config.gap_mode = gap_mode.parse().unwrap_or_else(|_| Default::default());
-        }
-        if let Some(padding) = padding {
-            config.padding = padding.parse().unwrap_or_else(|_| Default::default());
-        }
+        let config = load_config(gap_mode, padding)?;
 
         let seq = Sequence::from_path_with_config(Path::new(&path), config).map_err(error2py)?;
         Ok(seq.into())
     }
 
-    /// load_folder(path, extension = "dnstap", /, gap_mode, padding)
+    /// load_folder(path, extension = "dnstap", /, gap_mode, padding, min_length, max_length, min_complexity, segmentation)
     /// --
     ///
     /// Load a whole folder of files with given `extension`.
     /// `extension` defaults to the value "dnstap".
+    /// The ids of the sequences are relative to `path`, see `sequence_path`.
+    ///
+    /// Sequences with fewer than `min_length` or more than `max_length` messages, or a complexity below `min_complexity` are skipped.
+    /// The number of skipped sequences is logged.
+    /// `segmentation` is `None` or `ChangePoint[:<n>]` and removes the traffic after the initial page load.
     #[pyfn(m)]
     #[pyo3(name = "load_folder")]
     fn load_folder(
@@ -57,21 +88,20 @@ fn pylib(_py: Python<'_>, m: &PyModule) -> PyResult<()> {
         extension: Option<String>,
         gap_mode: Option<String>,
         padding: Option<String>,
+        min_length: Option<usize>,
+        max_length: Option<usize>,
+        min_complexity: Option<usize>,
+        segmentation: Option<String>,
     ) -> PyResult<Vec<(String, Vec<PySequence>)>> {
         let extension = extension.unwrap_or_else(|| "dnstap".to_string());
-        let mut config = LoadSequenceConfig::default();
-        if let Some(gap_mode) = gap_mode {
-            config.gap_mode = match gap_mode.as_ref() {
-                "Log2" => GapMode::Log2,
-                "Ident" => GapMode::Ident,
-                _ => GapMode::default(),
-            };
-        }
-        if let Some(padding) = padding {
-            config.padding = match padding.as_ref() {
-                "Q128R468" => Padding::Q128R468,
-                _ => Padding::default(),
-            };
+        let mut config = LoadSequenceConfig {
+            min_length,
+            max_length,
+            min_complexity,
+            ..load_config(gap_mode, padding)?
+        };
+        if let Some(segmentation) = segmentation {
+            config.segmentation = segmentation.parse().map_err(error2py)?;
         }
 
         let seqs = py
@@ -102,7 +132,7 @@ fn pylib(_py: Python<'_>, m: &PyModule) -> PyResult<()> {
         let s = misc_utils::fs::read_to_string(&path)
             .with_context(|| anyhow!("Could not open {} to read from it.", path))
             .map_err(|err| error2py(err.into()))?;
-        let seqs: Vec<LabelledSequences<String>> = serde_json::from_str(&s)
+        let seqs: Vec<LabelledSequences<String>> = sequences::from_json_any_version(&s)
             .with_context(|| {
                 anyhow!(
                     "The file {} could not be deserialized into LabelledSequences",
@@ -122,6 +152,109 @@ fn pylib(_py: Python<'_>, m: &PyModule) -> PyResult<()> {
             .collect())
     }
 
+    /// sequence_path(id, root)
+    /// --
+    ///
+    /// Return the path of the file from which the sequence with `id` was loaded.
+    /// `root` is the folder passed to `load_folder` or the dataset directory of the misclassification log.
+    #[pyfn(m)]
+    #[pyo3(name = "sequence_path")]
+    fn sequence_path(id: String, root: String) -> String {
+        sequences::sequence_id_to_path(&id, Path::new(&root))
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    /// element_histogram(sequences)
+    /// --
+    ///
+    /// Count the sizes and the gaps over a list of sequences.
+    /// Returns a tuple of two dicts, mapping each size and each gap value to the number of occurrences.
+    #[pyfn(m)]
+    #[pyo3(name = "element_histogram")]
+    fn element_histogram(
+        sequences: Vec<PyRef<'_, PySequence>>,
+    ) -> (BTreeMap<u8, usize>, BTreeMap<u16, usize>) {
+        let histogram = sequences::element_histogram(sequences.iter().map(|seq| &seq.sequence));
+        (histogram.sizes, histogram.gaps)
+    }
+
+    /// distances(seqs_a, seqs_b, /, metric = "edit")
+    /// --
+    ///
+    /// Calculate the distances between all pairs of sequences from `seqs_a` and `seqs_b`.
+    /// Returns a numpy matrix of shape `(len(seqs_a), len(seqs_b))`.
+    /// `metric` is one of "edit", "dtw", "cumul", or "rle".
+    ///
+    /// The distances are calculated in parallel without holding the GIL.
+    #[pyfn(m)]
+    #[pyo3(name = "distances")]
+    fn distances<'py>(
+        py: Python<'py>,
+        seqs_a: Vec<PyRef<'_, PySequence>>,
+        seqs_b: Vec<PyRef<'_, PySequence>>,
+        metric: Option<String>,
+    ) -> PyResult<&'py PyArray2<u64>> {
+        let metric = parse_metric(metric)?;
+        let seqs_a = clone_sequences(&seqs_a);
+        let seqs_b = clone_sequences(&seqs_b);
+
+        let distances: Vec<u64> = py.allow_threads(|| {
+            seqs_a
+                .par_iter()
+                .flat_map_iter(|a| seqs_b.iter().map(move |b| metric.distance(a, b) as u64))
+                .collect()
+        });
+        PyArray::from_vec(py, distances).reshape([seqs_a.len(), seqs_b.len()])
+    }
+
+    /// knn_predict(training, test, k, /, metric = "edit", weighting = "uniform")
+    /// --
+    ///
+    /// Classify the `test` sequences with k-NN using the labelled `training` sequences.
+    /// `training` has the same format as the result of `load_folder`, a list of tuples of label and sequences.
+    /// Returns a list with a tuple of label and confidence for each test sequence.
+    /// The label is `None` if no label could be determined.
+    /// `weighting` is one of `uniform`, `inverse-distance`, or `rank` and determines how the neighbors vote.
+    ///
+    /// The classification runs in parallel without holding the GIL.
+    #[pyfn(m)]
+    #[pyo3(name = "knn_predict")]
+    fn knn_predict(
+        py: Python<'_>,
+        training: Vec<(String, Vec<PyRef<'_, PySequence>>)>,
+        test: Vec<PyRef<'_, PySequence>>,
+        k: u8,
+        metric: Option<String>,
+        weighting: Option<String>,
+    ) -> PyResult<Vec<(Option<String>, f64)>> {
+        if k == 0 {
+            return Err(error2py(anyhow!("k must be larger than 0")));
+        }
+        let metric = parse_metric(metric)?;
+        let weighting: VoteWeighting = weighting
+            .as_deref()
+            .unwrap_or("uniform")
+            .parse()
+            .map_err(error2py)?;
+        let training: Vec<LabelledSequences<String>> = training
+            .into_iter()
+            .map(|(label, seqs)| LabelledSequences {
+                true_domain: label.clone(),
+                mapped_domain: label,
+                sequences: clone_sequences(&seqs),
+            })
+            .collect();
+        let test = clone_sequences(&test);
+
+        let results =
+            py.allow_threads(|| knn_with_metric(&training, &test, k, metric, weighting, false));
+        Ok(results
+            .iter()
+            .map(|res| (res.top_label().map(ToString::to_string), res.confidence()))
+            .collect())
+    }
+
     Ok(())
 }
 
@@ -155,12 +288,28 @@ impl PySequence {
         &self,
         other: &PySequence,
     ) -> PyResult<(usize, BTreeMap<String, usize>)> {
-        let (cost, cost_info) =
-            self.sequence
-                .distance_with_limit::<CostTracker>(&other.sequence, false, false);
+        let (cost, cost_info) = self.sequence.distance_with_limit::<CostTracker>(
+            &other.sequence,
+            usize::max_value(),
+            false,
+            false,
+        );
         Ok((cost, cost_info.as_btreemap()))
     }
 
+    /// Calculate the distance between two sequences and the cumulative cost after each step of the alignment
+    ///
+    /// The last value of the profile is the distance.
+    pub fn distance_cost_profile(&self, other: &PySequence) -> PyResult<(usize, Vec<usize>)> {
+        let (cost, cost_info) = self.sequence.distance_with_limit::<CostTracker>(
+            &other.sequence,
+            usize::max_value(),
+            false,
+            false,
+        );
+        Ok((cost, cost_info.cumulative_position_costs()))
+    }
+
     /// Try to classify the sequence, if it belongs to one of a couple of common categories
     pub fn classify(&self) -> PyResult<Option<&'static str>> {
         Ok(self.sequence.classify())
@@ -186,6 +335,12 @@ impl PySequence {
         self.sequence.message_count()
     }
 
+    /// Returns the histograms of the sizes and of the gaps in this sequence
+    pub fn element_histogram(&self) -> (BTreeMap<u8, usize>, BTreeMap<u16, usize>) {
+        let histogram = self.sequence.element_histogram();
+        (histogram.sizes, histogram.gaps)
+    }
+
     /// Returns the complexity score of this sequence
     pub fn complexity(&self) -> usize {
         self.sequence.complexity()
@@ -0,0 +1,56 @@
+#!/usr/bin/env python3
+import sys
+from pathlib import Path
+
+import pandas as pd
+from matplotlib import pyplot as plt
+
+
+def help(pgrm: str) -> None:
+    print(
+        f"""Usage: ./{pgrm} STATISTICS_CSV
+
+    Plot the reliability diagram and the rejection curve written by `dns-sequence`.
+    The files `.calibration.csv` and `.rejection.csv` are read from next to the statistics file.
+    The plot is stored next to the statistics file with the extension `.calibration.svg`."""
+    )
+    sys.exit(1)
+
+
+def main() -> None:
+    if len(sys.argv) != 2:
+        help(sys.argv[0])
+
+    path = Path(sys.argv[1])
+    calibration = pd.read_csv(path.with_suffix(".calibration.csv"))
+    rejection = pd.read_csv(path.with_suffix(".rejection.csv"))
+
+    _fig, (ax_rel, ax_rej) = plt.subplots(1, 2, figsize=(12, 5))
+    ax_rel.plot([0, 1], [0, 1], color="black", linestyle=":", label="Perfect calibration")
+    for k, subset in calibration.groupby("k"):
+        subset = subset.dropna()
+        ax_rel.plot(
+            subset["mean_confidence"], subset["accuracy"], marker=".", label=f"k={k}"
+        )
+    ax_rel.set_xlabel("Confidence")
+    ax_rel.set_ylabel("Accuracy")
+    ax_rel.set_title("Reliability diagram")
+    ax_rel.legend()
+
+    for k, subset in rejection.groupby("k"):
+        ax_rej.plot(subset["threshold"], subset["accuracy"], label=f"Accuracy k={k}")
+        ax_rej.plot(
+            subset["threshold"],
+            subset["coverage"],
+            linestyle="--",
+            label=f"Coverage k={k}",
+        )
+    ax_rej.set_xlabel("Minimal confidence")
+    ax_rej.set_title("Rejection curve")
+    ax_rej.legend()
+
+    plt.savefig(path.with_suffix(".calibration.svg"), bbox_inches="tight")
+
+
+if __name__ == "__main__":
+    main()
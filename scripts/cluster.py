@@ -4,7 +4,7 @@ import sys
 import typing as t
 from glob import glob
 
-import pylib
+import padding_aint_enough as pylib
 import scipy.cluster.hierarchy as cluster
 from matplotlib import pyplot as plt
 from natsort import natsorted
@@ -0,0 +1,43 @@
+#!/usr/bin/env python3
+import sys
+from pathlib import Path
+
+import pandas as pd
+from matplotlib import pyplot as plt
+
+
+def help(pgrm: str) -> None:
+    print(
+        f"""Usage: ./{pgrm} CSV
+
+    Plot the information gain per message position written by the `information-gain` binary.
+    The plot is stored next to the CSV file with the extension `.svg`."""
+    )
+    sys.exit(1)
+
+
+def main() -> None:
+    if len(sys.argv) != 2:
+        help(sys.argv[0])
+
+    path = Path(sys.argv[1])
+    data = pd.read_csv(path)
+    label_entropy = data["label_entropy"].max()
+
+    _fig, ax = plt.subplots(figsize=(10, 5))
+    for feature in ["Size", "Gap"]:
+        subset = data[data["feature"] == feature]
+        ax.plot(
+            subset["position"], subset["mutual_information"], marker=".", label=feature
+        )
+    for count in data[data["feature"] == "Count"]["mutual_information"]:
+        ax.axhline(count, color="gray", linestyle="--", label="Count")
+    ax.axhline(label_entropy, color="black", linestyle=":", label="Label entropy")
+    ax.set_xlabel("Message position")
+    ax.set_ylabel("Mutual information (bits)")
+    ax.legend()
+    plt.savefig(path.with_suffix(".svg"), bbox_inches="tight")
+
+
+if __name__ == "__main__":
+    main()
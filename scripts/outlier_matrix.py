@@ -0,0 +1,68 @@
+#!/usr/bin/env python3
+import json
+import sys
+from pathlib import Path
+
+import numpy as np
+from matplotlib import pyplot as plt
+from matplotlib.patches import Rectangle
+
+
+def help(pgrm: str) -> None:
+    print(
+        f"""Usage: ./{pgrm} OUTLIER_JSON...
+
+    Plot the distance matrix of an outlier report written by the taskmanager sanity check.
+    Tasks whose median distance differs too much from the average are highlighted in red.
+    Each plot is stored next to the JSON file with the extension `.svg`."""
+    )
+    sys.exit(1)
+
+
+def main() -> None:
+    if len(sys.argv) < 2:
+        help(sys.argv[0])
+
+    for arg in sys.argv[1:]:
+        path = Path(arg)
+        with open(path) as f:
+            report = json.load(f)
+
+        distances = np.array(report["distances"])
+        labels = [
+            f"{task} (median {median})"
+            for task, median in zip(report["tasks"], report["median_distances"])
+        ]
+
+        fig, ax = plt.subplots(figsize=(8, 7))
+        image = ax.imshow(distances, cmap="viridis")
+        plt.colorbar(image, ax=ax).set_label("Distance")
+        ax.set_xticks(range(len(labels)))
+        ax.set_xticklabels(report["tasks"], rotation="vertical")
+        ax.set_yticks(range(len(labels)))
+        ax.set_yticklabels(labels)
+
+        # Frame the row and column of each outlier
+        for idx, is_outlier in enumerate(report["outliers"]):
+            if not is_outlier:
+                continue
+            ax.get_xticklabels()[idx].set_color("red")
+            ax.get_yticklabels()[idx].set_color("red")
+            for xy, width, height in [
+                ((-0.5, idx - 0.5), len(labels), 1),
+                ((idx - 0.5, -0.5), 1, len(labels)),
+            ]:
+                ax.add_patch(
+                    Rectangle(xy, width, height, fill=False, edgecolor="red", lw=2)
+                )
+
+        ax.set_title(
+            f"{report['website']} group {report['groupid']} ({report['vantage_point']})\n"
+            f"Average median distance {report['avg_median_distance']}: {report['reason']}"
+        )
+        plt.savefig(path.with_suffix(".svg"), bbox_inches="tight")
+        plt.close(fig)
+
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,102 @@
+#!/usr/bin/env python3
+import json
+import sys
+import typing as t
+from pathlib import Path
+
+import numpy as np
+from matplotlib import pyplot as plt
+
+
+def help(pgrm: str) -> None:
+    print(
+        f"""Usage: ./{pgrm} PLOT_JSON...
+
+    Render the plotting data written by `dns-sequence`.
+    Supported are the kinds `cdf`, `boxplot`, and `heatmap`.
+    Each plot is stored next to the JSON file with the extension `.svg`."""
+    )
+    sys.exit(1)
+
+
+def apply_axis(ax: t.Any, axis: t.Dict[str, t.Any], which: str) -> None:
+    if axis["label"] is not None:
+        getattr(ax, f"set_{which}label")(axis["label"])
+    if axis["log_scale"]:
+        # symlog also supports values of 0, which are common for distances
+        getattr(ax, f"set_{which}scale")("symlog")
+
+
+def colors(config: t.Dict[str, t.Any]) -> t.List[t.Optional[str]]:
+    return config["colors"] or [None]
+
+
+def plot_cdf(ax: t.Any, data: t.Any, config: t.Dict[str, t.Any]) -> None:
+    cols = colors(config)
+    for i, (label, values) in enumerate(data):
+        if not values:
+            continue
+        values = np.sort(values)
+        cdf = np.arange(1, len(values) + 1) / len(values)
+        ax.step(values, cdf, where="post", label=label, color=cols[i % len(cols)])
+    ax.legend()
+
+
+def plot_boxplot(ax: t.Any, data: t.Any, config: t.Dict[str, t.Any]) -> None:
+    cols = colors(config)
+    boxes = ax.boxplot(
+        [values for _, values in data],
+        labels=[label for label, _ in data],
+        patch_artist=True,
+    )
+    for i, box in enumerate(boxes["boxes"]):
+        color = cols[i % len(cols)]
+        if color is not None:
+            box.set_facecolor(color)
+
+
+def plot_heatmap(ax: t.Any, data: t.Any, config: t.Dict[str, t.Any]) -> None:
+    from matplotlib.colors import LogNorm
+
+    values = np.array(data["values"], dtype=float)
+    kwargs = dict()
+    if config["color_axis"]["log_scale"]:
+        # LogNorm cannot show 0, so mask the empty cells
+        values = np.ma.masked_less_equal(values, 0)
+        kwargs["norm"] = LogNorm()
+    image = ax.imshow(values, aspect="auto", **kwargs)
+    colorbar = plt.colorbar(image, ax=ax)
+    if config["color_axis"]["label"] is not None:
+        colorbar.set_label(config["color_axis"]["label"])
+    ax.set_xticks(range(len(data["column_labels"])))
+    ax.set_xticklabels(data["column_labels"], rotation="vertical")
+    ax.set_yticks(range(len(data["row_labels"])))
+    ax.set_yticklabels(data["row_labels"])
+
+
+PLOTS = {"cdf": plot_cdf, "boxplot": plot_boxplot, "heatmap": plot_heatmap}
+
+
+def main() -> None:
+    if len(sys.argv) < 2:
+        help(sys.argv[0])
+
+    for arg in sys.argv[1:]:
+        path = Path(arg)
+        with open(path) as f:
+            plot = json.load(f)
+        config = plot["config"]
+
+        fig, ax = plt.subplots(figsize=(12, 6))
+        PLOTS[plot["kind"]](ax, plot["data"], config)
+        apply_axis(ax, config["x_axis"], "x")
+        apply_axis(ax, config["y_axis"], "y")
+        if config["title"] is not None:
+            ax.set_title(config["title"])
+
+        plt.savefig(path.with_suffix(".svg"), bbox_inches="tight")
+        plt.close(fig)
+
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,547 @@
+//! State machine of the Adaptive Padding defense
+//!
+//! The state machine is independent of any clock.
+//! All times are given as offsets since the creation of the [`AdaptivePaddingCore`].
+//! This allows the same implementation to drive the live stream adapter in the proxy and the simulator for [`PrecisionSequence`](crate::PrecisionSequence).
+
+mod trace;
+
+pub use self::trace::{ApState, ApTraceEvent, ApTraceRecord, ApTraceWriter};
+use log::debug;
+use once_cell::sync::Lazy;
+use rand::{
+    distributions::{Distribution, Uniform, WeightedError, WeightedIndex},
+    Rng,
+};
+use rand_xorshift::XorShiftRng;
+use std::time::Duration;
+
+/// Random number generator for the [`AdaptivePaddingCore`]
+///
+/// The live stream adapter and the simulator use the same generator, such that equal seeds lead to equal schedules.
+pub type ApRng = XorShiftRng;
+
+/// Value representing the infinity bin of the histograms
+const DURATION_MAX: Duration = Duration::from_secs(3600 * 24 * 365);
+const DURATION_ONE_MS: Duration = Duration::from_millis(1);
+
+static DISTRIBUTION_BASE_VALUE: Lazy<f64> = Lazy::new(|| 2f64.sqrt());
+static DISTRIBUTION: Lazy<Vec<(Duration, u16)>> = Lazy::new(|| {
+    [
+        (0, 0),
+        (1, 0),
+        (2, 0),
+        (3, 0),
+        (4, 0),
+        (5, 0),
+        (6, 0),
+        (7, 0),
+        (8, 0),
+        (9, 0),
+        (10, 0),
+        (11, 11),
+        (12, 48),
+        (13, 41),
+        (14, 28),
+        (15, 15),
+        (16, 15),
+        (17, 14),
+        (18, 12),
+        (19, 11),
+        (20, 12),
+        (21, 15),
+        (22, 20),
+        (23, 23),
+        (24, 24),
+        (25, 36),
+        (26, 24),
+        (27, 22),
+        (28, 22),
+        (29, 116),
+        (30, 20),
+        (31, 23),
+        (32, 101),
+        (33, 104),
+        (34, 29),
+        (35, 41),
+        (36, 39),
+        (37, 43),
+        (38, 31),
+        (39, 25),
+        (40, 16),
+        (41, 10),
+        (42, 6),
+        (43, 2),
+        (44, 1),
+    ]
+    .iter()
+    .map(|&(gap, count)| {
+        (
+            Duration::from_micros(DISTRIBUTION_BASE_VALUE.powi(gap) as u64),
+            count,
+        )
+    })
+    .collect()
+});
+
+/// Smallest timeout which can be sampled in the Burst state
+pub fn min_inter_burst_gap() -> Duration {
+    DISTRIBUTION
+        .iter()
+        .map(|&(gap, _)| gap)
+        .filter(|&gap| gap >= DURATION_ONE_MS)
+        .min()
+        .expect("The distribution contains gaps larger than 1 ms")
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+enum State {
+    Idle,
+    Burst,
+    Gap,
+}
+
+impl From<State> for ApState {
+    fn from(state: State) -> Self {
+        match state {
+            State::Idle => ApState::Idle,
+            State::Burst => ApState::Burst,
+            State::Gap => ApState::Gap,
+        }
+    }
+}
+
+/// Clock independent state machine of Adaptive Padding
+///
+/// The state machine has the states Idle, Burst, and Gap.
+/// Each payload item switches into the Burst state.
+/// In the Burst and Gap states a timeout is sampled from a histogram of inter-arrival times.
+/// If the timeout expires before the next payload item, the caller must emit a dummy item and call [`AdaptivePaddingCore::handle_timeout`].
+/// Sampling the infinity bin falls back from Gap to Burst and from Burst to Idle.
+/// In the Idle state there is no deadline.
+///
+/// All times are offsets since the creation of the state machine.
+/// The caller is responsible for converting them to its clock.
+#[derive(Debug)]
+pub struct AdaptivePaddingCore<R> {
+    rng: R,
+    eipi: Duration,
+    last_created_item: Duration,
+    deadline: Option<Duration>,
+    /// Relevant for Gap mode
+    intra_burst_gaps: Vec<(Duration, u16)>,
+    /// Relevant for Burst mode
+    inter_burst_gaps: Vec<(Duration, u16)>,
+    state: State,
+    /// Median length of burst generated
+    median_burst_length: u32,
+    /// Probability of creating a fake burst
+    probability_fake_burst: f64,
+    trace: Option<ApTraceWriter>,
+}
+
+impl<R> AdaptivePaddingCore<R>
+where
+    R: Rng,
+{
+    /// Create a new state machine in the Idle state
+    ///
+    /// # Panics
+    ///
+    /// The function panics, if `median_burst_length` is smaller than 2.
+    pub fn new(rng: R, median_burst_length: u32, probability_fake_burst: f64) -> Self {
+        assert!(
+            median_burst_length >= 2,
+            "The median burst length must be at least 2"
+        );
+        let mut res = Self {
+            rng,
+            eipi: DURATION_MAX,
+            last_created_item: Duration::default(),
+            deadline: None,
+            intra_burst_gaps: Vec::default(),
+            inter_burst_gaps: Vec::default(),
+            state: State::Idle,
+            median_burst_length,
+            probability_fake_burst,
+            trace: None,
+        };
+        res.refill_inter_distribution();
+        res.refill_intra_distribution();
+        res
+    }
+
+    /// Record all state transitions into `trace`
+    pub fn set_trace(&mut self, trace: Option<ApTraceWriter>) {
+        self.trace = trace;
+    }
+
+    /// Offset at which the next dummy item is due, `None` while Idle
+    pub fn deadline(&self) -> Option<Duration> {
+        self.deadline
+    }
+
+    /// Returns `true` if the state machine is Idle and will not create dummy items until the next payload
+    pub fn is_idle(&self) -> bool {
+        self.state == State::Idle
+    }
+
+    fn record(&self, now: Duration, event: ApTraceEvent) {
+        if let Some(trace) = &self.trace {
+            trace.record(now.as_secs_f64(), event);
+        }
+    }
+
+    /// Change into `state` and record the transition
+    fn set_state(&mut self, now: Duration, state: State) {
+        if self.state != state {
+            self.record(
+                now,
+                ApTraceEvent::StateChange {
+                    from: self.state.into(),
+                    to: state.into(),
+                },
+            );
+            self.state = state;
+        }
+    }
+
+    /// Return the distribution for the current state
+    ///
+    /// # Panics
+    ///
+    /// The function panics, if `self.state == `[`State::Idle`], as there is no distribution for idle.
+    fn current_distribution(&mut self) -> &mut Vec<(Duration, u16)> {
+        match self.state {
+            State::Burst => &mut self.inter_burst_gaps,
+            State::Gap => &mut self.intra_burst_gaps,
+            State::Idle => {
+                panic!("There is no distribution in state idle, as there is no token sampled.");
+            }
+        }
+    }
+
+    /// Sample a token from one of the distributions
+    ///
+    /// The correct distribution is determined using `self.state`.
+    /// Returns `None` if the infinity bin caused a fallback to Idle.
+    ///
+    /// # Panics
+    ///
+    /// The function panics, if `self.state == `[`State::Idle`], as there is no distribution for idle.
+    fn sample_token(&mut self, now: Duration) -> Option<Duration> {
+        // Build a distribution based on the counts in self.distribution
+        let dist = match WeightedIndex::new(self.current_distribution().iter().map(|item| item.1)) {
+            Ok(dist) => dist,
+            Err(WeightedError::NoItem) | Err(WeightedError::AllWeightsZero) => {
+                self.refill_current_distribution(now);
+                WeightedIndex::new(self.current_distribution().iter().map(|item| item.1)).unwrap()
+            }
+            Err(WeightedError::InvalidWeight) => {
+                panic!("Negative weights are impossible due to the type being u16")
+            }
+            Err(WeightedError::TooMany) => panic!("We never have more than `u32::MAX` buckets"),
+        };
+        // Get the index of the value
+        let idx = dist.sample(&mut self.rng);
+        // Retrieve the matching element from the distribution
+        let &mut (duration, ref mut count) = &mut self.current_distribution()[idx];
+        *count -= 1;
+
+        if duration == DURATION_MAX {
+            debug!("Sampled infinity token");
+            self.record(
+                now,
+                ApTraceEvent::SampledTimeout {
+                    state: self.state.into(),
+                    timeout: None,
+                },
+            );
+            return match self.state {
+                State::Idle => unreachable!("We do not sample tokens in this state"),
+                State::Burst => {
+                    debug!("Infinity Token: Fallback to Idle");
+                    self.set_state(now, State::Idle);
+                    None
+                }
+                State::Gap => {
+                    debug!("Infinity Token: Fallback to Burst");
+                    self.set_state(now, State::Burst);
+                    self.sample_token(now)
+                }
+            };
+        }
+        // Now that we have a base duration, we need to pick a duration uniformly between this bucket and the next bucket
+        let uniform = Uniform::new(duration, duration.mul_f64(*DISTRIBUTION_BASE_VALUE));
+        let duration = uniform.sample(&mut self.rng);
+
+        debug!("Sampled {:?} token", duration);
+        self.record(
+            now,
+            ApTraceEvent::SampledTimeout {
+                state: self.state.into(),
+                timeout: Some(duration.as_secs_f64()),
+            },
+        );
+        Some(duration)
+    }
+
+    /// Refill the distribution needed for Burst mode
+    fn refill_inter_distribution(&mut self) {
+        if self.inter_burst_gaps.is_empty() {
+            // Fill in the normal distribution
+            self.inter_burst_gaps.extend(
+                DISTRIBUTION
+                    .iter()
+                    .filter(|(gap, _)| *gap >= DURATION_ONE_MS)
+                    .cloned(),
+            );
+            self.inter_burst_gaps.push((DURATION_MAX, 0));
+            // Maybe safe a bit of space
+            self.inter_burst_gaps.shrink_to_fit();
+        } else {
+            self.inter_burst_gaps
+                .iter_mut()
+                .zip(
+                    DISTRIBUTION
+                        .iter()
+                        .filter(|(gap, _)| *gap >= DURATION_ONE_MS)
+                        .cloned(),
+                )
+                .for_each(|((_, old_count), (_, new_count))| *old_count += new_count);
+        }
+
+        // Fix the infinity bins
+        // INTER
+        // kn = Pn / (1 − Pn) * K
+        // Pn = 1 - propability_fake_burst
+        // Pn propability to choose bucket n
+        // K: tokens for all other buckets
+        let sum_tokens: u32 = self
+            .inter_burst_gaps
+            .iter()
+            .filter(|(gap, _)| *gap != DURATION_MAX)
+            .map(|(_, count)| u32::from(*count))
+            .sum();
+        let kn = ((1. - self.probability_fake_burst) / self.probability_fake_burst
+            * f64::from(sum_tokens))
+        .round() as u16;
+        let len = self.inter_burst_gaps.len();
+        self.inter_burst_gaps[len - 1].1 = kn;
+    }
+
+    /// Refill the distribution needed for Gap mode
+    fn refill_intra_distribution(&mut self) {
+        if self.intra_burst_gaps.is_empty() {
+            // Fill in the normal distribution
+            self.intra_burst_gaps.extend(
+                DISTRIBUTION
+                    .iter()
+                    .filter(|(gap, _)| *gap < DURATION_ONE_MS)
+                    .cloned(),
+            );
+            self.intra_burst_gaps.push((DURATION_MAX, 0));
+            // Maybe safe a bit of space
+            self.intra_burst_gaps.shrink_to_fit();
+        } else {
+            self.intra_burst_gaps
+                .iter_mut()
+                .zip(
+                    DISTRIBUTION
+                        .iter()
+                        .filter(|(gap, _)| *gap < DURATION_ONE_MS)
+                        .cloned(),
+                )
+                .for_each(|((_, old_count), (_, new_count))| *old_count += new_count);
+        }
+
+        // Fix the infinity bins
+        // INTRA
+        // kn = (K + µL + 1) / (µL - 1)
+        // K: tokens for all other buckets
+        // µL: Median burst length
+        let sum_tokens: u32 = self
+            .intra_burst_gaps
+            .iter()
+            .filter(|(gap, _)| *gap != DURATION_MAX)
+            .map(|(_, count)| u32::from(*count))
+            .sum();
+        let kn = (f64::from(sum_tokens + self.median_burst_length + 1)
+            / f64::from(self.median_burst_length - 1))
+        .round() as u16;
+        let len = self.intra_burst_gaps.len();
+        self.intra_burst_gaps[len - 1].1 = kn;
+    }
+
+    /// Increase the token count for the token bucket matching `duration`
+    ///
+    /// # Panics
+    ///
+    /// The function panics, if `self.state == `[`State::Idle`], as there is no distribution for idle.
+    fn put_back_token(&mut self, duration: Duration) {
+        // Put token back into bucket
+        if let Some((_gap, count)) = self
+            .current_distribution()
+            .iter_mut()
+            .find(|(gap, _count)| (2 * *gap) > duration)
+        {
+            *count += 1;
+        }
+    }
+
+    /// Refill the distribtion for the current state
+    ///
+    /// # Panics
+    ///
+    /// The function panics, if `self.state == `[`State::Idle`], as there is no distribution for idle.
+    fn refill_current_distribution(&mut self, now: Duration) {
+        match self.state {
+            State::Burst => self.refill_inter_distribution(),
+            State::Gap => self.refill_intra_distribution(),
+            State::Idle => {
+                panic!("Cannot refill since there is no associated distribution");
+            }
+        }
+        let tokens = self
+            .current_distribution()
+            .iter()
+            .map(|(_, count)| u32::from(*count))
+            .sum();
+        self.record(
+            now,
+            ApTraceEvent::Refill {
+                state: self.state.into(),
+                tokens,
+            },
+        );
+    }
+
+    /// Remove a token from the current distribution with the bucket matching `duration`
+    ///
+    /// # Panics
+    ///
+    /// The function panics, if `self.state == `[`State::Idle`], as there is no distribution for idle.
+    fn remove_token(&mut self, now: Duration, duration: Duration) {
+        // Find next bucket larger with count larger zero and remove token
+        if let Some((_gap, count)) = self
+            .current_distribution()
+            .iter_mut()
+            .find(|(gap, count)| *gap >= duration && *count > 0)
+        {
+            *count -= 1;
+        } else {
+            self.refill_current_distribution(now);
+            if let Some((_duration, count)) = self
+                .current_distribution()
+                .iter_mut()
+                .find(|(gap, count)| *gap >= duration && *count > 0)
+            {
+                *count -= 1;
+            }
+        }
+    }
+
+    /// Set the new deadline to `now + duration` or disable it
+    fn set_deadline(&mut self, now: Duration, duration: Option<Duration>) {
+        self.eipi = duration.unwrap_or(DURATION_MAX);
+        self.deadline = duration.map(|duration| now + duration);
+
+        debug!(
+            "New Deadline {:?}, Duration {:?}, State {:?}",
+            self.deadline, duration, self.state
+        );
+    }
+
+    /// Callback if a payload item is sent at offset `now`
+    ///
+    /// Returns the new deadline.
+    pub fn handle_payload(&mut self, now: Duration) -> Option<Duration> {
+        self.record(
+            now,
+            ApTraceEvent::Payload {
+                state: self.state.into(),
+            },
+        );
+        if self.state != State::Idle {
+            self.put_back_token(self.eipi);
+            // Calculate real duration
+            let dur = now.checked_sub(self.last_created_item).unwrap_or_default();
+            debug!("Real duration is {:?}", dur);
+            self.remove_token(now, dur);
+        }
+        self.set_state(now, State::Burst);
+        let duration = self.sample_token(now);
+        self.set_deadline(now, duration);
+        self.last_created_item = now;
+        self.deadline
+    }
+
+    /// Callback if the deadline expired and a dummy item is sent at offset `now`
+    ///
+    /// Returns the new deadline.
+    ///
+    /// # Panics
+    ///
+    /// The function panics, if the state machine is Idle, since there is no deadline in this state.
+    pub fn handle_timeout(&mut self, now: Duration) -> Option<Duration> {
+        self.record(
+            now,
+            ApTraceEvent::Timeout {
+                state: self.state.into(),
+            },
+        );
+        match self.state {
+            State::Idle => unreachable!("We never choose a timeout in idle state"),
+            State::Burst => self.set_state(now, State::Gap),
+            State::Gap => {}
+        }
+        // Sample a new timeout fitting for the new state
+        let duration = self.sample_token(now);
+        self.set_deadline(now, duration);
+        self.last_created_item = now;
+        self.deadline
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::SeedableRng;
+    use rand_xorshift::XorShiftRng;
+
+    #[test]
+    fn test_core_returns_to_idle() {
+        let rng = XorShiftRng::seed_from_u64(0);
+        let mut ap = AdaptivePaddingCore::new(rng, 2, 0.9);
+        assert!(ap.is_idle());
+        assert_eq!(ap.deadline(), None);
+
+        let mut deadline = ap.handle_payload(Duration::default());
+        assert!(!ap.is_idle());
+        let mut timeouts = 0;
+        while let Some(now) = deadline {
+            deadline = ap.handle_timeout(now);
+            timeouts += 1;
+            assert!(timeouts < 100_000, "Adaptive Padding never became idle");
+        }
+        assert!(ap.is_idle());
+    }
+
+    #[test]
+    fn test_core_is_deterministic() {
+        let run = || {
+            let mut ap = AdaptivePaddingCore::new(XorShiftRng::seed_from_u64(42), 3, 0.8);
+            let mut deadlines = vec![];
+            for payload in &[0, 1, 5, 200, 1000] {
+                let now = Duration::from_millis(*payload);
+                // Process all timeouts before the payload
+                while let Some(deadline) = ap.deadline().filter(|&deadline| deadline < now) {
+                    deadlines.push(ap.handle_timeout(deadline));
+                }
+                deadlines.push(ap.handle_payload(now));
+            }
+            deadlines
+        };
+        assert_eq!(run(), run());
+    }
+}
@@ -0,0 +1,176 @@
+//! Export the internal state of Adaptive Padding as JSONL
+//!
+//! The [`AdaptivePaddingCore`](super::AdaptivePaddingCore) is driven by the live stream adapter in the proxy and by the simulator for [`PrecisionSequence`](crate::PrecisionSequence).
+//! Both write their state transitions into an [`ApTraceWriter`] using the same format.
+//! Comparing the traces shows where the timing of the live implementation and the simulator diverge.
+
+use anyhow::Error;
+use log::error;
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+/// Mode of the Adaptive Padding state machine
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ApState {
+    Idle,
+    Burst,
+    Gap,
+}
+
+/// A single state transition of Adaptive Padding
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum ApTraceEvent {
+    /// A real payload item was forwarded
+    Payload { state: ApState },
+    /// The timeout expired and a dummy item was created
+    Timeout { state: ApState },
+    /// The mode changed from `from` to `to`
+    StateChange { from: ApState, to: ApState },
+    /// A timeout of `timeout` seconds was sampled from the histogram of `state`
+    ///
+    /// The timeout is `None` if the infinity bin was sampled, which causes a fallback into the next lower mode.
+    SampledTimeout {
+        state: ApState,
+        timeout: Option<f64>,
+    },
+    /// The histogram of `state` ran out of tokens and was refilled to `tokens` tokens
+    Refill { state: ApState, tokens: u32 },
+}
+
+/// One line of the JSONL trace
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct ApTraceRecord {
+    /// Identifies the sequence or connection, if multiple are written into the same trace
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    /// Seconds since the Adaptive Padding instance was created
+    pub time: f64,
+    #[serde(flatten)]
+    pub event: ApTraceEvent,
+}
+
+/// Shared sink for [`ApTraceRecord`]s
+///
+/// Cloning the writer is cheap and all clones write into the same output.
+/// Write errors are logged once and disable the trace, since the trace is only a debugging aid.
+#[derive(Clone)]
+pub struct ApTraceWriter {
+    out: Arc<Mutex<Option<Box<dyn Write + Send>>>>,
+    id: Option<String>,
+}
+
+impl ApTraceWriter {
+    /// Write the trace into `out`
+    pub fn new<W>(out: W) -> Self
+    where
+        W: Write + Send + 'static,
+    {
+        Self {
+            out: Arc::new(Mutex::new(Some(Box::new(out)))),
+            id: None,
+        }
+    }
+
+    /// Create or truncate the file at `path` and write the trace into it
+    ///
+    /// Returns an [`io::Error`], such that callers with their own error types can use it directly.
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let file = File::create(path).map_err(|err| {
+            io::Error::new(
+                err.kind(),
+                format!("Failed to create AP trace file {}: {}", path.display(), err),
+            )
+        })?;
+        Ok(Self::new(BufWriter::new(file)))
+    }
+
+    /// Return a writer into the same output, which tags all records with `id`
+    pub fn with_id(&self, id: impl Into<String>) -> Self {
+        Self {
+            out: self.out.clone(),
+            id: Some(id.into()),
+        }
+    }
+
+    /// Append a record for `event` happening `time` seconds after the start
+    pub fn record(&self, time: f64, event: ApTraceEvent) {
+        let record = ApTraceRecord {
+            id: self.id.clone(),
+            time,
+            event,
+        };
+        let mut out = self.out.lock().unwrap();
+        if let Some(wtr) = &mut *out {
+            let res = serde_json::to_writer(&mut *wtr, &record)
+                .map_err(Error::from)
+                .and_then(|()| {
+                    wtr.write_all(b"\n")?;
+                    wtr.flush()?;
+                    Ok(())
+                });
+            if let Err(err) = res {
+                error!("Could not write the AP trace, disabling it: {}", err);
+                *out = None;
+            }
+        }
+    }
+}
+
+impl fmt::Debug for ApTraceWriter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ApTraceWriter")
+            .field("id", &self.id)
+            .finish()
+    }
+}
+
+#[test]
+fn test_ap_trace_jsonl_format() {
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let buf = SharedBuf::default();
+    let trace = ApTraceWriter::new(buf.clone());
+    trace.record(
+        0.,
+        ApTraceEvent::StateChange {
+            from: ApState::Idle,
+            to: ApState::Burst,
+        },
+    );
+    trace.with_id("example").record(
+        0.5,
+        ApTraceEvent::SampledTimeout {
+            state: ApState::Burst,
+            timeout: Some(0.25),
+        },
+    );
+
+    let output = String::from_utf8(buf.0.lock().unwrap().clone()).unwrap();
+    let lines: Vec<_> = output.lines().collect();
+    assert_eq!(
+        lines,
+        vec![
+            r#"{"time":0.0,"event":"state_change","from":"idle","to":"burst"}"#,
+            r#"{"id":"example","time":0.5,"event":"sampled_timeout","state":"burst","timeout":0.25}"#,
+        ]
+    );
+    let record: ApTraceRecord = serde_json::from_str(lines[1]).unwrap();
+    assert_eq!(record.id.as_deref(), Some("example"));
+}
@@ -0,0 +1,409 @@
+//! Transformations of [`Sequence`]s, which simulate measurement noise or a different cache state
+//!
+//! Applying them to the trainings or test data quantifies how robust the classification is against small changes of the traces.
+//! [`Augmentation::ExpiredCache`] replays a trace as if some answers had expired from the cache, which allows a sensitivity analysis without new crawls.
+//! The randomness is seeded with the identifier of each [`Sequence`], such that augmenting the same data twice yields the same result.
+
+use crate::{knn::LabelledSequences, Sequence, SequenceElement};
+use anyhow::{bail, Context as _, Error};
+use fnv::FnvHasher;
+use ordered_float::NotNan;
+use rand::{Rng, SeedableRng};
+use rand_xorshift::XorShiftRng;
+use serde_with::{DeserializeFromStr, SerializeDisplay};
+use std::{
+    fmt::{self, Display},
+    hash::{Hash, Hasher},
+    str::FromStr,
+};
+
+/// A single transformation of a [`Sequence`]
+///
+/// The string form is `<name>:<parameter>`, e.g., `drop:0.1` or `truncate:20`.
+#[derive(
+    Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, SerializeDisplay, DeserializeFromStr,
+)]
+pub enum Augmentation {
+    /// Drop each message, i.e., each [`SequenceElement::Size`], with the given probability
+    ///
+    /// The gaps around a dropped message are merged into the larger one.
+    /// At least one message is always kept.
+    Drop(NotNan<f64>),
+    /// Change each [`SequenceElement::Gap`] by a uniformly random value between `-n` and `n`
+    GapJitter(u16),
+    /// Change each [`SequenceElement::Size`] by one padding block up or down with the given probability
+    SizeShift(NotNan<f64>),
+    /// Only keep the first `n` elements
+    Truncate(usize),
+    /// Replay the [`Sequence`] as if some answers had expired from the cache
+    ///
+    /// Each expired answer has to be fetched again, which adds an extra query/response pair of the same size before the message.
+    /// The gap between both is drawn from the gaps between the messages of the [`Sequence`], such that it fits the latency of the measurement.
+    /// The [`TtlModel`] determines which fraction of answers is expired.
+    ExpiredCache(TtlModel),
+}
+
+/// Model of the TTLs in the cache, which determines the probability that an answer is expired
+///
+/// The string form is either `fixed:<p>` or `exponential:<mean TTL>:<cache age>` with both durations in seconds.
+#[derive(
+    Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, SerializeDisplay, DeserializeFromStr,
+)]
+pub enum TtlModel {
+    /// Each answer is expired with the given probability
+    Fixed(NotNan<f64>),
+    /// The TTLs are exponentially distributed and all entries were cached `cache_age` seconds ago
+    ///
+    /// An answer is expired, if its TTL is shorter than the age of the cache, which happens with probability `1 - exp(-cache_age / mean_ttl)`.
+    Exponential {
+        mean_ttl: NotNan<f64>,
+        cache_age: NotNan<f64>,
+    },
+}
+
+impl TtlModel {
+    /// Probability that a single answer is expired
+    pub fn expiry_probability(self) -> f64 {
+        match self {
+            Self::Fixed(probability) => probability.into_inner(),
+            Self::Exponential {
+                mean_ttl,
+                cache_age,
+            } => 1. - (-cache_age.into_inner() / mean_ttl.into_inner()).exp(),
+        }
+    }
+}
+
+impl Display for TtlModel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Fixed(probability) => write!(f, "fixed:{}", probability),
+            Self::Exponential {
+                mean_ttl,
+                cache_age,
+            } => write!(f, "exponential:{}:{}", mean_ttl, cache_age),
+        }
+    }
+}
+
+impl FromStr for TtlModel {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split(':').collect();
+        match &*parts {
+            ["fixed", probability] => Ok(Self::Fixed(parse_probability(probability)?)),
+            ["exponential", mean_ttl, cache_age] => {
+                let parse_seconds = |value: &str| -> Result<NotNan<f64>, Error> {
+                    let seconds: f64 = value
+                        .parse()
+                        .with_context(|| format!("Invalid duration in TTL model `{}`", s))?;
+                    if !seconds.is_finite() || seconds < 0. {
+                        bail!("The durations in TTL model `{}` must not be negative", s);
+                    }
+                    Ok(NotNan::new(seconds)?)
+                };
+                let mean_ttl = parse_seconds(mean_ttl)?;
+                if mean_ttl.into_inner() == 0. {
+                    bail!("The mean TTL in TTL model `{}` must be positive", s);
+                }
+                Ok(Self::Exponential {
+                    mean_ttl,
+                    cache_age: parse_seconds(cache_age)?,
+                })
+            }
+            _ => bail!(
+                "Unknown TTL model `{}`. Supported are `fixed:<p>` and `exponential:<mean TTL>:<cache age>`.",
+                s
+            ),
+        }
+    }
+}
+
+/// Parse a probability between 0 and 1
+fn parse_probability(s: &str) -> Result<NotNan<f64>, Error> {
+    let probability: f64 = s
+        .parse()
+        .with_context(|| format!("Invalid probability `{}`", s))?;
+    if !(0. ..=1.).contains(&probability) {
+        bail!("The probability `{}` must be between 0 and 1", s);
+    }
+    Ok(NotNan::new(probability)?)
+}
+
+impl Augmentation {
+    /// Apply the transformation to the elements of a [`Sequence`]
+    fn apply(self, elements: &mut Vec<SequenceElement>, rng: &mut impl Rng) {
+        match self {
+            Self::Drop(probability) => {
+                let probability = probability.into_inner();
+                let mut remaining_messages = elements
+                    .iter()
+                    .filter(|elem| matches!(elem, SequenceElement::Size(_)))
+                    .count();
+                let mut kept_messages = 0;
+                let mut dropped = Vec::with_capacity(elements.len());
+                for &elem in elements.iter() {
+                    if let SequenceElement::Size(_) = elem {
+                        remaining_messages -= 1;
+                        // Never drop the last message, if all others are already dropped
+                        let must_keep = kept_messages == 0 && remaining_messages == 0;
+                        if !must_keep && rng.gen_bool(probability) {
+                            continue;
+                        }
+                        kept_messages += 1;
+                    }
+                    dropped.push(elem);
+                }
+                *elements = merge_gaps(dropped);
+            }
+            Self::GapJitter(n) => {
+                for elem in elements.iter_mut() {
+                    if let SequenceElement::Gap(gap) = elem {
+                        let jitter = rng.gen_range(-i32::from(n)..=i32::from(n));
+                        *gap = (i32::from(*gap) + jitter).clamp(0, i32::from(u16::MAX)) as u16;
+                    }
+                }
+            }
+            Self::SizeShift(probability) => {
+                let probability = probability.into_inner();
+                for elem in elements.iter_mut() {
+                    if let SequenceElement::Size(size) = elem {
+                        if rng.gen_bool(probability) {
+                            *size = if *size == 1 || (*size < u8::MAX && rng.gen()) {
+                                *size + 1
+                            } else {
+                                *size - 1
+                            };
+                        }
+                    }
+                }
+            }
+            Self::Truncate(n) => {
+                elements.truncate(n);
+                // A trailing gap does not belong to any message
+                if let Some(SequenceElement::Gap(_)) = elements.last() {
+                    elements.pop();
+                }
+            }
+            Self::ExpiredCache(ttl_model) => {
+                let probability = ttl_model.expiry_probability();
+                // The gap before each message except the first, `None` if the messages are back to back
+                let gaps: Vec<Option<SequenceElement>> = elements
+                    .windows(2)
+                    .filter_map(|window| match window {
+                        [SequenceElement::Size(_), SequenceElement::Size(_)] => Some(None),
+                        [SequenceElement::Gap(_), SequenceElement::Size(_)] => {
+                            Some(Some(window[0]))
+                        }
+                        _ => None,
+                    })
+                    .collect();
+                let mut replayed = Vec::with_capacity(elements.len());
+                for &elem in elements.iter() {
+                    if let SequenceElement::Size(_) = elem {
+                        if rng.gen_bool(probability) {
+                            replayed.push(elem);
+                            if !gaps.is_empty() {
+                                replayed.extend(gaps[rng.gen_range(0..gaps.len())]);
+                            }
+                        }
+                    }
+                    replayed.push(elem);
+                }
+                *elements = replayed;
+            }
+        }
+    }
+}
+
+/// Merge consecutive gaps into the larger one and remove leading and trailing gaps
+fn merge_gaps(elements: Vec<SequenceElement>) -> Vec<SequenceElement> {
+    let mut res: Vec<SequenceElement> = Vec::with_capacity(elements.len());
+    for elem in elements {
+        match (res.last_mut(), elem) {
+            (None, SequenceElement::Gap(_)) => {}
+            (Some(SequenceElement::Gap(last)), SequenceElement::Gap(gap)) => {
+                *last = (*last).max(gap);
+            }
+            _ => res.push(elem),
+        }
+    }
+    if let Some(SequenceElement::Gap(_)) = res.last() {
+        res.pop();
+    }
+    res
+}
+
+impl Display for Augmentation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Drop(probability) => write!(f, "drop:{}", probability),
+            Self::GapJitter(n) => write!(f, "gap-jitter:{}", n),
+            Self::SizeShift(probability) => write!(f, "size-shift:{}", probability),
+            Self::Truncate(n) => write!(f, "truncate:{}", n),
+            Self::ExpiredCache(ttl_model) => write!(f, "expired-cache:{}", ttl_model),
+        }
+    }
+}
+
+impl FromStr for Augmentation {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, param) = s.split_once(':').with_context(|| {
+            format!(
+                "Augmentation `{}` has no parameter, use the form `<name>:<parameter>`",
+                s
+            )
+        })?;
+        let probability =
+            || parse_probability(param).with_context(|| format!("Invalid augmentation `{}`", s));
+        match &*name.to_ascii_lowercase() {
+            "drop" => Ok(Self::Drop(probability()?)),
+            "gap-jitter" => Ok(Self::GapJitter(
+                param
+                    .parse()
+                    .with_context(|| format!("Invalid gap jitter in `{}`", s))?,
+            )),
+            "size-shift" => Ok(Self::SizeShift(probability()?)),
+            "truncate" => Ok(Self::Truncate(
+                param
+                    .parse()
+                    .with_context(|| format!("Invalid length in `{}`", s))?,
+            )),
+            "expired-cache" => Ok(Self::ExpiredCache(
+                param
+                    .parse()
+                    .with_context(|| format!("Invalid augmentation `{}`", s))?,
+            )),
+            _ => bail!(
+                "Unknown augmentation `{}`. Supported are `drop`, `gap-jitter`, `size-shift`, `truncate`, and `expired-cache`.",
+                s
+            ),
+        }
+    }
+}
+
+/// Apply all `augmentations` in order to the [`Sequence`]
+///
+/// The identifier and metadata of the [`Sequence`] are kept.
+pub fn augment(sequence: &Sequence, augmentations: &[Augmentation]) -> Sequence {
+    if augmentations.is_empty() {
+        return sequence.clone();
+    }
+
+    // Setup a predictable RNG for each sequence
+    let mut rng = {
+        let mut hasher = FnvHasher::with_key(0);
+        sequence.id().hash(&mut hasher);
+        XorShiftRng::seed_from_u64(hasher.finish())
+    };
+
+    let mut elements = sequence.as_elements().to_vec();
+    for augmentation in augmentations {
+        augmentation.apply(&mut elements, &mut rng);
+    }
+    let res = Sequence::new(elements, sequence.id().to_string());
+    match sequence.metadata() {
+        Some(metadata) => res.with_metadata(metadata.clone()),
+        None => res,
+    }
+}
+
+/// Apply all `augmentations` to each [`Sequence`] of the `data`
+pub fn augment_data<S>(data: &mut [LabelledSequences<S>], augmentations: &[Augmentation]) {
+    if augmentations.is_empty() {
+        return;
+    }
+    for lseqs in data {
+        for seq in &mut lseqs.sequences {
+            *seq = augment(seq, augmentations);
+        }
+    }
+}
+
+#[test]
+fn test_augmentation_roundtrip() {
+    for augmentation in &[
+        Augmentation::Drop(NotNan::new(0.1).unwrap()),
+        Augmentation::GapJitter(2),
+        Augmentation::SizeShift(NotNan::new(0.5).unwrap()),
+        Augmentation::Truncate(20),
+        Augmentation::ExpiredCache(TtlModel::Fixed(NotNan::new(0.25).unwrap())),
+        Augmentation::ExpiredCache(TtlModel::Exponential {
+            mean_ttl: NotNan::new(300.).unwrap(),
+            cache_age: NotNan::new(60.).unwrap(),
+        }),
+    ] {
+        assert_eq!(
+            *augmentation,
+            augmentation.to_string().parse::<Augmentation>().unwrap()
+        );
+    }
+    assert!("drop:1.5".parse::<Augmentation>().is_err());
+    assert!("truncate".parse::<Augmentation>().is_err());
+    assert!("shuffle:1".parse::<Augmentation>().is_err());
+    assert!("expired-cache:exponential:0:60"
+        .parse::<Augmentation>()
+        .is_err());
+}
+
+#[test]
+fn test_augment() {
+    use SequenceElement::{Gap, Size};
+
+    let seq = Sequence::new(
+        vec![Size(1), Gap(4), Size(2), Gap(7), Size(1), Gap(2), Size(3)],
+        "example.com/example.com-0-0.dnstap.xz".into(),
+    );
+
+    // Dropping everything keeps a single message
+    let dropped = augment(&seq, &[Augmentation::Drop(NotNan::new(1.).unwrap())]);
+    assert_eq!(&[Size(3)], dropped.as_elements());
+    assert_eq!(seq.id(), dropped.id());
+
+    let truncated = augment(&seq, &[Augmentation::Truncate(4)]);
+    assert_eq!(&[Size(1), Gap(4), Size(2)], truncated.as_elements());
+
+    // The augmentation is deterministic
+    let augmentations = [
+        Augmentation::GapJitter(3),
+        Augmentation::SizeShift(NotNan::new(0.5).unwrap()),
+    ];
+    let augmented = augment(&seq, &augmentations);
+    assert_eq!(augmented, augment(&seq, &augmentations));
+    assert_eq!(seq.len(), augmented.len());
+    assert!(augmented.as_elements().iter().all(|elem| match elem {
+        Size(size) => *size >= 1,
+        Gap(_) => true,
+    }));
+
+    assert_eq!(
+        vec![Size(1), Gap(7), Size(3)],
+        merge_gaps(vec![Gap(1), Size(1), Gap(4), Gap(7), Size(3), Gap(2)])
+    );
+
+    // Every message is expired and fetched twice
+    let expired = augment(
+        &seq,
+        &[Augmentation::ExpiredCache(TtlModel::Fixed(
+            NotNan::new(1.).unwrap(),
+        ))],
+    );
+    assert_eq!(seq.message_count() * 2, expired.message_count());
+    assert_eq!(Some(&Size(1)), expired.as_elements().first());
+    assert_eq!(Some(&Size(3)), expired.as_elements().last());
+}
+
+#[test]
+fn test_ttl_model() {
+    let fixed: TtlModel = "fixed:0.3".parse().unwrap();
+    assert!((fixed.expiry_probability() - 0.3).abs() < 1e-9);
+    let exponential: TtlModel = "exponential:300:0".parse().unwrap();
+    assert!(exponential.expiry_probability().abs() < 1e-9);
+    let exponential: TtlModel = "exponential:300:300".parse().unwrap();
+    assert!((exponential.expiry_probability() - (1. - (-1_f64).exp())).abs() < 1e-9);
+    assert!("fixed".parse::<TtlModel>().is_err());
+    assert!("exponential:300".parse::<TtlModel>().is_err());
+}
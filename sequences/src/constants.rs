@@ -55,6 +55,12 @@ pub mod common_sequence_classifications {
     pub const R008: &str =
         "R008 Domain did not load properly and Chrome performed a Google search on the error page.";
     pub const R009: &str = "R009 No network response received.";
+    pub const R010: &str = "R010 Server error (HTTP 5xx) while loading the main document.";
+    pub const R011: &str = "R011 DNS resolution failed temporarily, e.g., due to a SERVFAIL.";
+    pub const R012: &str = "R012 Domain does not exist.";
+    pub const R013: &str = "R013 Client error (HTTP 4xx) while loading the main document.";
+    pub const R014: &str =
+        "R014 Cannot establish a secure connection, e.g., due to an invalid certificate.";
 
     // These patterns are intended for traces without DNSSEC
     pub const R102: &str = "R102 Single Domain with www redirect. A + A (for www)";
@@ -0,0 +1,122 @@
+//! Cache of [`Sequence`]s converted from pcap and dnstap files
+//!
+//! Parsing the raw captures dominates the time to load a dataset.
+//! The converted [`Sequence`] only depends on the content of the file, the [`LoadSequenceConfig`], and the version of this crate.
+//! The [`ConversionCache`] stores each [`Sequence`] under the hash of the file, in one directory per hash of the configuration and crate version.
+//! Repeated runs over the same raw data skip the parsing entirely.
+//! Since the file content is hashed, moved files are still found in the cache and modified files are never served from it.
+//! The hash is memoized by the size and modification time of the file, such that converting a missing file does not read it twice.
+//!
+//! The noise of [`SimulatedCountermeasure::DifferentialPrivacy`] is seeded with the path of the file, so in this case the path is hashed too.
+//! The length and complexity filters of the [`LoadSequenceConfig`] are part of the key, since they are checked before the simulated countermeasure and cannot be checked on the cached [`Sequence`].
+
+use crate::{
+    format_version::from_json_any_version, sequence::file_hash, LoadSequenceConfig, Sequence,
+    SimulatedCountermeasure, VERSION,
+};
+use anyhow::{Context as _, Error};
+use log::warn;
+use misc_utils::path::PathExt;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// Counter to create unique names for temporary files
+static TMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Directory storing converted [`Sequence`]s, see the [module documentation](self)
+#[derive(Clone, Debug)]
+pub struct ConversionCache {
+    dir: PathBuf,
+}
+
+impl ConversionCache {
+    /// Create a new [`ConversionCache`] storing its files in `dir`
+    pub fn new(dir: PathBuf) -> Result<Self, Error> {
+        fs::create_dir_all(&dir).with_context(|| {
+            format!(
+                "Cannot create conversion cache directory '{}'",
+                dir.display()
+            )
+        })?;
+        Ok(Self { dir })
+    }
+
+    /// Directory containing the cached [`Sequence`]s
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Same as [`Sequence::from_path_with_config`], but use the cache for pcap and dnstap files
+    ///
+    /// The identifier of the returned [`Sequence`] is always the path of the file, even if the cached [`Sequence`] was converted from a different path.
+    /// Errors while accessing the cache are only logged and the file is converted instead.
+    pub fn load(&self, path: &Path, config: LoadSequenceConfig) -> Result<Sequence, Error> {
+        if !path
+            .extensions()
+            .any(|ext| ext == "dnstap" || ext == "pcap")
+        {
+            return Sequence::from_path_with_config(path, config);
+        }
+
+        let cache_path = self.cache_path(path, config)?;
+        if cache_path.exists() {
+            let cached = fs::read_to_string(&cache_path)
+                .map_err(Error::from)
+                .and_then(|content| from_json_any_version::<Sequence>(&content));
+            match cached {
+                Ok(seq) => return Ok(seq.with_id(path.to_string_lossy().to_string())),
+                Err(err) => warn!(
+                    "Cannot read cached sequence '{}', converting again: {}",
+                    cache_path.display(),
+                    err
+                ),
+            }
+        }
+
+        let seq = Sequence::from_path_with_config(path, config)?;
+        if let Err(err) = store(&cache_path, &seq) {
+            warn!(
+                "Cannot write cached sequence '{}': {:#}",
+                cache_path.display(),
+                err
+            );
+        }
+        Ok(seq)
+    }
+
+    /// Location of the cached [`Sequence`] for the file `path` converted with `config`
+    fn cache_path(&self, path: &Path, config: LoadSequenceConfig) -> Result<PathBuf, Error> {
+        // The hash is reused for the `SequenceMetadata` while converting the file
+        let mut file_hash = file_hash(path)?;
+        if let SimulatedCountermeasure::DifferentialPrivacy { .. } = config.simulated_countermeasure
+        {
+            file_hash = blake3::hash(format!("{} {}", file_hash, path.display()).as_bytes())
+                .to_hex()
+                .to_string();
+        }
+
+        let config_hash = blake3::hash(format!("{:?} {}", config, VERSION).as_bytes()).to_hex();
+        Ok(self
+            .dir
+            .join(&config_hash[..16])
+            .join(format!("{}.json", file_hash)))
+    }
+}
+
+fn store(path: &Path, seq: &Sequence) -> Result<(), Error> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    // Write to a temporary file first, such that concurrent readers never see a partial file
+    let tmp_path = path.with_extension(format!(
+        "{}-{}.tmp",
+        std::process::id(),
+        TMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    fs::write(&tmp_path, seq.to_json()?)?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
@@ -3,26 +3,43 @@
 //! The module has to entry points for building sequences: [`build_sequence`] and
 //! [`build_precision_sequence`]
 //!
+//! [`build_sequence_streaming`] processes the dnstap file in a single pass instead of collecting all events first.
+//! This bounds the memory usage for very large files, e.g., from long captures.
+//!
 //! Additionally, the function [`load_matching_query_responses_from_dnstap`] is exported, which
 //! returns a list of Query/Response pairs for both the client and forwarder queries. If only part
 //! of the data is needed (e.g., only the forwarder messages) additional filtering must be applied.
+//!
+//! The function [`analyze_padding`] checks which EDNS(0) padding the forwarded messages carry.
 
 use crate::{
-    load_sequence::{convert_to_precision_sequence, convert_to_sequence, LoadSequenceConfig},
+    load_sequence::{
+        convert_to_precision_sequence, try_convert_to_sequence, LoadSequenceConfig, Padding,
+    },
     precision_sequence::PrecisionSequence,
-    AbstractQueryResponse, Sequence,
+    AbstractQueryResponse, Sequence, SequenceMetadata,
 };
 use anyhow::{anyhow, bail, Context as _, Error};
 use chrono::{DateTime, Utc};
 use dnstap::{
-    dnstap::Message_Type,
-    process_dnstap,
+    client_message_marker, process_dnstap,
     protos::{self, DnstapContent},
-    sanity_check_dnstap,
+    sanity_check_dnstap, Marker, MarkerCounts, MarkerPolicy, MessageType,
 };
-use log::{debug, info};
+use log::{debug, info, warn};
 use serde::Serialize;
-use std::{collections::BTreeMap, path::Path};
+use std::{
+    collections::BTreeMap,
+    mem,
+    net::{IpAddr, SocketAddr},
+    path::Path,
+};
+use trust_dns_proto::{op::Message as DnsMessage, rr::rdata::opt::EdnsCode};
+
+/// Block sizes which are checked by [`analyze_padding`], from largest to smallest
+const PADDING_BLOCK_SIZES: &[u32] = &[512, 468, 256, 128, 64, 32, 16];
+/// Default number of events [`build_sequence_streaming`] holds back to restore their order
+pub const DEFAULT_REORDER_BUFFER: usize = 4096;
 
 /// Representation of a single Query/Response pair in dnstap
 #[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Serialize)]
@@ -34,6 +51,8 @@ pub struct Query {
     pub end: DateTime<Utc>,
     pub query_size: u32,
     pub response_size: u32,
+    /// Address of the upstream resolver, only set for forwarder queries
+    pub resolver: Option<SocketAddr>,
 }
 
 impl From<Query> for AbstractQueryResponse {
@@ -54,6 +73,15 @@ impl From<&Query> for AbstractQueryResponse {
     }
 }
 
+/// Times of the marker queries, which delimit the measurement in a dnstap file
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Default, Serialize)]
+pub struct MarkerTimes {
+    /// Time of the first response to the start marker query, or of the first query if there is no response
+    pub start: Option<DateTime<Utc>>,
+    /// Time of the first end marker query
+    pub end: Option<DateTime<Utc>>,
+}
+
 /// Source for the Query or Response
 #[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Serialize)]
 pub enum QuerySource {
@@ -85,15 +113,47 @@ struct UnmatchedClientQuery {
 ///
 /// `config` allows to alter the loading according to [`LoadSequenceConfig`]
 pub fn build_sequence(dnstap_file: &Path, config: LoadSequenceConfig) -> Result<Sequence, Error> {
-    let matched = load_matching_query_responses_from_dnstap(dnstap_file)?;
-    let forwarder_queries = matched
+    let matched =
+        load_matching_query_responses_from_dnstap_with_policy(dnstap_file, config.marker_policy)?;
+    sequence_from_queries(dnstap_file, matched, config)
+}
+
+/// Same as [`build_sequence`], but process the dnstap file in a single pass with bounded memory
+///
+/// See [`stream_matching_query_responses_from_dnstap`] for the meaning of `reorder_buffer`.
+pub fn build_sequence_streaming(
+    dnstap_file: &Path,
+    config: LoadSequenceConfig,
+    reorder_buffer: usize,
+) -> Result<Sequence, Error> {
+    let matched = stream_matching_query_responses_from_dnstap(
+        dnstap_file,
+        config.marker_policy,
+        reorder_buffer,
+    )?;
+    sequence_from_queries(dnstap_file, matched, config)
+}
+
+fn sequence_from_queries(
+    dnstap_file: &Path,
+    matched: Vec<Query>,
+    config: LoadSequenceConfig,
+) -> Result<Sequence, Error> {
+    let forwarder_queries: Vec<_> = matched
         .into_iter()
-        .filter(|q| q.source == QuerySource::Forwarder);
-    convert_to_sequence(
+        .filter(|q| q.source == QuerySource::Forwarder)
+        .collect();
+    let metadata = SequenceMetadata {
+        capture_start: forwarder_queries.iter().map(|q| q.start).min(),
+        resolver: forwarder_queries.iter().find_map(|q| q.resolver),
+        ..SequenceMetadata::for_file(dnstap_file, config)?
+    };
+    try_convert_to_sequence(
         forwarder_queries,
         dnstap_file.to_string_lossy().to_string(),
         config,
-    )
+    )?
+    .map(|seq| seq.with_metadata(metadata))
     .ok_or_else(|| anyhow!("Sequence is empty"))
 }
 
@@ -111,75 +171,327 @@ pub fn build_precision_sequence(dnstap_file: &Path) -> Result<PrecisionSequence,
 ///
 /// The output needs to be filtered if only client or forwarder messages should be included
 pub fn load_matching_query_responses_from_dnstap(dnstap_file: &Path) -> Result<Vec<Query>, Error> {
-    // process dnstap if available
-    let mut events: Vec<protos::Dnstap> = process_dnstap(&*dnstap_file)?
-        .collect::<Result<_, Error>>()
-        .with_context(|| "Failed to read the raw DNSTAP file")?;
+    load_matching_query_responses_from_dnstap_with_policy(dnstap_file, MarkerPolicy::default())
+}
 
-    // the dnstap events can be out of order, so sort them by timestamp
-    // always take the later timestamp if there are multiple
-    events.sort_by_key(|ev| {
+/// Load the times of the start and end marker queries
+///
+/// The start time matches where [`load_matching_query_responses_from_dnstap`] starts the measurement.
+pub fn load_marker_times_from_dnstap(dnstap_file: &Path) -> Result<MarkerTimes, Error> {
+    let mut start_query = None;
+    let mut start_response = None;
+    let mut end = None;
+    for ev in process_dnstap(&*dnstap_file)? {
+        let ev = ev.with_context(|| "Failed to read the raw DNSTAP file")?;
+        let (message_type, marker) = match client_message_marker(&ev) {
+            Some(message) => message,
+            None => continue,
+        };
         let DnstapContent::Message {
             query_time,
             response_time,
             ..
         } = ev.content;
-        if let Some(time) = response_time {
-            time
-        } else if let Some(time) = query_time {
-            time
-        } else {
-            panic!("The dnstap message must contain either a query or response time.")
+        let earliest =
+            |current: Option<DateTime<Utc>>, time: Option<DateTime<Utc>>| match (current, time) {
+                (Some(current), Some(time)) => Some(current.min(time)),
+                (current, time) => current.or(time),
+            };
+        match (message_type, marker) {
+            (MessageType::ClientQuery, Marker::Start) => {
+                start_query = earliest(start_query, query_time)
+            }
+            (MessageType::ClientResponse, Marker::Start) => {
+                start_response = earliest(start_response, response_time)
+            }
+            (MessageType::ClientQuery, Marker::End) => end = earliest(end, query_time),
+            _ => {}
         }
-    });
+    }
 
-    // Place some sanity checks on the dnstap files
-    sanity_check_dnstap(&events)?;
+    Ok(MarkerTimes {
+        start: start_response.or(start_query),
+        end,
+    })
+}
+
+/// Same as [`load_matching_query_responses_from_dnstap`] but with a configurable [`MarkerPolicy`]
+///
+/// The measurement starts after the first response to the start marker query.
+/// If the policy allows missing start marker responses and there is none, the measurement starts after the first start marker query instead.
+pub fn load_matching_query_responses_from_dnstap_with_policy(
+    dnstap_file: &Path,
+    marker_policy: MarkerPolicy,
+) -> Result<Vec<Query>, Error> {
+    // process dnstap if available
+    let mut events: Vec<protos::Dnstap> = process_dnstap(&*dnstap_file)?
+        .collect::<Result<_, Error>>()
+        .with_context(|| "Failed to read the raw DNSTAP file")?;
+    let event_count = events.len();
+    events.retain(|ev| event_time(ev).is_some());
+    warn_untimed_events(dnstap_file, event_count - events.len());
+
+    // the dnstap events can be out of order, so sort them by timestamp
+    events.sort_by_key(event_time);
 
-    let mut unanswered_client_queries: BTreeMap<MatchKey, UnmatchedClientQuery> = BTreeMap::new();
-    let mut unanswered_forwarder_queries: BTreeMap<MatchKey, UnmatchedClientQuery> =
-        BTreeMap::new();
-    let mut matched = Vec::new();
+    // Place some sanity checks on the dnstap files
+    let warnings = sanity_check_dnstap(&events, &marker_policy).into_result()?;
+    for warning in warnings {
+        warn!("{}: {}", dnstap_file.display(), warning);
+    }
+    let start_marker_type = if events
+        .iter()
+        .filter_map(client_message_marker)
+        .any(|marker| marker == (MessageType::ClientResponse, Marker::Start))
+    {
+        MessageType::ClientResponse
+    } else {
+        MessageType::ClientQuery
+    };
 
+    let mut matcher = QueryMatcher::default();
     for ev in events
         .into_iter()
         // search for the CLIENT_RESPONE `start.example.` message as the end of the prefetching events
-        .skip_while(|ev| {
-            let DnstapContent::Message {
-                message_type,
-                ref response_message,
-                ..
-            } = ev.content;
-            if message_type == Message_Type::CLIENT_RESPONSE {
-                let (dnsmsg, _size) = response_message.as_ref().expect("Unbound always sets this");
-                let qname = dnsmsg.queries()[0].name().to_utf8();
-                if qname == "start.example." {
-                    return false;
-                }
-            }
-            true
-        })
+        .skip_while(|ev| client_message_marker(ev) != Some((start_marker_type, Marker::Start)))
         // the skip while returns the CLIENT_RESPONSE with `start.example.`
         // We want to remove this as well, so skip over the first element here
         .skip(1)
         // Only process messages until the end message is found in form of the first (thus CLIENT_QUERY)
         // message forr domain `end.example.`
-        .take_while(|ev| {
-            let DnstapContent::Message {
-                message_type,
-                ref query_message,
-                ..
-            } = ev.content;
-            if message_type == Message_Type::CLIENT_QUERY {
-                let (dnsmsg, _size) = query_message.as_ref().expect("Unbound always sets this");
-                let qname = dnsmsg.queries()[0].name().to_utf8();
-                if qname == "end.example." {
-                    return false;
+        .take_while(|ev| client_message_marker(ev) != Some((MessageType::ClientQuery, Marker::End)))
+        // Retried start marker queries can occur after the first response
+        .filter(|ev| !is_start_marker(&client_message_marker(ev)))
+    {
+        matcher.process(ev);
+    }
+    matcher.finish()
+}
+
+/// Same as [`load_matching_query_responses_from_dnstap_with_policy`], but process the dnstap file in a single pass
+///
+/// Instead of collecting and sorting all events, at most `reorder_buffer` events are held back to restore their order.
+/// Events which are delayed by more than that are processed out of order and reported in a warning.
+/// The result is identical to [`load_matching_query_responses_from_dnstap_with_policy`] as long as no event is delayed too much.
+///
+/// Only the events between the first start marker query and its response are kept in addition, since the measurement starts at the query if the response is missing.
+/// At most `reorder_buffer` of them are kept, after which the response is assumed to be missing.
+/// Thus, the result also differs if the start marker response arrives after more than `reorder_buffer` events.
+pub fn stream_matching_query_responses_from_dnstap(
+    dnstap_file: &Path,
+    marker_policy: MarkerPolicy,
+    reorder_buffer: usize,
+) -> Result<Vec<Query>, Error> {
+    let mut reorder = ReorderBuffer::new(reorder_buffer);
+    let mut window = MeasurementWindow::BeforeStart;
+    let mut counts = MarkerCounts::default();
+    let mut matcher = QueryMatcher::default();
+    let mut untimed = 0;
+
+    for ev in process_dnstap(&*dnstap_file)? {
+        let ev = ev.with_context(|| "Failed to read the raw DNSTAP file")?;
+        let time = match event_time(&ev) {
+            Some(time) => time,
+            None => {
+                untimed += 1;
+                continue;
+            }
+        };
+        counts.add(&ev);
+        if let Some(ev) = reorder.push(time, ev) {
+            window.process(ev, &mut matcher, reorder.capacity);
+        }
+    }
+    while let Some(ev) = reorder.pop() {
+        window.process(ev, &mut matcher, reorder.capacity);
+    }
+    window.finish(&mut matcher);
+    warn_untimed_events(dnstap_file, untimed);
+
+    // Place some sanity checks on the dnstap files
+    let warnings = counts.check(&marker_policy).into_result()?;
+    for warning in warnings {
+        warn!("{}: {}", dnstap_file.display(), warning);
+    }
+    if reorder.late > 0 {
+        warn!(
+            "{}: {} events were delayed by more than the reorder buffer of {} events and are processed out of order",
+            dnstap_file.display(),
+            reorder.late,
+            reorder.capacity
+        );
+    }
+    matcher.finish()
+}
+
+/// Time used to order the dnstap events
+///
+/// Always take the later timestamp if there are multiple.
+/// Returns [`None`] if the message contains neither a query nor a response time.
+fn event_time(ev: &protos::Dnstap) -> Option<DateTime<Utc>> {
+    let DnstapContent::Message {
+        query_time,
+        response_time,
+        ..
+    } = ev.content;
+    response_time.or(query_time)
+}
+
+/// Events without a time cannot be ordered and are skipped
+fn warn_untimed_events(dnstap_file: &Path, count: usize) {
+    if count > 0 {
+        warn!(
+            "{}: Skipped {} events without a query or response time",
+            dnstap_file.display(),
+            count
+        );
+    }
+}
+
+/// Restores the order of almost sorted dnstap events, while holding back a bounded number of events
+struct ReorderBuffer {
+    capacity: usize,
+    /// Events by their time and arrival index, which keeps events with the same time in arrival order
+    events: BTreeMap<(DateTime<Utc>, usize), protos::Dnstap>,
+    next_index: usize,
+    last_emitted: Option<DateTime<Utc>>,
+    /// Number of events older than an already emitted event
+    late: usize,
+}
+
+impl ReorderBuffer {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            events: BTreeMap::new(),
+            next_index: 0,
+            last_emitted: None,
+            late: 0,
+        }
+    }
+
+    /// Add `ev`, which happened at `time`, and return the oldest event, if the buffer is full
+    fn push(&mut self, time: DateTime<Utc>, ev: protos::Dnstap) -> Option<protos::Dnstap> {
+        if self.last_emitted.map_or(false, |last| time < last) {
+            self.late += 1;
+        }
+        self.events.insert((time, self.next_index), ev);
+        self.next_index += 1;
+        if self.events.len() > self.capacity {
+            self.pop()
+        } else {
+            None
+        }
+    }
+
+    /// Remove the oldest event
+    fn pop(&mut self) -> Option<protos::Dnstap> {
+        let key = *self.events.keys().next()?;
+        self.last_emitted = Some(key.0);
+        self.events.remove(&key)
+    }
+}
+
+/// Position of the ordered events relative to the marker queries, while streaming
+enum MeasurementWindow {
+    /// Before the first start marker query
+    BeforeStart,
+    /// After the first start marker query, but before the first response to it
+    ///
+    /// The events are kept, since the measurement starts after the query, if there is no response.
+    /// Once more than `max_pending` events are kept, the response is assumed to be missing.
+    AwaitingStartResponse(Vec<protos::Dnstap>),
+    Measuring,
+    /// After the first end marker query
+    Finished,
+}
+
+impl MeasurementWindow {
+    fn process(&mut self, ev: protos::Dnstap, matcher: &mut QueryMatcher, max_pending: usize) {
+        let marker = client_message_marker(&ev);
+
+        match self {
+            Self::BeforeStart => {
+                if marker == Some((MessageType::ClientResponse, Marker::Start)) {
+                    *self = Self::Measuring;
+                } else if marker == Some((MessageType::ClientQuery, Marker::Start)) {
+                    *self = Self::AwaitingStartResponse(Vec::new());
                 }
             }
-            true
-        })
-    {
+            Self::AwaitingStartResponse(events) => {
+                if marker == Some((MessageType::ClientResponse, Marker::Start)) {
+                    *self = Self::Measuring;
+                } else if marker == Some((MessageType::ClientQuery, Marker::End)) {
+                    self.finish(matcher);
+                } else {
+                    events.push(ev);
+                    if events.len() > max_pending {
+                        debug!(
+                            "No response to the start marker query within {} events, starting the measurement at the query",
+                            max_pending
+                        );
+                        self.flush_pending(matcher, Self::Measuring);
+                    }
+                }
+            }
+            Self::Measuring => {
+                if marker == Some((MessageType::ClientQuery, Marker::End)) {
+                    *self = Self::Finished;
+                } else if !is_start_marker(&marker) {
+                    matcher.process(ev);
+                }
+            }
+            Self::Finished => {}
+        }
+    }
+
+    /// Process the kept events, if the start marker query never got a response
+    fn finish(&mut self, matcher: &mut QueryMatcher) {
+        self.flush_pending(matcher, Self::Finished);
+    }
+
+    /// Process the kept events, if any, and continue with `next`
+    fn flush_pending(&mut self, matcher: &mut QueryMatcher, next: Self) {
+        if let Self::AwaitingStartResponse(events) = mem::replace(self, next) {
+            for ev in events {
+                if !is_start_marker(&client_message_marker(&ev)) {
+                    matcher.process(ev);
+                }
+            }
+        }
+    }
+}
+
+/// Retried start marker queries can occur after the first response
+fn is_start_marker(marker: &Option<(MessageType, Marker)>) -> bool {
+    matches!(marker, Some((_, Marker::Start)))
+}
+
+/// Return the query name and type of the first question of `dnsmsg`
+fn question(dnsmsg: &DnsMessage) -> Option<(String, String)> {
+    let query = dnsmsg.queries().first()?;
+    Some((query.name().to_utf8(), query.query_type().to_string()))
+}
+
+/// Matches the queries with their responses
+#[derive(Debug, Default)]
+struct QueryMatcher {
+    unanswered_client_queries: BTreeMap<MatchKey, UnmatchedClientQuery>,
+    unanswered_forwarder_queries: BTreeMap<MatchKey, UnmatchedClientQuery>,
+    matched: Vec<Query>,
+}
+
+impl QueryMatcher {
+    /// Process the next event of the measurement
+    ///
+    /// Events with missing fields are skipped with a warning.
+    fn process(&mut self, ev: protos::Dnstap) {
+        if let Err(err) = self.try_process(ev) {
+            warn!("Skipping dnstap event: {}", err);
+        }
+    }
+
+    fn try_process(&mut self, ev: protos::Dnstap) -> Result<(), Error> {
         let DnstapContent::Message {
             message_type,
             query_message,
@@ -187,16 +499,18 @@ pub fn load_matching_query_responses_from_dnstap(dnstap_file: &Path) -> Result<V
             query_time,
             response_time,
             query_port,
+            response_address,
+            response_port,
             ..
         } = ev.content;
+        let missing = |field: &str| anyhow!("{:?} without {}", message_type, field);
         match message_type {
-            Message_Type::CLIENT_QUERY => {
-                let (dnsmsg, size) = query_message.expect("Unbound always sets this");
-                let qname = dnsmsg.queries()[0].name().to_utf8();
-                let qtype = dnsmsg.queries()[0].query_type().to_string();
+            MessageType::ClientQuery => {
+                let (dnsmsg, size) = query_message.ok_or_else(|| missing("query message"))?;
+                let (qname, qtype) = question(&dnsmsg).ok_or_else(|| missing("question"))?;
                 let id = dnsmsg.id();
-                let start = query_time.expect("Unbound always sets this");
-                let port = query_port.expect("Unbound always sets this");
+                let start = query_time.ok_or_else(|| missing("query time"))?;
+                let port = query_port.ok_or_else(|| missing("query port"))?;
 
                 let key = MatchKey {
                     qname: qname.clone(),
@@ -210,7 +524,7 @@ pub fn load_matching_query_responses_from_dnstap(dnstap_file: &Path) -> Result<V
                     start,
                     size: size as u32,
                 };
-                let existing_value = unanswered_client_queries.insert(key, value);
+                let existing_value = self.unanswered_client_queries.insert(key, value);
                 if let Some(existing_value) = existing_value {
                     info!(
                         "Duplicate Client Query for '{}' ({})",
@@ -219,13 +533,12 @@ pub fn load_matching_query_responses_from_dnstap(dnstap_file: &Path) -> Result<V
                 }
             }
 
-            Message_Type::CLIENT_RESPONSE => {
-                let (dnsmsg, size) = response_message.expect("Unbound always sets this: FR r msg");
-                let qname = dnsmsg.queries()[0].name().to_utf8();
-                let qtype = dnsmsg.queries()[0].query_type().to_string();
+            MessageType::ClientResponse => {
+                let (dnsmsg, size) = response_message.ok_or_else(|| missing("response message"))?;
+                let (qname, qtype) = question(&dnsmsg).ok_or_else(|| missing("question"))?;
                 let id = dnsmsg.id();
-                let end = response_time.expect("Unbound always sets this: FR r time");
-                let port = query_port.expect("Unbound always sets this");
+                let end = response_time.ok_or_else(|| missing("response time"))?;
+                let port = query_port.ok_or_else(|| missing("query port"))?;
 
                 let key = MatchKey {
                     qname: qname.clone(),
@@ -233,8 +546,8 @@ pub fn load_matching_query_responses_from_dnstap(dnstap_file: &Path) -> Result<V
                     id,
                     port,
                 };
-                if let Some(unmatched) = unanswered_client_queries.remove(&key) {
-                    matched.push(Query {
+                if let Some(unmatched) = self.unanswered_client_queries.remove(&key) {
+                    self.matched.push(Query {
                         source: QuerySource::Client,
                         qname,
                         qtype,
@@ -242,18 +555,18 @@ pub fn load_matching_query_responses_from_dnstap(dnstap_file: &Path) -> Result<V
                         end,
                         query_size: unmatched.size,
                         response_size: size as u32,
+                        resolver: None,
                     });
                 } else {
                     info!("Unmatched Client Response for '{}' ({})", qname, qtype);
                 };
             }
 
-            Message_Type::FORWARDER_QUERY => {
-                let (dnsmsg, size) = query_message.expect("Unbound always sets this");
-                let qname = dnsmsg.queries()[0].name().to_utf8();
-                let qtype = dnsmsg.queries()[0].query_type().to_string();
+            MessageType::ForwarderQuery => {
+                let (dnsmsg, size) = query_message.ok_or_else(|| missing("query message"))?;
+                let (qname, qtype) = question(&dnsmsg).ok_or_else(|| missing("question"))?;
                 let id = dnsmsg.id();
-                let start = query_time.expect("Unbound always sets this");
+                let start = query_time.ok_or_else(|| missing("query time"))?;
 
                 let key = MatchKey {
                     qname: qname.clone(),
@@ -267,7 +580,7 @@ pub fn load_matching_query_responses_from_dnstap(dnstap_file: &Path) -> Result<V
                     start,
                     size: size as u32,
                 };
-                let existing_value = unanswered_forwarder_queries.insert(key, value);
+                let existing_value = self.unanswered_forwarder_queries.insert(key, value);
                 if let Some(existing_value) = existing_value {
                     info!(
                         "Duplicate Forwarder Query for '{}' ({})",
@@ -276,13 +589,12 @@ pub fn load_matching_query_responses_from_dnstap(dnstap_file: &Path) -> Result<V
                 }
             }
 
-            Message_Type::FORWARDER_RESPONSE => {
-                let (dnsmsg, size) = response_message.expect("Unbound always sets this: FR r msg");
-                let qname = dnsmsg.queries()[0].name().to_utf8();
-                let qtype = dnsmsg.queries()[0].query_type().to_string();
-                let start = query_time.expect("Unbound always sets this: FR q time");
+            MessageType::ForwarderResponse => {
+                let (dnsmsg, size) = response_message.ok_or_else(|| missing("response message"))?;
+                let (qname, qtype) = question(&dnsmsg).ok_or_else(|| missing("question"))?;
+                let start = query_time.ok_or_else(|| missing("query time"))?;
                 let id = dnsmsg.id();
-                let end = response_time.expect("Unbound always sets this: FR r time");
+                let end = response_time.ok_or_else(|| missing("response time"))?;
 
                 let key = MatchKey {
                     qname: qname.clone(),
@@ -290,8 +602,8 @@ pub fn load_matching_query_responses_from_dnstap(dnstap_file: &Path) -> Result<V
                     id,
                     port: 0,
                 };
-                if let Some(unmatched) = unanswered_forwarder_queries.remove(&key) {
-                    matched.push(Query {
+                if let Some(unmatched) = self.unanswered_forwarder_queries.remove(&key) {
+                    self.matched.push(Query {
                         source: QuerySource::Forwarder,
                         qname,
                         qtype,
@@ -299,6 +611,8 @@ pub fn load_matching_query_responses_from_dnstap(dnstap_file: &Path) -> Result<V
                         end,
                         query_size: unmatched.size,
                         response_size: size as u32,
+                        resolver: response_address
+                            .map(|addr| SocketAddr::new(addr, response_port.unwrap_or_default())),
                     });
                 } else {
                     info!("Unmatched Forwarder Response for '{}' ({})", qname, qtype);
@@ -307,26 +621,163 @@ pub fn load_matching_query_responses_from_dnstap(dnstap_file: &Path) -> Result<V
 
             _ => {}
         }
+        Ok(())
     }
 
-    // cleanup some messages
-    // filter out all the queries which are just noise
-    matched.retain(|query| {
-        // _ta queries are queries sent to the root servers to indicate which root DNSSEC key is trusted.
-        !(query.qtype == "NULL" && query.qname.starts_with("_ta")) || query.qname.is_empty()
-    });
-    for msg in unanswered_client_queries {
-        debug!("Unanswered client query: {:?}", msg);
+    /// Return all matched queries sorted by the time of the response
+    fn finish(self) -> Result<Vec<Query>, Error> {
+        let mut matched = self.matched;
+        // cleanup some messages
+        // filter out all the queries which are just noise
+        matched.retain(|query| {
+            // _ta queries are queries sent to the root servers to indicate which root DNSSEC key is trusted.
+            !(query.qtype == "NULL" && query.qname.starts_with("_ta")) || query.qname.is_empty()
+        });
+        for msg in self.unanswered_client_queries {
+            debug!("Unanswered client query: {:?}", msg);
+        }
+        for msg in self.unanswered_forwarder_queries {
+            debug!("Unanswered forwarder query: {:?}", msg);
+        }
+        // the values are not necessarily in correct order, thus sort them here by end time
+        // end time is the time when the response arrives, which is the most interesting field for the attacker
+        matched.sort_by_key(|x| x.end);
+
+        sanity_check_matched_queries(&matched)?;
+        Ok(matched)
     }
-    for msg in unanswered_forwarder_queries {
-        debug!("Unanswered forwarder query: {:?}", msg);
+}
+
+/// Padding information about a single forwarded DNS message
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Serialize)]
+pub struct MessagePadding {
+    /// Upstream resolver to which the message was sent or from which it was received
+    pub resolver: Option<IpAddr>,
+    pub is_query: bool,
+    pub qname: String,
+    /// Size of the DNS message in bytes
+    pub size: u32,
+    /// Length of the EDNS(0) padding option, if present
+    pub padding_length: Option<u16>,
+    /// Largest block size out of a list of common block sizes, which evenly divides the message size
+    ///
+    /// This is only set if the message contains a padding option.
+    pub block_size: Option<u32>,
+}
+
+/// Aggregated padding information for all messages of one direction
+#[derive(Clone, Eq, PartialEq, Debug, Default, Serialize)]
+pub struct PaddingCounts {
+    pub messages: usize,
+    /// Number of messages with an EDNS(0) padding option
+    pub padded: usize,
+    /// Number of padded messages for each detected block size
+    pub block_sizes: BTreeMap<u32, usize>,
+    /// Number of messages whose size is not a multiple of the block size expected by the [`Padding`] policy
+    pub policy_violations: usize,
+}
+
+impl PaddingCounts {
+    fn update(&mut self, msg: &MessagePadding, policy: Padding) {
+        self.messages += 1;
+        if msg.padding_length.is_some() {
+            self.padded += 1;
+        }
+        if let Some(block_size) = msg.block_size {
+            *self.block_sizes.entry(block_size).or_default() += 1;
+        }
+        if msg.size % policy.block_size(msg.is_query) != 0 {
+            self.policy_violations += 1;
+        }
     }
-    // the values are not necessarily in correct order, thus sort them here by end time
-    // end time is the time when the response arrives, which is the most interesting field for the attacker
-    matched.sort_by_key(|x| x.end);
+}
 
-    sanity_check_matched_queries(&matched)?;
-    Ok(matched)
+/// Padding statistics for a single upstream resolver
+#[derive(Clone, Eq, PartialEq, Debug, Default, Serialize)]
+pub struct ResolverPadding {
+    pub queries: PaddingCounts,
+    pub responses: PaddingCounts,
+}
+
+/// Aggregate the padding information per resolver
+///
+/// Each message is checked against the block sizes expected by `policy`.
+pub fn aggregate_padding<'a>(
+    messages: impl IntoIterator<Item = &'a MessagePadding>,
+    policy: Padding,
+) -> BTreeMap<Option<IpAddr>, ResolverPadding> {
+    let mut res: BTreeMap<_, ResolverPadding> = BTreeMap::new();
+    for msg in messages {
+        let entry = res.entry(msg.resolver).or_default();
+        if msg.is_query {
+            entry.queries.update(msg, policy);
+        } else {
+            entry.responses.update(msg, policy);
+        }
+    }
+    res
+}
+
+/// Report the EDNS(0) padding of all forwarder messages in a dnstap file
+///
+/// Unlike [`load_matching_query_responses_from_dnstap`], this considers all messages in the file, including the marker queries.
+/// Only the messages between the forwarder and the upstream resolver are reported, as only they are visible to an on-path attacker.
+pub fn analyze_padding(dnstap_file: &Path) -> Result<Vec<MessagePadding>, Error> {
+    let mut res = Vec::new();
+    for ev in process_dnstap(&*dnstap_file)? {
+        let ev = ev.with_context(|| "Failed to read the raw DNSTAP file")?;
+        let DnstapContent::Message {
+            message_type,
+            query_message,
+            response_message,
+            response_address,
+            ..
+        } = ev.content;
+        let (msg, is_query) = match message_type {
+            MessageType::ForwarderQuery => (query_message, true),
+            MessageType::ForwarderResponse => (response_message, false),
+            _ => continue,
+        };
+        let (dnsmsg, size) =
+            msg.ok_or_else(|| anyhow!("Unbound always sets the message for {:?}", message_type))?;
+        res.push(message_padding(
+            &dnsmsg,
+            size as u32,
+            is_query,
+            response_address,
+        ));
+    }
+    Ok(res)
+}
+
+fn message_padding(
+    dnsmsg: &DnsMessage,
+    size: u32,
+    is_query: bool,
+    resolver: Option<IpAddr>,
+) -> MessagePadding {
+    let padding_length = dnsmsg
+        .edns()
+        .and_then(|edns| edns.option(EdnsCode::Padding))
+        .map(|opt| opt.len());
+    let block_size = padding_length.and_then(|_| {
+        PADDING_BLOCK_SIZES
+            .iter()
+            .cloned()
+            .find(|&block_size| size % block_size == 0)
+    });
+    MessagePadding {
+        resolver,
+        is_query,
+        qname: dnsmsg
+            .queries()
+            .first()
+            .map(|q| q.name().to_utf8())
+            .unwrap_or_default(),
+        size,
+        padding_length,
+        block_size,
+    }
 }
 
 /// Run a basic sanity check on the dnstap file to make sure it is not empty and some queries of type A could be found
@@ -349,3 +800,215 @@ fn sanity_check_matched_queries(matched: &[Query]) -> Result<(), Error> {
 
     Ok(())
 }
+
+#[cfg(test)]
+fn client_message(message_type: MessageType, qname: &str, millis: i64) -> protos::Dnstap {
+    use chrono::TimeZone;
+    use trust_dns_proto::{
+        op::Query,
+        rr::{Name, RecordType},
+    };
+
+    let mut msg = DnsMessage::new();
+    msg.add_query(Query::query(
+        Name::from_ascii(qname).unwrap(),
+        RecordType::A,
+    ));
+    let time = Some(Utc.timestamp_millis(millis));
+    let (query_message, response_message, query_time, response_time) = match message_type {
+        MessageType::ClientResponse => (None, Some((msg, 100)), None, time),
+        _ => (Some((msg, 50)), None, time, None),
+    };
+    protos::Dnstap {
+        identity: None,
+        version: None,
+        extra: None,
+        content: DnstapContent::Message {
+            message_type,
+            query_address: None,
+            response_address: None,
+            query_port: Some(1234),
+            response_port: None,
+            query_time,
+            response_time,
+            query_message,
+            response_message,
+            query_zone: None,
+        },
+    }
+}
+
+#[test]
+fn test_reorder_buffer() {
+    let mut reorder = ReorderBuffer::new(2);
+    let mut emitted = Vec::new();
+    for &millis in &[2, 1, 3, 0, 4] {
+        let ev = client_message(MessageType::ClientQuery, "a.", millis);
+        if let Some(ev) = reorder.push(event_time(&ev).unwrap(), ev) {
+            emitted.push(event_time(&ev).unwrap().timestamp_millis());
+        }
+    }
+    while let Some(ev) = reorder.pop() {
+        emitted.push(event_time(&ev).unwrap().timestamp_millis());
+    }
+    // The out of order event within the capacity is sorted, the late one is not
+    assert_eq!(emitted, vec![1, 0, 2, 3, 4]);
+    assert_eq!(reorder.late, 1);
+}
+
+#[test]
+fn test_measurement_window() {
+    use dnstap::{END_MARKER, START_MARKER};
+
+    let events = vec![
+        client_message(MessageType::ClientQuery, START_MARKER, 0),
+        client_message(MessageType::ClientQuery, "prefetch.", 1),
+        client_message(MessageType::ClientResponse, "prefetch.", 2),
+        client_message(MessageType::ClientResponse, START_MARKER, 3),
+        client_message(MessageType::ClientQuery, "www.example.", 5),
+        client_message(MessageType::ClientQuery, START_MARKER, 4),
+        client_message(MessageType::ClientResponse, "www.example.", 6),
+        client_message(MessageType::ClientQuery, END_MARKER, 7),
+        client_message(MessageType::ClientQuery, "after.", 8),
+    ];
+    let matched_qnames = |events: Vec<protos::Dnstap>, reorder_buffer: usize| {
+        let mut reorder = ReorderBuffer::new(reorder_buffer);
+        let mut window = MeasurementWindow::BeforeStart;
+        let mut matcher = QueryMatcher::default();
+        for ev in events {
+            if let Some(ev) = reorder.push(event_time(&ev).unwrap(), ev) {
+                window.process(ev, &mut matcher, reorder.capacity);
+            }
+        }
+        while let Some(ev) = reorder.pop() {
+            window.process(ev, &mut matcher, reorder.capacity);
+        }
+        window.finish(&mut matcher);
+        matcher
+            .matched
+            .into_iter()
+            .map(|query| query.qname)
+            .collect::<Vec<_>>()
+    };
+
+    // The retried start marker query is reordered and ignored
+    assert_eq!(matched_qnames(events.clone(), 2), vec!["www.example."]);
+    // The kept events are bounded by the reorder buffer, so a late start marker response is treated as missing
+    assert_eq!(
+        matched_qnames(events.clone(), 1),
+        vec!["prefetch.", "www.example."]
+    );
+    // Without the start marker response, the measurement starts at the query
+    let without_response: Vec<_> = events
+        .into_iter()
+        .filter(|ev| {
+            client_message_marker(ev) != Some((MessageType::ClientResponse, Marker::Start))
+        })
+        .collect();
+    assert_eq!(
+        matched_qnames(without_response, 8),
+        vec!["prefetch.", "www.example."]
+    );
+}
+
+#[test]
+fn test_skip_incomplete_events() {
+    let mut matcher = QueryMatcher::default();
+
+    let mut without_time = client_message(MessageType::ClientQuery, "www.example.", 1);
+    let DnstapContent::Message { query_time, .. } = &mut without_time.content;
+    *query_time = None;
+    assert_eq!(event_time(&without_time), None);
+    matcher.process(without_time);
+
+    let mut without_question = client_message(MessageType::ClientResponse, "www.example.", 2);
+    let DnstapContent::Message {
+        response_message, ..
+    } = &mut without_question.content;
+    *response_message = Some((DnsMessage::new(), 0));
+    assert_eq!(client_message_marker(&without_question), None);
+    matcher.process(without_question);
+
+    assert!(matcher.unanswered_client_queries.is_empty());
+    assert!(matcher.matched.is_empty());
+}
+
+#[test]
+fn test_analyze_padding() {
+    use trust_dns_proto::{
+        op::Query,
+        rr::{rdata::opt::EdnsOption, Name, RecordType},
+    };
+
+    let dns_message = |padding: Option<usize>| {
+        let mut msg = DnsMessage::new();
+        msg.add_query(Query::query(
+            Name::from_ascii("www.example.").unwrap(),
+            RecordType::A,
+        ));
+        if let Some(padding) = padding {
+            msg.edns_mut().set_max_payload(1232);
+            msg.edns_mut()
+                .options_mut()
+                .insert(EdnsOption::from((EdnsCode::Padding, &vec![0; padding][..])));
+        }
+        msg
+    };
+    let resolver: IpAddr = "192.0.2.1".parse().unwrap();
+    let messages = vec![
+        message_padding(&dns_message(Some(10)), 128, true, Some(resolver)),
+        // A multiple of the 128 B blocks is reported with the larger block size
+        message_padding(&dns_message(Some(20)), 256, true, Some(resolver)),
+        message_padding(&dns_message(None), 45, true, Some(resolver)),
+        message_padding(&dns_message(Some(300)), 468, false, Some(resolver)),
+        message_padding(&dns_message(Some(300)), 512, false, Some(resolver)),
+        message_padding(&dns_message(None), 100, false, None),
+    ];
+    assert_eq!("www.example.", messages[0].qname);
+    assert_eq!(
+        vec![Some(10), Some(20), None, Some(300), Some(300), None],
+        messages
+            .iter()
+            .map(|msg| msg.padding_length)
+            .collect::<Vec<_>>()
+    );
+    assert_eq!(
+        vec![Some(128), Some(256), None, Some(468), Some(512), None],
+        messages
+            .iter()
+            .map(|msg| msg.block_size)
+            .collect::<Vec<_>>()
+    );
+
+    let counts = aggregate_padding(&messages, Padding::Q128R468);
+    assert_eq!(2, counts.len());
+    assert_eq!(
+        ResolverPadding {
+            queries: PaddingCounts {
+                messages: 3,
+                padded: 2,
+                block_sizes: vec![(128, 1), (256, 1)].into_iter().collect(),
+                policy_violations: 1,
+            },
+            responses: PaddingCounts {
+                messages: 2,
+                padded: 2,
+                block_sizes: vec![(468, 1), (512, 1)].into_iter().collect(),
+                policy_violations: 1,
+            },
+        },
+        counts[&Some(resolver)]
+    );
+    assert_eq!(
+        ResolverPadding {
+            queries: PaddingCounts::default(),
+            responses: PaddingCounts {
+                messages: 1,
+                padded: 0,
+                block_sizes: BTreeMap::new(),
+                policy_violations: 1,
+            },
+        },
+        counts[&None]
+    );
+}
@@ -0,0 +1,807 @@
+//! Bulk export of [`LabelledSequences`] into formats used by other analysis tools
+//!
+//! The exports are intended for pipelines outside of Rust, e.g., deep-learning in Python, which should not need to parse and encode each [`Sequence`](crate::Sequence) individually.
+
+use crate::{knn::LabelledSequences, OneHotEncoding, Sequence, SequenceElement};
+use anyhow::{bail, Context as _, Error};
+#[cfg(feature = "export_parquet")]
+use arrow::{
+    array::{ArrayData, ArrayRef, ListArray, StringArray, StructArray, UInt16Array, UInt32Array},
+    buffer::Buffer,
+    datatypes::{DataType, Field, Schema},
+    record_batch::RecordBatch,
+};
+use misc_utils::fs::file_write;
+#[cfg(feature = "export_parquet")]
+use parquet::{arrow::ArrowWriter, basic::Compression, file::properties::WriterProperties};
+use rayon::prelude::*;
+#[cfg(feature = "export_parquet")]
+use std::sync::Arc;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::Path,
+};
+use zip::{write::FileOptions, CompressionMethod, ZipWriter};
+
+/// Number of entries in each [`OneHotEncoding`]
+const ONE_HOT_WIDTH: usize = 16;
+/// Token id of the positions after the end of a sequence
+pub const PAD_TOKEN_ID: u32 = 0;
+/// Token id of all [`SequenceElement`]s missing in the [`Vocabulary`]
+pub const UNKNOWN_TOKEN_ID: u32 = 1;
+const PAD_TOKEN: &str = "<pad>";
+const UNKNOWN_TOKEN: &str = "<unk>";
+
+/// Mapping of each [`SequenceElement`] to an integer token id
+///
+/// The ids [`PAD_TOKEN_ID`] and [`UNKNOWN_TOKEN_ID`] are reserved, all elements get the following ids in their sort order.
+/// The ids only depend on the set of elements and not on the order of the data.
+/// The vocabulary of the training data should be [saved](Vocabulary::save) and [loaded](Vocabulary::load) for all further exports, such that the ids stay the same.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Vocabulary {
+    ids: BTreeMap<SequenceElement, u32>,
+}
+
+impl Vocabulary {
+    /// Create a vocabulary containing all [`SequenceElement`]s occuring in `data`
+    pub fn from_data<S>(data: &[LabelledSequences<S>]) -> Self {
+        let elements: BTreeSet<SequenceElement> = data
+            .iter()
+            .flat_map(|lseq| &lseq.sequences)
+            .flat_map(|seq| seq.as_elements())
+            .cloned()
+            .collect();
+        Self {
+            ids: elements.into_iter().zip(UNKNOWN_TOKEN_ID + 1..).collect(),
+        }
+    }
+
+    /// Number of token ids including the reserved ones
+    ///
+    /// This is the size of the embedding table needed for the token ids.
+    pub fn size(&self) -> usize {
+        self.ids
+            .values()
+            .max()
+            .map_or(UNKNOWN_TOKEN_ID, |&id| id.max(UNKNOWN_TOKEN_ID)) as usize
+            + 1
+    }
+
+    /// Token id of a single element
+    pub fn token_id(&self, elem: SequenceElement) -> u32 {
+        self.ids.get(&elem).copied().unwrap_or(UNKNOWN_TOKEN_ID)
+    }
+
+    /// Token ids of all elements of `seq`
+    pub fn encode(&self, seq: &Sequence) -> Vec<u32> {
+        seq.as_elements()
+            .iter()
+            .map(|&elem| self.token_id(elem))
+            .collect()
+    }
+
+    /// Write the vocabulary as a text file with one token per line
+    ///
+    /// The line number, counting from 0, is the token id.
+    /// Elements are written in their serialized form, e.g., `S01` or `G05`, and the reserved ids as `<pad>` and `<unk>`.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        let path = path.as_ref();
+        let mut tokens = vec![None; self.size()];
+        for (elem, &id) in &self.ids {
+            tokens[id as usize] = Some(elem);
+        }
+
+        let mut wtr = file_write(path)
+            .create(true)
+            .truncate()
+            .with_context(|| format!("Cannot open file `{}`", path.display()))?;
+        writeln!(wtr, "{}", PAD_TOKEN)?;
+        writeln!(wtr, "{}", UNKNOWN_TOKEN)?;
+        for token in &tokens[UNKNOWN_TOKEN_ID as usize + 1..] {
+            match token {
+                Some(SequenceElement::Size(size)) => writeln!(wtr, "S{:0>2}", size)?,
+                Some(SequenceElement::Gap(gap)) => writeln!(wtr, "G{:0>2}", gap)?,
+                // Unused id, which cannot occur in a saved vocabulary
+                None => writeln!(wtr, "{}", UNKNOWN_TOKEN)?,
+            }
+        }
+        wtr.flush()?;
+        Ok(())
+    }
+
+    /// Read a vocabulary written by [`Vocabulary::save`]
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Cannot read file `{}`", path.display()))?;
+        let mut lines = content.lines();
+        if lines.next() != Some(PAD_TOKEN) || lines.next() != Some(UNKNOWN_TOKEN) {
+            bail!(
+                "The vocabulary `{}` must start with the tokens `{}` and `{}`",
+                path.display(),
+                PAD_TOKEN,
+                UNKNOWN_TOKEN
+            );
+        }
+
+        let mut ids = BTreeMap::new();
+        for (id, token) in (UNKNOWN_TOKEN_ID + 1..).zip(lines) {
+            if token == UNKNOWN_TOKEN {
+                continue;
+            }
+            let elem = match (token.get(..1), token.get(1..)) {
+                (Some("S"), Some(value)) => value
+                    .parse()
+                    .ok()
+                    .filter(|&size| size > 0)
+                    .map(SequenceElement::Size),
+                (Some("G"), Some(value)) => value.parse().ok().map(SequenceElement::Gap),
+                _ => None,
+            }
+            .with_context(|| {
+                format!(
+                    "Invalid token `{}` in line {} of the vocabulary `{}`",
+                    token,
+                    id + 1,
+                    path.display()
+                )
+            })?;
+            if ids.insert(elem, id).is_some() {
+                bail!(
+                    "The token `{}` occurs multiple times in the vocabulary `{}`",
+                    token,
+                    path.display()
+                );
+            }
+        }
+        Ok(Self { ids })
+    }
+}
+
+/// Write all [`Sequence`](crate::Sequence)s as a single padded one-hot tensor into a numpy `.npz` file
+///
+/// The file contains the following arrays, where `N` is the number of sequences and `L` the padded length:
+///
+/// * `x`: `uint16` array of shape `(N, L, 16)` with the [one-hot encoding](crate::Sequence::to_one_hot_encoding) of each sequence.
+///     Positions after the end of a sequence are all `0`.
+/// * `mask`: `uint8` array of shape `(N, L)`, which is `1` for all valid positions and `0` for padding.
+/// * `lengths`: `uint32` array of shape `(N,)` with the unpadded length of each sequence.
+/// * `labels`: `int32` array of shape `(N,)` with an index into `label_names`.
+/// * `label_names`: unicode array with the mapped domain of each label index.
+/// * `ids`: unicode array of shape `(N,)` with the [`id`](crate::Sequence::id) of each sequence.
+///
+/// `L` is the length of the longest sequence, unless `max_len` is given, in which case longer sequences are truncated.
+/// The encoding of the sequences is parallelized with rayon.
+pub fn to_npz<S>(
+    path: impl AsRef<Path>,
+    data: &[LabelledSequences<S>],
+    max_len: Option<usize>,
+) -> Result<(), Error>
+where
+    S: AsRef<str> + Sync,
+{
+    let path = path.as_ref();
+
+    let (label_names, sequences) = labelled_sequences(data);
+    let padded_len = padded_len(&sequences, max_len);
+
+    // Encode each sequence into its row of the `x` and `mask` arrays
+    let rows: Vec<(Vec<u8>, Vec<u8>)> = sequences
+        .par_iter()
+        .map(|(_, seq)| {
+            let mut x = Vec::with_capacity(padded_len * ONE_HOT_WIDTH * 2);
+            let mut mask = Vec::with_capacity(padded_len);
+            let encoding: Vec<OneHotEncoding> = seq.to_one_hot_encoding();
+            for elem in encoding.iter().take(padded_len) {
+                for &v in elem {
+                    x.extend_from_slice(&v.to_le_bytes());
+                }
+                mask.push(1);
+            }
+            x.resize(padded_len * ONE_HOT_WIDTH * 2, 0);
+            mask.resize(padded_len, 0);
+            (x, mask)
+        })
+        .collect();
+
+    let (mut zip, options) = create_npz(path)?;
+    let n = sequences.len();
+    zip.start_file("x.npy", options)?;
+    write_npy_header(&mut zip, "<u2", &[n, padded_len, ONE_HOT_WIDTH])?;
+    for (x, _) in &rows {
+        zip.write_all(x)?;
+    }
+
+    zip.start_file("mask.npy", options)?;
+    write_npy_header(&mut zip, "|u1", &[n, padded_len])?;
+    for (_, mask) in &rows {
+        zip.write_all(mask)?;
+    }
+
+    write_npz_metadata(&mut zip, options, &label_names, &sequences)?;
+    zip.finish()?.flush()?;
+    Ok(())
+}
+
+/// Write all [`Sequence`]s as padded arrays of token ids into a numpy `.npz` file
+///
+/// The token ids are determined by the `vocabulary`, which must be [saved](Vocabulary::save) separately.
+/// The file contains the following arrays, where `N` is the number of sequences and `L` the padded length:
+///
+/// * `tokens`: `uint32` array of shape `(N, L)` with the token id of each element.
+///     Positions after the end of a sequence are [`PAD_TOKEN_ID`].
+/// * `lengths`, `labels`, `label_names`, and `ids`: Identical to [`to_npz`].
+///
+/// `L` is the length of the longest sequence, unless `max_len` is given, in which case longer sequences are truncated.
+pub fn to_token_npz<S>(
+    path: impl AsRef<Path>,
+    data: &[LabelledSequences<S>],
+    vocabulary: &Vocabulary,
+    max_len: Option<usize>,
+) -> Result<(), Error>
+where
+    S: AsRef<str> + Sync,
+{
+    let path = path.as_ref();
+
+    let (label_names, sequences) = labelled_sequences(data);
+    let padded_len = padded_len(&sequences, max_len);
+
+    let rows: Vec<Vec<u8>> = sequences
+        .par_iter()
+        .map(|(_, seq)| {
+            let mut tokens = Vec::with_capacity(padded_len * 4);
+            for id in vocabulary.encode(seq).into_iter().take(padded_len) {
+                tokens.extend_from_slice(&id.to_le_bytes());
+            }
+            while tokens.len() < padded_len * 4 {
+                tokens.extend_from_slice(&PAD_TOKEN_ID.to_le_bytes());
+            }
+            tokens
+        })
+        .collect();
+
+    let (mut zip, options) = create_npz(path)?;
+    zip.start_file("tokens.npy", options)?;
+    write_npy_header(&mut zip, "<u4", &[sequences.len(), padded_len])?;
+    for tokens in &rows {
+        zip.write_all(tokens)?;
+    }
+
+    write_npz_metadata(&mut zip, options, &label_names, &sequences)?;
+    zip.finish()?.flush()?;
+    Ok(())
+}
+
+/// Sorted list of all labels and all sequences with the index of their label
+fn labelled_sequences<S>(data: &[LabelledSequences<S>]) -> (Vec<&str>, Vec<(i32, &Sequence)>)
+where
+    S: AsRef<str>,
+{
+    let label_names: Vec<&str> = {
+        let mut names: Vec<_> = data
+            .iter()
+            .map(|lseq| lseq.mapped_domain.as_ref())
+            .collect();
+        names.sort_unstable();
+        names.dedup();
+        names
+    };
+    let label_index: BTreeMap<&str, i32> = label_names
+        .iter()
+        .enumerate()
+        .map(|(idx, &name)| (name, idx as i32))
+        .collect();
+
+    let sequences: Vec<_> = data
+        .iter()
+        .flat_map(|lseq| {
+            let label = label_index[lseq.mapped_domain.as_ref()];
+            lseq.sequences.iter().map(move |seq| (label, seq))
+        })
+        .collect();
+    (label_names, sequences)
+}
+
+fn padded_len(sequences: &[(i32, &Sequence)], max_len: Option<usize>) -> usize {
+    max_len.unwrap_or_else(|| {
+        sequences
+            .iter()
+            .map(|(_, seq)| seq.len())
+            .max()
+            .unwrap_or(0)
+    })
+}
+
+fn create_npz(path: &Path) -> Result<(ZipWriter<BufWriter<File>>, FileOptions), Error> {
+    // The zip format requires seeking, so misc_utils' `file_write` cannot be used here
+    let wtr =
+        File::create(path).with_context(|| format!("Cannot open file `{}`", path.display()))?;
+    let options = FileOptions::default()
+        .compression_method(CompressionMethod::Stored)
+        .large_file(true);
+    Ok((ZipWriter::new(BufWriter::new(wtr)), options))
+}
+
+/// Write the `lengths`, `labels`, `label_names`, and `ids` arrays shared by all `.npz` exports
+fn write_npz_metadata(
+    zip: &mut ZipWriter<BufWriter<File>>,
+    options: FileOptions,
+    label_names: &[&str],
+    sequences: &[(i32, &Sequence)],
+) -> Result<(), Error> {
+    let n = sequences.len();
+    zip.start_file("lengths.npy", options)?;
+    write_npy_header(zip, "<u4", &[n])?;
+    for (_, seq) in sequences {
+        zip.write_all(&(seq.len() as u32).to_le_bytes())?;
+    }
+
+    zip.start_file("labels.npy", options)?;
+    write_npy_header(zip, "<i4", &[n])?;
+    for (label, _) in sequences {
+        zip.write_all(&label.to_le_bytes())?;
+    }
+
+    zip.start_file("label_names.npy", options)?;
+    write_npy_strings(zip, label_names)?;
+
+    zip.start_file("ids.npy", options)?;
+    let ids: Vec<&str> = sequences.iter().map(|(_, seq)| seq.id()).collect();
+    write_npy_strings(zip, &ids)?;
+    Ok(())
+}
+
+/// Write all [`Sequence`](crate::Sequence)s into a Parquet file with one row per sequence
+///
+/// The file has the following columns:
+///
+/// * `true_domain` and `mapped_domain`: The labels of the [`LabelledSequences`].
+/// * `id`: The [`id`](crate::Sequence::id) of the sequence.
+/// * `elements`: List of structs with the fields `kind` (`"Size"` or `"Gap"`) and `value`.
+/// * `length`, `message_count`, `complexity`: The metadata as returned by the equally named methods on [`Sequence`](crate::Sequence).
+/// * `classification`: The [reason](crate::Sequence::classify) why a sequence is hard to classify, or null.
+///
+/// The file can be read directly with pandas, Spark, or duckdb.
+#[cfg(feature = "export_parquet")]
+pub fn to_parquet<S>(path: impl AsRef<Path>, data: &[LabelledSequences<S>]) -> Result<(), Error>
+where
+    S: AsRef<str>,
+{
+    let path = path.as_ref();
+
+    let sequences: Vec<_> = data
+        .iter()
+        .flat_map(|lseq| {
+            lseq.sequences
+                .iter()
+                .map(move |seq| (lseq.true_domain.as_ref(), lseq.mapped_domain.as_ref(), seq))
+        })
+        .collect();
+
+    let element_fields = vec![
+        Field::new("kind", DataType::Utf8, false),
+        Field::new("value", DataType::UInt16, false),
+    ];
+    let elements_type = DataType::List(Box::new(Field::new(
+        "item",
+        DataType::Struct(element_fields.clone()),
+        false,
+    )));
+    let schema = Arc::new(Schema::new(vec![
+        Field::new("true_domain", DataType::Utf8, false),
+        Field::new("mapped_domain", DataType::Utf8, false),
+        Field::new("id", DataType::Utf8, false),
+        Field::new("elements", elements_type.clone(), false),
+        Field::new("length", DataType::UInt32, false),
+        Field::new("message_count", DataType::UInt32, false),
+        Field::new("complexity", DataType::UInt32, false),
+        Field::new("classification", DataType::Utf8, true),
+    ]));
+
+    // The element lists are stored as one flat struct array and the offsets of each row into it
+    let mut offsets = Vec::with_capacity(sequences.len() + 1);
+    let mut kinds = Vec::new();
+    let mut values = Vec::new();
+    offsets.push(0i32);
+    for (_, _, seq) in &sequences {
+        for elem in seq.as_elements() {
+            let (kind, value) = match *elem {
+                SequenceElement::Size(size) => ("Size", u16::from(size)),
+                SequenceElement::Gap(gap) => ("Gap", gap),
+            };
+            kinds.push(kind);
+            values.push(value);
+        }
+        offsets.push(kinds.len() as i32);
+    }
+    let elements = StructArray::from(
+        element_fields
+            .into_iter()
+            .zip(vec![
+                Arc::new(StringArray::from(kinds)) as ArrayRef,
+                Arc::new(UInt16Array::from(values)) as ArrayRef,
+            ])
+            .collect::<Vec<_>>(),
+    );
+    let elements = ListArray::from(
+        ArrayData::builder(elements_type)
+            .len(sequences.len())
+            .add_buffer(Buffer::from_slice_ref(&offsets))
+            .add_child_data(elements.data().clone())
+            .build()?,
+    );
+
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(StringArray::from(
+            sequences.iter().map(|(td, _, _)| *td).collect::<Vec<_>>(),
+        )),
+        Arc::new(StringArray::from(
+            sequences.iter().map(|(_, md, _)| *md).collect::<Vec<_>>(),
+        )),
+        Arc::new(StringArray::from(
+            sequences
+                .iter()
+                .map(|(_, _, seq)| seq.id())
+                .collect::<Vec<_>>(),
+        )),
+        Arc::new(elements),
+        Arc::new(UInt32Array::from(
+            sequences
+                .iter()
+                .map(|(_, _, seq)| seq.len() as u32)
+                .collect::<Vec<_>>(),
+        )),
+        Arc::new(UInt32Array::from(
+            sequences
+                .iter()
+                .map(|(_, _, seq)| seq.message_count() as u32)
+                .collect::<Vec<_>>(),
+        )),
+        Arc::new(UInt32Array::from(
+            sequences
+                .iter()
+                .map(|(_, _, seq)| seq.complexity() as u32)
+                .collect::<Vec<_>>(),
+        )),
+        Arc::new(StringArray::from(
+            sequences
+                .iter()
+                .map(|(_, _, seq)| seq.classify())
+                .collect::<Vec<_>>(),
+        )),
+    ];
+    let batch = RecordBatch::try_new(schema.clone(), columns)?;
+
+    let wtr =
+        File::create(path).with_context(|| format!("Cannot open file `{}`", path.display()))?;
+    let props = WriterProperties::builder()
+        .set_compression(Compression::SNAPPY)
+        .build();
+    let mut writer = ArrowWriter::try_new(BufWriter::new(wtr), schema, Some(props))?;
+    writer.write(&batch)?;
+    writer.close()?;
+    Ok(())
+}
+
+/// Write the header of a `.npy` file in format version 1.0
+fn write_npy_header(wtr: &mut impl Write, descr: &str, shape: &[usize]) -> Result<(), Error> {
+    let shape = match shape {
+        [single] => format!("({},)", single),
+        _ => format!(
+            "({})",
+            shape
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    };
+    let mut header = format!(
+        "{{'descr': '{}', 'fortran_order': False, 'shape': {}, }}",
+        descr, shape
+    );
+    // The magic string, version, and header length take 10 bytes
+    // The total header length must be divisible by 64 and end in a newline
+    let unpadded_len = 10 + header.len() + 1;
+    header.extend(std::iter::repeat(' ').take((64 - unpadded_len % 64) % 64));
+    header.push('\n');
+
+    wtr.write_all(b"\x93NUMPY\x01\x00")?;
+    wtr.write_all(&(header.len() as u16).to_le_bytes())?;
+    wtr.write_all(header.as_bytes())?;
+    Ok(())
+}
+
+/// Write a one-dimensional `.npy` array of fixed-size unicode strings
+fn write_npy_strings(wtr: &mut impl Write, strings: &[&str]) -> Result<(), Error> {
+    let width = strings
+        .iter()
+        .map(|s| s.chars().count())
+        .max()
+        .unwrap_or(0)
+        .max(1);
+    write_npy_header(wtr, &format!("<U{}", width), &[strings.len()])?;
+    for s in strings {
+        let mut count = 0;
+        for c in s.chars() {
+            wtr.write_all(&(c as u32).to_le_bytes())?;
+            count += 1;
+        }
+        for _ in count..width {
+            wtr.write_all(&0u32.to_le_bytes())?;
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_vocabulary() -> Result<(), Error> {
+    use SequenceElement::{Gap, Size};
+
+    let data = vec![LabelledSequences {
+        true_domain: "example.com",
+        mapped_domain: "example.com",
+        sequences: vec![
+            Sequence::new(vec![Size(2), Gap(3), Size(1)], "a".to_string()),
+            Sequence::new(vec![Gap(1), Size(2)], "b".to_string()),
+        ],
+    }];
+    let vocabulary = Vocabulary::from_data(&data);
+    assert_eq!(vocabulary.size(), 6);
+    assert_eq!(vocabulary.encode(&data[0].sequences[0]), vec![3, 5, 2]);
+    assert_eq!(vocabulary.token_id(Size(7)), UNKNOWN_TOKEN_ID);
+
+    let path = std::env::temp_dir().join(format!("vocabulary-{}.txt", std::process::id()));
+    vocabulary.save(&path)?;
+    assert_eq!(
+        fs::read_to_string(&path)?,
+        "<pad>\n<unk>\nS01\nS02\nG01\nG03\n"
+    );
+    let loaded = Vocabulary::load(&path);
+    fs::remove_file(&path)?;
+    assert_eq!(loaded?, vocabulary);
+    Ok(())
+}
+
+/// Read all arrays of a `.npz` file as pairs of the `.npy` header and the raw data
+#[cfg(test)]
+fn read_npz(path: &Path) -> Result<BTreeMap<String, (String, Vec<u8>)>, Error> {
+    use std::io::Read;
+
+    let mut archive = zip::ZipArchive::new(File::open(path)?)?;
+    let mut arrays = BTreeMap::new();
+    for idx in 0..archive.len() {
+        let mut file = archive.by_index(idx)?;
+        let name = file.name().trim_end_matches(".npy").to_string();
+        let mut content = Vec::new();
+        file.read_to_end(&mut content)?;
+        assert_eq!(&content[..8], b"\x93NUMPY\x01\x00");
+        let header_len = u16::from_le_bytes([content[8], content[9]]) as usize;
+        assert_eq!(
+            (10 + header_len) % 64,
+            0,
+            "Header of {} is not aligned",
+            name
+        );
+        let header = String::from_utf8(content[10..10 + header_len].to_vec())?;
+        arrays.insert(name, (header, content[10 + header_len..].to_vec()));
+    }
+    Ok(arrays)
+}
+
+#[test]
+fn test_npz_roundtrip() -> Result<(), Error> {
+    use SequenceElement::{Gap, Size};
+
+    let data = vec![
+        LabelledSequences {
+            true_domain: "b.example",
+            mapped_domain: "b.example",
+            sequences: vec![Sequence::new(
+                vec![Size(2), Gap(3), Size(1)],
+                "x".to_string(),
+            )],
+        },
+        LabelledSequences {
+            true_domain: "a.example",
+            mapped_domain: "a.example",
+            sequences: vec![Sequence::new(vec![Gap(1)], "yy".to_string())],
+        },
+    ];
+    let u16s = |data: &[u8]| -> Vec<u16> {
+        data.chunks(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+            .collect()
+    };
+    let u32s = |data: &[u8]| -> Vec<u32> {
+        data.chunks(4)
+            .map(|c| u32::from_le_bytes([c[0], c[1], c[2], c[3]]))
+            .collect()
+    };
+    let strings = |data: &[u8], width: usize| -> Vec<String> {
+        u32s(data)
+            .chunks(width)
+            .map(|chars| {
+                chars
+                    .iter()
+                    .filter(|&&c| c != 0)
+                    .map(|&c| std::char::from_u32(c).unwrap())
+                    .collect()
+            })
+            .collect()
+    };
+
+    let path = std::env::temp_dir().join(format!("export-{}.npz", std::process::id()));
+    to_npz(&path, &data, None)?;
+    let arrays = read_npz(&path);
+    fs::remove_file(&path)?;
+    let arrays = arrays?;
+    assert_eq!(
+        arrays.keys().collect::<Vec<_>>(),
+        vec!["ids", "label_names", "labels", "lengths", "mask", "x"]
+    );
+
+    let (header, x) = &arrays["x"];
+    assert!(header.contains("'descr': '<u2'"), "{}", header);
+    assert!(header.contains("'shape': (2, 3, 16)"), "{}", header);
+    let mut expected = vec![0; 2 * 3 * 16];
+    expected[2] = 1;
+    expected[16] = 3;
+    expected[2 * 16 + 1] = 1;
+    expected[3 * 16] = 1;
+    assert_eq!(u16s(x), expected);
+
+    let (header, mask) = &arrays["mask"];
+    assert!(header.contains("'shape': (2, 3)"), "{}", header);
+    assert_eq!(mask, &[1, 1, 1, 1, 0, 0]);
+    assert_eq!(u32s(&arrays["lengths"].1), vec![3, 1]);
+    // The labels are indices into the sorted label names
+    assert_eq!(u32s(&arrays["labels"].1), vec![1, 0]);
+    let (header, label_names) = &arrays["label_names"];
+    assert!(header.contains("'descr': '<U9'"), "{}", header);
+    assert_eq!(strings(label_names, 9), vec!["a.example", "b.example"]);
+    let (header, ids) = &arrays["ids"];
+    assert!(header.contains("'shape': (2,)"), "{}", header);
+    assert_eq!(strings(ids, 2), vec!["x", "yy"]);
+
+    // Longer sequences are truncated, but keep their real length
+    to_npz(&path, &data, Some(2))?;
+    let arrays = read_npz(&path);
+    fs::remove_file(&path)?;
+    let arrays = arrays?;
+    assert!(arrays["x"].0.contains("'shape': (2, 2, 16)"));
+    assert_eq!(arrays["mask"].1, vec![1, 1, 1, 0]);
+    assert_eq!(u32s(&arrays["lengths"].1), vec![3, 1]);
+    Ok(())
+}
+
+#[cfg(feature = "export_parquet")]
+#[test]
+fn test_parquet_roundtrip() -> Result<(), Error> {
+    use arrow::array::Array;
+    use parquet::arrow::{ArrowReader, ParquetFileArrowReader};
+    use SequenceElement::{Gap, Size};
+
+    let data = vec![
+        LabelledSequences {
+            true_domain: "www.a.example",
+            mapped_domain: "a.example",
+            sequences: vec![
+                Sequence::new(vec![Size(2), Gap(3), Size(1)], "x".to_string()),
+                Sequence::new(vec![Size(1)], "y".to_string()),
+            ],
+        },
+        LabelledSequences {
+            true_domain: "b.example",
+            mapped_domain: "b.example",
+            sequences: vec![Sequence::new(vec![Gap(7), Size(4)], "z".to_string())],
+        },
+    ];
+
+    let path = std::env::temp_dir().join(format!("export-{}.parquet", std::process::id()));
+    to_parquet(&path, &data)?;
+    let batches: Result<Vec<RecordBatch>, Error> = (|| {
+        let mut reader = ParquetFileArrowReader::try_new(File::open(&path)?)?;
+        Ok(reader.get_record_reader(1024)?.collect::<Result<_, _>>()?)
+    })();
+    fs::remove_file(&path)?;
+    let batches = batches?;
+    assert_eq!(batches.len(), 1);
+    let batch = &batches[0];
+    assert_eq!(batch.num_rows(), 3);
+
+    let strings = |name: &str| -> Vec<Option<String>> {
+        let idx = batch.schema().index_of(name).unwrap();
+        let column = batch
+            .column(idx)
+            .as_any()
+            .downcast_ref::<StringArray>()
+            .unwrap();
+        (0..column.len())
+            .map(|row| {
+                if column.is_valid(row) {
+                    Some(column.value(row).to_string())
+                } else {
+                    None
+                }
+            })
+            .collect()
+    };
+    let numbers = |name: &str| -> Vec<u32> {
+        let idx = batch.schema().index_of(name).unwrap();
+        let column = batch
+            .column(idx)
+            .as_any()
+            .downcast_ref::<UInt32Array>()
+            .unwrap();
+        column.values().to_vec()
+    };
+    let some = |values: &[&str]| -> Vec<Option<String>> {
+        values.iter().map(|v| Some(v.to_string())).collect()
+    };
+    assert_eq!(
+        strings("true_domain"),
+        some(&["www.a.example", "www.a.example", "b.example"])
+    );
+    assert_eq!(
+        strings("mapped_domain"),
+        some(&["a.example", "a.example", "b.example"])
+    );
+    assert_eq!(strings("id"), some(&["x", "y", "z"]));
+    let sequences: Vec<&Sequence> = data.iter().flat_map(|lseq| &lseq.sequences).collect();
+    assert_eq!(numbers("length"), vec![3, 1, 2]);
+    assert_eq!(
+        numbers("message_count"),
+        sequences
+            .iter()
+            .map(|seq| seq.message_count() as u32)
+            .collect::<Vec<_>>()
+    );
+    assert_eq!(
+        numbers("complexity"),
+        sequences
+            .iter()
+            .map(|seq| seq.complexity() as u32)
+            .collect::<Vec<_>>()
+    );
+    assert_eq!(
+        strings("classification"),
+        sequences
+            .iter()
+            .map(|seq| seq.classify().map(ToString::to_string))
+            .collect::<Vec<_>>()
+    );
+
+    let idx = batch.schema().index_of("elements").unwrap();
+    let elements = batch
+        .column(idx)
+        .as_any()
+        .downcast_ref::<ListArray>()
+        .unwrap();
+    for (row, seq) in sequences.iter().enumerate() {
+        let row = elements.value(row);
+        let row = row.as_any().downcast_ref::<StructArray>().unwrap();
+        let kinds = row
+            .column(0)
+            .as_any()
+            .downcast_ref::<StringArray>()
+            .unwrap();
+        let values = row
+            .column(1)
+            .as_any()
+            .downcast_ref::<UInt16Array>()
+            .unwrap();
+        let read: Vec<SequenceElement> = (0..row.len())
+            .map(|idx| match kinds.value(idx) {
+                "Size" => Size(values.value(idx) as u8),
+                "Gap" => Gap(values.value(idx)),
+                kind => panic!("Unknown element kind {}", kind),
+            })
+            .collect();
+        assert_eq!(read, seq.as_elements());
+    }
+    Ok(())
+}
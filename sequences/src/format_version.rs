@@ -0,0 +1,117 @@
+//! Versioning of the JSON formats of [`Sequence`](crate::Sequence), [`PrecisionSequence`](crate::PrecisionSequence), and [`LabelledSequences`](crate::knn::LabelledSequences)
+//!
+//! All types write the current [`FORMAT_VERSION`] and their [`Deserialize`](serde::Deserialize) implementations only accept this version.
+//! Files written by older versions of this crate do not contain a version and are treated as version 1.
+//! They can be loaded with [`from_json_any_version`], which migrates them to the current layout first.
+//!
+//! Version history:
+//!
+//! 1. No version field.
+//!     [`Sequence`](crate::Sequence) is a map with the identifier as the only key,
+//!     [`PrecisionSequence`](crate::PrecisionSequence) is a list of the events and the identifier.
+//! 2. All types contain a version field.
+//!     [`PrecisionSequence`](crate::PrecisionSequence) is a map with the keys `id` and `events`.
+
+use anyhow::{anyhow, bail, Context as _, Error};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+/// Version of the JSON formats written by this crate
+pub const FORMAT_VERSION: u32 = 2;
+
+/// A type with a versioned JSON format, which can be migrated from older versions
+pub trait VersionedFormat: DeserializeOwned {
+    /// Convert a JSON value of any supported format version into the layout of [`FORMAT_VERSION`]
+    fn migrate(value: Value) -> Result<Value, Error>;
+}
+
+impl<T: VersionedFormat> VersionedFormat for Vec<T> {
+    fn migrate(value: Value) -> Result<Value, Error> {
+        match value {
+            Value::Array(values) => values
+                .into_iter()
+                .enumerate()
+                .map(|(idx, value)| {
+                    T::migrate(value).with_context(|| format!("Invalid entry at index {}", idx))
+                })
+                .collect::<Result<_, _>>()
+                .map(Value::Array),
+            _ => bail!("Expected a JSON list"),
+        }
+    }
+}
+
+/// Parse JSON in the current or any older format version
+///
+/// Data written by a newer version of this crate results in an error.
+pub fn from_json_any_version<T: VersionedFormat>(s: &str) -> Result<T, Error> {
+    let value = serde_json::from_str(s)?;
+    Ok(serde_json::from_value(T::migrate(value)?)?)
+}
+
+/// Read the format version stored under `key`
+///
+/// Returns `1` if `value` is not a map or if the key is missing, since the first format did not contain a version.
+/// Versions newer than [`FORMAT_VERSION`] are rejected.
+pub(crate) fn read_format_version(value: &Value, key: &str) -> Result<u32, Error> {
+    let version = match value.get(key) {
+        None => 1,
+        Some(version) => version
+            .as_u64()
+            .ok_or_else(|| anyhow!("The format version must be a number, but is `{}`", version))?
+            as u32,
+    };
+    if version > FORMAT_VERSION {
+        bail!(
+            "Format version {} is newer than the supported version {}",
+            version,
+            FORMAT_VERSION
+        );
+    }
+    Ok(version)
+}
+
+/// Check the format version during deserialization
+pub(crate) fn check_format_version<E: serde::de::Error>(version: Option<u32>) -> Result<(), E> {
+    match version {
+        Some(FORMAT_VERSION) => Ok(()),
+        Some(version) => Err(E::custom(format_args!(
+            "Unsupported format version {}, expected {}",
+            version, FORMAT_VERSION
+        ))),
+        None => Err(E::custom(
+            "Missing format version, use `from_json_any_version` to load data written in an old format",
+        )),
+    }
+}
+
+#[test]
+fn test_migrate_version_1() {
+    use crate::{knn::LabelledSequences, PrecisionSequence, SequenceElement::*};
+
+    let pseq: PrecisionSequence = from_json_any_version(
+        r#"[[{"time": "2019-01-01T00:00:00", "size": 128, "is_dummy_event": false}], "id"]"#,
+    )
+    .unwrap();
+    assert_eq!("id", pseq.id());
+    assert_eq!(1, pseq.count_queries());
+
+    let v1 = r#"[{"true_domain": "a.com", "mapped_domain": "b.com", "sequences": [{"id": ["S01", "G02", "S01"]}]}]"#;
+    assert!(serde_json::from_str::<Vec<LabelledSequences<String>>>(v1).is_err());
+    let lseqs: Vec<LabelledSequences<String>> = from_json_any_version(v1).unwrap();
+    assert_eq!(1, lseqs.len());
+    assert_eq!("a.com", lseqs[0].true_domain);
+    assert_eq!("b.com", lseqs[0].mapped_domain);
+    assert_eq!(
+        &[Size(1), Gap(2), Size(1)],
+        lseqs[0].sequences[0].as_elements()
+    );
+
+    // The current format roundtrips without changes
+    let json = serde_json::to_string(&lseqs).unwrap();
+    assert_eq!(
+        lseqs,
+        from_json_any_version::<Vec<LabelledSequences<String>>>(&json).unwrap()
+    );
+    assert_eq!(lseqs, serde_json::from_str::<Vec<_>>(&json).unwrap());
+}
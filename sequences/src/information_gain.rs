@@ -0,0 +1,228 @@
+//! Mutual information between the features of [`Sequence`]s and their labels
+//!
+//! The analysis shows how much information about the visited website each feature leaks, which indicates which features a defense needs to hide.
+//! The mutual information is calculated with the plug-in estimator from the empirical distributions.
+//! It overestimates the mutual information for features with many distinct values compared to the number of samples.
+
+use crate::{knn::LabelledSequences, Sequence, SequenceElement};
+use serde::Serialize;
+use std::{collections::HashMap, hash::Hash};
+
+/// Feature of a [`Sequence`] for which the information gain is measured
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Serialize)]
+pub enum Feature {
+    /// Size of the n-th message
+    Size,
+    /// Gap between the n-th and the (n+1)-th message
+    ///
+    /// A missing [`SequenceElement::Gap`] between two messages counts as `Gap(0)`.
+    Gap,
+    /// Number of messages in the [`Sequence`]
+    Count,
+}
+
+/// Mutual information between a single [`Feature`] and the labels
+#[derive(Clone, PartialEq, Debug, Serialize)]
+pub struct InformationGain {
+    pub feature: Feature,
+    /// Position of the message, if the [`Feature`] is specific to a message
+    pub position: Option<usize>,
+    /// Mutual information between feature and label in bits
+    pub mutual_information: f64,
+    /// Entropy of the labels in bits, which is the upper bound of the mutual information
+    pub label_entropy: f64,
+    /// Number of [`Sequence`]s containing the message at this position
+    pub samples: usize,
+}
+
+/// Message sizes and gaps of a [`Sequence`] indexed by the message position
+struct MessageFeatures {
+    sizes: Vec<u8>,
+    gaps: Vec<u16>,
+}
+
+impl MessageFeatures {
+    fn new(sequence: &Sequence) -> Self {
+        let mut sizes = Vec::new();
+        let mut gaps = Vec::new();
+        for elem in sequence.as_elements() {
+            match *elem {
+                SequenceElement::Size(size) => {
+                    // There was no explicit gap before this message
+                    if gaps.len() < sizes.len() {
+                        gaps.push(0);
+                    }
+                    sizes.push(size);
+                }
+                // Ignore gaps before the first message
+                SequenceElement::Gap(gap) if !sizes.is_empty() => gaps.push(gap),
+                SequenceElement::Gap(_) => {}
+            }
+        }
+        // The gap after the last message is undefined
+        gaps.truncate(sizes.len().saturating_sub(1));
+        Self { sizes, gaps }
+    }
+}
+
+/// Calculate the information gain of each [`Feature`] for the `data`
+///
+/// The labels are the `mapped_domain`s.
+/// The [`Feature::Size`] and [`Feature::Gap`] are evaluated for the first `max_positions` messages.
+/// A [`Sequence`] contributes to a position only if it has a message at this position, thus later positions are based on fewer samples.
+pub fn information_gain<S>(
+    data: &[LabelledSequences<S>],
+    max_positions: usize,
+) -> Vec<InformationGain>
+where
+    S: Eq + Hash,
+{
+    let samples: Vec<(&S, MessageFeatures)> = data
+        .iter()
+        .flat_map(|lseq| {
+            lseq.sequences
+                .iter()
+                .map(move |seq| (&lseq.mapped_domain, MessageFeatures::new(seq)))
+        })
+        .collect();
+
+    let mut res = Vec::new();
+    let (mutual_information, label_entropy) = mutual_information(
+        samples
+            .iter()
+            .map(|(label, features)| (features.sizes.len(), *label)),
+    );
+    res.push(InformationGain {
+        feature: Feature::Count,
+        position: None,
+        mutual_information,
+        label_entropy,
+        samples: samples.len(),
+    });
+
+    for position in 0..max_positions {
+        let sizes: Vec<_> = samples
+            .iter()
+            .filter_map(|(label, features)| features.sizes.get(position).map(|&s| (s, *label)))
+            .collect();
+        if sizes.is_empty() {
+            break;
+        }
+        let (mutual_information, label_entropy) = mutual_information(sizes.iter().copied());
+        res.push(InformationGain {
+            feature: Feature::Size,
+            position: Some(position),
+            mutual_information,
+            label_entropy,
+            samples: sizes.len(),
+        });
+
+        let gaps: Vec<_> = samples
+            .iter()
+            .filter_map(|(label, features)| features.gaps.get(position).map(|&g| (g, *label)))
+            .collect();
+        if !gaps.is_empty() {
+            let (mutual_information, label_entropy) = mutual_information(gaps.iter().copied());
+            res.push(InformationGain {
+                feature: Feature::Gap,
+                position: Some(position),
+                mutual_information,
+                label_entropy,
+                samples: gaps.len(),
+            });
+        }
+    }
+
+    res
+}
+
+/// Calculate the mutual information I(X; Y) and the entropy H(Y) in bits from samples of (X, Y)
+fn mutual_information<X, Y>(samples: impl IntoIterator<Item = (X, Y)>) -> (f64, f64)
+where
+    X: Eq + Hash + Copy,
+    Y: Eq + Hash + Copy,
+{
+    let mut joint: HashMap<(X, Y), usize> = HashMap::new();
+    let mut xs: HashMap<X, usize> = HashMap::new();
+    let mut ys: HashMap<Y, usize> = HashMap::new();
+    let mut total = 0;
+    for (x, y) in samples {
+        *joint.entry((x, y)).or_default() += 1;
+        *xs.entry(x).or_default() += 1;
+        *ys.entry(y).or_default() += 1;
+        total += 1;
+    }
+    if total == 0 {
+        return (0., 0.);
+    }
+
+    let total = total as f64;
+    let label_entropy = -ys
+        .values()
+        .map(|&count| {
+            let p = count as f64 / total;
+            p * p.log2()
+        })
+        .sum::<f64>();
+    let mutual_information = joint
+        .iter()
+        .map(|(&(x, y), &count)| {
+            let p_xy = count as f64 / total;
+            let p_x = xs[&x] as f64 / total;
+            let p_y = ys[&y] as f64 / total;
+            p_xy * (p_xy / (p_x * p_y)).log2()
+        })
+        .sum::<f64>()
+        // Rounding errors can result in tiny negative values
+        .max(0.);
+    (mutual_information, label_entropy)
+}
+
+#[test]
+fn test_information_gain() {
+    use SequenceElement::{Gap, Size};
+
+    let lseq = |label: &'static str, seqs: Vec<Vec<SequenceElement>>| LabelledSequences {
+        true_domain: label,
+        mapped_domain: label,
+        sequences: seqs
+            .into_iter()
+            .map(|seq| Sequence::new(seq, label.to_string()))
+            .collect(),
+    };
+    // The sizes and the count are identical for both labels, only the gaps differ
+    let data = vec![
+        lseq(
+            "a.com",
+            vec![
+                vec![Size(1), Gap(5), Size(2)],
+                vec![Size(1), Gap(5), Size(2)],
+            ],
+        ),
+        lseq(
+            "b.com",
+            vec![vec![Size(1), Size(2)], vec![Size(1), Size(2)]],
+        ),
+    ];
+
+    let res = information_gain(&data, 10);
+    assert_eq!(4, res.len());
+    for ig in &res {
+        assert!((ig.label_entropy - 1.).abs() < 1e-9);
+        let expected = if ig.feature == Feature::Gap { 1. } else { 0. };
+        assert!(
+            (ig.mutual_information - expected).abs() < 1e-9,
+            "Unexpected information gain {:?}",
+            ig
+        );
+    }
+    assert_eq!(Feature::Count, res[0].feature);
+    assert_eq!(
+        (Feature::Gap, Some(0), 4),
+        (res[2].feature, res[2].position, res[2].samples)
+    );
+    assert_eq!(
+        (Feature::Size, Some(1), 4),
+        (res[3].feature, res[3].position, res[3].samples)
+    );
+}
@@ -1,23 +1,46 @@
+pub mod adaptive_padding;
+pub mod augment;
 mod constants;
+pub mod conversion_cache;
 pub mod dnstap;
+#[cfg(feature = "export")]
+pub mod export;
+pub mod format_version;
+pub mod information_gain;
 pub mod load_sequence;
 #[cfg(feature = "read_pcap")]
 pub mod pcap;
 pub mod precision_sequence;
+pub mod scenario;
 mod sequence;
 mod utils;
 
 pub use crate::{
     constants::common_sequence_classifications,
+    format_version::from_json_any_version,
     load_sequence::{
-        convert_to_sequence, GapMode, LoadSequenceConfig, Padding, SimulatedCountermeasure,
+        convert_to_sequence, try_convert_to_sequence, FilterReason, FilteredSequences, GapMode,
+        LoadSequenceConfig, MarkerPolicy, NoiseMechanism, Padding, PageLoadSegmentation,
+        SimulatedCountermeasure, TlsOverheadModel, TruncationMode,
     },
     precision_sequence::PrecisionSequence,
-    sequence::{distance_cost_info, knn, OneHotEncoding, Sequence, SequenceElement},
-    utils::{load_all_files_with_extension_from_dir_with_config, Probability},
+    sequence::{
+        distance_cost_info, element_histogram, knn, vantage_point_from_path, ElementHistogram,
+        OneHotEncoding, RunLengthSequence, Sequence, SequenceElement, SequenceMetadata,
+        DEFAULT_VANTAGE_POINT,
+    },
+    utils::{
+        load_all_files_with_extension_from_dir_with_cache,
+        load_all_files_with_extension_from_dir_with_config, load_sequence_directory,
+        load_sequence_directory_with_cache, normalize_sequence_id, sequence_directories,
+        sequence_id_to_path, Probability,
+    },
 };
 use chrono::NaiveDateTime;
 
+/// Version of this crate, e.g., to record it alongside experiment results
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
 /// Interaperability type used when building sequences
 #[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
 pub struct AbstractQueryResponse {
@@ -35,13 +58,63 @@ pub fn sequence_stats(
     sequences_a: &[Sequence],
     sequences_b: &[Sequence],
 ) -> (Vec<usize>, Vec<usize>, usize, usize) {
-    let dists: Vec<Vec<usize>> = sequences_a
+    sequence_stats_with_metric(sequences_a, sequences_b, knn::DistanceMetric::EditDistance)
+}
+
+/// Same as [`sequence_stats`] but with a configurable [`DistanceMetric`](knn::DistanceMetric)
+pub fn sequence_stats_with_metric(
+    sequences_a: &[Sequence],
+    sequences_b: &[Sequence],
+    metric: knn::DistanceMetric,
+) -> (Vec<usize>, Vec<usize>, usize, usize) {
+    sequence_stats_from_distances(
+        sequences_a,
+        sequences_b,
+        &distance_matrix(sequences_a, sequences_b, metric),
+    )
+}
+
+/// Calculate the distances between all pairs of `sequences_a` and `sequences_b`
+///
+/// The result contains one row per sequence of `sequences_a`.
+/// Identical sequences have a distance of 0 without calculating it.
+pub fn distance_matrix(
+    sequences_a: &[Sequence],
+    sequences_b: &[Sequence],
+    metric: knn::DistanceMetric,
+) -> Vec<Vec<usize>> {
+    sequences_a
         .iter()
         .map(|seq| {
             sequences_b
                 .iter()
-                .filter(|other_seq| seq != *other_seq)
-                .map(|other_seq| seq.distance(other_seq))
+                .map(|other_seq| {
+                    if seq == other_seq {
+                        0
+                    } else {
+                        metric.distance(seq, other_seq)
+                    }
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// Same as [`sequence_stats`] but with the precomputed `distances` of [`distance_matrix`]
+pub fn sequence_stats_from_distances(
+    sequences_a: &[Sequence],
+    sequences_b: &[Sequence],
+    distances: &[Vec<usize>],
+) -> (Vec<usize>, Vec<usize>, usize, usize) {
+    let dists: Vec<Vec<usize>> = sequences_a
+        .iter()
+        .zip(distances)
+        .map(|(seq, row)| {
+            sequences_b
+                .iter()
+                .zip(row)
+                .filter(|(other_seq, _)| seq != *other_seq)
+                .map(|(_, &distance)| distance)
                 .collect()
         })
         .collect();
@@ -1,9 +1,30 @@
 use crate::{
     precision_sequence::PrecisionSequence, AbstractQueryResponse, Sequence, SequenceElement,
 };
-use anyhow::{bail, Error};
+use anyhow::{bail, Context as _, Error};
 use chrono::Duration;
-use std::str::FromStr;
+pub use dnstap::MarkerPolicy;
+use fnv::FnvHasher;
+use ordered_float::NotNan;
+use rand::{distributions::Open01, Rng, SeedableRng};
+use rand_xorshift::XorShiftRng;
+use serde_with::{DeserializeFromStr, SerializeDisplay};
+use std::{
+    f64::consts::PI,
+    fmt::{self, Display},
+    hash::{Hash, Hasher},
+    ops::AddAssign,
+    str::FromStr,
+};
+
+/// The δ parameter of the [`NoiseMechanism::Gaussian`] mechanism
+const GAUSSIAN_DELTA: f64 = 1e-5;
+/// Length used when parsing [`SimulatedCountermeasure::ConstantLength`] without a length
+const DEFAULT_CONSTANT_LENGTH: usize = 50;
+/// Epsilon used when parsing [`SimulatedCountermeasure::DifferentialPrivacy`] without an epsilon
+const DEFAULT_DP_EPSILON: f64 = 1.0;
+/// Minimal increase used when parsing [`PageLoadSegmentation::ChangePoint`] without a parameter
+const DEFAULT_CHANGE_POINT_INCREASE: u8 = 3;
 
 /// Specifies how to load data into a [`Sequence`] and which processing steps to perform
 #[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Default)]
@@ -11,10 +32,154 @@ pub struct LoadSequenceConfig {
     pub padding: Padding,
     pub gap_mode: GapMode,
     pub simulated_countermeasure: SimulatedCountermeasure,
+    pub tls_overhead: TlsOverheadModel,
+    pub truncation: TruncationMode,
+    /// Expected marker messages in dnstap files
+    pub marker_policy: MarkerPolicy,
+    /// Remove trailing traffic after the initial page load
+    pub segmentation: PageLoadSegmentation,
+    /// Skip [`Sequence`]s with fewer messages, e.g., parked domains or pages with a single query
+    ///
+    /// The length and complexity filters are applied to the messages before the [`SimulatedCountermeasure`], see [`try_convert_to_sequence`].
+    /// Otherwise, countermeasures like [`SimulatedCountermeasure::ConstantLength`] would hide the original length.
+    pub min_length: Option<usize>,
+    /// Skip [`Sequence`]s with more messages
+    pub max_length: Option<usize>,
+    /// Skip [`Sequence`]s with a lower [`Sequence::complexity`]
+    pub min_complexity: Option<usize>,
+}
+
+impl LoadSequenceConfig {
+    /// Return if any of the length and complexity filters is set
+    pub fn has_filters(&self) -> bool {
+        self.min_length.is_some() || self.max_length.is_some() || self.min_complexity.is_some()
+    }
+
+    /// Same configuration without the length and complexity filters
+    ///
+    /// The filters only decide which [`Sequence`]s are kept, but do not influence how a kept [`Sequence`] looks like.
+    pub fn without_filters(self) -> Self {
+        Self {
+            min_length: None,
+            max_length: None,
+            min_complexity: None,
+            ..self
+        }
+    }
+
+    /// Check a [`Sequence`] with `length` messages and the given `complexity` against the length and complexity filters
+    ///
+    /// If multiple filters apply, the first of `min_length`, `max_length`, and `min_complexity` is reported.
+    pub fn check_filters(&self, length: usize, complexity: usize) -> Result<(), FilterReason> {
+        if self.min_length.map_or(false, |min| length < min) {
+            Err(FilterReason::TooShort)
+        } else if self.max_length.map_or(false, |max| length > max) {
+            Err(FilterReason::TooLong)
+        } else if self.min_complexity.map_or(false, |min| complexity < min) {
+            Err(FilterReason::TooSimple)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Remove all already loaded [`Sequence`]s, which do not pass the length and complexity filters
+    ///
+    /// This is meant for [`Sequence`]s, which are not loaded from raw captures, e.g., preprocessed JSON files.
+    /// The length of a [`Sequence`] is its [`Sequence::message_count`].
+    /// Returns how many [`Sequence`]s are removed by which filter, see [`LoadSequenceConfig::check_filters`].
+    pub fn filter_sequences(&self, sequences: &mut Vec<Sequence>) -> FilteredSequences {
+        let mut filtered = FilteredSequences::default();
+        if !self.has_filters() {
+            return filtered;
+        }
+        sequences.retain(
+            |seq| match self.check_filters(seq.message_count(), seq.complexity()) {
+                Ok(()) => true,
+                Err(reason) => {
+                    filtered.add(reason);
+                    false
+                }
+            },
+        );
+        filtered
+    }
+}
+
+/// Filter of [`LoadSequenceConfig`], which removed a [`Sequence`]
+///
+/// Loading a [`Sequence`] fails with this error, if it does not pass the filters.
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub enum FilterReason {
+    /// Fewer messages than [`LoadSequenceConfig::min_length`]
+    TooShort,
+    /// More messages than [`LoadSequenceConfig::max_length`]
+    TooLong,
+    /// Lower complexity than [`LoadSequenceConfig::min_complexity`]
+    TooSimple,
+}
+
+impl Display for FilterReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self {
+            Self::TooShort => "fewer messages than the minimal length",
+            Self::TooLong => "more messages than the maximal length",
+            Self::TooSimple => "lower complexity than the minimal complexity",
+        };
+        write!(f, "The sequence is filtered, since it has {}", reason)
+    }
+}
+
+impl std::error::Error for FilterReason {}
+
+/// Number of [`Sequence`]s removed by the filters of [`LoadSequenceConfig`]
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug, Default)]
+pub struct FilteredSequences {
+    /// Fewer messages than [`LoadSequenceConfig::min_length`]
+    pub too_short: usize,
+    /// More messages than [`LoadSequenceConfig::max_length`]
+    pub too_long: usize,
+    /// Lower complexity than [`LoadSequenceConfig::min_complexity`]
+    pub too_simple: usize,
+}
+
+impl FilteredSequences {
+    /// Count one [`Sequence`] removed by the filter `reason`
+    pub fn add(&mut self, reason: FilterReason) {
+        match reason {
+            FilterReason::TooShort => self.too_short += 1,
+            FilterReason::TooLong => self.too_long += 1,
+            FilterReason::TooSimple => self.too_simple += 1,
+        }
+    }
+
+    /// Total number of removed [`Sequence`]s
+    pub fn total(&self) -> usize {
+        self.too_short + self.too_long + self.too_simple
+    }
+}
+
+impl AddAssign for FilteredSequences {
+    fn add_assign(&mut self, other: Self) {
+        self.too_short += other.too_short;
+        self.too_long += other.too_long;
+        self.too_simple += other.too_simple;
+    }
+}
+
+impl Display for FilteredSequences {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} too short, {} too long, {} too simple",
+            self.too_short, self.too_long, self.too_simple
+        )
+    }
 }
 
 /// Specify padding strategy to use
-#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+#[derive(
+    Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, SerializeDisplay, DeserializeFromStr,
+)]
 pub enum Padding {
     ///  \[DEFAULT\]
     Q128R468,
@@ -26,19 +191,235 @@ impl Default for Padding {
     }
 }
 
+impl Padding {
+    /// Block size in bytes to which queries (`is_query = true`) or responses are padded
+    pub fn block_size(self, is_query: bool) -> u32 {
+        match (self, is_query) {
+            (Self::Q128R468, true) => 128,
+            (Self::Q128R468, false) => 468,
+        }
+    }
+}
+
+impl Display for Padding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Q128R468 => write!(f, "Q128R468"),
+        }
+    }
+}
+
 impl FromStr for Padding {
     type Err = Error;
 
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match &*s.to_ascii_lowercase() {
+            "q128r468" => Ok(Self::Q128R468),
+            _ => bail!("Unknown padding '{}'. Supported is 'Q128R468'.", s),
+        }
+    }
+}
+
+/// Specifies how many bytes of a TLS record are overhead and not part of the DNS message
+///
+/// This is only used when loading [`Sequence`]s from TLS records, e.g., from pcap files.
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+pub enum TlsOverheadModel {
+    /// Subtract a constant number of bytes from each TLS record
+    ///
+    /// The default of 40 bytes is the historical value and matches neither TLS 1.2 nor TLS 1.3 exactly. \[DEFAULT\]
+    Constant(u32),
+    /// Determine the overhead from the negotiated TLS version and cipher suite
+    ///
+    /// This accounts for the authentication tag, the explicit nonce of TLS 1.2 AEAD ciphers, the inner content type byte of TLS 1.3, and the 2 byte length prefix of DNS over TLS.
+    /// The `fallback` is used if the connection uses an unknown cipher suite or if no ServerHello was observed.
+    Negotiated { fallback: u32 },
+}
+
+impl Default for TlsOverheadModel {
+    fn default() -> Self {
+        Self::Constant(40)
+    }
+}
+
+impl FromStr for TlsOverheadModel {
+    type Err = Error;
+
+    /// Parse either a number for [`TlsOverheadModel::Constant`] or `negotiated[:<fallback>]`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fallback = match Self::default() {
+            Self::Constant(overhead) => overhead,
+            Self::Negotiated { fallback } => fallback,
+        };
+        match s.split_once(':') {
+            None if s.eq_ignore_ascii_case("negotiated") => Ok(Self::Negotiated { fallback }),
+            Some((model, fallback)) if model.eq_ignore_ascii_case("negotiated") => {
+                Ok(Self::Negotiated {
+                    fallback: fallback.parse()?,
+                })
+            }
+            _ => match s.parse() {
+                Ok(overhead) => Ok(Self::Constant(overhead)),
+                Err(_) => bail!("Unknown TLS overhead model: '{}'", s),
+            },
+        }
+    }
+}
+
+/// Detection of the end of the initial page load within a trace
+///
+/// Some pages keep sending DNS queries after they finished loading, e.g., for server push, long polling, or keepalives.
+/// These trailing queries differ between collection runs and only add noise to the classification.
+///
+/// The string representation is `None` or `ChangePoint:<n>`, where the parameter defaults to 3 when parsing.
+#[derive(
+    Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, SerializeDisplay, DeserializeFromStr,
+)]
+pub enum PageLoadSegmentation {
+    /// Keep the whole trace \[DEFAULT\]
+    None,
+    /// Truncate the trace at a change-point in the gaps between messages
+    ///
+    /// The gaps are compared on a log2 scale of milliseconds.
+    /// A split is only considered, if the gap at the split and the mean of the following gaps exceed the mean gap of the page load by at least `n`, i.e., they are `2^n` times longer.
+    /// Out of these, the split with the smallest squared error of both parts is chosen.
+    ChangePoint(u8),
+}
+
+impl Default for PageLoadSegmentation {
+    fn default() -> Self {
+        Self::None
+    }
+}
+
+impl PageLoadSegmentation {
+    /// Return the number of `messages` belonging to the initial page load
+    ///
+    /// The `messages` must be ordered by time.
+    pub fn page_load_end(self, messages: &[AbstractQueryResponse]) -> usize {
+        let min_increase = match self {
+            Self::None => return messages.len(),
+            Self::ChangePoint(min_increase) => f64::from(min_increase),
+        };
+
+        // `gaps[i]` is the gap between the messages `i` and `i + 1`
+        let gaps: Vec<f64> = messages
+            .windows(2)
+            .map(|window| {
+                let micros = (window[1].time - window[0].time)
+                    .num_microseconds()
+                    .unwrap_or(i64::MAX);
+                (micros as f64 / 1000.).max(1.).log2()
+            })
+            .collect();
+        // Prefix sums allow calculating the mean and squared error of each part in constant time
+        let mut sums = vec![(0., 0.)];
+        for gap in &gaps {
+            let (sum, sum_sq) = sums[sums.len() - 1];
+            sums.push((sum + gap, sum_sq + gap * gap));
+        }
+        let mean = |from: usize, to: usize| {
+            if from == to {
+                0.
+            } else {
+                (sums[to].0 - sums[from].0) / (to - from) as f64
+            }
+        };
+        let squared_error = |from: usize, to: usize| {
+            if from == to {
+                0.
+            } else {
+                let sum = sums[to].0 - sums[from].0;
+                (sums[to].1 - sums[from].1) - sum * sum / (to - from) as f64
+            }
+        };
+
+        let mut best: Option<(f64, usize)> = None;
+        // Splitting at `split` keeps the messages up to and including `split`
+        // The page load needs at least one gap, otherwise its mean is meaningless and every large gap would qualify
+        for split in 1..gaps.len() {
+            let page_load_mean = mean(0, split);
+            if gaps[split] - page_load_mean < min_increase
+                || mean(split, gaps.len()) - page_load_mean < min_increase
+            {
+                continue;
+            }
+            let cost = squared_error(0, split) + squared_error(split, gaps.len());
+            if best.map_or(true, |(best_cost, _)| cost < best_cost) {
+                best = Some((cost, split));
+            }
+        }
+        best.map_or(messages.len(), |(_, split)| split + 1)
+    }
+}
+
+impl Display for PageLoadSegmentation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::None => write!(f, "None"),
+            Self::ChangePoint(min_increase) => write!(f, "ChangePoint:{}", min_increase),
+        }
+    }
+}
+
+impl FromStr for PageLoadSegmentation {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, param) = match s.split_once(':') {
+            Some((name, param)) => (name, Some(param.trim())),
+            None => (s, None),
+        };
+        match (&*name.trim().to_ascii_lowercase(), param) {
+            ("none", None) => Ok(Self::None),
+            ("changepoint", None) => Ok(Self::ChangePoint(DEFAULT_CHANGE_POINT_INCREASE)),
+            ("changepoint", Some(min_increase)) => Ok(Self::ChangePoint(
+                min_increase
+                    .parse()
+                    .with_context(|| format!("Invalid minimal increase in '{}'", s))?,
+            )),
+            _ => bail!(
+                "Unknown page load segmentation '{}'. Supported are 'None' and 'ChangePoint[:<n>]'.",
+                s
+            ),
+        }
+    }
+}
+
+/// Specifies how to handle truncated packets in pcap files, e.g., due to a limited snaplen
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+pub enum TruncationMode {
+    /// Fail loading the file \[DEFAULT\]
+    Strict,
+    /// Process the available data and fill the missing bytes of each packet with zeros
+    ///
+    /// This works as long as the TLS record headers are contained in the captured bytes.
+    /// The number of truncated packets is reported in the [`PcapSummary`](crate::pcap::PcapSummary).
+    Lenient,
+}
+
+impl Default for TruncationMode {
+    fn default() -> Self {
+        Self::Strict
+    }
+}
+
+impl FromStr for TruncationMode {
+    type Err = Error;
+
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s {
-            "Q128R468" | "q128r468" => Ok(Self::Q128R468),
+            "Strict" | "strict" => Ok(Self::Strict),
+            "Lenient" | "lenient" => Ok(Self::Lenient),
             unkwn => bail!("Unknown variant: '{}'", unkwn),
         }
     }
 }
 
 /// Specifies how time should be converted into gaps
-#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+#[derive(
+    Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, SerializeDisplay, DeserializeFromStr,
+)]
 pub enum GapMode {
     /// Convert time based on the log2 function \[DEFAULT\]
     Log2,
@@ -52,20 +433,40 @@ impl Default for GapMode {
     }
 }
 
+impl Display for GapMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Log2 => write!(f, "Log2"),
+            Self::Ident => write!(f, "Ident"),
+        }
+    }
+}
+
 impl FromStr for GapMode {
     type Err = Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s {
-            "Log2" | "log2" => Ok(Self::Log2),
-            "Ident" | "ident" => Ok(Self::Ident),
-            unkwn => bail!("Unknown variant: '{}'", unkwn),
+        match &*s.to_ascii_lowercase() {
+            "log2" => Ok(Self::Log2),
+            "ident" => Ok(Self::Ident),
+            _ => bail!(
+                "Unknown gap mode '{}'. Supported are 'Log2' and 'Ident'.",
+                s
+            ),
         }
     }
 }
 
 /// Simulate different countermeasures while loading the [Sequence] data
-#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+///
+/// The string representation is the variant name, e.g., `PerfectTiming`, with the parameters separated by `:`.
+/// [`SimulatedCountermeasure::ConstantLength`] is written as `ConstantLength:<n>`,
+/// [`SimulatedCountermeasure::DifferentialPrivacy`] as `DpLaplace:<epsilon>` or `DpGaussian:<epsilon>`.
+/// When parsing, the parameters are optional and default to a length of 50 and an epsilon of 1.
+/// `Normal` is accepted as an alias for [`SimulatedCountermeasure::None`].
+#[derive(
+    Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, SerializeDisplay, DeserializeFromStr,
+)]
 pub enum SimulatedCountermeasure {
     /// Do not apply any post-processing steps
     None,
@@ -77,6 +478,27 @@ pub enum SimulatedCountermeasure {
     ///
     /// This removes all [`SequenceElement::Gap`] from the [`Sequence`].
     PerfectTiming,
+    /// Assume perfect padding and perfect timing defenses
+    ///
+    /// This removes all [`SequenceElement::Gap`] and replaces each message by a `Size(1)`, such that only the number of messages remains.
+    PerfectPaddingAndTiming,
+    /// Only keep how many messages of each size exist
+    ///
+    /// This removes all [`SequenceElement::Gap`] and sorts the [`SequenceElement::Size`]s, which hides the order of the messages.
+    CountOnly,
+    /// Truncate or pad each [`Sequence`] to exactly `n` [`SequenceElement`]s
+    ///
+    /// Missing elements are filled with `Size(1)`, i.e., dummy messages of a single block.
+    ConstantLength(usize),
+    /// Perturb the sizes and the time between messages with noise calibrated for ε-differential privacy
+    ///
+    /// The sensitivity is one padding block for sizes and one base gap (1 ms) for the time between messages.
+    /// A smaller `epsilon` results in more noise.
+    /// The noise is seeded with the identifier, such that loading the same data twice yields the same [`Sequence`].
+    DifferentialPrivacy {
+        mechanism: NoiseMechanism,
+        epsilon: NotNan<f64>,
+    },
 }
 
 impl Default for SimulatedCountermeasure {
@@ -85,36 +507,224 @@ impl Default for SimulatedCountermeasure {
     }
 }
 
+impl SimulatedCountermeasure {
+    /// Create a [`SimulatedCountermeasure::DifferentialPrivacy`] after validating `epsilon`
+    pub fn differential_privacy(mechanism: NoiseMechanism, epsilon: f64) -> Result<Self, Error> {
+        if !(epsilon.is_finite() && epsilon > 0.) {
+            bail!("Epsilon must be a positive number, but is {}", epsilon);
+        }
+        Ok(Self::DifferentialPrivacy {
+            mechanism,
+            epsilon: NotNan::new(epsilon)?,
+        })
+    }
+}
+
+impl Display for SimulatedCountermeasure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::None => write!(f, "None"),
+            Self::PerfectPadding => write!(f, "PerfectPadding"),
+            Self::PerfectTiming => write!(f, "PerfectTiming"),
+            Self::PerfectPaddingAndTiming => write!(f, "PerfectPaddingAndTiming"),
+            Self::CountOnly => write!(f, "CountOnly"),
+            Self::ConstantLength(length) => write!(f, "ConstantLength:{}", length),
+            Self::DifferentialPrivacy { mechanism, epsilon } => {
+                write!(f, "Dp{}:{}", mechanism, epsilon)
+            }
+        }
+    }
+}
+
+impl FromStr for SimulatedCountermeasure {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, param) = match s.split_once(':') {
+            Some((name, param)) => (name, Some(param.trim())),
+            None => (s, None),
+        };
+        let no_param = |countermeasure: Self| -> Result<Self, Error> {
+            if param.is_some() {
+                bail!("The simulated countermeasure '{}' has no parameter", name)
+            }
+            Ok(countermeasure)
+        };
+        let epsilon = || -> Result<f64, Error> {
+            match param {
+                Some(epsilon) => epsilon
+                    .parse()
+                    .with_context(|| format!("Invalid epsilon in '{}'", s)),
+                None => Ok(DEFAULT_DP_EPSILON),
+            }
+        };
+
+        match &*name.trim().to_ascii_lowercase() {
+            "none" | "normal" => no_param(Self::None),
+            "perfectpadding" => no_param(Self::PerfectPadding),
+            "perfecttiming" => no_param(Self::PerfectTiming),
+            "perfectpaddingandtiming" => no_param(Self::PerfectPaddingAndTiming),
+            "countonly" => no_param(Self::CountOnly),
+            "constantlength" => match param {
+                Some(length) => Ok(Self::ConstantLength(
+                    length
+                        .parse()
+                        .with_context(|| format!("Invalid length in '{}'", s))?,
+                )),
+                None => Ok(Self::ConstantLength(DEFAULT_CONSTANT_LENGTH)),
+            },
+            "dplaplace" => Self::differential_privacy(NoiseMechanism::Laplace, epsilon()?),
+            "dpgaussian" => Self::differential_privacy(NoiseMechanism::Gaussian, epsilon()?),
+            _ => bail!(
+                "Unknown simulated countermeasure '{}'. Supported are 'None', 'PerfectPadding', 'PerfectTiming', 'PerfectPaddingAndTiming', 'CountOnly', 'ConstantLength[:<n>]', 'DpLaplace[:<epsilon>]', and 'DpGaussian[:<epsilon>]'.",
+                s
+            ),
+        }
+    }
+}
+
+/// Distribution of the noise used by [`SimulatedCountermeasure::DifferentialPrivacy`]
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+pub enum NoiseMechanism {
+    /// Laplace noise with scale `sensitivity / epsilon`
+    Laplace,
+    /// Gaussian noise for (ε, δ)-differential privacy with δ = 10⁻⁵
+    Gaussian,
+}
+
+impl NoiseMechanism {
+    /// Draw a noise value for the given `sensitivity` and privacy parameter `epsilon`
+    fn sample(self, rng: &mut impl Rng, sensitivity: f64, epsilon: f64) -> f64 {
+        match self {
+            Self::Laplace => {
+                let scale = sensitivity / epsilon;
+                let u = rng.sample::<f64, _>(Open01) - 0.5;
+                -scale * u.signum() * (1. - 2. * u.abs()).ln()
+            }
+            Self::Gaussian => {
+                let sigma = sensitivity * (2. * (1.25 / GAUSSIAN_DELTA).ln()).sqrt() / epsilon;
+                // Box-Muller transform
+                let u1: f64 = rng.sample(Open01);
+                let u2: f64 = rng.sample(Open01);
+                sigma * (-2. * u1.ln()).sqrt() * (2. * PI * u2).cos()
+            }
+        }
+    }
+}
+
+impl Display for NoiseMechanism {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Laplace => write!(f, "Laplace"),
+            Self::Gaussian => write!(f, "Gaussian"),
+        }
+    }
+}
+
+impl FromStr for NoiseMechanism {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "Laplace" | "laplace" => Ok(Self::Laplace),
+            "Gaussian" | "gaussian" => Ok(Self::Gaussian),
+            unkwn => bail!("Unknown variant: '{}'", unkwn),
+        }
+    }
+}
+
 /// Takes a list of Queries and returns a [`Sequence`]
 ///
 /// The functions abstracts over some details of Queries, such as absolute size and absolute time.
 /// The function only returns [`None`], if the input sequence is empty.
+/// The length and complexity filters of `config` are ignored, see [`try_convert_to_sequence`] for applying them.
 pub fn convert_to_sequence<QR>(
     data: impl IntoIterator<Item = QR>,
     identifier: String,
     config: LoadSequenceConfig,
 ) -> Option<Sequence>
+where
+    QR: Into<AbstractQueryResponse>,
+{
+    try_convert_to_sequence(data, identifier, config.without_filters())
+        .expect("Without filters no Sequence is removed")
+}
+
+/// Same as [`convert_to_sequence`], but apply the length and complexity filters of `config`
+///
+/// The filters are checked after the [`PageLoadSegmentation`], but before the [`SimulatedCountermeasure`].
+/// The length is the number of messages and the complexity is computed from the padded sizes, like [`Sequence::complexity`].
+pub fn try_convert_to_sequence<QR>(
+    data: impl IntoIterator<Item = QR>,
+    identifier: String,
+    config: LoadSequenceConfig,
+) -> Result<Option<Sequence>, FilterReason>
 where
     QR: Into<AbstractQueryResponse>,
 {
     let base_gap_size = Duration::microseconds(1000);
 
+    // Setup a predictable RNG for the noise
+    let mut rng = {
+        let mut hasher = FnvHasher::with_key(0);
+        identifier.hash(&mut hasher);
+        XorShiftRng::seed_from_u64(hasher.finish())
+    };
+
+    let mut messages: Vec<AbstractQueryResponse> = data.into_iter().map(Into::into).collect();
+    messages.truncate(config.segmentation.page_load_end(&messages));
+    if messages.is_empty() {
+        return Ok(None);
+    }
+    if config.has_filters() {
+        let block_size = config.padding.block_size(false);
+        let complexity = messages
+            .iter()
+            .map(|msg| (block_padding(msg.size, block_size) / block_size) as u8 as usize)
+            .sum();
+        config.check_filters(messages.len(), complexity)?;
+    }
+
     let mut last_time = None;
-    let data: Vec<_> = data
+    let mut data: Vec<_> = messages
         .into_iter()
         .flat_map(|d| {
-            let d: AbstractQueryResponse = d.into();
+            let mut time_diff = last_time.map(|last_end| d.time - last_end);
+            let mut msg_size = d.size;
+            if let SimulatedCountermeasure::DifferentialPrivacy { mechanism, epsilon } =
+                config.simulated_countermeasure
+            {
+                let epsilon = epsilon.into_inner();
+                time_diff = time_diff.map(|diff| {
+                    let micros = diff.num_microseconds().unwrap_or(i64::MAX) as f64;
+                    let noise = mechanism.sample(
+                        &mut rng,
+                        base_gap_size.num_microseconds().unwrap() as f64,
+                        epsilon,
+                    );
+                    Duration::microseconds((micros + noise).round().max(0.) as i64)
+                });
+                let block_size = f64::from(config.padding.block_size(false));
+                let noise = mechanism.sample(&mut rng, block_size, epsilon);
+                // Limit the size such that the number of blocks still fits into a `SequenceElement::Size`
+                msg_size = (f64::from(msg_size) + noise)
+                    .round()
+                    .max(1.)
+                    .min(block_size * f64::from(u8::MAX)) as u32;
+            }
 
             let mut gap = None;
-            if let Some(last_end) = last_time {
-                gap = gap_size(d.time - last_end, base_gap_size, config.gap_mode);
+            if let Some(time_diff) = time_diff {
+                gap = gap_size(time_diff, base_gap_size, config.gap_mode);
             }
 
-            let mut size = Some(pad_size(d.size, false, config.padding));
+            let mut size = Some(pad_size(msg_size, false, config.padding));
 
             // The config allows us to remove either Gap or Size
             match config.simulated_countermeasure {
-                SimulatedCountermeasure::None => {}
+                SimulatedCountermeasure::None
+                | SimulatedCountermeasure::ConstantLength(_)
+                | SimulatedCountermeasure::DifferentialPrivacy { .. } => {}
                 SimulatedCountermeasure::PerfectPadding => {
                     // We need to enforce Gap(0) messages to ensure that counting the number of messages still works
 
@@ -125,9 +735,13 @@ where
                     }
                     size = None;
                 }
-                SimulatedCountermeasure::PerfectTiming => {
+                SimulatedCountermeasure::PerfectTiming | SimulatedCountermeasure::CountOnly => {
                     gap = None;
                 }
+                SimulatedCountermeasure::PerfectPaddingAndTiming => {
+                    gap = None;
+                    size = Some(SequenceElement::Size(1));
+                }
             }
 
             // Mark this as being not the first iteration anymore
@@ -138,10 +752,19 @@ where
         .collect();
 
     if data.is_empty() {
-        return None;
+        return Ok(None);
     }
 
-    Some(Sequence::new(data, identifier))
+    match config.simulated_countermeasure {
+        SimulatedCountermeasure::CountOnly => data.sort(),
+        SimulatedCountermeasure::ConstantLength(n) => {
+            data.truncate(n);
+            data.resize(n, SequenceElement::Size(1));
+        }
+        _ => {}
+    }
+
+    Ok(Some(Sequence::new(data, identifier)))
 }
 
 /// Takes a list of Queries and returns a [`PrecisionSequence`]
@@ -194,11 +817,8 @@ pub(crate) fn gap_size(gap: Duration, base: Duration, mode: GapMode) -> Option<S
 }
 
 pub(crate) fn pad_size(size: u32, is_query: bool, padding: Padding) -> SequenceElement {
-    use self::Padding::*;
-    SequenceElement::Size(match (padding, is_query) {
-        (Q128R468, true) => block_padding(size, 128) / 128,
-        (Q128R468, false) => block_padding(size, 468) / 468,
-    } as u8)
+    let block_size = padding.block_size(is_query);
+    SequenceElement::Size((block_padding(size, block_size) / block_size) as u8)
 }
 
 fn block_padding(size: u32, block_size: u32) -> u32 {
@@ -209,6 +829,23 @@ fn block_padding(size: u32, block_size: u32) -> u32 {
     }
 }
 
+#[test]
+fn test_parse_tls_overhead_model() {
+    assert_eq!(
+        TlsOverheadModel::Constant(29),
+        "29".parse::<TlsOverheadModel>().unwrap()
+    );
+    assert_eq!(
+        TlsOverheadModel::Negotiated { fallback: 40 },
+        "negotiated".parse::<TlsOverheadModel>().unwrap()
+    );
+    assert_eq!(
+        TlsOverheadModel::Negotiated { fallback: 19 },
+        "Negotiated:19".parse::<TlsOverheadModel>().unwrap()
+    );
+    assert!("foobar".parse::<TlsOverheadModel>().is_err());
+}
+
 #[test]
 fn test_block_padding() {
     assert_eq!(0, block_padding(0, 128));
@@ -0,0 +1,993 @@
+//! Decrypt the TLS 1.3 connections of a pcap with the secrets from a key log file
+//!
+//! The key log file uses the NSS format, which is written by applications if the `SSLKEYLOGFILE` environment variable is set.
+//! Each line contains a label, the random value of the ClientHello, and the secret, e.g.,
+//! `CLIENT_TRAFFIC_SECRET_0 <client random hex> <secret hex>`.
+//!
+//! Only TLS 1.3 with the cipher suites AES-128-GCM, AES-256-GCM, and ChaCha20-Poly1305 is supported.
+//! TLS 1.2 connections are reported as [`DecryptionStatus::Unsupported`].
+//! Early data is not decrypted.
+//!
+//! The decrypted DNS messages provide the ground truth for the [`Sequence`]s extracted from the encrypted TLS records.
+//! [`build_decrypted_sequence`] uses the exact DNS message sizes before padding, instead of the TLS record sizes.
+
+use super::{
+    FlowIdentifier, MessageType, PcapFilter, TlsRecord, TlsSessionParameters, TlsVersion,
+    TwoWayFlowIdentifier,
+};
+use crate::{
+    AbstractQueryResponse, LoadSequenceConfig, PrecisionSequence, Sequence, SequenceMetadata,
+    TruncationMode,
+};
+use anyhow::{anyhow, bail, Context as _, Error};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use dnstap::{END_MARKER, START_MARKER};
+use misc_utils::fs;
+use ring::{
+    aead,
+    hkdf::{self, KeyType},
+};
+use rustls::internal::msgs::{
+    enums::ContentType as TlsContentType,
+    handshake::HandshakePayload as TlsHandshakePayload,
+    message::{MessagePayload as TlsMessagePayload, OpaqueMessage as OpaqueTlsMessage},
+};
+use serde::Serialize;
+use std::{collections::HashMap, net::SocketAddrV4, path::Path, str::FromStr};
+use trust_dns_proto::{op::Message as DnsMessage, rr::rdata::opt::EdnsCode};
+
+const CLIENT_HANDSHAKE_TRAFFIC_SECRET: &str = "CLIENT_HANDSHAKE_TRAFFIC_SECRET";
+const SERVER_HANDSHAKE_TRAFFIC_SECRET: &str = "SERVER_HANDSHAKE_TRAFFIC_SECRET";
+const CLIENT_TRAFFIC_SECRET_0: &str = "CLIENT_TRAFFIC_SECRET_0";
+const SERVER_TRAFFIC_SECRET_0: &str = "SERVER_TRAFFIC_SECRET_0";
+
+/// Handshake message types, which change the keys of the sender
+const HANDSHAKE_FINISHED: u8 = 20;
+const HANDSHAKE_KEY_UPDATE: u8 = 24;
+
+/// Secrets of all TLS connections from a key log file, indexed by the random value of the ClientHello
+#[derive(Clone, Debug, Default)]
+pub struct KeyLog {
+    secrets: HashMap<[u8; 32], HashMap<String, Vec<u8>>>,
+}
+
+impl KeyLog {
+    pub fn from_path(path: &Path) -> Result<Self, Error> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Cannot read key log file `{}`", path.display()))?;
+        content
+            .parse()
+            .with_context(|| format!("Invalid key log file `{}`", path.display()))
+    }
+
+    /// Number of TLS connections with secrets in the key log
+    pub fn len(&self) -> usize {
+        self.secrets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.secrets.is_empty()
+    }
+
+    fn secret(&self, client_random: &[u8; 32], label: &str) -> Option<&[u8]> {
+        self.secrets
+            .get(client_random)?
+            .get(label)
+            .map(Vec::as_slice)
+    }
+}
+
+impl FromStr for KeyLog {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut keylog = Self::default();
+        for (idx, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() != 3 {
+                bail!(
+                    "Line {}: Expected three fields but found `{}`",
+                    idx + 1,
+                    line
+                );
+            }
+            let mut client_random = [0; 32];
+            let random = decode_hex(fields[1]).with_context(|| format!("Line {}", idx + 1))?;
+            if random.len() != client_random.len() {
+                bail!(
+                    "Line {}: The client random must be 32 bytes long but is {} bytes",
+                    idx + 1,
+                    random.len()
+                );
+            }
+            client_random.copy_from_slice(&random);
+            let secret = decode_hex(fields[2]).with_context(|| format!("Line {}", idx + 1))?;
+            keylog
+                .secrets
+                .entry(client_random)
+                .or_default()
+                .insert(fields[0].to_string(), secret);
+        }
+        Ok(keylog)
+    }
+}
+
+fn decode_hex(s: &str) -> Result<Vec<u8>, Error> {
+    s.as_bytes()
+        .chunks(2)
+        .map(|byte| {
+            std::str::from_utf8(byte)
+                .ok()
+                .filter(|byte| byte.len() == 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                .ok_or_else(|| anyhow!("Invalid hex string `{}`", s))
+        })
+        .collect()
+}
+
+/// Outcome of decrypting a single TLS connection
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Serialize)]
+pub enum DecryptionStatus {
+    /// All encrypted records could be decrypted
+    Decrypted,
+    /// The capture does not contain the ClientHello and ServerHello of the connection
+    IncompleteHandshake,
+    /// The TLS version or cipher suite is not supported
+    Unsupported,
+    /// The key log contains no secrets for this connection
+    MissingSecrets,
+    /// The secrets in the key log do not decrypt the connection
+    WrongSecrets,
+}
+
+/// Decrypted content of a TLS record
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct DecryptedRecord {
+    /// ID of the containing packet within the pcap
+    pub packet_in_pcap: u32,
+    pub time: NaiveDateTime,
+    pub from_client: bool,
+    /// Content type of the plaintext
+    pub message_type: MessageType,
+    /// Plaintext of the record without the TLS 1.3 padding
+    pub payload: Vec<u8>,
+    /// Size of the encrypted record payload
+    pub encrypted_length: u32,
+}
+
+/// A single TLS connection and its decrypted records
+#[derive(Clone, Debug)]
+pub struct DecryptedFlow {
+    pub client: SocketAddrV4,
+    pub server: SocketAddrV4,
+    pub session: TlsSessionParameters,
+    pub status: DecryptionStatus,
+    /// Decrypted records after the handshake in the order of the capture
+    ///
+    /// Contains the records decrypted before an error, if the [`DecryptionStatus`] is not [`DecryptionStatus::Decrypted`].
+    pub records: Vec<DecryptedRecord>,
+}
+
+/// A DNS message transported over TLS
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct DnsOverTlsMessage {
+    /// ID of the packet which contains the start of the message
+    pub packet_in_pcap: u32,
+    /// Time of the packet which contains the start of the message
+    pub time: NaiveDateTime,
+    pub from_client: bool,
+    /// The DNS message without the length prefix
+    pub message: Vec<u8>,
+}
+
+impl DecryptedFlow {
+    /// Split the application data into the DNS messages of the DNS over TLS stream
+    ///
+    /// Each DNS message is prefixed by its length as two byte integer ([RFC 7858](https://tools.ietf.org/html/rfc7858#section-3.3)).
+    /// An incomplete message at the end of the stream is dropped.
+    pub fn dns_messages(&self) -> Vec<DnsOverTlsMessage> {
+        let mut res = Vec::new();
+        for &from_client in &[true, false] {
+            let mut buffer: Vec<u8> = Vec::new();
+            // Packet ID and time of the first byte in the buffer
+            let mut start = None;
+            for rec in self.records.iter().filter(|rec| {
+                rec.from_client == from_client && rec.message_type == MessageType::ApplicationData
+            }) {
+                if buffer.is_empty() {
+                    start = Some((rec.packet_in_pcap, rec.time));
+                }
+                buffer.extend_from_slice(&rec.payload);
+                while buffer.len() >= 2 {
+                    let len = usize::from(u16::from_be_bytes([buffer[0], buffer[1]]));
+                    if buffer.len() < 2 + len {
+                        break;
+                    }
+                    let (packet_in_pcap, time) =
+                        start.expect("The start is set whenever the buffer is filled");
+                    res.push(DnsOverTlsMessage {
+                        packet_in_pcap,
+                        time,
+                        from_client,
+                        message: buffer[2..2 + len].to_vec(),
+                    });
+                    buffer.drain(..2 + len);
+                    start = Some((rec.packet_in_pcap, rec.time));
+                }
+            }
+        }
+        res.sort_by_key(|msg| (msg.time, msg.packet_in_pcap));
+        res
+    }
+}
+
+/// Decrypt all TLS connections in the pcap `file` with the secrets from `keylog`
+pub fn decrypt_tls_flows(file: &Path, keylog: &KeyLog) -> Result<Vec<DecryptedFlow>, Error> {
+    let mut flows: HashMap<TwoWayFlowIdentifier, Vec<CapturedRecord>> = HashMap::default();
+    super::parse_tls_messages(file, TruncationMode::Strict, |record, message| {
+        flows
+            .entry(FlowIdentifier::from(&record).into())
+            .or_default()
+            .push(CapturedRecord::new(record, message));
+    })?;
+
+    let mut res: Vec<_> = flows
+        .into_values()
+        .filter_map(|records| decrypt_flow(&records, keylog))
+        .collect();
+    res.sort_by_key(|flow| (flow.client, flow.server));
+    Ok(res)
+}
+
+/// Summary of how well the key log file matches the DNS over TLS connections of a pcap
+#[derive(Clone, Eq, PartialEq, Debug, Default, Serialize)]
+pub struct TlsKeyCheck {
+    /// Number of connections to the DNS server
+    pub connections: usize,
+    /// Number of connections for each [`DecryptionStatus`]
+    pub decrypted: usize,
+    pub incomplete_handshake: usize,
+    pub unsupported: usize,
+    pub missing_secrets: usize,
+    pub wrong_secrets: usize,
+    /// Number of DNS messages in the decrypted connections
+    pub dns_messages: usize,
+    /// Number of DNS messages, which cannot be parsed
+    pub invalid_dns_messages: usize,
+}
+
+impl TlsKeyCheck {
+    /// No connection contradicts the key log and all decrypted data is valid DNS
+    ///
+    /// Connections which cannot be checked, like incomplete or unsupported ones, are ignored.
+    pub fn keys_match(&self) -> bool {
+        self.missing_secrets == 0 && self.wrong_secrets == 0 && self.invalid_dns_messages == 0
+    }
+}
+
+/// Check that the key log decrypts the DNS over TLS connections in the pcap `file` to valid DNS messages
+///
+/// The connections to the DNS `server` are checked, or all connections to port 853 if unspecified.
+pub fn check_tls_keys(
+    file: &Path,
+    keylog: &Path,
+    server: Option<SocketAddrV4>,
+) -> Result<TlsKeyCheck, Error> {
+    let keylog = KeyLog::from_path(keylog)?;
+    let mut res = TlsKeyCheck::default();
+    for flow in decrypt_tls_flows(file, &keylog)? {
+        let is_dns_server = match server {
+            Some(server) => flow.server == server,
+            None => flow.server.port() == 853,
+        };
+        if !is_dns_server {
+            continue;
+        }
+
+        res.connections += 1;
+        match flow.status {
+            DecryptionStatus::Decrypted => res.decrypted += 1,
+            DecryptionStatus::IncompleteHandshake => res.incomplete_handshake += 1,
+            DecryptionStatus::Unsupported => res.unsupported += 1,
+            DecryptionStatus::MissingSecrets => res.missing_secrets += 1,
+            DecryptionStatus::WrongSecrets => res.wrong_secrets += 1,
+        }
+        for msg in flow.dns_messages() {
+            res.dns_messages += 1;
+            if DnsMessage::from_vec(&msg.message).is_err() {
+                res.invalid_dns_messages += 1;
+            }
+        }
+    }
+    Ok(res)
+}
+
+/// A decrypted DNS message between the marker queries
+#[derive(Clone, Eq, PartialEq, Debug, Serialize)]
+pub struct DecryptedDnsMessage {
+    /// ID of the packet which contains the start of the message
+    pub packet_in_pcap: u32,
+    pub time: NaiveDateTime,
+    pub from_client: bool,
+    pub qname: String,
+    /// Size of the DNS message in bytes
+    pub size: u32,
+    /// Size of the DNS message without the EDNS(0) padding option
+    pub unpadded_size: u32,
+}
+
+impl From<&DecryptedDnsMessage> for AbstractQueryResponse {
+    fn from(msg: &DecryptedDnsMessage) -> Self {
+        Self {
+            time: msg.time,
+            size: msg.unpadded_size,
+        }
+    }
+}
+
+/// Extract the DNS messages of the website from the DNS over TLS connections in the pcap `file`
+///
+/// The messages are the same as for [`build_sequence`](super::build_sequence), i.e., the responses after the `start.example.` marker and before the `end.example.` marker query.
+/// Instead of guessing the markers from the TLS record sizes, they are identified by their query names.
+/// The DNS server is selected by the [`PcapFilter`], or all connections to port 853 are used if unspecified.
+///
+/// Returns an error if any connection to the DNS server cannot be decrypted, as the messages would be incomplete.
+pub fn extract_decrypted_dns_messages(
+    file: &Path,
+    keylog: &KeyLog,
+    filter: PcapFilter,
+) -> Result<Vec<DecryptedDnsMessage>, Error> {
+    dns_messages_between_markers(decrypt_tls_flows(file, keylog)?, filter)
+}
+
+/// Implementation of [`extract_decrypted_dns_messages`] after the decryption
+fn dns_messages_between_markers(
+    flows: Vec<DecryptedFlow>,
+    filter: PcapFilter,
+) -> Result<Vec<DecryptedDnsMessage>, Error> {
+    let mut messages = Vec::new();
+    for flow in flows {
+        let is_dns_server = match filter.server {
+            Some(server) => flow.server == server,
+            None => flow.server.port() == 853,
+        };
+        let is_client = filter
+            .client
+            .map(|client| *flow.client.ip() == client)
+            .unwrap_or(true);
+        if !is_dns_server || !is_client {
+            continue;
+        }
+        if flow.status != DecryptionStatus::Decrypted {
+            bail!(
+                "Cannot decrypt the connection from {} to {}: {:?}",
+                flow.client,
+                flow.server,
+                flow.status
+            );
+        }
+
+        for msg in flow.dns_messages() {
+            let dnsmsg = DnsMessage::from_vec(&msg.message).with_context(|| {
+                format!(
+                    "Invalid DNS message in packet {} of the connection from {} to {}",
+                    msg.packet_in_pcap, flow.client, flow.server
+                )
+            })?;
+            let qname = dnsmsg
+                .queries()
+                .first()
+                .map(|query| query.name().to_utf8())
+                .unwrap_or_default();
+            // The padding option consists of the option code, the option length, and the padding bytes
+            let padding = dnsmsg
+                .edns()
+                .and_then(|edns| edns.option(EdnsCode::Padding))
+                .map(|opt| 4 + u32::from(opt.len()))
+                .unwrap_or(0);
+            let size = msg.message.len() as u32;
+            messages.push(DecryptedDnsMessage {
+                packet_in_pcap: msg.packet_in_pcap,
+                time: msg.time,
+                from_client: msg.from_client,
+                qname,
+                size,
+                unpadded_size: size.saturating_sub(padding),
+            });
+        }
+    }
+    messages.sort_by_key(|msg| (msg.time, msg.packet_in_pcap));
+
+    let start = messages
+        .iter()
+        .position(|msg| !msg.from_client && msg.qname == START_MARKER)
+        .ok_or_else(|| {
+            anyhow!(
+                "Cannot find the response to the start marker {}",
+                START_MARKER
+            )
+        })?;
+    let end = messages
+        .iter()
+        .skip(start)
+        .position(|msg| msg.from_client && msg.qname == END_MARKER)
+        .map(|end| start + end)
+        .unwrap_or_else(|| messages.len());
+    messages.truncate(end);
+    messages.drain(..=start);
+    if !filter.both_directions {
+        messages.retain(|msg| !msg.from_client);
+    }
+    Ok(messages)
+}
+
+/// Build the ground truth [`Sequence`] from the decrypted DNS messages
+///
+/// This is the equivalent of [`build_sequence`](super::build_sequence), but uses the DNS message sizes before padding.
+/// Comparing both shows how much the padding and the TLS record sizes change the [`Sequence`]s.
+pub fn build_decrypted_sequence(
+    file: &Path,
+    keylog: &Path,
+    filter: PcapFilter,
+    config: LoadSequenceConfig,
+) -> Result<Sequence, Error> {
+    let keylog = KeyLog::from_path(keylog)?;
+    let messages = extract_decrypted_dns_messages(file, &keylog, filter)?;
+    let metadata = SequenceMetadata {
+        capture_start: messages
+            .first()
+            .map(|msg| DateTime::from_utc(msg.time, Utc)),
+        ..SequenceMetadata::for_file(file, config)?
+    };
+    Ok(
+        crate::try_convert_to_sequence(&messages, file.to_string_lossy().to_string(), config)?
+            .ok_or_else(|| {
+                anyhow!(
+                    "Could not build Sequence from the decrypted DNS messages of file {}",
+                    file.display()
+                )
+            })?
+            .with_metadata(metadata),
+    )
+}
+
+/// Same as [`build_decrypted_sequence`] but keeps the exact message sizes and times
+pub fn build_decrypted_precision_sequence(
+    file: &Path,
+    keylog: &Path,
+    filter: PcapFilter,
+) -> Result<PrecisionSequence, Error> {
+    let keylog = KeyLog::from_path(keylog)?;
+    let messages = extract_decrypted_dns_messages(file, &keylog, filter)?;
+    crate::load_sequence::convert_to_precision_sequence(
+        &messages,
+        file.to_string_lossy().to_string(),
+    )
+    .ok_or_else(|| {
+        anyhow!(
+            "Could not build PrecisionSequence from the decrypted DNS messages of file {}",
+            file.display()
+        )
+    })
+}
+
+/// A TLS record as extracted from the pcap
+struct CapturedRecord {
+    record: TlsRecord,
+    payload: Vec<u8>,
+    /// Random value, if this is a ClientHello
+    client_random: Option<[u8; 32]>,
+}
+
+impl CapturedRecord {
+    fn new(record: TlsRecord, message: &OpaqueTlsMessage) -> Self {
+        let client_random =
+            match TlsMessagePayload::new(message.typ, message.version, message.payload.clone()) {
+                Ok(TlsMessagePayload::Handshake(handshake)) => match handshake.payload {
+                    TlsHandshakePayload::ClientHello(client_hello) => Some(client_hello.random.0),
+                    _ => None,
+                },
+                _ => None,
+            };
+        Self {
+            record,
+            payload: message.payload.0.clone(),
+            client_random,
+        }
+    }
+}
+
+/// Decrypt the records of a single TCP connection
+///
+/// Returns [`None`] if the connection does not contain a ClientHello, such that client and server are unknown.
+fn decrypt_flow(records: &[CapturedRecord], keylog: &KeyLog) -> Option<DecryptedFlow> {
+    let (client_hello, client_random) = records
+        .iter()
+        .find_map(|rec| rec.client_random.map(|random| (&rec.record, random)))?;
+    let client = SocketAddrV4::new(client_hello.sender, client_hello.sender_port);
+    let server = SocketAddrV4::new(client_hello.receiver, client_hello.receiver_port);
+    let session = TlsSessionParameters::from_records(records.iter().map(|rec| &rec.record));
+    let mut flow = DecryptedFlow {
+        client,
+        server,
+        session,
+        status: DecryptionStatus::Decrypted,
+        records: Vec::new(),
+    };
+
+    let suite = match (session.tls_version, session.cipher_suite) {
+        (None, _) | (_, None) => {
+            flow.status = DecryptionStatus::IncompleteHandshake;
+            return Some(flow);
+        }
+        (Some(TlsVersion::Tls1_3), Some(cipher_suite)) => CipherSuite::new(cipher_suite),
+        _ => None,
+    };
+    let suite = match suite {
+        Some(suite) => suite,
+        None => {
+            flow.status = DecryptionStatus::Unsupported;
+            return Some(flow);
+        }
+    };
+
+    let secret = |label| keylog.secret(&client_random, label);
+    let (client_keys, server_keys) = match (
+        secret(CLIENT_HANDSHAKE_TRAFFIC_SECRET),
+        secret(CLIENT_TRAFFIC_SECRET_0),
+        secret(SERVER_HANDSHAKE_TRAFFIC_SECRET),
+        secret(SERVER_TRAFFIC_SECRET_0),
+    ) {
+        (Some(client_hs), Some(client_app), Some(server_hs), Some(server_app)) => (
+            DirectionKeys::new(suite, client_hs, client_app),
+            DirectionKeys::new(suite, server_hs, server_app),
+        ),
+        _ => {
+            flow.status = DecryptionStatus::MissingSecrets;
+            return Some(flow);
+        }
+    };
+    let (mut client_keys, mut server_keys) = match (client_keys, server_keys) {
+        (Some(client_keys), Some(server_keys)) => (client_keys, server_keys),
+        _ => {
+            flow.status = DecryptionStatus::WrongSecrets;
+            return Some(flow);
+        }
+    };
+
+    let mut has_seen_server_hello = false;
+    for rec in records {
+        let from_client =
+            rec.record.sender == *client.ip() && rec.record.sender_port == client.port();
+        if rec.record.message_type == MessageType::Handshake && !from_client {
+            has_seen_server_hello = true;
+        }
+        // Encrypted records of the client before the ServerHello are early data
+        if rec.record.message_type != MessageType::ApplicationData || !has_seen_server_hello {
+            continue;
+        }
+
+        let keys = if from_client {
+            &mut client_keys
+        } else {
+            &mut server_keys
+        };
+        let (message_type, payload) = match keys.decrypt(&rec.payload) {
+            Some(plaintext) => plaintext,
+            None => {
+                flow.status = DecryptionStatus::WrongSecrets;
+                return Some(flow);
+            }
+        };
+        if message_type == MessageType::Handshake && !keys.process_handshake(&payload) {
+            flow.status = DecryptionStatus::WrongSecrets;
+            return Some(flow);
+        }
+        flow.records.push(DecryptedRecord {
+            packet_in_pcap: rec.record.packet_in_pcap,
+            time: rec.record.time,
+            from_client,
+            message_type,
+            payload,
+            encrypted_length: rec.record.message_length,
+        });
+    }
+    Some(flow)
+}
+
+/// Algorithms of a TLS 1.3 cipher suite
+#[derive(Copy, Clone)]
+struct CipherSuite {
+    aead: &'static aead::Algorithm,
+    hkdf: hkdf::Algorithm,
+}
+
+impl CipherSuite {
+    fn new(cipher_suite: u16) -> Option<Self> {
+        let (aead, hkdf) = match cipher_suite {
+            // TLS_AES_128_GCM_SHA256
+            0x1301 => (&aead::AES_128_GCM, hkdf::HKDF_SHA256),
+            // TLS_AES_256_GCM_SHA384
+            0x1302 => (&aead::AES_256_GCM, hkdf::HKDF_SHA384),
+            // TLS_CHACHA20_POLY1305_SHA256
+            0x1303 => (&aead::CHACHA20_POLY1305, hkdf::HKDF_SHA256),
+            _ => return None,
+        };
+        Some(Self { aead, hkdf })
+    }
+}
+
+/// Output length for [`hkdf_expand_label`]
+struct Len(usize);
+
+impl KeyType for Len {
+    fn len(&self) -> usize {
+        self.0
+    }
+}
+
+/// The `HKDF-Expand-Label` function of [RFC 8446](https://tools.ietf.org/html/rfc8446#section-7.1) with an empty context
+fn hkdf_expand_label(
+    algorithm: hkdf::Algorithm,
+    secret: &[u8],
+    label: &[u8],
+    len: usize,
+) -> Option<Vec<u8>> {
+    let output_len = (len as u16).to_be_bytes();
+    let label_len = [(b"tls13 ".len() + label.len()) as u8];
+    let context_len = [0];
+    let info = [
+        &output_len[..],
+        &label_len[..],
+        &b"tls13 "[..],
+        label,
+        &context_len[..],
+    ];
+    let mut out = vec![0; len];
+    hkdf::Prk::new_less_safe(algorithm, secret)
+        .expand(&info, Len(len))
+        .ok()?
+        .fill(&mut out)
+        .ok()?;
+    Some(out)
+}
+
+/// Keys of a single traffic secret
+struct TrafficKeys {
+    secret: Vec<u8>,
+    key: aead::LessSafeKey,
+    iv: [u8; aead::NONCE_LEN],
+    sequence_number: u64,
+}
+
+impl TrafficKeys {
+    fn new(suite: CipherSuite, secret: &[u8]) -> Option<Self> {
+        let key = hkdf_expand_label(suite.hkdf, secret, b"key", suite.aead.key_len())?;
+        let iv = hkdf_expand_label(suite.hkdf, secret, b"iv", aead::NONCE_LEN)?;
+        let mut iv_array = [0; aead::NONCE_LEN];
+        iv_array.copy_from_slice(&iv);
+        Some(Self {
+            secret: secret.to_vec(),
+            key: aead::LessSafeKey::new(aead::UnboundKey::new(suite.aead, &key).ok()?),
+            iv: iv_array,
+            sequence_number: 0,
+        })
+    }
+}
+
+/// Keys of one direction of a TLS 1.3 connection
+struct DirectionKeys {
+    suite: CipherSuite,
+    current: TrafficKeys,
+    /// Keys after the Finished message, [`None`] once they are in use
+    application: Option<TrafficKeys>,
+    /// Buffer for handshake messages spanning multiple records
+    handshake_buffer: Vec<u8>,
+}
+
+impl DirectionKeys {
+    fn new(suite: CipherSuite, handshake_secret: &[u8], application_secret: &[u8]) -> Option<Self> {
+        Some(Self {
+            suite,
+            current: TrafficKeys::new(suite, handshake_secret)?,
+            application: Some(TrafficKeys::new(suite, application_secret)?),
+            handshake_buffer: Vec::new(),
+        })
+    }
+
+    /// Decrypt the payload of an encrypted record and return the inner content type and plaintext
+    fn decrypt(&mut self, payload: &[u8]) -> Option<(MessageType, Vec<u8>)> {
+        let keys = &mut self.current;
+        let mut nonce = keys.iv;
+        for (n, seq) in nonce[4..]
+            .iter_mut()
+            .zip(keys.sequence_number.to_be_bytes().iter())
+        {
+            *n ^= seq;
+        }
+        keys.sequence_number += 1;
+
+        // The additional data is the header of the encrypted record
+        let len = (payload.len() as u16).to_be_bytes();
+        let header = [
+            TlsContentType::ApplicationData.get_u8(),
+            0x03,
+            0x03,
+            len[0],
+            len[1],
+        ];
+        let mut buffer = payload.to_vec();
+        let plaintext = keys
+            .key
+            .open_in_place(
+                aead::Nonce::assume_unique_for_key(nonce),
+                aead::Aad::from(header),
+                &mut buffer,
+            )
+            .ok()?;
+        // The plaintext is followed by the real content type and zero padding
+        let content_end = plaintext.iter().rposition(|&b| b != 0)?;
+        let message_type = TlsContentType::from(plaintext[content_end]).into();
+        Some((message_type, plaintext[..content_end].to_vec()))
+    }
+
+    /// Switch the keys after the Finished and KeyUpdate handshake messages
+    ///
+    /// Returns `false` if the new keys cannot be derived.
+    fn process_handshake(&mut self, payload: &[u8]) -> bool {
+        self.handshake_buffer.extend_from_slice(payload);
+        let mut switch_keys = false;
+        while self.handshake_buffer.len() >= 4 {
+            let len = u32::from_be_bytes([
+                0,
+                self.handshake_buffer[1],
+                self.handshake_buffer[2],
+                self.handshake_buffer[3],
+            ]) as usize;
+            if self.handshake_buffer.len() < 4 + len {
+                break;
+            }
+            switch_keys |= matches!(
+                self.handshake_buffer[0],
+                HANDSHAKE_FINISHED | HANDSHAKE_KEY_UPDATE
+            );
+            self.handshake_buffer.drain(..4 + len);
+        }
+        if !switch_keys {
+            return true;
+        }
+
+        let next = match self.application.take() {
+            Some(application) => Some(application),
+            // The application keys are already in use, so this is a KeyUpdate
+            None => hkdf_expand_label(
+                self.suite.hkdf,
+                &self.current.secret,
+                b"traffic upd",
+                self.suite.hkdf.len(),
+            )
+            .and_then(|secret| TrafficKeys::new(self.suite, &secret)),
+        };
+        match next {
+            Some(next) => {
+                self.current = next;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[test]
+fn test_hkdf_expand_label_rfc8448() {
+    // Server handshake traffic secret of the "Simple 1-RTT Handshake" in RFC 8448
+    let secret =
+        decode_hex("b67b7d690cc16c4e75e54213cb2d37b4e9c912bcded9105d42befd59d391ad38").unwrap();
+    assert_eq!(
+        decode_hex("3fce516009c21727d0f2e4e86ee403bc").unwrap(),
+        hkdf_expand_label(hkdf::HKDF_SHA256, &secret, b"key", 16).unwrap()
+    );
+    assert_eq!(
+        decode_hex("5d313eb2671276ee13000b30").unwrap(),
+        hkdf_expand_label(hkdf::HKDF_SHA256, &secret, b"iv", 12).unwrap()
+    );
+}
+
+#[test]
+fn test_parse_keylog() {
+    let random = "a".repeat(64);
+    let keylog: KeyLog = format!(
+        "# SSL/TLS secrets log file\n\
+        CLIENT_TRAFFIC_SECRET_0 {random} 0102\n\
+        SERVER_TRAFFIC_SECRET_0 {random} 0304\n",
+        random = random
+    )
+    .parse()
+    .unwrap();
+    assert_eq!(1, keylog.len());
+    assert_eq!(
+        Some(&[3, 4][..]),
+        keylog.secret(&[0xaa; 32], SERVER_TRAFFIC_SECRET_0)
+    );
+    assert!("CLIENT_TRAFFIC_SECRET_0 aabb 0102"
+        .parse::<KeyLog>()
+        .is_err());
+    assert!(format!("CLIENT_TRAFFIC_SECRET_0 {} 0x", random)
+        .parse::<KeyLog>()
+        .is_err());
+}
+
+#[cfg(test)]
+const TEST_CLIENT: &str = "10.0.0.2:40000";
+#[cfg(test)]
+const TEST_SERVER: &str = "1.1.1.1:853";
+
+/// Serialize a DNS message for `qname` with `padding` bytes in the EDNS padding option
+#[cfg(test)]
+fn test_dns_message(qname: &str, is_response: bool, padding: Option<usize>) -> Vec<u8> {
+    use trust_dns_proto::{
+        op::{MessageType as DnsMessageType, Query},
+        rr::{rdata::opt::EdnsOption, Name, RecordType},
+    };
+
+    let mut msg = DnsMessage::new();
+    msg.set_message_type(if is_response {
+        DnsMessageType::Response
+    } else {
+        DnsMessageType::Query
+    });
+    msg.add_query(Query::query(
+        Name::from_ascii(qname).unwrap(),
+        RecordType::A,
+    ));
+    if let Some(padding) = padding {
+        msg.edns_mut().set_max_payload(1232);
+        msg.edns_mut()
+            .options_mut()
+            .insert(EdnsOption::from((EdnsCode::Padding, &vec![0; padding][..])));
+    }
+    msg.to_vec().unwrap()
+}
+
+/// Build a decrypted flow, which carries each DNS message in its own TLS record
+///
+/// The n-th message is sent at second n.
+#[cfg(test)]
+fn test_flow(server: &str, messages: &[(bool, Vec<u8>)]) -> DecryptedFlow {
+    DecryptedFlow {
+        client: TEST_CLIENT.parse().unwrap(),
+        server: server.parse().unwrap(),
+        session: TlsSessionParameters::default(),
+        status: DecryptionStatus::Decrypted,
+        records: messages
+            .iter()
+            .enumerate()
+            .map(|(idx, (from_client, msg))| {
+                let mut payload = (msg.len() as u16).to_be_bytes().to_vec();
+                payload.extend_from_slice(msg);
+                DecryptedRecord {
+                    packet_in_pcap: idx as u32 + 1,
+                    time: NaiveDateTime::from_timestamp(idx as i64, 0),
+                    from_client: *from_client,
+                    message_type: MessageType::ApplicationData,
+                    encrypted_length: payload.len() as u32 + 17,
+                    payload,
+                }
+            })
+            .collect(),
+    }
+}
+
+#[test]
+fn test_dns_messages_between_markers() {
+    let response = test_dns_message("example.com.", true, Some(100));
+    let messages = vec![
+        (true, test_dns_message("unrelated.example.", false, None)),
+        (true, test_dns_message(START_MARKER, false, None)),
+        (false, test_dns_message(START_MARKER, true, None)),
+        (true, test_dns_message("example.com.", false, None)),
+        (false, response.clone()),
+        (true, test_dns_message(END_MARKER, false, None)),
+        (false, test_dns_message(END_MARKER, true, None)),
+    ];
+    let flows = vec![
+        test_flow(TEST_SERVER, &messages),
+        // Connections to other servers must not be considered, even if they cannot be decrypted
+        DecryptedFlow {
+            status: DecryptionStatus::WrongSecrets,
+            ..test_flow("1.1.1.1:443", &messages)
+        },
+    ];
+
+    let res = dns_messages_between_markers(flows.clone(), PcapFilter::default()).unwrap();
+    assert_eq!(
+        vec![DecryptedDnsMessage {
+            packet_in_pcap: 5,
+            time: NaiveDateTime::from_timestamp(4, 0),
+            from_client: false,
+            qname: "example.com.".to_string(),
+            size: response.len() as u32,
+            // The padding option has 4 bytes overhead
+            unpadded_size: response.len() as u32 - 104,
+        }],
+        res
+    );
+
+    let res = dns_messages_between_markers(
+        flows.clone(),
+        PcapFilter {
+            both_directions: true,
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    assert_eq!(
+        vec![(true, 4), (false, 5)],
+        res.iter()
+            .map(|msg| (msg.from_client, msg.packet_in_pcap))
+            .collect::<Vec<_>>()
+    );
+
+    // Only connections of the selected client are used
+    let err = dns_messages_between_markers(
+        flows,
+        PcapFilter {
+            client: Some("10.0.0.3".parse().unwrap()),
+            ..Default::default()
+        },
+    )
+    .unwrap_err();
+    assert!(err.to_string().contains("start marker"), "{}", err);
+}
+
+#[test]
+fn test_dns_messages_between_markers_split_records() {
+    // The page load ends with the capture if the end marker is missing
+    let mut flow = test_flow(
+        TEST_SERVER,
+        &[
+            (false, test_dns_message(START_MARKER, true, None)),
+            (false, test_dns_message("a.example.", true, None)),
+            (false, test_dns_message("b.example.", true, None)),
+        ],
+    );
+    // Split the first response across two records, such that it starts in the second packet
+    let mut tail = flow.records[1].payload.split_off(10);
+    tail.append(&mut flow.records[2].payload);
+    flow.records[2].payload = tail;
+
+    let res = dns_messages_between_markers(vec![flow], PcapFilter::default()).unwrap();
+    assert_eq!(
+        vec![("a.example.", 2), ("b.example.", 3)],
+        res.iter()
+            .map(|msg| (&*msg.qname, msg.packet_in_pcap))
+            .collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn test_dns_messages_between_markers_errors() {
+    let messages = vec![
+        (false, test_dns_message(START_MARKER, true, None)),
+        (false, test_dns_message("example.com.", true, None)),
+    ];
+
+    // Missing messages of the DNS server make the sequence incomplete
+    let flow = DecryptedFlow {
+        status: DecryptionStatus::MissingSecrets,
+        ..test_flow(TEST_SERVER, &messages)
+    };
+    assert!(dns_messages_between_markers(vec![flow], PcapFilter::default()).is_err());
+
+    // The start marker must be answered
+    let flow = test_flow(TEST_SERVER, &messages[1..]);
+    assert!(dns_messages_between_markers(vec![flow], PcapFilter::default()).is_err());
+
+    // All decrypted data must be valid DNS
+    let flow = test_flow(TEST_SERVER, &[(false, vec![0; 5])]);
+    assert!(dns_messages_between_markers(vec![flow], PcapFilter::default()).is_err());
+}
@@ -13,20 +13,30 @@
 //!     or it uses the [`guess_dns_flow_identifier`] function to guess based on IP or port information.
 //! 3. The extracted size and time information are converted into a sequence using [`crate::convert_to_sequence`].
 //!
+//! Instead of a pcap file, step 1 can also use the JSON output of tshark, see the [`tshark`] module.
+//! Such files are recognized by the `.tsharkjson` file extension.
+//!
+//! If the TLS secrets are logged during the capture, the [`decrypt`] module recovers the DNS messages of the connections.
+//!
 //! Steps 1 and 2 are combined in a single [`extract_and_filter_tls_records_from_file`], such that it can be shared
 //! for both [`build_sequence`]/[`build_precision_sequence`] functions.
 
 mod bounded_buffer;
+pub mod decrypt;
 mod tcp_buffer;
+pub mod tshark;
 
 use self::{bounded_buffer::BoundedBuffer, tcp_buffer::TcpBuffer};
-use crate::{AbstractQueryResponse, LoadSequenceConfig, PrecisionSequence, Sequence};
+use crate::{
+    AbstractQueryResponse, LoadSequenceConfig, PrecisionSequence, Sequence, SequenceMetadata,
+    TlsOverheadModel, TruncationMode,
+};
 use anyhow::{anyhow, bail, Context as _, Error};
-use chrono::NaiveDateTime;
+use chrono::{DateTime, NaiveDateTime, Utc};
 use etherparse::{InternetSlice, Ipv4HeaderSlice, SlicedPacket, TcpHeaderSlice, TransportSlice};
 use itertools::Itertools;
-use log::{debug, trace};
-use misc_utils::fs;
+use log::{debug, trace, warn};
+use misc_utils::{fs, path::PathExt};
 use pcap_parser::{data::PacketData, PcapCapture, PcapError};
 use rustls::{
     internal::msgs::{
@@ -34,16 +44,22 @@ use rustls::{
         enums::ContentType as TlsContentType,
         handshake::{
             HandshakePayload as TlsHandshakePayload, ServerExtension as TlsServerExtensions,
+            SessionID as TlsSessionId,
+        },
+        message::{
+            MessageError as TlsMessageError, MessagePayload as TlsMessagePayload,
+            OpaqueMessage as OpaqueTlsMessage,
         },
-        message::{MessagePayload as TlsMessagePayload, OpaqueMessage as OpaqueTlsMessage},
     },
     ProtocolVersion,
 };
 use serde::{Deserialize, Serialize};
+use serde_with::serde_as;
 use std::{
+    borrow::Cow,
     cmp::Ordering,
-    collections::{HashMap, HashSet},
-    mem,
+    collections::{BTreeMap, HashMap, HashSet},
+    ffi::OsStr,
     net::{Ipv4Addr, SocketAddrV4},
     path::Path,
 };
@@ -177,6 +193,27 @@ pub struct TlsRecord {
     pub message_length: u32,
     /// TLS version choosen by the server, if this is the ServerHello handshake message
     pub tls_version: Option<TlsVersion>,
+    /// Cipher suite choosen by the server, if this is the ServerHello handshake message
+    #[serde(default)]
+    pub cipher_suite: Option<u16>,
+    /// The server accepted to resume a previous session, if this is the ServerHello handshake message
+    #[serde(default)]
+    pub session_resumed: bool,
+}
+
+impl TlsRecord {
+    /// Size of the DNS payload after removing the overhead according to `model`
+    pub fn payload_size(&self, session: TlsSessionParameters, model: TlsOverheadModel) -> u32 {
+        let overhead = match model {
+            TlsOverheadModel::Constant(overhead) => overhead,
+            TlsOverheadModel::Negotiated { fallback } => session
+                .record_overhead()
+                // The DNS over TLS length prefix
+                .map(|overhead| overhead + 2)
+                .unwrap_or(fallback),
+        };
+        self.message_length.saturating_sub(overhead)
+    }
 }
 
 impl From<&TlsRecord> for AbstractQueryResponse {
@@ -184,7 +221,133 @@ impl From<&TlsRecord> for AbstractQueryResponse {
         Self {
             time: tls.time,
             // Substract some overhead from the TLS encryption
-            size: tls.message_length - 40,
+            size: tls.payload_size(TlsSessionParameters::default(), TlsOverheadModel::default()),
+        }
+    }
+}
+
+/// Parameters of a TLS connection as negotiated in the ServerHello
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug, Default, Deserialize, Serialize)]
+pub struct TlsSessionParameters {
+    pub tls_version: Option<TlsVersion>,
+    pub cipher_suite: Option<u16>,
+    pub resumed: bool,
+}
+
+impl TlsSessionParameters {
+    /// Collect the parameters from the ServerHello within `records`
+    pub fn from_records<'a>(records: impl IntoIterator<Item = &'a TlsRecord>) -> Self {
+        let mut res = Self::default();
+        for rec in records {
+            if rec.tls_version.is_some() {
+                res.tls_version = rec.tls_version;
+            }
+            if rec.cipher_suite.is_some() {
+                res.cipher_suite = rec.cipher_suite;
+            }
+            res.resumed |= rec.session_resumed;
+        }
+        res
+    }
+
+    /// Number of bytes each encrypted TLS record is larger than its plaintext
+    ///
+    /// Returns [`None`] for unknown versions and non-AEAD cipher suites.
+    pub fn record_overhead(&self) -> Option<u32> {
+        // Size of the authentication tag
+        let tag = match self.cipher_suite? {
+            // TLS_AES_128_CCM_8_SHA256 and the TLS 1.2 CCM_8 cipher suites
+            0x1305 | 0xc0a0..=0xc0a3 | 0xc0ae..=0xc0af => 8,
+            // TLS 1.3 GCM, ChaCha20-Poly1305, and CCM
+            0x1301..=0x1304
+            // TLS 1.2 GCM
+            | 0x009c..=0x009f
+            | 0xc02b..=0xc030
+            // TLS 1.2 CCM
+            | 0xc09c..=0xc09f
+            | 0xc0ac..=0xc0ad
+            // TLS 1.2 ChaCha20-Poly1305
+            | 0xcca8..=0xccaa => 16,
+            _ => return None,
+        };
+        let is_chacha = matches!(self.cipher_suite?, 0x1303 | 0xcca8..=0xccaa);
+        match self.tls_version? {
+            // The inner content type byte
+            TlsVersion::Tls1_3 => Some(tag + 1),
+            // GCM and CCM use an 8 byte explicit nonce
+            TlsVersion::Tls1_2 if is_chacha => Some(tag),
+            TlsVersion::Tls1_2 => Some(tag + 8),
+            TlsVersion::Unknown => None,
+        }
+    }
+}
+
+/// Summary of the TLS connections to the DNS server within a single pcap file
+///
+/// This allows to monitor the behavior of the resolver across a dataset.
+#[serde_as]
+#[derive(Clone, Eq, PartialEq, Debug, Deserialize, Serialize)]
+pub struct PcapSummary {
+    /// The DNS server, either specified or guessed
+    pub server: SocketAddrV4,
+    /// Number of TLS connections to the DNS server
+    pub connections: usize,
+    /// TLS version of the connection containing the most DNS records
+    pub tls_version: Option<TlsVersion>,
+    /// Cipher suite of the connection containing the most DNS records
+    pub cipher_suite: Option<u16>,
+    /// At least one of the connections resumed a previous TLS session
+    pub resumed: bool,
+    /// Number of TLS records of each type in all connections to the DNS server
+    #[serde_as(as = "Vec<(_, _)>")]
+    pub record_counts: BTreeMap<MessageType, usize>,
+    /// Number of TLS records left after filtering, i.e., which are used to build the [`Sequence`]
+    pub dns_records: usize,
+    /// Number of packets in the file, which were not fully captured
+    ///
+    /// If this is non-zero, the [`Sequence`] might be incomplete.
+    #[serde(default)]
+    pub truncated_packets: usize,
+}
+
+impl PcapSummary {
+    fn new(
+        server: SocketAddrV4,
+        unfiltered: &HashMap<TwoWayFlowIdentifier, Vec<TlsRecord>>,
+        filtered: &HashMap<TwoWayFlowIdentifier, (TlsSessionParameters, Vec<TlsRecord>)>,
+        truncated_packets: usize,
+    ) -> Self {
+        let is_server_flow = |id: &TwoWayFlowIdentifier| {
+            let flow = id.0;
+            SocketAddrV4::new(flow.source_ip, flow.source_port) == server
+                || SocketAddrV4::new(flow.destination_ip, flow.destination_port) == server
+        };
+
+        let mut record_counts = BTreeMap::new();
+        let mut connections = 0;
+        for (_id, records) in unfiltered.iter().filter(|(id, _)| is_server_flow(id)) {
+            connections += 1;
+            for rec in records {
+                *record_counts.entry(rec.message_type).or_default() += 1;
+            }
+        }
+
+        let server_flows = || filtered.iter().filter(|(id, _)| is_server_flow(id));
+        let main_session = server_flows()
+            .max_by_key(|(_id, (_session, records))| records.len())
+            .map(|(_id, (session, _records))| *session)
+            .unwrap_or_default();
+        Self {
+            server,
+            connections,
+            tls_version: main_session.tls_version,
+            cipher_suite: main_session.cipher_suite,
+            resumed: server_flows().any(|(_id, (session, _records))| session.resumed),
+            record_counts,
+            dns_records: server_flows()
+                .map(|(_id, (_session, records))| records.len())
+                .sum(),
+            truncated_packets,
         }
     }
 }
@@ -204,9 +367,29 @@ impl Ord for TlsRecord {
 /// First step in processing a pcap file, extracting *all* Tls records
 ///
 /// This extracts all Tls records from the pcap file, from both client and server.
+/// Additionally, the number of truncated packets is returned, which can only be non-zero for [`TruncationMode::Lenient`].
 fn extract_tls_records(
     file: impl AsRef<Path>,
-) -> Result<HashMap<TwoWayFlowIdentifier, Vec<TlsRecord>>, Error> {
+    truncation: TruncationMode,
+) -> Result<(HashMap<TwoWayFlowIdentifier, Vec<TlsRecord>>, usize), Error> {
+    let mut tls_records: HashMap<TwoWayFlowIdentifier, Vec<TlsRecord>> = HashMap::default();
+    let truncated_packets = parse_tls_messages(file, truncation, |record, _message| {
+        tls_records
+            .entry(FlowIdentifier::from(&record).into())
+            .or_default()
+            .push(record);
+    })?;
+    Ok((tls_records, truncated_packets))
+}
+
+/// Parse all TLS messages of the pcap file and call `on_message` for each of them in capture order
+///
+/// Returns the number of truncated packets.
+fn parse_tls_messages(
+    file: impl AsRef<Path>,
+    truncation: TruncationMode,
+    mut on_message: impl FnMut(TlsRecord, &OpaqueTlsMessage),
+) -> Result<usize, Error> {
     let file_content = fs::read(file)?;
     let capture = PcapCapture::from_file(&file_content).map_err(|err| match err {
         PcapError::Eof => anyhow!("Failed reading pcap: EOF"),
@@ -221,8 +404,6 @@ fn extract_tls_records(
     // ID of the packet with in the pcap file.
     // Makes it easier to map it to the same packet within wireshark
     let mut packet_id = 0;
-    // List of all parsed TLS records
-    let mut tls_records: HashMap<TwoWayFlowIdentifier, Vec<TlsRecord>> = HashMap::default();
     // Buffer all unprocessed bytes.
     //
     // It needs to be a HashMap, because it needs to be stored per direction.
@@ -241,12 +422,22 @@ fn extract_tls_records(
     let mut next_time: HashMap<FlowIdentifier, Option<NaiveDateTime>> = HashMap::default();
     // Keep a list of flowids and processed sequence numbers to be able to detect retransmissions
     let mut seen_sequences: BoundedBuffer<(FlowIdentifier, u32)> = BoundedBuffer::new(30);
+    // Number of packets for which not all bytes were captured
+    let mut truncated_packets = 0;
+    // Flows which contain zero-filled data due to truncated packets
+    let mut truncated_flows: HashSet<FlowIdentifier> = HashSet::default();
+    // Session IDs offered in the ClientHello, to detect TLS 1.2 session resumption
+    let mut client_session_ids: HashMap<TwoWayFlowIdentifier, TlsSessionId> = HashMap::default();
 
     (|| {
         'packet: for (id, pkt) in capture.blocks.into_iter().enumerate() {
             packet_id = id as u32 + 1;
-            if pkt.caplen != pkt.origlen {
-                bail!("Cannot process packets, as they are truncated");
+            let is_truncated = pkt.caplen != pkt.origlen;
+            if is_truncated {
+                if truncation == TruncationMode::Strict {
+                    bail!("Cannot process packets, as they are truncated");
+                }
+                truncated_packets += 1;
             }
 
             // Try extracting an IPv4 packet from the raw bytes we have
@@ -261,20 +452,29 @@ fn extract_tls_records(
                 }
                 Some(PacketData::L2(data)) => {
                     // Normal Ethernet captures
-                    parsed_packet =
-                        SlicedPacket::from_ethernet(data).map_err(|err| anyhow!("{:?}", err))?;
+                    parsed_packet = SlicedPacket::from_ethernet(data);
                 }
                 Some(PacketData::L3(_, data)) => {
                     // Linux cooked capture
                     // Used for capturing the `any` device
-                    parsed_packet =
-                        SlicedPacket::from_ip(data).map_err(|err| anyhow!("{:?}", err))?;
+                    parsed_packet = SlicedPacket::from_ip(data);
                 }
             };
+            let parsed_packet = match parsed_packet {
+                Ok(parsed_packet) => parsed_packet,
+                // The headers might be cut off, so there is nothing we can do
+                Err(err) if is_truncated => {
+                    debug!("({:>2}) Skip truncated packet: {:?}", packet_id, err);
+                    continue;
+                }
+                Err(err) => bail!("{:?}", err),
+            };
+            // The flow identifiers only support IPv4, so skip IPv6 and other packets
             if let Some(InternetSlice::Ipv4(inner, _)) = parsed_packet.ip {
                 ipv4 = inner;
             } else {
-                bail!("Could not find an IPv4 packet for packet_id: {}", packet_id);
+                debug!("({:>2}) Skip non-IPv4 packet", packet_id);
+                continue;
             }
 
             // Only process TCP packets, skip rest
@@ -284,8 +484,19 @@ fn extract_tls_records(
                 continue;
             }
 
+            let mut payload = Cow::Borrowed(parsed_packet.payload);
+            if is_truncated {
+                // Restore the original payload length by filling it with zeros.
+                // We are only interested in the TLS record headers, which are usually at the start of a segment.
+                let full_len = usize::from(ipv4.total_len())
+                    .saturating_sub(usize::from(ipv4.ihl()) * 4 + usize::from(tcp.header_len()));
+                if full_len > payload.len() {
+                    payload.to_mut().resize(full_len, 0);
+                }
+            }
+
             // Filter empty acknowledgements
-            if parsed_packet.payload.is_empty() {
+            if payload.is_empty() {
                 continue;
             }
 
@@ -297,6 +508,9 @@ fn extract_tls_records(
             }
 
             let flowid = FlowIdentifier::from_ip_and_tcp(&ipv4, &tcp);
+            if let Cow::Owned(_) = payload {
+                truncated_flows.insert(flowid);
+            }
 
             // We only want to keep unique entries and filter out all retransmissions
             if !seen_sequences.add((flowid, tcp.sequence_number())) {
@@ -305,7 +519,7 @@ fn extract_tls_records(
             }
 
             let buffer = buffer_unprocessed.entry(flowid).or_default();
-            buffer.add_data(tcp.sequence_number(), parsed_packet.payload);
+            buffer.add_data(tcp.sequence_number(), &payload);
 
             // We only want to keep the next_time of the previous iteration, if we have a partially processed packet
             if buffer.is_empty() {
@@ -320,6 +534,17 @@ fn extract_tls_records(
             while !buffer.is_empty() {
                 let tls = match OpaqueTlsMessage::read(&mut Reader::init(buffer.view_data())) {
                     Ok(tls) => tls,
+                    // A zero-filled TLS record header cannot be parsed.
+                    // Drop the buffered data to resync with the start of the next segment.
+                    Err(TlsMessageError::IllegalLength)
+                    | Err(TlsMessageError::IllegalContentType)
+                    | Err(TlsMessageError::IllegalProtocolVersion)
+                        if truncated_flows.contains(&flowid) =>
+                    {
+                        debug!("({:>2}) Drop unparsable data of truncated flow", packet_id);
+                        buffer.clear_data();
+                        continue 'packet;
+                    }
                     // We cannot parse the packet yet, so just skip the processing
                     Err(_) => continue 'packet,
                 };
@@ -334,24 +559,44 @@ fn extract_tls_records(
                 );
 
                 let mut tls_version = None;
+                let mut cipher_suite = None;
+                let mut session_resumed = false;
 
                 // See if this is a server send ServerHello with a version
                 if let Ok(TlsMessagePayload::Handshake(handshake_payload)) =
                     TlsMessagePayload::new(tls.typ, tls.version, tls.payload.clone())
                 {
-                    if let TlsHandshakePayload::ServerHello(server_hello) =
-                        handshake_payload.payload
-                    {
-                        let mut min_version = server_hello.legacy_version.into();
-                        for ext in &server_hello.extensions {
-                            if let TlsServerExtensions::SupportedVersions(vers) = ext {
-                                let vers = vers.into();
-                                if vers > min_version {
-                                    min_version = vers;
+                    match handshake_payload.payload {
+                        TlsHandshakePayload::ClientHello(client_hello) => {
+                            client_session_ids.insert(flowid.into(), client_hello.session_id);
+                        }
+                        TlsHandshakePayload::ServerHello(server_hello) => {
+                            let mut min_version = server_hello.legacy_version.into();
+                            for ext in &server_hello.extensions {
+                                if let TlsServerExtensions::SupportedVersions(vers) = ext {
+                                    let vers = vers.into();
+                                    if vers > min_version {
+                                        min_version = vers;
+                                    }
                                 }
                             }
+                            tls_version = Some(min_version);
+                            cipher_suite = Some(server_hello.cipher_suite.get_u16());
+
+                            session_resumed = if min_version == TlsVersion::Tls1_3 {
+                                // TLS 1.3 always echos the session ID, but only a resumption selects a PSK
+                                server_hello
+                                    .extensions
+                                    .iter()
+                                    .any(|ext| matches!(ext, TlsServerExtensions::PresharedKey(_)))
+                            } else {
+                                // TLS 1.2 accepts a resumption by echoing the session ID of the client
+                                !server_hello.session_id.is_empty()
+                                    && client_session_ids.get(&flowid.into())
+                                        == Some(&server_hello.session_id)
+                            };
                         }
-                        tls_version = Some(min_version);
+                        _ => {}
                     }
                 };
                 let record = TlsRecord {
@@ -365,8 +610,10 @@ fn extract_tls_records(
                     message_type: tls.typ.into(),
                     message_length: tls.payload.0.len() as u32,
                     tls_version,
+                    cipher_suite,
+                    session_resumed,
                 };
-                tls_records.entry(flowid.into()).or_default().push(record);
+                on_message(record, &tls);
 
                 // Now that we build the TLS record, we can update the time
                 next_time.insert(flowid, Some(time));
@@ -376,23 +623,38 @@ fn extract_tls_records(
     })()
     .with_context(|| format!("Packet ID: {}", packet_id))?;
 
-    Ok(tls_records)
+    if truncated_packets > 0 {
+        warn!(
+            "Processed {} truncated packets, the sequence might be incomplete",
+            truncated_packets
+        );
+    }
+    Ok(truncated_packets)
 }
 
 /// Filter a list of TLS records and only return *interesting* ones
 ///
 /// The interesting TLS records are those needed to build the feature set.
 /// This means only those containing DNS traffic and maybe only the client or server.
+///
+/// With `both_directions`, the records of the client are kept too.
 fn filter_tls_records(
     records: Vec<TlsRecord>,
     (server, server_port): (Ipv4Addr, u16),
+    both_directions: bool,
 ) -> Vec<TlsRecord> {
     let base_message_size = 128;
     let client_marker_query_size = 128 * 3;
 
-    // First we ignore everything until we have seen the ChangeCipherSpec message from sever and client
+    // First we ignore everything until the handshake is done.
+    // For TLS 1.2 this is signaled by the ChangeCipherSpec message from sever and client.
     // This tells us that the initial unencrypted part of the handshake is done.
     //
+    // TLS 1.3 only sends ChangeCipherSpec messages for middlebox compatibility, they are missing in many connections, e.g., with session resumption.
+    // With a HelloRetryRequest, they can even be sent before the actual handshake.
+    // Here the handshake is done with the client's Finished message, which is the first ApplicationData record of the client after the server sent its encrypted handshake messages.
+    // This also ignores any 0-RTT data, as it is sent before the ServerHello.
+    //
     // Then we wait for the transmission of the aaa.aaa.aaa.aaa query and the corresponding response.
     // They can both be recognized by their large size, of at least 3*128 bytes (255 Qname + header overhead + block padding).
     // Afterwards, we check for a small query, the `start.example` one.
@@ -402,32 +664,60 @@ fn filter_tls_records(
     // This might need adapting later on.
 
     trace!("Filter TLS Server: {} {}", server, server_port);
-    let mut tls_version = None;
+    let is_server = |rec: &TlsRecord| rec.sender == server && rec.sender_port == server_port;
+    let session = TlsSessionParameters::from_records(&records);
+    let tls_version = session.tls_version;
+    let uses_change_cipher_spec = tls_version != Some(TlsVersion::Tls1_3)
+        && records
+            .iter()
+            .any(|rec| rec.message_type == MessageType::ChangeCipherSpec && is_server(rec))
+        && records
+            .iter()
+            .any(|rec| rec.message_type == MessageType::ChangeCipherSpec && !is_server(rec));
+    if !uses_change_cipher_spec {
+        debug!(
+            "Align TLS handshake without ChangeCipherSpec (TLS version {:?}, resumed {})",
+            tls_version, session.resumed
+        );
+    }
+
     let mut has_seen_server_change_cipher_spec = false;
     let mut has_seen_client_change_cipher_spec = false;
+    let mut has_seen_server_application_data = false;
+    let mut has_seen_client_finished = false;
     let mut has_seen_large_marker_query = false;
     let mut has_seen_start_marker_query = false;
     let mut has_seen_end_marker_query = false;
     let mut records: Vec<_> = records
         .into_iter()
-        .inspect(|rec| {
-            if rec.tls_version.is_some() {
-                tls_version = rec.tls_version;
-            }
-        })
         .skip_while(|rec| {
-            if rec.message_type == MessageType::ChangeCipherSpec {
-                if rec.sender == server && rec.sender_port == server_port {
-                    has_seen_server_change_cipher_spec = true;
-                } else {
-                    has_seen_client_change_cipher_spec = true;
+            if uses_change_cipher_spec {
+                if rec.message_type == MessageType::ChangeCipherSpec {
+                    if is_server(rec) {
+                        has_seen_server_change_cipher_spec = true;
+                    } else {
+                        has_seen_client_change_cipher_spec = true;
+                    }
                 }
-            }
 
-            if has_seen_server_change_cipher_spec && has_seen_client_change_cipher_spec {
-                trace!("Second ChangeCipherSpec seen in ID: {}", rec.packet_in_pcap);
+                if has_seen_server_change_cipher_spec && has_seen_client_change_cipher_spec {
+                    trace!("Second ChangeCipherSpec seen in ID: {}", rec.packet_in_pcap);
+                }
+                !(has_seen_server_change_cipher_spec && has_seen_client_change_cipher_spec)
+            } else {
+                if rec.message_type == MessageType::ApplicationData {
+                    if is_server(rec) {
+                        has_seen_server_application_data = true;
+                    } else if has_seen_server_application_data {
+                        has_seen_client_finished = true;
+                    }
+                }
+
+                if has_seen_client_finished {
+                    trace!("Client Finished seen in ID: {}", rec.packet_in_pcap);
+                }
+                !has_seen_client_finished
             }
-            !(has_seen_server_change_cipher_spec && has_seen_client_change_cipher_spec)
         })
         // Filter for the large marker query aaa.aaa.aaa.aaa
         .skip_while(|rec| {
@@ -468,11 +758,11 @@ fn filter_tls_records(
             !has_seen_end_marker_query
         })
         // Only keep the server replies
-        .filter(|rec| rec.sender == server && rec.sender_port == server_port)
+        .filter(|rec| both_directions || is_server(rec))
         // Only keep `Application Data` entries
         .filter(|rec| rec.message_type == MessageType::ApplicationData)
-        // Skip the start marker query responses
-        .skip(1)
+        // Skip the start marker query responses, and with both directions also the query
+        .skip(if both_directions { 2 } else { 1 })
         .collect();
 
     // if the connection is build using TLSv1.2 the messages are not necessarily padded to 128 bytes
@@ -489,11 +779,38 @@ fn filter_tls_records(
         // but only if we are sure we observed it.
         // The last part is important as sometimes the `end.example.` and
         // zzz.zzz.zzz.zzz queries are part of a new TCP session due to timeouts.
-        records.truncate(records.len().saturating_sub(1));
+        let marker_records = if both_directions { 2 } else { 1 };
+        records.truncate(records.len().saturating_sub(marker_records));
     }
     records
 }
 
+/// Specifies which TLS connections and records are used to build the [`Sequence`]
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug, Default)]
+pub struct PcapFilter {
+    /// IP and port of the DNS server
+    ///
+    /// If unspecified, it is guessed based on the common DNS over TLS ports.
+    pub server: Option<SocketAddrV4>,
+    /// Only consider connections from this client IP
+    ///
+    /// This is necessary if the capture contains connections of multiple clients to the same server.
+    pub client: Option<Ipv4Addr>,
+    /// Keep the records of the client in addition to the ones of the server
+    ///
+    /// The client records are then part of the [`Sequence`] and are converted the same way as the server records.
+    pub both_directions: bool,
+}
+
+impl From<Option<SocketAddrV4>> for PcapFilter {
+    fn from(server: Option<SocketAddrV4>) -> Self {
+        Self {
+            server,
+            ..Self::default()
+        }
+    }
+}
+
 /// Perform all the steps to generate a [`Sequence`] from a pcap-file
 pub fn build_sequence(
     file: &Path,
@@ -501,80 +818,138 @@ pub fn build_sequence(
     verbose: bool,
     config: LoadSequenceConfig,
 ) -> Result<Sequence, Error> {
-    let records = extract_and_filter_tls_records_from_file(file, filter, verbose)?;
+    build_sequence_with_summary(file, filter.into(), verbose, config).map(|(seq, _summary)| seq)
+}
+
+/// Same as [`build_sequence`] but also return a [`PcapSummary`] of the TLS connections
+///
+/// The [`PcapFilter`] allows more control over which records are used.
+pub fn build_sequence_with_summary(
+    file: &Path,
+    filter: PcapFilter,
+    verbose: bool,
+    config: LoadSequenceConfig,
+) -> Result<(Sequence, PcapSummary), Error> {
+    let (summary, records) =
+        extract_and_filter_tls_records_from_file(file, filter, verbose, config.truncation)?;
     let records: Vec<_> = records
         .into_iter()
-        .flat_map(|(_id, recs)| recs)
-        .sorted()
+        .flat_map(|(_id, (session, recs))| recs.into_iter().map(move |rec| (session, rec)))
+        .sorted_by_key(|(_session, rec)| rec.time)
+        .map(|(session, rec)| AbstractQueryResponse {
+            time: rec.time,
+            size: rec.payload_size(session, config.tls_overhead),
+        })
         .collect();
-    crate::convert_to_sequence(&records, file.to_string_lossy().to_string(), config).ok_or_else(
-        || {
+    let metadata = SequenceMetadata {
+        capture_start: records.first().map(|rec| DateTime::from_utc(rec.time, Utc)),
+        resolver: Some(summary.server.into()),
+        truncated_packets: summary.truncated_packets,
+        ..SequenceMetadata::for_file(file, config)?
+    };
+    let seq = crate::try_convert_to_sequence(records, file.to_string_lossy().to_string(), config)?
+        .ok_or_else(|| {
             anyhow!(
                 "Could not build Sequence from extracted TLS records for file {}",
                 file.display()
             )
-        },
-    )
+        })?
+        .with_metadata(metadata);
+    Ok((seq, summary))
 }
 
 /// Perform all the steps to generate a [`PrecisionSequence`] from a pcap-file
+///
+/// The message sizes and truncated packets are handled according to the `tls_overhead` and `truncation` of the `config`, like for [`build_sequence`].
 pub fn build_precision_sequence(
     file: &Path,
     filter: Option<SocketAddrV4>,
     verbose: bool,
+    config: LoadSequenceConfig,
 ) -> Result<PrecisionSequence, Error> {
-    let records = extract_and_filter_tls_records_from_file(file, filter, verbose)?;
+    let (_summary, records) =
+        extract_and_filter_tls_records_from_file(file, filter.into(), verbose, config.truncation)?;
     let records: Vec<_> = records
         .into_iter()
-        .flat_map(|(_id, recs)| recs)
-        .sorted()
+        .flat_map(|(_id, (session, recs))| recs.into_iter().map(move |rec| (session, rec)))
+        .sorted_by_key(|(_session, rec)| rec.time)
+        .map(|(session, rec)| AbstractQueryResponse {
+            time: rec.time,
+            size: rec.payload_size(session, config.tls_overhead),
+        })
         .collect();
-    crate::load_sequence::convert_to_precision_sequence(
-        &records,
-        file.to_string_lossy().to_string(),
-    )
-    .ok_or_else(|| {
-        anyhow!(
-            "Could not build PrecisionSequence from extracted TLS records for file {}",
-            file.display()
-        )
-    })
+    crate::load_sequence::convert_to_precision_sequence(records, file.to_string_lossy().to_string())
+        .ok_or_else(|| {
+            anyhow!(
+                "Could not build PrecisionSequence from extracted TLS records for file {}",
+                file.display()
+            )
+        })
 }
 
 /// Extract TLS records from a file and filter them to only contain DNS entries
+///
+/// The [`TlsSessionParameters`] of each flow and the [`PcapSummary`] are determined before the filtering, which removes the handshake.
+#[allow(clippy::type_complexity)]
 fn extract_and_filter_tls_records_from_file(
     file: &Path,
-    mut filter: Option<SocketAddrV4>,
+    filter: PcapFilter,
     verbose: bool,
-) -> Result<HashMap<TwoWayFlowIdentifier, Vec<TlsRecord>>, Error> {
+    truncation: TruncationMode,
+) -> Result<
+    (
+        PcapSummary,
+        HashMap<TwoWayFlowIdentifier, (TlsSessionParameters, Vec<TlsRecord>)>,
+    ),
+    Error,
+> {
     // Extract TLS records
-    let mut records = extract_tls_records(&file)?;
+    let (mut records, truncated_packets) =
+        if file.extensions().any(|ext| ext == OsStr::new("tsharkjson")) {
+            (tshark::extract_tls_records(&file)?, 0)
+        } else {
+            extract_tls_records(&file, truncation)?
+        };
     trace!("Extracted TLS Recrods:\n{:#?}", records);
 
-    // Guess which connection contains the DNS flow if not manually specified
-    if filter.is_none() {
-        filter = Some(guess_dns_flow_identifier(&records)?);
+    // Only keep the connections of the client
+    if let Some(client) = filter.client {
+        records.retain(|id, _| id.0.source_ip == client || id.0.destination_ip == client);
     }
-    // Filter was set to Some() in the snippet above
-    let filter = filter.unwrap();
+
+    // Guess which connection contains the DNS flow if not manually specified
+    let server = match filter.server {
+        Some(server) => server,
+        None => guess_dns_flow_identifier(&records)?,
+    };
 
     // Filter to only those records containing DNS
-    records.values_mut().for_each(|records| {
-        // `filter_tls_records` takes the Vec by value, which is why we first need to move it out
-        // of the HashMap and back it afterwards.
-        let mut tmp = Vec::new();
-        mem::swap(records, &mut tmp);
-        tmp = filter_tls_records(tmp, (*filter.ip(), filter.port()));
-        mem::swap(records, &mut tmp);
-    });
+    let filtered: HashMap<_, _> = records
+        .iter()
+        .map(|(&id, records)| {
+            let session = TlsSessionParameters::from_records(records);
+            let records = filter_tls_records(
+                records.clone(),
+                (*server.ip(), server.port()),
+                filter.both_directions,
+            );
+            (id, (session, records))
+        })
+        .collect();
+    let summary = PcapSummary::new(server, &records, &filtered, truncated_packets);
+    let records = filtered;
 
     trace!("Extracted Flows:\n{:#?}", records);
     if verbose {
-        let records: Vec<_> = records.values().flatten().sorted().collect();
+        let records: Vec<_> = records
+            .values()
+            .flat_map(|(_session, records)| records)
+            .sorted()
+            .collect();
         eprintln!("{}", serde_json::to_string_pretty(&records).unwrap());
     }
 
-    Ok(records)
+    Ok((summary, records))
 
     // // Build final Sequence
     // let seq = build_sequence(records, file.to_string_lossy(), config);
@@ -635,3 +1010,204 @@ fn guess_dns_flow_identifier(
 
     bail!(make_error(endpoints))
 }
+
+#[cfg(test)]
+const TEST_SERVER: (Ipv4Addr, u16) = (Ipv4Addr::new(1, 1, 1, 1), 853);
+
+/// Create a [`TlsRecord`] between [`TEST_SERVER`] and a client using `client_port`
+///
+/// The time of the record is derived from the `packet_in_pcap`.
+#[cfg(test)]
+fn test_record(
+    packet_in_pcap: u32,
+    client_port: u16,
+    from_server: bool,
+    message_type: MessageType,
+    message_length: u32,
+) -> TlsRecord {
+    let server = TEST_SERVER;
+    let client = (Ipv4Addr::new(10, 0, 0, 2), client_port);
+    let ((sender, sender_port), (receiver, receiver_port)) = if from_server {
+        (server, client)
+    } else {
+        (client, server)
+    };
+    TlsRecord {
+        packet_in_pcap,
+        sender,
+        sender_port,
+        receiver,
+        receiver_port,
+        time: NaiveDateTime::from_timestamp(1_600_000_000, packet_in_pcap * 1_000_000),
+        message_type,
+        message_length,
+        tls_version: None,
+        cipher_suite: None,
+        session_resumed: false,
+    }
+}
+
+/// A resumed TLS 1.3 connection with the marker queries and two DNS responses in packets 9 and 11
+///
+/// The connection sends ChangeCipherSpec messages only for middlebox compatibility.
+#[cfg(test)]
+fn test_tls13_connection(client_port: u16) -> Vec<TlsRecord> {
+    use MessageType::*;
+
+    let rec = |packet, from_server, message_type, message_length| {
+        test_record(
+            packet,
+            client_port,
+            from_server,
+            message_type,
+            message_length,
+        )
+    };
+    vec![
+        rec(1, false, Handshake, 512),
+        TlsRecord {
+            tls_version: Some(TlsVersion::Tls1_3),
+            cipher_suite: Some(0x1302),
+            session_resumed: true,
+            ..rec(2, true, Handshake, 122)
+        },
+        rec(3, true, ChangeCipherSpec, 1),
+        // Encrypted handshake of the server, which is larger than the marker query
+        rec(4, true, ApplicationData, 500),
+        rec(5, false, ChangeCipherSpec, 1),
+        // Finished of the client
+        rec(6, false, ApplicationData, 53),
+        // aaa.aaa.aaa.aaa
+        rec(7, false, ApplicationData, 400),
+        rec(8, true, ApplicationData, 468),
+        // start.example.
+        rec(9, false, ApplicationData, 150),
+        rec(10, true, ApplicationData, 150),
+        // The DNS traffic
+        rec(11, true, ApplicationData, 256),
+        rec(12, false, ApplicationData, 150),
+        rec(13, true, ApplicationData, 128),
+        // end.example.
+        rec(14, false, ApplicationData, 150),
+        rec(15, true, ApplicationData, 128),
+        // zzz.zzz.zzz.zzz
+        rec(16, false, ApplicationData, 400),
+        rec(17, true, ApplicationData, 468),
+    ]
+}
+
+#[test]
+fn test_pcap_summary() {
+    use MessageType::*;
+
+    let server = SocketAddrV4::new(TEST_SERVER.0, TEST_SERVER.1);
+    let main_connection = test_tls13_connection(40000);
+    // An aborted TLS 1.2 connection without any DNS traffic
+    let aborted_connection = vec![
+        test_record(20, 40001, false, Handshake, 512),
+        TlsRecord {
+            tls_version: Some(TlsVersion::Tls1_2),
+            cipher_suite: Some(0xc02f),
+            ..test_record(21, 40001, true, Handshake, 90)
+        },
+        test_record(22, 40001, true, Alert, 2),
+    ];
+    // A connection to another server
+    let other_connection = vec![TlsRecord {
+        sender: Ipv4Addr::new(9, 9, 9, 9),
+        ..test_record(30, 40002, true, Handshake, 90)
+    }];
+
+    let unfiltered: HashMap<TwoWayFlowIdentifier, Vec<TlsRecord>> =
+        vec![main_connection, aborted_connection, other_connection]
+            .into_iter()
+            .map(|records| (FlowIdentifier::from(&records[0]).into(), records))
+            .collect();
+    let filtered = unfiltered
+        .iter()
+        .map(|(&id, records)| {
+            let session = TlsSessionParameters::from_records(records);
+            let records = filter_tls_records(records.clone(), TEST_SERVER, false);
+            (id, (session, records))
+        })
+        .collect();
+    let summary = PcapSummary::new(server, &unfiltered, &filtered, 0);
+
+    assert_eq!(summary.server, server);
+    assert_eq!(summary.connections, 2);
+    assert_eq!(summary.tls_version, Some(TlsVersion::Tls1_3));
+    assert_eq!(summary.cipher_suite, Some(0x1302));
+    assert!(summary.resumed);
+    assert_eq!(summary.dns_records, 2);
+    assert_eq!(
+        summary.record_counts,
+        vec![
+            (ChangeCipherSpec, 2),
+            (Alert, 1),
+            (Handshake, 4),
+            (ApplicationData, 13)
+        ]
+        .into_iter()
+        .collect()
+    );
+}
+
+#[test]
+fn test_filter_tls_records_without_change_cipher_spec() {
+    let packets = |records: Vec<TlsRecord>| -> Vec<u32> {
+        records.iter().map(|rec| rec.packet_in_pcap).collect()
+    };
+
+    // The encrypted handshake of the server must not be mistaken for the marker query
+    let connection = test_tls13_connection(40000);
+    assert_eq!(
+        packets(filter_tls_records(connection.clone(), TEST_SERVER, false)),
+        vec![11, 13]
+    );
+    assert_eq!(
+        packets(filter_tls_records(connection, TEST_SERVER, true)),
+        vec![11, 12, 13]
+    );
+
+    // Without any ChangeCipherSpec messages
+    let connection: Vec<_> = test_tls13_connection(40000)
+        .into_iter()
+        .filter(|rec| rec.message_type != MessageType::ChangeCipherSpec)
+        .collect();
+    assert_eq!(
+        packets(filter_tls_records(connection, TEST_SERVER, false)),
+        vec![11, 13]
+    );
+}
+
+#[test]
+fn test_filter_tls_records_with_change_cipher_spec() {
+    use MessageType::*;
+
+    let mut connection = vec![
+        test_record(1, 40000, false, Handshake, 512),
+        TlsRecord {
+            tls_version: Some(TlsVersion::Tls1_2),
+            cipher_suite: Some(0xc02f),
+            ..test_record(2, 40000, true, Handshake, 90)
+        },
+        test_record(3, 40000, false, ChangeCipherSpec, 1),
+        test_record(4, 40000, false, Handshake, 40),
+        test_record(5, 40000, true, ChangeCipherSpec, 1),
+        test_record(6, 40000, true, Handshake, 40),
+    ];
+    // Reuse the marker queries and DNS traffic of the TLS 1.3 connection
+    connection.extend(
+        test_tls13_connection(40000)
+            .into_iter()
+            .filter(|rec| rec.packet_in_pcap >= 7),
+    );
+    let records = filter_tls_records(connection, TEST_SERVER, false);
+    assert_eq!(
+        records
+            .iter()
+            .map(|rec| rec.packet_in_pcap)
+            .collect::<Vec<_>>(),
+        vec![11, 13]
+    );
+}
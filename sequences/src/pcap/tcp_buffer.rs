@@ -57,7 +57,6 @@ impl TcpBuffer {
     }
 
     /// Clear all the data stored in the buffer
-    #[allow(dead_code)]
     pub fn clear_data(&mut self) {
         self.buffer.clear();
         self.unprocessed_data.clear();
@@ -0,0 +1,347 @@
+//! Extract TLS records from Wireshark/tshark JSON dissections
+//!
+//! The input is the output of `tshark -r <file.pcap> -T json`.
+//! This allows processing traces for which only the dissected metadata is available, but not the raw pcap.
+//!
+//! Tshark reassembles TLS records spanning multiple TCP segments and attributes them to the last segment.
+//! The pcap parser uses the time of the first segment instead, which can cause small timing differences between both formats.
+//! Retransmissions are not removed by tshark, so they should be filtered out before the export, e.g., with `-Y '!tcp.analysis.retransmission'`.
+
+use super::{FlowIdentifier, MessageType, TlsRecord, TlsVersion, TwoWayFlowIdentifier};
+use anyhow::{anyhow, bail, Context as _, Error};
+use chrono::NaiveDateTime;
+use log::debug;
+use misc_utils::fs;
+use serde_json::Value;
+use std::{collections::HashMap, path::Path, str::FromStr};
+
+/// First step in processing a tshark JSON file, extracting *all* Tls records
+///
+/// This is the equivalent of [`extract_tls_records`](super::extract_tls_records) for pcap files.
+pub(super) fn extract_tls_records(
+    file: impl AsRef<Path>,
+) -> Result<HashMap<TwoWayFlowIdentifier, Vec<TlsRecord>>, Error> {
+    let file = file.as_ref();
+    let content = fs::read_to_string(file)
+        .with_context(|| format!("Cannot read file `{}`", file.display()))?;
+    let packets: Vec<Value> = serde_json::from_str(&content)
+        .with_context(|| format!("File `{}` is not a tshark JSON export", file.display()))?;
+
+    let mut tls_records: HashMap<TwoWayFlowIdentifier, Vec<TlsRecord>> = HashMap::default();
+    for (idx, packet) in packets.iter().enumerate() {
+        let records = records_from_packet(packet)
+            .with_context(|| format!("Invalid packet at index {}", idx))?;
+        for record in records {
+            tls_records
+                .entry(FlowIdentifier::from(&record).into())
+                .or_default()
+                .push(record);
+        }
+    }
+    Ok(tls_records)
+}
+
+/// Convert all TLS records of a single tshark packet
+fn records_from_packet(packet: &Value) -> Result<Vec<TlsRecord>, Error> {
+    let layers = &packet["_source"]["layers"];
+    let (ip, tcp) = match (layers.get("ip"), layers.get("ipv6"), layers.get("tcp")) {
+        (Some(ip), None, Some(tcp)) => (ip, tcp),
+        // The flow identifiers only support IPv4, so skip IPv6 and tunneled packets
+        (_, Some(_), _) => {
+            debug!(
+                "Skip IPv6 packet {}",
+                layers["frame"]["frame.number"].as_str().unwrap_or("?")
+            );
+            return Ok(Vec::new());
+        }
+        // Only process TCP packets, skip rest
+        _ => return Ok(Vec::new()),
+    };
+    // Older versions of wireshark call the TLS dissector `ssl`
+    let (prefix, tls_layers) = match (layers.get("tls"), layers.get("ssl")) {
+        (Some(tls), _) => ("tls", tls),
+        (None, Some(ssl)) => ("ssl", ssl),
+        (None, None) => return Ok(Vec::new()),
+    };
+
+    let frame = &layers["frame"];
+    let packet_in_pcap = parse_field(frame, "frame.number")?;
+    let time = parse_epoch_time(get_str(frame, "frame.time_epoch")?)?;
+    let sender = parse_field(ip, "ip.src")?;
+    let receiver = parse_field(ip, "ip.dst")?;
+    let sender_port = parse_field(tcp, "tcp.srcport")?;
+    let receiver_port = parse_field(tcp, "tcp.dstport")?;
+
+    let mut res = Vec::new();
+    for tls in as_list(tls_layers) {
+        let records = match tls.get(&format!("{}.record", prefix)) {
+            Some(records) => records,
+            None => continue,
+        };
+        for record in as_list(records) {
+            // Only the summary line exists, e.g., for ignored unknown records
+            if !record.is_object() {
+                continue;
+            }
+            let content_type: u8 = parse_field(record, &format!("{}.record.content_type", prefix))?;
+            let message_length = parse_field(record, &format!("{}.record.length", prefix))?;
+            res.push(TlsRecord {
+                packet_in_pcap,
+                sender,
+                sender_port,
+                receiver,
+                receiver_port,
+                time,
+                message_type: message_type_from_u8(content_type),
+                message_length,
+                tls_version: server_hello_version(record, prefix)?,
+                cipher_suite: server_hello_cipher_suite(record, prefix)?,
+                session_resumed: server_hello_selected_psk(record, prefix),
+            });
+        }
+    }
+    Ok(res)
+}
+
+fn is_server_hello(record: &Value, prefix: &str) -> bool {
+    let mut handshake_types = Vec::new();
+    find_values(
+        record,
+        &format!("{}.handshake.type", prefix),
+        &mut handshake_types,
+    );
+    // Handshake type 2 is the ServerHello
+    handshake_types.contains(&"2")
+}
+
+/// Determine the negotiated version if the record contains a ServerHello
+fn server_hello_version(record: &Value, prefix: &str) -> Result<Option<TlsVersion>, Error> {
+    if !is_server_hello(record, prefix) {
+        return Ok(None);
+    }
+
+    let mut versions = Vec::new();
+    find_values(
+        record,
+        &format!("{}.handshake.extensions.supported_version", prefix),
+        &mut versions,
+    );
+    if versions.is_empty() {
+        find_values(
+            record,
+            &format!("{}.handshake.version", prefix),
+            &mut versions,
+        );
+    }
+    let mut max_version = None;
+    for version in versions {
+        let version = u16::from_str_radix(version.trim_start_matches("0x"), 16)
+            .with_context(|| format!("Invalid TLS version `{}`", version))?;
+        max_version = max_version.max(Some(version));
+    }
+    Ok(max_version.map(|version| match version {
+        0x0303 => TlsVersion::Tls1_2,
+        0x0304 => TlsVersion::Tls1_3,
+        _ => TlsVersion::Unknown,
+    }))
+}
+
+/// Determine the cipher suite if the record contains a ServerHello
+fn server_hello_cipher_suite(record: &Value, prefix: &str) -> Result<Option<u16>, Error> {
+    if !is_server_hello(record, prefix) {
+        return Ok(None);
+    }
+
+    let mut cipher_suites = Vec::new();
+    find_values(
+        record,
+        &format!("{}.handshake.ciphersuite", prefix),
+        &mut cipher_suites,
+    );
+    cipher_suites
+        .first()
+        .map(|cipher_suite| {
+            u16::from_str_radix(cipher_suite.trim_start_matches("0x"), 16)
+                .with_context(|| format!("Invalid cipher suite `{}`", cipher_suite))
+        })
+        .transpose()
+}
+
+/// Check if the ServerHello selects a pre-shared key, i.e., resumes a TLS 1.3 session
+///
+/// TLS 1.2 resumption is not detected, as it requires comparing the session IDs of ClientHello and ServerHello.
+fn server_hello_selected_psk(record: &Value, prefix: &str) -> bool {
+    let mut extension_types = Vec::new();
+    find_values(
+        record,
+        &format!("{}.handshake.extension.type", prefix),
+        &mut extension_types,
+    );
+    // Extension 41 is the pre_shared_key extension
+    is_server_hello(record, prefix) && extension_types.contains(&"41")
+}
+
+fn message_type_from_u8(content_type: u8) -> MessageType {
+    match content_type {
+        20 => MessageType::ChangeCipherSpec,
+        21 => MessageType::Alert,
+        22 => MessageType::Handshake,
+        23 => MessageType::ApplicationData,
+        24 => MessageType::Heartbeat,
+        u => MessageType::Unknown(u),
+    }
+}
+
+/// Tshark uses a list if a layer or field occurs multiple times and the value itself otherwise
+fn as_list(value: &Value) -> Vec<&Value> {
+    match value {
+        Value::Array(values) => values.iter().collect(),
+        value => vec![value],
+    }
+}
+
+/// Recursively collect all string values stored under `key`
+fn find_values<'a>(value: &'a Value, key: &str, out: &mut Vec<&'a str>) {
+    match value {
+        Value::Object(map) => {
+            for (k, v) in map {
+                if k == key {
+                    out.extend(as_list(v).into_iter().filter_map(Value::as_str));
+                } else {
+                    find_values(v, key, out);
+                }
+            }
+        }
+        Value::Array(values) => values.iter().for_each(|v| find_values(v, key, out)),
+        _ => {}
+    }
+}
+
+fn get_str<'a>(value: &'a Value, key: &str) -> Result<&'a str, Error> {
+    value
+        .get(key)
+        .and_then(Value::as_str)
+        .ok_or_else(|| anyhow!("Missing field `{}`", key))
+}
+
+fn parse_field<T>(value: &Value, key: &str) -> Result<T, Error>
+where
+    T: FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    let s = get_str(value, key)?;
+    s.parse()
+        .with_context(|| format!("Invalid value `{}` for field `{}`", s, key))
+}
+
+/// Parse the `frame.time_epoch` value, which has the format `<seconds>.<fraction>`
+fn parse_epoch_time(s: &str) -> Result<NaiveDateTime, Error> {
+    let (secs, fraction) = match s.find('.') {
+        Some(idx) => (&s[..idx], &s[idx + 1..]),
+        None => (s, ""),
+    };
+    if fraction.len() > 9 || !fraction.bytes().all(|b| b.is_ascii_digit()) {
+        bail!("Invalid timestamp `{}`", s);
+    }
+    let secs: i64 = secs
+        .parse()
+        .with_context(|| format!("Invalid timestamp `{}`", s))?;
+    let nanos = if fraction.is_empty() {
+        0
+    } else {
+        fraction.parse::<u32>()? * 10u32.pow(9 - fraction.len() as u32)
+    };
+    NaiveDateTime::from_timestamp_opt(secs, nanos)
+        .ok_or_else(|| anyhow!("Invalid timestamp `{}`", s))
+}
+
+#[test]
+fn test_records_from_packet() {
+    use std::net::Ipv4Addr;
+
+    let packet: Value = serde_json::from_str(
+        r#"{
+            "_index": "packets-2019-10-10",
+            "_source": {
+                "layers": {
+                    "frame": {
+                        "frame.time_epoch": "1570713185.123456000",
+                        "frame.number": "12"
+                    },
+                    "ip": {"ip.src": "1.1.1.1", "ip.dst": "10.0.0.2"},
+                    "tcp": {"tcp.srcport": "853", "tcp.dstport": "40000"},
+                    "tls": {
+                        "tls.record": [
+                            {
+                                "tls.record.content_type": "22",
+                                "tls.record.version": "0x0303",
+                                "tls.record.length": "122",
+                                "tls.handshake": {
+                                    "tls.handshake.type": "2",
+                                    "tls.handshake.version": "0x0303",
+                                    "tls.handshake.ciphersuite": "0x1302",
+                                    "Extension: supported_versions (len=2)": {
+                                        "tls.handshake.extensions.supported_version": "0x0304"
+                                    }
+                                }
+                            },
+                            {
+                                "tls.record.content_type": "23",
+                                "tls.record.version": "0x0303",
+                                "tls.record.length": "468"
+                            }
+                        ]
+                    }
+                }
+            }
+        }"#,
+    )
+    .unwrap();
+
+    let records = records_from_packet(&packet).unwrap();
+    assert_eq!(records.len(), 2);
+    assert_eq!(records[0].packet_in_pcap, 12);
+    assert_eq!(records[0].sender, Ipv4Addr::new(1, 1, 1, 1));
+    assert_eq!(records[0].sender_port, 853);
+    assert_eq!(records[0].message_type, MessageType::Handshake);
+    assert_eq!(records[0].tls_version, Some(TlsVersion::Tls1_3));
+    assert_eq!(records[0].cipher_suite, Some(0x1302));
+    assert_eq!(records[1].message_type, MessageType::ApplicationData);
+    assert_eq!(records[1].message_length, 468);
+    assert_eq!(records[1].tls_version, None);
+    assert_eq!(
+        records[1].time,
+        NaiveDateTime::from_timestamp(1_570_713_185, 123_456_000)
+    );
+}
+
+#[test]
+fn test_records_from_packet_skips_ipv6_and_udp() {
+    let packet = |layers: &str| -> Value {
+        serde_json::from_str(&format!(
+            r#"{{"_source": {{"layers": {{
+                "frame": {{"frame.time_epoch": "1570713185.0", "frame.number": "1"}},
+                {},
+                "tls": {{"tls.record": {{"tls.record.content_type": "23", "tls.record.length": "10"}}}}
+            }}}}}}"#,
+            layers
+        ))
+        .unwrap()
+    };
+
+    let ipv6 = packet(
+        r#""ipv6": {"ipv6.src": "2001:db8::1", "ipv6.dst": "2001:db8::2"},
+        "tcp": {"tcp.srcport": "853", "tcp.dstport": "40000"}"#,
+    );
+    assert!(records_from_packet(&ipv6).unwrap().is_empty());
+    let udp = packet(
+        r#""ip": {"ip.src": "1.1.1.1", "ip.dst": "10.0.0.2"},
+        "udp": {"udp.srcport": "853", "udp.dstport": "40000"}"#,
+    );
+    assert!(records_from_packet(&udp).unwrap().is_empty());
+    let tcp = packet(
+        r#""ip": {"ip.src": "1.1.1.1", "ip.dst": "10.0.0.2"},
+        "tcp": {"tcp.srcport": "853", "tcp.dstport": "40000"}"#,
+    );
+    assert_eq!(records_from_packet(&tcp).unwrap().len(), 1);
+}
@@ -1,16 +1,19 @@
-mod adaptive_padding;
-
-use self::adaptive_padding::AdaptivePadding;
-use crate::{utils::Probability, AbstractQueryResponse, LoadSequenceConfig, Sequence};
+use crate::{
+    adaptive_padding::{AdaptivePaddingCore, ApRng, ApTraceWriter},
+    format_version::{check_format_version, read_format_version, VersionedFormat, FORMAT_VERSION},
+    utils::Probability,
+    AbstractQueryResponse, LoadSequenceConfig, Sequence,
+};
 #[cfg(feature = "read_pcap")]
-use anyhow::{anyhow, Context as _};
-use anyhow::{bail, Error};
+use anyhow::anyhow;
+use anyhow::{bail, Context as _, Error};
 use chrono::{Duration, NaiveDateTime};
 use fnv::FnvHasher;
 use misc_utils::{fs, path::PathExt};
 use rand::{distributions::Open01, Rng, SeedableRng};
 use rand_xorshift::XorShiftRng;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::{json, Value};
 use serde_with::{formats::Flexible, serde_as, DurationSecondsWithFrac};
 use std::{
     cmp::{max, min},
@@ -20,9 +23,25 @@ use std::{
 };
 
 /// This type is similar to [`Sequence`] but provides higher precision timestamps and sizes.
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug)]
 pub struct PrecisionSequence(Vec<PrecisionSequenceEvent>, String);
 
+/// Serialization format of [`PrecisionSequence`]
+#[derive(Serialize)]
+struct PrecisionSequenceRef<'a> {
+    format_version: u32,
+    id: &'a str,
+    events: &'a [PrecisionSequenceEvent],
+}
+
+/// Deserialization format of [`PrecisionSequence`]
+#[derive(Deserialize)]
+struct PrecisionSequenceOwned {
+    format_version: Option<u32>,
+    id: String,
+    events: Vec<PrecisionSequenceEvent>,
+}
+
 impl PrecisionSequence {
     /// Create a new [`PrecisionSequence`] from it's building blocks
     ///
@@ -45,13 +64,19 @@ impl PrecisionSequence {
                 Some("dnstap") => return crate::dnstap::build_precision_sequence(path),
                 #[cfg(feature = "read_pcap")]
                 Some("pcap") => {
-                    return crate::pcap::build_precision_sequence(path, None, false).with_context(
-                        || anyhow!("Could not build a sequence from the list of filtered records."),
-                    );
+                    return crate::pcap::build_precision_sequence(
+                        path,
+                        None,
+                        false,
+                        LoadSequenceConfig::default(),
+                    )
+                    .with_context(|| {
+                        anyhow!("Could not build a sequence from the list of filtered records.")
+                    });
                 }
                 Some("json") => {
                     let s = fs::read_to_string(path)?;
-                    return Ok(serde_json::from_str(&s)?);
+                    return crate::format_version::from_json_any_version(&s);
                 }
                 _ => {}
             }
@@ -126,65 +151,139 @@ impl PrecisionSequence {
         Self(events, self.1.clone())
     }
 
+    /// Seed of the [`ApRng`] used by [`PrecisionSequence::apply_adaptive_padding`]
+    ///
+    /// The seed only depends on the file name of the identifier.
+    /// Using it as the seed of the live Adaptive Padding yields the same schedule.
+    pub fn adaptive_padding_seed(&self) -> u64 {
+        let path = Path::new(&self.1);
+        let filename = path.file_name().unwrap();
+        let mut hasher = FnvHasher::with_key(0);
+        filename.hash(&mut hasher);
+        hasher.finish()
+    }
+
     #[must_use]
     pub fn apply_adaptive_padding(
         &self,
         median_burst_length: u32,
         probability_fake_burst: Probability,
+    ) -> Self {
+        self.apply_adaptive_padding_traced(median_burst_length, probability_fake_burst, None)
+    }
+
+    /// Same as [`PrecisionSequence::apply_adaptive_padding`] but records the internal state transitions into `trace`
+    ///
+    /// All records are tagged with the identifier of this sequence.
+    #[must_use]
+    pub fn apply_adaptive_padding_traced(
+        &self,
+        median_burst_length: u32,
+        probability_fake_burst: Probability,
+        trace: Option<&ApTraceWriter>,
     ) -> Self {
         // Setup a predictable RNG to randomly determine the ends
-        let path = Path::new(&self.1);
-        let filename = path.file_name().unwrap();
-        let mut hasher = FnvHasher::with_key(0);
-        filename.hash(&mut hasher);
-        let rng = XorShiftRng::seed_from_u64(hasher.finish());
+        let rng = ApRng::seed_from_u64(self.adaptive_padding_seed());
+
+        // All times of the state machine are relative to the first event
+        let start = self.0[0].time;
+        let to_offset = |time: NaiveDateTime| (time - start).to_std().unwrap_or_default();
+        let from_offset = |offset| start + Duration::from_std(offset).unwrap();
 
         // Internal state
         let mut events = vec![];
-        // Tracks the current simulated time
-        let mut now;
-        let mut ap = AdaptivePadding::new(
+        let mut ap = AdaptivePaddingCore::new(
             rng,
-            self.0[0].time,
             median_burst_length,
-            probability_fake_burst,
+            f64::from(probability_fake_burst.to_float()),
         );
+        ap.set_trace(trace.map(|trace| trace.with_id(&*self.1)));
 
         for event in self.0.iter().cloned() {
             // This loop handles all timeouts which happen BEFORE the current packet can be send
             // Each timeout generates a dummy packet
-            while event.time > ap.deadline {
-                now = ap.deadline;
+            let now = to_offset(event.time);
+            while let Some(deadline) = ap.deadline().filter(|&deadline| now > deadline) {
                 events.push(PrecisionSequenceEvent {
-                    time: now,
+                    time: from_offset(deadline),
                     size: 128,
                     is_dummy_event: true,
                 });
-                ap.handle_timeout(now);
+                ap.handle_timeout(deadline);
             }
 
             // Now that the time out this packet came send it
-            now = event.time;
-            ap.handle_application_payload(now);
+            ap.handle_payload(now);
             events.push(event);
         }
 
         // AP continues after the last real packet.
         // This processes all timeouts at the very end
-        while !ap.has_terminated() {
-            now = ap.deadline;
+        while let Some(deadline) = ap.deadline() {
             events.push(PrecisionSequenceEvent {
-                time: now,
+                time: from_offset(deadline),
                 size: 128,
                 is_dummy_event: true,
             });
-            ap.handle_timeout(now);
+            ap.handle_timeout(deadline);
         }
 
         assert!(self.0.len() <= events.len());
         Self(events, self.1.clone())
     }
 
+    /// Merge this [`PrecisionSequence`] with other traces on a common timeline
+    ///
+    /// Each of the `others` is shifted, such that its first event happens `offset` after the first event of this sequence.
+    /// The offset may be negative, if the other trace started earlier.
+    /// All events are kept, e.g., to simulate multiple page loads in parallel tabs.
+    #[must_use]
+    pub fn merge<'a>(
+        &self,
+        others: impl IntoIterator<Item = (&'a PrecisionSequence, Duration)>,
+        identifier: String,
+    ) -> Self {
+        let start = self.0[0].time;
+
+        let mut events = self.0.clone();
+        for (trace, offset) in others {
+            let shift = start + offset - trace.0[0].time;
+            events.extend(trace.0.iter().map(|event| PrecisionSequenceEvent {
+                time: event.time + shift,
+                ..event.clone()
+            }));
+        }
+        // The sort is stable, thus events of this sequence stay in front of simultaneous events of the others
+        events.sort_by_key(|event| event.time);
+
+        Self(events, identifier)
+    }
+
+    /// Interleave this [`PrecisionSequence`] with background traffic on a common timeline
+    ///
+    /// The background traces are placed like in [`PrecisionSequence::merge`].
+    /// Background events outside of the time span of this sequence are dropped, since they would not be recorded as part of the page load.
+    /// They count as real DNS messages, such that [`PrecisionSequence::to_sequence`] yields what an observer sees under mixed traffic.
+    #[must_use]
+    pub fn interleave<'a>(
+        &self,
+        background: impl IntoIterator<Item = (&'a PrecisionSequence, Duration)>,
+    ) -> Self {
+        let start = self.0[0].time;
+        let end = self.0[self.0.len() - 1].time;
+
+        let mut interleaved = self.merge(background, self.1.clone());
+        interleaved
+            .0
+            .retain(|event| start <= event.time && event.time <= end);
+        interleaved
+    }
+
+    /// Return all events, including the dummy events added by a countermeasure
+    pub fn events(&self) -> &[PrecisionSequenceEvent] {
+        &self.0
+    }
+
     pub fn count_queries(&self) -> usize {
         self.0.len()
     }
@@ -233,6 +332,47 @@ Total Duration: {}
     }
 }
 
+impl Serialize for PrecisionSequence {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        PrecisionSequenceRef {
+            format_version: FORMAT_VERSION,
+            id: &self.1,
+            events: &self.0,
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for PrecisionSequence {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let repr = PrecisionSequenceOwned::deserialize(deserializer)?;
+        check_format_version(repr.format_version)?;
+        Ok(PrecisionSequence(repr.events, repr.id))
+    }
+}
+
+impl VersionedFormat for PrecisionSequence {
+    fn migrate(value: Value) -> Result<Value, Error> {
+        if read_format_version(&value, "format_version")? == 1 {
+            // Version 1 is the tuple `[events, id]`
+            let (events, id): (Value, Value) = serde_json::from_value(value)
+                .context("A PrecisionSequence of format version 1 must be a list")?;
+            return Ok(json!({
+                "format_version": FORMAT_VERSION,
+                "id": id,
+                "events": events,
+            }));
+        }
+        Ok(value)
+    }
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct PrecisionSequenceEvent {
     time: NaiveDateTime,
@@ -240,6 +380,21 @@ pub struct PrecisionSequenceEvent {
     is_dummy_event: bool,
 }
 
+impl PrecisionSequenceEvent {
+    pub fn time(&self) -> NaiveDateTime {
+        self.time
+    }
+
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    /// Returns `true` if a countermeasure added this event, i.e., it does not carry a real DNS message
+    pub fn is_dummy_event(&self) -> bool {
+        self.is_dummy_event
+    }
+}
+
 impl From<AbstractQueryResponse> for PrecisionSequenceEvent {
     fn from(aqr: AbstractQueryResponse) -> Self {
         Self {
@@ -330,3 +485,80 @@ impl fmt::Display for Overhead {
         )
     }
 }
+
+#[test]
+fn test_interleave() {
+    let base = NaiveDateTime::from_timestamp(1_600_000_000, 0);
+    let trace = |id: &str, events: &[(i64, u32)]| {
+        PrecisionSequence::new(
+            events.iter().map(|&(millis, size)| AbstractQueryResponse {
+                time: base + Duration::milliseconds(millis),
+                size,
+            }),
+            id.to_string(),
+        )
+    };
+    let foreground = trace("foreground", &[(0, 100), (50, 200), (100, 300)]);
+    // Starts an hour later, but is shifted onto the timeline of the foreground
+    let background = trace(
+        "background",
+        &[(3_600_000, 50), (3_600_060, 60), (3_600_200, 70)],
+    );
+
+    let mixed = foreground.interleave(vec![(&background, Duration::milliseconds(20))]);
+    assert_eq!("foreground", mixed.id());
+    let events: Vec<_> = mixed
+        .events()
+        .iter()
+        .map(|event| ((event.time() - base).num_milliseconds(), event.size()))
+        .collect();
+    // The last background event happens after the page load and is dropped
+    assert_eq!(
+        vec![(0, 100), (20, 50), (50, 200), (80, 60), (100, 300)],
+        events
+    );
+    assert_eq!(foreground.duration(), mixed.duration());
+}
+
+#[test]
+fn test_merge() {
+    let base = NaiveDateTime::from_timestamp(1_600_000_000, 0);
+    let trace = |id: &str, events: &[(i64, u32)]| {
+        PrecisionSequence::new(
+            events.iter().map(|&(millis, size)| AbstractQueryResponse {
+                time: base + Duration::milliseconds(millis),
+                size,
+            }),
+            id.to_string(),
+        )
+    };
+    let first = trace("first", &[(0, 100), (50, 200)]);
+    let second = trace("second", &[(1_000, 50), (1_100, 60)]);
+    let third = trace("third", &[(500, 70), (520, 80)]);
+
+    // Unlike interleaving, the merged events may extend beyond the first trace
+    let merged = first.merge(
+        vec![
+            (&second, Duration::milliseconds(50)),
+            (&third, Duration::milliseconds(-10)),
+        ],
+        "merged".to_string(),
+    );
+    assert_eq!("merged", merged.id());
+    let events: Vec<_> = merged
+        .events()
+        .iter()
+        .map(|event| ((event.time() - base).num_milliseconds(), event.size()))
+        .collect();
+    assert_eq!(
+        vec![
+            (-10, 70),
+            (0, 100),
+            (10, 80),
+            (50, 200),
+            (50, 50),
+            (150, 60)
+        ],
+        events
+    );
+}
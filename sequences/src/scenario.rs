@@ -0,0 +1,334 @@
+//! Scenarios of overlapping page loads, e.g., multiple tabs opened at the same time
+//!
+//! A [`ScenarioGenerator`] samples a few domains and start offsets and merges one trace of each domain into a single [`Sequence`].
+//! The classifier then has to identify the constituent domains, which is scored with [`Scenario::evaluate`].
+
+use crate::{knn::ClassificationResult, precision_sequence::PrecisionSequence, Sequence};
+use anyhow::{bail, Error};
+use chrono::Duration;
+use rand::{seq::index, Rng, SeedableRng};
+use rand_xorshift::XorShiftRng;
+use serde::Serialize;
+use serde_with::{serde_as, DurationSecondsWithFrac};
+use std::{
+    collections::BTreeSet,
+    fmt::{self, Display},
+};
+
+/// Samples [`Scenario`]s of overlapping page loads
+#[derive(Copy, Clone, Debug)]
+pub struct ScenarioGenerator {
+    /// Number of page loads per scenario
+    pub tabs: usize,
+    /// Maximal start offset of a page load relative to the first one
+    pub max_offset: Duration,
+    /// Seed of the RNG, such that the scenarios can be reproduced
+    pub seed: u64,
+}
+
+impl ScenarioGenerator {
+    /// Generate `count` scenarios from the traces of `data`
+    ///
+    /// `data` contains the label and the traces of each domain.
+    /// Each scenario consists of distinct domains, each represented by a randomly chosen trace.
+    pub fn generate(
+        &self,
+        data: &[(String, Vec<PrecisionSequence>)],
+        count: usize,
+    ) -> Result<Vec<Scenario>, Error> {
+        let data: Vec<_> = data
+            .iter()
+            .filter(|(_, traces)| !traces.is_empty())
+            .collect();
+        if self.tabs == 0 {
+            bail!("A scenario needs at least one tab.");
+        }
+        if data.len() < self.tabs {
+            bail!(
+                "Scenarios with {} tabs need at least as many domains, but only {} domains have traces.",
+                self.tabs,
+                data.len()
+            );
+        }
+        let max_offset = self.max_offset.num_milliseconds().max(0);
+
+        let mut rng = XorShiftRng::seed_from_u64(self.seed);
+        Ok((0..count)
+            .map(|_| {
+                let mut tabs: Vec<(Duration, &str, &PrecisionSequence)> =
+                    index::sample(&mut rng, data.len(), self.tabs)
+                        .into_iter()
+                        .enumerate()
+                        .map(|(tab, idx)| {
+                            let (domain, traces) = data[idx];
+                            let trace = &traces[rng.gen_range(0..traces.len())];
+                            // The first tab defines the start of the scenario
+                            let offset = if tab == 0 {
+                                0
+                            } else {
+                                rng.gen_range(0..=max_offset)
+                            };
+                            (Duration::milliseconds(offset), &**domain, trace)
+                        })
+                        .collect();
+                tabs.sort_by_key(|&(offset, _, _)| offset);
+                Scenario::new(&tabs)
+            })
+            .collect())
+    }
+}
+
+/// Overlapping page loads merged into a single [`Sequence`]
+#[serde_as]
+#[derive(Clone, Debug, Serialize)]
+pub struct Scenario {
+    /// Ground truth, i.e., the domain of each page load in the order they start
+    pub domains: Vec<String>,
+    /// Identifier of the trace used for each page load
+    pub trace_ids: Vec<String>,
+    /// Start of each page load relative to the first one
+    #[serde_as(as = "Vec<DurationSecondsWithFrac<f64>>")]
+    pub offsets: Vec<Duration>,
+    /// The merged trace as seen by an observer
+    #[serde(skip)]
+    pub sequence: Sequence,
+}
+
+impl Scenario {
+    /// Merge the traces of `tabs`, which must be ordered by their offset
+    fn new(tabs: &[(Duration, &str, &PrecisionSequence)]) -> Self {
+        let trace_ids: Vec<String> = tabs.iter().map(|(_, _, trace)| trace.id().into()).collect();
+        let (first_offset, _, first) = tabs[0];
+        let merged = first.merge(
+            tabs[1..]
+                .iter()
+                .map(|&(offset, _, trace)| (trace, offset - first_offset)),
+            trace_ids.join(" + "),
+        );
+
+        Self {
+            domains: tabs
+                .iter()
+                .map(|(_, domain, _)| domain.to_string())
+                .collect(),
+            trace_ids,
+            offsets: tabs.iter().map(|&(offset, _, _)| offset).collect(),
+            sequence: merged.to_sequence(),
+        }
+    }
+
+    /// Compare the classification of the merged [`Sequence`] with the ground truth
+    ///
+    /// The classifier predicts as many domains as there are page loads, by taking the labels with the most votes.
+    /// Thus, `k` should be at least the number of page loads.
+    pub fn evaluate(&self, result: &ClassificationResult) -> SubsetMatch {
+        let predicted: Vec<String> = result
+            .ranked_labels()
+            .into_iter()
+            .take(self.domains.len())
+            .map(String::from)
+            .collect();
+        let truth: BTreeSet<&str> = self.domains.iter().map(String::as_str).collect();
+        let found = predicted
+            .iter()
+            .filter(|label| truth.contains(label.as_str()))
+            .count();
+        SubsetMatch {
+            predicted,
+            found,
+            tabs: self.domains.len(),
+        }
+    }
+}
+
+/// Result of identifying the domains of a [`Scenario`]
+#[derive(Clone, Debug, Serialize)]
+pub struct SubsetMatch {
+    /// Labels predicted by the classifier
+    pub predicted: Vec<String>,
+    /// Number of correctly identified domains
+    pub found: usize,
+    /// Number of page loads in the scenario
+    pub tabs: usize,
+}
+
+impl SubsetMatch {
+    /// At least one of the domains was identified
+    pub fn any(&self) -> bool {
+        self.found > 0
+    }
+
+    /// All domains were identified, i.e., the predicted set equals the ground truth
+    pub fn all(&self) -> bool {
+        self.found == self.tabs
+    }
+}
+
+/// Aggregated [`SubsetMatch`]es over many [`Scenario`]s
+#[derive(Copy, Clone, Debug, Default, Serialize)]
+pub struct SubsetAccuracy {
+    pub scenarios: usize,
+    /// Number of scenarios in which at least one domain was identified
+    pub any: usize,
+    /// Number of scenarios in which all domains were identified
+    pub all: usize,
+    /// Number of identified domains over all scenarios
+    pub found: usize,
+    /// Number of page loads over all scenarios
+    pub tabs: usize,
+}
+
+impl SubsetAccuracy {
+    pub fn add(&mut self, subset_match: &SubsetMatch) {
+        self.scenarios += 1;
+        self.any += subset_match.any() as usize;
+        self.all += subset_match.all() as usize;
+        self.found += subset_match.found;
+        self.tabs += subset_match.tabs;
+    }
+}
+
+impl Display for SubsetAccuracy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let percent = |count: usize, total: usize| {
+            if total == 0 {
+                0.
+            } else {
+                count as f64 / total as f64 * 100.
+            }
+        };
+        writeln!(f, "Scenarios: {}", self.scenarios)?;
+        writeln!(
+            f,
+            "Any domain identified:           {:>6.2}%",
+            percent(self.any, self.scenarios)
+        )?;
+        writeln!(
+            f,
+            "All domains (subset accuracy):   {:>6.2}%",
+            percent(self.all, self.scenarios)
+        )?;
+        write!(
+            f,
+            "Identified domains:              {:>6.2}%",
+            percent(self.found, self.tabs)
+        )
+    }
+}
+
+#[test]
+fn test_generate_scenarios() {
+    use crate::AbstractQueryResponse;
+    use chrono::NaiveDateTime;
+
+    let trace = |id: String, start: i64| {
+        PrecisionSequence::new(
+            (0..5).map(|i| AbstractQueryResponse {
+                time: NaiveDateTime::from_timestamp(start, 0) + Duration::milliseconds(i * 100),
+                size: 100,
+            }),
+            id,
+        )
+    };
+    let data: Vec<(String, Vec<PrecisionSequence>)> = (0..4)
+        .map(|domain| {
+            let traces = (0..3)
+                .map(|i| trace(format!("domain{}/{}.dnstap", domain, i), 1_600_000_000 + i))
+                .collect();
+            (format!("domain{}", domain), traces)
+        })
+        .collect();
+
+    let generator = ScenarioGenerator {
+        tabs: 3,
+        max_offset: Duration::seconds(2),
+        seed: 0,
+    };
+    let scenarios = generator.generate(&data, 10).unwrap();
+    assert_eq!(10, scenarios.len());
+    for scenario in &scenarios {
+        let domains: BTreeSet<_> = scenario.domains.iter().collect();
+        assert_eq!(3, domains.len());
+        assert_eq!(Duration::zero(), scenario.offsets[0]);
+        assert!(scenario.offsets.windows(2).all(|w| w[0] <= w[1]));
+        assert!(scenario
+            .offsets
+            .iter()
+            .all(|&offset| offset <= Duration::seconds(2)));
+        assert_eq!(15, scenario.sequence.message_count());
+    }
+    // The same seed reproduces the scenarios
+    let again = generator.generate(&data, 10).unwrap();
+    assert_eq!(scenarios[3].trace_ids, again[3].trace_ids);
+    assert_eq!(scenarios[3].offsets, again[3].offsets);
+
+    let too_many = ScenarioGenerator {
+        tabs: 5,
+        ..generator
+    };
+    assert!(too_many.generate(&data, 1).is_err());
+}
+
+#[test]
+fn test_evaluate_scenario() {
+    use crate::{knn, knn::LabelledSequences, SequenceElement::Size};
+
+    let training_data = vec![
+        LabelledSequences {
+            true_domain: "a.example",
+            mapped_domain: "a.example",
+            sequences: vec![
+                Sequence::new(vec![Size(1); 4], "a-0".into()),
+                Sequence::new(vec![Size(1); 4], "a-1".into()),
+            ],
+        },
+        LabelledSequences {
+            true_domain: "b.example",
+            mapped_domain: "b.example",
+            sequences: vec![Sequence::new(vec![Size(2); 4], "b-0".into())],
+        },
+        LabelledSequences {
+            true_domain: "c.example",
+            mapped_domain: "c.example",
+            sequences: vec![Sequence::new(vec![Size(5); 8], "c-0".into())],
+        },
+    ];
+    let merged = Sequence::new(vec![Size(1), Size(1), Size(2), Size(2)], "merged".into());
+    let results = knn::knn(&training_data, &[merged.clone()], 3, false);
+    // Two votes for `a.example` and one for `b.example`
+    assert_eq!(vec!["a.example", "b.example"], results[0].ranked_labels());
+
+    let scenario = |domains: &[&str]| Scenario {
+        domains: domains.iter().map(|domain| domain.to_string()).collect(),
+        trace_ids: Vec::new(),
+        offsets: Vec::new(),
+        sequence: merged.clone(),
+    };
+    let mut accuracy = SubsetAccuracy::default();
+
+    let both = scenario(&["b.example", "a.example"]).evaluate(&results[0]);
+    assert_eq!(2, both.found);
+    assert!(both.any());
+    assert!(both.all());
+    accuracy.add(&both);
+
+    let one = scenario(&["a.example", "c.example"]).evaluate(&results[0]);
+    assert_eq!(vec!["a.example", "b.example"], one.predicted);
+    assert_eq!(1, one.found);
+    assert!(one.any());
+    assert!(!one.all());
+    accuracy.add(&one);
+
+    // Only as many labels are predicted as there are page loads
+    let single = scenario(&["b.example"]).evaluate(&results[0]);
+    assert_eq!(vec!["a.example"], single.predicted);
+    assert!(!single.any());
+    accuracy.add(&single);
+
+    assert_eq!(3, accuracy.scenarios);
+    assert_eq!(2, accuracy.any);
+    assert_eq!(1, accuracy.all);
+    assert_eq!(3, accuracy.found);
+    assert_eq!(5, accuracy.tabs);
+    assert!(accuracy.to_string().ends_with("60.00%"));
+}
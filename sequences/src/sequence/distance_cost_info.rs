@@ -50,6 +50,30 @@ pub struct CostTracker {
     pub is_abort: bool,
     pub from_gap_to_gap: Arc<BTreeMap<(u16, u16), usize>>,
     current_cost: usize,
+    /// Last step of the alignment, which links to all previous steps
+    ///
+    /// The steps are shared between the cells of the distance matrix, such that cloning stays cheap.
+    last_step: Option<Arc<AlignmentStep>>,
+}
+
+/// Cost of a single operation along the alignment of two sequences
+#[derive(Debug)]
+struct AlignmentStep {
+    cost: usize,
+    previous: Option<Arc<AlignmentStep>>,
+}
+
+impl Drop for AlignmentStep {
+    /// Drop long chains iteratively instead of recursing once per step
+    fn drop(&mut self) {
+        let mut previous = self.previous.take();
+        while let Some(step) = previous {
+            match Arc::try_unwrap(step) {
+                Ok(mut step) => previous = step.previous.take(),
+                Err(_) => break,
+            }
+        }
+    }
 }
 
 impl CostTracker {
@@ -77,6 +101,35 @@ impl CostTracker {
         res
     }
 
+    /// Cost of each step of the alignment, from the start to the end of the sequences
+    ///
+    /// Each step is one insert, delete, substitute, or swap operation, including the free substitutions of equal elements.
+    /// The sum over all steps is the distance.
+    pub fn position_costs(&self) -> Vec<usize> {
+        let mut costs = Vec::new();
+        let mut step = self.last_step.as_deref();
+        while let Some(current) = step {
+            costs.push(current.cost);
+            step = current.previous.as_deref();
+        }
+        costs.reverse();
+        costs
+    }
+
+    /// Cumulative cost after each step of the alignment, see [`CostTracker::position_costs`]
+    ///
+    /// This shows where along the sequences the differences concentrate.
+    pub fn cumulative_position_costs(&self) -> Vec<usize> {
+        let mut sum = 0;
+        self.position_costs()
+            .into_iter()
+            .map(|cost| {
+                sum += cost;
+                sum
+            })
+            .collect()
+    }
+
     fn update<F>(&self, cost: usize, f: F) -> Self
     where
         F: Fn(&mut Self, usize),
@@ -84,6 +137,10 @@ impl CostTracker {
         let mut res = self.clone();
         let diff = cost - self.current_cost;
         res.current_cost = cost;
+        res.last_step = Some(Arc::new(AlignmentStep {
+            cost: diff,
+            previous: self.last_step.clone(),
+        }));
         f(&mut res, diff);
         res
     }
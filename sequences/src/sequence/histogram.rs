@@ -0,0 +1,95 @@
+//! Counts of the [`SequenceElement`]s in [`Sequence`]s
+
+use super::{Sequence, SequenceElement};
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// Number of occurrences of each [`SequenceElement`] value
+///
+/// The sizes are counted per number of padding blocks and the gaps per gap value.
+#[derive(Clone, Eq, PartialEq, Debug, Default, Serialize)]
+pub struct ElementHistogram {
+    pub sizes: BTreeMap<u8, usize>,
+    pub gaps: BTreeMap<u16, usize>,
+}
+
+impl ElementHistogram {
+    /// Add the [`SequenceElement`]s of the `sequence` to the counts
+    pub fn add_sequence(&mut self, sequence: &Sequence) {
+        for elem in sequence.as_elements() {
+            match *elem {
+                SequenceElement::Size(size) => *self.sizes.entry(size).or_default() += 1,
+                SequenceElement::Gap(gap) => *self.gaps.entry(gap).or_default() += 1,
+            }
+        }
+    }
+
+    /// Add the counts of `other` to this histogram
+    pub fn merge(&mut self, other: &Self) {
+        for (&size, count) in &other.sizes {
+            *self.sizes.entry(size).or_default() += count;
+        }
+        for (&gap, count) in &other.gaps {
+            *self.gaps.entry(gap).or_default() += count;
+        }
+    }
+
+    /// Total number of [`SequenceElement::Size`]s, i.e., the number of messages
+    pub fn message_count(&self) -> usize {
+        self.sizes.values().sum()
+    }
+
+    /// Total number of [`SequenceElement::Gap`]s
+    pub fn gap_count(&self) -> usize {
+        self.gaps.values().sum()
+    }
+}
+
+/// Calculate the [`ElementHistogram`] over a whole dataset
+pub fn element_histogram<'a>(
+    sequences: impl IntoIterator<Item = &'a Sequence>,
+) -> ElementHistogram {
+    let mut histogram = ElementHistogram::default();
+    for sequence in sequences {
+        histogram.add_sequence(sequence);
+    }
+    histogram
+}
+
+#[test]
+fn test_element_histogram() {
+    use SequenceElement::{Gap, Size};
+
+    let seq1 = Sequence::new(vec![Size(1), Gap(3), Size(2), Size(1)], "1".into());
+    let seq2 = Sequence::new(vec![Size(1), Gap(3), Size(1), Gap(5), Size(1)], "2".into());
+
+    let histogram = seq1.element_histogram();
+    assert_eq!(
+        vec![(1, 2), (2, 1)],
+        histogram.sizes.into_iter().collect::<Vec<_>>()
+    );
+    assert_eq!(vec![(3, 1)], histogram.gaps.into_iter().collect::<Vec<_>>());
+
+    let mut merged = seq1.element_histogram();
+    merged.merge(&seq2.element_histogram());
+    let dataset = element_histogram(&[seq1, seq2]);
+    assert_eq!(merged, dataset);
+    assert_eq!(
+        vec![(1, 5), (2, 1)],
+        dataset
+            .sizes
+            .iter()
+            .map(|(&k, &v)| (k, v))
+            .collect::<Vec<_>>()
+    );
+    assert_eq!(
+        vec![(3, 2), (5, 1)],
+        dataset
+            .gaps
+            .iter()
+            .map(|(&k, &v)| (k, v))
+            .collect::<Vec<_>>()
+    );
+    assert_eq!(6, dataset.message_count());
+    assert_eq!(3, dataset.gap_count());
+}
@@ -1,23 +1,214 @@
 //! All k-NN related types and k-NN implementing functions
 
-use super::{InternedSequence, Sequence};
-use crate::utils::take_smallest;
+use super::{Sequence, SequenceStorage};
+use crate::{
+    format_version::{check_format_version, read_format_version, VersionedFormat, FORMAT_VERSION},
+    utils::take_smallest,
+};
+use anyhow::{anyhow, bail, Context as _, Error};
+use fnv::FnvHasher;
 use log::{debug, error};
 use misc_utils::{Max, Min};
 use once_cell::sync::Lazy;
 use ordered_float::NotNan;
+use rand::{seq::SliceRandom, SeedableRng};
+use rand_xorshift::XorShiftRng;
 use rayon::prelude::*;
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::Value;
 use serde_with::{serde_as, DisplayFromStr};
 use std::{
     cmp::{Eq, Ord, Ordering, PartialEq, PartialOrd},
-    fmt::{self, Display},
+    collections::HashMap,
+    fmt::{self, Debug, Display},
+    hash::{Hash, Hasher},
+    mem,
+    str::FromStr,
 };
 use string_cache::DefaultAtom as Atom;
 
 /// Memorize distance calculations
-static PRECOMPUTED_DISTANCES: Lazy<dashmap::DashMap<(InternedSequence, InternedSequence), usize>> =
-    Lazy::new(Default::default);
+#[allow(clippy::type_complexity)]
+static PRECOMPUTED_DISTANCES: Lazy<
+    dashmap::DashMap<(DistanceMetric, SequenceStorage, SequenceStorage), usize>,
+> = Lazy::new(Default::default);
+
+/// Distance functions usable for k-NN classification
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Serialize, Deserialize)]
+pub enum DistanceMetric {
+    /// Damerau-Levenshtein distance, see [`Sequence::distance`]
+    EditDistance,
+    /// Dynamic time warping, see [`Sequence::dtw_distance`]
+    Dtw,
+    /// Euclidean distance of the CUMUL features, see [`Sequence::cumul_distance`]
+    Cumul,
+    /// Edit distance on the runs of identical elements, see [`Sequence::run_length_distance`]
+    RunLength,
+}
+
+impl DistanceMetric {
+    /// Calculate the distance between two [`Sequence`]s with this metric
+    ///
+    /// In contrast to the k-NN functions the result is not memorized.
+    pub fn distance(self, a: &Sequence, b: &Sequence) -> usize {
+        match self {
+            DistanceMetric::EditDistance => a.distance(b),
+            DistanceMetric::Dtw => a.dtw_distance(b),
+            DistanceMetric::Cumul => a.cumul_distance(b).round() as usize,
+            DistanceMetric::RunLength => a.run_length_distance(b),
+        }
+    }
+}
+
+impl Display for DistanceMetric {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DistanceMetric::EditDistance => write!(f, "edit"),
+            DistanceMetric::Dtw => write!(f, "dtw"),
+            DistanceMetric::Cumul => write!(f, "cumul"),
+            DistanceMetric::RunLength => write!(f, "rle"),
+        }
+    }
+}
+
+impl FromStr for DistanceMetric {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match &*s.to_ascii_lowercase() {
+            "edit" => Ok(DistanceMetric::EditDistance),
+            "dtw" => Ok(DistanceMetric::Dtw),
+            "cumul" => Ok(DistanceMetric::Cumul),
+            "rle" => Ok(DistanceMetric::RunLength),
+            _ => bail!(
+                "Unknown distance metric `{}`. Supported are `edit`, `dtw`, `cumul`, and `rle`.",
+                s
+            ),
+        }
+    }
+}
+
+/// How the results of the members of an [`Ensemble`] are combined
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Serialize, Deserialize)]
+pub enum EnsembleVoting {
+    /// Each member has a single vote
+    Majority,
+    /// Each member has as many votes as its weight
+    Weighted,
+}
+
+impl Display for EnsembleVoting {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EnsembleVoting::Majority => write!(f, "majority"),
+            EnsembleVoting::Weighted => write!(f, "weighted"),
+        }
+    }
+}
+
+impl FromStr for EnsembleVoting {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match &*s.to_ascii_lowercase() {
+            "majority" => Ok(EnsembleVoting::Majority),
+            "weighted" => Ok(EnsembleVoting::Weighted),
+            _ => bail!(
+                "Unknown voting scheme `{}`. Supported are `majority` and `weighted`.",
+                s
+            ),
+        }
+    }
+}
+
+/// How the neighbors contribute to the votes of a k-NN classification
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Serialize, Deserialize)]
+pub enum VoteWeighting {
+    /// Each neighbor has a single vote
+    Uniform,
+    /// Each neighbor votes with `1 / (1 + distance)`
+    InverseDistance,
+    /// The nearest of `n` neighbors has `n` votes, the second nearest `n - 1`, and so on
+    ///
+    /// Neighbors with the same distance have the same rank.
+    Rank,
+}
+
+impl VoteWeighting {
+    /// Votes of a neighbor with `distance` and `rank`, i.e., the number of closer neighbors out of `neighbors`
+    fn votes(self, distance: usize, rank: usize, neighbors: usize) -> f64 {
+        match self {
+            VoteWeighting::Uniform => 1.,
+            VoteWeighting::InverseDistance => 1. / (1. + distance as f64),
+            VoteWeighting::Rank => (neighbors - rank) as f64,
+        }
+    }
+}
+
+impl Display for VoteWeighting {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VoteWeighting::Uniform => write!(f, "uniform"),
+            VoteWeighting::InverseDistance => write!(f, "inverse-distance"),
+            VoteWeighting::Rank => write!(f, "rank"),
+        }
+    }
+}
+
+impl FromStr for VoteWeighting {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match &*s.to_ascii_lowercase() {
+            "uniform" => Ok(VoteWeighting::Uniform),
+            "inverse-distance" => Ok(VoteWeighting::InverseDistance),
+            "rank" => Ok(VoteWeighting::Rank),
+            _ => bail!(
+                "Unknown vote weighting `{}`. Supported are `uniform`, `inverse-distance`, and `rank`.",
+                s
+            ),
+        }
+    }
+}
+
+/// A single classifier as part of an [`Ensemble`]
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Serialize, Deserialize)]
+pub struct EnsembleMember {
+    pub metric: DistanceMetric,
+    /// Number of votes for [`EnsembleVoting::Weighted`]
+    pub weight: u8,
+}
+
+impl Display for EnsembleMember {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.metric, self.weight)
+    }
+}
+
+impl FromStr for EnsembleMember {
+    type Err = Error;
+
+    /// Parse the format `<metric>[=<weight>]`, e.g., `edit=2` or `dtw`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, '=');
+        let metric = parts.next().unwrap_or_default().trim().parse()?;
+        let weight = match parts.next() {
+            Some(weight) => weight
+                .trim()
+                .parse()
+                .with_context(|| format!("Invalid weight in ensemble member `{}`", s))?,
+            None => 1,
+        };
+        Ok(EnsembleMember { metric, weight })
+    }
+}
+
+/// Combination of multiple k-NN classifiers using different [`DistanceMetric`]s
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Serialize, Deserialize)]
+pub struct Ensemble {
+    pub members: Vec<EnsembleMember>,
+    pub voting: EnsembleVoting,
+}
 
 /// [`Sequence`] with additional data about the true domain and the canonical domain
 pub struct LabelledSequence<S = Atom> {
@@ -27,17 +218,99 @@ pub struct LabelledSequence<S = Atom> {
 }
 
 /// A set of DNS [`Sequence`]s which all belong to the same true domain and canonical domain
-#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Serialize, Deserialize)]
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
 pub struct LabelledSequences<S = Atom> {
     pub true_domain: S,
     pub mapped_domain: S,
     pub sequences: Vec<Sequence>,
 }
 
+impl<S> LabelledSequences<S> {
+    /// Only keep the [`Sequence`]s recorded at one of the `vantage_points`
+    ///
+    /// An empty list keeps all [`Sequence`]s.
+    pub fn retain_vantage_points(&mut self, vantage_points: &[String]) {
+        if !vantage_points.is_empty() {
+            self.sequences
+                .retain(|seq| vantage_points.iter().any(|vp| vp == seq.vantage_point()));
+        }
+    }
+}
+
+/// Serialization format of [`LabelledSequences`]
+#[derive(Serialize)]
+struct LabelledSequencesRef<'a, S> {
+    format_version: u32,
+    true_domain: &'a S,
+    mapped_domain: &'a S,
+    sequences: &'a [Sequence],
+}
+
+/// Deserialization format of [`LabelledSequences`]
+#[derive(Deserialize)]
+struct LabelledSequencesOwned<S> {
+    format_version: Option<u32>,
+    true_domain: S,
+    mapped_domain: S,
+    sequences: Vec<Sequence>,
+}
+
+impl<S: Serialize> Serialize for LabelledSequences<S> {
+    fn serialize<Ser>(&self, serializer: Ser) -> Result<Ser::Ok, Ser::Error>
+    where
+        Ser: Serializer,
+    {
+        LabelledSequencesRef {
+            format_version: FORMAT_VERSION,
+            true_domain: &self.true_domain,
+            mapped_domain: &self.mapped_domain,
+            sequences: &self.sequences,
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de, S: Deserialize<'de>> Deserialize<'de> for LabelledSequences<S> {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let repr = LabelledSequencesOwned::deserialize(deserializer)?;
+        check_format_version(repr.format_version)?;
+        Ok(LabelledSequences {
+            true_domain: repr.true_domain,
+            mapped_domain: repr.mapped_domain,
+            sequences: repr.sequences,
+        })
+    }
+}
+
+impl<S: DeserializeOwned> VersionedFormat for LabelledSequences<S> {
+    fn migrate(mut value: Value) -> Result<Value, Error> {
+        if read_format_version(&value, "format_version")? == 1 {
+            let map = value
+                .as_object_mut()
+                .ok_or_else(|| anyhow!("LabelledSequences must be a JSON map"))?;
+            map.insert("format_version".to_string(), FORMAT_VERSION.into());
+            if let Some(sequences) = map.remove("sequences") {
+                map.insert(
+                    "sequences".to_string(),
+                    <Vec<Sequence>>::migrate(sequences)?,
+                );
+            }
+        }
+        Ok(value)
+    }
+}
+
 #[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Serialize, Deserialize)]
 pub enum ClassificationResultQuality {
     /// There are no classification labels
     NoResult,
+    /// The classifier abstained, because the confidence was too low
+    ///
+    /// See [`ClassificationResult::reject_below`].
+    Unclassified,
     /// None of the classification labels matches the real label
     Wrong,
     /// One of the classification labels matches the real label
@@ -67,6 +340,7 @@ impl Display for ClassificationResultQuality {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             ClassificationResultQuality::NoResult => write!(f, "NoResult"),
+            ClassificationResultQuality::Unclassified => write!(f, "Unclassified"),
             ClassificationResultQuality::Wrong => write!(f, "Wrong"),
             ClassificationResultQuality::Contains => write!(f, "Contains"),
             ClassificationResultQuality::PluralityThenMinDist => write!(f, "PluralityThenMinDist"),
@@ -83,6 +357,7 @@ impl ClassificationResultQuality {
         use self::ClassificationResultQuality::*;
         [
             NoResult,
+            Unclassified,
             Wrong,
             Contains,
             PluralityThenMinDist,
@@ -98,6 +373,16 @@ impl ClassificationResultQuality {
 #[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Serialize, Deserialize)]
 pub struct ClassificationResult {
     options: Vec<LabelOption>,
+    /// Same as `options`, but labelled with the true domains instead of the mapped domains
+    ///
+    /// This is empty for results serialized before the true domains were tracked.
+    #[serde(default)]
+    true_options: Vec<LabelOption>,
+    /// The classifier abstained from choosing a label
+    ///
+    /// The label options are kept, such that the [`ClassificationResult::confidence`] is still available.
+    #[serde(default)]
+    unclassified: bool,
 }
 
 #[serde_as]
@@ -105,6 +390,11 @@ pub struct ClassificationResult {
 struct LabelOption {
     name: String,
     count: u8,
+    /// Sum of the weighted votes of the neighbors, or `None` if each neighbor has a single vote
+    ///
+    /// See [`VoteWeighting`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    votes: Option<NotNan<f64>>,
     #[serde_as(as = "DisplayFromStr")]
     distance_min: Min<usize>,
     #[serde_as(as = "DisplayFromStr")]
@@ -116,40 +406,54 @@ struct LabelOption {
 }
 
 impl ClassificationResult {
-    fn from_classifier_data<S: AsRef<str>>(data: &[ClassifierData<'_, S>]) -> ClassificationResult {
+    fn from_classifier_data<S: AsRef<str>>(
+        data: &[ClassifierData<'_, S>],
+        weighting: VoteWeighting,
+    ) -> ClassificationResult {
         let mut result = ClassificationResult {
             options: Vec::with_capacity(9),
+            true_options: Vec::with_capacity(9),
+            unclassified: false,
         };
 
         for entry in data {
-            match result
-                .options
-                .iter_mut()
-                .find(|opt| opt.is(entry.label.as_ref()))
-            {
-                None => {
-                    let new_opt = LabelOption {
-                        name: entry.label.as_ref().to_string(),
-                        count: 1,
-                        distance_min: Min::with_initial(entry.distance),
-                        distance_max: Max::with_initial(entry.distance),
-                        distance_min_norm: Min::with_initial(entry.distance_norm),
-                        distance_max_norm: Max::with_initial(entry.distance_norm),
-                    };
-                    result.options.push(new_opt);
-                }
-                Some(opt) => opt.update(entry.distance),
-            }
+            let votes = if weighting == VoteWeighting::Uniform {
+                None
+            } else {
+                let rank = data.iter().filter(|&other| other < entry).count();
+                NotNan::new(weighting.votes(entry.distance, rank, data.len())).ok()
+            };
+            LabelOption::add_vote(&mut result.options, entry.label.as_ref(), entry, votes);
+            LabelOption::add_vote(
+                &mut result.true_options,
+                entry.true_label.as_ref(),
+                entry,
+                votes,
+            );
         }
 
         result
     }
 
+    /// Return the same result, but labelled with the true domains instead of the mapped domains
+    ///
+    /// This allows evaluating the classification of domains, which are merged by the confusion domain mapping.
+    pub fn true_domain_result(&self) -> ClassificationResult {
+        ClassificationResult {
+            options: self.true_options.clone(),
+            true_options: self.true_options.clone(),
+            unclassified: self.unclassified,
+        }
+    }
+
     #[allow(clippy::blocks_in_if_conditions)]
     pub fn determine_quality(&self, real_label: &str) -> ClassificationResultQuality {
         if self.options.is_empty() {
             return ClassificationResultQuality::NoResult;
         }
+        if self.unclassified {
+            return ClassificationResultQuality::Unclassified;
+        }
 
         if self.is(real_label) {
             return ClassificationResultQuality::Exact;
@@ -160,10 +464,10 @@ impl ClassificationResult {
             None => return ClassificationResultQuality::Wrong,
             Some(opt) => opt,
         };
-        // Total number of label options
-        let option_count = self.options.iter().map(|opt| opt.count).sum();
+        // Total number of votes over all label options
+        let total_votes: f64 = self.options.iter().map(LabelOption::votes).sum();
 
-        if (corr_option.count * 2) > option_count {
+        if corr_option.votes() * 2. > total_votes {
             return ClassificationResultQuality::Majority;
         }
 
@@ -173,7 +477,7 @@ impl ClassificationResult {
             .iter()
             // ignore the corr_option for the later tests
             .filter(|&opt| opt != corr_option)
-            .any(|other| other.count >= corr_option.count)
+            .any(|other| other.votes() >= corr_option.votes())
         {
             return ClassificationResultQuality::Plurality;
         }
@@ -186,9 +490,9 @@ impl ClassificationResult {
             .filter(|&opt| opt != corr_option)
             .any(|other| {
                 // if this is true, then corr_option is not a plurality
-                other.count > corr_option.count
+                other.votes() > corr_option.votes()
                 // if there are multiple pluralities check if there is one with a smaller or equal minimal distance
-                    || (other.count == corr_option.count
+                    || (other.votes() == corr_option.votes()
                         && other.distance_min <= corr_option.distance_min)
             })
         {
@@ -203,6 +507,238 @@ impl ClassificationResult {
     fn is(&self, real_label: &str) -> bool {
         self.options.len() == 1 && self.options[0].is(real_label)
     }
+
+    /// Return the label with the highest count
+    ///
+    /// Ties are broken by the smaller minimal distance.
+    /// Returns [`None`] if there are no classification labels.
+    pub fn best_label(&self) -> Option<&str> {
+        self.best_option().map(|opt| &*opt.name)
+    }
+
+    /// Return the label option with the highest count
+    ///
+    /// Ties are broken by the smaller minimal distance.
+    /// Returns [`None`] if the classifier abstained.
+    fn best_option(&self) -> Option<&LabelOption> {
+        if self.unclassified {
+            return None;
+        }
+        self.top_option()
+    }
+
+    /// Same as [`ClassificationResult::best_option`] but ignores if the classifier abstained
+    fn top_option(&self) -> Option<&LabelOption> {
+        LabelOption::best(&self.options)
+    }
+
+    /// Return the label with the highest count, even if the classifier abstained
+    ///
+    /// This is the label the classifier would have chosen without the rejection.
+    pub fn top_label(&self) -> Option<&str> {
+        self.top_option().map(|opt| &*opt.name)
+    }
+
+    /// Return all labels ordered from the most to the least votes
+    ///
+    /// Ties are broken by the smaller minimal distance, like for [`ClassificationResult::best_label`].
+    /// Returns an empty list if the classifier abstained.
+    pub fn ranked_labels(&self) -> Vec<&str> {
+        if self.unclassified {
+            return Vec::new();
+        }
+        let mut options: Vec<&LabelOption> = self.options.iter().collect();
+        options.sort_by(|a, b| {
+            b.votes()
+                .partial_cmp(&a.votes())
+                .unwrap_or(Ordering::Equal)
+                .then_with(|| a.distance_min.cmp(&b.distance_min))
+        });
+        options.into_iter().map(|opt| &*opt.name).collect()
+    }
+
+    /// Return the distance to the nearest neighbor over all label options
+    ///
+    /// Returns [`None`] if there are no label options.
+    pub fn nearest_distance(&self) -> Option<usize> {
+        self.options
+            .iter()
+            .filter_map(|opt| opt.distance_min.get_min())
+            .min()
+    }
+
+    /// Confidence in the label with the highest count as a value between 0 and 1
+    ///
+    /// The confidence is the fraction of votes for the label, scaled down by the minimal normalized distance of the label.
+    /// This distinguishes close matches from distant ones even for `k = 1`.
+    /// Results without any label options have a confidence of 0.
+    pub fn confidence(&self) -> f64 {
+        let top = match self.top_option() {
+            None => return 0.,
+            Some(top) => top,
+        };
+        let total: f64 = self.options.iter().map(LabelOption::votes).sum();
+        let vote_share = top.votes() / total;
+        let distance = top
+            .distance_min_norm
+            .get_min()
+            .map(|dist| dist.into_inner().max(0.).min(1.))
+            .unwrap_or(1.);
+        vote_share * (1. - distance)
+    }
+
+    /// Abstain from choosing a label, if the [`ClassificationResult::confidence`] is below `min_confidence`
+    pub fn reject_below(&mut self, min_confidence: f64) {
+        self.unclassified = !self.options.is_empty() && self.confidence() < min_confidence;
+    }
+
+    /// Returns `true` if the classifier abstained from choosing a label
+    pub fn is_unclassified(&self) -> bool {
+        self.unclassified
+    }
+
+    /// Combine multiple [`ClassificationResult`]s by voting
+    ///
+    /// Each result casts a single vote for its best label option, i.e., the one with the highest count.
+    /// The vote counts saturate at [`u8::MAX`].
+    pub fn aggregate_votes<'a, I>(results: I) -> ClassificationResult
+    where
+        I: IntoIterator<Item = &'a ClassificationResult>,
+    {
+        Self::aggregate_weighted_votes(results.into_iter().map(|res| (res, 1)))
+    }
+
+    /// Same as [`ClassificationResult::aggregate_votes`] but each result casts `weight` votes
+    pub fn aggregate_weighted_votes<'a, I>(results: I) -> ClassificationResult
+    where
+        I: IntoIterator<Item = (&'a ClassificationResult, u8)>,
+    {
+        let mut aggregated = ClassificationResult {
+            options: Vec::with_capacity(9),
+            true_options: Vec::with_capacity(9),
+            unclassified: false,
+        };
+
+        for (res, weight) in results
+            .into_iter()
+            .filter(|&(res, weight)| weight > 0 && !res.unclassified)
+        {
+            if let Some(best) = LabelOption::best(&res.options) {
+                LabelOption::add_weighted_vote(&mut aggregated.options, best, weight);
+            }
+            if let Some(best) = LabelOption::best(&res.true_options) {
+                LabelOption::add_weighted_vote(&mut aggregated.true_options, best, weight);
+            }
+        }
+
+        aggregated
+    }
+}
+
+/// Decides whether a [`ClassificationResult`] counts as correct
+///
+/// In contrast to [`ClassificationResult::determine_quality`], which grades a result on a fixed ladder, each metric is a binary decision.
+/// Multiple metrics can be evaluated on the same results, e.g., to also count confusions between subdomains of the same site as correct.
+pub trait QualityMetric: Debug + Send + Sync {
+    /// Name of the metric in the statistics
+    fn name(&self) -> String;
+
+    /// Returns `true` if `result` counts as correct for a sequence of `real_label`
+    fn is_correct(&self, result: &ClassificationResult, real_label: &str) -> bool;
+}
+
+/// The label with the highest count is the real label
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct ExactMatch;
+
+impl QualityMetric for ExactMatch {
+    fn name(&self) -> String {
+        "exact".to_string()
+    }
+
+    fn is_correct(&self, result: &ClassificationResult, real_label: &str) -> bool {
+        result.best_label() == Some(real_label)
+    }
+}
+
+/// The label with the highest count has the same registrable domain as the real label, see [`registrable_domain`]
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct RegistrableDomainMatch;
+
+impl QualityMetric for RegistrableDomainMatch {
+    fn name(&self) -> String {
+        "etld1".to_string()
+    }
+
+    fn is_correct(&self, result: &ClassificationResult, real_label: &str) -> bool {
+        result.best_label().map_or(false, |label| {
+            registrable_domain(label) == registrable_domain(real_label)
+        })
+    }
+}
+
+/// The label with the highest count is in the same set of similar domains as the real label
+///
+/// Domains without a set only match themselves.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ConfusionSetMatch {
+    /// Name of the metric, e.g., derived from the file the sets were loaded from
+    pub name: String,
+    /// Maps each domain to the identifier of its set
+    pub sets: HashMap<String, String>,
+}
+
+impl ConfusionSetMatch {
+    fn set_of<'a>(&'a self, domain: &'a str) -> &'a str {
+        self.sets.get(domain).map_or(domain, |set| &**set)
+    }
+}
+
+impl QualityMetric for ConfusionSetMatch {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn is_correct(&self, result: &ClassificationResult, real_label: &str) -> bool {
+        result
+            .best_label()
+            .map_or(false, |label| self.set_of(label) == self.set_of(real_label))
+    }
+}
+
+/// The real label is among the `n` labels with the most votes, see [`ClassificationResult::ranked_labels`]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct TopK(pub usize);
+
+impl QualityMetric for TopK {
+    fn name(&self) -> String {
+        format!("top-{}", self.0)
+    }
+
+    fn is_correct(&self, result: &ClassificationResult, real_label: &str) -> bool {
+        result
+            .ranked_labels()
+            .into_iter()
+            .take(self.0)
+            .any(|label| label == real_label)
+    }
+}
+
+/// Approximate the registrable domain, i.e., the public suffix plus one label, of `domain`
+///
+/// Without a public suffix list, only single label suffixes and the common second level suffixes of country code TLDs, like `co.uk` or `com.au`, are recognized.
+/// A trailing dot and the case are ignored.
+pub fn registrable_domain(domain: &str) -> String {
+    const SECOND_LEVEL: &[&str] = &["ac", "co", "com", "edu", "gov", "ne", "net", "or", "org"];
+
+    let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+    let labels: Vec<&str> = domain.split('.').collect();
+    let suffix_len = match labels.as_slice() {
+        [.., sld, tld] if tld.len() == 2 && SECOND_LEVEL.contains(sld) => 2,
+        _ => 1,
+    };
+    let start = labels.len().saturating_sub(suffix_len + 1);
+    labels[start..].join(".")
 }
 
 impl LabelOption {
@@ -211,11 +747,170 @@ impl LabelOption {
         self.name == name
     }
 
-    fn update(&mut self, distance: usize) {
+    /// Return the option with the highest count
+    ///
+    /// Ties are broken by the smaller minimal distance.
+    fn best(options: &[LabelOption]) -> Option<&LabelOption> {
+        options.iter().max_by(|a, b| {
+            a.votes()
+                .partial_cmp(&b.votes())
+                .unwrap_or(Ordering::Equal)
+                .then_with(|| b.distance_min.cmp(&a.distance_min))
+        })
+    }
+
+    /// Number of votes for this label
+    ///
+    /// This is the sum of the weighted votes, if a [`VoteWeighting`] was used, and the count otherwise.
+    fn votes(&self) -> f64 {
+        self.votes
+            .map(NotNan::into_inner)
+            .unwrap_or_else(|| f64::from(self.count))
+    }
+
+    /// Count the classifier data `entry` as a vote for `label`
+    ///
+    /// `votes` is the weight of the vote, or `None` for a single unweighted vote.
+    fn add_vote<S>(
+        options: &mut Vec<LabelOption>,
+        label: &str,
+        entry: &ClassifierData<'_, S>,
+        votes: Option<NotNan<f64>>,
+    ) {
+        match options.iter_mut().find(|opt| opt.is(label)) {
+            None => options.push(LabelOption {
+                name: label.to_string(),
+                count: 1,
+                votes,
+                distance_min: Min::with_initial(entry.distance),
+                distance_max: Max::with_initial(entry.distance),
+                distance_min_norm: Min::with_initial(entry.distance_norm),
+                distance_max_norm: Max::with_initial(entry.distance_norm),
+            }),
+            Some(opt) => opt.update(entry.distance, votes),
+        }
+    }
+
+    /// Count `best` as `weight` votes for its label
+    fn add_weighted_vote(options: &mut Vec<LabelOption>, best: &LabelOption, weight: u8) {
+        match options.iter_mut().find(|opt| opt.is(&best.name)) {
+            None => {
+                let mut new_opt = best.clone();
+                new_opt.count = weight;
+                // The aggregated votes are counted per result and not per neighbor
+                new_opt.votes = None;
+                options.push(new_opt);
+            }
+            Some(opt) => opt.merge(best, weight),
+        }
+    }
+
+    fn update(&mut self, distance: usize, votes: Option<NotNan<f64>>) {
         self.count += 1;
+        self.votes = match (self.votes, votes) {
+            (Some(a), Some(b)) => Some(a + b),
+            _ => None,
+        };
         self.distance_min.update(distance);
         self.distance_max.update(distance);
     }
+
+    /// Count `other` as `weight` more votes for this label and merge the distances
+    fn merge(&mut self, other: &LabelOption, weight: u8) {
+        self.count = self.count.saturating_add(weight);
+        if other.distance_min < self.distance_min {
+            self.distance_min = other.distance_min.clone();
+        }
+        if other.distance_max > self.distance_max {
+            self.distance_max = other.distance_max.clone();
+        }
+        if other.distance_min_norm < self.distance_min_norm {
+            self.distance_min_norm = other.distance_min_norm.clone();
+        }
+        if other.distance_max_norm > self.distance_max_norm {
+            self.distance_max_norm = other.distance_max_norm.clone();
+        }
+    }
+}
+
+/// Size and stride of the windows used by [`knn_windows`]
+///
+/// The textual format is `<size>[:<stride>]`, the stride defaults to the size, i.e., non-overlapping windows.
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Serialize, Deserialize)]
+pub struct WindowSpec {
+    /// Number of [`SequenceElement`](crate::SequenceElement)s per window
+    pub size: usize,
+    /// Number of elements between the starts of two consecutive windows
+    pub stride: usize,
+}
+
+impl Display for WindowSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.size, self.stride)
+    }
+}
+
+impl FromStr for WindowSpec {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (size, stride) = match s.split_once(':') {
+            Some((size, stride)) => (size, Some(stride)),
+            None => (s, None),
+        };
+        let size: usize = size
+            .trim()
+            .parse()
+            .with_context(|| format!("Invalid window size in `{}`", s))?;
+        let stride: usize = stride
+            .map(|stride| stride.trim().parse())
+            .transpose()
+            .with_context(|| format!("Invalid window stride in `{}`", s))?
+            .unwrap_or(size);
+        if size == 0 || stride == 0 {
+            bail!(
+                "The window size and stride must be larger than 0, got `{}`",
+                s
+            );
+        }
+        Ok(WindowSpec { size, stride })
+    }
+}
+
+/// Classification result of a [`Sequence`] split into windows
+///
+/// See [`knn_windows`].
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Serialize, Deserialize)]
+pub struct WindowedClassificationResult {
+    /// One [`ClassificationResult`] per window, in the order of the windows
+    pub windows: Vec<ClassificationResult>,
+    /// Votes of all windows combined
+    pub aggregated: ClassificationResult,
+}
+
+impl WindowedClassificationResult {
+    /// Return the number of windows required until the votes reach `min_quality` for `real_label`
+    ///
+    /// The votes of all windows up to and including the current one are aggregated.
+    /// The result is the smallest number of windows, such that the aggregated result and all later aggregated results have at least `min_quality`.
+    /// Returns [`None`] if the quality is not reached with all windows.
+    pub fn windows_until_classified(
+        &self,
+        real_label: &str,
+        min_quality: ClassificationResultQuality,
+    ) -> Option<usize> {
+        let mut stable_since = None;
+        for count in 1..=self.windows.len() {
+            let quality = ClassificationResult::aggregate_votes(&self.windows[..count])
+                .determine_quality(real_label);
+            if quality >= min_quality {
+                stable_since.get_or_insert(count);
+            } else {
+                stable_since = None;
+            }
+        }
+        stable_since
+    }
 }
 
 /// Find the k-nearest-neighbours in `trainings_data` for each element in `validation_data`
@@ -228,6 +923,30 @@ pub fn knn<S>(
     k: u8,
     use_cr_mode: bool,
 ) -> Vec<ClassificationResult>
+where
+    S: AsRef<str> + Clone + Display + Sync,
+{
+    knn_with_metric(
+        trainings_data,
+        validation_data,
+        k,
+        DistanceMetric::EditDistance,
+        VoteWeighting::Uniform,
+        use_cr_mode,
+    )
+}
+
+/// Same as [`knn`] but with a configurable [`DistanceMetric`] and [`VoteWeighting`]
+///
+/// `use_cr_mode` only affects the [`DistanceMetric::EditDistance`].
+pub fn knn_with_metric<S>(
+    trainings_data: &[LabelledSequences<S>],
+    validation_data: &[Sequence],
+    k: u8,
+    metric: DistanceMetric,
+    weighting: VoteWeighting,
+    use_cr_mode: bool,
+) -> Vec<ClassificationResult>
 where
     S: AsRef<str> + Clone + Display + Sync,
 {
@@ -247,22 +966,23 @@ where
                 trainings_data
                     .iter()
                     // iterate over all elements of the trainings data
-                    .flat_map(|tlseq| {
-                        tlseq.sequences.iter().map(move |s| {
-                            let (distance, distance_norm) =
-                                memorize_distance(vsample, s, use_cr_mode);
-
-                            ClassifierData {
-                                label: &tlseq.mapped_domain,
-                                distance,
-                                distance_norm,
-                            }
-                        })
-                    }),
+                    .flat_map(|tlseq| tlseq.sequences.iter().map(move |s| (tlseq, s))),
                 // collect the k smallest distances
                 k as usize,
+                |(tlseq, s), max_distance| {
+                    // Candidates further away than the current k-th neighbor are abandoned early
+                    let (distance, distance_norm) =
+                        memorize_distance(vsample, s, metric, max_distance, use_cr_mode);
+
+                    Some(ClassifierData {
+                        label: &tlseq.mapped_domain,
+                        true_label: &tlseq.true_domain,
+                        distance,
+                        distance_norm,
+                    })
+                },
             );
-            ClassificationResult::from_classifier_data(&distances)
+            ClassificationResult::from_classifier_data(&distances, weighting)
         })
         .collect()
 }
@@ -272,6 +992,7 @@ pub fn knn_with_threshold<S>(
     validation_data: &[Sequence],
     k: u8,
     distance_threshold: f64,
+    weighting: VoteWeighting,
     use_cr_mode: bool,
 ) -> Vec<ClassificationResult>
 where
@@ -289,52 +1010,463 @@ where
         .into_par_iter()
         .with_max_len(1)
         .map(|vsample| {
-            let distances = take_smallest(
+            let distances = smallest_within_threshold(
+                trainings_data,
+                vsample,
+                k as usize,
+                distance_threshold,
+                use_cr_mode,
+            );
+            ClassificationResult::from_classifier_data(&distances, weighting)
+        })
+        .collect()
+}
+
+/// The `k` nearest neighbours of `vsample` with a normalized edit distance of at most `distance_threshold`
+fn smallest_within_threshold<'a, S>(
+    trainings_data: &'a [LabelledSequences<S>],
+    vsample: &Sequence,
+    k: usize,
+    distance_threshold: f64,
+    use_cr_mode: bool,
+) -> Vec<ClassifierData<'a, S>> {
+    take_smallest(
+        trainings_data
+            .iter()
+            // iterate over all elements of the trainings data
+            .flat_map(|tlseq| tlseq.sequences.iter().map(move |s| (tlseq, s))),
+        // collect the k smallest distances
+        k,
+        |(tlseq, s), max_distance| {
+            // Larger distances than this always exceed the threshold
+            let threshold_distance =
+                (distance_threshold * vsample.len().max(s.len()) as f64).floor() as usize;
+            let (distance, distance_norm) = memorize_distance(
+                vsample,
+                s,
+                DistanceMetric::EditDistance,
+                max_distance.min(threshold_distance),
+                use_cr_mode,
+            );
+            if *distance_norm.as_ref() > distance_threshold {
+                // In case the distance reaches our threshold, we do not want any result
+                None
+            } else {
+                Some(ClassifierData {
+                    label: &tlseq.mapped_domain,
+                    true_label: &tlseq.true_domain,
+                    distance,
+                    distance_norm,
+                })
+            }
+        },
+    )
+}
+
+/// A trainings [`Sequence`] close to a validation [`Sequence`]
+///
+/// See [`nearest_neighbors`].
+#[derive(Clone, Eq, PartialEq, Hash, Debug, Serialize, Deserialize)]
+pub struct Neighbor {
+    /// Mapped domain of the trainings [`Sequence`]
+    pub label: String,
+    /// True domain of the trainings [`Sequence`]
+    pub true_label: String,
+    pub distance: usize,
+    pub distance_norm: NotNan<f64>,
+}
+
+/// Find the `depth` nearest neighbours in `trainings_data` for each element in `validation_data`
+///
+/// The neighbours are sorted by increasing distance.
+/// They do not depend on `k` or on a distance threshold, such that they can be stored and classified later with [`knn_from_neighbors`].
+pub fn nearest_neighbors<S>(
+    trainings_data: &[LabelledSequences<S>],
+    validation_data: &[Sequence],
+    depth: usize,
+    metric: DistanceMetric,
+    use_cr_mode: bool,
+) -> Vec<Vec<Neighbor>>
+where
+    S: AsRef<str> + Clone + Display + Sync,
+{
+    assert!(depth > 0, "The number of neighbours must be larger than 0");
+
+    validation_data
+        .into_par_iter()
+        .with_max_len(1)
+        .map(|vsample| {
+            let mut distances = take_smallest(
                 trainings_data
                     .iter()
                     // iterate over all elements of the trainings data
-                    .flat_map(|tlseq| {
-                        tlseq.sequences.iter().flat_map(move |s| {
-                            let (distance, distance_norm) =
-                                memorize_distance(vsample, s, use_cr_mode);
-                            if *distance_norm.as_ref() > distance_threshold {
-                                // In case the distance reaches our threshold, we do not want any result
-                                None
-                            } else {
-                                Some(ClassifierData {
-                                    label: &tlseq.mapped_domain,
-                                    distance,
-                                    distance_norm,
-                                })
-                            }
-                        })
-                    }),
-                // collect the k smallest distances
-                k as usize,
+                    .flat_map(|tlseq| tlseq.sequences.iter().map(move |s| (tlseq, s))),
+                depth,
+                |(tlseq, s), max_distance| {
+                    let (distance, distance_norm) =
+                        memorize_distance(vsample, s, metric, max_distance, use_cr_mode);
+
+                    Some(ClassifierData {
+                        label: &tlseq.mapped_domain,
+                        true_label: &tlseq.true_domain,
+                        distance,
+                        distance_norm,
+                    })
+                },
+            );
+            distances.sort();
+            distances
+                .into_iter()
+                .map(|data| Neighbor {
+                    label: data.label.as_ref().to_string(),
+                    true_label: data.true_label.as_ref().to_string(),
+                    distance: data.distance,
+                    distance_norm: data.distance_norm,
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// Classify each entry of `validation_data` with the `k` nearest of its stored `neighbors`
+///
+/// `neighbors` is the output of [`nearest_neighbors`] with the [`DistanceMetric::EditDistance`] and needs a depth of at least `k`.
+/// With a `distance_threshold`, neighbours with a larger normalized distance are skipped after loading them, like in [`knn_with_threshold`].
+/// If fewer than `k` stored neighbours are within the threshold, closer ones might have been cut off by the depth.
+/// The neighbours of such entries are calculated again from `trainings_data`, such that the result always matches [`knn_with_threshold`].
+pub fn knn_from_neighbors<S>(
+    trainings_data: &[LabelledSequences<S>],
+    validation_data: &[Sequence],
+    neighbors: &[Vec<Neighbor>],
+    k: u8,
+    distance_threshold: Option<f64>,
+    weighting: VoteWeighting,
+    use_cr_mode: bool,
+) -> Vec<ClassificationResult>
+where
+    S: AsRef<str> + Clone + Display + Sync,
+{
+    assert!(k > 0, "kNN needs a k with k > 0");
+    assert_eq!(
+        validation_data.len(),
+        neighbors.len(),
+        "Each validation sequence needs its neighbours"
+    );
+    let trainings_size: usize = trainings_data
+        .iter()
+        .map(|tlseq| tlseq.sequences.len())
+        .sum();
+
+    neighbors
+        .par_iter()
+        .zip(validation_data)
+        .with_max_len(1)
+        .map(|(neighbors, vsample)| {
+            let selected: Vec<&Neighbor> = neighbors
+                .iter()
+                .filter(|neighbor| {
+                    distance_threshold
+                        .map(|threshold| *neighbor.distance_norm.as_ref() <= threshold)
+                        .unwrap_or(true)
+                })
+                .take(k as usize)
+                .collect();
+            if let Some(threshold) = distance_threshold {
+                if selected.len() < k as usize && neighbors.len() < trainings_size {
+                    let distances = smallest_within_threshold(
+                        trainings_data,
+                        vsample,
+                        k as usize,
+                        threshold,
+                        use_cr_mode,
+                    );
+                    return ClassificationResult::from_classifier_data(&distances, weighting);
+                }
+            }
+
+            let distances: Vec<ClassifierData<'_, String>> = selected
+                .into_iter()
+                .map(|neighbor| ClassifierData {
+                    label: &neighbor.label,
+                    true_label: &neighbor.true_label,
+                    distance: neighbor.distance,
+                    distance_norm: neighbor.distance_norm,
+                })
+                .collect();
+            ClassificationResult::from_classifier_data(&distances, weighting)
+        })
+        .collect()
+}
+
+/// Hash the [`Sequence`]s and labels of a classification run
+///
+/// The hash identifies the inputs of [`nearest_neighbors`], e.g., to detect outdated stored neighbours.
+/// The order of the [`Sequence`]s is part of the hash.
+pub fn dataset_hash<S>(
+    trainings_data: &[LabelledSequences<S>],
+    validation_data: &[Sequence],
+) -> Result<String, Error>
+where
+    S: AsRef<str>,
+{
+    let mut hasher = blake3::Hasher::new();
+    for tlseq in trainings_data {
+        for label in &[tlseq.true_domain.as_ref(), tlseq.mapped_domain.as_ref()] {
+            hasher.update(label.as_bytes());
+            hasher.update(b"\n");
+        }
+        for seq in &tlseq.sequences {
+            serde_json::to_writer(&mut hasher, seq)?;
+            hasher.update(b"\n");
+        }
+    }
+    // Separate the trainings data from the validation data
+    hasher.update(b"\0");
+    for seq in validation_data {
+        serde_json::to_writer(&mut hasher, seq)?;
+        hasher.update(b"\n");
+    }
+    Ok(hasher.finalize().to_hex().to_string())
+}
+
+/// Classification results for growing prefixes of a [`Sequence`]
+///
+/// See [`knn_prefixes`].
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Default, Serialize, Deserialize)]
+pub struct PrefixClassificationResult {
+    /// Length of the prefix in [`SequenceElement`](super::SequenceElement)s for each entry in `results`
+    pub prefix_lengths: Vec<usize>,
+    /// One [`ClassificationResult`] per prefix, ordered by increasing prefix length
+    ///
+    /// The last entry is the classification of the full [`Sequence`].
+    pub results: Vec<ClassificationResult>,
+}
+
+impl PrefixClassificationResult {
+    /// Return the final label, i.e., the best label of the full [`Sequence`]
+    pub fn final_label(&self) -> Option<&str> {
+        self.results
+            .last()
+            .and_then(ClassificationResult::best_label)
+    }
+
+    /// Return the minimal prefix length after which the decision no longer changes
+    ///
+    /// This is the shortest prefix length, such that the best label of this prefix and of all longer prefixes equals the [final label](Self::final_label).
+    /// Returns [`None`] if there is no final label.
+    pub fn stable_prefix_length(&self) -> Option<usize> {
+        let final_label = self.final_label()?;
+        let unstable = self
+            .results
+            .iter()
+            .rposition(|res| res.best_label() != Some(final_label));
+        let stable_idx = unstable.map(|idx| idx + 1).unwrap_or(0);
+        Some(self.prefix_lengths[stable_idx])
+    }
+}
+
+/// Classify growing prefixes of each element in `validation_data`
+///
+/// The prefixes have lengths of `step`, `2 * step`, etc. up to the full length of each [`Sequence`].
+/// A prefix of length `len` is classified against the prefixes of the same length of the trainings data.
+/// The full [`Sequence`] is classified against the full trainings data, thus the last result is identical to [`knn`].
+///
+/// # Panics
+///
+/// If `step` is `0`.
+pub fn knn_prefixes<S>(
+    trainings_data: &[LabelledSequences<S>],
+    validation_data: &[Sequence],
+    k: u8,
+    step: usize,
+    use_cr_mode: bool,
+) -> Vec<PrefixClassificationResult>
+where
+    S: AsRef<str> + Clone + Display + Sync,
+{
+    assert!(step > 0, "The prefix step must be larger than 0");
+
+    let mut results: Vec<PrefixClassificationResult> =
+        vec![Default::default(); validation_data.len()];
+    let max_len = validation_data.iter().map(Sequence::len).max().unwrap_or(0);
+
+    for len in (step..max_len).step_by(step) {
+        let trainings_prefixes: Vec<_> = trainings_data
+            .iter()
+            .map(|tlseq| LabelledSequences {
+                true_domain: tlseq.true_domain.clone(),
+                mapped_domain: tlseq.mapped_domain.clone(),
+                sequences: tlseq.sequences.iter().map(|seq| seq.prefix(len)).collect(),
+            })
+            .collect();
+        // Only classify proper prefixes here, the full sequences are handled below
+        let (idxs, validation_prefixes): (Vec<_>, Vec<_>) = validation_data
+            .iter()
+            .enumerate()
+            .filter(|(_, seq)| seq.len() > len)
+            .map(|(idx, seq)| (idx, seq.prefix(len)))
+            .unzip();
+
+        let classification = knn(&trainings_prefixes, &validation_prefixes, k, use_cr_mode);
+        for (idx, res) in idxs.into_iter().zip(classification) {
+            results[idx].prefix_lengths.push(len);
+            results[idx].results.push(res);
+        }
+    }
+
+    let classification = knn(trainings_data, validation_data, k, use_cr_mode);
+    for ((result, seq), res) in results.iter_mut().zip(validation_data).zip(classification) {
+        result.prefix_lengths.push(seq.len());
+        result.results.push(res);
+    }
+    results
+}
+
+/// Classify sliding windows of each element in `validation_data`
+///
+/// All [`Sequence`]s, both in `trainings_data` and `validation_data`, are split with [`Sequence::windows`] using the size and stride of `window`.
+/// Each window of a validation [`Sequence`] is classified with [`knn`] against all trainings windows.
+/// The per window results are combined with [`ClassificationResult::aggregate_votes`].
+///
+/// This allows evaluating how early during a page load the domain can be identified, see [`WindowedClassificationResult::windows_until_classified`].
+pub fn knn_windows<S>(
+    trainings_data: &[LabelledSequences<S>],
+    validation_data: &[Sequence],
+    k: u8,
+    window: WindowSpec,
+    use_cr_mode: bool,
+) -> Vec<WindowedClassificationResult>
+where
+    S: AsRef<str> + Clone + Display + Sync,
+{
+    let windowed_trainings_data: Vec<_> = trainings_data
+        .iter()
+        .map(|tlseq| LabelledSequences {
+            true_domain: tlseq.true_domain.clone(),
+            mapped_domain: tlseq.mapped_domain.clone(),
+            sequences: tlseq
+                .sequences
+                .iter()
+                .flat_map(|seq| seq.windows(window.size, window.stride))
+                .collect(),
+        })
+        .collect();
+
+    validation_data
+        .iter()
+        .map(|vsample| {
+            let windows = knn(
+                &windowed_trainings_data,
+                &vsample.windows(window.size, window.stride),
+                k,
+                use_cr_mode,
             );
-            ClassificationResult::from_classifier_data(&distances)
+            let aggregated = ClassificationResult::aggregate_votes(&windows);
+            WindowedClassificationResult {
+                windows,
+                aggregated,
+            }
+        })
+        .collect()
+}
+
+/// Classify each element in `validation_data` with all members of the `ensemble` and combine the results
+///
+/// Each member performs a [`knn_with_metric`] classification, whose neighbors vote according to `weighting`.
+/// The best label of each member is counted as a vote according to [`Ensemble::voting`].
+pub fn knn_ensemble<S>(
+    trainings_data: &[LabelledSequences<S>],
+    validation_data: &[Sequence],
+    k: u8,
+    ensemble: &Ensemble,
+    weighting: VoteWeighting,
+    use_cr_mode: bool,
+) -> Vec<ClassificationResult>
+where
+    S: AsRef<str> + Clone + Display + Sync,
+{
+    assert!(
+        !ensemble.members.is_empty(),
+        "The ensemble needs at least one member"
+    );
+
+    let member_results: Vec<Vec<ClassificationResult>> = ensemble
+        .members
+        .iter()
+        .map(|member| {
+            knn_with_metric(
+                trainings_data,
+                validation_data,
+                k,
+                member.metric,
+                weighting,
+                use_cr_mode,
+            )
+        })
+        .collect();
+
+    (0..validation_data.len())
+        .map(|idx| {
+            ClassificationResult::aggregate_weighted_votes(
+                member_results
+                    .iter()
+                    .zip(&ensemble.members)
+                    .map(|(results, member)| {
+                        let weight = match ensemble.voting {
+                            EnsembleVoting::Majority => 1,
+                            EnsembleVoting::Weighted => member.weight,
+                        };
+                        (&results[idx], weight)
+                    }),
+            )
         })
         .collect()
 }
 
 /// Perform the distance calculation between two [`Sequence`]s and memorize the result.
+///
+/// The [`DistanceMetric::EditDistance`] is abandoned early, if it exceeds `max_distance`, and `usize::max_value()` is returned.
+/// Only exact distances are memorized, such that later calls with a larger `max_distance` are unaffected.
 fn memorize_distance(
     validation_sample: &Sequence,
     trainings_sample: &Sequence,
+    metric: DistanceMetric,
+    max_distance: usize,
     use_cr_mode: bool,
 ) -> (usize, NotNan<f64>) {
     let v = validation_sample.intern();
     let t = trainings_sample.intern();
     // Distance is symmetric, so sort the two parts of the key, such that we store them only once
-    let key = if v < t { (v, t) } else { (t, v) };
+    let key = if v < t {
+        (metric, v, t)
+    } else {
+        (metric, t, v)
+    };
 
-    // Only fill these with temporary values. They will get overwritten by the lambda below, but
-    // they need to be initialized before the lambda.
-    let distance = *PRECOMPUTED_DISTANCES.entry(key).or_insert_with(|| {
-        validation_sample
-            .distance_with_limit::<()>(trainings_sample, true, use_cr_mode)
-            .0
-    });
+    let cached = PRECOMPUTED_DISTANCES.get(&key).map(|distance| *distance);
+    let distance = match cached {
+        Some(distance) => distance,
+        None => {
+            let distance = match metric {
+                DistanceMetric::EditDistance => {
+                    validation_sample
+                        .distance_with_limit::<()>(
+                            trainings_sample,
+                            max_distance,
+                            true,
+                            use_cr_mode,
+                        )
+                        .0
+                }
+                _ => metric.distance(validation_sample, trainings_sample),
+            };
+            // An abandoned calculation only provides a lower bound, which must not be memorized
+            if distance <= max_distance || metric != DistanceMetric::EditDistance {
+                PRECOMPUTED_DISTANCES.insert(key, distance);
+            }
+            distance
+        }
+    };
 
     // Avoid divide by 0 cases, which can happen in the PerfectPadding scenario
     // If both sequences are 0 length, then the distance must also be 0
@@ -350,6 +1482,131 @@ fn memorize_distance(
     (distance, distance_norm)
 }
 
+/// How to balance the number of [`Sequence`]s per class before splitting the data
+///
+/// Aborted measurements leave some domains with fewer [`Sequence`]s than others.
+/// Each mapped domain counts as a class, even if it spans multiple [`LabelledSequences`].
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Serialize, Deserialize)]
+pub enum ClassBalance {
+    /// Keep all [`Sequence`]s
+    None,
+    /// Reduce each class to the size of the smallest non-empty class
+    Undersample,
+    /// Keep at most this many [`Sequence`]s per class
+    Cap(usize),
+}
+
+impl Default for ClassBalance {
+    fn default() -> Self {
+        Self::None
+    }
+}
+
+impl Display for ClassBalance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClassBalance::None => write!(f, "none"),
+            ClassBalance::Undersample => write!(f, "undersample"),
+            ClassBalance::Cap(n) => write!(f, "cap:{}", n),
+        }
+    }
+}
+
+impl FromStr for ClassBalance {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match &*s.to_ascii_lowercase() {
+            "none" => Ok(ClassBalance::None),
+            "undersample" => Ok(ClassBalance::Undersample),
+            other => match other.strip_prefix("cap:") {
+                Some(n) => Ok(ClassBalance::Cap(n.parse().with_context(|| {
+                    format!("Invalid number of sequences per class in `{}`", s)
+                })?)),
+                None => bail!(
+                    "Unknown class balance `{}`. Supported are `none`, `undersample`, and `cap:<n>`.",
+                    s
+                ),
+            },
+        }
+    }
+}
+
+/// Reduce the number of [`Sequence`]s per mapped domain according to `balance`
+///
+/// The kept [`Sequence`]s of each class are drawn randomly, such that they do not depend on the order of the files.
+/// The randomness is seeded with the mapped domain, such that the result is deterministic.
+/// The kept [`Sequence`]s stay in their original order.
+/// Returns the number of removed [`Sequence`]s.
+pub fn balance_classes<S: Eq + Hash>(
+    data: &mut [LabelledSequences<S>],
+    balance: ClassBalance,
+) -> usize {
+    if balance == ClassBalance::None {
+        return 0;
+    }
+
+    // Indices of the `LabelledSequences` belonging to each mapped domain
+    let mut classes: Vec<Vec<usize>> = Vec::new();
+    let mut class_index = HashMap::new();
+    for (idx, lseqs) in data.iter().enumerate() {
+        let class = *class_index.entry(&lseqs.mapped_domain).or_insert_with(|| {
+            classes.push(Vec::new());
+            classes.len() - 1
+        });
+        classes[class].push(idx);
+    }
+
+    let class_size =
+        |members: &[usize]| -> usize { members.iter().map(|&idx| data[idx].sequences.len()).sum() };
+    let limit = match balance {
+        ClassBalance::None => return 0,
+        ClassBalance::Undersample => match classes
+            .iter()
+            .map(|members| class_size(members))
+            .filter(|&len| len > 0)
+            .min()
+        {
+            Some(limit) => limit,
+            None => return 0,
+        },
+        ClassBalance::Cap(limit) => limit,
+    };
+
+    let mut removed = 0;
+    for members in classes {
+        // Position of each `Sequence` of the class as index of the `LabelledSequences` and index in it
+        let mut positions: Vec<(usize, usize)> = members
+            .iter()
+            .flat_map(|&idx| (0..data[idx].sequences.len()).map(move |pos| (idx, pos)))
+            .collect();
+        if positions.len() <= limit {
+            continue;
+        }
+
+        let mut rng = {
+            let mut hasher = FnvHasher::with_key(0);
+            data[members[0]].mapped_domain.hash(&mut hasher);
+            XorShiftRng::seed_from_u64(hasher.finish())
+        };
+        positions.shuffle(&mut rng);
+        let mut dropped = positions.split_off(limit);
+        dropped.sort_unstable();
+        removed += dropped.len();
+
+        for idx in members {
+            let sequences = mem::take(&mut data[idx].sequences);
+            data[idx].sequences = sequences
+                .into_iter()
+                .enumerate()
+                .filter(|&(pos, _)| dropped.binary_search(&(idx, pos)).is_err())
+                .map(|(_, seq)| seq)
+                .collect();
+        }
+    }
+    removed
+}
+
 #[allow(clippy::type_complexity)]
 pub fn split_training_test_data<S>(
     data: &[LabelledSequences<S>],
@@ -401,6 +1658,7 @@ where
 #[derive(Debug)]
 pub(crate) struct ClassifierData<'a, S: ?Sized> {
     label: &'a S,
+    true_label: &'a S,
     pub distance: usize,
     pub distance_norm: NotNan<f64>,
 }
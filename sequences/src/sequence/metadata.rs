@@ -0,0 +1,160 @@
+use crate::LoadSequenceConfig;
+use anyhow::{Context as _, Error};
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::SystemTime,
+};
+
+/// Vantage point of all files which do not specify one in their file name
+pub const DEFAULT_VANTAGE_POINT: &str = "local";
+/// Upper bound of entries in [`FILE_HASHES`]
+const MAX_CACHED_FILE_HASHES: usize = 4096;
+
+/// Recently computed file hashes, keyed by the path, size, and modification time of the file
+///
+/// Loading a single file can require its hash multiple times, e.g., for the [`ConversionCache`](crate::conversion_cache::ConversionCache) and the [`SequenceMetadata`].
+/// A changed size or modification time invalidates the entry.
+static FILE_HASHES: Lazy<Mutex<HashMap<(PathBuf, u64, SystemTime), String>>> =
+    Lazy::new(Default::default);
+
+/// Provenance information describing how a [`Sequence`](crate::Sequence) was created
+///
+/// The metadata allows tracing a [`Sequence`](crate::Sequence) back to the exact input file and the parameters used while loading it.
+/// All fields are optional, since not every input format provides all information.
+#[derive(Clone, Eq, PartialEq, Debug, Deserialize, Serialize)]
+pub struct SequenceMetadata {
+    /// Hex encoded BLAKE3 hash of the file the [`Sequence`](crate::Sequence) was loaded from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source_file_hash: Option<String>,
+    /// Time of the first DNS message used for the [`Sequence`](crate::Sequence)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub capture_start: Option<DateTime<Utc>>,
+    /// Address of the upstream resolver
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resolver: Option<SocketAddr>,
+    /// Location from which the data was recorded, see [`vantage_point_from_path`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vantage_point: Option<String>,
+    /// The [`LoadSequenceConfig`] used, in its [`Debug`] representation
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub config: Option<String>,
+    /// Number of packets of the source file, which were not fully captured
+    ///
+    /// Only pcaps loaded with [`TruncationMode::Lenient`](crate::TruncationMode::Lenient) can contain truncated packets.
+    /// The [`Sequence`](crate::Sequence) might be incomplete, if this is non-zero.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub truncated_packets: usize,
+    /// Version of the `sequences` crate which created the [`Sequence`](crate::Sequence)
+    #[serde(default)]
+    pub crate_version: String,
+}
+
+impl SequenceMetadata {
+    /// Create the metadata for a [`Sequence`](crate::Sequence) loaded from `path` using `config`
+    ///
+    /// This hashes the whole file.
+    /// `capture_start` and `resolver` depend on the file format and have to be filled in by the caller.
+    pub fn for_file(path: &Path, config: LoadSequenceConfig) -> Result<Self, Error> {
+        Ok(Self {
+            source_file_hash: Some(file_hash(path)?),
+            vantage_point: Some(vantage_point_from_path(&path.to_string_lossy()).to_string()),
+            config: Some(format!("{:?}", config)),
+            ..Self::default()
+        })
+    }
+}
+
+impl Default for SequenceMetadata {
+    fn default() -> Self {
+        Self {
+            source_file_hash: None,
+            capture_start: None,
+            resolver: None,
+            vantage_point: None,
+            config: None,
+            truncated_packets: 0,
+            crate_version: env!("CARGO_PKG_VERSION").to_string(),
+        }
+    }
+}
+
+/// Hex encoded BLAKE3 hash of the content of the file at `path`
+///
+/// The hash is only computed once, as long as the size and modification time of the file stay the same.
+pub(crate) fn file_hash(path: &Path) -> Result<String, Error> {
+    let metadata =
+        fs::metadata(path).with_context(|| format!("Cannot access file `{}`", path.display()))?;
+    // Without a modification time, changes to the file cannot be detected
+    let key = metadata
+        .modified()
+        .ok()
+        .map(|modified| (path.to_path_buf(), metadata.len(), modified));
+    if let Some(key) = &key {
+        if let Some(hash) = FILE_HASHES.lock().unwrap().get(key) {
+            return Ok(hash.clone());
+        }
+    }
+
+    let mut hasher = blake3::Hasher::new();
+    let mut file =
+        File::open(path).with_context(|| format!("Cannot open file `{}`", path.display()))?;
+    io::copy(&mut file, &mut hasher)
+        .with_context(|| format!("Cannot hash file `{}`", path.display()))?;
+    let hash = hasher.finalize().to_hex().to_string();
+
+    if let Some(key) = key {
+        let mut hashes = FILE_HASHES.lock().unwrap();
+        if hashes.len() >= MAX_CACHED_FILE_HASHES {
+            hashes.clear();
+        }
+        hashes.insert(key, hash.clone());
+    }
+    Ok(hash)
+}
+
+fn is_zero(value: &usize) -> bool {
+    *value == 0
+}
+
+/// Extract the vantage point from the file name of a result file
+///
+/// The taskmanager names the result files `<task name>@<vantage point>.<extension>`.
+/// Files without a vantage point in their name were recorded at the [`DEFAULT_VANTAGE_POINT`].
+pub fn vantage_point_from_path(path: &str) -> &str {
+    let file_name = path.rsplit('/').next().unwrap_or(path);
+    file_name
+        .split_once('@')
+        .and_then(|(_, rest)| rest.split('.').next())
+        .filter(|vantage_point| !vantage_point.is_empty())
+        .unwrap_or(DEFAULT_VANTAGE_POINT)
+}
+
+#[test]
+fn test_file_hash_is_memoized() {
+    let path = std::env::temp_dir().join(format!("file-hash-{}", std::process::id()));
+    fs::write(&path, b"first content").unwrap();
+    let hash = file_hash(&path).unwrap();
+    assert_eq!(hash, blake3::hash(b"first content").to_hex().to_string());
+    assert!(FILE_HASHES
+        .lock()
+        .unwrap()
+        .values()
+        .any(|cached| cached == &hash));
+    assert_eq!(file_hash(&path).unwrap(), hash);
+
+    // A different size invalidates the memoized hash
+    fs::write(&path, b"second, longer content").unwrap();
+    assert_eq!(
+        file_hash(&path).unwrap(),
+        blake3::hash(b"second, longer content").to_hex().to_string()
+    );
+    fs::remove_file(&path).unwrap();
+}
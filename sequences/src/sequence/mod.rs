@@ -6,38 +6,95 @@
 //! Additionally, the [`knn`] module contains all functions and types to perform k-NN classification.
 
 pub mod distance_cost_info;
+mod histogram;
 pub mod knn;
+mod metadata;
+mod run_length;
 mod sequence_element;
+mod storage;
 
-pub use self::sequence_element::{OneHotEncoding, SequenceElement};
-use crate::{common_sequence_classifications::*, dnstap, load_sequence::*};
-use anyhow::{bail, Context as _, Error};
-use internment::Intern;
+pub use self::{
+    histogram::{element_histogram, ElementHistogram},
+    metadata::{vantage_point_from_path, SequenceMetadata, DEFAULT_VANTAGE_POINT},
+    run_length::RunLengthSequence,
+    sequence_element::{OneHotEncoding, SequenceElement},
+};
+pub(crate) use self::{metadata::file_hash, storage::SequenceStorage};
+use crate::{
+    common_sequence_classifications::*,
+    dnstap,
+    format_version::{check_format_version, read_format_version, VersionedFormat, FORMAT_VERSION},
+    load_sequence::*,
+};
+use anyhow::{anyhow, bail, Context as _, Error};
 use misc_utils::{fs, path::PathExt, Min};
 use serde::{
     de::{Error as SerdeError, MapAccess, Visitor},
     ser::SerializeMap,
     Deserialize, Deserializer, Serialize, Serializer,
 };
+use serde_json::Value;
 use std::{
     cmp::{Eq, Ord, Ordering, PartialEq, PartialOrd},
     fmt::{self, Debug},
     hash::Hash,
     mem,
+    ops::Range,
     path::Path,
+    sync::Arc,
 };
 
-type InternedSequence = Intern<Vec<SequenceElement>>;
+/// Key under which the [`SequenceMetadata`] is serialized, next to the identifier
+const METADATA_KEY: &str = "__metadata__";
+/// Key under which the [format version](crate::format_version) is serialized, next to the identifier
+const FORMAT_VERSION_KEY: &str = "__format_version__";
 
 /// A sequence of DNS messages and timing gaps between them.
+///
+/// The optional [`SequenceMetadata`] is not considered for comparisons or hashing.
 #[derive(Clone, Debug)]
-pub struct Sequence(InternedSequence, String);
+pub struct Sequence(SequenceStorage, String, Option<Arc<SequenceMetadata>>);
 
 #[allow(clippy::len_without_is_empty)]
 impl Sequence {
     pub fn new(sequence: Vec<SequenceElement>, identifier: String) -> Sequence {
-        let interned = InternedSequence::new(sequence);
-        Sequence(interned, identifier)
+        Sequence(SequenceStorage::new(sequence), identifier, None)
+    }
+
+    /// Replace the identifier of the [`Sequence`]
+    ///
+    /// See [`normalize_sequence_id`](crate::normalize_sequence_id) for making identifiers independent of the dataset location.
+    pub fn with_id(mut self, identifier: String) -> Self {
+        self.1 = identifier;
+        self
+    }
+
+    /// Attach provenance information to the [`Sequence`]
+    pub fn with_metadata(mut self, metadata: SequenceMetadata) -> Self {
+        self.2 = Some(Arc::new(metadata));
+        self
+    }
+
+    /// Return the provenance information, if the [`Sequence`] has any
+    pub fn metadata(&self) -> Option<&SequenceMetadata> {
+        self.2.as_deref()
+    }
+
+    /// Return `true` if the [`Sequence`] was built from a capture with truncated packets
+    ///
+    /// Such [`Sequence`]s might be incomplete, so the caller can decide whether to keep them.
+    pub fn is_truncated(&self) -> bool {
+        self.metadata()
+            .map_or(false, |metadata| metadata.truncated_packets > 0)
+    }
+
+    /// Return the vantage point at which the [`Sequence`] was recorded
+    ///
+    /// Uses the [`SequenceMetadata`] if available and otherwise the identifier, which is the path of the source file.
+    pub fn vantage_point(&self) -> &str {
+        self.metadata()
+            .and_then(|metadata| metadata.vantage_point.as_deref())
+            .unwrap_or_else(|| vantage_point_from_path(&self.1))
     }
 
     /// Load a [`Sequence`] from a file path with default configuration.
@@ -49,6 +106,8 @@ impl Sequence {
 
     /// Load a [`Sequence`] from a file path. The file has to be a dnstap file.
     ///
+    /// With the `read_pcap` feature, pcap files and tshark JSON dissections (`.tsharkjson`) are supported too.
+    ///
     /// `config` allows to alter the loading according to [`LoadSequenceConfig`]
     pub fn from_path_with_config(
         path: &Path,
@@ -57,22 +116,32 @@ impl Sequence {
         // Iterate over all file extensions, from last to first.
         for ext in path.extensions() {
             match ext.to_str() {
-                Some("dnstap") => return dnstap::build_sequence(path, config),
+                Some("dnstap") => {
+                    return dnstap::build_sequence_streaming(
+                        path,
+                        config,
+                        dnstap::DEFAULT_REORDER_BUFFER,
+                    )
+                }
                 Some("json") => {
-                    if config != Default::default() {
+                    if config.without_filters() != Default::default() {
                         bail!("Trying to load a Sequence from JSON with a custom LoadSequenceConfig: LoadSequenceConfig is not supported for JSON format.")
                     }
                     let seq_json = fs::read_to_string(path)
                         .with_context(|| format!("Cannot read file `{}`", path.display()))?;
-                    return Ok(serde_json::from_str(&seq_json)?);
+                    let seq: Sequence = crate::format_version::from_json_any_version(&seq_json)?;
+                    config.check_filters(seq.message_count(), seq.complexity())?;
+                    return Ok(seq);
                 }
                 #[cfg(feature = "read_pcap")]
-                Some("pcap") => return crate::pcap::build_sequence(path, None, false, config),
+                Some("pcap") | Some("tsharkjson") => {
+                    return crate::pcap::build_sequence(path, None, false, config)
+                }
                 _ => {}
             }
         }
         // Fallback to the old behavior
-        dnstap::build_sequence(path, config)
+        dnstap::build_sequence_streaming(path, config, dnstap::DEFAULT_REORDER_BUFFER)
     }
 
     /// Return the [`Sequence`]'s identifier. Normally, the file name.
@@ -120,6 +189,13 @@ impl Sequence {
             .count()
     }
 
+    /// Count how often each [`SequenceElement`] value occurs in the [`Sequence`]
+    pub fn element_histogram(&self) -> ElementHistogram {
+        let mut histogram = ElementHistogram::default();
+        histogram.add_sequence(self);
+        histogram
+    }
+
     pub fn to_one_hot_encoding(&self) -> Vec<OneHotEncoding> {
         self.as_elements()
             .iter()
@@ -138,11 +214,14 @@ impl Sequence {
 
     /// Return the distance to the `other` [`Sequence`].
     pub fn distance(&self, other: &Self) -> usize {
-        self.distance_with_limit::<()>(other, false, false).0
+        self.distance_with_limit::<()>(other, usize::max_value(), false, false)
+            .0
     }
 
     /// Same as [`Sequence::distance`] but with an early exit criteria
     ///
+    /// The calculation is abandoned as soon as the distance is guaranteed to be larger than `max_distance`.
+    /// In this case `usize::max_value()` is returned instead of the exact distance.
     /// This means that early exit can be disabled by setting `max_distance` to `usize::max_value()`, as there can be no larger value.
     ///
     /// If `use_length_prefilter` is true, the function performs an initial check, if the length of the sequences are similar enough.
@@ -154,6 +233,7 @@ impl Sequence {
     pub fn distance_with_limit<DCI>(
         &self,
         other: &Self,
+        max_distance: usize,
         use_length_prefilter: bool,
         use_cr_mode: bool,
     ) -> (usize, DCI)
@@ -210,12 +290,13 @@ impl Sequence {
         let mut prev_prev_row: RowType<DCI> =
             (0..=smaller.len()).map(|_| (0, DCI::default())).collect();
         let mut cost = 0;
+        let mut cost_info = DCI::default();
         let mut previous_row: RowType<DCI> = Some((0, DCI::default()))
             .into_iter()
             .chain(smaller.iter().map(|&elem| {
                 cost += elem.insert_cost();
-                let cost_info = DCI::default().insert(cost, elem);
-                (cost, cost_info)
+                cost_info = cost_info.insert(cost, elem);
+                (cost, cost_info.clone())
             }))
             .collect();
         let mut current_row: RowType<DCI> =
@@ -226,12 +307,14 @@ impl Sequence {
             "Row length must be equal"
         );
 
+        // Minimal cost within the previous row, used for the early exit
+        let mut min_cost_previous_row = 0;
         for (i, &elem1) in larger.iter().enumerate() {
             current_row.clear();
             let p = previous_row[0].0 + elem1.delete_cost();
             let p_info = previous_row[0].1.delete(p, elem1);
             current_row.push((p, p_info));
-            let mut min_cost_current_row: Min<usize> = Default::default();
+            let mut min_cost_current_row = Min::with_initial(p);
 
             for (j, &elem2) in smaller.iter().enumerate() {
                 let insertions = previous_row[j + 1].0 + elem1.insert_cost();
@@ -269,6 +352,17 @@ impl Sequence {
 
             mem::swap(&mut prev_prev_row, &mut previous_row);
             mem::swap(&mut previous_row, &mut current_row);
+
+            // All costs are non-negative, so each cell is at least as large as the minimum of the two rows it is computed from.
+            // Two rows are necessary, since swaps refer back to the row before the previous one.
+            // Once both row minima exceed `max_distance`, the final distance will do so, too.
+            let min_cost_current_row = min_cost_current_row
+                .get_min()
+                .expect("The row minimum is initialized with the first cell");
+            if min_cost_current_row.min(min_cost_previous_row) > max_distance {
+                return (usize::max_value(), DCI::default().abort());
+            }
+            min_cost_previous_row = min_cost_current_row;
         }
 
         previous_row
@@ -277,9 +371,131 @@ impl Sequence {
             .expect("The rows are never empty, thus there is a last.")
     }
 
+    /// Return the distance to the `other` [`Sequence`] computed on the runs of identical elements
+    ///
+    /// See [`RunLengthSequence::distance`] for the details.
+    pub fn run_length_distance(&self, other: &Self) -> usize {
+        RunLengthSequence::from(self).distance(&RunLengthSequence::from(other))
+    }
+
+    /// Return the dynamic time warping (DTW) distance to the `other` [`Sequence`]
+    ///
+    /// The DTW distance operates on the [vector encoding](Sequence::to_vector_encoding) and uses the L1 distance between two elements.
+    pub fn dtw_distance(&self, other: &Self) -> usize {
+        let a = self.to_vector_encoding();
+        let b = other.to_vector_encoding();
+        let element_cost = |x: (u16, u16), y: (u16, u16)| -> usize {
+            (i32::from(x.0) - i32::from(y.0)).abs() as usize
+                + (i32::from(x.1) - i32::from(y.1)).abs() as usize
+        };
+
+        if a.is_empty() || b.is_empty() {
+            // Every element needs to be matched against "nothing"
+            return a.iter().chain(&b).map(|&x| element_cost(x, (0, 0))).sum();
+        }
+
+        let mut previous_row = vec![usize::max_value(); b.len() + 1];
+        previous_row[0] = 0;
+        let mut current_row = vec![usize::max_value(); b.len() + 1];
+        for &x in &a {
+            current_row[0] = usize::max_value();
+            for (j, &y) in b.iter().enumerate() {
+                let best = previous_row[j].min(previous_row[j + 1]).min(current_row[j]);
+                current_row[j + 1] = best.saturating_add(element_cost(x, y));
+            }
+            mem::swap(&mut previous_row, &mut current_row);
+        }
+        previous_row[b.len()]
+    }
+
+    /// Return the CUMUL feature vector of the [`Sequence`]
+    ///
+    /// The CUMUL features are the cumulative sum of all [`SequenceElement::Size`] elements, linearly interpolated at `n` equidistant points.
+    /// They are described in "Website Fingerprinting at Internet Scale" by Panchenko et al.
+    pub fn to_cumul_features(&self, n: usize) -> Vec<f64> {
+        let mut cumulative = vec![0.];
+        for elem in self.as_elements() {
+            if let SequenceElement::Size(s) = elem {
+                let last = *cumulative.last().expect("Vector is never empty");
+                cumulative.push(last + f64::from(*s));
+            }
+        }
+
+        let max_idx = (cumulative.len() - 1) as f64;
+        (0..n)
+            .map(|i| {
+                let pos = if n > 1 {
+                    i as f64 / (n - 1) as f64 * max_idx
+                } else {
+                    max_idx
+                };
+                let lower = pos.floor() as usize;
+                let upper = pos.ceil() as usize;
+                let fraction = pos - lower as f64;
+                cumulative[lower] * (1. - fraction) + cumulative[upper] * fraction
+            })
+            .collect()
+    }
+
+    /// Return the euclidean distance between the [CUMUL features](Sequence::to_cumul_features) of both [`Sequence`]s
+    pub fn cumul_distance(&self, other: &Self) -> f64 {
+        const CUMUL_FEATURES: usize = 100;
+        self.to_cumul_features(CUMUL_FEATURES)
+            .into_iter()
+            .zip(other.to_cumul_features(CUMUL_FEATURES))
+            .map(|(a, b)| (a - b) * (a - b))
+            .sum::<f64>()
+            .sqrt()
+    }
+
+    /// Return the first `len` elements of the [`Sequence`] as a new [`Sequence`]
+    ///
+    /// If the [`Sequence`] is shorter than `len` all elements are returned.
+    /// The ID of the prefix is the original ID with the element range appended, e.g., `domain.dnstap#0..10`.
+    pub fn prefix(&self, len: usize) -> Sequence {
+        let len = len.min(self.len());
+        self.derive(0..len, format!("{}#0..{}", self.id(), len))
+    }
+
+    /// Split the [`Sequence`] into overlapping subsequences of `size` elements
+    ///
+    /// A new window starts every `stride` elements.
+    /// Only windows which fit completely into the [`Sequence`] are returned, with the exception that a [`Sequence`] shorter than `size` is returned as a single window.
+    /// The ID of each window is the original ID with the element range appended, e.g., `domain.dnstap#10..20`.
+    ///
+    /// # Panics
+    ///
+    /// If `size` or `stride` is `0`.
+    pub fn windows(&self, size: usize, stride: usize) -> Vec<Sequence> {
+        assert!(size > 0, "The window size must be larger than 0");
+        assert!(stride > 0, "The window stride must be larger than 0");
+
+        let len = self.len();
+        if len <= size {
+            return vec![self.derive(0..len, format!("{}#0..{}", self.id(), len))];
+        }
+
+        (0..=(len - size))
+            .step_by(stride)
+            .map(|start| {
+                self.derive(
+                    start..start + size,
+                    format!("{}#{}..{}", self.id(), start, start + size),
+                )
+            })
+            .collect()
+    }
+
+    /// Create a new [`Sequence`] from the `range` of elements of this one, keeping the [`SequenceMetadata`]
+    ///
+    /// The new [`Sequence`] shares the storage of the elements with this one.
+    fn derive(&self, range: Range<usize>, identifier: String) -> Sequence {
+        Sequence(self.0.slice(range), identifier, self.2.clone())
+    }
+
     /// Return the internal slice of [`SequenceElement`]s
     pub fn as_elements(&self) -> &[SequenceElement] {
-        self.0.as_ref()
+        self.0.as_elements()
     }
 
     pub fn classify(&self) -> Option<&'static str> {
@@ -343,7 +559,8 @@ impl Sequence {
         Ok(serde_json::to_string(self)?)
     }
 
-    pub fn intern(&self) -> InternedSequence {
+    /// Return the storage of the elements, which is cheap to compare and hash
+    pub(crate) fn intern(&self) -> SequenceStorage {
         self.0
     }
 }
@@ -351,7 +568,8 @@ impl Sequence {
 impl PartialEq for Sequence {
     fn eq(&self, other: &Self) -> bool {
         // compare IDs first, only then the sequences
-        self.1 == other.1 && self.0 == other.0
+        // Sequences sharing the same storage are always identical, which avoids comparing the elements
+        self.1 == other.1 && (self.0 == other.0 || self.as_elements() == other.as_elements())
     }
 }
 
@@ -362,8 +580,9 @@ impl Hash for Sequence {
     where
         H: std::hash::Hasher,
     {
+        // Hash the elements, since identical elements can be stored differently
         self.1.hash(state);
-        self.0.hash(state);
+        self.as_elements().hash(state);
     }
 }
 
@@ -386,8 +605,12 @@ impl Serialize for Sequence {
     where
         S: Serializer,
     {
-        let mut map_ser = serializer.serialize_map(Some(1))?;
-        map_ser.serialize_entry(&self.1, &self.0)?;
+        let mut map_ser = serializer.serialize_map(Some(2 + self.2.is_some() as usize))?;
+        map_ser.serialize_entry(&self.1, self.as_elements())?;
+        map_ser.serialize_entry(FORMAT_VERSION_KEY, &FORMAT_VERSION)?;
+        if let Some(metadata) = &self.2 {
+            map_ser.serialize_entry(METADATA_KEY, metadata)?;
+        }
         map_ser.end()
     }
 }
@@ -410,12 +633,29 @@ impl<'de> Deserialize<'de> for Sequence {
             where
                 A: MapAccess<'de>,
             {
-                let entry = map.next_entry()?;
-                if let Some(entry) = entry {
-                    Ok(Sequence::new(entry.1, entry.0))
-                } else {
-                    Err(SerdeError::custom("The map must contain one element."))
+                let mut sequence = None;
+                let mut metadata = None;
+                let mut format_version = None;
+                while let Some(key) = map.next_key::<String>()? {
+                    if key == METADATA_KEY {
+                        metadata = Some(map.next_value()?);
+                    } else if key == FORMAT_VERSION_KEY {
+                        format_version = Some(map.next_value()?);
+                    } else if sequence.is_none() {
+                        sequence = Some(Sequence::new(map.next_value()?, key));
+                    } else {
+                        return Err(SerdeError::custom(
+                            "The map must contain only one sequence.",
+                        ));
+                    }
+                }
+                check_format_version(format_version)?;
+                let mut sequence = sequence
+                    .ok_or_else(|| SerdeError::custom("The map must contain one sequence."))?;
+                if let Some(metadata) = metadata {
+                    sequence = sequence.with_metadata(metadata);
                 }
+                Ok(sequence)
             }
         }
 
@@ -423,6 +663,19 @@ impl<'de> Deserialize<'de> for Sequence {
     }
 }
 
+impl VersionedFormat for Sequence {
+    fn migrate(mut value: Value) -> Result<Value, Error> {
+        if read_format_version(&value, FORMAT_VERSION_KEY)? == 1 {
+            // Version 1 only differs by the missing version
+            value
+                .as_object_mut()
+                .ok_or_else(|| anyhow!("A Sequence must be a JSON map"))?
+                .insert(FORMAT_VERSION_KEY.to_string(), FORMAT_VERSION.into());
+        }
+        Ok(value)
+    }
+}
+
 #[test]
 fn test_serialization_roundtrip_sequence() {
     use SequenceElement::*;
@@ -434,6 +687,98 @@ fn test_serialization_roundtrip_sequence() {
     let seq = Sequence::new(vec![Size(1), Gap(2), Size(1), Size(2), Size(1)], "".into());
     let from_des = serde_json::from_str(&serde_json::to_string(&seq).unwrap()).unwrap();
     assert_eq!(seq, from_des);
+
+    let metadata = SequenceMetadata {
+        source_file_hash: Some("abcdef".into()),
+        resolver: Some("1.1.1.1:853".parse().unwrap()),
+        ..SequenceMetadata::default()
+    };
+    let seq = Sequence::new(vec![Size(1), Gap(2), Size(1)], "id".into()).with_metadata(metadata);
+    let from_des: Sequence = serde_json::from_str(&serde_json::to_string(&seq).unwrap()).unwrap();
+    assert_eq!(seq, from_des);
+    assert_eq!(seq.metadata(), from_des.metadata());
+    assert!(!from_des.is_truncated());
+
+    let metadata = SequenceMetadata {
+        truncated_packets: 3,
+        ..SequenceMetadata::default()
+    };
+    let seq = Sequence::new(vec![Size(1)], "id".into()).with_metadata(metadata);
+    let from_des: Sequence = serde_json::from_str(&serde_json::to_string(&seq).unwrap()).unwrap();
+    assert_eq!(3, from_des.metadata().unwrap().truncated_packets);
+    assert!(from_des.is_truncated());
+}
+
+#[test]
+fn test_sequence_format_version() {
+    use crate::format_version::from_json_any_version;
+    use SequenceElement::*;
+
+    let v1 = r#"{"id": ["S01", "G02", "S01"]}"#;
+    assert!(serde_json::from_str::<Sequence>(v1).is_err());
+    let seq: Sequence = from_json_any_version(v1).unwrap();
+    assert_eq!(
+        Sequence::new(vec![Size(1), Gap(2), Size(1)], "id".into()),
+        seq
+    );
+    assert_eq!(None, seq.metadata());
+
+    let json = serde_json::to_string(&seq).unwrap();
+    assert_eq!(seq, from_json_any_version(&json).unwrap());
+
+    let v3 = r#"{"id": ["S01"], "__format_version__": 3}"#;
+    assert!(serde_json::from_str::<Sequence>(v3).is_err());
+    assert!(from_json_any_version::<Sequence>(v3).is_err());
+}
+
+#[test]
+fn test_sequence_vantage_point() {
+    use SequenceElement::*;
+
+    let seq = Sequence::new(
+        vec![Size(1)],
+        "/data/example.com/example.com-1-1.dnstap.xz".into(),
+    );
+    assert_eq!(DEFAULT_VANTAGE_POINT, seq.vantage_point());
+    let seq = Sequence::new(
+        vec![Size(1)],
+        "/data/example.com/example.com-1-1@frankfurt.dnstap.xz".into(),
+    );
+    assert_eq!("frankfurt", seq.vantage_point());
+
+    // The metadata takes precedence over the identifier
+    let metadata = SequenceMetadata {
+        vantage_point: Some("tokyo".into()),
+        ..SequenceMetadata::default()
+    };
+    let seq = seq.with_metadata(metadata);
+    assert_eq!("tokyo", seq.vantage_point());
+}
+
+#[test]
+fn test_sequence_windows() {
+    use SequenceElement::*;
+
+    let seq = Sequence::new(
+        vec![Size(1), Gap(2), Size(1), Size(2), Size(1)],
+        "id".into(),
+    );
+    let windows = seq.windows(3, 2);
+    assert_eq!(2, windows.len());
+    assert_eq!(&[Size(1), Gap(2), Size(1)], windows[0].as_elements());
+    assert_eq!("id#0..3", windows[0].id());
+    assert_eq!(&[Size(1), Size(2), Size(1)], windows[1].as_elements());
+    assert_eq!("id#2..5", windows[1].id());
+
+    // Sequences shorter than the window are returned as a whole
+    let windows = seq.windows(10, 1);
+    assert_eq!(1, windows.len());
+    assert_eq!(seq.as_elements(), windows[0].as_elements());
+
+    let prefix = seq.prefix(2);
+    assert_eq!(&[Size(1), Gap(2)], prefix.as_elements());
+    assert_eq!("id#0..2", prefix.id());
+    assert_eq!(seq.as_elements(), seq.prefix(10).as_elements());
 }
 
 #[cfg(test)]
@@ -515,6 +860,26 @@ mod test_edit_dist {
         )
     }
 
+    #[test]
+    fn test_dtw_and_cumul_distance() {
+        let seq1 = Sequence::new(vec![Size(1), Gap(2), Size(1), Size(2), Size(1)], "".into());
+        let seq2 = Sequence::new(
+            vec![Size(1), Gap(2), Size(1), Size(1), Size(2), Size(1)],
+            "".into(),
+        );
+        assert_eq!(0, seq1.dtw_distance(&seq1));
+        // The repeated element can be warped without additional cost
+        assert_eq!(0, seq1.dtw_distance(&seq2));
+        assert_eq!(
+            7,
+            seq1.dtw_distance(&Sequence::new(vec![Size(2)], "".into()))
+        );
+
+        assert!(seq1.cumul_distance(&seq1) < f64::EPSILON);
+        assert!(seq1.cumul_distance(&seq2) > 0.);
+        assert_eq!(vec![0., 2., 5.], seq1.to_cumul_features(3));
+    }
+
     #[test]
     fn test_edit_distance_equal() {
         let seq1 = Sequence::new(vec![], "".into());
@@ -526,4 +891,60 @@ mod test_edit_dist {
         let seq4 = Sequence::new(vec![Size(1), Gap(2), Size(1), Size(2), Size(1)], "".into());
         assert_eq!(0, seq3.distance(&seq4));
     }
+
+    #[test]
+    fn test_edit_distance_early_exit() {
+        let seq1 = Sequence::new(vec![Size(1), Gap(2), Size(1), Size(2), Size(1)], "".into());
+        let others = [
+            // swapping
+            Sequence::new(vec![Size(1), Gap(2), Size(2), Size(1), Size(1)], "".into()),
+            // insertion
+            Sequence::new(
+                vec![Size(1), Size(2), Gap(2), Size(1), Size(2), Size(1)],
+                "".into(),
+            ),
+            Sequence::new(vec![Gap(10), Size(2), Gap(10), Size(2)], "".into()),
+            Sequence::new(vec![], "".into()),
+        ];
+
+        for other in &others {
+            let exact = seq1.distance(other);
+            for max_distance in 0..exact + 5 {
+                let (distance, ()) = seq1.distance_with_limit(other, max_distance, false, false);
+                if exact <= max_distance {
+                    assert_eq!(exact, distance, "Distance below the limit must be exact");
+                } else {
+                    // The calculation may or may not be abandoned, but never reports a too small value
+                    assert!(distance == exact || distance == usize::max_value());
+                }
+            }
+        }
+
+        // Completely different sequences are abandoned early
+        let (distance, ()) = seq1.distance_with_limit(&others[2], 0, false, false);
+        assert_eq!(usize::max_value(), distance);
+    }
+}
+
+#[test]
+fn test_distance_cost_profile() {
+    use distance_cost_info::CostTracker;
+    use SequenceElement::*;
+
+    let a = Sequence::new(vec![Size(1), Gap(2), Size(1), Size(2)], "a".into());
+    let b = Sequence::new(vec![Size(1), Gap(5), Size(1)], "b".into());
+    let (distance, tracker) =
+        a.distance_with_limit::<CostTracker>(&b, usize::max_value(), false, false);
+    assert_eq!(distance, a.distance(&b));
+    let profile = tracker.cumulative_position_costs();
+    assert_eq!(profile.last(), Some(&distance));
+    assert!(profile.windows(2).all(|w| w[0] <= w[1]));
+    assert_eq!(tracker.position_costs().iter().sum::<usize>(), distance);
+
+    // Against an empty sequence every element is one step
+    let empty = Sequence::new(vec![], "empty".into());
+    let (distance, tracker) =
+        a.distance_with_limit::<CostTracker>(&empty, usize::max_value(), false, false);
+    assert_eq!(tracker.position_costs().len(), a.len());
+    assert_eq!(tracker.cumulative_position_costs().last(), Some(&distance));
 }
@@ -0,0 +1,178 @@
+//! Run-length encoded representation of a [`Sequence`]
+//!
+//! Padded traces often contain long runs of identical [`SequenceElement`]s, e.g., many `Size(1)` messages directly after each other.
+//! The [`RunLengthSequence`] stores each run only once together with its length, and [`RunLengthSequence::distance`] operates directly on the runs.
+
+use super::{Sequence, SequenceElement};
+use serde::{Deserialize, Serialize};
+use std::mem;
+
+/// A [`Sequence`] where consecutive identical [`SequenceElement`]s are merged into runs
+#[derive(Clone, Eq, PartialEq, Hash, Debug, Default, Serialize, Deserialize)]
+pub struct RunLengthSequence {
+    /// Pairs of element and the number of repetitions, which is always larger than 0
+    runs: Vec<(SequenceElement, u32)>,
+}
+
+impl RunLengthSequence {
+    /// Return the runs as pairs of element and number of repetitions
+    pub fn runs(&self) -> &[(SequenceElement, u32)] {
+        &self.runs
+    }
+
+    /// Return the number of [`SequenceElement`]s, i.e., the length of the uncompressed [`Sequence`]
+    pub fn len(&self) -> usize {
+        self.runs.iter().map(|&(_, count)| count as usize).sum()
+    }
+
+    /// Returns `true` if there are no [`SequenceElement`]s
+    pub fn is_empty(&self) -> bool {
+        self.runs.is_empty()
+    }
+
+    /// Expand the runs into the individual [`SequenceElement`]s
+    pub fn to_elements(&self) -> Vec<SequenceElement> {
+        self.runs
+            .iter()
+            .flat_map(|&(elem, count)| std::iter::repeat(elem).take(count as usize))
+            .collect()
+    }
+
+    /// Return the distance to the `other` [`RunLengthSequence`]
+    ///
+    /// This is an edit distance, where the edit operations are applied to whole runs instead of single elements.
+    /// Inserting or deleting a run costs as much as inserting or deleting all its elements.
+    /// Substituting a run with another substitutes the elements pairwise and inserts or deletes the remaining elements of the longer run.
+    /// The costs of the element operations are the same as for [`Sequence::distance`].
+    ///
+    /// The runtime is quadratic in the number of runs instead of the number of elements.
+    /// Since every edit of runs can be expressed by edits of the elements, the result is never smaller than the distance of [`Sequence::distance`] without swapping.
+    /// It is larger, if the best alignment splits a run.
+    pub fn distance(&self, other: &Self) -> usize {
+        let mut larger = &*self.runs;
+        let mut smaller = &*other.runs;
+        if larger.len() < smaller.len() {
+            mem::swap(&mut larger, &mut smaller);
+        }
+
+        let run_cost = |(elem, count): (SequenceElement, u32)| count as usize * elem.insert_cost();
+
+        let mut previous_row: Vec<usize> = Some(0)
+            .into_iter()
+            .chain(smaller.iter().scan(0, |cost, &run| {
+                *cost += run_cost(run);
+                Some(*cost)
+            }))
+            .collect();
+        let mut current_row: Vec<usize> = Vec::with_capacity(previous_row.len());
+
+        for &run1 in larger {
+            current_row.clear();
+            current_row.push(previous_row[0] + run_cost(run1));
+
+            for (j, &run2) in smaller.iter().enumerate() {
+                let insertions = previous_row[j + 1] + run_cost(run1);
+                let deletions = current_row[j] + run_cost(run2);
+                let substitutions = previous_row[j] + substitute_runs(run1, run2);
+                current_row.push(insertions.min(deletions).min(substitutions));
+            }
+
+            mem::swap(&mut previous_row, &mut current_row);
+        }
+
+        *previous_row
+            .last()
+            .expect("The rows are never empty, thus there is a last.")
+    }
+}
+
+/// Cost to transform the run `a` into the run `b`
+fn substitute_runs(
+    (elem_a, count_a): (SequenceElement, u32),
+    (elem_b, count_b): (SequenceElement, u32),
+) -> usize {
+    let common = count_a.min(count_b) as usize;
+    let remainder = if count_a > count_b {
+        (count_a - count_b) as usize * elem_a.delete_cost()
+    } else {
+        (count_b - count_a) as usize * elem_b.insert_cost()
+    };
+    common * elem_a.substitute_cost(elem_b) + remainder
+}
+
+impl From<&[SequenceElement]> for RunLengthSequence {
+    fn from(elements: &[SequenceElement]) -> Self {
+        let mut runs: Vec<(SequenceElement, u32)> = Vec::new();
+        for &elem in elements {
+            match runs.last_mut() {
+                Some((last, count)) if *last == elem => *count += 1,
+                _ => runs.push((elem, 1)),
+            }
+        }
+        Self { runs }
+    }
+}
+
+impl From<&Sequence> for RunLengthSequence {
+    fn from(sequence: &Sequence) -> Self {
+        sequence.as_elements().into()
+    }
+}
+
+#[test]
+fn test_run_length_encoding() {
+    use SequenceElement::{Gap, Size};
+
+    let elements = vec![Size(1), Size(1), Size(1), Gap(2), Size(1), Size(2), Size(2)];
+    let rle = RunLengthSequence::from(&*elements);
+    assert_eq!(
+        &[(Size(1), 3), (Gap(2), 1), (Size(1), 1), (Size(2), 2)],
+        rle.runs()
+    );
+    assert_eq!(7, rle.len());
+    assert_eq!(elements, rle.to_elements());
+    assert!(RunLengthSequence::from(&[][..]).is_empty());
+}
+
+#[test]
+fn test_run_length_distance() {
+    use SequenceElement::{Gap, Size};
+
+    let check = |a: Vec<SequenceElement>, b: Vec<SequenceElement>| {
+        let rle_distance = RunLengthSequence::from(&*a).distance(&RunLengthSequence::from(&*b));
+        let distance = Sequence::new(a, "".into()).distance(&Sequence::new(b, "".into()));
+        (rle_distance, distance)
+    };
+
+    // Identical sequences
+    let (rle, full) = check(vec![Size(1); 20], vec![Size(1); 20]);
+    assert_eq!((0, 0), (rle, full));
+
+    // Runs of different lengths only need insertions
+    let (rle, full) = check(
+        vec![Size(1), Size(1), Size(1), Gap(2), Size(2)],
+        vec![Size(1), Gap(2), Size(2)],
+    );
+    assert_eq!(full, rle);
+    let (rle, full) = check(vec![Size(1); 5], vec![]);
+    assert_eq!(full, rle);
+
+    // The best alignment splits the run of `Size(1)`, which the run-length distance cannot do
+    let (rle, full) = check(
+        vec![Size(1), Size(1), Size(1), Gap(2), Size(2)],
+        vec![Size(1), Gap(2), Size(2), Size(2)],
+    );
+    assert!(rle > full);
+
+    // Substitutions of whole runs
+    let (rle, full) = check(
+        vec![Size(1), Size(1), Gap(3), Size(2)],
+        vec![Size(2), Size(2), Gap(1), Size(2)],
+    );
+    assert_eq!(full, rle);
+
+    // The distance is symmetric
+    let a = RunLengthSequence::from(&[Size(1), Size(1), Gap(4), Size(3), Size(3)][..]);
+    let b = RunLengthSequence::from(&[Size(2), Gap(1), Size(3)][..]);
+    assert_eq!(a.distance(&b), b.distance(&a));
+}
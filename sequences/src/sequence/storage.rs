@@ -0,0 +1,87 @@
+//! Deduplicated storage for the [`SequenceElement`]s of a [`Sequence`](super::Sequence)
+//!
+//! All element buffers are interned, such that identical sequences are only stored once.
+//! Sequences derived from another one, like prefixes and windows, reference a range of the original buffer instead of interning a copy.
+//! Interned buffers are never freed, so copying each prefix would keep all of them alive for the remaining runtime.
+
+use super::SequenceElement;
+use internment::Intern;
+use std::{convert::TryFrom, fmt, ops::Range};
+
+/// A range of an interned buffer of [`SequenceElement`]s
+///
+/// Equality and hashing are based on the identity of the buffer and the range, which makes them cheap.
+/// Two storages with identical elements are not necessarily equal, if one of them is derived from a larger buffer.
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub(crate) struct SequenceStorage {
+    buffer: Intern<Vec<SequenceElement>>,
+    start: u32,
+    len: u32,
+}
+
+impl SequenceStorage {
+    /// Intern the `elements`, reusing the buffer of identical elements if they exist already
+    pub(crate) fn new(elements: Vec<SequenceElement>) -> Self {
+        let len = to_u32(elements.len());
+        Self {
+            buffer: Intern::new(elements),
+            start: 0,
+            len,
+        }
+    }
+
+    /// Return a storage for the `range` of this one without copying the elements
+    ///
+    /// # Panics
+    ///
+    /// If the `range` is out of bounds.
+    pub(crate) fn slice(&self, range: Range<usize>) -> Self {
+        assert!(
+            range.start <= range.end && range.end <= self.len as usize,
+            "The range {:?} is out of bounds for a sequence of length {}",
+            range,
+            self.len
+        );
+        Self {
+            buffer: self.buffer,
+            start: self.start + to_u32(range.start),
+            len: to_u32(range.end - range.start),
+        }
+    }
+
+    pub(crate) fn as_elements(&self) -> &[SequenceElement] {
+        let start = self.start as usize;
+        &self.buffer.as_ref()[start..start + self.len as usize]
+    }
+}
+
+impl fmt::Debug for SequenceStorage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.as_elements()).finish()
+    }
+}
+
+fn to_u32(value: usize) -> u32 {
+    u32::try_from(value).expect("Sequences are limited to u32::MAX elements")
+}
+
+#[test]
+fn test_storage_slice() {
+    use SequenceElement::{Gap, Size};
+
+    let storage = SequenceStorage::new(vec![Size(1), Gap(2), Size(1), Size(2), Size(1)]);
+    let slice = storage.slice(1..4);
+    assert_eq!(&[Gap(2), Size(1), Size(2)], slice.as_elements());
+    assert_eq!(&[Size(1), Size(2)], slice.slice(1..3).as_elements());
+    assert_eq!(storage, storage.slice(0..5));
+    // The slice shares the buffer of the original storage
+    assert_eq!(
+        storage.as_elements()[1..].as_ptr(),
+        slice.as_elements().as_ptr()
+    );
+    // Identical elements are interned only once
+    assert_eq!(
+        storage,
+        SequenceStorage::new(vec![Size(1), Gap(2), Size(1), Size(2), Size(1)])
+    );
+}
@@ -1,11 +1,15 @@
-use crate::{knn::ClassifierData, LoadSequenceConfig, Sequence};
+use crate::{
+    conversion_cache::ConversionCache, knn::ClassifierData, FilterReason, FilteredSequences,
+    LoadSequenceConfig, Sequence,
+};
 use anyhow::{bail, Context as _, Error};
-use log::{debug, warn};
+use log::{debug, info, warn};
 use misc_utils::path::PathExt;
 use rayon::prelude::*;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use std::{
     cmp,
+    convert::TryFrom,
     ffi::OsStr,
     fmt, fs,
     path::{Path, PathBuf},
@@ -17,8 +21,51 @@ pub fn load_all_files_with_extension_from_dir_with_config(
     file_extension: &OsStr,
     config: LoadSequenceConfig,
 ) -> Result<Vec<(String, Vec<Sequence>)>, Error> {
-    // Get a list of directories
-    // Each directory corresponds to a label
+    load_all_files_with_extension_from_dir_with_cache(base_dir, file_extension, config, None)
+}
+
+/// Same as [`load_all_files_with_extension_from_dir_with_config`], but load the files through the [`ConversionCache`], if any
+pub fn load_all_files_with_extension_from_dir_with_cache(
+    base_dir: &Path,
+    file_extension: &OsStr,
+    config: LoadSequenceConfig,
+    cache: Option<&ConversionCache>,
+) -> Result<Vec<(String, Vec<Sequence>)>, Error> {
+    let directories = sequence_directories(base_dir)?;
+
+    // Pairs of Label with Data (the Sequences)
+    let data: Vec<(Option<(String, Vec<Sequence>)>, FilteredSequences)> = directories
+        .into_par_iter()
+        .with_max_len(1)
+        .map(|dir| load_sequence_directory_filtered(&dir, file_extension, config, cache))
+        .collect::<Result<_, Error>>()?;
+
+    let mut filtered = FilteredSequences::default();
+    let data: Vec<(String, Vec<Sequence>)> = data
+        .into_iter()
+        .filter_map(|(data, dir_filtered)| {
+            filtered += dir_filtered;
+            // Remove all the empty directories from the previous step
+            data
+        })
+        .collect();
+    if config.has_filters() {
+        info!(
+            "Filtered {} sequences while loading '{}': {}",
+            filtered.total(),
+            base_dir.display(),
+            filtered
+        );
+    }
+
+    // return all loaded data
+    Ok(data)
+}
+
+/// Return the sorted list of directories in `base_dir`
+///
+/// Each directory corresponds to a label.
+pub fn sequence_directories(base_dir: &Path) -> Result<Vec<PathBuf>, Error> {
     let mut directories: Vec<PathBuf> = fs::read_dir(base_dir)?
         .flat_map(|x| {
             x.and_then(|entry| {
@@ -37,83 +84,171 @@ pub fn load_all_files_with_extension_from_dir_with_config(
         })
         .collect::<Result<_, _>>()?;
     directories.sort();
+    Ok(directories)
+}
 
-    // Pairs of Label with Data (the Sequences)
-    let data: Vec<(String, Vec<Sequence>)> = directories
-        .into_par_iter()
-        .with_max_len(1)
-        .map(|dir| {
-            let label = dir
-                .file_name()
-                .expect("Each directory has a name")
-                .to_string_lossy()
-                .into();
-
-            let mut filenames: Vec<PathBuf> = fs::read_dir(&dir)?
-                .flat_map(|x| {
-                    x.and_then(|entry| {
-                        // Result<Option<PathBuf>>
-                        entry.file_type().map(|ft| {
-                            if ft.is_file()
-                                && entry.path().extensions().any(|ext| ext == file_extension)
-                            {
-                                Some(entry.path())
-                            } else {
-                                None
-                            }
-                        })
-                    })
-                    .transpose()
+/// Load all files with the `file_extension` in `dir` as [`Sequence`]s
+///
+/// The label is the name of the directory.
+/// The identifiers of the [`Sequence`]s are relative to the parent of `dir`, which is the root of the dataset, see [`normalize_sequence_id`].
+/// The [`Sequence`]s are filtered according to [`LoadSequenceConfig::check_filters`] before applying the simulated countermeasure.
+/// Returns [`None`] if the directory does not contain any loadable files.
+pub fn load_sequence_directory(
+    dir: &Path,
+    file_extension: &OsStr,
+    config: LoadSequenceConfig,
+) -> Result<Option<(String, Vec<Sequence>)>, Error> {
+    load_sequence_directory_with_cache(dir, file_extension, config, None)
+}
+
+/// Same as [`load_sequence_directory`], but load the files through the [`ConversionCache`], if any
+pub fn load_sequence_directory_with_cache(
+    dir: &Path,
+    file_extension: &OsStr,
+    config: LoadSequenceConfig,
+    cache: Option<&ConversionCache>,
+) -> Result<Option<(String, Vec<Sequence>)>, Error> {
+    let (data, filtered) = load_sequence_directory_filtered(dir, file_extension, config, cache)?;
+    if filtered.total() > 0 {
+        info!(
+            "Filtered {} sequences in '{}': {}",
+            filtered.total(),
+            dir.display(),
+            filtered
+        );
+    }
+    Ok(data)
+}
+
+/// Same as [`load_sequence_directory`], but return the number of filtered [`Sequence`]s instead of logging them
+fn load_sequence_directory_filtered(
+    dir: &Path,
+    file_extension: &OsStr,
+    config: LoadSequenceConfig,
+    cache: Option<&ConversionCache>,
+) -> Result<(Option<(String, Vec<Sequence>)>, FilteredSequences), Error> {
+    let root = dir.parent().unwrap_or(dir);
+    let label = dir
+        .file_name()
+        .expect("Each directory has a name")
+        .to_string_lossy()
+        .into();
+
+    let mut filenames: Vec<PathBuf> = fs::read_dir(dir)?
+        .flat_map(|x| {
+            x.and_then(|entry| {
+                // Result<Option<PathBuf>>
+                entry.file_type().map(|ft| {
+                    if ft.is_file() && entry.path().extensions().any(|ext| ext == file_extension) {
+                        Some(entry.path())
+                    } else {
+                        None
+                    }
                 })
-                .collect::<Result<_, _>>()?;
-            // sort filenames for predictable results
-            filenames.sort();
-
-            let sequences: Vec<Sequence> = filenames
-                .into_iter()
-                .filter_map(|file| {
-                    debug!("Processing {:?} file '{}'", file_extension, file.display());
-                    match Sequence::from_path_with_config(&file, config).with_context(|| {
-                        format!("Processing {:?} file '{}'", file_extension, file.display())
-                    }) {
-                        Ok(seq) => Some(seq),
-                        Err(err) => {
-                            warn!("{}", err);
-                            None
+            })
+            .transpose()
+        })
+        .collect::<Result<_, _>>()?;
+    // sort filenames for predictable results
+    filenames.sort();
+
+    let mut filtered = FilteredSequences::default();
+    let sequences: Vec<Sequence> = filenames
+        .into_iter()
+        .filter_map(|file| {
+            debug!("Processing {:?} file '{}'", file_extension, file.display());
+            let seq = match cache {
+                Some(cache) => cache.load(&file, config),
+                None => Sequence::from_path_with_config(&file, config),
+            };
+            match seq.with_context(|| {
+                format!("Processing {:?} file '{}'", file_extension, file.display())
+            }) {
+                Ok(seq) => {
+                    let id = normalize_sequence_id(seq.id(), root);
+                    Some(seq.with_id(id))
+                }
+                Err(err) => {
+                    // Filtered sequences are expected and only counted
+                    match err.downcast_ref::<FilterReason>() {
+                        Some(&reason) => {
+                            debug!("{:#}", err);
+                            filtered.add(reason);
                         }
+                        None => warn!("{}", err),
                     }
-                })
-                .collect();
-
-            // Some directories do not contain data, e.g., because the site didn't exists
-            // Skip all directories with 0 results
-            if sequences.is_empty() {
-                warn!("Directory contains no data: {}", dir.display());
-                Ok(None)
-            } else {
-                Ok(Some((label, sequences)))
+                    None
+                }
             }
         })
-        // Remove all the empty directories from the previous step
-        .filter_map(Result::transpose)
-        .collect::<Result<_, Error>>()?;
+        .collect();
 
-    // return all loaded data
-    Ok(data)
+    if sequences.is_empty() && filtered.total() > 0 {
+        debug!(
+            "All sequences of the directory are filtered: {}",
+            dir.display()
+        );
+        Ok((None, filtered))
+    } else if sequences.is_empty() {
+        // Some directories do not contain data, e.g., because the site didn't exists
+        // Skip all directories with 0 results
+        warn!("Directory contains no data: {}", dir.display());
+        Ok((None, filtered))
+    } else {
+        Ok((Some((label, sequences)), filtered))
+    }
+}
+
+/// Express the identifier of a [`Sequence`] relative to the dataset `root`
+///
+/// The identifiers of loaded [`Sequence`]s are the paths of their source files.
+/// Absolute paths break when the dataset moves and leak local paths into shared results.
+/// The normalized identifier always uses `/` as separator, e.g., `example.com/example.com-1-1.dnstap.xz`.
+/// Identifiers, which are not below `root`, are returned unchanged.
+///
+/// [`sequence_id_to_path`] maps a normalized identifier back to the file.
+pub fn normalize_sequence_id(id: &str, root: &Path) -> String {
+    match Path::new(id).strip_prefix(root) {
+        Ok(relative) => relative
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/"),
+        Err(_) => id.to_string(),
+    }
+}
+
+/// Return the path of the file from which the [`Sequence`] with the normalized `id` was loaded
+///
+/// This is the inverse of [`normalize_sequence_id`] for the same `root`.
+/// Identifiers, which are still absolute paths, are returned unchanged.
+pub fn sequence_id_to_path(id: &str, root: &Path) -> PathBuf {
+    if Path::new(id).is_absolute() {
+        return PathBuf::from(id);
+    }
+    let mut path = root.to_path_buf();
+    path.extend(id.split('/'));
+    path
 }
 
-/// Take the `n` smallest elements from `iter`
+/// Take the `n` smallest elements computed by `f` for each candidate in `iter`
+///
+/// `f` receives the candidate and the largest distance which can still be part of the result.
+/// This allows `f` to abandon the calculation early, e.g., by passing the bound to [`Sequence::distance_with_limit`](crate::Sequence::distance_with_limit).
+/// The bound is `usize::max_value()` until `n` elements are found.
+/// If `f` returns [`None`] the candidate is skipped.
 ///
 /// It is unspecified which `n` smallest elements are being returned.
-pub(crate) fn take_smallest<'a, I, S>(iter: I, n: usize) -> Vec<ClassifierData<'a, S>>
+pub(crate) fn take_smallest<'a, I, S, F>(iter: I, n: usize, mut f: F) -> Vec<ClassifierData<'a, S>>
 where
-    I: IntoIterator<Item = ClassifierData<'a, S>>,
+    I: IntoIterator,
     S: ?Sized,
+    F: FnMut(I::Item, usize) -> Option<ClassifierData<'a, S>>,
 {
     let mut iter = iter.into_iter();
     if n == 1 {
         // get a first element to make the rest of the code simpler
-        let best = iter.next();
+        let best = (&mut iter).find_map(|elem| f(elem, usize::max_value()));
         // iter is empty
         if best.is_none() {
             return vec![];
@@ -125,6 +260,10 @@ where
         }
 
         for elem in iter {
+            let elem = match f(elem, best.distance) {
+                Some(elem) => elem,
+                None => continue,
+            };
             if elem < best {
                 // found a better element, so replace the current best
                 best = elem;
@@ -141,7 +280,11 @@ where
 
     let mut res = Vec::with_capacity(n);
     // fill the vector with n elements
-    res.extend((&mut iter).take(n));
+    res.extend(
+        (&mut iter)
+            .filter_map(|elem| f(elem, usize::max_value()))
+            .take(n),
+    );
     res.sort();
 
     // the iterator is already exhausted, so we can stop early
@@ -151,8 +294,12 @@ where
     }
 
     // replace exisiting elements keeping the heap size
-    for v in iter {
+    for elem in iter {
         // compare with worst element so far
+        let v = match f(elem, res[n - 1].distance) {
+            Some(v) => v,
+            None => continue,
+        };
         if v < res[n - 1] {
             res[n - 1] = v;
             res.sort();
@@ -170,6 +317,7 @@ fn take_smallest_empty() {
             .into_iter()
             .map(|_: usize| -> ClassifierData<'static, str> { unimplemented!() }),
         1,
+        |elem, _| Some(elem),
     );
     assert!(res.is_empty());
 
@@ -178,15 +326,51 @@ fn take_smallest_empty() {
             .into_iter()
             .map(|_: usize| -> ClassifierData<'static, str> { unimplemented!() }),
         12,
+        |elem, _| Some(elem),
     );
     assert!(res.is_empty());
 }
 
+#[test]
+fn test_sequence_id_normalization() {
+    let root = Path::new("/data/dataset");
+    let id = normalize_sequence_id("/data/dataset/example.com/example.com-1-1.dnstap.xz", root);
+    assert_eq!("example.com/example.com-1-1.dnstap.xz", id);
+    assert_eq!(
+        Path::new("/data/dataset/example.com/example.com-1-1.dnstap.xz"),
+        sequence_id_to_path(&id, root)
+    );
+    // Normalizing twice does not change the identifier
+    assert_eq!(id, normalize_sequence_id(&id, root));
+
+    // Files outside of the dataset keep their path
+    let id = "/other/example.com-1-1.dnstap.xz";
+    assert_eq!(id, normalize_sequence_id(id, root));
+    assert_eq!(Path::new(id), sequence_id_to_path(id, root));
+}
+
 /// Represents an arbitraty propability value
-#[derive(Copy, Clone, PartialEq, Debug, Default, Serialize)]
+///
+/// The value is always finite and in the range `[0, 1]`.
+/// It is serialized as a float and can be deserialized from a float or from any string accepted by [`FromStr`].
+#[derive(Copy, Clone, PartialEq, Debug, Default, Serialize, Deserialize)]
+#[serde(try_from = "ProbabilityRepr")]
 pub struct Probability(f32);
 
+/// Accepted representations while deserializing a [`Probability`]
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ProbabilityRepr {
+    Float(f32),
+    String(String),
+}
+
 impl Probability {
+    /// The impossible event
+    pub const ZERO: Self = Probability(0.);
+    /// The certain event
+    pub const ONE: Self = Probability(1.);
+
     /// Create a new probability value
     ///
     /// Returns an Error if the value is negative, larger than 1, or NaN.
@@ -204,6 +388,53 @@ impl Probability {
     pub fn to_float(self) -> f32 {
         self.0
     }
+
+    /// Probability that the event does not happen
+    pub fn complement(self) -> Self {
+        Probability(1. - self.0)
+    }
+
+    /// Probability that both independent events happen
+    pub fn and(self, other: Self) -> Self {
+        Probability(self.0 * other.0)
+    }
+
+    /// Probability that at least one of both independent events happens
+    pub fn or(self, other: Self) -> Self {
+        // Clamp, since rounding might end up slightly above 1
+        Probability((self.0 + other.0 - self.0 * other.0).min(1.))
+    }
+}
+
+impl TryFrom<f32> for Probability {
+    type Error = Error;
+
+    fn try_from(pb: f32) -> Result<Self, Error> {
+        Self::new(pb)
+    }
+}
+
+impl TryFrom<ProbabilityRepr> for Probability {
+    type Error = Error;
+
+    fn try_from(repr: ProbabilityRepr) -> Result<Self, Error> {
+        match repr {
+            ProbabilityRepr::Float(pb) => Self::new(pb),
+            ProbabilityRepr::String(s) => s.parse(),
+        }
+    }
+}
+
+impl From<Probability> for f32 {
+    fn from(pb: Probability) -> Self {
+        pb.0
+    }
+}
+
+impl From<Probability> for f64 {
+    fn from(pb: Probability) -> Self {
+        f64::from(pb.0)
+    }
 }
 
 // Implementing `Eq` is fine, as the internal float cannot be `NaN` or infinite.
@@ -221,11 +452,22 @@ impl PartialOrd for Probability {
     }
 }
 
+/// Parse a probability either as a float, e.g., `0.25`, or as a percentage, e.g., `25%`
 impl FromStr for Probability {
     type Err = Error;
 
     fn from_str(s: &str) -> Result<Self, Error> {
-        Self::new(f32::from_str(s)?)
+        let s = s.trim();
+        match s.strip_suffix('%') {
+            Some(percent) => {
+                let percent = f32::from_str(percent.trim_end())
+                    .with_context(|| format!("Invalid percentage '{}'", s))?;
+                Self::new(percent / 100.)
+            }
+            None => {
+                Self::new(f32::from_str(s).with_context(|| format!("Invalid probability '{}'", s))?)
+            }
+        }
     }
 }
 
@@ -234,3 +476,27 @@ impl fmt::Display for Probability {
         self.0.fmt(f)
     }
 }
+
+#[test]
+fn test_probability() {
+    let half: Probability = "50%".parse().unwrap();
+    assert_eq!(Probability::new(0.5).unwrap(), half);
+    assert_eq!(Probability::new(0.25).unwrap(), " 0.25 ".parse().unwrap());
+    assert!("".parse::<Probability>().is_err());
+    assert!("101%".parse::<Probability>().is_err());
+    assert!("-0.1".parse::<Probability>().is_err());
+    assert!("NaN".parse::<Probability>().is_err());
+    assert!(Probability::new(f32::NAN).is_err());
+    assert!(Probability::try_from(1.5f32).is_err());
+
+    assert_eq!(Probability::ONE, Probability::ZERO.complement());
+    assert_eq!(0.25, half.and(half).to_float());
+    assert_eq!(0.75, half.or(half).to_float());
+    assert_eq!(Probability::ONE, Probability::ONE.or(Probability::ONE));
+
+    assert_eq!("0.5", serde_json::to_string(&half).unwrap());
+    assert_eq!(half, serde_json::from_str("0.5").unwrap());
+    assert_eq!(half, serde_json::from_str(r#""50%""#).unwrap());
+    assert!(serde_json::from_str::<Probability>("2").is_err());
+    assert!(serde_json::from_str::<Probability>(r#""-5%""#).is_err());
+}
@@ -0,0 +1,98 @@
+//! Decrypt a captured DNS over TLS connection with the secrets from its key log file
+#![cfg(feature = "read_pcap")]
+
+use pretty_assertions::assert_eq;
+use sequences::pcap::decrypt::{check_tls_keys, decrypt_tls_flows, DecryptionStatus, KeyLog};
+use std::path::Path;
+
+/// Pcap file with a single DNS over TLS connection using TLS 1.3
+const PCAP: &str = "./tests/data/google.com-0-0.pcap";
+/// Key log file with the secrets of the connection in [`PCAP`]
+const KEYLOG: &str = "./tests/data/google.com-0-0.tlskeys.txt.xz";
+
+/// Read the key log and replace the secret with `label` using `f`
+fn modified_keylog(label: &str, f: impl Fn(&str) -> Option<String>) -> KeyLog {
+    let content = misc_utils::fs::read_to_string(KEYLOG).unwrap();
+    content
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            if fields.next() != Some(label) {
+                return Some(line.to_string());
+            }
+            let random = fields.next().unwrap();
+            f(fields.next().unwrap()).map(|secret| format!("{} {} {}", label, random, secret))
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+        .parse()
+        .unwrap()
+}
+
+fn decryption_status(keylog: &KeyLog) -> Vec<DecryptionStatus> {
+    decrypt_tls_flows(PCAP.as_ref(), keylog)
+        .unwrap()
+        .into_iter()
+        .filter(|flow| flow.server.port() == 853)
+        .map(|flow| flow.status)
+        .collect()
+}
+
+#[test]
+fn test_decrypt_dns_over_tls() {
+    let keylog = KeyLog::from_path(KEYLOG.as_ref()).unwrap();
+    let flows: Vec<_> = decrypt_tls_flows(PCAP.as_ref(), &keylog)
+        .unwrap()
+        .into_iter()
+        .filter(|flow| flow.server.port() == 853)
+        .collect();
+    assert_eq!(flows.len(), 1);
+    assert_eq!(flows[0].status, DecryptionStatus::Decrypted);
+
+    // The page load is enclosed by the marker queries, which are both answered
+    let messages = flows[0].dns_messages();
+    let qnames: Vec<String> = messages
+        .iter()
+        .filter(|msg| !msg.from_client)
+        .map(|msg| {
+            trust_dns_proto::op::Message::from_vec(&msg.message)
+                .unwrap()
+                .queries()[0]
+                .name()
+                .to_ascii()
+        })
+        .collect();
+    assert!(qnames.iter().any(|qname| qname == "aaa.aaa.aaa.aaa."));
+    assert!(qnames.iter().any(|qname| qname == "zzz.zzz.zzz.zzz."));
+    // The encrypted sequence has 10 responses between the markers
+    assert!(qnames.len() >= 12, "{:?}", qnames);
+
+    let check = check_tls_keys(PCAP.as_ref(), Path::new(KEYLOG), None).unwrap();
+    assert!(check.keys_match(), "{:?}", check);
+    assert_eq!(check.connections, 1);
+    assert_eq!(check.decrypted, 1);
+    assert_eq!(check.dns_messages, messages.len());
+    assert_eq!(check.invalid_dns_messages, 0);
+}
+
+#[test]
+fn test_decrypt_dns_over_tls_wrong_keys() {
+    let keylog = modified_keylog("SERVER_HANDSHAKE_TRAFFIC_SECRET", |secret| {
+        Some("00".repeat(secret.len() / 2))
+    });
+    assert_eq!(
+        decryption_status(&keylog),
+        vec![DecryptionStatus::WrongSecrets]
+    );
+
+    let keylog = modified_keylog("CLIENT_TRAFFIC_SECRET_0", |_| None);
+    assert_eq!(
+        decryption_status(&keylog),
+        vec![DecryptionStatus::MissingSecrets]
+    );
+
+    assert_eq!(
+        decryption_status(&KeyLog::default()),
+        vec![DecryptionStatus::MissingSecrets]
+    );
+}
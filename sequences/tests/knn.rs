@@ -0,0 +1,538 @@
+use sequences::{
+    knn::{
+        self, registrable_domain, ClassBalance, ClassificationResultQuality, ConfusionSetMatch,
+        DistanceMetric, ExactMatch, LabelledSequences, QualityMetric, RegistrableDomainMatch, TopK,
+        VoteWeighting, WindowSpec,
+    },
+    Sequence, SequenceElement,
+};
+
+fn seq(elements: Vec<SequenceElement>, id: &str) -> Sequence {
+    Sequence::new(elements, id.to_string())
+}
+
+#[test]
+fn test_window_spec_from_str() {
+    assert_eq!(
+        WindowSpec { size: 4, stride: 4 },
+        "4".parse::<WindowSpec>().unwrap()
+    );
+    assert_eq!(
+        WindowSpec { size: 4, stride: 2 },
+        "4:2".parse::<WindowSpec>().unwrap()
+    );
+    let window = WindowSpec { size: 5, stride: 1 };
+    assert_eq!(window, window.to_string().parse().unwrap());
+    assert!("0".parse::<WindowSpec>().is_err());
+    assert!("4:0".parse::<WindowSpec>().is_err());
+    assert!("4:x".parse::<WindowSpec>().is_err());
+}
+
+#[test]
+fn test_knn_windows() {
+    use SequenceElement::Size;
+
+    let training_data = vec![
+        LabelledSequences {
+            true_domain: "a.example",
+            mapped_domain: "a.example",
+            sequences: vec![seq(vec![Size(1), Size(1), Size(1), Size(1)], "a")],
+        },
+        LabelledSequences {
+            true_domain: "b.example",
+            mapped_domain: "b.example",
+            sequences: vec![seq(vec![Size(2), Size(2), Size(3), Size(3)], "b")],
+        },
+    ];
+    // The first window only matches `a.example`, the later ones only `b.example`
+    let test_data = vec![seq(
+        vec![Size(1), Size(1), Size(3), Size(3), Size(3), Size(3)],
+        "test",
+    )];
+
+    let results = knn::knn_windows(
+        &training_data,
+        &test_data,
+        1,
+        WindowSpec { size: 2, stride: 2 },
+        false,
+    );
+    assert_eq!(1, results.len());
+    let result = &results[0];
+    assert_eq!(3, result.windows.len());
+    assert_eq!(Some("a.example"), result.windows[0].best_label());
+    assert_eq!(Some("b.example"), result.windows[1].best_label());
+    assert_eq!(Some("b.example"), result.windows[2].best_label());
+    assert_eq!(Some("b.example"), result.aggregated.best_label());
+    assert_eq!(
+        Some(3),
+        result.windows_until_classified("b.example", ClassificationResultQuality::Plurality)
+    );
+    // The early decision for `a.example` does not last
+    assert_eq!(
+        None,
+        result.windows_until_classified("a.example", ClassificationResultQuality::Plurality)
+    );
+}
+
+#[test]
+fn test_knn_confidence() {
+    use SequenceElement::Size;
+
+    let elements = vec![Size(1), Size(1), Size(2), Size(2)];
+    let training_data = vec![
+        LabelledSequences {
+            true_domain: "a.example",
+            mapped_domain: "a.example",
+            sequences: vec![seq(elements.clone(), "a1"), seq(elements.clone(), "a2")],
+        },
+        LabelledSequences {
+            true_domain: "b.example",
+            mapped_domain: "b.example",
+            sequences: vec![seq(elements.clone(), "b")],
+        },
+    ];
+
+    // All neighbors match exactly, so only the vote share counts
+    let mut result = knn::knn(&training_data, &[seq(elements, "test")], 3, false).remove(0);
+    assert!((result.confidence() - 2. / 3.).abs() < 1e-12);
+    result.reject_below(0.5);
+    assert!(!result.is_unclassified());
+    assert_eq!(Some("a.example"), result.best_label());
+    assert_eq!(
+        ClassificationResultQuality::Majority,
+        result.determine_quality("a.example")
+    );
+
+    result.reject_below(0.9);
+    assert!(result.is_unclassified());
+    assert_eq!(None, result.best_label());
+    assert_eq!(Some("a.example"), result.top_label());
+    assert_eq!(
+        ClassificationResultQuality::Unclassified,
+        result.determine_quality("a.example")
+    );
+
+    // A distant neighbor lowers the confidence, even if it is the only one
+    let result = knn::knn(
+        &training_data,
+        &[seq(vec![Size(1), Size(3), Size(3), Size(3)], "test")],
+        1,
+        false,
+    )
+    .remove(0);
+    assert!(result.confidence() < 1.);
+
+    // Without any neighbors there is nothing to reject
+    let mut result = knn::knn::<&str>(&[], &[seq(vec![Size(1)], "test")], 1, false).remove(0);
+    assert_eq!(0., result.confidence());
+    result.reject_below(0.5);
+    assert!(!result.is_unclassified());
+    assert_eq!(
+        ClassificationResultQuality::NoResult,
+        result.determine_quality("a.example")
+    );
+}
+
+#[test]
+fn test_knn_from_neighbors_matches_threshold() {
+    use SequenceElement::Size;
+
+    let sizes = |sizes: &[(usize, u8)]| -> Vec<SequenceElement> {
+        sizes
+            .iter()
+            .flat_map(|&(count, size)| vec![Size(size); count])
+            .collect()
+    };
+    let labels = ["short.example", "long.example", "other.example"];
+    // The short sequences have a small absolute but a large normalized distance.
+    // They fill the stored neighbors, while the long sequences are within the threshold.
+    let training_data = vec![
+        LabelledSequences {
+            true_domain: labels[0],
+            mapped_domain: labels[0],
+            sequences: vec![seq(sizes(&[(2, 1)]), "s1"), seq(sizes(&[(3, 1)]), "s2")],
+        },
+        LabelledSequences {
+            true_domain: labels[1],
+            mapped_domain: labels[1],
+            sequences: vec![
+                seq(sizes(&[(8, 1), (7, 2)]), "l1"),
+                seq(sizes(&[(8, 1), (9, 2)]), "l2"),
+            ],
+        },
+        LabelledSequences {
+            true_domain: labels[2],
+            mapped_domain: labels[2],
+            sequences: vec![seq(sizes(&[(12, 3)]), "o")],
+        },
+    ];
+    let test_data = vec![
+        seq(sizes(&[(8, 1)]), "t1"),
+        seq(sizes(&[(6, 1), (2, 2)]), "t2"),
+        seq(sizes(&[(4, 3)]), "t3"),
+    ];
+
+    for k in 1..=3 {
+        // The neighbor cache stores as many neighbors as the largest k
+        let neighbors = knn::nearest_neighbors(
+            &training_data,
+            &test_data,
+            k,
+            DistanceMetric::EditDistance,
+            false,
+        );
+        for &threshold in &[0.1, 0.3, 0.5, 0.8, 1.0] {
+            let from_neighbors = knn::knn_from_neighbors(
+                &training_data,
+                &test_data,
+                &neighbors,
+                k as u8,
+                Some(threshold),
+                VoteWeighting::Uniform,
+                false,
+            );
+            let direct = knn::knn_with_threshold(
+                &training_data,
+                &test_data,
+                k as u8,
+                threshold,
+                VoteWeighting::Uniform,
+                false,
+            );
+            for (cached, direct) in from_neighbors.iter().zip(&direct) {
+                let msg = format!("k={} threshold={}", k, threshold);
+                assert_eq!(cached.best_label(), direct.best_label(), "{}", msg);
+                assert_eq!(cached.confidence(), direct.confidence(), "{}", msg);
+                for label in &labels {
+                    assert_eq!(
+                        cached.determine_quality(label),
+                        direct.determine_quality(label),
+                        "{} label={}",
+                        msg,
+                        label
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_vote_weighting_parse() {
+    for weighting in &[
+        VoteWeighting::Uniform,
+        VoteWeighting::InverseDistance,
+        VoteWeighting::Rank,
+    ] {
+        assert_eq!(*weighting, weighting.to_string().parse().unwrap());
+    }
+    assert_eq!(
+        VoteWeighting::InverseDistance,
+        "Inverse-Distance".parse().unwrap()
+    );
+    assert!("distance".parse::<VoteWeighting>().is_err());
+}
+
+#[test]
+fn test_vote_weighting() {
+    use SequenceElement::Size;
+
+    let test = seq(vec![Size(1); 4], "test");
+    // Two distant neighbors of `a.example` and one exact match of `b.example`
+    let distant = [vec![Size(1); 4], vec![Size(2); 4]].concat();
+    let training_data = vec![
+        LabelledSequences {
+            true_domain: "a.example",
+            mapped_domain: "a.example",
+            sequences: vec![seq(distant.clone(), "a1"), seq(distant, "a2")],
+        },
+        LabelledSequences {
+            true_domain: "b.example",
+            mapped_domain: "b.example",
+            sequences: vec![seq(vec![Size(1); 4], "b")],
+        },
+    ];
+    let classify = |weighting| {
+        knn::knn_with_metric(
+            &training_data,
+            &[test.clone()],
+            3,
+            DistanceMetric::EditDistance,
+            weighting,
+            false,
+        )
+        .remove(0)
+    };
+
+    // Two votes against one
+    let result = classify(VoteWeighting::Uniform);
+    assert_eq!(Some("a.example"), result.best_label());
+    assert_eq!(
+        ClassificationResultQuality::Majority,
+        result.determine_quality("a.example")
+    );
+
+    // The exact match has a full vote, each distant neighbor less than half a vote
+    let result = classify(VoteWeighting::InverseDistance);
+    assert_eq!(Some("b.example"), result.best_label());
+    assert_eq!(
+        ClassificationResultQuality::Majority,
+        result.determine_quality("b.example")
+    );
+    // The exact match is fully confident
+    assert!(result.confidence() > 0.5);
+
+    // The exact match has 3 votes, the equally distant neighbors share the second rank with 2 votes each
+    let result = classify(VoteWeighting::Rank);
+    assert_eq!(Some("a.example"), result.best_label());
+    assert_eq!(
+        ClassificationResultQuality::Majority,
+        result.determine_quality("a.example")
+    );
+}
+
+#[test]
+fn test_balance_classes() {
+    use SequenceElement::Size;
+
+    fn lseqs(
+        true_domain: &'static str,
+        mapped_domain: &'static str,
+        count: usize,
+    ) -> LabelledSequences<&'static str> {
+        LabelledSequences {
+            true_domain,
+            mapped_domain,
+            sequences: (0..count)
+                .map(|idx| seq(vec![Size(1)], &format!("{}-{:02}", true_domain, idx)))
+                .collect(),
+        }
+    }
+    fn class_sizes(data: &[LabelledSequences<&'static str>]) -> Vec<(&'static str, usize)> {
+        let mut sizes = std::collections::BTreeMap::new();
+        for lseqs in data {
+            *sizes.entry(lseqs.mapped_domain).or_insert(0) += lseqs.sequences.len();
+        }
+        sizes.into_iter().collect()
+    }
+    // `a.example` and `www.a.example` form a single class with 6 sequences
+    let data = vec![
+        lseqs("a.example", "a.example", 3),
+        lseqs("www.a.example", "a.example", 3),
+        lseqs("b.example", "b.example", 10),
+        lseqs("c.example", "c.example", 0),
+    ];
+
+    let mut balanced = data.clone();
+    assert_eq!(0, knn::balance_classes(&mut balanced, ClassBalance::None));
+    assert_eq!(data, balanced);
+
+    // The smallest non-empty class is the merged `a.example`
+    let mut balanced = data.clone();
+    assert_eq!(
+        4,
+        knn::balance_classes(&mut balanced, ClassBalance::Undersample)
+    );
+    assert_eq!(
+        vec![("a.example", 6), ("b.example", 6), ("c.example", 0)],
+        class_sizes(&balanced)
+    );
+    // The kept sequences keep their order
+    let ids: Vec<&str> = balanced[2].sequences.iter().map(|seq| seq.id()).collect();
+    let mut sorted = ids.clone();
+    sorted.sort_unstable();
+    assert_eq!(sorted, ids);
+    // The random selection is seeded
+    let mut again = data.clone();
+    knn::balance_classes(&mut again, ClassBalance::Undersample);
+    assert_eq!(balanced, again);
+
+    // The cap applies to the merged class and not to each directory
+    let mut balanced = data.clone();
+    assert_eq!(
+        12,
+        knn::balance_classes(&mut balanced, ClassBalance::Cap(2))
+    );
+    assert_eq!(
+        vec![("a.example", 2), ("b.example", 2), ("c.example", 0)],
+        class_sizes(&balanced)
+    );
+
+    assert_eq!(ClassBalance::Cap(5), "cap:5".parse().unwrap());
+    assert_eq!(ClassBalance::Undersample, "Undersample".parse().unwrap());
+    assert_eq!("cap:5", ClassBalance::Cap(5).to_string());
+    assert!("cap:x".parse::<ClassBalance>().is_err());
+}
+
+#[test]
+fn test_registrable_domain() {
+    assert_eq!("example.com", registrable_domain("www.example.com"));
+    assert_eq!("example.com", registrable_domain("Example.COM."));
+    assert_eq!("example.co.uk", registrable_domain("a.b.example.co.uk"));
+    assert_eq!("example.com.au", registrable_domain("example.com.au"));
+    // Only second level labels below country code TLDs are suffixes
+    assert_eq!("co.example", registrable_domain("www.co.example"));
+    assert_eq!("co.uk", registrable_domain("co.uk"));
+    assert_eq!("localhost", registrable_domain("localhost"));
+}
+
+#[test]
+fn test_quality_metrics() {
+    use SequenceElement::Size;
+
+    let elements = vec![Size(1), Size(1), Size(2), Size(2)];
+    let training_data = vec![
+        LabelledSequences {
+            true_domain: "news.example.co.uk",
+            mapped_domain: "news.example.co.uk",
+            sequences: vec![seq(elements.clone(), "a1"), seq(elements.clone(), "a2")],
+        },
+        LabelledSequences {
+            true_domain: "other.example",
+            mapped_domain: "other.example",
+            sequences: vec![seq(vec![Size(1), Size(1), Size(2), Size(3)], "b")],
+        },
+    ];
+    let mut result = knn::knn(&training_data, &[seq(elements, "test")], 3, false).remove(0);
+    assert_eq!(
+        vec!["news.example.co.uk", "other.example"],
+        result.ranked_labels()
+    );
+
+    assert!(ExactMatch.is_correct(&result, "news.example.co.uk"));
+    assert!(!ExactMatch.is_correct(&result, "www.example.co.uk"));
+
+    assert!(RegistrableDomainMatch.is_correct(&result, "news.example.co.uk"));
+    assert!(RegistrableDomainMatch.is_correct(&result, "www.example.co.uk"));
+    assert!(!RegistrableDomainMatch.is_correct(&result, "other.example"));
+
+    assert!(TopK(1).is_correct(&result, "news.example.co.uk"));
+    assert!(!TopK(1).is_correct(&result, "other.example"));
+    assert!(TopK(2).is_correct(&result, "other.example"));
+    assert!(!TopK(2).is_correct(&result, "third.example"));
+    assert_eq!("top-2", TopK(2).name());
+
+    let confusion_sets = ConfusionSetMatch {
+        name: "confusion-set:sets.csv".to_string(),
+        sets: vec![
+            ("news.example.co.uk", "example"),
+            ("mirror.example", "example"),
+            ("other.example", "other"),
+        ]
+        .into_iter()
+        .map(|(domain, set)| (domain.to_string(), set.to_string()))
+        .collect(),
+    };
+    assert!(confusion_sets.is_correct(&result, "news.example.co.uk"));
+    assert!(confusion_sets.is_correct(&result, "mirror.example"));
+    assert!(!confusion_sets.is_correct(&result, "other.example"));
+    // Domains without a set only match themselves
+    assert!(!confusion_sets.is_correct(&result, "www.example.co.uk"));
+    assert_eq!("confusion-set:sets.csv", confusion_sets.name());
+
+    // Without a label, no metric counts the result as correct
+    result.reject_below(0.9);
+    let metrics: Vec<Box<dyn QualityMetric>> = vec![
+        Box::new(ExactMatch),
+        Box::new(RegistrableDomainMatch),
+        Box::new(TopK(2)),
+        Box::new(confusion_sets),
+    ];
+    for metric in &metrics {
+        assert!(
+            !metric.is_correct(&result, "news.example.co.uk"),
+            "{}",
+            metric.name()
+        );
+    }
+}
+
+#[test]
+fn test_knn_prefixes() {
+    use SequenceElement::Size;
+
+    let training_data = vec![
+        LabelledSequences {
+            true_domain: "a.example",
+            mapped_domain: "a.example",
+            sequences: vec![seq(vec![Size(1); 9], "a")],
+        },
+        LabelledSequences {
+            true_domain: "b.example",
+            mapped_domain: "b.example",
+            sequences: vec![seq(vec![Size(2); 9], "b")],
+        },
+    ];
+    let test_data = vec![
+        // The first prefix is closer to `b.example`, all longer ones to `a.example`
+        seq(
+            vec![
+                Size(2),
+                Size(2),
+                Size(1),
+                Size(1),
+                Size(1),
+                Size(1),
+                Size(1),
+                Size(1),
+                Size(1),
+            ],
+            "stable",
+        ),
+        // Only the full sequence is closer to `a.example`
+        seq(
+            vec![
+                Size(2),
+                Size(2),
+                Size(2),
+                Size(2),
+                Size(1),
+                Size(1),
+                Size(1),
+                Size(1),
+                Size(1),
+            ],
+            "unstable",
+        ),
+    ];
+
+    let results = knn::knn_prefixes(&training_data, &test_data, 1, 3, false);
+    assert_eq!(2, results.len());
+    for result in &results {
+        assert_eq!(vec![3, 6, 9], result.prefix_lengths);
+        assert_eq!(Some("a.example"), result.final_label());
+    }
+    let labels = |result: &knn::PrefixClassificationResult| -> Vec<_> {
+        result
+            .results
+            .iter()
+            .map(|res| res.best_label().map(str::to_string))
+            .collect()
+    };
+    assert_eq!(
+        vec![
+            Some("b.example".to_string()),
+            Some("a.example".to_string()),
+            Some("a.example".to_string())
+        ],
+        labels(&results[0])
+    );
+    assert_eq!(Some(6), results[0].stable_prefix_length());
+    // The decision only stabilizes with the full sequence
+    assert_eq!(
+        vec![
+            Some("b.example".to_string()),
+            Some("b.example".to_string()),
+            Some("a.example".to_string())
+        ],
+        labels(&results[1])
+    );
+    assert_eq!(Some(9), results[1].stable_prefix_length());
+
+    // Without any classification there is no stable prefix
+    assert_eq!(
+        None,
+        knn::PrefixClassificationResult::default().stable_prefix_length()
+    );
+}
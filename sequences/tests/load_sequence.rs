@@ -1,6 +1,10 @@
+use chrono::{Duration, NaiveDateTime};
 use pretty_assertions::assert_eq;
 use sequences::{
-    LoadSequenceConfig, Sequence,
+    conversion_cache::ConversionCache,
+    convert_to_sequence, dnstap, try_convert_to_sequence, AbstractQueryResponse, FilterReason,
+    FilteredSequences, GapMode, LoadSequenceConfig, MarkerPolicy, NoiseMechanism, Padding,
+    PageLoadSegmentation, Sequence,
     SequenceElement::{Gap, Size},
     SimulatedCountermeasure,
 };
@@ -40,6 +44,72 @@ fn test_load_sequence_normal() {
     assert_eq!(expected, seq);
 }
 
+#[test]
+fn test_load_sequence_streaming() {
+    let config = LoadSequenceConfig::default();
+    for file in &[DNSTAP1, DNSTAP2] {
+        let expected = dnstap::build_sequence(file.as_ref(), config).unwrap();
+        let seq = Sequence::from_path_with_config(file.as_ref(), config).unwrap();
+        assert_eq!(expected, seq);
+        let seq =
+            dnstap::build_sequence_streaming(file.as_ref(), config, dnstap::DEFAULT_REORDER_BUFFER)
+                .unwrap();
+        assert_eq!(expected, seq);
+
+        let expected = dnstap::load_matching_query_responses_from_dnstap(file.as_ref()).unwrap();
+        let queries = dnstap::stream_matching_query_responses_from_dnstap(
+            file.as_ref(),
+            MarkerPolicy::default(),
+            dnstap::DEFAULT_REORDER_BUFFER,
+        )
+        .unwrap();
+        assert_eq!(expected, queries);
+    }
+}
+
+#[test]
+fn test_conversion_cache() {
+    let dir = std::env::temp_dir().join(format!("conversion-cache-{}", std::process::id()));
+    let cache = ConversionCache::new(dir.clone()).unwrap();
+    let config = LoadSequenceConfig::default();
+
+    let expected = Sequence::from_path_with_config(DNSTAP1.as_ref(), config).unwrap();
+    // The first load converts the file and the second one reads the cache
+    let seq = cache.load(DNSTAP1.as_ref(), config).unwrap();
+    assert_eq!(expected, seq);
+    assert_eq!(1, walk_files(&dir).len());
+    let seq = cache.load(DNSTAP1.as_ref(), config).unwrap();
+    assert_eq!(expected, seq);
+    assert_eq!(expected.metadata(), seq.metadata());
+
+    // A different configuration must not reuse the cached sequence
+    let config = LoadSequenceConfig {
+        simulated_countermeasure: SimulatedCountermeasure::PerfectPadding,
+        ..Default::default()
+    };
+    let seq = cache.load(DNSTAP1.as_ref(), config).unwrap();
+    assert_eq!(
+        Sequence::from_path_with_config(DNSTAP1.as_ref(), config).unwrap(),
+        seq
+    );
+    assert_eq!(2, walk_files(&dir).len());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+fn walk_files(dir: &std::path::Path) -> Vec<std::path::PathBuf> {
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            files.extend(walk_files(&path));
+        } else {
+            files.push(path);
+        }
+    }
+    files
+}
+
 #[test]
 fn test_load_sequence_perfect_padding() {
     let config = LoadSequenceConfig {
@@ -78,6 +148,154 @@ fn test_load_sequence_perfect_timing() {
     assert_eq!(expected, seq);
 }
 
+#[test]
+fn test_load_sequence_perfect_padding_and_timing() {
+    let config = LoadSequenceConfig {
+        simulated_countermeasure: SimulatedCountermeasure::PerfectPaddingAndTiming,
+        ..Default::default()
+    };
+
+    let seq = Sequence::from_path_with_config(DNSTAP2.as_ref(), config).unwrap();
+    let expected = Sequence::new(vec![Size(1); 5], DNSTAP2.to_string());
+    assert_eq!(expected, seq);
+}
+
+#[test]
+fn test_load_sequence_count_only() {
+    let config = LoadSequenceConfig {
+        simulated_countermeasure: SimulatedCountermeasure::CountOnly,
+        ..Default::default()
+    };
+
+    let seq = Sequence::from_path_with_config(DNSTAP2.as_ref(), config).unwrap();
+    let expected = Sequence::new(
+        vec![Size(1), Size(1), Size(1), Size(1), Size(2)],
+        DNSTAP2.to_string(),
+    );
+    assert_eq!(expected, seq);
+}
+
+#[test]
+fn test_load_sequence_constant_length() {
+    let config = LoadSequenceConfig {
+        simulated_countermeasure: SimulatedCountermeasure::ConstantLength(3),
+        ..Default::default()
+    };
+    let seq = Sequence::from_path_with_config(DNSTAP2.as_ref(), config).unwrap();
+    let expected = Sequence::new(vec![Size(1), Gap(9), Size(1)], DNSTAP2.to_string());
+    assert_eq!(expected, seq);
+
+    let config = LoadSequenceConfig {
+        simulated_countermeasure: SimulatedCountermeasure::ConstantLength(9),
+        ..Default::default()
+    };
+    let seq = Sequence::from_path_with_config(DNSTAP2.as_ref(), config).unwrap();
+    let expected = Sequence::new(
+        vec![
+            Size(1),
+            Gap(9),
+            Size(1),
+            Size(2),
+            Size(1),
+            Gap(6),
+            Size(1),
+            Size(1),
+            Size(1),
+        ],
+        DNSTAP2.to_string(),
+    );
+    assert_eq!(expected, seq);
+}
+
+#[test]
+fn test_load_sequence_differential_privacy() {
+    let normal = Sequence::from_path(DNSTAP2.as_ref()).unwrap();
+
+    for mechanism in [NoiseMechanism::Laplace, NoiseMechanism::Gaussian] {
+        // A huge epsilon adds (almost) no noise
+        let config = LoadSequenceConfig {
+            simulated_countermeasure: SimulatedCountermeasure::differential_privacy(mechanism, 1e9)
+                .unwrap(),
+            ..Default::default()
+        };
+        let seq = Sequence::from_path_with_config(DNSTAP2.as_ref(), config).unwrap();
+        assert_eq!(normal, seq);
+
+        // A small epsilon changes the sequence, but the noise is reproducible
+        let config = LoadSequenceConfig {
+            simulated_countermeasure: SimulatedCountermeasure::differential_privacy(mechanism, 0.1)
+                .unwrap(),
+            ..Default::default()
+        };
+        let seq = Sequence::from_path_with_config(DNSTAP2.as_ref(), config).unwrap();
+        assert_ne!(normal, seq);
+        assert_eq!(
+            seq,
+            Sequence::from_path_with_config(DNSTAP2.as_ref(), config).unwrap()
+        );
+    }
+
+    assert!(SimulatedCountermeasure::differential_privacy(NoiseMechanism::Laplace, 0.).is_err());
+    assert!(
+        SimulatedCountermeasure::differential_privacy(NoiseMechanism::Laplace, f64::NAN).is_err()
+    );
+}
+
+#[test]
+fn test_load_sequence_with_each_countermeasure() {
+    let dir = std::env::temp_dir().join(format!("each-countermeasure-{}", std::process::id()));
+    let cache = ConversionCache::new(dir.clone()).unwrap();
+
+    for name in &[
+        "None",
+        "PerfectPadding",
+        "PerfectTiming",
+        "PerfectPaddingAndTiming",
+        "CountOnly",
+        "ConstantLength:4",
+        "DpLaplace:1",
+        "DpGaussian:1",
+    ] {
+        let simulated_countermeasure: SimulatedCountermeasure = name.parse().unwrap();
+        let config = LoadSequenceConfig {
+            simulated_countermeasure,
+            ..Default::default()
+        };
+        let seq = Sequence::from_path_with_config(DNSTAP2.as_ref(), config)
+            .unwrap_or_else(|err| panic!("Loading with {} failed: {:#}", name, err));
+        // Converting through the cache must not change the result
+        assert_eq!(
+            seq,
+            cache.load(DNSTAP2.as_ref(), config).unwrap(),
+            "{}",
+            name
+        );
+
+        let expected = match simulated_countermeasure {
+            SimulatedCountermeasure::None => {
+                vec![Size(1), Gap(9), Size(1), Size(2), Size(1), Gap(6), Size(1)]
+            }
+            SimulatedCountermeasure::PerfectPadding => vec![Gap(9), Gap(0), Gap(0), Gap(6)],
+            SimulatedCountermeasure::PerfectTiming => {
+                vec![Size(1), Size(1), Size(2), Size(1), Size(1)]
+            }
+            SimulatedCountermeasure::PerfectPaddingAndTiming => vec![Size(1); 5],
+            SimulatedCountermeasure::CountOnly => {
+                vec![Size(1), Size(1), Size(1), Size(1), Size(2)]
+            }
+            SimulatedCountermeasure::ConstantLength(_) => vec![Size(1), Gap(9), Size(1), Size(2)],
+            // The noise is seeded with the identifier, so only check that a sequence is produced
+            SimulatedCountermeasure::DifferentialPrivacy { .. } => {
+                assert!(!seq.as_elements().is_empty(), "{}", name);
+                continue;
+            }
+        };
+        assert_eq!(seq.as_elements(), &*expected, "{}", name);
+    }
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
 /// Ensure that an uncompressed pcap file can be read
 #[test]
 #[cfg_attr(not(feature = "read_pcap"), ignore)]
@@ -133,3 +351,239 @@ fn test_load_pcap_reordered_packets() {
         "Failed to load vk.com pcap file"
     );
 }
+
+#[test]
+fn test_gap_mode_padding_round_trip() {
+    for gap_mode in &[GapMode::Log2, GapMode::Ident] {
+        assert_eq!(gap_mode.to_string().parse::<GapMode>().unwrap(), *gap_mode);
+        let json = serde_json::to_string(gap_mode).unwrap();
+        assert_eq!(serde_json::from_str::<GapMode>(&json).unwrap(), *gap_mode);
+    }
+    assert_eq!("ident".parse::<GapMode>().unwrap(), GapMode::Ident);
+    assert!("log".parse::<GapMode>().is_err());
+
+    let padding = Padding::Q128R468;
+    assert_eq!(padding.to_string().parse::<Padding>().unwrap(), padding);
+    assert_eq!(serde_json::to_string(&padding).unwrap(), r#""Q128R468""#);
+    assert_eq!(
+        serde_json::from_str::<Padding>(r#""q128r468""#).unwrap(),
+        padding
+    );
+    assert!("Q128".parse::<Padding>().is_err());
+}
+
+#[test]
+fn test_simulated_countermeasure_round_trip() {
+    let countermeasures = [
+        SimulatedCountermeasure::None,
+        SimulatedCountermeasure::PerfectPadding,
+        SimulatedCountermeasure::PerfectTiming,
+        SimulatedCountermeasure::PerfectPaddingAndTiming,
+        SimulatedCountermeasure::CountOnly,
+        SimulatedCountermeasure::ConstantLength(7),
+        SimulatedCountermeasure::differential_privacy(NoiseMechanism::Laplace, 0.5).unwrap(),
+        SimulatedCountermeasure::differential_privacy(NoiseMechanism::Gaussian, 2.).unwrap(),
+    ];
+    for countermeasure in &countermeasures {
+        assert_eq!(
+            countermeasure
+                .to_string()
+                .parse::<SimulatedCountermeasure>()
+                .unwrap(),
+            *countermeasure
+        );
+        let json = serde_json::to_string(countermeasure).unwrap();
+        assert_eq!(
+            serde_json::from_str::<SimulatedCountermeasure>(&json).unwrap(),
+            *countermeasure
+        );
+    }
+
+    assert_eq!(
+        SimulatedCountermeasure::ConstantLength(7).to_string(),
+        "ConstantLength:7"
+    );
+    assert_eq!(countermeasures[6].to_string(), "DpLaplace:0.5");
+    assert_eq!(
+        "normal".parse::<SimulatedCountermeasure>().unwrap(),
+        SimulatedCountermeasure::None
+    );
+    assert_eq!(
+        "ConstantLength".parse::<SimulatedCountermeasure>().unwrap(),
+        SimulatedCountermeasure::ConstantLength(50)
+    );
+    assert_eq!(
+        "DpGaussian".parse::<SimulatedCountermeasure>().unwrap(),
+        SimulatedCountermeasure::differential_privacy(NoiseMechanism::Gaussian, 1.).unwrap()
+    );
+    assert!("PerfectTiming:1"
+        .parse::<SimulatedCountermeasure>()
+        .is_err());
+    assert!("ConstantLength:x"
+        .parse::<SimulatedCountermeasure>()
+        .is_err());
+    assert!("DpLaplace:0".parse::<SimulatedCountermeasure>().is_err());
+    assert!("Unknown".parse::<SimulatedCountermeasure>().is_err());
+}
+
+#[test]
+fn test_filter_sequences() {
+    let seqs = vec![
+        Sequence::new(vec![Size(1)], "single-query".into()),
+        Sequence::new(vec![Size(1), Gap(2), Size(1), Size(1)], "simple".into()),
+        Sequence::new(vec![Size(2), Gap(2), Size(3), Size(1)], "complex".into()),
+        Sequence::new(vec![Size(1); 10], "long".into()),
+    ];
+
+    // Without filters all sequences are kept
+    let mut sequences = seqs.clone();
+    let filtered = LoadSequenceConfig::default().filter_sequences(&mut sequences);
+    assert_eq!(FilteredSequences::default(), filtered);
+    assert_eq!(seqs, sequences);
+
+    let config = LoadSequenceConfig {
+        min_length: Some(2),
+        max_length: Some(5),
+        min_complexity: Some(4),
+        ..Default::default()
+    };
+    let mut sequences = seqs.clone();
+    let filtered = config.filter_sequences(&mut sequences);
+    assert_eq!(
+        FilteredSequences {
+            too_short: 1,
+            too_long: 1,
+            too_simple: 1,
+        },
+        filtered
+    );
+    assert_eq!(3, filtered.total());
+    assert_eq!(vec![seqs[2].clone()], sequences);
+
+    // The filters do not change how a single file is loaded
+    assert_eq!(LoadSequenceConfig::default(), config.without_filters());
+}
+
+#[test]
+fn test_filters_before_simulation() {
+    let start = NaiveDateTime::from_timestamp(1_600_000_000, 0);
+    let messages: Vec<AbstractQueryResponse> = (0..3)
+        .map(|i| AbstractQueryResponse {
+            time: start + Duration::milliseconds(i * 10),
+            // Two blocks of the response padding each
+            size: 500,
+        })
+        .collect();
+    let simulated = LoadSequenceConfig {
+        simulated_countermeasure: SimulatedCountermeasure::ConstantLength(50),
+        ..Default::default()
+    };
+
+    // The simulated sequence is longer than the maximal length, but the original trace is not
+    let config = LoadSequenceConfig {
+        max_length: Some(10),
+        ..simulated
+    };
+    let seq = try_convert_to_sequence(&messages, "short".into(), config)
+        .unwrap()
+        .unwrap();
+    assert_eq!(50, seq.message_count());
+
+    // The simulated sequence is long enough, but the original trace is too short
+    let config = LoadSequenceConfig {
+        min_length: Some(5),
+        ..simulated
+    };
+    assert_eq!(
+        Err(FilterReason::TooShort),
+        try_convert_to_sequence(&messages, "short".into(), config)
+    );
+    // The complexity uses the padded sizes of the original trace
+    let config = LoadSequenceConfig {
+        min_complexity: Some(7),
+        ..simulated
+    };
+    assert_eq!(
+        Err(FilterReason::TooSimple),
+        try_convert_to_sequence(&messages, "short".into(), config)
+    );
+    let config = LoadSequenceConfig {
+        min_complexity: Some(6),
+        ..simulated
+    };
+    assert!(try_convert_to_sequence(&messages, "short".into(), config).is_ok());
+    // Without the filters the sequence is always converted
+    assert!(convert_to_sequence(&messages, "short".into(), config).is_some());
+}
+
+#[test]
+fn test_page_load_segmentation() {
+    let start = NaiveDateTime::from_timestamp(1_600_000_000, 0);
+    // A page load with short gaps, followed by keepalive queries every 30 seconds
+    let millis = [0, 5, 12, 20, 60, 64, 100, 30_100, 60_100, 90_100];
+    let messages: Vec<AbstractQueryResponse> = millis
+        .iter()
+        .map(|&millis| AbstractQueryResponse {
+            time: start + Duration::milliseconds(millis),
+            size: 100,
+        })
+        .collect();
+
+    assert_eq!(
+        messages.len(),
+        PageLoadSegmentation::None.page_load_end(&messages)
+    );
+    let segmentation: PageLoadSegmentation = "ChangePoint".parse().unwrap();
+    assert_eq!(PageLoadSegmentation::ChangePoint(3), segmentation);
+    assert_eq!(7, segmentation.page_load_end(&messages));
+    // Without trailing traffic nothing is removed
+    assert_eq!(7, segmentation.page_load_end(&messages[..7]));
+
+    let config = LoadSequenceConfig {
+        segmentation,
+        ..Default::default()
+    };
+    let seq = convert_to_sequence(&messages, "keepalive".into(), config).unwrap();
+    assert_eq!(7, seq.message_count());
+
+    assert_eq!(
+        PageLoadSegmentation::ChangePoint(5),
+        "ChangePoint:5".parse().unwrap()
+    );
+    assert_eq!(
+        "ChangePoint:5",
+        PageLoadSegmentation::ChangePoint(5).to_string()
+    );
+    assert!("None:1".parse::<PageLoadSegmentation>().is_err());
+}
+
+#[test]
+fn test_page_load_segmentation_uniform_gaps() {
+    let start = NaiveDateTime::from_timestamp(1_600_000_000, 0);
+    let segmentation = PageLoadSegmentation::ChangePoint(3);
+    for &gap in &[1, 100, 30_000] {
+        let messages: Vec<AbstractQueryResponse> = (0..6)
+            .map(|i| AbstractQueryResponse {
+                time: start + Duration::milliseconds(i * gap),
+                size: 100,
+            })
+            .collect();
+        // Without a change in the gaps, the trace is not truncated, even if all gaps are long
+        assert_eq!(
+            messages.len(),
+            segmentation.page_load_end(&messages),
+            "Gap of {} ms",
+            gap
+        );
+    }
+
+    // A single long gap after the first message does not form a page load
+    let messages: Vec<AbstractQueryResponse> = [0, 30_000, 30_010, 30_020]
+        .iter()
+        .map(|&millis| AbstractQueryResponse {
+            time: start + Duration::milliseconds(millis),
+            size: 100,
+        })
+        .collect();
+    assert_eq!(4, segmentation.page_load_end(&messages));
+}
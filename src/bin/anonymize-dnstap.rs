@@ -0,0 +1,54 @@
+use anyhow::{Context as _, Error};
+use dnstap::anonymize::{anonymize_dnstap_file, Anonymizer};
+use log::info;
+use misc_utils::fs::file_write;
+use sequences::dnstap::load_matching_query_responses_from_dnstap;
+use std::path::PathBuf;
+use structopt::{self, StructOpt};
+
+/// Replace all domain names in a dnstap file with salted hashes
+///
+/// The sizes and timings of all messages stay unchanged, such that the anonymized files can be shared instead of the originals.
+#[derive(StructOpt, Debug)]
+#[structopt(global_settings(&[
+    structopt::clap::AppSettings::ColoredHelp,
+    structopt::clap::AppSettings::VersionlessSubcommands
+]))]
+struct CliArgs {
+    /// Secret salt for the hashes
+    ///
+    /// Use the same salt for all files of a dataset to keep identical names identical across files.
+    #[structopt(long = "salt")]
+    salt: String,
+    /// Write the matched queries and responses of the anonymized file as JSON to this path
+    #[structopt(long = "queries", parse(from_os_str))]
+    queries: Option<PathBuf>,
+    /// Input dnstap file
+    #[structopt(parse(from_os_str))]
+    input: PathBuf,
+    /// Output dnstap file
+    #[structopt(parse(from_os_str))]
+    output: PathBuf,
+}
+
+fn main() -> Result<(), Error> {
+    // generic setup
+    env_logger::init();
+    let cli_args = CliArgs::from_args();
+
+    let anonymizer = Anonymizer::new(cli_args.salt.as_bytes());
+    let count = anonymize_dnstap_file(&cli_args.input, &cli_args.output, &anonymizer)
+        .with_context(|| format!("Failed to anonymize '{}'", cli_args.input.display()))?;
+    info!("Anonymized {} dnstap messages", count);
+
+    if let Some(path) = &cli_args.queries {
+        let queries = load_matching_query_responses_from_dnstap(&cli_args.output)?;
+        let wtr = file_write(path)
+            .create(true)
+            .truncate()
+            .with_context(|| format!("Opening output file '{}' failed", path.display()))?;
+        serde_json::to_writer_pretty(wtr, &queries)?;
+    }
+
+    Ok(())
+}
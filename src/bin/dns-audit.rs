@@ -0,0 +1,258 @@
+use anyhow::{Context as _, Error};
+use chrome::ChromeDebuggerMessage;
+use misc_utils::fs::{file_write, read_to_string};
+use sequences::dnstap::{load_matching_query_responses_from_dnstap, QuerySource};
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    net::{IpAddr, ToSocketAddrs},
+    path::PathBuf,
+};
+use structopt::{self, StructOpt};
+use url::Url;
+
+/// Compare the domains requested by Chrome with the DNS queries in the dnstap file
+///
+/// This helps to explain why the DNS sequences of the same website differ between collection rounds.
+/// Domains requested by Chrome without a DNS query were answered from a cache in the browser.
+/// Domains with a client query but without a forwarder query were answered from the cache of the resolver.
+/// With `--live`, the domains are resolved again and compared against the IP addresses Chrome connected to, which shows rotating CDN addresses.
+#[derive(StructOpt, Debug)]
+#[structopt(global_settings(&[
+    structopt::clap::AppSettings::ColoredHelp,
+    structopt::clap::AppSettings::VersionlessSubcommands
+]))]
+struct CliArgs {
+    /// Resolve all domains with the system resolver and compare the addresses
+    #[structopt(long = "live")]
+    live: bool,
+    /// Write the results as CSV to this file
+    #[structopt(short = "o", long = "out", value_name = "FILE", parse(from_os_str))]
+    outfile: Option<PathBuf>,
+    /// Chrome debugger log of the website
+    #[structopt(parse(from_os_str))]
+    chrome_log: PathBuf,
+    /// Dnstap file recorded together with the Chrome log
+    #[structopt(parse(from_os_str))]
+    dnstap: PathBuf,
+}
+
+/// How the DNS resolution of a domain was observed
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Serialize)]
+enum Resolution {
+    /// The query was forwarded to the upstream resolver
+    Forwarded,
+    /// The resolver answered the query from its cache
+    ResolverCache,
+    /// The browser did not send a query, e.g., because the answer was still cached
+    NotQueried,
+}
+
+impl Resolution {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Forwarded => "Forwarded",
+            Self::ResolverCache => "ResolverCache",
+            Self::NotQueried => "NotQueried",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct DomainAudit {
+    domain: String,
+    /// Number of requests Chrome made to this domain
+    requests: usize,
+    /// Number of requests Chrome served from its own cache
+    served_from_cache: usize,
+    resolution: Resolution,
+    /// Addresses Chrome connected to during the measurement
+    recorded_ips: String,
+    /// Addresses returned by the system resolver now
+    live_ips: String,
+    /// The recorded and the live addresses are disjoint
+    addresses_changed: bool,
+}
+
+#[derive(Default)]
+struct ChromeDomain {
+    requests: usize,
+    served_from_cache: usize,
+    ips: BTreeSet<IpAddr>,
+}
+
+fn main() -> Result<(), Error> {
+    // generic setup
+    env_logger::init();
+    let cli_args = CliArgs::from_args();
+
+    let content = read_to_string(&cli_args.chrome_log).with_context(|| {
+        format!(
+            "Reading input file '{}' failed",
+            cli_args.chrome_log.display()
+        )
+    })?;
+    let messages: Vec<ChromeDebuggerMessage> =
+        serde_json::from_str(&content).with_context(|| {
+            format!(
+                "Error while deserializing '{}'",
+                cli_args.chrome_log.display()
+            )
+        })?;
+    let domains = chrome_domains(&messages);
+
+    let queries = load_matching_query_responses_from_dnstap(&cli_args.dnstap)?;
+    let client_queries: HashSet<_> = queries
+        .iter()
+        .filter(|query| query.source == QuerySource::Client)
+        .map(|query| query.qname.to_ascii_lowercase())
+        .collect();
+    let forwarder_queries: HashSet<_> = queries
+        .iter()
+        .filter(|query| query.source == QuerySource::Forwarder)
+        .map(|query| query.qname.to_ascii_lowercase())
+        .collect();
+
+    let mut results = Vec::new();
+    for (domain, info) in domains {
+        let resolution = if forwarder_queries.contains(&domain) {
+            Resolution::Forwarded
+        } else if client_queries.contains(&domain) {
+            Resolution::ResolverCache
+        } else {
+            Resolution::NotQueried
+        };
+
+        let live_ips: BTreeSet<IpAddr> = if cli_args.live {
+            (domain.trim_end_matches('.'), 0)
+                .to_socket_addrs()
+                .map(|addrs| addrs.map(|addr| addr.ip()).collect())
+                .unwrap_or_default()
+        } else {
+            BTreeSet::new()
+        };
+        let addresses_changed = cli_args.live
+            && !info.ips.is_empty()
+            && !live_ips.is_empty()
+            && info.ips.is_disjoint(&live_ips);
+
+        results.push(DomainAudit {
+            domain,
+            requests: info.requests,
+            served_from_cache: info.served_from_cache,
+            resolution,
+            recorded_ips: join_ips(&info.ips),
+            live_ips: join_ips(&live_ips),
+            addresses_changed,
+        });
+    }
+
+    for result in &results {
+        println!(
+            "{:<50} {:>4} requests {:>4} cached  {:<13} {}",
+            result.domain,
+            result.requests,
+            result.served_from_cache,
+            result.resolution.as_str(),
+            if result.addresses_changed {
+                "addresses changed"
+            } else {
+                ""
+            }
+        );
+    }
+    let count = |resolution| {
+        results
+            .iter()
+            .filter(|result| result.resolution == resolution)
+            .count()
+    };
+    println!(
+        "\n{} domains: {} forwarded, {} from resolver cache, {} not queried, {} with changed addresses",
+        results.len(),
+        count(Resolution::Forwarded),
+        count(Resolution::ResolverCache),
+        count(Resolution::NotQueried),
+        results
+            .iter()
+            .filter(|result| result.addresses_changed)
+            .count()
+    );
+
+    if let Some(path) = &cli_args.outfile {
+        let mut writer = csv::Writer::from_writer(
+            file_write(path)
+                .create(true)
+                .truncate()
+                .with_context(|| format!("Opening output file '{}' failed", path.display()))?,
+        );
+        for result in &results {
+            writer.serialize(result)?;
+        }
+        writer.flush()?;
+    }
+
+    Ok(())
+}
+
+/// Collect the domains of all requests in the Chrome log
+///
+/// The domains are lowercase and end with a dot, like the names in dnstap files.
+fn chrome_domains(messages: &[ChromeDebuggerMessage]) -> BTreeMap<String, ChromeDomain> {
+    let mut domains: BTreeMap<String, ChromeDomain> = BTreeMap::new();
+    let mut request_domains: BTreeMap<&str, String> = BTreeMap::new();
+
+    let domain_of = |url: &str| -> Option<String> {
+        let url = Url::parse(url).ok()?;
+        match url.host()? {
+            url::Host::Domain(domain) => Some(format!("{}.", domain.to_ascii_lowercase())),
+            // IP literals do not require DNS
+            url::Host::Ipv4(_) | url::Host::Ipv6(_) => None,
+        }
+    };
+
+    for msg in messages {
+        match msg {
+            ChromeDebuggerMessage::NetworkRequestWillBeSent {
+                request_id,
+                request,
+                ..
+            } => {
+                if let Some(domain) = domain_of(&request.url) {
+                    domains.entry(domain.clone()).or_default().requests += 1;
+                    request_domains.insert(request_id.as_str(), domain);
+                }
+            }
+            ChromeDebuggerMessage::NetworkWebSocketCreated { url, .. } => {
+                if let Some(domain) = domain_of(url) {
+                    domains.entry(domain).or_default().requests += 1;
+                }
+            }
+            ChromeDebuggerMessage::NetworkRequestServedFromCache { request_id } => {
+                if let Some(domain) = request_domains.get(request_id.as_str()) {
+                    domains.entry(domain.clone()).or_default().served_from_cache += 1;
+                }
+            }
+            ChromeDebuggerMessage::NetworkResponseReceived { response, .. } => {
+                let ip = response
+                    .remote_ip_address
+                    .as_ref()
+                    // IPv6 addresses are enclosed in brackets
+                    .and_then(|ip| ip.trim_matches(|c| c == '[' || c == ']').parse().ok());
+                if let (Some(domain), Some(ip)) = (domain_of(&response.url), ip) {
+                    domains.entry(domain).or_default().ips.insert(ip);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    domains
+}
+
+fn join_ips(ips: &BTreeSet<IpAddr>) -> String {
+    ips.iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
@@ -1,6 +1,7 @@
 use chrome::ChromeDebuggerMessage;
 use min_max_heap::MinMaxHeap;
-use sequences::common_sequence_classifications::{R008, R009};
+use sequences::common_sequence_classifications::{R008, R009, R010, R011, R012, R013, R014};
+use std::fmt::{self, Display};
 
 pub fn take_largest<I, T>(iter: I, n: usize) -> Vec<T>
 where
@@ -35,10 +36,62 @@ where
     res
 }
 
-pub fn chrome_log_contains_errors<S>(msgs: &[ChromeDebuggerMessage<S>]) -> Option<&'static str>
+/// Distinguishes failed measurements which might succeed when repeated from those which will not
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+pub enum FailureKind {
+    /// The failure might disappear, e.g., a SERVFAIL of the resolver or an overloaded server
+    Transient,
+    /// Repeating the measurement fails again, e.g., because the domain does not exist
+    Permanent,
+}
+
+/// Reason why a Chrome log does not represent a successful page load
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+pub struct ChromeLogError {
+    pub reason: &'static str,
+    pub kind: FailureKind,
+}
+
+impl ChromeLogError {
+    fn transient(reason: &'static str) -> Self {
+        Self {
+            reason,
+            kind: FailureKind::Transient,
+        }
+    }
+
+    fn permanent(reason: &'static str) -> Self {
+        Self {
+            reason,
+            kind: FailureKind::Permanent,
+        }
+    }
+}
+
+impl Display for ChromeLogError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.reason)
+    }
+}
+
+pub fn chrome_log_contains_errors<S>(msgs: &[ChromeDebuggerMessage<S>]) -> Option<ChromeLogError>
 where
     S: AsRef<str>,
 {
+    // The first request is the navigation to the main document
+    let main_request_id = msgs.iter().find_map(|msg| {
+        if let ChromeDebuggerMessage::NetworkRequestWillBeSent { request_id, .. } = msg {
+            Some(request_id.as_ref())
+        } else {
+            None
+        }
+    });
+    if let Some(main_request_id) = main_request_id {
+        if let Some(err) = main_document_error(msgs, main_request_id) {
+            return Some(err);
+        }
+    }
+
     // test if there is a chrome error page
     let contains_chrome_error = msgs.iter().any(|msg| {
         if let ChromeDebuggerMessage::NetworkRequestWillBeSent { document_url, .. } = msg {
@@ -48,7 +101,7 @@ where
         }
     });
     if contains_chrome_error {
-        return Some(R008);
+        return Some(ChromeLogError::transient(R008));
     }
 
     // Ensure at least one network request has succeeded.
@@ -59,9 +112,148 @@ where
         .iter()
         .any(|msg| matches!(msg, ChromeDebuggerMessage::NetworkDataReceived { .. }));
     if !(contains_response_received && contains_data_received) {
-        return Some(R009);
+        return Some(ChromeLogError::transient(R009));
     }
 
     // default case is `false`, meaning the data is good
     None
 }
+
+/// Classify network errors and HTTP errors of the main document
+fn main_document_error<S>(
+    msgs: &[ChromeDebuggerMessage<S>],
+    main_request_id: &str,
+) -> Option<ChromeLogError>
+where
+    S: AsRef<str>,
+{
+    msgs.iter().find_map(|msg| match msg {
+        ChromeDebuggerMessage::NetworkLoadingFailed {
+            request_id,
+            error_text: Some(error_text),
+        } if request_id.as_ref() == main_request_id => {
+            // https://source.chromium.org/chromium/chromium/src/+/main:net/base/net_error_list.h
+            match error_text.as_ref().trim_start_matches("net::") {
+                "ERR_NAME_NOT_RESOLVED" => Some(ChromeLogError::permanent(R012)),
+                "ERR_NAME_RESOLUTION_FAILED" => Some(ChromeLogError::transient(R011)),
+                "ERR_SSL_PROTOCOL_ERROR" | "ERR_SSL_VERSION_OR_CIPHER_MISMATCH" => {
+                    Some(ChromeLogError::permanent(R014))
+                }
+                err if err.starts_with("ERR_CERT_") => Some(ChromeLogError::permanent(R014)),
+                _ => None,
+            }
+        }
+        ChromeDebuggerMessage::NetworkResponseReceived {
+            request_id,
+            response,
+        } if request_id.as_ref() == main_request_id => match response.status? {
+            500..=599 => Some(ChromeLogError::transient(R010)),
+            // Timeouts and rate limiting can disappear on a later try
+            408 | 429 => Some(ChromeLogError::transient(R013)),
+            400..=499 => Some(ChromeLogError::permanent(R013)),
+            _ => None,
+        },
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+fn chrome_log(main_document: serde_json::Value) -> Vec<ChromeDebuggerMessage> {
+    let mut msgs = vec![serde_json::json!({
+        "method": "Network.requestWillBeSent",
+        "params": {
+            "documentURL": "https://example.com/",
+            "requestId": "1",
+            "request": {"url": "https://example.com/", "headers": {}},
+            "initiator": {"type": "other"},
+            "wallTime": 1_600_000_000,
+        },
+    })];
+    msgs.push(main_document);
+    msgs.push(serde_json::json!({
+        "method": "Network.dataReceived",
+        "params": {"requestId": "1"},
+    }));
+    msgs.into_iter()
+        .map(|msg| serde_json::from_value(msg).unwrap())
+        .collect()
+}
+
+#[cfg(test)]
+fn response_with_status(status: u16) -> serde_json::Value {
+    serde_json::json!({
+        "method": "Network.responseReceived",
+        "params": {
+            "requestId": "1",
+            "response": {"url": "https://example.com/", "status": status},
+        },
+    })
+}
+
+#[cfg(test)]
+fn loading_failed(error_text: &str) -> serde_json::Value {
+    serde_json::json!({
+        "method": "Network.loadingFailed",
+        "params": {"requestId": "1", "errorText": error_text},
+    })
+}
+
+#[test]
+fn test_chrome_log_successful_load() {
+    assert_eq!(
+        chrome_log_contains_errors(&chrome_log(response_with_status(200))),
+        None
+    );
+}
+
+#[test]
+fn test_chrome_log_http_errors() {
+    assert_eq!(
+        chrome_log_contains_errors(&chrome_log(response_with_status(503))),
+        Some(ChromeLogError::transient(R010))
+    );
+    assert_eq!(
+        chrome_log_contains_errors(&chrome_log(response_with_status(429))),
+        Some(ChromeLogError::transient(R013))
+    );
+    assert_eq!(
+        chrome_log_contains_errors(&chrome_log(response_with_status(404))),
+        Some(ChromeLogError::permanent(R013))
+    );
+}
+
+#[test]
+fn test_chrome_log_network_errors() {
+    assert_eq!(
+        chrome_log_contains_errors(&chrome_log(loading_failed("net::ERR_NAME_NOT_RESOLVED"))),
+        Some(ChromeLogError::permanent(R012))
+    );
+    assert_eq!(
+        chrome_log_contains_errors(&chrome_log(loading_failed(
+            "net::ERR_NAME_RESOLUTION_FAILED"
+        ))),
+        Some(ChromeLogError::transient(R011))
+    );
+    assert_eq!(
+        chrome_log_contains_errors(&chrome_log(loading_failed("net::ERR_CERT_DATE_INVALID"))),
+        Some(ChromeLogError::permanent(R014))
+    );
+    // Unknown errors fall back to the generic checks, here because no response was received
+    assert_eq!(
+        chrome_log_contains_errors(&chrome_log(loading_failed("net::ERR_ABORTED"))),
+        Some(ChromeLogError::transient(R009))
+    );
+}
+
+#[test]
+fn test_chrome_log_errors_of_other_requests_are_ignored() {
+    let mut msgs = chrome_log(response_with_status(200));
+    msgs.push(
+        serde_json::from_value(serde_json::json!({
+            "method": "Network.loadingFailed",
+            "params": {"requestId": "2", "errorText": "net::ERR_NAME_NOT_RESOLVED"},
+        }))
+        .unwrap(),
+    );
+    assert_eq!(chrome_log_contains_errors(&msgs), None);
+}
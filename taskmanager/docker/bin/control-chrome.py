@@ -9,6 +9,7 @@ import typing as t
 from subprocess import DEVNULL, STDOUT
 
 import tbselenium.common as cm
+import toml
 from selenium import webdriver
 from selenium.webdriver.common.desired_capabilities import DesiredCapabilities
 from selenium.webdriver.firefox.firefox_profile import FirefoxProfile
@@ -22,6 +23,12 @@ TBB_DIR = "/opt/tor-browser_en-US"
 # Wait this many seconds after every browser event before a browser close can occur
 WEBPAGE_TOTAL_TIME = 20.0
 
+# Wait this many seconds after the page load to make sure it is really loaded
+DWELL_TIME = 5.0
+
+# Per-task configuration written by the taskmanager
+TASK_CONFIG_FILE = "/output/task.toml"
+
 DNSTAP_SOCKET = "/var/run/unbound/dnstap.sock"
 DNSTAP_FILE = "/output/website-log.dnstap"
 
@@ -56,7 +63,19 @@ PREFERENCES = {
 }
 
 
-def start_webdriver(profile_directory: t.Optional[str] = None) -> t.Any:
+def load_task_config() -> t.Dict[str, t.Any]:
+    """Return the task config, if the file exists"""
+    if not os.path.exists(TASK_CONFIG_FILE):
+        return {}
+    return toml.load(TASK_CONFIG_FILE)
+
+
+def start_webdriver(
+    profile_directory: t.Optional[str] = None,
+    user_agent: t.Optional[str] = None,
+    language: t.Optional[str] = None,
+    viewport: t.Tuple[int, int] = (1920, 1080),
+) -> t.Any:
     global PROC_TOR_PROCESS
 
     d = DesiredCapabilities.FIREFOX
@@ -70,6 +89,10 @@ def start_webdriver(profile_directory: t.Optional[str] = None) -> t.Any:
 
     for key, value in PREFERENCES.items():
         profile.set_preference(key, value)
+    if user_agent is not None:
+        profile.set_preference("general.useragent.override", user_agent)
+    if language is not None:
+        profile.set_preference("intl.accept_languages", language)
 
     if os.getenv("USE_TOR", None) is not None:
         if PROC_TOR_PROCESS:
@@ -91,15 +114,24 @@ def start_webdriver(profile_directory: t.Optional[str] = None) -> t.Any:
             log_path="/output/website-log.geckodriver.log",
         )
 
-    driver.set_window_size(1920, 1080)
+    driver.set_window_size(*viewport)
     driver.set_page_load_timeout(WEBPAGE_TOTAL_TIME)
 
     return driver
 
 
 def handle_url(url: str) -> None:
-    driver_tmp = start_webdriver()
-    driver = start_webdriver(driver_tmp.profile.path)
+    task_config = load_task_config()
+    measurement: t.Dict[str, t.Any] = task_config.get("measurement", {})
+    # The randomly chosen client profile takes precedence over the measurement settings
+    client_profile: t.Dict[str, t.Any] = task_config.get("client_profile", {})
+    user_agent = client_profile.get("user_agent", measurement.get("user_agent"))
+    language = client_profile.get("language")
+    viewport = tuple(client_profile.get("viewport", (1920, 1080)))
+    driver_tmp = start_webdriver(
+        measurement.get("browser_profile"), user_agent, language, viewport
+    )
+    driver = start_webdriver(driver_tmp.profile.path, user_agent, language, viewport)
     driver_tmp.close()
     del driver_tmp
     time.sleep(2)
@@ -108,7 +140,15 @@ def handle_url(url: str) -> None:
     before_experiment()
     driver.get(url)
     # Wait some time after the page load to make sure it is really loaded
-    time.sleep(5)
+    dwell_time = float(measurement.get("dwell_time_secs", DWELL_TIME))
+    if measurement.get("scroll", False):
+        # Scroll in steps to trigger lazily loaded content
+        steps = max(int(dwell_time), 1)
+        for _ in range(steps):
+            driver.execute_script("window.scrollBy(0, window.innerHeight);")
+            time.sleep(dwell_time / steps)
+    else:
+        time.sleep(dwell_time)
     driver.save_screenshot("/output/website-log.screenshot.png")
     after_experiment()
 
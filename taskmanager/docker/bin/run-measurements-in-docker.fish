@@ -5,6 +5,8 @@
 # * `taskname` file, containing the name of the task. This will be used to create an output directory
 # * `display` file, containing the X11 display number, see DISPLAY variable
 # * `domain` file, containing the domain to load
+# * optional `netem` file, containing the arguments for `tc qdisc add dev eth0 root netem`
+# * optional `task.toml` file, containing the measurement configuration read by control-chrome.py
 
 function start_fstrm
     set -l LOG_FILE /output/website-log.dnstap
@@ -37,6 +39,12 @@ function run
 
     set -g DNSTAP_SOCK /var/run/unbound/dnstap.sock
 
+    # Emulate the network conditions of a client
+    if [ -e netem ];
+        echo "Apply netem:" (cat netem)
+        sudo tc qdisc add dev eth0 root netem (cat netem | string split ' ')
+    end
+
     # Start fstrm_capture
     start_fstrm
     # Ports
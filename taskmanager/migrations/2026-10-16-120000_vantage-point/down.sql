@@ -0,0 +1,7 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX IF EXISTS tasks_groups;
+
+CREATE INDEX IF NOT EXISTS tasks_groups ON tasks (website, groupid);
+
+ALTER TABLE tasks
+    DROP COLUMN "vantage_point";
@@ -0,0 +1,7 @@
+-- All existing tasks were measured locally
+ALTER TABLE tasks
+    ADD COLUMN "vantage_point" text NOT NULL DEFAULT 'local';
+
+DROP INDEX IF EXISTS tasks_groups;
+
+CREATE INDEX IF NOT EXISTS tasks_groups ON tasks (website, groupid, vantage_point);
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE tasks
+    DROP COLUMN "cold_cache",
+    DROP COLUMN "cache_dump_hash";
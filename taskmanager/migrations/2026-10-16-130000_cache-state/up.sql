@@ -0,0 +1,4 @@
+-- All existing tasks used the shared cache dump, but its hash was not recorded
+ALTER TABLE tasks
+    ADD COLUMN "cold_cache" boolean NOT NULL DEFAULT false,
+    ADD COLUMN "cache_dump_hash" text;
@@ -0,0 +1,12 @@
+-- Duration of the individual processing steps of a task, e.g., the runtime of the container
+CREATE TABLE task_timings (
+    id SERIAL PRIMARY KEY,
+    task_id INTEGER NOT NULL,
+    time TIMESTAMP WITH TIME ZONE NOT NULL,
+    phase TEXT NOT NULL,
+    duration_ms BIGINT NOT NULL,
+
+    FOREIGN KEY (task_id) REFERENCES tasks(id)
+);
+
+CREATE INDEX task_timings_phase_idx ON task_timings (phase);
@@ -0,0 +1,3 @@
+-- Name of the client profile chosen for the last execution, NULL if no profiles are configured
+ALTER TABLE tasks
+    ADD COLUMN "client_profile" text;
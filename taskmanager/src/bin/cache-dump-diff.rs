@@ -0,0 +1,57 @@
+use anyhow::Error;
+use std::path::PathBuf;
+use structopt::{self, StructOpt};
+use taskmanager::cache_dump::{CacheDump, CacheDumpDiff};
+
+/// Compare two Unbound cache dumps and report which domains were added, expired, or changed
+#[derive(StructOpt, Debug)]
+#[structopt(global_settings(&[
+    structopt::clap::AppSettings::ColoredHelp,
+    structopt::clap::AppSettings::VersionlessSubcommands
+]))]
+struct CliArgs {
+    /// Print the differences as JSON
+    #[structopt(long = "json")]
+    json: bool,
+    /// The older cache dump
+    #[structopt(parse(from_os_str))]
+    old: PathBuf,
+    /// The newer cache dump
+    #[structopt(parse(from_os_str))]
+    new: PathBuf,
+}
+
+fn main() -> Result<(), Error> {
+    // generic setup
+    env_logger::init();
+    let cli_args = CliArgs::from_args();
+
+    let old = CacheDump::from_path(&cli_args.old)?;
+    let new = CacheDump::from_path(&cli_args.new)?;
+    let diff = CacheDumpDiff::new(&old, &new);
+
+    if cli_args.json {
+        println!("{}", serde_json::to_string_pretty(&diff)?);
+        return Ok(());
+    }
+
+    for domain in &diff.added {
+        println!("+ {}", domain);
+    }
+    for domain in &diff.expired {
+        println!("- {}", domain);
+    }
+    for (domain, rtype) in &diff.changed {
+        println!("~ {} {}", domain, rtype);
+    }
+    println!(
+        "\n{} domains before, {} domains after: {} added, {} expired, {} RRsets changed",
+        old.domains().len(),
+        new.domains().len(),
+        diff.added.len(),
+        diff.expired.len(),
+        diff.changed.len()
+    );
+
+    Ok(())
+}
@@ -0,0 +1,402 @@
+//! Parser for the cache dumps created by `unbound-control dump_cache`
+//!
+//! A dump consists of an RRset section and a message section.
+//! Each RRset starts with a `;rrset` header line, followed by the resource records and their signatures in zone file format.
+//! Each message starts with a `msg` line, followed by one line per referenced RRset.
+
+use anyhow::{anyhow, bail, Context as _, Error};
+use misc_utils::fs::read_to_string;
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::Path,
+    str::{FromStr, Lines},
+};
+
+/// Unbound cache dump without any entries
+pub const EMPTY_CACHE_DUMP: &str =
+    "START_RRSET_CACHE\nEND_RRSET_CACHE\nSTART_MSG_CACHE\nEND_MSG_CACHE\nEOF\n";
+
+/// Content of an Unbound cache dump
+#[derive(Clone, Eq, PartialEq, Debug, Default, Serialize)]
+pub struct CacheDump {
+    pub rrsets: Vec<RrSet>,
+    pub messages: Vec<CachedMessage>,
+}
+
+/// A cached RRset with its signatures
+#[derive(Clone, Eq, PartialEq, Debug, Serialize)]
+pub struct RrSet {
+    /// The RRset is an NSEC record at the zone apex
+    pub nsec_apex: bool,
+    pub ttl: u32,
+    pub trust: u8,
+    pub security: u8,
+    pub records: Vec<ResourceRecord>,
+    pub signatures: Vec<ResourceRecord>,
+}
+
+/// A single resource record in zone file format
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Serialize)]
+pub struct ResourceRecord {
+    pub name: String,
+    pub ttl: u32,
+    pub class: String,
+    pub rtype: String,
+    pub rdata: String,
+}
+
+/// A cached DNS response, which references the [`RrSet`]s of its sections
+#[derive(Clone, Eq, PartialEq, Debug, Serialize)]
+pub struct CachedMessage {
+    pub qname: String,
+    pub qclass: String,
+    pub qtype: String,
+    pub flags: u16,
+    pub ttl: u32,
+    pub security: u8,
+    pub answer: Vec<RrSetRef>,
+    pub authority: Vec<RrSetRef>,
+    pub additional: Vec<RrSetRef>,
+}
+
+/// Reference from a [`CachedMessage`] to an [`RrSet`]
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Serialize)]
+pub struct RrSetRef {
+    pub name: String,
+    pub class: String,
+    pub rtype: String,
+    pub flags: u32,
+}
+
+impl CacheDump {
+    pub fn from_path(path: &Path) -> Result<Self, Error> {
+        let content = read_to_string(path)
+            .with_context(|| format!("Failed to read cache dump {}", path.display()))?;
+        content
+            .parse()
+            .with_context(|| format!("Failed to parse cache dump {}", path.display()))
+    }
+
+    /// Return all owner names of the cached RRsets, in lowercase
+    pub fn domains(&self) -> BTreeSet<String> {
+        self.rrsets
+            .iter()
+            .flat_map(|rrset| &rrset.records)
+            .map(|rr| rr.name.to_ascii_lowercase())
+            .collect()
+    }
+
+    /// Return the record data of all RRsets grouped by owner name and type
+    fn records_by_name(&self) -> BTreeMap<(String, String), BTreeSet<String>> {
+        let mut res: BTreeMap<_, BTreeSet<_>> = BTreeMap::new();
+        for rr in self.rrsets.iter().flat_map(|rrset| &rrset.records) {
+            res.entry((rr.name.to_ascii_lowercase(), rr.rtype.clone()))
+                .or_default()
+                .insert(rr.rdata.clone());
+        }
+        res
+    }
+}
+
+impl FromStr for CacheDump {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut dump = CacheDump::default();
+        let mut lines = s.lines();
+        expect_line(&mut lines, "START_RRSET_CACHE")?;
+        loop {
+            let line = next_line(&mut lines)?;
+            if line == "END_RRSET_CACHE" {
+                break;
+            }
+            dump.rrsets.push(parse_rrset(line, &mut lines)?);
+        }
+        expect_line(&mut lines, "START_MSG_CACHE")?;
+        loop {
+            let line = next_line(&mut lines)?;
+            if line == "END_MSG_CACHE" {
+                break;
+            }
+            dump.messages.push(parse_message(line, &mut lines)?);
+        }
+        expect_line(&mut lines, "EOF")?;
+        Ok(dump)
+    }
+}
+
+fn next_line<'a>(lines: &mut Lines<'a>) -> Result<&'a str, Error> {
+    lines
+        .next()
+        .map(str::trim_end)
+        .ok_or_else(|| anyhow!("Unexpected end of the cache dump"))
+}
+
+fn expect_line(lines: &mut Lines<'_>, expected: &str) -> Result<(), Error> {
+    let line = next_line(lines)?;
+    if line != expected {
+        bail!("Expected `{}` but found `{}`", expected, line);
+    }
+    Ok(())
+}
+
+/// Parse the whitespace separated field `idx` of `line`
+fn field<T>(fields: &[&str], idx: usize, line: &str) -> Result<T, Error>
+where
+    T: FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    fields
+        .get(idx)
+        .ok_or_else(|| anyhow!("Missing field {} in line `{}`", idx, line))?
+        .parse()
+        .with_context(|| format!("Invalid field {} in line `{}`", idx, line))
+}
+
+/// Parse an RRset starting with the header `;rrset [nsec_apex] <ttl> <rr count> <rrsig count> <trust> <security>`
+fn parse_rrset(header: &str, lines: &mut Lines<'_>) -> Result<RrSet, Error> {
+    let mut fields: Vec<&str> = header.split_whitespace().collect();
+    if fields.first() != Some(&";rrset") {
+        bail!("Expected an RRset header but found `{}`", header);
+    }
+    let nsec_apex = fields.get(1) == Some(&"nsec_apex");
+    if nsec_apex {
+        fields.remove(1);
+    }
+    let rr_count: usize = field(&fields, 2, header)?;
+    let rrsig_count: usize = field(&fields, 3, header)?;
+
+    let mut records = Vec::with_capacity(rr_count);
+    for _ in 0..rr_count {
+        records.push(parse_resource_record(next_line(lines)?)?);
+    }
+    let mut signatures = Vec::with_capacity(rrsig_count);
+    for _ in 0..rrsig_count {
+        signatures.push(parse_resource_record(next_line(lines)?)?);
+    }
+
+    Ok(RrSet {
+        nsec_apex,
+        ttl: field(&fields, 1, header)?,
+        trust: field(&fields, 4, header)?,
+        security: field(&fields, 5, header)?,
+        records,
+        signatures,
+    })
+}
+
+/// Parse a resource record in the format `<name> <ttl> <class> <type> <rdata>`
+fn parse_resource_record(line: &str) -> Result<ResourceRecord, Error> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    if fields.len() < 4 {
+        bail!("Expected a resource record but found `{}`", line);
+    }
+    Ok(ResourceRecord {
+        name: fields[0].to_string(),
+        ttl: field(&fields, 1, line)?,
+        class: fields[2].to_string(),
+        rtype: fields[3].to_string(),
+        rdata: fields[4..].join(" "),
+    })
+}
+
+/// Parse a message starting with the header `msg <qname> <qclass> <qtype> <flags> <qdcount> <ttl> <security> <an> <ns> <ar>`
+///
+/// Newer Unbound versions append more fields, which are ignored.
+fn parse_message(header: &str, lines: &mut Lines<'_>) -> Result<CachedMessage, Error> {
+    let fields: Vec<&str> = header.split_whitespace().collect();
+    if fields.first() != Some(&"msg") || fields.len() < 11 {
+        bail!("Expected a message header but found `{}`", header);
+    }
+    let mut read_refs = |count: usize| -> Result<Vec<RrSetRef>, Error> {
+        (0..count)
+            .map(|_| {
+                let line = next_line(lines)?;
+                let fields: Vec<&str> = line.split_whitespace().collect();
+                if fields.len() != 4 {
+                    bail!("Expected an RRset reference but found `{}`", line);
+                }
+                Ok(RrSetRef {
+                    name: fields[0].to_string(),
+                    class: fields[1].to_string(),
+                    rtype: fields[2].to_string(),
+                    flags: field(&fields, 3, line)?,
+                })
+            })
+            .collect()
+    };
+    let answer = read_refs(field(&fields, 8, header)?)?;
+    let authority = read_refs(field(&fields, 9, header)?)?;
+    let additional = read_refs(field(&fields, 10, header)?)?;
+
+    Ok(CachedMessage {
+        qname: fields[1].to_string(),
+        qclass: fields[2].to_string(),
+        qtype: fields[3].to_string(),
+        flags: field(&fields, 4, header)?,
+        ttl: field(&fields, 6, header)?,
+        security: field(&fields, 7, header)?,
+        answer,
+        authority,
+        additional,
+    })
+}
+
+/// Differences between two [`CacheDump`]s
+#[derive(Clone, Eq, PartialEq, Debug, Default, Serialize)]
+pub struct CacheDumpDiff {
+    /// Domains only cached in the new dump
+    pub added: BTreeSet<String>,
+    /// Domains only cached in the old dump
+    pub expired: BTreeSet<String>,
+    /// Domains and record types cached in both dumps, but with different record data
+    pub changed: BTreeSet<(String, String)>,
+}
+
+impl CacheDumpDiff {
+    pub fn new(old: &CacheDump, new: &CacheDump) -> Self {
+        let old_domains = old.domains();
+        let new_domains = new.domains();
+        let old_records = old.records_by_name();
+        let changed = new
+            .records_by_name()
+            .into_iter()
+            .filter(|(key, rdata)| matches!(old_records.get(key), Some(old) if old != rdata))
+            .map(|(key, _)| key)
+            .collect();
+
+        Self {
+            added: new_domains.difference(&old_domains).cloned().collect(),
+            expired: old_domains.difference(&new_domains).cloned().collect(),
+            changed,
+        }
+    }
+}
+
+#[cfg(test)]
+const TEST_CACHE_DUMP: &str = "START_RRSET_CACHE
+;rrset 86400 2 0 8 0
+example.com.\t86400\tIN\tA\t93.184.216.34
+example.com.\t86400\tIN\tA\t93.184.216.35
+;rrset nsec_apex 3600 1 1 7 3
+example.org.\t3600\tIN\tNSEC\twww.example.org. A NS SOA RRSIG NSEC
+example.org.\t3600\tIN\tRRSIG\tNSEC 13 2 3600 20221001000000 20220901000000 12345 example.org. abc=
+END_RRSET_CACHE
+START_MSG_CACHE
+msg example.com. IN A 33152 1 86400 0 1 0 0 0
+example.com. IN A 0
+END_MSG_CACHE
+EOF
+";
+
+#[test]
+fn test_parse_cache_dump() {
+    let dump: CacheDump = TEST_CACHE_DUMP.parse().unwrap();
+    assert_eq!(dump.rrsets.len(), 2);
+
+    let rrset = &dump.rrsets[0];
+    assert!(!rrset.nsec_apex);
+    assert_eq!((rrset.ttl, rrset.trust, rrset.security), (86400, 8, 0));
+    assert_eq!(rrset.records.len(), 2);
+    assert_eq!(
+        rrset.records[1],
+        ResourceRecord {
+            name: "example.com.".to_string(),
+            ttl: 86400,
+            class: "IN".to_string(),
+            rtype: "A".to_string(),
+            rdata: "93.184.216.35".to_string(),
+        }
+    );
+    assert!(rrset.signatures.is_empty());
+
+    let rrset = &dump.rrsets[1];
+    assert!(rrset.nsec_apex);
+    assert_eq!((rrset.ttl, rrset.trust, rrset.security), (3600, 7, 3));
+    assert_eq!(
+        rrset.records[0].rdata,
+        "www.example.org. A NS SOA RRSIG NSEC"
+    );
+    assert_eq!(rrset.signatures.len(), 1);
+    assert_eq!(rrset.signatures[0].rtype, "RRSIG");
+
+    assert_eq!(
+        dump.messages,
+        vec![CachedMessage {
+            qname: "example.com.".to_string(),
+            qclass: "IN".to_string(),
+            qtype: "A".to_string(),
+            flags: 33152,
+            ttl: 86400,
+            security: 0,
+            answer: vec![RrSetRef {
+                name: "example.com.".to_string(),
+                class: "IN".to_string(),
+                rtype: "A".to_string(),
+                flags: 0,
+            }],
+            authority: vec![],
+            additional: vec![],
+        }]
+    );
+    assert_eq!(
+        dump.domains().into_iter().collect::<Vec<_>>(),
+        vec!["example.com.".to_string(), "example.org.".to_string()]
+    );
+
+    assert_eq!(
+        EMPTY_CACHE_DUMP.parse::<CacheDump>().unwrap(),
+        CacheDump::default()
+    );
+}
+
+#[test]
+fn test_parse_cache_dump_errors() {
+    // Missing end of the dump
+    assert!(TEST_CACHE_DUMP
+        .trim_end_matches("EOF\n")
+        .parse::<CacheDump>()
+        .is_err());
+    // The RRset announces more records than it contains
+    assert!(TEST_CACHE_DUMP
+        .replace(";rrset 86400 2 0", ";rrset 86400 3 0")
+        .parse::<CacheDump>()
+        .is_err());
+    // Invalid TTL
+    assert!(TEST_CACHE_DUMP
+        .replace("example.com.\t86400\tIN", "example.com.\tlong\tIN")
+        .parse::<CacheDump>()
+        .is_err());
+    // Truncated message header
+    assert!(TEST_CACHE_DUMP
+        .replace("msg example.com. IN A 33152", "msg example.com.")
+        .parse::<CacheDump>()
+        .is_err());
+}
+
+#[test]
+fn test_cache_dump_diff() {
+    let old: CacheDump = TEST_CACHE_DUMP.parse().unwrap();
+    let new: CacheDump = TEST_CACHE_DUMP
+        .replace("93.184.216.35", "93.184.216.36")
+        .replace("example.org.", "example.net.")
+        .parse()
+        .unwrap();
+
+    let diff = CacheDumpDiff::new(&old, &new);
+    assert_eq!(
+        diff.added.into_iter().collect::<Vec<_>>(),
+        vec!["example.net.".to_string()]
+    );
+    assert_eq!(
+        diff.expired.into_iter().collect::<Vec<_>>(),
+        vec!["example.org.".to_string()]
+    );
+    assert_eq!(
+        diff.changed.into_iter().collect::<Vec<_>>(),
+        vec![("example.com.".to_string(), "A".to_string())]
+    );
+
+    assert_eq!(CacheDumpDiff::new(&old, &old), CacheDumpDiff::default());
+}
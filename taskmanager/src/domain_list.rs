@@ -0,0 +1,403 @@
+//! Validation and normalization of the domain lists used to create tasks
+//!
+//! Typos and duplicates in a domain list otherwise only show up as failing measurements in the browser.
+//! All entries are normalized, such that internationalized domain names are converted to punycode and the same website is never measured twice.
+//!
+//! Each entry gets a class label, which is stored as the website of its tasks.
+//! Normally, the label is the domain, such that all pages of a domain belong to the same class.
+//! For fingerprinting individual pages of a site, the label is derived from the full URL instead, see [`url_label`].
+
+use anyhow::{Context as _, Error};
+use misc_utils::fs::file_write;
+use rayon::prelude::*;
+use serde::Serialize;
+use std::{collections::HashMap, net::ToSocketAddrs, path::Path};
+use url::{Host, Url};
+
+/// Maximal length of a domain name in its textual representation
+const MAX_DOMAIN_LENGTH: usize = 253;
+/// Maximal length of a single label of a domain name
+const MAX_LABEL_LENGTH: usize = 63;
+/// Maximal length of a class label, which must leave space for the suffixes of the result file names
+const MAX_CLASS_LABEL_LENGTH: usize = 200;
+
+/// A valid and normalized entry of a domain list
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DomainListEntry {
+    /// Line number in the domain list, starting at 1
+    pub line: usize,
+    /// The line as written in the domain list
+    pub input: String,
+    pub domain: String,
+    pub uri: String,
+    /// Class label of the entry, either the domain or the [`url_label`]
+    pub label: String,
+}
+
+/// An entry of a domain list which is not used to create tasks
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Reject {
+    /// Line number in the domain list, starting at 1
+    pub line: usize,
+    pub input: String,
+    pub reason: String,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ValidatedDomainList {
+    /// All valid entries in the order of the domain list
+    pub entries: Vec<DomainListEntry>,
+    /// All rejected entries in the order of the domain list
+    pub rejects: Vec<Reject>,
+}
+
+impl ValidatedDomainList {
+    /// Write the rejected entries as JSON into `path`
+    pub fn write_rejects(&self, path: &Path) -> Result<(), Error> {
+        let wtr = file_write(path)
+            .create(true)
+            .truncate()
+            .with_context(|| format!("Failed to open rejects report {}", path.display()))?;
+        serde_json::to_writer_pretty(wtr, &self.rejects)
+            .with_context(|| format!("Failed to write rejects report {}", path.display()))
+    }
+}
+
+/// Validate and normalize all `lines` of a domain list
+///
+/// Empty lines and lines starting with `#` are skipped.
+/// If `label_by_url` is set, each URI becomes its own class with the [`url_label`] as label.
+/// If `check_dns` is set, all domains which do not resolve with the system resolver are rejected, too.
+pub fn validate_domain_list(
+    lines: impl IntoIterator<Item = String>,
+    are_uris: bool,
+    label_by_url: bool,
+    check_dns: bool,
+) -> ValidatedDomainList {
+    let mut res = ValidatedDomainList::default();
+    // Map from the normalized entry to its first line
+    let mut seen: HashMap<String, usize> = HashMap::new();
+
+    for (idx, input) in lines.into_iter().enumerate() {
+        let line = idx + 1;
+        let trimmed = input.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+
+        match normalize_entry(trimmed, are_uris).and_then(|(domain, uri)| {
+            let label = if label_by_url {
+                let url = Url::parse(&uri).expect("The URI is already normalized");
+                check_class_label(url_label(&domain, &url))?
+            } else {
+                domain.clone()
+            };
+            Ok((domain, uri, label))
+        }) {
+            Ok((domain, uri, label)) => {
+                // Different URIs with the same label would end up in the same class
+                let key = if label_by_url {
+                    &label
+                } else if are_uris {
+                    &uri
+                } else {
+                    &domain
+                };
+                if let Some(first_line) = seen.get(key) {
+                    res.rejects.push(Reject {
+                        line,
+                        input,
+                        reason: format!("Duplicate of line {}", first_line),
+                    });
+                } else {
+                    seen.insert(key.clone(), line);
+                    res.entries.push(DomainListEntry {
+                        line,
+                        input,
+                        domain,
+                        uri,
+                        label,
+                    });
+                }
+            }
+            Err(reason) => res.rejects.push(Reject {
+                line,
+                input,
+                reason,
+            }),
+        }
+    }
+
+    if check_dns {
+        let resolves: Vec<bool> = res
+            .entries
+            .par_iter()
+            .map(|entry| domain_resolves(&entry.domain))
+            .collect();
+        let entries = std::mem::take(&mut res.entries);
+        for (entry, resolves) in entries.into_iter().zip(resolves) {
+            if resolves {
+                res.entries.push(entry);
+            } else {
+                res.rejects.push(Reject {
+                    line: entry.line,
+                    input: entry.input,
+                    reason: format!("The domain {} does not resolve", entry.domain),
+                });
+            }
+        }
+        res.rejects.sort_by_key(|reject| reject.line);
+    }
+
+    res
+}
+
+/// Return the normalized domain and URI of a single entry of a domain list
+fn normalize_entry(input: &str, is_uri: bool) -> Result<(String, String), String> {
+    if is_uri {
+        let url = Url::parse(input).map_err(|err| format!("Invalid URI: {}", err))?;
+        if url.scheme() != "http" && url.scheme() != "https" {
+            return Err(format!("Unsupported URI scheme {}", url.scheme()));
+        }
+        let domain = match url.host() {
+            Some(Host::Domain(domain)) => normalize_domain(domain)?,
+            Some(host) => host.to_string(),
+            None => return Err("The URI has no host".to_string()),
+        };
+        Ok((domain, url.to_string()))
+    } else {
+        let domain = normalize_domain(input)?;
+        let uri = format!("http://{}", domain);
+        Ok((domain, uri))
+    }
+}
+
+/// Class label of `url` for measuring multiple pages of the same domain as separate classes
+///
+/// The label starts with `domain`, followed by all non-empty path segments and the query, each separated by `_`.
+/// All other characters except ASCII alphanumerics, `.`, and `-` are replaced by `-`, such that the label is a valid file name.
+/// The root page of a domain has the domain as label, e.g., `example.com` and `example.com_news_index.html`.
+pub fn url_label(domain: &str, url: &Url) -> String {
+    let mut label = domain.to_string();
+    let parts = url.path_segments().into_iter().flatten().chain(url.query());
+    for part in parts.filter(|part| !part.is_empty()) {
+        label.push('_');
+        label.extend(part.chars().map(|c| {
+            if c.is_ascii_alphanumeric() || c == '.' || c == '-' {
+                c
+            } else {
+                '-'
+            }
+        }));
+    }
+    label
+}
+
+/// Reject class labels, which are too long to be used in file names
+fn check_class_label(label: String) -> Result<String, String> {
+    if label.len() > MAX_CLASS_LABEL_LENGTH {
+        return Err(format!(
+            "The label {} is longer than {} characters",
+            label, MAX_CLASS_LABEL_LENGTH
+        ));
+    }
+    Ok(label)
+}
+
+/// Convert `domain` into its lowercase ASCII form and check that it is a valid hostname
+fn normalize_domain(domain: &str) -> Result<String, String> {
+    let domain = domain.trim_end_matches('.');
+    let ascii = idna::domain_to_ascii(domain)
+        .map_err(|err| format!("Invalid internationalized domain name: {:?}", err))?;
+
+    if ascii.is_empty() {
+        return Err("Empty domain".to_string());
+    }
+    if ascii.len() > MAX_DOMAIN_LENGTH {
+        return Err(format!(
+            "The domain is longer than {} characters",
+            MAX_DOMAIN_LENGTH
+        ));
+    }
+    if !ascii.contains('.') {
+        return Err("The domain has no top-level domain".to_string());
+    }
+    for label in ascii.split('.') {
+        if label.is_empty() {
+            return Err("The domain contains an empty label".to_string());
+        }
+        if label.len() > MAX_LABEL_LENGTH {
+            return Err(format!(
+                "The label {} is longer than {} characters",
+                label, MAX_LABEL_LENGTH
+            ));
+        }
+        if !label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+            return Err(format!("The label {} contains invalid characters", label));
+        }
+        if label.starts_with('-') || label.ends_with('-') {
+            return Err(format!("The label {} starts or ends with a hyphen", label));
+        }
+    }
+    Ok(ascii)
+}
+
+/// Check if `domain` has at least one address record
+fn domain_resolves(domain: &str) -> bool {
+    (domain, 80)
+        .to_socket_addrs()
+        .map(|mut addrs| addrs.next().is_some())
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+fn lines(lines: &[&str]) -> Vec<String> {
+    lines.iter().map(|line| line.to_string()).collect()
+}
+
+#[test]
+fn test_validate_domains() {
+    let list = validate_domain_list(
+        lines(&[
+            "# Comment",
+            "Example.COM.",
+            "",
+            "bücher.example",
+            "example.com",
+            "localhost",
+            "in_valid.example",
+            "-start.example",
+        ]),
+        false,
+        false,
+        false,
+    );
+    assert_eq!(
+        vec![
+            (2, "example.com", "http://example.com"),
+            (4, "xn--bcher-kva.example", "http://xn--bcher-kva.example"),
+        ],
+        list.entries
+            .iter()
+            .map(|entry| (entry.line, &*entry.domain, &*entry.uri))
+            .collect::<Vec<_>>()
+    );
+    assert!(list.entries.iter().all(|entry| entry.label == entry.domain));
+    assert_eq!(
+        vec![5, 6, 7, 8],
+        list.rejects
+            .iter()
+            .map(|reject| reject.line)
+            .collect::<Vec<_>>()
+    );
+    assert_eq!("Duplicate of line 2", list.rejects[0].reason);
+}
+
+#[test]
+fn test_validate_uris() {
+    let list = validate_domain_list(
+        lines(&[
+            "https://Example.com/",
+            "https://example.com/news",
+            "https://example.com",
+            "ftp://example.com/",
+            "not a uri",
+        ]),
+        true,
+        false,
+        false,
+    );
+    // Different pages of the same domain are kept and share the domain as label
+    assert_eq!(
+        vec![
+            ("https://example.com/", "example.com"),
+            ("https://example.com/news", "example.com"),
+        ],
+        list.entries
+            .iter()
+            .map(|entry| (&*entry.uri, &*entry.label))
+            .collect::<Vec<_>>()
+    );
+    assert_eq!(
+        vec![3, 4, 5],
+        list.rejects
+            .iter()
+            .map(|reject| reject.line)
+            .collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn test_url_label() {
+    let label = |url: &str| {
+        let url = Url::parse(url).unwrap();
+        url_label(url.host_str().unwrap(), &url)
+    };
+    assert_eq!("example.com", label("https://example.com/"));
+    assert_eq!("example.com", label("https://example.com"));
+    assert_eq!(
+        "example.com_news_index.html",
+        label("https://example.com/news/index.html")
+    );
+    // Empty path segments are skipped
+    assert_eq!("example.com_news", label("https://example.com//news/"));
+    assert_eq!(
+        "example.com_search_q-dns-x-1",
+        label("https://example.com/search?q=dns&x=1")
+    );
+    // Characters, which are not valid in file names, are replaced, including the `%` of escapes
+    assert_eq!(
+        "example.com_a-20b_c-d",
+        label("https://example.com/a%20b/c_d")
+    );
+    // The fragment does not change the page
+    assert_eq!("example.com_news", label("https://example.com/news#top"));
+}
+
+#[test]
+fn test_validate_label_by_url() {
+    let list = validate_domain_list(
+        lines(&[
+            "https://example.com/",
+            "https://example.com/news/",
+            "https://example.com/a-b",
+            // Same label as the entries above
+            "http://example.com/news",
+            "https://example.com/a_b",
+            "https://example.com/news#top",
+            "https://example.com/",
+            // The long label does not fit into a file name
+            &format!("https://example.com/{}", "x".repeat(200)),
+        ]),
+        true,
+        true,
+        false,
+    );
+    assert_eq!(
+        vec![
+            ("https://example.com/", "example.com"),
+            ("https://example.com/news/", "example.com_news"),
+            ("https://example.com/a-b", "example.com_a-b"),
+        ],
+        list.entries
+            .iter()
+            .map(|entry| (&*entry.uri, &*entry.label))
+            .collect::<Vec<_>>()
+    );
+    assert_eq!(
+        vec![
+            (4, "Duplicate of line 2"),
+            (5, "Duplicate of line 3"),
+            (6, "Duplicate of line 2"),
+            (7, "Duplicate of line 1"),
+        ],
+        list.rejects[..4]
+            .iter()
+            .map(|reject| (reject.line, &*reject.reason))
+            .collect::<Vec<_>>()
+    );
+    assert_eq!(8, list.rejects[4].line);
+    assert!(list.rejects[4]
+        .reason
+        .starts_with("The label example.com_xxx"));
+}
@@ -11,16 +11,25 @@ use chrono::{Duration, Utc};
 use diesel::prelude::*;
 use log::info;
 use misc_utils::fs::read_to_string;
+use rand::seq::SliceRandom;
+use sequences::{
+    knn::DistanceMetric, LoadSequenceConfig, MarkerPolicy, PageLoadSegmentation,
+    DEFAULT_VANTAGE_POINT,
+};
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     fmt::{self, Debug, Display},
     path::{Path, PathBuf},
     sync::{Arc, Mutex},
 };
 
+pub mod cache_dump;
 pub mod models;
+pub mod report;
+pub mod retention;
 pub mod schema;
+pub mod store;
 
 // This createa a module called `embedded_migrations` which can then be used to run them.
 embed_migrations!("./migrations");
@@ -30,6 +39,13 @@ embed_migrations!("./migrations");
 /// The full number of tries which are executed are `MAX_RESTART_COUNT` + 1, for the initial try.
 const MAX_RESTART_COUNT: i32 = 3;
 
+/// Size of the `website_counter` and `groupid` ranges reserved for each network profile
+///
+/// Tasks of a network sweep with the profile index `i` use the ranges starting at `(i + 1) * NETWORK_PROFILE_RANGE`.
+/// The profile index 0 is the measurement without emulated network conditions and index `i > 0` is the `i`-th entry of [`Config::network_profiles`].
+/// The ranges starting at 0 are used for measurements outside of a network sweep.
+pub const NETWORK_PROFILE_RANGE: i32 = 1_000_000;
+
 type TasksColumnType = (
     schema::tasks::id,
     schema::tasks::priority,
@@ -43,6 +59,10 @@ type TasksColumnType = (
     schema::tasks::groupid,
     schema::tasks::groupsize,
     schema::tasks::uri,
+    schema::tasks::vantage_point,
+    schema::tasks::cold_cache,
+    schema::tasks::cache_dump_hash,
+    schema::tasks::client_profile,
 );
 const TASKS_COLUMNS: TasksColumnType = (
     schema::tasks::id,
@@ -57,15 +77,24 @@ const TASKS_COLUMNS: TasksColumnType = (
     schema::tasks::groupid,
     schema::tasks::groupsize,
     schema::tasks::uri,
+    schema::tasks::vantage_point,
+    schema::tasks::cold_cache,
+    schema::tasks::cache_dump_hash,
+    schema::tasks::client_profile,
 );
 
 #[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
 pub struct AddWebsiteConfig {
+    /// Class label of the tasks, which is the domain or, when measuring individual pages, a label derived from the URI
+    ///
+    /// All tasks of a class share the same result directory and are loaded with this label.
     pub(crate) website: String,
     pub(crate) website_counter: i32,
     pub(crate) groupid: i32,
     pub(crate) groupsize: u8,
     pub(crate) uri: String,
+    pub(crate) vantage_point: String,
+    pub(crate) cold_cache: bool,
 }
 
 impl AddWebsiteConfig {
@@ -84,10 +113,65 @@ impl AddWebsiteConfig {
             groupid,
             groupsize,
             uri: uri.into(),
+            vantage_point: DEFAULT_VANTAGE_POINT.to_string(),
+            cold_cache: false,
+        }
+    }
+
+    /// Create the same task group to be measured from `vantage_point`
+    pub fn for_vantage_point(&self, vantage_point: impl Into<String>) -> Self {
+        Self {
+            vantage_point: vantage_point.into(),
+            ..self.clone()
+        }
+    }
+
+    /// Create the same task group, which starts with an empty DNS cache if `cold_cache` is set
+    pub fn for_cache_state(&self, cold_cache: bool) -> Self {
+        Self {
+            cold_cache,
+            ..self.clone()
+        }
+    }
+
+    /// Create the same task group for the network profile index `profile` of a network sweep
+    ///
+    /// The `website_counter` and `groupid` are moved into the range reserved for the profile, see [`NETWORK_PROFILE_RANGE`].
+    pub fn for_network_profile(&self, profile: usize) -> Self {
+        let offset = (profile as i32 + 1) * NETWORK_PROFILE_RANGE;
+        Self {
+            website_counter: self.website_counter + offset,
+            groupid: self.groupid + offset,
+            ..self.clone()
         }
     }
 }
 
+/// Return the name of a task, which is also used as file name for its results
+///
+/// Tasks measured from a vantage point other than [`DEFAULT_VANTAGE_POINT`] get the suffix `@<vantage point>`.
+/// This is the format expected by [`sequences::vantage_point_from_path`].
+pub fn task_name(website: &str, website_counter: i32, groupid: i32, vantage_point: &str) -> String {
+    if vantage_point == DEFAULT_VANTAGE_POINT {
+        format!("{}-{}-{}", website, website_counter, groupid)
+    } else {
+        format!(
+            "{}-{}-{}@{}",
+            website, website_counter, groupid, vantage_point
+        )
+    }
+}
+
+/// Return the network profile index a task of a network sweep was measured with
+///
+/// Returns [`None`] for measurements outside of a network sweep, see [`NETWORK_PROFILE_RANGE`].
+pub fn network_profile_index(website_counter: i32) -> Option<usize> {
+    match website_counter / NETWORK_PROFILE_RANGE {
+        0 => None,
+        n => Some(n as usize - 1),
+    }
+}
+
 #[derive(Clone)]
 pub struct TaskManager {
     db_connection: Arc<Mutex<PgConnection>>,
@@ -122,8 +206,8 @@ impl TaskManager {
     pub fn delete_all(&self) -> Result<(), Error> {
         let conn = self.db_connection.lock().unwrap();
         conn.transaction::<(), _, _>(|| {
-            conn.execute("TRUNCATE TABLE infos, tasks;")
-                .context("Trying to delete tables `infos` and `tasks`")?;
+            conn.execute("TRUNCATE TABLE infos, task_timings, tasks;")
+                .context("Trying to delete tables `infos`, `task_timings`, and `tasks`")?;
             Ok(())
         })
     }
@@ -159,7 +243,12 @@ impl TaskManager {
                     let wc = config.website_counter + i32::from(i);
                     let row = models::TaskInsert {
                         priority: prio + i32::from(i) + initial_priority,
-                        name: &format!("{}-{}-{}", config.website, wc, config.groupid),
+                        name: &task_name(
+                            &config.website,
+                            wc,
+                            config.groupid,
+                            &config.vantage_point,
+                        ),
                         website: &config.website,
                         website_counter: wc,
                         state: models::TaskState::Created,
@@ -169,6 +258,8 @@ impl TaskManager {
                         groupid: config.groupid,
                         groupsize: i32::from(config.groupsize),
                         uri: &config.uri,
+                        vantage_point: &config.vantage_point,
+                        cold_cache: config.cold_cache,
                     };
                     diesel::insert_into(schema::tasks::table)
                         .values(&row)
@@ -182,8 +273,8 @@ impl TaskManager {
         })
     }
 
-    /// Return a task which waits for a VM to be executed
-    pub fn get_task_for_vm(&self) -> Result<Option<models::Task>, Error> {
+    /// Return a task of `vantage_point` which waits for a VM to be executed
+    pub fn get_task_for_vm(&self, vantage_point: &str) -> Result<Option<models::Task>, Error> {
         use crate::schema::tasks::dsl::{aborted, priority, state, tasks};
 
         let conn = self.db_connection.lock().unwrap();
@@ -191,6 +282,7 @@ impl TaskManager {
             let res = tasks
                 .filter(state.eq(models::TaskState::Created))
                 .filter(aborted.eq(false))
+                .filter(schema::tasks::vantage_point.eq(vantage_point))
                 .order_by(priority.asc())
                 .limit(1)
                 .select(TASKS_COLUMNS)
@@ -200,6 +292,14 @@ impl TaskManager {
             // we only fetch one task, so this next is sufficient to retrieve all data
             let mut task = res.into_iter().next();
             if let Some(ref mut task) = &mut task {
+                // The task waited since its creation or last restart
+                let queue_wait = Utc::now() - task.last_modified();
+                self.insert_timing(
+                    &*conn,
+                    task,
+                    models::TimingPhase::QueueWait,
+                    queue_wait.num_milliseconds(),
+                )?;
                 task.advance();
                 diesel::update(&*task)
                     .set(&*task)
@@ -210,8 +310,8 @@ impl TaskManager {
         })
     }
 
-    /// Return all tasks which did not make any progress for a too long time
-    pub fn get_stale_tasks(&self) -> Result<Vec<models::Task>, Error> {
+    /// Return all tasks of `vantage_point` which did not make any progress for a too long time
+    pub fn get_stale_tasks(&self, vantage_point: &str) -> Result<Vec<models::Task>, Error> {
         use crate::schema::tasks::dsl::{aborted, last_modified, state, tasks};
 
         let conn = self.db_connection.lock().unwrap();
@@ -221,6 +321,7 @@ impl TaskManager {
                 .filter(state.ne(models::TaskState::Done))
                 .filter(state.ne(models::TaskState::Aborted))
                 .filter(aborted.eq(false))
+                .filter(schema::tasks::vantage_point.eq(vantage_point))
                 .filter(last_modified.lt(Utc::now() - Duration::hours(2)))
                 .select(TASKS_COLUMNS)
                 .load::<models::Task>(&*conn)
@@ -242,7 +343,10 @@ impl TaskManager {
         conn.transaction(|| self.update_tasks(&*conn, Some(&*task)))
     }
 
-    pub fn results_need_sanity_check_single(&self) -> Result<Vec<models::Task>, Error> {
+    pub fn results_need_sanity_check_single(
+        &self,
+        vantage_point: &str,
+    ) -> Result<Vec<models::Task>, Error> {
         use crate::schema::tasks::dsl::{aborted, priority, state, tasks};
 
         let conn = self.db_connection.lock().unwrap();
@@ -250,6 +354,7 @@ impl TaskManager {
             tasks
                 .filter(state.eq(models::TaskState::CheckQualitySingle))
                 .filter(aborted.eq(false))
+                .filter(schema::tasks::vantage_point.eq(vantage_point))
                 .order_by(priority.asc())
                 .select(TASKS_COLUMNS)
                 .load::<models::Task>(&*conn)
@@ -270,8 +375,11 @@ impl TaskManager {
         conn.transaction(|| self.update_tasks(&conn, Some(&*task)))
     }
 
-    pub fn results_need_sanity_check_website(&self) -> Result<Option<Vec<models::Task>>, Error> {
-        use diesel::dsl::sql_query;
+    pub fn results_need_sanity_check_website(
+        &self,
+        vantage_point: &str,
+    ) -> Result<Option<Vec<models::Task>>, Error> {
+        use diesel::{dsl::sql_query, sql_types::Text};
 
         let conn = self.db_connection.lock().unwrap();
         let tasks = conn.transaction::<Vec<models::Task>, Error, _>(|| {
@@ -288,25 +396,32 @@ impl TaskManager {
                 t.associated_data,
                 t.groupid,
                 t.groupsize,
-                t.uri
+                t.uri,
+                t.vantage_point,
+                t.cold_cache,
+                t.cache_dump_hash,
+                t.client_profile
             FROM (
-                SELECT website, groupid
+                SELECT website, groupid, vantage_point
                 FROM tasks
                 WHERE state = 'check_quality_domain'
                     AND aborted = false
-                GROUP BY website, groupid
+                    AND vantage_point = $1
+                GROUP BY website, groupid, vantage_point
                 HAVING count(*) = MAX(groupsize)
                 LIMIT 1
             ) AS s
             JOIN tasks t
                 ON s.website = t.website
                AND s.groupid = t.groupid
+               AND s.vantage_point = t.vantage_point
 
             ORDER BY
                 t.website,
                 priority ASC
             ;"#,
             )
+            .bind::<Text, _>(vantage_point)
             .load::<models::Task>(&*conn)
             .context("Cannot retrieve tasks from database")
         })?;
@@ -357,6 +472,79 @@ impl TaskManager {
         })
     }
 
+    /// Return the log messages of all `tasks` ordered by time
+    pub fn get_infos(&self, tasks: &[models::Task]) -> Result<Vec<models::Info>, Error> {
+        use crate::schema::infos::dsl::{infos, task_id, time};
+
+        let ids: Vec<i32> = tasks.iter().map(models::Task::id).collect();
+        let conn = self.db_connection.lock().unwrap();
+        infos
+            .filter(task_id.eq_any(ids))
+            .order_by(time.asc())
+            .load::<models::Info>(&*conn)
+            .context("Cannot retrieve infos from database")
+    }
+
+    /// Record that `phase` of `task` took `duration`
+    pub fn record_timing(
+        &self,
+        task: &models::Task,
+        phase: models::TimingPhase,
+        duration: std::time::Duration,
+    ) -> Result<(), Error> {
+        let conn = self.db_connection.lock().unwrap();
+        self.insert_timing(&*conn, task, phase, duration.as_millis() as i64)
+    }
+
+    fn insert_timing(
+        &self,
+        conn: &PgConnection,
+        task: &models::Task,
+        phase: models::TimingPhase,
+        duration_ms: i64,
+    ) -> Result<(), Error> {
+        let row = models::TaskTimingInsert {
+            task_id: task.id(),
+            time: Utc::now(),
+            phase: phase.as_str(),
+            duration_ms,
+        };
+        diesel::insert_into(schema::task_timings::table)
+            .values(&row)
+            .execute(conn)
+            .context("Error creating new task timing")?;
+        Ok(())
+    }
+
+    /// Return the aggregated durations of all recorded [`TimingPhase`](models::TimingPhase)s
+    ///
+    /// Only timings of tasks from `vantage_point` are considered.
+    pub fn get_timing_stats(&self, vantage_point: &str) -> Result<Vec<models::TimingStats>, Error> {
+        use diesel::{dsl::sql_query, sql_types::Text};
+
+        let conn = self.db_connection.lock().unwrap();
+        sql_query(
+            r#"SELECT
+                tt.phase,
+                COUNT(*) AS count,
+                AVG(tt.duration_ms)::float8 AS mean_ms,
+                percentile_cont(0.5) WITHIN GROUP (ORDER BY tt.duration_ms) AS median_ms,
+                percentile_cont(0.95) WITHIN GROUP (ORDER BY tt.duration_ms) AS p95_ms,
+                MAX(tt.duration_ms) AS max_ms,
+                SUM(tt.duration_ms)::int8 AS total_ms
+            FROM task_timings tt
+            JOIN tasks t
+                ON tt.task_id = t.id
+            WHERE t.vantage_point = $1
+            GROUP BY tt.phase
+            ORDER BY tt.phase
+            ;"#,
+        )
+        .bind::<Text, _>(vantage_point)
+        .load::<models::TimingStats>(&*conn)
+        .context("Cannot retrieve timing statistics from database")
+    }
+
     pub fn restart_task(&self, task: &mut models::Task, reason: &dyn Display) -> Result<(), Error> {
         task.restart();
         task.associated_data = None;
@@ -381,42 +569,64 @@ impl TaskManager {
                 Ok(())
             })
         } else {
-            use crate::schema::tasks::dsl::{groupid, tasks, website};
-
             // We must abort all tasks for this website
             let msg = format!("Too many restarts for task {}, abort domain.", task.name());
-
-            conn.transaction(|| {
-                // get all tasks for the same website
-                let other_tasks = tasks
-                    .filter(website.eq(task.website()))
-                    .filter(groupid.eq(task.groupid()))
-                    .select(TASKS_COLUMNS)
-                    .load::<models::Task>(&*conn)
-                    .context("Cannot retrieve task from database")?;
-
-                for mut other_task in other_tasks {
-                    let abort_task = other_task.abort(&msg);
-                    diesel::update(&abort_task)
-                        .set(&abort_task)
-                        .execute(&*conn)
-                        .context("Cannot update task")?;
-                    let row = models::InfoInsert {
-                        id: None,
-                        task_id: other_task.id(),
-                        time: Utc::now(),
-                        message: &*msg,
-                    };
-                    diesel::insert_into(schema::infos::table)
-                        .values(&row)
-                        .execute(&*conn)
-                        .context("Error creating new task")?;
-                }
-                Ok(())
-            })
+            self.abort_group(&conn, task, &msg)
         }
     }
 
+    /// Abort all tasks of the same group as `task` without trying any restarts
+    ///
+    /// This is used for failures which are known to occur again, such as a non-existing domain.
+    pub fn abort_task_group(&self, task: &models::Task, reason: &dyn Display) -> Result<(), Error> {
+        let msg = format!(
+            "Abort domain because task {} failed: {}",
+            task.name(),
+            reason
+        );
+        let conn = self.db_connection.lock().unwrap();
+        self.abort_group(&conn, task, &msg)
+    }
+
+    fn abort_group(
+        &self,
+        conn: &PgConnection,
+        task: &models::Task,
+        msg: &str,
+    ) -> Result<(), Error> {
+        use crate::schema::tasks::dsl::{groupid, tasks, vantage_point, website};
+
+        conn.transaction(|| {
+            // get all tasks for the same website
+            let other_tasks = tasks
+                .filter(website.eq(task.website()))
+                .filter(groupid.eq(task.groupid()))
+                .filter(vantage_point.eq(task.vantage_point()))
+                .select(TASKS_COLUMNS)
+                .load::<models::Task>(conn)
+                .context("Cannot retrieve task from database")?;
+
+            for mut other_task in other_tasks {
+                let abort_task = other_task.abort(msg);
+                diesel::update(&abort_task)
+                    .set(&abort_task)
+                    .execute(conn)
+                    .context("Cannot update task")?;
+                let row = models::InfoInsert {
+                    id: None,
+                    task_id: other_task.id(),
+                    time: Utc::now(),
+                    message: msg,
+                };
+                diesel::insert_into(schema::infos::table)
+                    .values(&row)
+                    .execute(conn)
+                    .context("Error creating new task")?;
+            }
+            Ok(())
+        })
+    }
+
     pub fn restart_tasks(
         &self,
         tasks: &mut [models::Task],
@@ -434,6 +644,11 @@ impl TaskManager {
                 task.groupid(),
                 "restart_tasks only works if all tasks belong to the same groupid"
             );
+            assert_eq!(
+                tasks[0].vantage_point(),
+                task.vantage_point(),
+                "restart_tasks only works if all tasks belong to the same vantage point"
+            );
         }
 
         let mut abort_tasks = false;
@@ -498,6 +713,21 @@ impl TaskManager {
         }
     }
 
+    /// Return the state and the aborted flag of all tasks with one of the `names`
+    pub fn get_task_states(
+        &self,
+        names: &[String],
+    ) -> Result<Vec<(String, models::TaskState, bool)>, Error> {
+        use crate::schema::tasks::dsl::{aborted, name, state, tasks};
+
+        let conn = self.db_connection.lock().unwrap();
+        tasks
+            .filter(name.eq_any(names))
+            .select((name, state, aborted))
+            .load(&*conn)
+            .context("Cannot retrieve task states from database")
+    }
+
     pub fn get_domain_state(
         &self,
         websites: impl IntoIterator<Item = impl AsRef<str>>,
@@ -511,11 +741,12 @@ impl TaskManager {
                     .into_iter()
                     .map(|website| -> Result<models::WebsiteCounters, Error> {
                         let website = website.as_ref();
+                        // Only consider the ranges of measurements without a network profile
                         let res = sql_query(
                             r#"SELECT
                             website,
-                            MAX(website_counter) + 1 as website_counter,
-                            MAX(groupid) + 1 as groupid
+                            MAX(website_counter % $2) + 1 as website_counter,
+                            MAX(groupid % $2) + 1 as groupid
                         FROM tasks
                         WHERE
                             website = $1
@@ -524,6 +755,7 @@ impl TaskManager {
                         ;"#,
                         )
                         .bind::<Text, _>(website)
+                        .bind::<Integer, _>(NETWORK_PROFILE_RANGE)
                         .load::<models::WebsiteCounters>(&*conn)
                         .with_context(|| {
                             format!(
@@ -556,8 +788,6 @@ pub struct Config {
     pub database: PathBuf,
     pub per_domain_datasets: u8,
     pub per_domain_datasets_repeated_measurements: u8,
-    pub max_allowed_dist_difference: f32,
-    pub max_allowed_dist_difference_abs: usize,
     pub initial_priority: i32,
     pub num_executors: u8,
     pub refresh_cache_seconds: u32,
@@ -565,12 +795,81 @@ pub struct Config {
     pub ssh: Option<SshConfig>,
     #[serde(default)]
     pub env: Environment,
+    #[serde(default)]
+    pub docker: DockerConfig,
+    /// Network conditions under which each task group is measured in a network sweep
+    ///
+    /// The order must not change once tasks are created, since the tasks only store the index, see [`NETWORK_PROFILE_RANGE`].
+    #[serde(default)]
+    pub network_profiles: Vec<NetworkProfile>,
+    /// Pool of client profiles, from which one is chosen randomly for every execution of a task
+    ///
+    /// The name of the chosen profile is stored with the task, see [`Config::choose_client_profile`].
+    /// Profiles must not be renamed or removed while tasks still reference them.
+    #[serde(default)]
+    pub client_profiles: Vec<ClientProfile>,
+    /// Name of the location from which this instance measures, defaults to `local`
+    ///
+    /// Multiple instances at different locations can share one database.
+    /// Each instance only executes the tasks of its own vantage point.
+    /// The name becomes part of the result file names and must not contain `@`, `.`, or `/`.
+    pub vantage_point: Option<String>,
+    /// Accept dnstap files with retried marker queries or missing marker responses
+    ///
+    /// See [`MarkerPolicy::tolerant`].
+    #[serde(default)]
+    pub tolerant_markers: bool,
+    /// Remove the traffic after the initial page load, e.g., `"ChangePoint:3"`
+    ///
+    /// See [`PageLoadSegmentation`].
+    #[serde(default)]
+    pub page_load_segmentation: PageLoadSegmentation,
+    /// Browser behavior during the measurement, see [`Config::task_config`]
+    #[serde(default)]
+    pub measurement: MeasurementConfig,
+    /// Decides which measurements are restarted by the sanity check
+    #[serde(default)]
+    pub quality: QualityPolicy,
+    /// Limits for the leftover results in the directory of collected results
+    #[serde(default)]
+    pub retention: RetentionPolicy,
 }
 
 impl Config {
     pub fn try_load_config(path: &Path) -> Result<Config, Error> {
         let content = read_to_string(path).context("Cannot read config file")?;
-        Ok(toml::from_str(&content)?)
+        let config: Config = toml::from_str(&content)?;
+        check_vantage_point(config.vantage_point())?;
+        let mut names = HashSet::new();
+        for profile in &config.client_profiles {
+            if !names.insert(&profile.name) {
+                bail!("The client profile '{}' is defined twice", profile.name);
+            }
+        }
+        if config.retention.interval_minutes == Some(0) {
+            bail!("The retention interval_minutes must be at least 1");
+        }
+        Ok(config)
+    }
+
+    /// Configuration for loading the measured [`Sequence`](sequences::Sequence)s
+    pub fn load_sequence_config(&self) -> LoadSequenceConfig {
+        LoadSequenceConfig {
+            marker_policy: if self.tolerant_markers {
+                MarkerPolicy::tolerant()
+            } else {
+                MarkerPolicy::strict()
+            },
+            segmentation: self.page_load_segmentation,
+            ..LoadSequenceConfig::default()
+        }
+    }
+
+    /// Return the vantage point of this instance
+    pub fn vantage_point(&self) -> &str {
+        self.vantage_point
+            .as_deref()
+            .unwrap_or(DEFAULT_VANTAGE_POINT)
     }
 
     pub fn get_database_path(&self) -> PathBuf {
@@ -585,6 +884,11 @@ impl Config {
         self.working_directory.join("processed")
     }
 
+    /// Location of the [`ResultStore`](store::ResultStore), which holds the files in [`Config::get_results_path`]
+    pub fn get_store_path(&self) -> PathBuf {
+        self.working_directory.join("store")
+    }
+
     pub fn get_cache_file(&self) -> PathBuf {
         self.working_directory.join("cache.dump")
     }
@@ -592,6 +896,96 @@ impl Config {
     pub fn get_prefetch_file(&self) -> PathBuf {
         self.working_directory.join("alexa-top30k-eff-tlds.txt")
     }
+
+    /// Return the network conditions to emulate while measuring `task`
+    ///
+    /// Tasks outside of a network sweep use [`DockerConfig::netem`].
+    /// The tasks of a network sweep use no emulation for the profile index 0, and the configured network profile otherwise.
+    pub fn netem_for_task(&self, task: &models::Task) -> Result<Option<&NetemConfig>, Error> {
+        self.netem_for_profile(network_profile_index(task.website_counter()))
+            .with_context(|| {
+                format!(
+                    "Cannot determine the network conditions of task {}",
+                    task.name()
+                )
+            })
+    }
+
+    fn netem_for_profile(&self, profile: Option<usize>) -> Result<Option<&NetemConfig>, Error> {
+        match profile {
+            None => Ok(self.docker.netem.as_ref()),
+            Some(0) => Ok(None),
+            Some(idx) => match self.network_profiles.get(idx - 1) {
+                Some(profile) => Ok(Some(&profile.netem)),
+                None => bail!(
+                    "The network profile index {} is used, but only {} profiles are configured",
+                    idx,
+                    self.network_profiles.len()
+                ),
+            },
+        }
+    }
+
+    /// Randomly choose one of the [`Config::client_profiles`] for the next execution of `task`
+    ///
+    /// The choice is recorded in the task and stored in the database with its next state change.
+    /// Without configured profiles, the task only uses the [`MeasurementConfig`].
+    pub fn choose_client_profile(&self, task: &mut models::Task) {
+        let profile = self.client_profiles.choose(&mut rand::thread_rng());
+        task.set_client_profile(profile.map(|profile| profile.name.clone()));
+    }
+
+    /// Return the client profile recorded for `task`
+    pub fn client_profile_for_task(
+        &self,
+        task: &models::Task,
+    ) -> Result<Option<&ClientProfile>, Error> {
+        match task.client_profile() {
+            None => Ok(None),
+            Some(name) => match self.client_profiles.iter().find(|p| p.name == name) {
+                Some(profile) => Ok(Some(profile)),
+                None => bail!(
+                    "Task {} uses the client profile '{}', which is not configured",
+                    task.name(),
+                    name
+                ),
+            },
+        }
+    }
+
+    /// Render the `task.toml` file, which configures the measurement of `task` inside the container
+    ///
+    /// The file contains the task fields, the [`MeasurementConfig`], and the [`ClientProfile`] of the task.
+    /// This allows changing the measurement behavior without rebuilding the docker image.
+    /// The [`Environment`] is not part of the file, since it can contain secrets, and the container receives it as environment variables anyway.
+    pub fn task_config(&self, task: &models::Task) -> Result<String, Error> {
+        let config = TaskConfig {
+            task: TaskInfo {
+                name: task.name(),
+                website: task.website(),
+                uri: task.uri(),
+                website_counter: task.website_counter(),
+                groupid: task.groupid(),
+                vantage_point: task.vantage_point(),
+                cold_cache: task.cold_cache(),
+            },
+            measurement: &self.measurement,
+            client_profile: self.client_profile_for_task(task)?,
+        };
+        toml::to_string(&config)
+            .with_context(|| format!("Cannot render the task config of {}", task.name()))
+    }
+}
+
+/// Ensure that the vantage point can be used in a file name and parsed back from it
+pub fn check_vantage_point(vantage_point: &str) -> Result<(), Error> {
+    if vantage_point.is_empty() || vantage_point.contains(&['@', '.', '/'][..]) {
+        bail!(
+            "Invalid vantage point '{}': The name must not be empty or contain `@`, `.`, or `/`",
+            vantage_point
+        );
+    }
+    Ok(())
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -600,8 +994,318 @@ pub struct SshConfig {
     pub docker_image: String,
 }
 
+/// Resource limits and network settings for the measurement containers
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct DockerConfig {
+    /// Number of CPUs the container may use, passed as `--cpus`
+    ///
+    /// Defaults to 4 for local containers and no limit for containers started via SSH.
+    pub cpus: Option<f32>,
+    /// Memory limit of the container, e.g., `4g`, passed as `--memory`
+    pub memory: Option<String>,
+    /// Dedicated docker network for the containers, passed as `--network`
+    ///
+    /// The network is created if it does not exist yet.
+    pub network: Option<String>,
+    /// Emulate network conditions inside the container
+    pub netem: Option<NetemConfig>,
+}
+
+/// Parameters for the `netem` queueing discipline of `tc`
+///
+/// The container applies them to its outgoing interface before the measurement starts.
+#[derive(Clone, Debug, Serialize, Deserialize, Default, PartialEq)]
+pub struct NetemConfig {
+    /// Additional delay of each packet in milliseconds
+    #[serde(default)]
+    pub delay_ms: u32,
+    /// Random variation of the delay in milliseconds
+    #[serde(default)]
+    pub jitter_ms: u32,
+    /// Bandwidth limit in the format of `tc`, e.g., `10mbit`
+    pub rate: Option<String>,
+    /// Percentage of lost packets
+    #[serde(default)]
+    pub loss_percent: f32,
+}
+
+/// Named set of network conditions used for network condition sweeps
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct NetworkProfile {
+    pub name: String,
+    #[serde(flatten)]
+    pub netem: NetemConfig,
+}
+
+impl NetemConfig {
+    /// Arguments for `tc qdisc add dev <dev> root netem`
+    pub fn to_tc_args(&self) -> String {
+        let mut args = format!("delay {}ms {}ms", self.delay_ms, self.jitter_ms);
+        if let Some(rate) = &self.rate {
+            args += &format!(" rate {}", rate);
+        }
+        if self.loss_percent > 0. {
+            args += &format!(" loss {}%", self.loss_percent);
+        }
+        args
+    }
+}
+
+/// Browser behavior during a measurement
+///
+/// Unset values use the defaults of the measurement script in the docker image.
+#[derive(Clone, Debug, Serialize, Deserialize, Default, PartialEq)]
+pub struct MeasurementConfig {
+    /// Seconds to wait after the page load before the browser is closed
+    pub dwell_time_secs: Option<f32>,
+    /// Path of the browser profile inside the container
+    pub browser_profile: Option<String>,
+    /// Overwrite the user agent of the browser
+    pub user_agent: Option<String>,
+    /// Scroll to the bottom of the page during the dwell time
+    #[serde(default)]
+    pub scroll: bool,
+}
+
+/// Client-side settings of the browser, which vary between the users of a website
+///
+/// Unset values use the [`MeasurementConfig`] or the defaults of the measurement script.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct ClientProfile {
+    /// Name of the profile, which is stored in the database for each task using it
+    pub name: String,
+    /// Overwrite the user agent of the browser, takes precedence over [`MeasurementConfig::user_agent`]
+    pub user_agent: Option<String>,
+    /// Preferred languages sent in the `Accept-Language` header, e.g., `de-DE,de,en-US,en`
+    pub language: Option<String>,
+    /// Size of the browser window in pixels as `[width, height]`
+    pub viewport: Option<[u32; 2]>,
+}
+
+/// Decides which measurements of a group differ too much from the others and need to be restarted
+///
+/// The sanity check computes the median distance of each sequence to all other sequences of its group.
+/// The average of these medians is the reference value for all sequences of the group.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct QualityPolicy {
+    /// Distance metric to compare the sequences of a group
+    pub metric: DistanceMetric,
+    /// Maximal absolute difference between a median distance and the reference value
+    pub max_difference_abs: usize,
+    /// Maximal factor by which a median distance may be larger or smaller than the reference value
+    pub max_difference_factor: f32,
+    /// Groups with a reference value up to this one are always accepted
+    ///
+    /// The relative differences of very similar sequences are large, even if they are nearly identical.
+    pub min_avg_median: usize,
+    /// Groups with fewer tasks are always accepted, since a few repeated measurements do not have a meaningful reference value
+    pub min_groupsize: i32,
+    /// Always check the initial measurement with groupid 0, regardless of [`QualityPolicy::min_groupsize`]
+    pub always_check_initial: bool,
+}
+
+impl Default for QualityPolicy {
+    fn default() -> Self {
+        Self {
+            metric: DistanceMetric::EditDistance,
+            max_difference_abs: 300,
+            max_difference_factor: 2.5,
+            min_avg_median: 10,
+            min_groupsize: 10,
+            always_check_initial: true,
+        }
+    }
+}
+
+impl QualityPolicy {
+    /// Returns `true` if the group of `task` needs to be checked for outliers
+    pub fn needs_check(&self, task: &models::Task, avg_median: usize) -> bool {
+        avg_median > self.min_avg_median
+            && ((self.always_check_initial && task.groupid() == 0)
+                || task.groupsize() >= self.min_groupsize)
+    }
+
+    /// Returns `true` if the median distance `dist` differs too much from the reference value `avg_median`
+    pub fn is_outlier(&self, dist: usize, avg_median: usize) -> bool {
+        // absolute difference is too much
+        ((dist as isize) - (avg_median as isize)).abs() > self.max_difference_abs as isize ||
+        // dist is at least X times larger than avg_avg
+        dist as f32 > (avg_median as f32 * self.max_difference_factor) ||
+        // dist is at least X time smaller than avg_avg
+        (dist as f32) < (avg_median as f32 / self.max_difference_factor)
+    }
+}
+
+/// Limits for the leftover results in [`Config::get_collected_results_path`], see [`retention`]
+///
+/// Without any limits, nothing is deleted automatically.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetentionPolicy {
+    /// Delete the results of finished tasks, which were not modified for this many hours
+    pub max_age_hours: Option<u64>,
+    /// Delete the oldest results of finished tasks, while all results are larger than this many megabytes
+    pub max_size_mb: Option<u64>,
+    /// Minutes between two cleanups of the background thread, defaults to 60
+    ///
+    /// Must be at least 1.
+    pub interval_minutes: Option<u64>,
+}
+
+impl RetentionPolicy {
+    /// Returns `true` if any limit is configured
+    pub fn is_enabled(&self) -> bool {
+        self.max_age_hours.is_some() || self.max_size_mb.is_some()
+    }
+
+    /// Time between two cleanups of the background thread
+    pub fn interval(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.interval_minutes.unwrap_or(60) * 60)
+    }
+}
+
+/// Content of the `task.toml` file, see [`Config::task_config`]
+#[derive(Serialize)]
+struct TaskConfig<'a> {
+    task: TaskInfo<'a>,
+    measurement: &'a MeasurementConfig,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client_profile: Option<&'a ClientProfile>,
+}
+
+#[derive(Serialize)]
+struct TaskInfo<'a> {
+    name: &'a str,
+    website: &'a str,
+    uri: &'a str,
+    website_counter: i32,
+    groupid: i32,
+    vantage_point: &'a str,
+    cold_cache: bool,
+}
+
 #[derive(Debug, Serialize, Deserialize, Default)]
 pub struct Environment {
     #[serde(flatten)]
     pub env: HashMap<String, String>,
 }
+
+#[test]
+fn test_quality_policy_is_outlier() {
+    let policy = QualityPolicy::default();
+    assert!(!policy.is_outlier(100, 100));
+    // Within the factor of 2.5 in both directions
+    assert!(!policy.is_outlier(250, 100));
+    assert!(!policy.is_outlier(40, 100));
+    assert!(policy.is_outlier(251, 100));
+    assert!(policy.is_outlier(39, 100));
+    // The absolute difference applies even within the factor
+    assert!(!policy.is_outlier(1300, 1000));
+    assert!(policy.is_outlier(1301, 1000));
+    assert!(policy.is_outlier(699, 1000));
+}
+
+#[test]
+fn test_quality_policy_needs_check() {
+    let policy = QualityPolicy::default();
+    // Very similar sequences are always accepted
+    assert!(!policy.needs_check(&models::Task::for_test(0, 10), 10));
+    assert!(policy.needs_check(&models::Task::for_test(0, 10), 11));
+    // Small groups are only checked for the initial measurement
+    assert!(policy.needs_check(&models::Task::for_test(0, 1), 11));
+    assert!(!policy.needs_check(&models::Task::for_test(1, 9), 11));
+    assert!(policy.needs_check(&models::Task::for_test(1, 10), 11));
+
+    let policy = QualityPolicy {
+        always_check_initial: false,
+        ..Default::default()
+    };
+    assert!(!policy.needs_check(&models::Task::for_test(0, 1), 11));
+}
+
+#[test]
+fn test_quality_policy_config() {
+    let policy: QualityPolicy =
+        toml::from_str("max_difference_abs = 100\nmin_groupsize = 3").unwrap();
+    assert_eq!(
+        QualityPolicy {
+            max_difference_abs: 100,
+            min_groupsize: 3,
+            ..Default::default()
+        },
+        policy
+    );
+    // Typos must not silently fall back to the default value
+    assert!(toml::from_str::<QualityPolicy>("max_diference_abs = 100").is_err());
+}
+
+#[test]
+fn test_network_profile_index() {
+    assert_eq!(None, network_profile_index(0));
+    assert_eq!(None, network_profile_index(NETWORK_PROFILE_RANGE - 1));
+    assert_eq!(Some(0), network_profile_index(NETWORK_PROFILE_RANGE));
+    assert_eq!(
+        Some(2),
+        network_profile_index(3 * NETWORK_PROFILE_RANGE + 42)
+    );
+
+    let uri = AddWebsiteConfig::new("example.com", 42, 7, 1, "https://example.com");
+    for profile in 0..3 {
+        let copy = uri.for_network_profile(profile);
+        assert_eq!(Some(profile), network_profile_index(copy.website_counter));
+        assert_eq!(Some(profile), network_profile_index(copy.groupid));
+        assert_eq!(42, copy.website_counter % NETWORK_PROFILE_RANGE);
+        assert_eq!(7, copy.groupid % NETWORK_PROFILE_RANGE);
+    }
+}
+
+#[test]
+fn test_netem_for_profile() {
+    let config: Config = toml::from_str(
+        r#"
+working_directory = "/tmp"
+database = "postgres://taskmanager@/taskmanager"
+per_domain_datasets = 1
+per_domain_datasets_repeated_measurements = 1
+initial_priority = 1000000
+num_executors = 1
+refresh_cache_seconds = 3600
+docker_image = "dnscapture"
+
+[docker.netem]
+delay_ms = 50
+
+[[network_profiles]]
+name = "dsl"
+delay_ms = 20
+rate = "16mbit"
+"#,
+    )
+    .unwrap();
+
+    // Outside of a network sweep the default emulation applies
+    assert_eq!(
+        Some(50),
+        config.netem_for_profile(None).unwrap().map(|n| n.delay_ms)
+    );
+    // The sweep measures once without any emulation
+    assert_eq!(None, config.netem_for_profile(Some(0)).unwrap());
+    assert_eq!(
+        Some(&config.network_profiles[0].netem),
+        config.netem_for_profile(Some(1)).unwrap()
+    );
+    assert!(config.netem_for_profile(Some(2)).is_err());
+}
+
+#[test]
+fn test_netem_to_tc_args() {
+    assert_eq!("delay 0ms 0ms", NetemConfig::default().to_tc_args());
+    let netem = NetemConfig {
+        delay_ms: 80,
+        jitter_ms: 20,
+        rate: Some("4mbit".to_string()),
+        loss_percent: 1.5,
+    };
+    assert_eq!("delay 80ms 20ms rate 4mbit loss 1.5%", netem.to_tc_args());
+}
@@ -1,18 +1,24 @@
 // Enabled for usage with nightly clippy
 // #![allow(clippy::unknown_clippy_lints)]
 
+mod domain_list;
 mod utils;
 
-use crate::utils::*;
-use anyhow::{anyhow, bail, Context as _, Error};
+use crate::{
+    domain_list::{validate_domain_list, ValidatedDomainList},
+    utils::*,
+};
+use anyhow::{bail, Context as _, Error};
 use chrome::ChromeDebuggerMessage;
-use encrypted_dns::chrome_log_contains_errors;
+use encrypted_dns::{chrome_log_contains_errors, FailureKind};
 use log::{debug, error, info, warn};
 use misc_utils::fs::{file_open_read, read_to_string};
 use once_cell::sync::Lazy;
-use sequences::{sequence_stats, Sequence};
+use sequences::{
+    distance_matrix, pcap::decrypt::check_tls_keys, sequence_stats_from_distances, Sequence,
+};
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
     ffi::{OsStr, OsString},
     fmt::{self, Debug},
     fs,
@@ -22,12 +28,19 @@ use std::{
     process::{Command, Stdio},
     sync::Arc,
     thread::{self, JoinHandle},
-    time::Duration,
+    time::{Duration, Instant},
 };
 use structopt::{self, StructOpt};
-use taskmanager::{models::Task, AddWebsiteConfig, Config, TaskManager};
+use taskmanager::{
+    cache_dump::EMPTY_CACHE_DUMP,
+    check_vantage_point,
+    models::{Task, TimingPhase},
+    report::{GroupReport, OutlierReport},
+    retention::collect_garbage,
+    store::{hash_file, ResultStore, TaskManifest},
+    AddWebsiteConfig, Config, RetentionPolicy, TaskManager,
+};
 use tempfile::{Builder as TempDirBuilder, TempDir};
-use url::Url;
 
 static DNSTAP_FILE_NAME: Lazy<&'static Path> = Lazy::new(|| Path::new("website-log.dnstap.xz"));
 static LOG_FILE: Lazy<&'static Path> = Lazy::new(|| Path::new("website-log.log.xz"));
@@ -79,6 +92,29 @@ enum SubCommand {
         /// --domain argument contains full URIs instead of only domains
         #[structopt(long)]
         domains_are_uris: bool,
+        /// Measure every URI as a separate class instead of grouping the URIs by domain
+        ///
+        /// The class label consists of the domain and the path of the URI, e.g., `example.com_news_index.html`.
+        /// It is used as the website of the tasks, which determines the task groups and the result directories.
+        #[structopt(long, requires = "domains_are_uris")]
+        label_by_url: bool,
+        /// Measure each task group once without emulated network conditions and once under every network profile of the config
+        #[structopt(long)]
+        network_sweep: bool,
+        /// Create each task group for this vantage point, defaults to the vantage point of the config
+        ///
+        /// This option can be applied multiple times to measure from multiple locations.
+        #[structopt(long = "vantage-point", value_name = "name")]
+        vantage_points: Vec<String>,
+        /// Start the tasks with an empty DNS cache instead of the shared cache dump
+        #[structopt(long)]
+        cold_cache: bool,
+        /// Reject all domains which do not resolve with the system resolver
+        #[structopt(long)]
+        check_dns: bool,
+        /// Write the rejected entries of the domain list as JSON into this file
+        #[structopt(long, value_name = "FILE", parse(from_os_str))]
+        rejects: Option<PathBuf>,
     },
     /// Start executing the tasks
     #[structopt(name = "run")]
@@ -86,6 +122,18 @@ enum SubCommand {
         /// Skip the creating step of a new DNS cache and use the old existing one
         #[structopt(long)]
         skip_dns_cache_prefetching: bool,
+        /// Do not start any containers, but copy the results from the `--fixtures` directory
+        ///
+        /// All other steps, i.e., scheduling, sanity checks, and database updates, are performed as usual.
+        /// This allows testing the taskmanager locally without docker.
+        #[structopt(long, requires = "fixtures")]
+        dry_run: bool,
+        /// Directory with canned measurement results for `--dry-run`
+        ///
+        /// The directory contains the uncompressed files of a measurement, e.g., `website-log.dnstap`.
+        /// If a subdirectory with the name of the website of a task exists, the files are taken from there instead.
+        #[structopt(long, value_name = "DIR", parse(from_os_str))]
+        fixtures: Option<PathBuf>,
     },
     /// Print the CLI arguments to stdout
     #[structopt(name = "debug")]
@@ -102,6 +150,42 @@ enum SubCommand {
         /// --domain argument contains full URIs instead of only domains
         #[structopt(long)]
         domains_are_uris: bool,
+        /// Measure every URI as a separate class instead of grouping the URIs by domain
+        ///
+        /// The class label consists of the domain and the path of the URI, e.g., `example.com_news_index.html`.
+        /// It is used as the website of the tasks, which determines the task groups and the result directories.
+        #[structopt(long, requires = "domains_are_uris")]
+        label_by_url: bool,
+        /// Measure each task group once without emulated network conditions and once under every network profile of the config
+        #[structopt(long)]
+        network_sweep: bool,
+        /// Create each task group for this vantage point, defaults to the vantage point of the config
+        ///
+        /// This option can be applied multiple times to measure from multiple locations.
+        #[structopt(long = "vantage-point", value_name = "name")]
+        vantage_points: Vec<String>,
+        /// Start the tasks with an empty DNS cache instead of the shared cache dump
+        #[structopt(long)]
+        cold_cache: bool,
+    },
+    /// Check that no stored result file is corrupted
+    #[structopt(name = "verify")]
+    Verify,
+    /// Print how long the processing steps of the tasks took
+    #[structopt(name = "timings")]
+    Timings,
+    /// Delete leftover results of finished tasks from the `unprocessed` directory
+    ///
+    /// Uses the limits of the `[retention]` section of the config.
+    /// Results of tasks, which are not in the Done or Aborted state, are never deleted.
+    #[structopt(name = "gc")]
+    Gc {
+        /// Only print which results would be deleted
+        #[structopt(long)]
+        dry_run: bool,
+        /// Delete the results of all finished tasks, regardless of the configured limits
+        #[structopt(long)]
+        all: bool,
     },
 }
 
@@ -109,8 +193,24 @@ enum SubCommand {
 enum TaskStatus {
     Completed,
     Restarted,
+    /// The whole task group was aborted due to a [`PermanentFailure`]
+    Aborted,
+}
+
+/// Failure of a task which occurs again on every restart
+///
+/// Returning this error from the function passed to [`execute_or_restart_task`] aborts the whole task group immediately.
+#[derive(Debug)]
+struct PermanentFailure(String);
+
+impl fmt::Display for PermanentFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
 }
 
+impl std::error::Error for PermanentFailure {}
+
 fn path_is_file_exists(path: &OsStr) -> Result<PathBuf, OsString> {
     let path = Path::new(path);
     if !path.exists() {
@@ -147,6 +247,9 @@ fn main() -> Result<(), Error> {
         SubCommand::Run { .. } => run_exec(cli_args.cmd, config),
         SubCommand::Debug => run_debug(cli_args, config),
         SubCommand::AddRecurring { .. } => run_add_recurring(cli_args.cmd, config),
+        SubCommand::Verify => run_verify(config),
+        SubCommand::Timings => run_timings(config),
+        SubCommand::Gc { dry_run, all } => run_gc(config, *dry_run, *all),
     }
 }
 
@@ -159,7 +262,12 @@ fn run_init(cmd: SubCommand, config: Config) -> Result<(), Error> {
     if let SubCommand::InitTaskSet {
         domain_list: (mut domain_list_reader, domain_list_path),
         domains_are_uris,
-        ..
+        label_by_url,
+        network_sweep,
+        vantage_points,
+        cold_cache,
+        check_dns,
+        rejects,
     } = cmd
     {
         let taskmgr = TaskManager::new(&*config.get_database_path().to_string_lossy())
@@ -168,40 +276,58 @@ fn run_init(cmd: SubCommand, config: Config) -> Result<(), Error> {
             .run_migrations()
             .context("Error while executing migrations")?;
 
-        debug!("Read domains file");
-        let domains_or_uris = BufReader::new(&mut domain_list_reader)
-            .lines()
-            .collect::<Result<Vec<String>, std::io::Error>>()
-            .with_context(|| format!("Failed to read line in {}", domain_list_path.display()))?;
+        // Validate the whole list before touching the database
+        let domain_list = read_domain_list(
+            &mut domain_list_reader,
+            &domain_list_path,
+            domains_are_uris,
+            label_by_url,
+            check_dns,
+        )?;
+        if let Some(rejects) = &rejects {
+            domain_list.write_rejects(rejects)?;
+        }
+        if domain_list.entries.is_empty() {
+            bail!(
+                "The domain list {} contains no valid entries",
+                domain_list_path.display()
+            );
+        }
+
         info!("Empty old database entries");
         taskmgr
             .delete_all()
             .context("Empty database before filling it")?;
         info!("Add new database entries");
-        let uris: Result<Vec<_>, Error> = domains_or_uris
+        let uris: Vec<_> = domain_list
+            .entries
             .into_iter()
             .enumerate()
-            .map(|(idx, domain_or_uri)| {
-                let DomainAndUri { domain, uri } =
-                    get_domain_and_uri(domain_or_uri.clone(), domains_are_uris).ok_or_else(
-                        || {
-                            anyhow!(
-                                "Cannot get domain and URI for input value: {}",
-                                domain_or_uri
-                            )
-                        },
-                    )?;
-                Ok(AddWebsiteConfig::new(
-                    domain,
+            .map(|(idx, entry)| {
+                // URIs of the same domain form separate task groups, unless each URI is its own class anyway
+                AddWebsiteConfig::new(
+                    entry.label,
                     0,
-                    if domains_are_uris { idx as _ } else { 0 },
+                    if domains_are_uris && !label_by_url {
+                        idx as _
+                    } else {
+                        0
+                    },
                     config.per_domain_datasets,
-                    uri,
-                ))
+                    entry.uri,
+                )
+                .for_cache_state(cold_cache)
             })
             .collect();
         taskmgr
-            .add_uris(uris?, config.initial_priority)
+            .add_uris(
+                with_vantage_points(
+                    with_network_profiles(uris, network_sweep, &config)?,
+                    &vantage_points,
+                    &config,
+                )?,
+                config.initial_priority,
+            )
             .context("Could not create tasks")?;
     } else {
         unreachable!("The run function verifies which enum variant this is.")
@@ -213,6 +339,8 @@ fn run_init(cmd: SubCommand, config: Config) -> Result<(), Error> {
 fn run_exec(cmd: SubCommand, config: Config) -> Result<(), Error> {
     if let SubCommand::Run {
         skip_dns_cache_prefetching,
+        dry_run,
+        fixtures,
     } = cmd
     {
         let taskmgr = TaskManager::new(&*config.get_database_path().to_string_lossy())
@@ -223,12 +351,25 @@ fn run_exec(cmd: SubCommand, config: Config) -> Result<(), Error> {
             bail!("You need to specify at least one executor.");
         }
 
+        if dry_run {
+            let fixtures = fixtures.expect("The CLI parser ensures the fixtures exist.");
+            return run_exec_dry_run(taskmgr, config, fixtures);
+        }
+
         if let Some(ssh_config) = &config.ssh {
             ensure_docker_image_exists_ssh(&ssh_config.remote_name, &config.docker_image)
                 .context("Check for docker image")?
         } else {
             ensure_docker_image_exists(&config.docker_image).context("Check for docker image")?;
         }
+        if let Some(network) = &config.docker.network {
+            // The cache dump is always created locally
+            ensure_docker_network_exists(network).context("Check for docker network")?;
+            if let Some(ssh_config) = &config.ssh {
+                ensure_docker_network_exists_ssh(&ssh_config.remote_name, network)
+                    .context("Check for docker network")?;
+            }
+        }
 
         init_global_environment(&config, skip_dns_cache_prefetching)
             .context("Could not setup the global environment")?;
@@ -257,12 +398,21 @@ fn run_exec(cmd: SubCommand, config: Config) -> Result<(), Error> {
                 Some("Sanity Check Single".to_string()),
             ));
             let taskmgr_ = taskmgr.clone();
+            let config_ = config.clone();
             handles.push(run_thread_restart(
-                move || result_sanity_checks_domain(&taskmgr_, &config),
+                move || result_sanity_checks_domain(&taskmgr_, &config_),
                 Some("Sanity Check Domain".to_string()),
             ));
+            if config.retention.is_enabled() {
+                let taskmgr_ = taskmgr.clone();
+                let config_ = config.clone();
+                handles.push(run_thread_restart(
+                    move || background_collect_garbage(&taskmgr_, &config_),
+                    Some("Retention".to_string()),
+                ));
+            }
             handles.push(run_thread_restart(
-                move || cleanup_stale_tasks(&taskmgr),
+                move || cleanup_stale_tasks(&taskmgr, &config),
                 Some("Cleanup stale tasks".to_string()),
             ));
         }
@@ -277,6 +427,69 @@ fn run_exec(cmd: SubCommand, config: Config) -> Result<(), Error> {
     Ok(())
 }
 
+/// Same as [`run_exec`] but uses [`process_tasks_dry_run`] instead of the docker executors
+///
+/// The Unbound cache dump is never updated, as this requires docker.
+/// If no cache dump exists yet, an empty one is used.
+fn run_exec_dry_run(
+    taskmgr: TaskManager,
+    config: Arc<Config>,
+    fixtures: PathBuf,
+) -> Result<(), Error> {
+    if !fixtures.is_dir() {
+        bail!(
+            "The fixtures directory {} does not exist.",
+            fixtures.display()
+        );
+    }
+    info!(
+        "Dry run: Copy the measurement results from {} instead of running containers",
+        fixtures.display()
+    );
+    if !config.get_cache_file().exists() {
+        ensure_path_exists(&config.working_directory)?;
+        fs::write(config.get_cache_file(), EMPTY_CACHE_DUMP)
+            .context("Failed to create an empty cache.dump")?;
+    }
+    let fixtures = Arc::new(fixtures);
+
+    let mut handles = Vec::new();
+
+    for i in 0..config.num_executors {
+        let taskmgr_ = taskmgr.clone();
+        let config_ = config.clone();
+        let fixtures_ = fixtures.clone();
+        handles.push(run_thread_restart(
+            move || process_tasks_dry_run(&taskmgr_, &config_, &fixtures_),
+            Some(format!("Dry Run Executor {}", i)),
+        ));
+    }
+
+    {
+        let taskmgr_ = taskmgr.clone();
+        let config_ = config.clone();
+        handles.push(run_thread_restart(
+            move || result_sanity_checks(&taskmgr_, &config_),
+            Some("Sanity Check Single".to_string()),
+        ));
+        let taskmgr_ = taskmgr.clone();
+        let config_ = config.clone();
+        handles.push(run_thread_restart(
+            move || result_sanity_checks_domain(&taskmgr_, &config_),
+            Some("Sanity Check Domain".to_string()),
+        ));
+        handles.push(run_thread_restart(
+            move || cleanup_stale_tasks(&taskmgr, &config),
+            Some("Cleanup stale tasks".to_string()),
+        ));
+    }
+
+    for handle in handles {
+        let _ = handle.join();
+    }
+    Ok(())
+}
+
 // The function signature is required to be compatible with the other run_* functions
 #[allow(clippy::needless_pass_by_value, clippy::unnecessary_wraps)]
 fn run_debug(args: CliArgs, config: Config) -> Result<(), Error> {
@@ -285,62 +498,131 @@ fn run_debug(args: CliArgs, config: Config) -> Result<(), Error> {
     Ok(())
 }
 
+/// Verify the hashes of all files in the [`ResultStore`]
+#[allow(clippy::needless_pass_by_value)]
+fn run_verify(config: Config) -> Result<(), Error> {
+    let store = ResultStore::new(config.get_store_path());
+    let tasks = store.tasks()?;
+    let mut corrupted = 0;
+    for task in &tasks {
+        if let Err(err) = store.verify_task(task) {
+            error!("{:?}", err);
+            corrupted += 1;
+        }
+    }
+    info!("Verified {} tasks", tasks.len());
+    if corrupted > 0 {
+        bail!("{} out of {} tasks are corrupted", corrupted, tasks.len());
+    }
+    Ok(())
+}
+
+#[allow(clippy::needless_pass_by_value)]
+fn run_gc(config: Config, dry_run: bool, all: bool) -> Result<(), Error> {
+    let taskmgr = TaskManager::new(&*config.get_database_path().to_string_lossy())
+        .context("Cannot create TaskManager")?;
+    let policy = if all {
+        RetentionPolicy {
+            max_age_hours: Some(0),
+            ..RetentionPolicy::default()
+        }
+    } else if config.retention.is_enabled() {
+        config.retention.clone()
+    } else {
+        bail!("The config has no retention limits. Configure them in the `[retention]` section or use --all.");
+    };
+    let report = collect_garbage(&taskmgr, &config, &policy, dry_run)?;
+    println!("{}", report);
+    Ok(())
+}
+
+#[allow(clippy::needless_pass_by_value)]
+fn run_timings(config: Config) -> Result<(), Error> {
+    let taskmgr = TaskManager::new(&*config.get_database_path().to_string_lossy())
+        .context("Cannot create TaskManager")?;
+    let stats = taskmgr.get_timing_stats(config.vantage_point())?;
+    println!(
+        "{:<22} {:>8} {:>10} {:>10} {:>10} {:>10} {:>12}",
+        "phase", "count", "mean (s)", "median (s)", "p95 (s)", "max (s)", "total (h)"
+    );
+    for stat in stats {
+        println!(
+            "{:<22} {:>8} {:>10.1} {:>10.1} {:>10.1} {:>10.1} {:>12.2}",
+            stat.phase,
+            stat.count,
+            stat.mean_ms / 1000.,
+            stat.median_ms / 1000.,
+            stat.p95_ms / 1000.,
+            stat.max_ms as f64 / 1000.,
+            stat.total_ms as f64 / 3_600_000.,
+        );
+    }
+    Ok(())
+}
+
 #[allow(clippy::needless_pass_by_value)]
 fn run_add_recurring(cmd: SubCommand, config: Config) -> Result<(), Error> {
     let config = &config;
     if let SubCommand::AddRecurring {
         domain_list: (mut domain_list_reader, domain_list_path),
         domains_are_uris,
-        ..
+        label_by_url,
+        network_sweep,
+        vantage_points,
+        cold_cache,
     } = cmd
     {
         let taskmgr = TaskManager::new(&*config.get_database_path().to_string_lossy())
             .context("Cannot create TaskManager")?;
 
-        debug!("Read domains file");
-        let domains_or_uris = BufReader::new(&mut domain_list_reader)
-            .lines()
-            .collect::<Result<Vec<String>, std::io::Error>>()
-            .with_context(|| format!("Failed to read line in {}", domain_list_path.display()))?;
-
-        let uris_per_domain = domains_or_uris.into_iter().try_fold(
-            HashMap::new(),
-            |mut uris_per_domain: HashMap<String, Vec<String>>,
-             domain_or_uri|
-             -> Result<_, Error> {
-                let DomainAndUri { domain, uri } =
-                    get_domain_and_uri(domain_or_uri.clone(), domains_are_uris).ok_or_else(
-                        || {
-                            anyhow!(
-                                "Cannot get domain and URI for input value: {}",
-                                domain_or_uri
-                            )
-                        },
-                    )?;
-                uris_per_domain.entry(domain).or_default().push(uri);
-                Ok(uris_per_domain)
-            },
+        let domain_list = read_domain_list(
+            &mut domain_list_reader,
+            &domain_list_path,
+            domains_are_uris,
+            label_by_url,
+            false,
         )?;
+        if domain_list.entries.is_empty() {
+            bail!(
+                "The domain list {} contains no valid entries",
+                domain_list_path.display()
+            );
+        }
+        let mut uris_per_domain: HashMap<String, Vec<String>> = HashMap::new();
+        for entry in domain_list.entries {
+            uris_per_domain
+                .entry(entry.label)
+                .or_default()
+                .push(entry.uri);
+        }
 
         let website_state = taskmgr
             .get_domain_state(uris_per_domain.keys())
             .context("Failed to retrieve the domainstate")?;
+        let uris = website_state
+            .into_iter()
+            .zip(uris_per_domain.values())
+            .flat_map(|(mut wc, uris)| {
+                uris.iter().map(move |uri| {
+                    let res = wc
+                        .clone()
+                        .into_add_website_config(
+                            config.per_domain_datasets_repeated_measurements,
+                            uri.clone(),
+                        )
+                        .for_cache_state(cold_cache);
+                    // Generate unique IDs for each URL set
+                    wc.groupid += 1;
+                    res
+                })
+            });
         taskmgr
             .add_uris(
-                website_state
-                    .into_iter()
-                    .zip(uris_per_domain.values())
-                    .flat_map(|(mut wc, uris)| {
-                        uris.iter().map(move |uri| {
-                            let res = wc.clone().into_add_website_config(
-                                config.per_domain_datasets_repeated_measurements,
-                                uri.clone(),
-                            );
-                            // Generate unique IDs for each URL set
-                            wc.groupid += 1;
-                            res
-                        })
-                    }),
+                with_vantage_points(
+                    with_network_profiles(uris, network_sweep, config)?,
+                    &vantage_points,
+                    config,
+                )?,
                 0,
             )
             .context("Failed to add repeated domains tasks")?;
@@ -350,6 +632,59 @@ fn run_add_recurring(cmd: SubCommand, config: Config) -> Result<(), Error> {
     Ok(())
 }
 
+/// Replace each task group by a copy for every network profile index, if `network_sweep` is set
+///
+/// The first copy is measured without emulated network conditions, see [`NETWORK_PROFILE_RANGE`](taskmanager::NETWORK_PROFILE_RANGE).
+/// The copies of one task group directly follow each other, such that they are executed at a similar time.
+fn with_network_profiles(
+    uris: impl IntoIterator<Item = AddWebsiteConfig>,
+    network_sweep: bool,
+    config: &Config,
+) -> Result<Vec<AddWebsiteConfig>, Error> {
+    if !network_sweep {
+        return Ok(uris.into_iter().collect());
+    }
+    if config.network_profiles.is_empty() {
+        bail!("A network sweep requires at least one network profile in the config.");
+    }
+    let profiles = config.network_profiles.len();
+    Ok(uris
+        .into_iter()
+        .flat_map(|uri| {
+            (0..=profiles)
+                .map(|idx| uri.for_network_profile(idx))
+                .collect::<Vec<_>>()
+        })
+        .collect())
+}
+
+/// Create each task group for all `vantage_points`
+///
+/// Without any explicit vantage points, the vantage point of the config is used.
+fn with_vantage_points(
+    uris: Vec<AddWebsiteConfig>,
+    vantage_points: &[String],
+    config: &Config,
+) -> Result<Vec<AddWebsiteConfig>, Error> {
+    if vantage_points.is_empty() {
+        return Ok(uris
+            .iter()
+            .map(|uri| uri.for_vantage_point(config.vantage_point()))
+            .collect());
+    }
+    for vantage_point in vantage_points {
+        check_vantage_point(vantage_point)?;
+    }
+    Ok(uris
+        .iter()
+        .flat_map(|uri| {
+            vantage_points
+                .iter()
+                .map(move |vantage_point| uri.for_vantage_point(vantage_point.clone()))
+        })
+        .collect())
+}
+
 /// Make function execution in threads persistent
 ///
 /// This is a small wrapper around `thread::spawn`, which ensures that if a thread panics or the
@@ -390,7 +725,7 @@ fn process_tasks_docker(taskmgr: &TaskManager, config: &Config) -> Result<(), Er
     }
 
     loop {
-        if let Some(mut task) = taskmgr.get_task_for_vm()? {
+        if let Some(mut task) = taskmgr.get_task_for_vm(config.vantage_point())? {
             let _taskstatus = execute_or_restart_task(&mut task, taskmgr, |mut task| {
                 let tmp_dir = TempDirBuilder::new().prefix("docker").tempdir()?;
                 info!(
@@ -402,47 +737,34 @@ fn process_tasks_docker(taskmgr: &TaskManager, config: &Config) -> Result<(), Er
 
                 debug!("{}: Copy initial files to mount point", task.name());
                 // Write all the required files to the mount point
-                fs::copy(config.get_cache_file(), tmp_dir.path().join("cache.dump"))
-                    .with_context(|| format!("{}: Failed to copy cache.dump", task.name()))?;
+                write_cache_dump(tmp_dir.path(), config, task)
+                    .with_context(|| format!("{}: Failed to write cache.dump", task.name()))?;
                 fs::write(tmp_dir.path().join("domain"), &task.uri())
                     .with_context(|| format!("{}: Failed to create file `domain`", task.name()))?;
+                config.choose_client_profile(task);
+                fs::write(tmp_dir.path().join("task.toml"), config.task_config(task)?)
+                    .with_context(|| {
+                        format!("{}: Failed to create file `task.toml`", task.name())
+                    })?;
+                write_netem_file(tmp_dir.path(), config.netem_for_task(task)?)
+                    .with_context(|| format!("{}: Failed to configure netem", task.name()))?;
 
                 debug!("{}: Run docker container", task.name());
+                let start = Instant::now();
                 let _status = docker_run(
                     &config.docker_image,
                     tmp_dir.path(),
                     None,
                     Duration::new(60, 0),
                     &config.env.env,
+                    &config.docker,
                 )
                 .with_context(|| format!("{}: Failed to start the measurements", task.name()))?;
+                record_timing(taskmgr, task, TimingPhase::Docker, start);
                 debug!("{}: Copy files from mount point to local back", task.name());
-                let local_path: PathBuf = config.get_collected_results_path().join(task.name());
-                ensure_path_exists(&local_path)?;
-
-                for (fname, required) in &[
-                    (&*DNSTAP_FILE_NAME, true),
-                    (&*LOG_FILE, true),
-                    (&*CHROME_LOG_FILE_NAME, false),
-                    (&*PCAP_FILE_NAME, true),
-                    (&*TIMING_FILE_NAME, true),
-                    (&*TLSKEYS_FILE_NAME, false),
-                ] {
-                    // strip the .xz extension
-                    let fname = fname.with_extension("");
-                    let status = fs::copy(tmp_dir.path().join(&fname), local_path.join(&fname))
-                        .with_context(|| {
-                            format!(
-                                "{}: Failed to copy back file {}",
-                                task.name(),
-                                fname.display()
-                            )
-                        });
-                    // Throw error if file is required but copy failed
-                    if *required {
-                        status?;
-                    }
-                }
+                let start = Instant::now();
+                copy_measurement_results(tmp_dir.path(), config, task)?;
+                record_timing(taskmgr, task, TimingPhase::CopyBack, start);
                 tmp_dir.close()?;
 
                 debug!("Finished task {} ({})", task.name(), task.id());
@@ -455,12 +777,91 @@ fn process_tasks_docker(taskmgr: &TaskManager, config: &Config) -> Result<(), Er
     }
 }
 
+/// Copy the uncompressed measurement results from `dir` into the collected results of `task`
+fn copy_measurement_results(dir: &Path, config: &Config, task: &Task) -> Result<(), Error> {
+    let local_path: PathBuf = config.get_collected_results_path().join(task.name());
+    ensure_path_exists(&local_path)?;
+
+    for (fname, required) in &[
+        (&*DNSTAP_FILE_NAME, true),
+        (&*LOG_FILE, true),
+        (&*CHROME_LOG_FILE_NAME, false),
+        (&*PCAP_FILE_NAME, true),
+        (&*TIMING_FILE_NAME, true),
+        (&*TLSKEYS_FILE_NAME, false),
+    ] {
+        // strip the .xz extension
+        let fname = fname.with_extension("");
+        let status = fs::copy(dir.join(&fname), local_path.join(&fname)).with_context(|| {
+            format!(
+                "{}: Failed to copy back file {}",
+                task.name(),
+                fname.display()
+            )
+        });
+        // Throw error if file is required but copy failed
+        if *required {
+            status?;
+        }
+    }
+    Ok(())
+}
+
+/// Same as [`process_tasks_docker`] but copies canned results from `fixtures` instead of running a container
+///
+/// The cache dump and `task.toml` are still created, such that configuration errors surface and the task records the same cache dump hash.
+fn process_tasks_dry_run(
+    taskmgr: &TaskManager,
+    config: &Config,
+    fixtures: &Path,
+) -> Result<(), Error> {
+    loop {
+        if let Some(mut task) = taskmgr.get_task_for_vm(config.vantage_point())? {
+            let _taskstatus = execute_or_restart_task(&mut task, taskmgr, |mut task| {
+                let tmp_dir = TempDirBuilder::new().prefix("dry-run").tempdir()?;
+                info!(
+                    "Process task {} ({}), step Dry Run, tmp dir {}",
+                    task.name(),
+                    task.id(),
+                    tmp_dir.path().display()
+                );
+
+                write_cache_dump(tmp_dir.path(), config, task)
+                    .with_context(|| format!("{}: Failed to write cache.dump", task.name()))?;
+                config.choose_client_profile(task);
+                fs::write(tmp_dir.path().join("task.toml"), config.task_config(task)?)
+                    .with_context(|| {
+                        format!("{}: Failed to create file `task.toml`", task.name())
+                    })?;
+                tmp_dir.close()?;
+
+                let website_fixtures = fixtures.join(task.website());
+                let fixtures = if website_fixtures.is_dir() {
+                    website_fixtures.as_path()
+                } else {
+                    fixtures
+                };
+                debug!("{}: Copy fixtures from {}", task.name(), fixtures.display());
+                let start = Instant::now();
+                copy_measurement_results(fixtures, config, task)?;
+                record_timing(taskmgr, task, TimingPhase::CopyBack, start);
+
+                debug!("Finished task {} ({})", task.name(), task.id());
+                taskmgr.finished_task_for_vm(&mut task)
+            })?;
+        } else {
+            info!("No tasks left for Dry Run");
+            thread::sleep(Duration::new(10, 0));
+        }
+    }
+}
+
 /// Same as [`process_tasks_docker`] but runs the container on a remote maschine
 fn process_tasks_docker_ssh(taskmgr: &TaskManager, config: &Config) -> Result<(), Error> {
     let ssh = config.ssh.as_ref().unwrap();
 
     loop {
-        if let Some(mut task) = taskmgr.get_task_for_vm()? {
+        if let Some(mut task) = taskmgr.get_task_for_vm(config.vantage_point())? {
             let _taskstatus = execute_or_restart_task(&mut task, taskmgr, |mut task| {
                 let tmp_dir = TempDirBuilder::new().prefix("docker").tempdir()?;
                 info!(
@@ -498,17 +899,26 @@ fn process_tasks_docker_ssh(taskmgr: &TaskManager, config: &Config) -> Result<()
 
                 debug!("{}: Copy initial files to mount point", task.name());
                 // Write all the required files to the mount point
-                fs::copy(config.get_cache_file(), tmp_dir.path().join("cache.dump"))
-                    .with_context(|| format!("{}: Failed to copy cache.dump", task.name()))?;
+                write_cache_dump(tmp_dir.path(), config, task)
+                    .with_context(|| format!("{}: Failed to write cache.dump", task.name()))?;
                 fs::write(tmp_dir.path().join("domain"), &task.uri())
                     .with_context(|| format!("{}: Failed to create file `domain`", task.name()))?;
+                config.choose_client_profile(task);
+                fs::write(tmp_dir.path().join("task.toml"), config.task_config(task)?)
+                    .with_context(|| {
+                        format!("{}: Failed to create file `task.toml`", task.name())
+                    })?;
+                let netem_file = write_netem_file(tmp_dir.path(), config.netem_for_task(task)?)
+                    .with_context(|| format!("{}: Failed to configure netem", task.name()))?;
                 // Copy files from local temp dir to remote temp dir
-                // Call scp -pr <local_tmp>/cache.dump <local_tmp>/domain <host>:<remote_tmp>
+                // Call scp -pr <local_tmp>/cache.dump <local_tmp>/domain <local_tmp>/task.toml [<local_tmp>/netem] <host>:<remote_tmp>
                 // Unfortunatly scp does not support globbing on the local site
                 let status = Command::new("scp")
                     .arg("-pr")
                     .arg(tmp_dir.path().join("cache.dump"))
                     .arg(tmp_dir.path().join("domain"))
+                    .arg(tmp_dir.path().join("task.toml"))
+                    .args(netem_file)
                     .arg(format!("{}:{}", ssh.remote_name, remote_tmp_dir))
                     .stdout(Stdio::null())
                     .stderr(Stdio::null())
@@ -524,6 +934,7 @@ fn process_tasks_docker_ssh(taskmgr: &TaskManager, config: &Config) -> Result<()
                 }
 
                 debug!("{}: Run docker container", task.name());
+                let start = Instant::now();
                 let _status = docker_run_ssh(
                     &ssh.remote_name,
                     &ssh.docker_image,
@@ -531,11 +942,12 @@ fn process_tasks_docker_ssh(taskmgr: &TaskManager, config: &Config) -> Result<()
                     None,
                     Duration::new(60, 0),
                     &config.env.env,
+                    &config.docker,
                 )
                 .with_context(|| format!("{}: Failed to start the measurements", task.name()))?;
+                record_timing(taskmgr, task, TimingPhase::Docker, start);
                 debug!("{}: Copy files from mount point to local back", task.name());
-                let local_path: PathBuf = config.get_collected_results_path().join(task.name());
-                ensure_path_exists(&local_path)?;
+                let start = Instant::now();
 
                 // Copy all files from remote temp dir to local temp dir
                 let status = Command::new("scp")
@@ -571,29 +983,8 @@ fn process_tasks_docker_ssh(taskmgr: &TaskManager, config: &Config) -> Result<()
                     bail!("{}: Cannot create delete temporary directory: ssh has exited with error {}", task.name(), status.code().unwrap_or(-1))
                 };
 
-                for (fname, required) in &[
-                    (&*DNSTAP_FILE_NAME, true),
-                    (&*LOG_FILE, true),
-                    (&*CHROME_LOG_FILE_NAME, false),
-                    (&*PCAP_FILE_NAME, true),
-                    (&*TIMING_FILE_NAME, true),
-                    (&*TLSKEYS_FILE_NAME, false),
-                ] {
-                    // strip the .xz extension
-                    let fname = fname.with_extension("");
-                    let status = fs::copy(tmp_dir.path().join(&fname), local_path.join(&fname))
-                        .with_context(|| {
-                            format!(
-                                "{}: Failed to copy back file {}",
-                                task.name(),
-                                fname.display()
-                            )
-                        });
-                    // Throw error if file is required but copy failed
-                    if *required {
-                        status?;
-                    }
-                }
+                copy_measurement_results(tmp_dir.path(), config, task)?;
+                record_timing(taskmgr, task, TimingPhase::CopyBack, start);
                 tmp_dir.close()?;
 
                 debug!("Finished task {} ({})", task.name(), task.id());
@@ -606,11 +997,21 @@ fn process_tasks_docker_ssh(taskmgr: &TaskManager, config: &Config) -> Result<()
     }
 }
 
+/// Enforce the [`RetentionPolicy`] of the config on the collected results
+fn background_collect_garbage(taskmgr: &TaskManager, config: &Config) -> Result<(), Error> {
+    loop {
+        let report = collect_garbage(taskmgr, config, &config.retention, false)
+            .context("Failed to enforce the retention policy")?;
+        info!("Retention: {}", report);
+        thread::sleep(config.retention.interval());
+    }
+}
+
 /// Cleanup stale tasks by resetting them
-fn cleanup_stale_tasks(taskmgr: &TaskManager) -> Result<(), Error> {
+fn cleanup_stale_tasks(taskmgr: &TaskManager, config: &Config) -> Result<(), Error> {
     loop {
         let tasks = taskmgr
-            .get_stale_tasks()
+            .get_stale_tasks(config.vantage_point())
             .context("Failed to get stale tasks")?;
         for mut task in tasks {
             taskmgr.restart_task(&mut task, &"Restart stale task")?;
@@ -626,9 +1027,10 @@ fn result_sanity_checks(taskmgr: &TaskManager, config: &Config) -> Result<(), Er
     let local_path = config.get_collected_results_path();
 
     loop {
-        let tasks = taskmgr.results_need_sanity_check_single()?;
+        let tasks = taskmgr.results_need_sanity_check_single(config.vantage_point())?;
         for mut task in tasks {
             execute_or_restart_task(&mut task, taskmgr, |mut task| {
+                let start = Instant::now();
                 // compress files to save space
                 for entry in fs::read_dir(local_path.join(task.name()))? {
                     let entry = entry?;
@@ -645,9 +1047,10 @@ fn result_sanity_checks(taskmgr: &TaskManager, config: &Config) -> Result<(), Er
                 // if a file is loadable, it passes all easy sanity checks
                 let dnstap_file = local_path.join(task.name()).join(&*DNSTAP_FILE_NAME);
                 if dnstap_file.exists() {
-                    Sequence::from_path(&dnstap_file).with_context(|| {
-                        format!("DNSTAP file is not loadable for task {}.", task.name())
-                    })?;
+                    Sequence::from_path_with_config(&dnstap_file, config.load_sequence_config())
+                        .with_context(|| {
+                            format!("DNSTAP file is not loadable for task {}.", task.name())
+                        })?;
                 }
 
                 // if a file is loadable, it passes all easy sanity checks
@@ -658,6 +1061,27 @@ fn result_sanity_checks(taskmgr: &TaskManager, config: &Config) -> Result<(), Er
                     })?;
                 }
 
+                // Keys from the wrong TLS session cannot decrypt the DNS over TLS connections
+                let tlskeys_file = local_path.join(task.name()).join(&*TLSKEYS_FILE_NAME);
+                if pcap_file.exists() && tlskeys_file.exists() {
+                    let check =
+                        check_tls_keys(&pcap_file, &tlskeys_file, None).with_context(|| {
+                            format!("Cannot check the TLS keys of task {}.", task.name())
+                        })?;
+                    if !check.keys_match() {
+                        bail!(
+                            "Fail task {} ({}) because the TLS keys do not match the PCAP: {} connections, {} with missing secrets, {} with wrong secrets, {} of {} DNS messages invalid",
+                            task.name(),
+                            task.id(),
+                            check.connections,
+                            check.missing_secrets,
+                            check.wrong_secrets,
+                            check.invalid_dns_messages,
+                            check.dns_messages,
+                        );
+                    }
+                }
+
                 let chrome_log = local_path.join(task.name()).join(&*CHROME_LOG_FILE_NAME);
                 // The log does not exist with the selenium driver
                 if chrome_log.exists() {
@@ -669,16 +1093,21 @@ fn result_sanity_checks(taskmgr: &TaskManager, config: &Config) -> Result<(), Er
                         .with_context(|| {
                             format!("Error while deserializing '{}'", chrome_log.display())
                         })?;
-                    if let Some(err_reason) = chrome_log_contains_errors(&msgs) {
-                        bail!(
+                    if let Some(err) = chrome_log_contains_errors(&msgs) {
+                        let msg = format!(
                             "Fail task {} ({}) due to chrome log: {}",
                             task.name(),
                             task.id(),
-                            err_reason
+                            err
                         );
+                        match err.kind {
+                            FailureKind::Transient => bail!(msg),
+                            FailureKind::Permanent => return Err(PermanentFailure(msg).into()),
+                        }
                     }
                 }
 
+                record_timing(taskmgr, task, TimingPhase::CheckQualitySingle, start);
                 taskmgr.mark_results_checked_single(&mut task)
             })?;
         }
@@ -696,6 +1125,34 @@ fn init_global_environment(config: &Config, skip_dns_cache_prefetching: bool) ->
     Ok(())
 }
 
+/// Write the cache dump for `task` into `dir` and record its hash in the task
+///
+/// Tasks with a cold cache get an empty cache dump.
+fn write_cache_dump(dir: &Path, config: &Config, task: &mut Task) -> Result<(), Error> {
+    let path = dir.join("cache.dump");
+    if task.cold_cache() {
+        fs::write(&path, EMPTY_CACHE_DUMP)?;
+    } else {
+        fs::copy(config.get_cache_file(), &path)?;
+    }
+    task.set_cache_dump_hash(hash_file(&path)?.hash);
+    Ok(())
+}
+
+/// Record the duration of `phase` since `start`
+///
+/// The timings are only used for statistics, so a failure is logged but does not affect the task.
+fn record_timing(taskmgr: &TaskManager, task: &Task, phase: TimingPhase, start: Instant) {
+    if let Err(err) = taskmgr.record_timing(task, phase, start.elapsed()) {
+        warn!(
+            "{}: Cannot record timing of phase {}: {:#}",
+            task.name(),
+            phase,
+            err
+        );
+    }
+}
+
 fn execute_or_restart_task<F>(
     task: &mut Task,
     taskmgr: &TaskManager,
@@ -707,8 +1164,13 @@ where
     let res = func(task);
     if let Err(err) = res {
         warn!("{}", err);
-        taskmgr.restart_task(task, &err)?;
-        Ok(TaskStatus::Restarted)
+        if err.downcast_ref::<PermanentFailure>().is_some() {
+            taskmgr.abort_task_group(task, &err)?;
+            Ok(TaskStatus::Aborted)
+        } else {
+            taskmgr.restart_task(task, &err)?;
+            Ok(TaskStatus::Restarted)
+        }
     } else {
         Ok(TaskStatus::Completed)
     }
@@ -718,11 +1180,12 @@ where
 fn result_sanity_checks_domain(taskmgr: &TaskManager, config: &Config) -> Result<(), Error> {
     let local_path = config.get_collected_results_path();
     let results_path = config.get_results_path();
+    let store = ResultStore::new(config.get_store_path());
 
     loop {
         ensure_path_exists(&results_path)?;
 
-        let tasks = taskmgr.results_need_sanity_check_website()?;
+        let tasks = taskmgr.results_need_sanity_check_website(config.vantage_point())?;
         if tasks.is_none() {
             info!("No tasks for sanity check domains");
             thread::sleep(Duration::new(10, 0));
@@ -732,22 +1195,37 @@ fn result_sanity_checks_domain(taskmgr: &TaskManager, config: &Config) -> Result
         let mut tasks = tasks.unwrap();
         info!("Sanity check domains: '{}'", tasks[0].name());
 
+        let start = Instant::now();
         let sequences: Vec<_> = tasks
             .iter()
             .map(|task| {
-                Sequence::from_path(&local_path.join(task.name()).join(&*DNSTAP_FILE_NAME))
-                    .expect("Loading a DNSTAP file cannot fail, as we checked that before.")
+                Sequence::from_path_with_config(
+                    &local_path.join(task.name()).join(&*DNSTAP_FILE_NAME),
+                    config.load_sequence_config(),
+                )
+                .expect("Loading a DNSTAP file cannot fail, as we checked that before.")
             })
             .collect();
 
+        let policy = &config.quality;
+        // The distances are calculated once and shared by the quality check and the reports
+        let distances = distance_matrix(&sequences, &sequences, policy.metric);
+        let (_, median_distances, _, avg_median) =
+            sequence_stats_from_distances(&sequences, &sequences, &distances);
+        // Loading the sequences and computing the distances dominates the time of the group check
+        record_timing(taskmgr, &tasks[0], TimingPhase::CheckQualityDomain, start);
+
         let mark_domain_good = |tasks: &mut Vec<Task>| -> Result<(), Error> {
             info!("Sanity check domain: Marked Good: '{}'", tasks[0].name());
             //everything is fine, advance the tasks to next stage
+            let mut files = Vec::with_capacity(tasks.len());
             for task in &*tasks {
                 let outdir = results_path.join(task.website());
                 ensure_path_exists(&outdir)?;
 
                 let old_task_dir = local_path.join(task.name());
+                let mut manifest = TaskManifest::new(task.name(), task.website());
+                let mut task_files = BTreeMap::new();
 
                 for (filename, new_file_ext, required) in &[
                     (&*DNSTAP_FILE_NAME, "dnstap.xz", true),
@@ -763,14 +1241,25 @@ fn result_sanity_checks_domain(taskmgr: &TaskManager, config: &Config) -> Result
                         task.name(),
                         new_file_ext
                     ));
-                    let status = fs::rename(&src, &dst).with_context(|| {
-                        format!("Failed to move {} to {}", src.display(), dst.display())
-                    });
+                    // Store the file content-addressed and keep the old layout as a hard link
+                    let status = store
+                        .insert(&src)
+                        .and_then(|entry| {
+                            store.link(&entry, &dst)?;
+                            manifest.files.insert(new_file_ext.to_string(), entry);
+                            task_files.insert(new_file_ext.to_string(), dst.clone());
+                            Ok(())
+                        })
+                        .with_context(|| {
+                            format!("Failed to store {} as {}", src.display(), dst.display())
+                        });
                     // Throw error if file is required but copy failed
                     if *required {
                         status?;
                     }
                 }
+                store.write_manifest(&manifest)?;
+                files.push(task_files);
                 fs::remove_dir(&old_task_dir).with_context(|| {
                     format!(
                         "Could not remove old task directory {}",
@@ -782,58 +1271,87 @@ fn result_sanity_checks_domain(taskmgr: &TaskManager, config: &Config) -> Result
             taskmgr
                 .mark_results_checked_website(tasks)
                 .context("Failed to mark domain tasks as finished.")?;
+
+            // The group is finished already, so a missing report is not a reason to fail
+            let report = taskmgr.get_infos(tasks).and_then(|infos| {
+                GroupReport::new(
+                    tasks,
+                    &sequences,
+                    policy.metric,
+                    distances.clone(),
+                    &median_distances,
+                    avg_median,
+                    infos,
+                    files,
+                )
+                .write(&results_path.join(tasks[0].website()))
+            });
+            match report {
+                Ok(path) => info!("Wrote group report {}", path.display()),
+                Err(err) => warn!(
+                    "Cannot write group report for domain '{}': {:#}",
+                    tasks[0].website(),
+                    err
+                ),
+            }
             Ok(())
         };
 
-        let (_, median_distances, _, avg_median) = sequence_stats(&sequences, &sequences);
-
-        let is_bad_dist = |dist| {
-            // absolute difference is too much
-            ((dist as isize) - (avg_median as isize)).abs() > config.max_allowed_dist_difference_abs as isize ||
-            // dist is at least X times larger than avg_avg
-            dist as f32 > (avg_median as f32 * config.max_allowed_dist_difference) ||
-            // dist is at least X time smaller than avg_avg
-            (dist as f32) < (avg_median as f32 / config.max_allowed_dist_difference)
+        let is_bad_dist = |dist| policy.is_outlier(dist, avg_median);
+
+        let write_outlier_report = |tasks: &[Task], outliers: &[bool], reason: &str| {
+            let report = OutlierReport::new(
+                tasks,
+                policy.metric,
+                distances.clone(),
+                &median_distances,
+                avg_median,
+                outliers,
+                reason,
+            );
+            match report.write(&results_path.join(tasks[0].website())) {
+                Ok(path) => info!("Wrote outlier report {}", path.display()),
+                Err(err) => warn!(
+                    "Cannot write outlier report for domain '{}': {:#}",
+                    tasks[0].website(),
+                    err
+                ),
+            }
         };
 
         // if there is only a single bad value, only restart that
         // if there are multiple bad values, restart whole domain
 
-        if avg_median <= 10 {
-            mark_domain_good(&mut tasks)?;
-            continue;
-        }
-
-        // Only do this for the initial measurement with groupid 0 or for measurements of sufficient size.
+        // Only do this for the initial measurement with groupid 0 or for measurements of sufficient size, see `QualityPolicy`.
         // It does not make sense to do this for the repeated measurements with a single or two requests each.
-        if tasks[0].groupid() == 0 || tasks[0].groupsize() >= 10 {
-            match median_distances
+        if policy.needs_check(&tasks[0], avg_median) {
+            let outliers: Vec<bool> = median_distances
                 .iter()
-                .filter(|dist| is_bad_dist(**dist))
-                .count()
-            {
+                .map(|dist| is_bad_dist(*dist))
+                .collect();
+            match outliers.iter().filter(|is_bad| **is_bad).count() {
                 0 => {
                     mark_domain_good(&mut tasks)?;
                 }
                 1 => {
                     // restart the single bad task
-                    let (dist, mut task) = median_distances
+                    let (dist, idx) = median_distances
                         .iter()
-                        .zip(tasks.iter_mut())
-                        .find(|(dist, _task)| is_bad_dist(**dist))
+                        .zip(0..)
+                        .find(|(dist, _idx)| is_bad_dist(**dist))
                         .expect("There is exactly one task");
+                    let reason = format!(
+                        "The task's distance is {} while the average distance is only {}",
+                        dist, avg_median
+                    );
+                    write_outlier_report(&tasks, &outliers, &reason);
+                    let task = &mut tasks[idx];
                     info!(
                         "Restart task {} because of distance difference",
                         task.name()
                     );
                     taskmgr
-                        .restart_task(
-                            &mut task,
-                            &format!(
-                                "The task's distance is {} while the average distance is only {}",
-                                dist, avg_median
-                            ),
-                        )
+                        .restart_task(task, &reason)
                         .context("Cannot restart single bad task")?;
                 }
                 n => {
@@ -843,14 +1361,13 @@ fn result_sanity_checks_domain(taskmgr: &TaskManager, config: &Config) -> Result
                         tasks[0].website(),
                         tasks[0].groupid(),
                     );
+                    let reason = format!(
+                        "{} out of {} differ by too much from the average distance",
+                        n, config.per_domain_datasets
+                    );
+                    write_outlier_report(&tasks, &outliers, &reason);
                     taskmgr
-                        .restart_tasks(
-                            &mut *tasks,
-                            &format!(
-                                "{} out of {} differ by too much from the average distance",
-                                n, config.per_domain_datasets
-                            ),
-                        )
+                        .restart_tasks(&mut *tasks, &reason)
                         .context("Cannot restart bad domain")?;
                 }
             }
@@ -881,6 +1398,7 @@ fn update_unbound_cache_dump(config: &Config) -> Result<(), Error> {
         Some("/usr/bin/create-cache-dump.fish"),
         Duration::new(120, 0),
         &config.env.env,
+        &config.docker,
     )
     .context("Failed to run docker image to create a cache dump")?;
     if !status.success() {
@@ -910,22 +1428,34 @@ fn background_update_unbound_cache_dump(config: &Config) -> Result<(), Error> {
     }
 }
 
-struct DomainAndUri {
-    domain: String,
-    uri: String,
-}
-
-/// Get a string representing the domain and the full URI
-fn get_domain_and_uri(domain_or_uri: String, is_uri: bool) -> Option<DomainAndUri> {
-    Some(if is_uri {
-        DomainAndUri {
-            domain: Url::parse(&domain_or_uri).ok()?.host_str()?.to_string(),
-            uri: domain_or_uri,
-        }
-    } else {
-        DomainAndUri {
-            uri: format!("http://{}", domain_or_uri),
-            domain: domain_or_uri,
-        }
-    })
+/// Read and validate the domain list, see [`validate_domain_list`]
+///
+/// All rejected entries are logged.
+fn read_domain_list(
+    reader: &mut dyn Read,
+    path: &Path,
+    are_uris: bool,
+    label_by_url: bool,
+    check_dns: bool,
+) -> Result<ValidatedDomainList, Error> {
+    debug!("Read domains file");
+    let domains_or_uris = BufReader::new(reader)
+        .lines()
+        .collect::<Result<Vec<String>, std::io::Error>>()
+        .with_context(|| format!("Failed to read line in {}", path.display()))?;
+
+    let domain_list = validate_domain_list(domains_or_uris, are_uris, label_by_url, check_dns);
+    for reject in &domain_list.rejects {
+        warn!(
+            "Reject line {} '{}': {}",
+            reject.line, reject.input, reject.reason
+        );
+    }
+    info!(
+        "Accepted {} entries, rejected {} entries of {}",
+        domain_list.entries.len(),
+        domain_list.rejects.len(),
+        path.display()
+    );
+    Ok(domain_list)
 }
@@ -1,11 +1,14 @@
 #![allow(proc_macro_derive_resolution_fallback)]
 
 use crate::{
-    schema::{infos, tasks},
+    schema::{infos, task_timings, tasks},
     AddWebsiteConfig,
 };
 use chrono::{DateTime, Utc};
+use diesel::sql_types::{BigInt, Double, Text};
 use diesel_derive_enum::DbEnum;
+use sequences::DEFAULT_VANTAGE_POINT;
+use std::fmt::{self, Display};
 
 #[derive(Identifiable, Queryable, AsChangeset, QueryableByName, Debug, PartialEq, Eq)]
 #[changeset_options(treat_none_as_null = "true")]
@@ -27,9 +30,36 @@ pub struct Task {
     groupid: i32,
     groupsize: i32,
     uri: String,
+    vantage_point: String,
+    cold_cache: bool,
+    cache_dump_hash: Option<String>,
+    client_profile: Option<String>,
 }
 
 impl Task {
+    /// Create a task of the group `groupid` with `groupsize` tasks for tests
+    #[cfg(test)]
+    pub(crate) fn for_test(groupid: i32, groupsize: i32) -> Self {
+        Self {
+            id: 1,
+            priority: 0,
+            name: format!("example.com-{}-0", groupid),
+            website: "example.com".to_string(),
+            website_counter: 0,
+            state: TaskState::Created,
+            restart_count: 0,
+            last_modified: Utc::now(),
+            associated_data: None,
+            groupid,
+            groupsize,
+            uri: "https://example.com".to_string(),
+            vantage_point: DEFAULT_VANTAGE_POINT.to_string(),
+            cold_cache: false,
+            cache_dump_hash: None,
+            client_profile: None,
+        }
+    }
+
     #[inline]
     pub fn id(&self) -> i32 {
         self.id
@@ -60,6 +90,12 @@ impl Task {
         self.restart_count
     }
 
+    /// Time of the last state change of the task
+    #[inline]
+    pub fn last_modified(&self) -> DateTime<Utc> {
+        self.last_modified
+    }
+
     pub(crate) fn advance(&mut self) {
         self.state.advance();
         self.last_modified = Utc::now();
@@ -96,6 +132,44 @@ impl Task {
     pub fn uri(&self) -> &str {
         &self.uri
     }
+
+    /// Name of the location from which the task is measured
+    #[inline]
+    pub fn vantage_point(&self) -> &str {
+        &self.vantage_point
+    }
+
+    /// The task starts with an empty DNS cache instead of the shared cache dump
+    #[inline]
+    pub fn cold_cache(&self) -> bool {
+        self.cold_cache
+    }
+
+    /// BLAKE3 hash of the cache dump used for the last execution of the task
+    #[inline]
+    pub fn cache_dump_hash(&self) -> Option<&str> {
+        self.cache_dump_hash.as_deref()
+    }
+
+    /// Record the hash of the cache dump used for the execution
+    ///
+    /// The hash is stored in the database together with the next state change of the task.
+    pub fn set_cache_dump_hash(&mut self, hash: String) {
+        self.cache_dump_hash = Some(hash);
+    }
+
+    /// Name of the client profile used for the last execution of the task
+    #[inline]
+    pub fn client_profile(&self) -> Option<&str> {
+        self.client_profile.as_deref()
+    }
+
+    /// Record the client profile used for the execution
+    ///
+    /// The name is stored in the database together with the next state change of the task.
+    pub fn set_client_profile(&mut self, name: Option<String>) {
+        self.client_profile = name;
+    }
 }
 
 #[derive(Identifiable, AsChangeset, Debug, PartialEq, Eq)]
@@ -121,6 +195,8 @@ pub struct TaskInsert<'a> {
     pub groupid: i32,
     pub groupsize: i32,
     pub uri: &'a str,
+    pub vantage_point: &'a str,
+    pub cold_cache: bool,
 }
 
 #[derive(Debug, PartialEq, Eq, Copy, Clone, DbEnum)]
@@ -176,6 +252,81 @@ pub struct InfoInsert<'a> {
     pub message: &'a str,
 }
 
+/// A log message of a task, e.g., the reason for a restart
+#[derive(Identifiable, Queryable, Associations, Debug, PartialEq, Eq)]
+#[belongs_to(Task)]
+#[table_name = "infos"]
+pub struct Info {
+    pub id: i32,
+    pub task_id: i32,
+    pub time: DateTime<Utc>,
+    pub message: String,
+}
+
+/// Processing steps of a task whose duration is recorded in the `task_timings` table
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum TimingPhase {
+    /// Time between the creation or restart of a task and the start of the execution
+    QueueWait,
+    /// Runtime of the measurement container
+    Docker,
+    /// Copying the results out of the container
+    CopyBack,
+    /// Checking the results of a single task
+    CheckQualitySingle,
+    /// Checking the results of a whole group, recorded only for the first task of the group
+    CheckQualityDomain,
+}
+
+impl TimingPhase {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TimingPhase::QueueWait => "queue_wait",
+            TimingPhase::Docker => "docker",
+            TimingPhase::CopyBack => "copy_back",
+            TimingPhase::CheckQualitySingle => "check_quality_single",
+            TimingPhase::CheckQualityDomain => "check_quality_domain",
+        }
+    }
+}
+
+impl Display for TimingPhase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Insertable, Associations, Debug, PartialEq, Eq)]
+#[belongs_to(Task)]
+#[table_name = "task_timings"]
+pub struct TaskTimingInsert<'a> {
+    pub task_id: i32,
+    pub time: DateTime<Utc>,
+    pub phase: &'a str,
+    pub duration_ms: i64,
+}
+
+/// Aggregated durations of a single [`TimingPhase`] over all recorded tasks
+#[derive(Clone, Debug, PartialEq, QueryableByName)]
+pub struct TimingStats {
+    /// Name of the phase, see [`TimingPhase::as_str`]
+    #[sql_type = "Text"]
+    pub phase: String,
+    #[sql_type = "BigInt"]
+    pub count: i64,
+    #[sql_type = "Double"]
+    pub mean_ms: f64,
+    #[sql_type = "Double"]
+    pub median_ms: f64,
+    #[sql_type = "Double"]
+    pub p95_ms: f64,
+    #[sql_type = "BigInt"]
+    pub max_ms: i64,
+    /// Sum of all durations, which shows where the most time is spent overall
+    #[sql_type = "BigInt"]
+    pub total_ms: i64,
+}
+
 #[derive(Clone, Debug, QueryableByName)]
 #[table_name = "tasks"]
 pub struct WebsiteCounters {
@@ -192,6 +343,8 @@ impl WebsiteCounters {
             groupid: self.groupid,
             groupsize,
             uri,
+            vantage_point: DEFAULT_VANTAGE_POINT.to_string(),
+            cold_cache: false,
         }
     }
 }
@@ -0,0 +1,212 @@
+//! Summaries of measurement groups
+//!
+//! After a group passed the quality check, a [`GroupReport`] is written next to the result files of the group.
+//! It gives immediate feedback about the quality of the measurements without querying the database or loading the sequences again.
+//!
+//! If the quality check restarts tasks, an [`OutlierReport`] with the distances of the group is written instead.
+//! `scripts/outlier_matrix.py` plots the distance matrix with the outliers highlighted.
+
+use crate::models::{Info, Task};
+use anyhow::{Context as _, Error};
+use chrono::{DateTime, Utc};
+use misc_utils::fs::file_write;
+use sequences::{knn::DistanceMetric, Sequence};
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// Summary of all tasks of a single group
+#[derive(Debug, Serialize)]
+pub struct GroupReport {
+    pub website: String,
+    pub groupid: i32,
+    pub vantage_point: String,
+    pub created: DateTime<Utc>,
+    pub tasks: Vec<TaskReport>,
+    /// Average of the median distances of all tasks, which is the reference value of the quality check
+    pub avg_median_distance: usize,
+    /// Distance metric of all distances in the report
+    pub metric: DistanceMetric,
+    /// Distances between all pairs of sequences, in the same order as `tasks`
+    pub distances: Vec<Vec<usize>>,
+}
+
+/// Summary of a single task of a [`GroupReport`]
+#[derive(Debug, Serialize)]
+pub struct TaskReport {
+    pub name: String,
+    /// Number of attempts which were dropped and restarted before this successful one
+    pub restart_count: i32,
+    pub sequence_length: usize,
+    pub message_count: usize,
+    pub complexity: usize,
+    /// Median distance to the other sequences of the group
+    pub median_distance: usize,
+    /// Log messages of the task, e.g., the reasons for restarts
+    pub infos: Vec<InfoReport>,
+    /// Mapping from the file type, e.g., `dnstap.xz`, to the path of the result file
+    pub files: BTreeMap<String, PathBuf>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct InfoReport {
+    pub time: DateTime<Utc>,
+    pub message: String,
+}
+
+impl GroupReport {
+    /// Create the report for the `tasks` of a group
+    ///
+    /// `sequences`, `median_distances`, and `files` contain one entry per task in the same order as `tasks`.
+    /// `distances` is the [`distance_matrix`](sequences::distance_matrix) of the `sequences`.
+    pub fn new(
+        tasks: &[Task],
+        sequences: &[Sequence],
+        metric: DistanceMetric,
+        distances: Vec<Vec<usize>>,
+        median_distances: &[usize],
+        avg_median_distance: usize,
+        infos: Vec<Info>,
+        files: Vec<BTreeMap<String, PathBuf>>,
+    ) -> Self {
+        assert_eq!(tasks.len(), sequences.len());
+        assert_eq!(tasks.len(), distances.len());
+        assert_eq!(tasks.len(), median_distances.len());
+        assert_eq!(tasks.len(), files.len());
+
+        let mut infos_by_task: HashMap<i32, Vec<InfoReport>> = HashMap::new();
+        for info in infos {
+            infos_by_task
+                .entry(info.task_id)
+                .or_default()
+                .push(InfoReport {
+                    time: info.time,
+                    message: info.message,
+                });
+        }
+
+        let task_reports = tasks
+            .iter()
+            .zip(sequences)
+            .zip(median_distances)
+            .zip(files)
+            .map(|(((task, sequence), &median_distance), files)| TaskReport {
+                name: task.name().to_string(),
+                restart_count: task.restart_count(),
+                sequence_length: sequence.len(),
+                message_count: sequence.message_count(),
+                complexity: sequence.complexity(),
+                median_distance,
+                infos: infos_by_task.remove(&task.id()).unwrap_or_default(),
+                files,
+            })
+            .collect();
+
+        Self {
+            website: tasks[0].website().to_string(),
+            groupid: tasks[0].groupid(),
+            vantage_point: tasks[0].vantage_point().to_string(),
+            created: Utc::now(),
+            tasks: task_reports,
+            avg_median_distance,
+            metric,
+            distances,
+        }
+    }
+
+    /// Write the report as JSON file into `dir` and return the path of the file
+    pub fn write(&self, dir: &Path) -> Result<PathBuf, Error> {
+        let path = dir.join(format!(
+            "{}-{}-{}.report.json",
+            self.website, self.groupid, self.vantage_point
+        ));
+        write_json(&path, self)?;
+        Ok(path)
+    }
+}
+
+/// Distances within a group whose tasks are restarted by the quality check
+#[derive(Debug, Serialize)]
+pub struct OutlierReport {
+    pub website: String,
+    pub groupid: i32,
+    pub vantage_point: String,
+    pub created: DateTime<Utc>,
+    /// The restart reason
+    pub reason: String,
+    /// Names of the tasks in the same order as all other lists
+    pub tasks: Vec<String>,
+    /// Median distance of each task to the other sequences of the group
+    pub median_distances: Vec<usize>,
+    /// Average of the median distances, which is the reference value of the quality check
+    pub avg_median_distance: usize,
+    /// `true` for each task whose median distance differs too much from the average
+    pub outliers: Vec<bool>,
+    /// Distance metric of all distances in the report
+    pub metric: DistanceMetric,
+    /// Distances between all pairs of sequences
+    pub distances: Vec<Vec<usize>>,
+}
+
+impl OutlierReport {
+    /// Create the report for the `tasks` of a group
+    ///
+    /// `distances`, `median_distances`, and `outliers` contain one entry per task in the same order as `tasks`.
+    pub fn new(
+        tasks: &[Task],
+        metric: DistanceMetric,
+        distances: Vec<Vec<usize>>,
+        median_distances: &[usize],
+        avg_median_distance: usize,
+        outliers: &[bool],
+        reason: impl Into<String>,
+    ) -> Self {
+        assert_eq!(tasks.len(), distances.len());
+        assert_eq!(tasks.len(), median_distances.len());
+        assert_eq!(tasks.len(), outliers.len());
+
+        Self {
+            website: tasks[0].website().to_string(),
+            groupid: tasks[0].groupid(),
+            vantage_point: tasks[0].vantage_point().to_string(),
+            created: Utc::now(),
+            reason: reason.into(),
+            tasks: tasks.iter().map(|task| task.name().to_string()).collect(),
+            median_distances: median_distances.to_vec(),
+            avg_median_distance,
+            outliers: outliers.to_vec(),
+            metric,
+            distances,
+        }
+    }
+
+    /// Write the report as JSON file into `dir` and return the path of the file
+    ///
+    /// Each restart creates a new file, such that the reports of earlier restarts are kept.
+    pub fn write(&self, dir: &Path) -> Result<PathBuf, Error> {
+        fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create directory {}", dir.display()))?;
+        let path = dir.join(format!(
+            "{}-{}-{}.outliers-{}.json",
+            self.website,
+            self.groupid,
+            self.vantage_point,
+            self.created.format("%Y%m%dT%H%M%S")
+        ));
+        write_json(&path, self)?;
+        Ok(path)
+    }
+}
+
+fn write_json(path: &Path, value: &impl Serialize) -> Result<(), Error> {
+    let wtr = file_write(path)
+        .create(true)
+        .truncate()
+        .with_context(|| format!("Failed to open report {}", path.display()))?;
+    serde_json::to_writer_pretty(wtr, value)
+        .with_context(|| format!("Failed to write report {}", path.display()))?;
+    Ok(())
+}
@@ -0,0 +1,285 @@
+//! Delete leftover measurement results from the directory of collected results
+//!
+//! The results of a task stay in [`Config::get_collected_results_path`] until the group sanity check moves them into the [`ResultStore`](crate::store::ResultStore).
+//! Results of aborted tasks, or of tasks whose move failed, are never removed and the directory grows without bounds.
+//! The [`RetentionPolicy`] bounds the age and the total size of the directory.
+//! Only results of tasks in the [`TaskState::Done`] or [`TaskState::Aborted`] state are deleted, since all other tasks might still need them.
+
+use crate::{models::TaskState, Config, RetentionPolicy, TaskManager};
+use anyhow::{Context as _, Error};
+use log::{info, warn};
+use std::{
+    collections::HashMap,
+    fmt::{self, Display},
+    fs, io,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+/// Outcome of a single run of [`collect_garbage`]
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct GcReport {
+    /// Number of deleted task directories
+    pub deleted: usize,
+    /// Size of all deleted task directories in bytes
+    pub freed_bytes: u64,
+    /// Number of task directories kept, because their task is not finished yet
+    pub unfinished: usize,
+    /// Number of directories kept, because no task with their name exists
+    pub unknown: usize,
+    /// Size of the remaining directories in bytes
+    pub remaining_bytes: u64,
+}
+
+impl Display for GcReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Deleted {} task directories ({:.1} MB), kept {} of unfinished tasks and {} unknown directories ({:.1} MB)",
+            self.deleted,
+            self.freed_bytes as f64 / 1e6,
+            self.unfinished,
+            self.unknown,
+            self.remaining_bytes as f64 / 1e6,
+        )
+    }
+}
+
+/// A directory in the collected results, which belongs to a single task
+#[derive(Clone, Debug)]
+struct ResultDir {
+    path: PathBuf,
+    task: String,
+    /// Newest modification time of the directory and its content
+    modified: SystemTime,
+    size: u64,
+}
+
+/// Decision of [`select_garbage`] for a single [`ResultDir`]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Verdict {
+    Delete,
+    /// The task is finished, but its results are within the limits
+    Keep,
+    /// The task is not finished yet
+    Unfinished,
+    /// No task with the name of the directory exists
+    Unknown,
+}
+
+/// Delete the results of finished tasks, which violate `policy`
+///
+/// See [`select_garbage`] for which results are deleted.
+/// With `dry_run` nothing is deleted, but the report contains what would be deleted.
+pub fn collect_garbage(
+    taskmgr: &TaskManager,
+    config: &Config,
+    policy: &RetentionPolicy,
+    dry_run: bool,
+) -> Result<GcReport, Error> {
+    let mut dirs = result_dirs(&config.get_collected_results_path())?;
+    // Oldest first
+    dirs.sort_by_key(|dir| dir.modified);
+    let names: Vec<String> = dirs.iter().map(|dir| dir.task.clone()).collect();
+    let task_states = taskmgr.get_task_states(&names)?;
+    let now = SystemTime::now();
+    let verdicts = select_garbage(&dirs, &task_states, policy, now);
+
+    let mut report = GcReport {
+        remaining_bytes: dirs.iter().map(|dir| dir.size).sum(),
+        ..GcReport::default()
+    };
+    for (dir, verdict) in dirs.iter().zip(verdicts) {
+        match verdict {
+            Verdict::Delete => {}
+            Verdict::Keep => continue,
+            Verdict::Unfinished => {
+                report.unfinished += 1;
+                continue;
+            }
+            Verdict::Unknown => {
+                report.unknown += 1;
+                continue;
+            }
+        }
+
+        let age = now.duration_since(dir.modified).unwrap_or_default();
+        if dry_run {
+            info!(
+                "Would delete {} ({} bytes, {} hours old)",
+                dir.path.display(),
+                dir.size,
+                age.as_secs() / 3600
+            );
+        } else {
+            info!(
+                "Delete {} ({} bytes, {} hours old)",
+                dir.path.display(),
+                dir.size,
+                age.as_secs() / 3600
+            );
+            if let Err(err) = fs::remove_dir_all(&dir.path) {
+                warn!("Cannot delete {}: {}", dir.path.display(), err);
+                continue;
+            }
+        }
+        report.deleted += 1;
+        report.freed_bytes += dir.size;
+        report.remaining_bytes -= dir.size;
+    }
+    Ok(report)
+}
+
+/// Decide for each of the `dirs`, sorted from oldest to newest, whether it is deleted
+///
+/// `task_states` contains the name, state, and aborted flag of the tasks of the `dirs`.
+/// Only the results of finished tasks are deleted, i.e., of tasks which are Done or Aborted.
+/// Results older than [`RetentionPolicy::max_age_hours`] are always deleted.
+/// Further results are deleted from oldest to newest, until all `dirs` are smaller than [`RetentionPolicy::max_size_mb`].
+fn select_garbage(
+    dirs: &[ResultDir],
+    task_states: &[(String, TaskState, bool)],
+    policy: &RetentionPolicy,
+    now: SystemTime,
+) -> Vec<Verdict> {
+    let mut finished: HashMap<&str, bool> = HashMap::new();
+    for (name, state, aborted) in task_states {
+        let is_finished = *aborted || *state == TaskState::Done || *state == TaskState::Aborted;
+        // Names are unique, but never delete anything if that does not hold
+        *finished.entry(name.as_str()).or_insert(true) &= is_finished;
+    }
+
+    let max_age = policy
+        .max_age_hours
+        .map(|hours| Duration::from_secs(hours * 3600));
+    let max_size = policy.max_size_mb.map(|mb| mb * 1_000_000);
+    let mut total_size: u64 = dirs.iter().map(|dir| dir.size).sum();
+
+    dirs.iter()
+        .map(|dir| {
+            match finished.get(&*dir.task) {
+                None => return Verdict::Unknown,
+                Some(false) => return Verdict::Unfinished,
+                Some(true) => {}
+            }
+
+            let age = now.duration_since(dir.modified).unwrap_or_default();
+            let too_old = max_age.map_or(false, |max_age| age > max_age);
+            let too_large = max_size.map_or(false, |max_size| total_size > max_size);
+            if too_old || too_large {
+                total_size -= dir.size;
+                Verdict::Delete
+            } else {
+                Verdict::Keep
+            }
+        })
+        .collect()
+}
+
+/// List all task directories in `path`
+fn result_dirs(path: &Path) -> Result<Vec<ResultDir>, Error> {
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let mut res = Vec::new();
+    for entry in fs::read_dir(path).with_context(|| format!("Cannot list {}", path.display()))? {
+        let entry = entry?;
+        let path = entry.path();
+        if !path.is_dir() {
+            continue;
+        }
+        let (size, modified) = dir_size_and_mtime(&path)
+            .with_context(|| format!("Cannot determine the size of {}", path.display()))?;
+        res.push(ResultDir {
+            task: entry.file_name().to_string_lossy().into_owned(),
+            path,
+            modified,
+            size,
+        });
+    }
+    Ok(res)
+}
+
+/// Total size and newest modification time of all files in `path`, including subdirectories
+fn dir_size_and_mtime(path: &Path) -> io::Result<(u64, SystemTime)> {
+    let mut size = 0;
+    let mut modified = fs::metadata(path)?.modified()?;
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        let (entry_size, entry_modified) = if metadata.is_dir() {
+            dir_size_and_mtime(&entry.path())?
+        } else {
+            (metadata.len(), metadata.modified()?)
+        };
+        size += entry_size;
+        modified = modified.max(entry_modified);
+    }
+    Ok((size, modified))
+}
+
+#[test]
+fn test_select_garbage() {
+    let now = SystemTime::now();
+    let hours = |hours: u64| now - Duration::from_secs(hours * 3600);
+    let dir = |task: &str, modified: SystemTime, size_mb: u64| ResultDir {
+        path: PathBuf::from(task),
+        task: task.to_string(),
+        modified,
+        size: size_mb * 1_000_000,
+    };
+    // Oldest first
+    let dirs = vec![
+        dir("unfinished", hours(100), 10),
+        dir("unknown", hours(90), 10),
+        dir("aborted", hours(80), 10),
+        dir("done-old", hours(50), 10),
+        dir("done", hours(10), 10),
+        dir("done-new", hours(1), 10),
+    ];
+    let task_states = vec![
+        (
+            "unfinished".to_string(),
+            TaskState::CheckQualityDomain,
+            false,
+        ),
+        ("aborted".to_string(), TaskState::SubmittedToVm, true),
+        ("done-old".to_string(), TaskState::Done, false),
+        ("done".to_string(), TaskState::Done, false),
+        ("done-new".to_string(), TaskState::Done, false),
+    ];
+    use Verdict::*;
+
+    // Without limits, nothing is deleted
+    assert_eq!(
+        vec![Unfinished, Unknown, Keep, Keep, Keep, Keep],
+        select_garbage(&dirs, &task_states, &RetentionPolicy::default(), now)
+    );
+
+    let max_age = RetentionPolicy {
+        max_age_hours: Some(24),
+        ..RetentionPolicy::default()
+    };
+    assert_eq!(
+        vec![Unfinished, Unknown, Delete, Delete, Keep, Keep],
+        select_garbage(&dirs, &task_states, &max_age, now)
+    );
+
+    // The unfinished and unknown directories count towards the size, but are never deleted
+    let max_size = RetentionPolicy {
+        max_size_mb: Some(35),
+        ..RetentionPolicy::default()
+    };
+    assert_eq!(
+        vec![Unfinished, Unknown, Delete, Delete, Delete, Keep],
+        select_garbage(&dirs, &task_states, &max_size, now)
+    );
+
+    // A duplicate task name, which is not finished, protects the directory
+    let mut duplicate_states = task_states.clone();
+    duplicate_states.push(("done".to_string(), TaskState::Created, false));
+    assert_eq!(
+        vec![Unfinished, Unknown, Delete, Delete, Unfinished, Keep],
+        select_garbage(&dirs, &duplicate_states, &max_age, now)
+    );
+}
@@ -35,6 +35,47 @@ table! {
     }
 }
 
+table! {
+    use diesel::sql_types::*;
+    use crate::models::Task_state;
+
+    /// Representation of the `task_timings` table.
+    ///
+    /// (Automatically generated by Diesel.)
+    task_timings (id) {
+        /// The `id` column of the `task_timings` table.
+        ///
+        /// Its SQL type is `Int4`.
+        ///
+        /// (Automatically generated by Diesel.)
+        id -> Int4,
+        /// The `task_id` column of the `task_timings` table.
+        ///
+        /// Its SQL type is `Int4`.
+        ///
+        /// (Automatically generated by Diesel.)
+        task_id -> Int4,
+        /// The `time` column of the `task_timings` table.
+        ///
+        /// Its SQL type is `Timestamptz`.
+        ///
+        /// (Automatically generated by Diesel.)
+        time -> Timestamptz,
+        /// The `phase` column of the `task_timings` table.
+        ///
+        /// Its SQL type is `Text`.
+        ///
+        /// (Automatically generated by Diesel.)
+        phase -> Text,
+        /// The `duration_ms` column of the `task_timings` table.
+        ///
+        /// Its SQL type is `Int8`.
+        ///
+        /// (Automatically generated by Diesel.)
+        duration_ms -> Int8,
+    }
+}
+
 table! {
     use diesel::sql_types::*;
     use crate::models::Task_state;
@@ -121,9 +162,34 @@ table! {
         ///
         /// (Automatically generated by Diesel.)
         uri -> Text,
+        /// The `vantage_point` column of the `tasks` table.
+        ///
+        /// Its SQL type is `Text`.
+        ///
+        /// (Automatically generated by Diesel.)
+        vantage_point -> Text,
+        /// The `cold_cache` column of the `tasks` table.
+        ///
+        /// Its SQL type is `Bool`.
+        ///
+        /// (Automatically generated by Diesel.)
+        cold_cache -> Bool,
+        /// The `cache_dump_hash` column of the `tasks` table.
+        ///
+        /// Its SQL type is `Nullable<Text>`.
+        ///
+        /// (Automatically generated by Diesel.)
+        cache_dump_hash -> Nullable<Text>,
+        /// The `client_profile` column of the `tasks` table.
+        ///
+        /// Its SQL type is `Nullable<Text>`.
+        ///
+        /// (Automatically generated by Diesel.)
+        client_profile -> Nullable<Text>,
     }
 }
 
 joinable!(infos -> tasks (task_id));
+joinable!(task_timings -> tasks (task_id));
 
-allow_tables_to_appear_in_same_query!(infos, tasks,);
+allow_tables_to_appear_in_same_query!(infos, task_timings, tasks,);
@@ -0,0 +1,238 @@
+//! Content-addressed storage for the result files of tasks
+//!
+//! Each file is stored exactly once under its BLAKE3 hash, which deduplicates identical artifacts, e.g., identical TLS key files.
+//! A [`TaskManifest`] per task records the hash of each result file of the task.
+//! [`ResultStore::verify_task`] checks the hashes again, which detects silent corruption of the stored results.
+
+use anyhow::{bail, Context as _, Error};
+use misc_utils::fs::{file_write, read_to_string};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    ffi::OsStr,
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+};
+
+/// A single file stored in the [`ResultStore`]
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    /// Hex encoded BLAKE3 hash of the file content
+    pub hash: String,
+    /// File size in bytes
+    pub size: u64,
+}
+
+/// List of all result files belonging to a task
+#[derive(Clone, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub struct TaskManifest {
+    pub task: String,
+    pub website: String,
+    /// Mapping from the file type, e.g., `dnstap.xz`, to the stored file
+    pub files: BTreeMap<String, ManifestEntry>,
+}
+
+impl TaskManifest {
+    pub fn new(task: impl Into<String>, website: impl Into<String>) -> Self {
+        Self {
+            task: task.into(),
+            website: website.into(),
+            files: BTreeMap::new(),
+        }
+    }
+}
+
+/// Storage of result files under their BLAKE3 hash
+///
+/// The files are stored under `<root>/objects/<first two hash characters>/<hash>` and the manifests under `<root>/manifests/<task>.json`.
+#[derive(Clone, Debug)]
+pub struct ResultStore {
+    root: PathBuf,
+}
+
+impl ResultStore {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    fn object_path(&self, hash: &str) -> PathBuf {
+        self.root.join("objects").join(&hash[..2]).join(hash)
+    }
+
+    fn manifest_path(&self, task: &str) -> PathBuf {
+        self.root.join("manifests").join(format!("{}.json", task))
+    }
+
+    /// Move the file `src` into the store
+    ///
+    /// If a file with identical content is already stored, `src` is removed instead.
+    pub fn insert(&self, src: &Path) -> Result<ManifestEntry, Error> {
+        let entry = hash_file(src)?;
+        let dst = self.object_path(&entry.hash);
+        if dst.exists() {
+            fs::remove_file(src)
+                .with_context(|| format!("Failed to remove duplicate file {}", src.display()))?;
+        } else {
+            let parent = dst.parent().expect("Object paths always have a parent");
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create directory {}", parent.display()))?;
+            // Renaming fails across file systems, so fall back to copying through a temporary file
+            if fs::rename(src, &dst).is_err() {
+                let tmp = dst.with_extension("tmp");
+                fs::copy(src, &tmp).with_context(|| {
+                    format!("Failed to copy {} to {}", src.display(), tmp.display())
+                })?;
+                fs::rename(&tmp, &dst).with_context(|| {
+                    format!("Failed to move {} to {}", tmp.display(), dst.display())
+                })?;
+                fs::remove_file(src)
+                    .with_context(|| format!("Failed to remove file {}", src.display()))?;
+            }
+        }
+        Ok(entry)
+    }
+
+    /// Make a stored file available under the path `dst` by creating a hard link
+    ///
+    /// An existing file at `dst` is replaced.
+    pub fn link(&self, entry: &ManifestEntry, dst: &Path) -> Result<(), Error> {
+        if dst.exists() {
+            fs::remove_file(dst)
+                .with_context(|| format!("Failed to remove old file {}", dst.display()))?;
+        }
+        let src = self.object_path(&entry.hash);
+        fs::hard_link(&src, dst)
+            .with_context(|| format!("Failed to link {} to {}", src.display(), dst.display()))?;
+        Ok(())
+    }
+
+    pub fn write_manifest(&self, manifest: &TaskManifest) -> Result<(), Error> {
+        let path = self.manifest_path(&manifest.task);
+        let parent = path.parent().expect("Manifest paths always have a parent");
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create directory {}", parent.display()))?;
+        let wtr = file_write(&path)
+            .create(true)
+            .truncate()
+            .with_context(|| format!("Failed to open manifest {}", path.display()))?;
+        serde_json::to_writer_pretty(wtr, manifest)
+            .with_context(|| format!("Failed to write manifest {}", path.display()))?;
+        Ok(())
+    }
+
+    pub fn read_manifest(&self, task: &str) -> Result<TaskManifest, Error> {
+        let path = self.manifest_path(task);
+        let content = read_to_string(&path)
+            .with_context(|| format!("Failed to read manifest {}", path.display()))?;
+        serde_json::from_str(&content)
+            .with_context(|| format!("Failed to parse manifest {}", path.display()))
+    }
+
+    /// Return the names of all tasks with a manifest
+    pub fn tasks(&self) -> Result<Vec<String>, Error> {
+        let dir = self.root.join("manifests");
+        if !dir.exists() {
+            return Ok(Vec::new());
+        }
+        let mut tasks = Vec::new();
+        for entry in fs::read_dir(&dir)
+            .with_context(|| format!("Failed to list directory {}", dir.display()))?
+        {
+            let path = entry?.path();
+            if path.extension() == Some(OsStr::new("json")) {
+                if let Some(task) = path.file_stem() {
+                    tasks.push(task.to_string_lossy().to_string());
+                }
+            }
+        }
+        tasks.sort();
+        Ok(tasks)
+    }
+
+    /// Verify the hashes of all files of a task
+    pub fn verify_task(&self, task: &str) -> Result<TaskManifest, Error> {
+        let manifest = self.read_manifest(task)?;
+        for (name, entry) in &manifest.files {
+            let path = self.object_path(&entry.hash);
+            let actual = hash_file(&path)
+                .with_context(|| format!("Verifying file {} of task {}", name, task))?;
+            if actual != *entry {
+                bail!(
+                    "The file {} of task {} is corrupted: Expected hash {} ({} bytes) but found {} ({} bytes) in {}",
+                    name,
+                    task,
+                    entry.hash,
+                    entry.size,
+                    actual.hash,
+                    actual.size,
+                    path.display(),
+                );
+            }
+        }
+        Ok(manifest)
+    }
+}
+
+/// Calculate the BLAKE3 hash and size of a file
+pub fn hash_file(path: &Path) -> Result<ManifestEntry, Error> {
+    let mut file =
+        File::open(path).with_context(|| format!("Failed to open file {}", path.display()))?;
+    let mut hasher = blake3::Hasher::new();
+    let size = io::copy(&mut file, &mut hasher)
+        .with_context(|| format!("Failed to hash file {}", path.display()))?;
+    Ok(ManifestEntry {
+        hash: hasher.finalize().to_hex().to_string(),
+        size,
+    })
+}
+
+#[test]
+fn test_insert_deduplicates_files() {
+    let dir = tempfile::tempdir().unwrap();
+    let store = ResultStore::new(dir.path().join("store"));
+    let first = dir.path().join("first.txt");
+    let second = dir.path().join("second.txt");
+    fs::write(&first, "identical content").unwrap();
+    fs::write(&second, "identical content").unwrap();
+
+    let entry = store.insert(&first).unwrap();
+    assert_eq!(entry.size, 17);
+    assert_eq!(store.insert(&second).unwrap(), entry);
+    assert!(!first.exists());
+    assert!(!second.exists());
+    let objects: Vec<_> = fs::read_dir(dir.path().join("store/objects").join(&entry.hash[..2]))
+        .unwrap()
+        .collect();
+    assert_eq!(objects.len(), 1);
+
+    let linked = dir.path().join("linked.txt");
+    store.link(&entry, &linked).unwrap();
+    assert_eq!(fs::read_to_string(&linked).unwrap(), "identical content");
+}
+
+#[test]
+fn test_verify_task() {
+    let dir = tempfile::tempdir().unwrap();
+    let store = ResultStore::new(dir.path().join("store"));
+    let src = dir.path().join("dnstap");
+    fs::write(&src, "dnstap content").unwrap();
+
+    let mut manifest = TaskManifest::new("task-1", "example.com");
+    let entry = store.insert(&src).unwrap();
+    manifest
+        .files
+        .insert("dnstap.xz".to_string(), entry.clone());
+    store.write_manifest(&manifest).unwrap();
+    assert_eq!(store.tasks().unwrap(), vec!["task-1".to_string()]);
+    assert_eq!(store.verify_task("task-1").unwrap(), manifest);
+
+    // Silently changing the stored file must be detected
+    fs::write(store.object_path(&entry.hash), "dnstap content, corrupted").unwrap();
+    let err = store.verify_task("task-1").unwrap_err();
+    assert!(err.to_string().contains("corrupted"), "{:#}", err);
+
+    // A missing file is an error too
+    fs::remove_file(store.object_path(&entry.hash)).unwrap();
+    assert!(store.verify_task("task-1").is_err());
+}
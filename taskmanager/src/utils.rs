@@ -7,10 +7,11 @@ use std::{
     ffi::OsStr,
     fs, io,
     os::unix::fs::PermissionsExt,
-    path::Path,
+    path::{Path, PathBuf},
     process::{Command, ExitStatus, Stdio},
     time::Duration,
 };
+use taskmanager::{DockerConfig, NetemConfig};
 use wait_timeout::ChildExt;
 
 /// Compress a file with xz
@@ -47,12 +48,14 @@ pub fn ensure_path_exists(path: &Path) -> io::Result<()> {
 /// * `host_dir` Mounts the path to the `/output` location in the container and uses it for the container ID file
 /// * `command` is an optional command to be run *inside* the docker container.
 /// * `timeout` make sure the container is kill after the duration specified in timeout. This functions makes sure to kill and remove the container.
+/// * `docker` contains the resource limits and the network of the container.
 pub fn docker_run(
     image: &str,
     host_dir: &Path,
     command: Option<&str>,
     timeout: Duration,
     environment: &HashMap<String, String>,
+    docker: &DockerConfig,
 ) -> Result<ExitStatus, Error> {
     // Change permissions, such that if a different user than the docker user creates the
     // host_dir, the docker container can still write to it
@@ -64,8 +67,7 @@ pub fn docker_run(
     cmd.args(&[
         "run",
         "--privileged",
-        "--cpus",
-        "4",
+        &format!("--cpus={}", docker.cpus.unwrap_or(4.)),
         &format!("--cidfile={}/cidfile", host_dir.to_string_lossy()),
         "-v",
         &format!("{}:/output", host_dir.to_string_lossy()),
@@ -76,6 +78,7 @@ pub fn docker_run(
         "--sysctl=net.ipv6.conf.all.disable_ipv6=1",
         "--rm",
     ])
+    .args(docker_limit_args(docker, false))
     .stdout(Stdio::null())
     .stderr(Stdio::null());
     for (var_name, var_value) in environment {
@@ -122,6 +125,7 @@ pub fn docker_run_ssh(
     command: Option<&str>,
     timeout: Duration,
     environment: &HashMap<String, String>,
+    docker: &DockerConfig,
 ) -> Result<ExitStatus, Error> {
     // Change permissions, such that if a different user than the docker user creates the
     // host_dir, the docker container can still write to it
@@ -149,6 +153,7 @@ pub fn docker_run_ssh(
         "--shm-size=2g",
         "--rm",
     ])
+    .args(docker_limit_args(docker, true))
     .stdout(Stdio::null())
     .stderr(Stdio::null());
     for (var_name, var_value) in environment {
@@ -194,6 +199,43 @@ pub fn docker_run_ssh(
     }
 }
 
+/// Arguments for `docker run` from the [`DockerConfig`]
+///
+/// The CPU limit of local containers is already set by [`docker_run`].
+fn docker_limit_args(docker: &DockerConfig, include_cpus: bool) -> Vec<String> {
+    let mut args = Vec::new();
+    if include_cpus {
+        if let Some(cpus) = docker.cpus {
+            args.push(format!("--cpus={}", cpus));
+        }
+    }
+    if let Some(memory) = &docker.memory {
+        args.push(format!("--memory={}", memory));
+        // Prevent the container from using swap in addition to the memory limit
+        args.push(format!("--memory-swap={}", memory));
+    }
+    if let Some(network) = &docker.network {
+        args.push(format!("--network={}", network));
+    }
+    args
+}
+
+/// Write the `netem` file, which instructs the container to emulate the network conditions
+///
+/// Returns the path of the file. Nothing is written if `netem` is [`None`].
+pub(crate) fn write_netem_file(
+    dir: &Path,
+    netem: Option<&NetemConfig>,
+) -> Result<Option<PathBuf>, Error> {
+    netem
+        .map(|netem| {
+            let path = dir.join("netem");
+            fs::write(&path, netem.to_tc_args()).context("Failed to create file `netem`")?;
+            Ok(path)
+        })
+        .transpose()
+}
+
 /// Make really really sure the docker container will not be running afterwards
 ///
 /// Required the id of the container to kill.
@@ -237,6 +279,46 @@ pub(crate) fn ensure_docker_image_exists(image: &str) -> Result<(), Error> {
     Ok(())
 }
 
+/// Create the docker network if it does not exist yet
+pub(crate) fn ensure_docker_network_exists(network: &str) -> Result<(), Error> {
+    let exists = Command::new("docker")
+        .args(&["network", "inspect", network])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()?
+        .success();
+    if !exists {
+        let status = Command::new("docker")
+            .args(&["network", "create", network])
+            .stdout(Stdio::null())
+            .status()?;
+        if !status.success() {
+            bail!("Cannot create docker network {}", network)
+        }
+    }
+    Ok(())
+}
+
+/// Like [`ensure_docker_network_exists`] but via SSH
+pub(crate) fn ensure_docker_network_exists_ssh(host: &str, network: &str) -> Result<(), Error> {
+    let exists = Command::new("ssh")
+        .args(&[host, "docker", "network", "inspect", network])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()?
+        .success();
+    if !exists {
+        let status = Command::new("ssh")
+            .args(&[host, "docker", "network", "create", network])
+            .stdout(Stdio::null())
+            .status()?;
+        if !status.success() {
+            bail!("Cannot create docker network {} on {}", network, host)
+        }
+    }
+    Ok(())
+}
+
 /// Like [`ensure_docker_image_exists`] but via SSH
 pub(crate) fn ensure_docker_image_exists_ssh(host: &str, image: &str) -> Result<(), Error> {
     let output = Command::new("ssh")
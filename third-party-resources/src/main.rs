@@ -1,9 +1,9 @@
 use anyhow::{Context as _, Error};
 use csv::ReaderBuilder;
 use dnstap::{
-    dnstap::Message_Type,
     process_dnstap,
     protos::{self, DnstapContent},
+    MessageType,
 };
 use log::{error, info};
 use misc_utils::fs::{file_open_read, file_write};
@@ -131,13 +131,13 @@ fn main() -> Result<(), Error> {
                                 ..
                             } = ev.content;
                             match message_type {
-                                // Message_Type::FORWARDER_QUERY => {
+                                // MessageType::ForwarderQuery => {
                                 //     let (_dnsmsg, size) =
                                 //         query_message.expect("Unbound always sets this: FR r msg");
                                 //     println!("{}", size);
                                 //     None
                                 // }
-                                Message_Type::FORWARDER_RESPONSE => {
+                                MessageType::ForwarderResponse => {
                                     let (dnsmsg, _size) = response_message
                                         .expect("Unbound always sets this: FR r msg");
                                     let qname = dnsmsg.queries()[0].name().to_utf8();